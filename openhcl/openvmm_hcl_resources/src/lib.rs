@@ -48,6 +48,8 @@
     // VPCI devices
     #[cfg(feature = "nvme")]
     nvme::resolver::NvmeControllerResolver,
+    #[cfg(feature = "nvme")]
+    nvme::resolver::RamNamespaceDiskTemplateResolver,
 }
 
 // Mesh workers.
@@ -58,6 +58,7 @@
 use vm_resource::ResolveError;
 use vm_resource::Resource;
 use vm_resource::ResourceResolver;
+use vmcore::vm_task::VmTaskDriverSource;
 
 #[derive(Error, Debug)]
 enum Error<'a> {
@@ -545,13 +546,14 @@ pub async fn disk_from_disk_type<'a>(
     disk_type: Resource<DiskHandleKind>,
     read_only: bool,
     resolver: &ResourceResolver,
+    driver_source: &VmTaskDriverSource,
 ) -> Result<Arc<dyn SimpleDisk>, Vtl2SettingsErrorInfo> {
     let disk = resolver
         .resolve(
             disk_type,
             ResolveDiskParameters {
                 read_only,
-                _async_trait_workaround: &(),
+                driver_source,
             },
         )
         .await
@@ -1409,6 +1411,7 @@ async fn make_nvme_controller_config(
             namespaces,
             max_io_queues: 64,
             msix_count: 64,
+            namespace_pool: None,
         }
         .into_resource(),
     })
@@ -264,6 +264,7 @@ fn load_linux(params: LoadLinuxParams<'_>) -> Result<VpContext, Error> {
         with_psp: platform_config.general.psp_enabled,
         pm_base: crate::worker::PM_BASE,
         acpi_irq: crate::worker::SYSTEM_IRQ_ACPI,
+        pcie_ecam: None,
     };
 
     let acpi_tables = acpi_builder.build_acpi_tables(ACPI_BASE, |mem_layout, dsdt| {
@@ -421,6 +422,7 @@ pub fn write_uefi_config(
         with_psp: platform_config.general.psp_enabled,
         pm_base: crate::worker::PM_BASE,
         acpi_irq: crate::worker::SYSTEM_IRQ_ACPI,
+        pcie_ecam: None,
     };
 
     // Build the ACPI tables as specified.
@@ -1794,6 +1794,7 @@ async fn new_underhill_vm(
                 with_psp: dps.general.psp_enabled,
                 pm_base: PM_BASE,
                 acpi_irq: SYSTEM_IRQ_ACPI,
+                pcie_ecam: None,
             };
 
             let config = firmware_pcat::config::PcatBiosConfig {
@@ -2054,7 +2055,9 @@ async fn new_underhill_vm(
                         read_only,
                         disk_parameters,
                     } => {
-                        let disk = disk_from_disk_type(disk_type, read_only, &resolver).await?;
+                        let disk =
+                            disk_from_disk_type(disk_type, read_only, &resolver, &driver_source)
+                                .await?;
                         let scsi_disk = Arc::new(scsidisk::SimpleScsiDisk::new(
                             disk.clone(),
                             disk_parameters.unwrap_or_default(),
@@ -2729,6 +2732,7 @@ async fn new_underhill_vm(
         vmbus_devices.push(
             offer_vmbus_device_handle_unit(
                 &driver_source,
+                &vmtime_source,
                 &state_units,
                 vmbus,
                 &resolver,
@@ -9,7 +9,9 @@
 use acpi::dsdt;
 use anyhow::Context;
 use cfg_if::cfg_if;
+use chipset_device::mmio::RegisterMmioIntercept;
 use chipset_device_resources::IRQ_LINE_SET;
+use closeable_mutex::CloseableMutex;
 use debug_ptr::DebugPtr;
 use disk_backend::resolve::ResolveDiskParameters;
 use disk_backend::SimpleDisk;
@@ -32,6 +34,7 @@
 use hvlite_defs::config::HypervisorConfig;
 use hvlite_defs::config::LoadMode;
 use hvlite_defs::config::MemoryConfig;
+use hvlite_defs::config::PcieRootComplexConfig;
 use hvlite_defs::config::ProcessorTopologyConfig;
 use hvlite_defs::config::SerialPipes;
 use hvlite_defs::config::VirtioBus;
@@ -72,6 +75,7 @@
 use pal_async::DefaultPool;
 use pci_core::msi::MsiInterruptSet;
 use pci_core::PciInterruptPin;
+use pcie_root_port::PcieRootPort;
 use scsi_core::ResolveScsiDeviceHandleParams;
 use scsidisk::atapi_scsi::AtapiScsiDisk;
 use scsidisk::SimpleScsiDisk;
@@ -99,6 +103,7 @@
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::kind::KeyboardInputHandleKind;
 use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::PciDeviceHandleKind;
 use vm_resource::kind::VirtioDeviceHandle;
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_resource::Resource;
@@ -115,6 +120,8 @@
 use vmbus_server::hvsock::HvsockRelay;
 use vmbus_server::HvsockRelayChannel;
 use vmbus_server::VmbusServer;
+use vmcore::device_state::ChangeDeviceState;
+use vmcore::ram_discard::DiscardRam;
 use vmcore::save_restore::SavedStateRoot;
 use vmcore::vm_task::thread::ThreadDriverBackend;
 use vmcore::vm_task::VmTaskDriverSource;
@@ -123,6 +130,7 @@
 use vmcore::vmtime::VmTimeSource;
 use vmgs_broker::resolver::VmgsFileResolver;
 use vmm_core::acpi_builder::AcpiTablesBuilder;
+use vmm_core::device_builder::DirectMsiTarget;
 use vmm_core::input_distributor::InputDistributor;
 use vmm_core::partition_unit::block_on_vp;
 use vmm_core::partition_unit::Halt;
@@ -170,6 +178,7 @@ fn from_config(config: Config) -> Self {
             memory: config.memory,
             processor_topology: config.processor_topology,
             chipset: config.chipset,
+            pcie_root_complex: config.pcie_root_complex,
             #[cfg(windows)]
             kernel_vmnics: config.kernel_vmnics,
             input: config.input,
@@ -209,6 +218,7 @@ pub struct Manifest {
     processor_topology: ProcessorTopologyConfig,
     hypervisor: HypervisorConfig,
     chipset: BaseChipsetManifest,
+    pcie_root_complex: Option<PcieRootComplexConfig>,
     #[cfg(windows)]
     kernel_vmnics: Vec<hvlite_defs::config::KernelVmNicConfig>,
     input: mesh::MpscReceiver<InputData>,
@@ -243,13 +253,14 @@ async fn open_simple_disk(
     resolver: &ResourceResolver,
     disk_type: Resource<DiskHandleKind>,
     read_only: bool,
+    driver_source: &VmTaskDriverSource,
 ) -> anyhow::Result<Arc<dyn SimpleDisk>> {
     let disk = resolver
         .resolve(
             disk_type,
             ResolveDiskParameters {
                 read_only,
-                _async_trait_workaround: &(),
+                driver_source,
             },
         )
         .await?;
@@ -374,6 +385,7 @@ struct InitializedVm {
     gm: GuestMemory,
     cfg: Manifest,
     mem_layout: MemoryLayout,
+    pcie_ecam: Option<MemoryRange>,
     processor_topology: ProcessorTopology,
     igvm_file: Option<IgvmFile>,
     driver_source: VmTaskDriverSource,
@@ -497,6 +509,7 @@ struct LoadedVmInner {
     partition: Arc<dyn HvlitePartition>,
     _chipset_devices: ChipsetDevices,
     _vmtime: SpawnedUnit<VmTimeKeeper>,
+    vmtime_source: VmTimeSource,
     _scsi_devices: Vec<SpawnedUnit<ChannelUnit<storvsp::StorageDevice>>>,
     memory_manager: GuestMemoryManager,
     gm: GuestMemory,
@@ -522,6 +535,11 @@ struct LoadedVmInner {
     virtio_serial: Option<SerialPipes>,
 
     chipset_cfg: BaseChipsetManifest,
+    pcie_root_complex: Option<PcieRootComplexConfig>,
+    /// The ECAM region of the PCIe root complex, carved out of the low MMIO
+    /// gap.
+    pcie_ecam: Option<MemoryRange>,
+    pcie_root_ports: Vec<PcieRootPortSlot>,
     #[cfg_attr(not(guest_arch = "x86_64"), allow(dead_code))]
     virtio_mmio_count: usize,
     #[cfg_attr(not(guest_arch = "x86_64"), allow(dead_code))]
@@ -538,6 +556,13 @@ struct LoadedVmInner {
     vmgs_client_inspect_handle: Option<vmgs_broker::VmgsClient>,
 }
 
+/// A hot-plug capable PCIe root port, along with the object used to register
+/// the MMIO regions of devices plugged into its slot.
+struct PcieRootPortSlot {
+    root_port: Arc<CloseableMutex<PcieRootPort>>,
+    register_mmio: Box<dyn RegisterMmioIntercept + Send>,
+}
+
 fn choose_hypervisor() -> anyhow::Result<Hypervisor> {
     cfg_if! {
         if #[cfg(target_os = "linux")] {
@@ -810,6 +835,45 @@ async fn new_with_hypervisor<P, H>(
         )
         .context("invalid memory configuration")?;
 
+        // The PCIe ECAM region (if any) is carved out of the start of the low
+        // MMIO gap.
+        let pcie_ecam = if let Some(pcie) = &cfg.pcie_root_complex {
+            if !cfg.chipset.with_generic_pci_bus {
+                anyhow::bail!("a pcie root complex requires the generic pci bus");
+            }
+            if pcie.root_port_count as u16 >= pcie.bus_count {
+                anyhow::bail!(
+                    "{} pcie root ports need more than {} buses",
+                    pcie.root_port_count,
+                    pcie.bus_count
+                );
+            }
+            #[cfg(not(guest_arch = "x86_64"))]
+            if pcie.root_port_count != 0 {
+                anyhow::bail!("pcie root ports are only supported on x86_64");
+            }
+            let low_mmio_gap = mem_layout.mmio()[0];
+            let ecam = MemoryRange::try_new(
+                low_mmio_gap.start()
+                    ..low_mmio_gap.start() + pcie.bus_count as u64 * pci_bus::ECAM_BUS_SIZE,
+            )
+            .ok()
+            .filter(|ecam| {
+                ecam.start() % pci_bus::ECAM_BUS_SIZE == 0
+                    && low_mmio_gap.contains(ecam)
+                    && ecam.end() < low_mmio_gap.end()
+            })
+            .with_context(|| {
+                format!(
+                    "cannot fit {} pcie buses in the low mmio gap {low_mmio_gap}",
+                    pcie.bus_count
+                )
+            })?;
+            Some(ecam)
+        } else {
+            None
+        };
+
         let mut memory_builder = GuestMemoryBuilder::new();
         memory_builder = memory_builder
             .existing_backing(shared_memory)
@@ -911,6 +975,7 @@ async fn new_with_hypervisor<P, H>(
             gm,
             cfg,
             mem_layout,
+            pcie_ecam,
             processor_topology,
             igvm_file,
             driver_source,
@@ -938,6 +1003,7 @@ async fn load(
             gm,
             cfg,
             mem_layout,
+            pcie_ecam,
             processor_topology,
             igvm_file,
             driver_source,
@@ -1117,6 +1183,7 @@ async fn load(
                             with_psp: cfg.chipset.with_generic_psp,
                             pm_base: PM_BASE,
                             acpi_irq: SYSTEM_IRQ_ACPI,
+                            pcie_ecam,
                         };
                         let srat = acpi_tables_builder.build_srat();
                         firmware_pcat::config::PcatBiosConfig {
@@ -1253,9 +1320,10 @@ async fn load(
                         read_only,
                         disk_parameters,
                     } => {
-                        let disk = open_simple_disk(&resolver, disk_type, read_only)
-                            .await
-                            .context("failed to open IDE disk")?;
+                        let disk =
+                            open_simple_disk(&resolver, disk_type, read_only, &driver_source)
+                                .await
+                                .context("failed to open IDE disk")?;
 
                         // Only disks get accelerator channels. DVDs dont.
                         let scsi_disk = ScsiControllerDisk::new(Arc::new(SimpleScsiDisk::new(
@@ -1359,7 +1427,7 @@ async fn load(
                     read_only,
                 } = disk_cfg;
 
-                let disk = open_simple_disk(&resolver, disk_type, read_only)
+                let disk = open_simple_disk(&resolver, disk_type, read_only, &driver_source)
                     .await
                     .context("failed to open floppy disk")?;
                 tracing::trace!("floppy opened based on config into DriveRibbon");
@@ -1422,6 +1490,8 @@ async fn load(
                 bus_id: pci_bus_id_generic.clone(),
                 pio_addr: pci_bus::standard_x86_io_ports::ADDR_START,
                 pio_data: pci_bus::standard_x86_io_ports::DATA_START,
+                ecam: pcie_ecam
+                    .map(|ecam| (ecam.start(), (ecam.len() / pci_bus::ECAM_BUS_SIZE) as u16)),
             });
 
         let deps_generic_pic = (cfg.chipset.with_generic_pic).then_some(dev::GenericPicDeps {});
@@ -1946,6 +2016,7 @@ async fn add_virtio_vpci(
             vmbus_devices.push(
                 offer_vmbus_device_handle_unit(
                     &driver_source,
+                    &vmtime_source,
                     &state_units,
                     vmbus,
                     &resolver,
@@ -1980,6 +2051,7 @@ async fn add_virtio_vpci(
                 VIRTIO_MMIO_IOAPIC_IRQ
             }
         };
+        let ram_discard: Arc<dyn DiscardRam> = Arc::new(memory_manager.ram_discard());
         for (bus, device) in cfg.virtio_devices.into_iter() {
             let id = device.id().to_string();
             let device = resolver
@@ -1988,6 +2060,7 @@ async fn add_virtio_vpci(
                     VirtioResolveInput {
                         driver_source: &driver_source,
                         guest_memory: &gm,
+                        ram_discard: &ram_discard,
                     },
                 )
                 .await?;
@@ -2062,6 +2135,38 @@ async fn add_virtio_vpci(
             }
         }
 
+        // Add the hot-plug capable PCIe root ports. Each port gets its own
+        // device number on bus 0, which doubles as its physical slot number.
+        let mut pcie_root_ports = Vec::new();
+        if let Some(pcie) = &cfg.pcie_root_complex {
+            for port in 0..pcie.root_port_count {
+                let pci_inta_line = pci_inta_line.context("missing PCI INT#A line")?;
+
+                let device_number = pci_device_number;
+                pci_device_number += 1;
+                if device_number >= 32 {
+                    anyhow::bail!("no pci device number left for pcie root port {port}");
+                }
+                pci_legacy_interrupts.push(((device_number, None), pci_inta_line));
+
+                let mut builder = chipset_builder
+                    .arc_mutex_device(format!("pcie-root-port{port}"))
+                    .with_pci_addr(0, device_number, 0)
+                    .on_pci_bus(pci_bus_id_generic.clone());
+                let register_mmio = builder.services().register_mmio();
+                let root_port = builder.add(|services| {
+                    PcieRootPort::new(
+                        device_number.into(),
+                        services.new_line(IRQ_LINE_SET, "hotplug", pci_inta_line),
+                    )
+                })?;
+                pcie_root_ports.push(PcieRootPortSlot {
+                    root_port,
+                    register_mmio: Box::new(register_mmio),
+                });
+            }
+        }
+
         let mut virt_serial_io = None;
         {
             if with_virtio_serial_mmio {
@@ -2216,6 +2321,7 @@ async fn add_virtio_vpci(
                 partition,
                 _chipset_devices: devices,
                 _vmtime: vmtime,
+                vmtime_source,
                 _scsi_devices: scsi_devices,
                 memory_manager,
                 gm,
@@ -2237,6 +2343,9 @@ async fn add_virtio_vpci(
                 _kernel_vmnics: kernel_vmnics,
                 vmbus_devices,
                 chipset_cfg: cfg.chipset,
+                pcie_root_complex: cfg.pcie_root_complex,
+                pcie_ecam,
+                pcie_root_ports,
                 firmware_event_send: cfg.firmware_event_send,
                 load_mode: cfg.load_mode,
                 virtio_mmio_count,
@@ -2281,6 +2390,7 @@ async fn load_firmware(&mut self, vtl2_only: bool) -> anyhow::Result<()> {
             with_pit: self.chipset_cfg.with_generic_pit,
             pm_base: PM_BASE,
             acpi_irq: SYSTEM_IRQ_ACPI,
+            pcie_ecam: self.pcie_ecam,
         };
 
         if vtl2_only {
@@ -2314,6 +2424,7 @@ async fn load_firmware(&mut self, vtl2_only: bool) -> anyhow::Result<()> {
                                     mem_layout,
                                     dsdt,
                                     &self.chipset_cfg,
+                                    self.pcie_ecam,
                                     enable_serial,
                                     self.virtio_mmio_count,
                                     self.virtio_mmio_irq,
@@ -2492,6 +2603,68 @@ async fn pause(&mut self) -> bool {
         true
     }
 
+    /// Resolves `resource` and plugs it into the slot of PCIe root port
+    /// `port`.
+    async fn add_pcie_device(
+        &mut self,
+        port: u8,
+        resource: Resource<PciDeviceHandleKind>,
+    ) -> anyhow::Result<()> {
+        let slot = self
+            .inner
+            .pcie_root_ports
+            .get_mut(port as usize)
+            .with_context(|| format!("no pcie root port {port}"))?;
+        if slot.root_port.lock().is_occupied() {
+            anyhow::bail!("pcie root port {port} is occupied");
+        }
+
+        let device_name = format!("{}:pcie-root-port{port}", resource.id());
+
+        // Root ports are only created on x86_64, where the guest programs the
+        // device's MSIs in the architectural APIC format.
+        #[cfg(guest_arch = "x86_64")]
+        let msi_target = {
+            let partition = self.inner.partition.clone();
+            DirectMsiTarget::new(move |address, data| {
+                partition.request_msi(Vtl::Vtl0, virt::irqcon::MsiRequest { address, data })
+            })
+        };
+        #[cfg(not(guest_arch = "x86_64"))]
+        let msi_target = DirectMsiTarget::new(|_, _| {
+            unreachable!("pcie root ports are only supported on x86_64")
+        });
+
+        let device = vmm_core::device_builder::build_pcie_device(
+            &self.inner.driver_source,
+            &self.inner.resolver,
+            &self.inner.gm,
+            resource,
+            slot.register_mmio.as_mut(),
+            &msi_target,
+        )
+        .await?;
+
+        slot.root_port.lock().attach(device_name, device)?;
+        Ok(())
+    }
+
+    /// Unplugs the device from the slot of PCIe root port `port`.
+    async fn remove_pcie_device(&mut self, port: u8) -> anyhow::Result<()> {
+        let slot = self
+            .inner
+            .pcie_root_ports
+            .get(port as usize)
+            .with_context(|| format!("no pcie root port {port}"))?;
+        let mut device = slot
+            .root_port
+            .lock()
+            .detach()
+            .with_context(|| format!("pcie root port {port} is empty"))?;
+        device.stop().await;
+        Ok(())
+    }
+
     pub async fn run(
         mut self,
         driver: &impl Spawn,
@@ -2539,6 +2712,7 @@ enum Event {
                         let mut stopped = false;
                         // First run the non-destructive operations.
                         let r = async {
+                            self.check_saveable()?;
                             let shared_memory = self.inner.memory_manager.shared_memory_backing();
                             if self.running {
                                 self.state_units.stop().await;
@@ -2623,6 +2797,7 @@ enum Event {
                                 .context("no vmbus available")?;
                                 let device = offer_vmbus_device_handle_unit(
                                     &this.inner.driver_source,
+                                    &this.inner.vmtime_source,
                                     &this.state_units,
                                     vmbus,
                                     &this.inner.resolver,
@@ -2636,6 +2811,14 @@ enum Event {
                         })
                         .await
                     }
+                    VmRpc::AddPcieDevice(rpc) => {
+                        rpc.handle_failable(|(port, resource)| self.add_pcie_device(port, resource))
+                            .await
+                    }
+                    VmRpc::RemovePcieDevice(rpc) => {
+                        rpc.handle_failable(|port| self.remove_pcie_device(port))
+                            .await
+                    }
                     VmRpc::ConnectHvsock(Rpc((mut ctx, service_id, vtl), response)) => {
                         if let Some(relay) = self.hvsock_relay(vtl) {
                             let fut = relay.connect(&mut ctx, service_id);
@@ -2655,6 +2838,7 @@ enum Event {
                             if !self.inner.partition.supports_reset() {
                                 return Err(PulseSaveRestoreError::ResetNotSupported);
                             }
+                            self.check_saveable()?;
                             let paused = self.pause().await;
                             self.save_reset_restore().await?;
 
@@ -2775,10 +2959,25 @@ fn hvsock_relay(&self, vtl: DeviceVtl) -> Option<&HvsockRelay> {
         }
     }
 
+    /// Fails if the VM is in a state that cannot be saved.
+    fn check_saveable(&self) -> anyhow::Result<()> {
+        // Hot-plugged devices are not part of the VM's configuration, so there
+        // would be nothing to restore their state into.
+        for (port, slot) in self.inner.pcie_root_ports.iter().enumerate() {
+            if slot.root_port.lock().is_occupied() {
+                anyhow::bail!(
+                    "cannot save the vm while a device is plugged into pcie root port {port}"
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Saves the VM's processor, partition, and device state.
     ///
     /// TODO: virtio & vmbus unsupported.
     async fn save(&mut self) -> anyhow::Result<SavedState> {
+        self.check_saveable()?;
         Ok(SavedState {
             units: self.state_units.save().await?,
         })
@@ -2820,6 +3019,7 @@ async fn serialize(
             memory: self.inner.memory_cfg,
             processor_topology: self.inner.processor_topology.to_config(),
             chipset: self.inner.chipset_cfg,
+            pcie_root_complex: self.inner.pcie_root_complex,
             vmbus: None,      // TODO
             vtl2_vmbus: None, // TODO
             hypervisor: self.inner.hypervisor_cfg,
@@ -2878,6 +3078,7 @@ fn add_devices_to_dsdt(
     mem_layout: &MemoryLayout,
     dsdt: &mut dsdt::Dsdt,
     cfg: &BaseChipsetManifest,
+    pcie_ecam: Option<MemoryRange>,
     serial_uarts: bool,
     virtio_mmio_count: usize,
     virtio_mmio_irq: u32,
@@ -2934,7 +3135,18 @@ fn add_devices_to_dsdt(
 
     let high_mmio_gap = MemoryRange::new(high_mmio_space);
 
-    if cfg.with_generic_pci_bus || cfg.with_i440bx_host_pci_bridge {
+    if let Some(ecam) = pcie_ecam {
+        // The ECAM region was carved out of the start of the low MMIO gap.
+        assert_eq!(ecam.start(), low_mmio_gap.start());
+        let low_mmio_gap = MemoryRange::new(ecam.end()..low_mmio_gap.end());
+        dsdt.add_pcie(
+            low_mmio_gap,
+            high_mmio_gap,
+            ecam,
+            (ecam.len() / pci_bus::ECAM_BUS_SIZE) as u16,
+            pci_legacy_interrupts,
+        );
+    } else if cfg.with_generic_pci_bus || cfg.with_i440bx_host_pci_bridge {
         // TODO: actually plumb through legacy PCI interrupts
         dsdt.add_pci(low_mmio_gap, high_mmio_gap, pci_legacy_interrupts);
     } else {
@@ -29,6 +29,7 @@ pub struct Config {
     pub processor_topology: ProcessorTopologyConfig,
     pub hypervisor: HypervisorConfig,
     pub chipset: BaseChipsetManifest,
+    pub pcie_root_complex: Option<PcieRootComplexConfig>,
     pub vmbus: Option<VmbusConfig>,
     pub vtl2_vmbus: Option<VmbusConfig>,
     #[cfg(windows)]
@@ -75,6 +76,12 @@ pub struct Config {
     MemoryRange::new(0x20_0000_0000..0x20_4000_0000), // 128GB to 129 GB
 ];
 
+/// Default number of buses decoded by the PCIe root complex.
+pub const DEFAULT_PCIE_BUS_COUNT: u16 = 16;
+
+/// Default number of hot-plug capable root ports on the PCIe root complex.
+pub const DEFAULT_PCIE_ROOT_PORT_COUNT: u8 = 4;
+
 pub const DEFAULT_GIC_DISTRIBUTOR_BASE: u64 = 0xFFFF_0000;
 // The KVM in-kernel vGICv3 requires the distributor and redistributor bases be 64KiB aligned.
 pub const DEFAULT_GIC_REDISTRIBUTORS_BASE: u64 = if cfg!(target_os = "linux") {
@@ -150,6 +157,20 @@ pub enum Vtl2BaseAddressType {
     Vtl2Allocate { size: Option<u64> },
 }
 
+/// Configuration for exposing the generic PCI bus as a PCI Express root
+/// complex.
+#[derive(Debug, Clone, MeshPayload)]
+pub struct PcieRootComplexConfig {
+    /// The number of buses, starting at bus 0, whose configuration space is
+    /// accessible via ECAM. Each bus takes 1MB at the start of the low MMIO
+    /// gap.
+    pub bus_count: u16,
+    /// The number of hot-plug capable root ports on bus 0. Each root port
+    /// needs at least one bus number of its own, so this must be less than
+    /// `bus_count`.
+    pub root_port_count: u8,
+}
+
 #[derive(Debug, MeshPayload)]
 pub struct VpciDeviceConfig {
     pub vtl: DeviceVtl,
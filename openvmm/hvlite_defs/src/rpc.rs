@@ -13,6 +13,7 @@
 use mesh::MeshPayload;
 use std::fmt;
 use std::fs::File;
+use vm_resource::kind::PciDeviceHandleKind;
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_resource::Resource;
 
@@ -25,6 +26,8 @@ pub enum VmRpc {
     Reset(FailableRpc<(), ()>),
     Nmi(Rpc<u32, ()>),
     AddVmbusDevice(FailableRpc<(DeviceVtl, Resource<VmbusDeviceHandleKind>), ()>),
+    AddPcieDevice(FailableRpc<(u8, Resource<PciDeviceHandleKind>), ()>),
+    RemovePcieDevice(FailableRpc<u8, ()>),
     ConnectHvsock(FailableRpc<(CancelContext, Guid, DeviceVtl), unix_socket::UnixStream>),
     PulseSaveRestore(Rpc<(), Result<(), PulseSaveRestoreError>>),
     StartReloadIgvm(FailableRpc<File, ()>),
@@ -57,6 +60,8 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             VmRpc::ClearHalt(_) => "ClearHalt",
             VmRpc::Nmi(_) => "Nmi",
             VmRpc::AddVmbusDevice(_) => "AddVmbusDevice",
+            VmRpc::AddPcieDevice(_) => "AddPcieDevice",
+            VmRpc::RemovePcieDevice(_) => "RemovePcieDevice",
             VmRpc::ConnectHvsock(_) => "ConnectHvsock",
             VmRpc::PulseSaveRestore(_) => "PulseSaveRestore",
             VmRpc::StartReloadIgvm(_) => "StartReloadIgvm",
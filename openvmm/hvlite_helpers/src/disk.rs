@@ -3,7 +3,10 @@
 
 //! Guest disk helpers.
 
+use anyhow::Context;
+use std::fs::File;
 use std::path::Path;
+use std::path::PathBuf;
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::Resource;
 
@@ -11,7 +14,10 @@
 ///
 /// If the file ends with .vhd and is a fixed VHD1, it will be opened using
 /// the user-mode VHD parser. Otherwise, if the file ends with .vhd or
-/// .vhdx, the file will be opened using the kernel-mode VHD parser.
+/// .vhdx, the file will be opened using the kernel-mode VHD parser on
+/// Windows. On other platforms, dynamic and differencing VHD1s are opened
+/// with [`open_vhd1`], and VHDXs with [`open_vhdx`]. If the file ends with
+/// .qcow2, it will be opened with [`open_qcow2`].
 pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
     Ok(match path.extension().and_then(|s| s.to_str()) {
         Some("vhd") => {
@@ -32,7 +38,9 @@ pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<D
                         ))
                     }
                     #[cfg(not(windows))]
-                    anyhow::bail!("non-fixed VHD not supported on Linux");
+                    {
+                        open_vhd1(path, read_only)?
+                    }
                 }
                 Err(err) => return Err(err.into()),
             }
@@ -45,8 +53,9 @@ pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<D
                 ))
             }
             #[cfg(not(windows))]
-            anyhow::bail!("VHDX not supported on Linux");
+            open_vhdx(path, read_only)?
         }
+        Some("qcow2") => open_qcow2(path, read_only)?,
         Some("iso") if !read_only => {
             anyhow::bail!("iso file cannot be opened as read/write")
         }
@@ -60,3 +69,175 @@ pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<D
         }
     })
 }
+
+/// The maximum length of a QCOW2 backing file chain.
+const MAX_QCOW2_CHAIN_DEPTH: usize = 16;
+
+/// Opens the resources needed for using a QCOW2 image at `path`, including
+/// the chain of backing files it references.
+///
+/// Backing files are always opened read-only. Relative backing file names are
+/// resolved relative to the directory containing the referencing image.
+pub fn open_qcow2(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_qcow2_chain(path, read_only, 0)
+}
+
+fn open_qcow2_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth > MAX_QCOW2_CHAIN_DEPTH {
+        anyhow::bail!("qcow2 backing file chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)?;
+
+    let backing = disk_qcow2::read_backing_file(&file)?
+        .map(|backing| {
+            let backing_path = path.parent().unwrap_or(Path::new(".")).join(&backing.name);
+            let is_qcow2 = match backing.format.as_deref() {
+                Some("qcow2") => true,
+                Some("raw") => false,
+                Some(format) => anyhow::bail!("unsupported backing file format {format}"),
+                None => disk_qcow2::is_qcow2(&File::open(&backing_path)?)?,
+            };
+            let disk = if is_qcow2 {
+                open_qcow2_chain(&backing_path, true, depth + 1)
+            } else {
+                File::open(&backing_path)
+                    .map(|file| Resource::new(disk_backend_resources::FileDiskHandle(file)))
+                    .map_err(Into::into)
+            };
+            disk.with_context(|| format!("failed to open backing file {}", backing_path.display()))
+        })
+        .transpose()?;
+
+    Ok(Resource::new(disk_backend_resources::Qcow2DiskHandle {
+        file,
+        backing,
+    }))
+}
+
+/// The maximum length of a differencing VHD1 parent chain.
+const MAX_VHD1_CHAIN_DEPTH: usize = 16;
+
+/// Opens the resources needed for using the VHD1 at `path` with the user-mode
+/// VHD parser, including the chain of parents of a differencing disk.
+///
+/// Parents are always opened read-only. They are located using the parent
+/// locators in the order they are stored, with relative locators resolved
+/// relative to the directory containing the child.
+pub fn open_vhd1(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_vhd1_chain(path, read_only, 0)
+}
+
+fn open_vhd1_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth > MAX_VHD1_CHAIN_DEPTH {
+        anyhow::bail!("vhd parent chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)?;
+
+    match disk_vhd1::Vhd1Disk::open_fixed(file.try_clone()?, read_only) {
+        Ok(_) => {
+            return Ok(Resource::new(disk_backend_resources::FixedVhd1DiskHandle(
+                file,
+            )))
+        }
+        Err(disk_vhd1::OpenError::NotFixed) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let parent = disk_vhd1::read_parent_location(&file)?
+        .map(|location| {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            let parent_path = location
+                .paths
+                .iter()
+                .map(|parent_path| match parent_path {
+                    disk_vhd1::ParentPath::Relative(p) => dir.join(p.replace('\\', "/")),
+                    disk_vhd1::ParentPath::Absolute(p) => PathBuf::from(p),
+                })
+                .find(|p| p.exists())
+                .with_context(|| format!("failed to locate parent disk {}", location.name))?;
+            open_vhd1_chain(&parent_path, true, depth + 1)
+                .with_context(|| format!("failed to open parent disk {}", parent_path.display()))
+        })
+        .transpose()?;
+
+    Ok(Resource::new(
+        disk_backend_resources::DynamicVhd1DiskHandle { file, parent },
+    ))
+}
+
+/// The maximum length of a differencing VHDX parent chain.
+const MAX_VHDX_CHAIN_DEPTH: usize = 16;
+
+/// Opens the resources needed for using the VHDX at `path` with the user-mode
+/// VHDX parser, including the chain of parents of a differencing disk.
+///
+/// Parents are always opened read-only. They are located by trying the
+/// relative, volume, and absolute paths in the parent locator, in that order,
+/// and the first one whose data write GUID matches the locator's linkage is
+/// used.
+pub fn open_vhdx(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    open_vhdx_chain(path, read_only, 0)
+}
+
+fn open_vhdx_chain(
+    path: &Path,
+    read_only: bool,
+    depth: usize,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
+    if depth > MAX_VHDX_CHAIN_DEPTH {
+        anyhow::bail!("vhdx parent chain is too long");
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)?;
+
+    let parent = disk_vhdx::read_parent_locator(&file)?
+        .map(|locator| {
+            let dir = path.parent().unwrap_or(Path::new("."));
+            let candidates = [
+                locator
+                    .relative_path
+                    .as_ref()
+                    .map(|p| dir.join(p.replace('\\', "/"))),
+                locator.volume_path.as_ref().map(PathBuf::from),
+                locator.absolute_win32_path.as_ref().map(PathBuf::from),
+            ];
+            for parent_path in candidates.into_iter().flatten() {
+                let Ok(parent_file) = File::open(&parent_path) else {
+                    continue;
+                };
+                if locator.matches(&parent_file).with_context(|| {
+                    format!("failed to read parent disk {}", parent_path.display())
+                })? {
+                    return open_vhdx_chain(&parent_path, true, depth + 1).with_context(|| {
+                        format!("failed to open parent disk {}", parent_path.display())
+                    });
+                }
+            }
+            anyhow::bail!(
+                "failed to locate parent disk with data write guid {}",
+                locator.linkage
+            )
+        })
+        .transpose()?;
+
+    Ok(Resource::new(disk_backend_resources::VhdxDiskHandle {
+        file,
+        parent,
+    }))
+}
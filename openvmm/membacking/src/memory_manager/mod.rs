@@ -27,6 +27,7 @@
 use std::thread::JoinHandle;
 use thiserror::Error;
 use vm_topology::memory::MemoryLayout;
+use vmcore::ram_discard::DiscardRam;
 
 /// The HvLite memory manager.
 #[derive(Debug, Inspect)]
@@ -57,6 +58,10 @@ pub struct GuestMemoryManager {
 #[derive(Debug)]
 struct RamRegion {
     range: MemoryRange,
+    /// The offset of the region within the RAM backing.
+    offset: u64,
+    /// Whether the region is VTL2 memory.
+    vtl2: bool,
     handle: RegionHandle,
 }
 
@@ -280,6 +285,8 @@ pub async fn build(
 
             ram_regions.push(RamRegion {
                 range: *range,
+                offset: start,
+                vtl2: Some(*range) == mem_layout.vtl2_range(),
                 handle: region,
             });
             start += range.len();
@@ -353,6 +360,19 @@ pub fn ram_visibility_control(&self) -> RamVisibilityControl {
         }
     }
 
+    /// Returns an object for releasing the host memory backing VTL0 RAM.
+    pub fn ram_discard(&self) -> RamDiscard {
+        RamDiscard {
+            guest_ram: self.guest_ram.clone(),
+            regions: self
+                .ram_regions
+                .iter()
+                .filter(|region| !region.vtl2)
+                .map(|region| (region.range, region.offset))
+                .collect(),
+        }
+    }
+
     /// Returns the shared memory resources that can be used to reconstruct the
     /// memory backing.
     ///
@@ -471,3 +491,99 @@ pub async fn set_ram_visibility(
         Ok(())
     }
 }
+
+/// A client to the [`GuestMemoryManager`] used to release the host memory
+/// backing VTL0 RAM, such as when the guest inflates a memory balloon.
+pub struct RamDiscard {
+    guest_ram: Mappable,
+    /// The VTL0 RAM ranges and their offsets within `guest_ram`.
+    regions: Vec<(MemoryRange, u64)>,
+}
+
+impl DiscardRam for RamDiscard {
+    fn is_supported(&self) -> bool {
+        // Shared memory can only be discarded on Linux.
+        cfg!(target_os = "linux")
+    }
+
+    fn discard_ram(&self, gpa: u64, len: u64) -> std::io::Result<()> {
+        for (offset, len) in backing_ranges(&self.regions, gpa, len)? {
+            sparse_mmap::discard_shared_memory(&self.guest_ram, offset, len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the `(offset, len)` ranges of the RAM backing for the guest
+/// physical address range `gpa..gpa + len`, failing if the range is not
+/// page-aligned or not entirely within `regions`.
+fn backing_ranges(
+    regions: &[(MemoryRange, u64)],
+    gpa: u64,
+    len: u64,
+) -> std::io::Result<Vec<(u64, u64)>> {
+    let range = gpa
+        .checked_add(len)
+        .and_then(|end| MemoryRange::try_new(gpa..end).ok())
+        .ok_or(std::io::ErrorKind::InvalidInput)?;
+    let backing = regions
+        .iter()
+        .filter(|(region, _)| region.overlaps(&range))
+        .map(|(region, offset)| {
+            let part = region.intersection(&range);
+            (offset + (part.start() - region.start()), part.len())
+        })
+        .collect::<Vec<_>>();
+    if backing.iter().map(|&(_, len)| len).sum::<u64>() != range.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{range} is not entirely VTL0 RAM"),
+        ));
+    }
+    Ok(backing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backing_ranges;
+    use memory_range::MemoryRange;
+
+    const MB: u64 = 0x100000;
+
+    #[test]
+    fn discard_ranges() {
+        // VTL0 RAM split around an MMIO gap, with VTL2 memory excluded.
+        let regions = [
+            (MemoryRange::new(0..2 * MB), 0),
+            (MemoryRange::new(4 * MB..6 * MB), 2 * MB),
+        ];
+
+        assert_eq!(
+            backing_ranges(&regions, MB, 0x2000).unwrap(),
+            [(MB, 0x2000)]
+        );
+        assert_eq!(
+            backing_ranges(&regions, 5 * MB, MB).unwrap(),
+            [(3 * MB, MB)]
+        );
+        assert!(backing_ranges(&regions, MB, 0).unwrap().is_empty());
+
+        // Ranges that are not entirely VTL0 RAM, including ranges in the gap
+        // and beyond the end of RAM, where VTL2 memory is.
+        for (gpa, len) in [
+            (MB, 2 * MB),
+            (2 * MB, 0x1000),
+            (3 * MB, 2 * MB),
+            (6 * MB - 0x1000, 0x2000),
+            (8 * MB, MB),
+        ] {
+            let err = backing_ranges(&regions, gpa, len).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{gpa:#x}");
+        }
+
+        // Unaligned and overflowing ranges.
+        for (gpa, len) in [(0x800, 0x1000), (0, 0x800), (u64::MAX - 0xfff, 0x2000)] {
+            assert!(backing_ranges(&regions, gpa, len).is_err(), "{gpa:#x}");
+        }
+    }
+}
@@ -21,6 +21,8 @@
 use anyhow::Context;
 use clap::Parser;
 use clap::ValueEnum;
+use disk_backend_resources::RateLimit;
+use disk_backend_resources::ThrottleLimits;
 use hvlite_defs::config::DeviceVtl;
 use hvlite_defs::config::Hypervisor;
 use hvlite_defs::config::PcatBootDevice;
@@ -28,6 +30,8 @@
 use hvlite_defs::config::Vtl2BaseAddressType;
 use hvlite_defs::config::X2ApicConfig;
 use hvlite_defs::config::DEFAULT_PCAT_BOOT_ORDER;
+use net_backend_resources::consomme::ConsommePortForward;
+use net_backend_resources::consomme::ConsommeProtocol;
 use std::ffi::OsString;
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -137,8 +141,20 @@ pub struct Options {
         <len>: length of ramdisk, e.g.: `1G`
     `memdiff:<disk>`               memory backed diff disk
         <disk>: lower disk, e.g.: `file:base.img`
+    `filediff:\<path\>=<disk>`       file backed diff disk
+        \<path\>: path to diff file, created if empty
+        <disk>: lower disk, e.g.: `file:base.img`
+    `throttle:<limits>:<disk>`     rate limited disk
+        <limits>: `+` separated `<limit>=<rate>[/<burst>]`, where
+            <limit> is `riops`, `wiops`, `rbps` or `wbps`,
+            e.g.: `riops=500/1000+wbps=10M`
+        <disk>: inner disk, e.g.: `file:disk.img`
     `file:\<path\>`                  file-backed disk
         \<path\>: path to file
+    `qcow2:\<path\>`                 qcow2 image, including its backing files
+        \<path\>: path to image
+    `vhdx:\<path\>`                  vhdx image, including its parents
+        \<path\>: path to image
 
 flags:
     `ro`                           open disk as read-only
@@ -160,8 +176,20 @@ pub struct Options {
         <len>: length of ramdisk, e.g.: `1G`
     `memdiff:<disk>`               memory backed diff disk
         <disk>: lower disk, e.g.: `file:base.img`
+    `filediff:\<path\>=<disk>`       file backed diff disk
+        \<path\>: path to diff file, created if empty
+        <disk>: lower disk, e.g.: `file:base.img`
+    `throttle:<limits>:<disk>`     rate limited disk
+        <limits>: `+` separated `<limit>=<rate>[/<burst>]`, where
+            <limit> is `riops`, `wiops`, `rbps` or `wbps`,
+            e.g.: `riops=500/1000+wbps=10M`
+        <disk>: inner disk, e.g.: `file:disk.img`
     `file:\<path\>`                  file-backed disk
         \<path\>: path to file
+    `qcow2:\<path\>`                 qcow2 image, including its backing files
+        \<path\>: path to image
+    `vhdx:\<path\>`                  vhdx image, including its parents
+        \<path\>: path to image
 
 flags:
     `ro`                           open disk as read-only
@@ -170,6 +198,14 @@ pub struct Options {
     #[clap(long)]
     pub nvme: Vec<DiskCli>,
 
+    /// let the guest create namespaces on the NVMe controller, backed by RAM
+    /// disks allocated from a pool of the given size
+    ///
+    /// Append `,max=<count>` to limit the number of namespaces (default 16), or
+    /// `,vtl2` to add the pool to the VTL2 controller, e.g. `4G,max=8`.
+    #[clap(long, value_name = "SIZE[,max=<count>][,vtl2]")]
+    pub nvme_namespace_pool: Option<NvmeNamespacePoolCli>,
+
     /// number of sub-channels for the SCSI controller
     #[clap(long, value_name = "COUNT", default_value = "0")]
     pub scsi_sub_channels: u16,
@@ -178,10 +214,26 @@ pub struct Options {
     #[clap(long)]
     pub nic: bool,
 
-    /// expose a virtual NIC with the given backend (consomme | dio | tap | none)
+    /// expose a virtual NIC with the given backend (consomme | dio | tap |
+    /// af_packet | switch | none)
+    ///
+    /// The consomme backend optionally takes an IPv4 CIDR and/or an IPv6 /64
+    /// prefix, separated by a comma, e.g. `consomme:10.1.0.0/24,fd00:1::/64`.
+    /// Host ports can be forwarded to the guest with additional
+    /// `tcp=[<host addr>:]<host port>:<guest port>` or `udp=...` options, e.g.
+    /// `consomme:tcp=127.0.0.1:2222:22` or `consomme:tcp=[::1]:2222:22`.
     ///
     /// Prefix with `uh:` to add this NIC via Mana emulation through Underhill,
-    /// or `vtl2:` to assign this NIC to VTL2.
+    /// or `vtl2:` to assign this NIC to VTL2. Prefix with `queues=<n>:` to
+    /// limit the number of queues; for the tap backend, this is also the number
+    /// of queues opened on the TAP device. Prefix with `capture:` to allow
+    /// capturing this NIC's packets with the interactive `capture` command.
+    ///
+    /// The af_packet backend takes the name of an existing host interface,
+    /// e.g. `af_packet:eth0`, and shares it with the guest without a bridge.
+    ///
+    /// The switch backend takes a directory, e.g. `switch:/tmp/vmswitch`; the
+    /// NICs of all VMs using the same directory share an Ethernet segment.
     #[clap(long)]
     pub net: Vec<NicConfigCli>,
 
@@ -233,6 +285,17 @@ pub struct Options {
     #[clap(long, conflicts_with("virtio_console"))]
     pub virtio_console_pci: bool,
 
+    /// expose the emulated PCI bus as a PCI Express root complex, with
+    /// configuration space accessible via ECAM (requires the generic PCI bus,
+    /// used when booting Linux directly without Hyper-V enlightenments)
+    #[clap(long, conflicts_with_all(&["hv", "uefi", "pcat", "igvm"]))]
+    pub pcie: bool,
+
+    /// number of hot-plug capable PCIe root ports to add to the root complex,
+    /// for use with the interactive console's `add-pcie`/`rm-pcie` commands
+    #[clap(long, value_name = "COUNT", requires("pcie"))]
+    pub pcie_root_ports: Option<u8>,
+
     /// COM1 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>] | none)
     #[clap(long, value_name = "SERIAL")]
     pub com1: Option<SerialConfigCli>,
@@ -335,11 +398,63 @@ pub struct Options {
     #[clap(long, value_name = "PATH")]
     pub virtio_pmem: Option<String>,
 
+    /// attach a virtio-blk disk (can be passed multiple times)
+    #[clap(long_help = r#"
+e.g: --virtio-blk file:/path/to/disk.img,queues=4
+
+syntax: <disk>[,flag,opt=arg,...]
+
+<disk> takes the same disk kinds as `--disk`, e.g. `file:disk.img`,
+`mem:1G` or `qcow2:disk.qcow2`.
+
+flags:
+    `ro`                           expose the disk to the guest as read-only
+    `queues=<n>`                   number of request queues (default 1)
+"#)]
+    #[clap(long, value_name = "FILE")]
+    pub virtio_blk: Vec<VirtioBlkCli>,
+
+    /// add virtio_blk devices under either the PCI or MMIO bus, or whatever the hypervisor supports (pci | mmio | auto)
+    #[clap(long, value_name = "BUS", default_value = "auto", value_parser = parse_virtio_bus_arg)]
+    pub virtio_blk_bus: VirtioBus,
+
+    /// add a virtio balloon device, whose target size is set with the
+    /// `balloon` interactive command
+    #[clap(long)]
+    pub virtio_balloon: bool,
+
+    /// add a virtio-vsock device that relays guest connections to the hybrid
+    /// vsock listener path
+    #[clap(long, value_name = "PATH")]
+    pub virtio_vsock_path: Option<String>,
+
+    /// the guest context ID for the virtio-vsock device
+    #[clap(
+        long,
+        value_name = "CID",
+        default_value = "3",
+        requires("virtio_vsock_path")
+    )]
+    pub virtio_vsock_cid: u64,
+
+    /// add a virtio entropy device
+    #[clap(long)]
+    pub virtio_rng: bool,
+
+    /// limit the virtio entropy device to this many bytes per second
+    #[clap(long, value_name = "BYTES", requires("virtio_rng"))]
+    pub virtio_rng_rate: Option<u64>,
+
+    /// add the virtio entropy device under either the PCI or MMIO bus, or whatever the hypervisor supports (pci | mmio | auto)
+    #[clap(long, value_name = "BUS", default_value = "auto", value_parser = parse_virtio_bus_arg)]
+    pub virtio_rng_bus: VirtioBus,
+
     /// expose a virtio network with the given backend (dio | vmnic | tap |
-    /// none)
+    /// af_packet | switch | none)
     ///
     /// Prefix with `uh:` to add this NIC via Mana emulation through Underhill,
-    /// or `vtl2:` to assign this NIC to VTL2.
+    /// or `vtl2:` to assign this NIC to VTL2. Prefix with `capture:` to allow
+    /// capturing this NIC's packets with the interactive `capture` command.
     #[clap(long)]
     pub virtio_net: Vec<NicConfigCli>,
 
@@ -455,8 +570,20 @@ pub struct Options {
         <len>: length of ramdisk, e.g.: `1G`
     `memdiff:<disk>`               memory backed diff disk
         <disk>: lower disk, e.g.: `file:base.img`
+    `filediff:\<path\>=<disk>`       file backed diff disk
+        \<path\>: path to diff file, created if empty
+        <disk>: lower disk, e.g.: `file:base.img`
+    `throttle:<limits>:<disk>`     rate limited disk
+        <limits>: `+` separated `<limit>=<rate>[/<burst>]`, where
+            <limit> is `riops`, `wiops`, `rbps` or `wbps`,
+            e.g.: `riops=500/1000+wbps=10M`
+        <disk>: inner disk, e.g.: `file:disk.img`
     `file:\<path\>`                  file-backed disk
         \<path\>: path to file
+    `qcow2:\<path\>`                 qcow2 image, including its backing files
+        \<path\>: path to image
+    `vhdx:\<path\>`                  vhdx image, including its parents
+        \<path\>: path to image
 
 flags:
     `ro`                           open disk as read-only
@@ -478,8 +605,20 @@ pub struct Options {
         <len>: length of ramdisk, e.g.: `1G`
     `memdiff:<disk>`               memory backed diff disk
         <disk>: lower disk, e.g.: `file:base.img`
+    `filediff:\<path\>=<disk>`       file backed diff disk
+        \<path\>: path to diff file, created if empty
+        <disk>: lower disk, e.g.: `file:base.img`
+    `throttle:<limits>:<disk>`     rate limited disk
+        <limits>: `+` separated `<limit>=<rate>[/<burst>]`, where
+            <limit> is `riops`, `wiops`, `rbps` or `wbps`,
+            e.g.: `riops=500/1000+wbps=10M`
+        <disk>: inner disk, e.g.: `file:disk.img`
     `file:\<path\>`                  file-backed disk
         \<path\>: path to file
+    `qcow2:\<path\>`                 qcow2 image, including its backing files
+        \<path\>: path to image
+    `vhdx:\<path\>`                  vhdx image, including its parents
+        \<path\>: path to image
 
 flags:
     `ro`                           open disk as read-only
@@ -503,6 +642,14 @@ pub struct Options {
     #[clap(long)]
     pub write_saved_state_proto: Option<PathBuf>,
 
+    /// commit the changes in a `filediff:` disk into its lower disk, then exit
+    #[clap(long, value_name = "DISK", conflicts_with("discard_disk_diff"))]
+    pub commit_disk_diff: Option<DiskCliKind>,
+
+    /// discard the changes in a `filediff:` disk file, then exit
+    #[clap(long, value_name = "PATH")]
+    pub discard_disk_diff: Option<PathBuf>,
+
     /// specify the IMC hive file for booting Windows
     #[clap(long)]
     pub imc: Option<PathBuf>,
@@ -541,7 +688,7 @@ pub enum SecureBootTemplateCli {
     UefiCa,
 }
 
-fn parse_memory(s: &str) -> anyhow::Result<u64> {
+pub(crate) fn parse_memory(s: &str) -> anyhow::Result<u64> {
     || -> Option<u64> {
         let mut b = s.as_bytes();
         if s.ends_with('B') {
@@ -574,18 +721,62 @@ fn parse_number(s: &str) -> Result<u64, std::num::ParseIntError> {
     }
 }
 
+/// Parses `+` separated `<limit>=<rate>[/<burst>]` throttle limits. The burst
+/// defaults to one second's worth of the rate.
+fn parse_throttle_limits(s: &str) -> anyhow::Result<ThrottleLimits> {
+    let mut limits = ThrottleLimits::default();
+    for limit in s.split('+') {
+        let (name, value) = limit
+            .split_once('=')
+            .with_context(|| format!("expected limit=rate in '{limit}'"))?;
+        let (rate, burst) = match value.split_once('/') {
+            Some((rate, burst)) => (parse_memory(rate)?, parse_memory(burst)?),
+            None => {
+                let rate = parse_memory(value)?;
+                (rate, rate)
+            }
+        };
+        let field = match name {
+            "riops" => &mut limits.read_iops,
+            "wiops" => &mut limits.write_iops,
+            "rbps" => &mut limits.read_bytes,
+            "wbps" => &mut limits.write_bytes,
+            _ => anyhow::bail!("unknown throttle limit {name}"),
+        };
+        *field = Some(RateLimit { rate, burst });
+    }
+    Ok(limits)
+}
+
 #[derive(Clone)]
 pub enum DiskCliKind {
     // mem:<len>
     Memory(u64),
     // memdiff:<kind>
     MemoryDiff(Box<DiskCliKind>),
+    // filediff:<path>=<kind>
+    FileDiff {
+        path: PathBuf,
+        lower: Box<DiskCliKind>,
+    },
     // prwrap:<kind>
     PersistentReservationsWrapper(Box<DiskCliKind>),
+    // throttle:<limits>:<kind>
+    Throttle {
+        limits: ThrottleLimits,
+        disk: Box<DiskCliKind>,
+    },
     // file:<path>
     File(PathBuf),
+    // qcow2:<path>
+    Qcow2(PathBuf),
+    // vhdx:<path>
+    Vhdx(PathBuf),
     // blob:<type>:<url>
-    Blob { kind: BlobKind, url: String },
+    Blob {
+        kind: BlobKind,
+        url: String,
+    },
 }
 
 #[derive(Copy, Clone)]
@@ -604,8 +795,24 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
             Some((kind, arg)) => match kind {
                 "mem" => DiskCliKind::Memory(parse_memory(arg)?),
                 "memdiff" => DiskCliKind::MemoryDiff(Box::new(arg.parse()?)),
+                "filediff" => {
+                    let (path, lower) = arg.split_once('=').context("expected path=disk")?;
+                    DiskCliKind::FileDiff {
+                        path: PathBuf::from(path),
+                        lower: Box::new(lower.parse()?),
+                    }
+                }
                 "prwrap" => DiskCliKind::PersistentReservationsWrapper(Box::new(arg.parse()?)),
+                "throttle" => {
+                    let (limits, disk) = arg.split_once(':').context("expected limits:disk")?;
+                    DiskCliKind::Throttle {
+                        limits: parse_throttle_limits(limits)?,
+                        disk: Box::new(disk.parse()?),
+                    }
+                }
                 "file" => DiskCliKind::File(PathBuf::from(arg)),
+                "qcow2" => DiskCliKind::Qcow2(PathBuf::from(arg)),
+                "vhdx" => DiskCliKind::Vhdx(PathBuf::from(arg)),
                 "blob" => {
                     let (blob_kind, url) = arg.split_once(':').context("expected kind:url")?;
                     let blob_kind = match blob_kind {
@@ -695,6 +902,51 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
     }
 }
 
+/// The default maximum number of guest-created NVMe namespaces.
+pub const DEFAULT_NVME_POOL_NAMESPACES: u32 = 16;
+
+// <size>[,max=<count>][,vtl2]
+#[derive(Clone)]
+pub struct NvmeNamespacePoolCli {
+    pub vtl: DeviceVtl,
+    pub capacity: u64,
+    pub max_namespaces: u32,
+}
+
+impl FromStr for NvmeNamespacePoolCli {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut opts = s.split(',');
+        let capacity = parse_memory(opts.next().unwrap())?;
+        if capacity == 0 {
+            anyhow::bail!("namespace pool capacity must be nonzero");
+        }
+
+        let mut max_namespaces = DEFAULT_NVME_POOL_NAMESPACES;
+        let mut vtl = DeviceVtl::Vtl0;
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("max", count)) => {
+                    max_namespaces = count
+                        .parse()
+                        .ok()
+                        .filter(|&count| count > 0)
+                        .with_context(|| format!("invalid namespace count '{count}'"))?;
+                }
+                None if opt == "vtl2" => vtl = DeviceVtl::Vtl2,
+                _ => anyhow::bail!("unknown option: '{opt}'"),
+            }
+        }
+
+        Ok(NvmeNamespacePoolCli {
+            vtl,
+            capacity,
+            max_namespaces,
+        })
+    }
+}
+
 // <kind>[,ro,s]
 #[derive(Clone)]
 pub struct IdeDiskCli {
@@ -771,6 +1023,44 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
     }
 }
 
+// <kind>[,ro][,queues=<n>]
+#[derive(Clone)]
+pub struct VirtioBlkCli {
+    pub kind: DiskCliKind,
+    pub read_only: bool,
+    pub max_queues: Option<u16>,
+}
+
+impl FromStr for VirtioBlkCli {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut opts = s.split(',');
+        let kind = opts.next().unwrap().parse()?;
+
+        let mut read_only = false;
+        let mut max_queues = None;
+        for opt in opts {
+            let mut s = opt.split('=');
+            let opt = s.next().unwrap();
+            match opt {
+                "ro" => read_only = true,
+                "queues" => {
+                    let n = s.next().context("missing queue count")?;
+                    max_queues = Some(n.parse().context("invalid queue count")?);
+                }
+                _ => anyhow::bail!("unknown option: '{opt}'"),
+            }
+        }
+
+        Ok(VirtioBlkCli {
+            kind,
+            read_only,
+            max_queues,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct DebugconSerialConfigCli {
     pub port: u16,
@@ -839,19 +1129,62 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
 #[derive(Clone)]
 pub enum EndpointConfigCli {
     None,
-    Consomme { cidr: Option<String> },
-    Dio { id: Option<String> },
-    Tap { name: String },
+    Consomme {
+        cidr: Option<String>,
+        ipv6_prefix: Option<String>,
+        port_forwards: Vec<ConsommePortForward>,
+    },
+    Dio {
+        id: Option<String>,
+    },
+    Tap {
+        name: String,
+    },
+    AfPacket {
+        interface: String,
+    },
+    Switch {
+        path: String,
+    },
 }
 
 impl FromStr for EndpointConfigCli {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The consomme options are a comma-separated IPv4 CIDR, IPv6 prefix,
+        // and port forwards, and the latter two contain colons.
+        if let Some(s) = s.strip_prefix("consomme:") {
+            let mut cidr = None;
+            let mut ipv6_prefix = None;
+            let mut port_forwards = Vec::new();
+            for opt in s.split(',').filter(|s| !s.is_empty()) {
+                if let Some((protocol, rule)) = opt.split_once('=') {
+                    port_forwards.push(parse_port_forward(protocol, rule)?);
+                    continue;
+                }
+                let slot = if opt.contains(':') {
+                    &mut ipv6_prefix
+                } else {
+                    &mut cidr
+                };
+                if slot.replace(opt.to_owned()).is_some() {
+                    return Err("invalid consomme configuration".into());
+                }
+            }
+            return Ok(EndpointConfigCli::Consomme {
+                cidr,
+                ipv6_prefix,
+                port_forwards,
+            });
+        }
+
         let ret = match s.split(':').collect::<Vec<_>>().as_slice() {
             ["none"] => EndpointConfigCli::None,
-            ["consomme", s @ ..] => EndpointConfigCli::Consomme {
-                cidr: s.first().map(|&s| s.to_owned()),
+            ["consomme"] => EndpointConfigCli::Consomme {
+                cidr: None,
+                ipv6_prefix: None,
+                port_forwards: Vec::new(),
             },
             ["dio", s @ ..] => EndpointConfigCli::Dio {
                 id: s.first().map(|s| (*s).to_owned()),
@@ -859,6 +1192,12 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
             ["tap", name] => EndpointConfigCli::Tap {
                 name: (*name).to_owned(),
             },
+            ["af_packet", interface] => EndpointConfigCli::AfPacket {
+                interface: (*interface).to_owned(),
+            },
+            ["switch", path] => EndpointConfigCli::Switch {
+                path: (*path).to_owned(),
+            },
             _ => return Err("invalid network backend".into()),
         };
 
@@ -866,12 +1205,50 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+/// Parses a consomme port forward of the form
+/// `[<host addr>:]<host port>:<guest port>`. IPv6 host addresses may be
+/// enclosed in brackets.
+fn parse_port_forward(protocol: &str, rule: &str) -> Result<ConsommePortForward, String> {
+    let protocol = match protocol {
+        "tcp" => ConsommeProtocol::Tcp,
+        "udp" => ConsommeProtocol::Udp,
+        _ => return Err(format!("invalid port forward protocol: {protocol}")),
+    };
+    let parse_port = |s: &str| {
+        s.parse::<u16>()
+            .map_err(|_| format!("invalid port forward port: {s}"))
+    };
+    // The host address may itself contain colons, so split from the end.
+    let (host_addr, host_port, guest_port) =
+        match rule.rsplitn(3, ':').collect::<Vec<_>>().as_slice() {
+            [guest_port, host_port] => (None, *host_port, *guest_port),
+            [guest_port, host_port, host_addr] => {
+                let host_addr = host_addr
+                    .strip_prefix('[')
+                    .and_then(|addr| addr.strip_suffix(']'))
+                    .unwrap_or(host_addr);
+                if host_addr.is_empty() {
+                    return Err(format!("invalid port forward: {rule}"));
+                }
+                (Some(host_addr.to_owned()), *host_port, *guest_port)
+            }
+            _ => return Err(format!("invalid port forward: {rule}")),
+        };
+    Ok(ConsommePortForward {
+        protocol,
+        host_addr,
+        host_port: parse_port(host_port)?,
+        guest_port: parse_port(guest_port)?,
+    })
+}
+
 #[derive(Clone)]
 pub struct NicConfigCli {
     pub vtl: DeviceVtl,
     pub endpoint: EndpointConfigCli,
     pub max_queues: Option<u16>,
     pub underhill: bool,
+    pub capture: bool,
 }
 
 impl FromStr for NicConfigCli {
@@ -881,6 +1258,7 @@ fn from_str(mut s: &str) -> Result<Self, Self::Err> {
         let mut vtl = DeviceVtl::Vtl0;
         let mut max_queues = None;
         let mut underhill = false;
+        let mut capture = false;
         while let Some((opt, rest)) = s.split_once(':') {
             if let Some((opt, val)) = opt.split_once('=') {
                 match opt {
@@ -895,6 +1273,7 @@ fn from_str(mut s: &str) -> Result<Self, Self::Err> {
                         vtl = DeviceVtl::Vtl2;
                     }
                     "uh" => underhill = true,
+                    "capture" => capture = true,
                     _ => break,
                 }
             }
@@ -911,6 +1290,7 @@ fn from_str(mut s: &str) -> Result<Self, Self::Err> {
             endpoint,
             max_queues,
             underhill,
+            capture,
         })
     }
 }
@@ -1101,3 +1481,69 @@ fn from(s: &std::ffi::OsStr) -> Self {
         OptionalPathBuf(if s.is_empty() { None } else { Some(s.into()) })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_port_forward;
+    use super::NvmeNamespacePoolCli;
+    use hvlite_defs::config::DeviceVtl;
+    use net_backend_resources::consomme::ConsommeProtocol;
+
+    #[test]
+    fn port_forward() {
+        let parse = |protocol, rule| {
+            let forward = parse_port_forward(protocol, rule)?;
+            Ok::<_, String>((
+                forward.protocol,
+                forward.host_addr,
+                forward.host_port,
+                forward.guest_port,
+            ))
+        };
+        assert_eq!(
+            parse("tcp", "2222:22").unwrap(),
+            (ConsommeProtocol::Tcp, None, 2222, 22)
+        );
+        assert_eq!(
+            parse("udp", "127.0.0.1:5353:53").unwrap(),
+            (ConsommeProtocol::Udp, Some("127.0.0.1".into()), 5353, 53)
+        );
+        assert_eq!(
+            parse("tcp", "[::1]:8080:80").unwrap(),
+            (ConsommeProtocol::Tcp, Some("::1".into()), 8080, 80)
+        );
+        assert_eq!(
+            parse("tcp", "fd00::1:8080:80").unwrap(),
+            (ConsommeProtocol::Tcp, Some("fd00::1".into()), 8080, 80)
+        );
+
+        for (protocol, rule) in [
+            ("icmp", "2222:22"),
+            ("tcp", "22"),
+            ("tcp", ":2222:22"),
+            ("tcp", "[]:2222:22"),
+            ("tcp", "[::1]:2222:70000"),
+            ("tcp", "[::1]:ssh:22"),
+        ] {
+            assert!(parse(protocol, rule).is_err(), "{protocol}={rule}");
+        }
+    }
+
+    #[test]
+    fn nvme_namespace_pool() {
+        let pool: NvmeNamespacePoolCli = "4G".parse().unwrap();
+        assert_eq!(
+            (pool.vtl, pool.capacity, pool.max_namespaces),
+            (DeviceVtl::Vtl0, 4 << 30, 16)
+        );
+        let pool: NvmeNamespacePoolCli = "512M,max=4,vtl2".parse().unwrap();
+        assert_eq!(
+            (pool.vtl, pool.capacity, pool.max_namespaces),
+            (DeviceVtl::Vtl2, 512 << 20, 4)
+        );
+
+        for s in ["", "0", "1G,max=0", "1G,max=x", "1G,ro"] {
+            assert!(s.parse::<NvmeNamespacePoolCli>().is_err(), "{s}");
+        }
+    }
+}
@@ -28,6 +28,7 @@
 use cli_args::NicConfigCli;
 use cli_args::SerialConfigCli;
 use cli_args::UefiConsoleModeCli;
+use disk_backend::resolve::ResolveDiskParameters;
 use floppy_resources::FloppyDiskConfig;
 use framebuffer::FramebufferAccess;
 use framebuffer::FRAMEBUFFER_SIZE;
@@ -48,6 +49,7 @@
 use hvlite_defs::config::LateMapVtl0MemoryPolicy;
 use hvlite_defs::config::LoadMode;
 use hvlite_defs::config::MemoryConfig;
+use hvlite_defs::config::PcieRootComplexConfig;
 use hvlite_defs::config::ProcessorTopologyConfig;
 use hvlite_defs::config::SerialInformation;
 use hvlite_defs::config::VirtioBus;
@@ -58,12 +60,16 @@
 use hvlite_defs::config::DEFAULT_MMIO_GAPS;
 use hvlite_defs::config::DEFAULT_MMIO_GAPS_WITH_VTL2;
 use hvlite_defs::config::DEFAULT_PCAT_BOOT_ORDER;
+use hvlite_defs::config::DEFAULT_PCIE_BUS_COUNT;
+use hvlite_defs::config::DEFAULT_PCIE_ROOT_PORT_COUNT;
 use hvlite_defs::rpc::PulseSaveRestoreError;
 use hvlite_defs::rpc::VmRpc;
 use hvlite_defs::worker::VmWorkerParameters;
 use hvlite_defs::worker::VM_WORKER;
 use hvlite_helpers::crash_dump::spawn_dump_handler;
 use hvlite_helpers::disk::open_disk_type;
+use hvlite_helpers::disk::open_qcow2;
+use hvlite_helpers::disk::open_vhdx;
 use input_core::MultiplexedInputHandle;
 use inspect::InspectMut;
 use inspect::InspectionBuilder;
@@ -78,6 +84,8 @@
 use mesh_worker::WorkerHandle;
 use meshworker::VmmMesh;
 use net_backend_resources::mac_address::MacAddress;
+use nvme_resources::NamespaceDefinition;
+use nvme_resources::NvmeControllerHandle;
 use pal_async::driver::Driver;
 use pal_async::pipe::PolledPipe;
 use pal_async::task::Spawn;
@@ -129,6 +137,8 @@
 use vmbus_serial_resources::VmbusSerialDeviceHandle;
 use vmbus_serial_resources::VmbusSerialPort;
 use vmcore::non_volatile_store::resources::EphemeralNonVolatileStoreHandle;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
 use vmgs_resources::VmgsFileHandle;
 use vmotherboard::ChipsetDeviceHandle;
 use vnc_worker_defs::VncParameters;
@@ -167,8 +177,15 @@ struct VmResources {
     console_in: Option<Box<dyn AsyncWrite + Send + Unpin>>,
     framebuffer_access: Option<FramebufferAccess>,
     shutdown_ic: Option<mesh::Sender<hyperv_ic_resources::shutdown::ShutdownRpc>>,
+    heartbeat_ic: Option<mesh::Sender<hyperv_ic_resources::heartbeat::HeartbeatRpc>>,
+    kvp_ic: Option<mesh::Sender<hyperv_ic_resources::kvp::KvpRpc>>,
+    balloon: Option<mesh::Sender<virtio_resources::balloon::BalloonRpc>>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     ged_rpc: Option<mesh::Sender<get_resources::ged::GuestEmulationRequest>>,
+    /// Packet capture control for each NIC, in NIC order, if enabled for the
+    /// NIC.
+    packet_capture:
+        Vec<Option<mesh::Sender<net_backend_resources::packet_capture::PacketCaptureRequest>>>,
     #[cfg(windows)]
     switch_ports: Vec<vmswitch::kernel::SwitchPort>,
 }
@@ -491,6 +508,15 @@ fn vm_config_from_command_line(
         )?;
     }
 
+    if let Some(cli_args::NvmeNamespacePoolCli {
+        vtl,
+        capacity,
+        max_namespaces,
+    }) = opt.nvme_namespace_pool
+    {
+        storage.set_nvme_namespace_pool(vtl, capacity, max_namespaces)?;
+    }
+
     let floppy_disks: Vec<_> = opt
         .floppy
         .iter()
@@ -539,9 +565,14 @@ fn vm_config_from_command_line(
         let nic_config = parse_endpoint(
             &NicConfigCli {
                 vtl: DeviceVtl::Vtl0,
-                endpoint: EndpointConfigCli::Consomme { cidr: None },
+                endpoint: EndpointConfigCli::Consomme {
+                    cidr: None,
+                    ipv6_prefix: None,
+                    port_forwards: Vec::new(),
+                },
                 max_queues: None,
                 underhill: false,
+                capture: false,
             },
             &mut nic_index,
             &mut resources,
@@ -1106,6 +1137,25 @@ fn vm_config_from_command_line(
             DeviceVtl::Vtl0,
             hyperv_ic_resources::shutdown::ShutdownIcHandle { recv }.into_resource(),
         ));
+
+        let (send, recv) = mesh::channel();
+        resources.heartbeat_ic = Some(send);
+        vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::heartbeat::HeartbeatIcHandle { recv }.into_resource(),
+        ));
+
+        let (send, recv) = mesh::channel();
+        resources.kvp_ic = Some(send);
+        vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::kvp::KvpIcHandle { recv }.into_resource(),
+        ));
+
+        vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
+        ));
     }
 
     if let Some(hive_path) = &opt.imc {
@@ -1178,8 +1228,60 @@ fn vm_config_from_command_line(
         ));
     }
 
+    for disk in &opt.virtio_blk {
+        let &cli_args::VirtioBlkCli {
+            ref kind,
+            read_only,
+            max_queues,
+        } = disk;
+        virtio_devices.push((
+            opt.virtio_blk_bus,
+            virtio_resources::blk::VirtioBlkHandle {
+                disk: disk_open(kind, read_only)?,
+                read_only,
+                max_queues,
+            }
+            .into_resource(),
+        ));
+    }
+
+    if opt.virtio_balloon {
+        let (send, recv) = mesh::channel();
+        resources.balloon = Some(send);
+        virtio_devices.push((
+            VirtioBus::Auto,
+            virtio_resources::balloon::VirtioBalloonHandle { recv }.into_resource(),
+        ));
+    }
+
+    if opt.virtio_rng {
+        virtio_devices.push((
+            opt.virtio_rng_bus,
+            virtio_resources::rng::VirtioRngHandle {
+                rate_limit: opt.virtio_rng_rate,
+            }
+            .into_resource(),
+        ));
+    }
+
+    if let Some(path) = &opt.virtio_vsock_path {
+        virtio_devices.push((
+            VirtioBus::Auto,
+            virtio_resources::vsock::VirtioVsockHandle {
+                guest_cid: opt.virtio_vsock_cid,
+                base_path: path.clone(),
+                listener: vsock_listener(Some(path))?.unwrap(),
+            }
+            .into_resource(),
+        ));
+    }
+
     let mut cfg = Config {
         chipset,
+        pcie_root_complex: opt.pcie.then_some(PcieRootComplexConfig {
+            bus_count: DEFAULT_PCIE_BUS_COUNT,
+            root_port_count: opt.pcie_root_ports.unwrap_or(DEFAULT_PCIE_ROOT_PORT_COUNT),
+        }),
         load_mode,
         floppy_disks,
         vpci_devices,
@@ -1303,11 +1405,17 @@ fn parse_endpoint(
     index: &mut usize,
     resources: &mut VmResources,
 ) -> anyhow::Result<NicConfig> {
-    let _ = resources;
     let endpoint = match &cli_cfg.endpoint {
-        EndpointConfigCli::Consomme { cidr } => {
-            net_backend_resources::consomme::ConsommeHandle { cidr: cidr.clone() }.into_resource()
+        EndpointConfigCli::Consomme {
+            cidr,
+            ipv6_prefix,
+            port_forwards,
+        } => net_backend_resources::consomme::ConsommeHandle {
+            cidr: cidr.clone(),
+            ipv6_prefix: ipv6_prefix.clone(),
+            port_forwards: port_forwards.clone(),
         }
+        .into_resource(),
         EndpointConfigCli::None => net_backend_resources::null::NullHandle.into_resource(),
         EndpointConfigCli::Dio { id } => {
             #[cfg(windows)]
@@ -1329,9 +1437,35 @@ fn parse_endpoint(
                 bail!("cannot use dio on non-windows platforms")
             }
         }
-        EndpointConfigCli::Tap { name } => {
-            net_backend_resources::tap::TapHandle { name: name.clone() }.into_resource()
+        EndpointConfigCli::Tap { name } => net_backend_resources::tap::TapHandle {
+            name: name.clone(),
+            queues: cli_cfg.max_queues.unwrap_or(1),
+        }
+        .into_resource(),
+        EndpointConfigCli::AfPacket { interface } => {
+            net_backend_resources::af_packet::AfPacketHandle {
+                interface: interface.clone(),
+            }
+            .into_resource()
+        }
+        EndpointConfigCli::Switch { path } => {
+            net_backend_resources::switch::SwitchHandle { path: path.clone() }.into_resource()
+        }
+    };
+
+    // Allow capturing the NIC's traffic from the console, if requested.
+    let endpoint = if cli_cfg.capture {
+        let (capture_send, capture_recv) = mesh::channel();
+        resources.packet_capture.push(Some(capture_send));
+        net_backend_resources::packet_capture::PacketCaptureHandle {
+            name: format!("nic{index}"),
+            endpoint,
+            control: capture_recv,
         }
+        .into_resource()
+    } else {
+        resources.packet_capture.push(None);
+        endpoint
     };
 
     // Pick a random MAC address.
@@ -1384,6 +1518,10 @@ fn disk_open(disk_cli: &DiskCliKind, read_only: bool) -> anyhow::Result<Resource
         &DiskCliKind::Memory(len) => Resource::new(disk_backend_resources::RamDiskHandle { len }),
         DiskCliKind::File(path) => open_disk_type(path, read_only)
             .with_context(|| format!("failed to open {}", path.display()))?,
+        DiskCliKind::Qcow2(path) => open_qcow2(path, read_only)
+            .with_context(|| format!("failed to open {}", path.display()))?,
+        DiskCliKind::Vhdx(path) => open_vhdx(path, read_only)
+            .with_context(|| format!("failed to open {}", path.display()))?,
         DiskCliKind::Blob { kind, url } => Resource::new(disk_backend_resources::BlobDiskHandle {
             url: url.to_owned(),
             format: match kind {
@@ -1396,14 +1534,59 @@ fn disk_open(disk_cli: &DiskCliKind, read_only: bool) -> anyhow::Result<Resource
                 lower: disk_open(inner, true)?,
             })
         }
+        DiskCliKind::FileDiff { path, lower } => {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .create(!read_only)
+                .truncate(false)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            Resource::new(disk_backend_resources::FileDiffDiskHandle {
+                file,
+                lower: disk_open(lower, true)?,
+            })
+        }
         DiskCliKind::PersistentReservationsWrapper(inner) => Resource::new(
             disk_backend_resources::DiskWithReservationsHandle(disk_open(inner, read_only)?),
         ),
+        DiskCliKind::Throttle { limits, disk } => {
+            Resource::new(disk_backend_resources::ThrottleDiskHandle {
+                disk: disk_open(disk, read_only)?,
+                limits: limits.clone(),
+            })
+        }
     };
 
     Ok(disk_type)
 }
 
+/// Commits the changes in a file-backed diff disk into its lower disk.
+async fn commit_disk_diff(driver: &DefaultDriver, disk_cli: &DiskCliKind) -> anyhow::Result<()> {
+    let DiskCliKind::FileDiff { path, lower } = disk_cli else {
+        anyhow::bail!("only filediff disks can be committed");
+    };
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let lower = vm_resource::ResourceResolver::new()
+        .resolve(
+            disk_open(lower, false)?,
+            ResolveDiskParameters {
+                read_only: false,
+                driver_source: &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+            },
+        )
+        .await
+        .context("failed to open lower disk")?;
+    let disk = disk_filediff::FileDiffDisk::open(file, lower.0, false)
+        .with_context(|| format!("failed to open diff disk {}", path.display()))?;
+    disk.commit().await.context("failed to commit diff disk")?;
+    Ok(())
+}
+
 fn do_main() -> anyhow::Result<()> {
     #[cfg(windows)]
     pal::windows::disable_hard_error_dialog();
@@ -1423,6 +1606,21 @@ fn do_main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if let Some(disk) = &opt.commit_disk_diff {
+        return DefaultPool::run_with(
+            |driver| async move { commit_disk_diff(&driver, disk).await },
+        );
+    }
+
+    if let Some(path) = &opt.discard_disk_diff {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        return disk_filediff::discard(&file).context("failed to discard diff disk");
+    }
+
     if let Some(path) = opt.relay_console_path {
         return console::relay_console(&path);
     }
@@ -1547,6 +1745,73 @@ enum InteractiveCommand {
         lun: u8,
     },
 
+    /// Hot add an NVMe controller to the slot of a PCIe root port.
+    AddPcie {
+        /// The index of the root port.
+        port: u8,
+        #[clap(long = "ro")]
+        read_only: bool,
+        /// Back the controller's namespace with a RAM disk of this size.
+        #[clap(long, value_parser = cli_args::parse_memory)]
+        ram: Option<u64>,
+        /// Let the guest create namespaces, backed by RAM disks, from a pool
+        /// of this size.
+        #[clap(long, value_parser = cli_args::parse_memory)]
+        namespace_pool: Option<u64>,
+        file_path: Option<PathBuf>,
+    },
+
+    /// Hot remove the device in the slot of a PCIe root port.
+    ///
+    /// This is a surprise removal: the device is removed immediately, without
+    /// waiting for the guest to release it.
+    RmPcie {
+        /// The index of the root port.
+        port: u8,
+    },
+
+    /// Set the virtio balloon's target size, or show its status.
+    Balloon {
+        /// The amount of guest memory to reclaim, such as `512M`.
+        #[clap(value_parser = cli_args::parse_memory)]
+        size: Option<u64>,
+    },
+
+    /// Start capturing a NIC's packets to a pcapng file, or stop capturing if
+    /// no file is given.
+    ///
+    /// The NIC must have been added with the `capture:` option.
+    Capture {
+        /// The index of the NIC, in command line order.
+        nic: usize,
+        /// The file to write.
+        file: Option<PathBuf>,
+        /// Only capture packets matching this BPF-style expression, such as
+        /// `tcp port 22 or arp`.
+        #[clap(short, long, requires("file"))]
+        filter: Option<String>,
+        /// The maximum number of bytes of each packet to capture.
+        #[clap(short, long, default_value_t = 65535, requires("file"))]
+        snaplen: u32,
+        /// The maximum size of each file, such as `100M`.
+        #[clap(long, value_parser = cli_args::parse_memory, requires("file"))]
+        max_file_size: Option<u64>,
+        /// Rotate through this many files once a file reaches
+        /// `--max-file-size`, deleting the oldest.
+        #[clap(long, default_value_t = 1, requires("max_file_size"))]
+        max_files: u32,
+    },
+
+    /// Show the guest's liveness, as reported by the heartbeat IC.
+    Heartbeat,
+
+    /// Get, set, or enumerate values in the guest's KVP (key-value pair)
+    /// pools.
+    Kvp {
+        #[clap(subcommand)]
+        command: KvpCommand,
+    },
+
     /// Inspect program state.
     #[clap(visible_alias = "x")]
     Inspect {
@@ -1661,6 +1926,69 @@ enum InteractiveCommand {
     Panic,
 }
 
+#[derive(clap::Subcommand)]
+enum KvpCommand {
+    /// Set a key's value.
+    Set {
+        /// The pool to modify.
+        #[clap(long, value_enum, default_value_t = KvpPoolCli::External)]
+        pool: KvpPoolCli,
+        /// The type of the value.
+        #[clap(long = "type", value_enum, default_value_t = KvpValueTypeCli::String)]
+        value_type: KvpValueTypeCli,
+        key: String,
+        value: String,
+    },
+    /// Get a key's value.
+    Get {
+        /// The pool to read.
+        #[clap(long, value_enum, default_value_t = KvpPoolCli::Auto)]
+        pool: KvpPoolCli,
+        key: String,
+    },
+    /// Delete a key.
+    Delete {
+        /// The pool to modify.
+        #[clap(long, value_enum, default_value_t = KvpPoolCli::External)]
+        pool: KvpPoolCli,
+        key: String,
+    },
+    /// List the keys and values in a pool.
+    Enum {
+        /// The pool to read.
+        #[clap(long, value_enum, default_value_t = KvpPoolCli::Auto)]
+        pool: KvpPoolCli,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum KvpPoolCli {
+    External,
+    Guest,
+    Auto,
+    AutoExternal,
+    AutoInternal,
+}
+
+impl From<KvpPoolCli> for hyperv_ic_resources::kvp::KvpPool {
+    fn from(pool: KvpPoolCli) -> Self {
+        match pool {
+            KvpPoolCli::External => Self::External,
+            KvpPoolCli::Guest => Self::Guest,
+            KvpPoolCli::Auto => Self::Auto,
+            KvpPoolCli::AutoExternal => Self::AutoExternal,
+            KvpPoolCli::AutoInternal => Self::AutoInternal,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum KvpValueTypeCli {
+    String,
+    U32,
+    U64,
+}
+
 struct CommandParser {
     app: clap::Command,
 }
@@ -2361,6 +2689,240 @@ fn state_change<U: 'static + Send>(
                     tracing::error!(error = error.as_error(), "error removing disk")
                 }
             }
+            InteractiveCommand::AddPcie {
+                port,
+                read_only,
+                ram,
+                namespace_pool,
+                file_path,
+            } => {
+                let action = async {
+                    let disk = match ram {
+                        None => {
+                            let path = file_path.context("no filename passed")?;
+                            open_disk_type(path.as_ref(), read_only)
+                                .with_context(|| format!("failed to open {}", path.display()))?
+                        }
+                        Some(size) => {
+                            Resource::new(disk_backend_resources::RamDiskHandle { len: size })
+                        }
+                    };
+
+                    let device = NvmeControllerHandle {
+                        subsystem_id: Guid::new_random(),
+                        namespaces: vec![NamespaceDefinition {
+                            nsid: 1,
+                            read_only,
+                            disk,
+                        }],
+                        max_io_queues: 64,
+                        msix_count: 64,
+                        namespace_pool: namespace_pool.map(|capacity| {
+                            storage_builder::ram_namespace_pool(
+                                capacity,
+                                cli_args::DEFAULT_NVME_POOL_NAMESPACES,
+                            )
+                        }),
+                    }
+                    .into_resource();
+
+                    vm_rpc
+                        .call_failable(VmRpc::AddPcieDevice, (port, device))
+                        .await?;
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "error adding pcie device")
+                }
+            }
+            InteractiveCommand::RmPcie { port } => {
+                let action = async {
+                    vm_rpc.call_failable(VmRpc::RemovePcieDevice, port).await?;
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "error removing pcie device")
+                }
+            }
+            InteractiveCommand::Balloon { size } => {
+                let action = async {
+                    let balloon = resources.balloon.as_ref().context("no virtio balloon")?;
+                    if let Some(size) = size {
+                        balloon
+                            .call(virtio_resources::balloon::BalloonRpc::SetTarget, size)
+                            .await?;
+                    } else {
+                        let status = balloon
+                            .call(virtio_resources::balloon::BalloonRpc::Query, ())
+                            .await?;
+                        println!("target: {} bytes", status.target);
+                        println!("actual: {} bytes", status.actual);
+                        let stats = &status.stats;
+                        for (name, value) in [
+                            ("swap in", stats.swap_in),
+                            ("swap out", stats.swap_out),
+                            ("major faults", stats.major_faults),
+                            ("minor faults", stats.minor_faults),
+                            ("free memory", stats.free_memory),
+                            ("total memory", stats.total_memory),
+                            ("available memory", stats.available_memory),
+                            ("disk caches", stats.disk_caches),
+                        ] {
+                            if let Some(value) = value {
+                                println!("{name}: {value}");
+                            }
+                        }
+                    }
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "error updating balloon")
+                }
+            }
+            InteractiveCommand::Capture {
+                nic,
+                file,
+                filter,
+                snaplen,
+                max_file_size,
+                max_files,
+            } => {
+                let action = async {
+                    let control = resources
+                        .packet_capture
+                        .get(nic)
+                        .with_context(|| format!("no nic {nic}"))?
+                        .as_ref()
+                        .with_context(|| {
+                            format!(
+                                "nic {nic} does not allow capture; add `capture:` to its options"
+                            )
+                        })?;
+                    if let Some(file) = file {
+                        control
+                            .call_failable(
+                                net_backend_resources::packet_capture::PacketCaptureRequest::Start,
+                                net_backend_resources::packet_capture::PacketCaptureConfig {
+                                    path: file.to_string_lossy().into_owned(),
+                                    snaplen,
+                                    filter,
+                                    max_file_size,
+                                    max_files,
+                                },
+                            )
+                            .await?;
+                    } else {
+                        control
+                            .call_failable(
+                                net_backend_resources::packet_capture::PacketCaptureRequest::Stop,
+                                (),
+                            )
+                            .await?;
+                    }
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "error controlling packet capture")
+                }
+            }
+            InteractiveCommand::Heartbeat => {
+                let action = async {
+                    use hyperv_ic_resources::heartbeat;
+
+                    let heartbeat_ic =
+                        resources.heartbeat_ic.as_ref().context("no heartbeat ic")?;
+                    let status = heartbeat_ic
+                        .call(heartbeat::HeartbeatRpc::Query, ())
+                        .await?;
+                    print!(
+                        "{:?}, sequence number {}",
+                        status.state, status.sequence_number
+                    );
+                    match status.last_heartbeat_age {
+                        Some(age) => println!(", last heartbeat {age:?} ago"),
+                        None => println!(),
+                    }
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "heartbeat error")
+                }
+            }
+            InteractiveCommand::Kvp { command } => {
+                let action = async {
+                    use hyperv_ic_resources::kvp;
+
+                    let kvp_ic = resources.kvp_ic.as_ref().context("no kvp ic")?;
+                    match command {
+                        KvpCommand::Set {
+                            pool,
+                            value_type,
+                            key,
+                            value,
+                        } => {
+                            let value = match value_type {
+                                KvpValueTypeCli::String => kvp::Value::String(value),
+                                KvpValueTypeCli::U32 => kvp::Value::U32(value.parse()?),
+                                KvpValueTypeCli::U64 => kvp::Value::U64(value.parse()?),
+                            };
+                            kvp_ic
+                                .call_failable(
+                                    kvp::KvpRpc::Set,
+                                    kvp::SetParams {
+                                        pool: pool.into(),
+                                        key,
+                                        value,
+                                    },
+                                )
+                                .await?;
+                        }
+                        KvpCommand::Get { pool, key } => {
+                            let value = kvp_ic
+                                .call_failable(
+                                    kvp::KvpRpc::Get,
+                                    kvp::GetParams {
+                                        pool: pool.into(),
+                                        key,
+                                    },
+                                )
+                                .await?;
+                            println!("{value}");
+                        }
+                        KvpCommand::Delete { pool, key } => {
+                            kvp_ic
+                                .call_failable(
+                                    kvp::KvpRpc::Delete,
+                                    kvp::DeleteParams {
+                                        pool: pool.into(),
+                                        key,
+                                    },
+                                )
+                                .await?;
+                        }
+                        KvpCommand::Enum { pool } => {
+                            let entries = kvp_ic
+                                .call_failable(
+                                    kvp::KvpRpc::Enumerate,
+                                    kvp::EnumerateParams { pool: pool.into() },
+                                )
+                                .await?;
+                            for kvp::KeyValue { key, value } in entries {
+                                println!("{key}: {value}");
+                            }
+                        }
+                    }
+                    anyhow::Ok(())
+                };
+
+                if let Err(error) = action.await {
+                    tracing::error!(error = error.as_error(), "kvp error")
+                }
+            }
             InteractiveCommand::Inspect {
                 recursive,
                 limit,
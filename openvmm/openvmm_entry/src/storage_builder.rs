@@ -17,7 +17,9 @@
 use ide_resources::IdeDeviceConfig;
 use ide_resources::IdePath;
 use nvme_resources::NamespaceDefinition;
+use nvme_resources::NamespacePoolDefinition;
 use nvme_resources::NvmeControllerHandle;
+use nvme_resources::RamNamespaceDiskTemplateHandle;
 use scsidisk_resources::SimpleScsiDiskHandle;
 use scsidisk_resources::SimpleScsiDvdHandle;
 use storvsp_resources::ScsiControllerHandle;
@@ -34,6 +36,8 @@ pub(super) struct StorageBuilder {
     vtl2_scsi_devices: Vec<ScsiDeviceAndPath>,
     vtl0_nvme_namespaces: Vec<NamespaceDefinition>,
     vtl2_nvme_namespaces: Vec<NamespaceDefinition>,
+    vtl0_nvme_pool: Option<NamespacePoolDefinition>,
+    vtl2_nvme_pool: Option<NamespacePoolDefinition>,
     underhill_scsi_luns: Vec<Lun>,
     underhill_nvme_luns: Vec<Lun>,
     openhcl_vtl: Option<DeviceVtl>,
@@ -66,6 +70,16 @@ fn from(value: UnderhillDiskSource) -> Self {
 const UNDERHILL_VTL0_NVME_INSTANCE: Guid =
     Guid::from_static_str("09a59b81-2bf6-4164-81d7-3a0dc977ba65");
 
+/// Returns a pool of `capacity` bytes from which the guest can create NVMe
+/// namespaces backed by RAM disks.
+pub fn ram_namespace_pool(capacity: u64, max_namespaces: u32) -> NamespacePoolDefinition {
+    NamespacePoolDefinition {
+        capacity,
+        max_namespaces,
+        disk_template: RamNamespaceDiskTemplateHandle.into_resource(),
+    }
+}
+
 impl StorageBuilder {
     pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
         Self {
@@ -74,6 +88,8 @@ pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
             vtl2_scsi_devices: Vec::new(),
             vtl0_nvme_namespaces: Vec::new(),
             vtl2_nvme_namespaces: Vec::new(),
+            vtl0_nvme_pool: None,
+            vtl2_nvme_pool: None,
             underhill_scsi_luns: Vec::new(),
             underhill_nvme_luns: Vec::new(),
             openhcl_vtl,
@@ -81,7 +97,26 @@ pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
     }
 
     pub fn has_vtl0_nvme(&self) -> bool {
-        !self.vtl0_nvme_namespaces.is_empty() || !self.underhill_nvme_luns.is_empty()
+        !self.vtl0_nvme_namespaces.is_empty()
+            || self.vtl0_nvme_pool.is_some()
+            || !self.underhill_nvme_luns.is_empty()
+    }
+
+    /// Lets the guest create namespaces on the NVMe controller for `vtl`,
+    /// backed by RAM disks allocated from a pool of `capacity` bytes.
+    pub fn set_nvme_namespace_pool(
+        &mut self,
+        vtl: DeviceVtl,
+        capacity: u64,
+        max_namespaces: u32,
+    ) -> anyhow::Result<()> {
+        let pool = match vtl {
+            DeviceVtl::Vtl0 => &mut self.vtl0_nvme_pool,
+            DeviceVtl::Vtl1 => anyhow::bail!("vtl1 unsupported"),
+            DeviceVtl::Vtl2 => &mut self.vtl2_nvme_pool,
+        };
+        *pool = Some(ram_namespace_pool(capacity, max_namespaces));
+        Ok(())
     }
 
     pub fn add(
@@ -330,7 +365,7 @@ pub fn build_config(
             ));
         }
 
-        if !self.vtl0_nvme_namespaces.is_empty() {
+        if !self.vtl0_nvme_namespaces.is_empty() || self.vtl0_nvme_pool.is_some() {
             config.vpci_devices.push(VpciDeviceConfig {
                 vtl: DeviceVtl::Vtl0,
                 instance_id: NVME_VTL0_INSTANCE_ID,
@@ -339,6 +374,7 @@ pub fn build_config(
                     namespaces: std::mem::take(&mut self.vtl0_nvme_namespaces),
                     max_io_queues: 64,
                     msix_count: 64,
+                    namespace_pool: self.vtl0_nvme_pool.take(),
                 }
                 .into_resource(),
             });
@@ -354,7 +390,7 @@ pub fn build_config(
             }
         }
 
-        if !self.vtl2_nvme_namespaces.is_empty() {
+        if !self.vtl2_nvme_namespaces.is_empty() || self.vtl2_nvme_pool.is_some() {
             if config
                 .hypervisor
                 .with_vtl2
@@ -371,6 +407,7 @@ pub fn build_config(
                     namespaces: std::mem::take(&mut self.vtl2_nvme_namespaces),
                     max_io_queues: 64,
                     msix_count: 64,
+                    namespace_pool: self.vtl2_nvme_pool.take(),
                 }
                 .into_resource(),
             });
@@ -4,7 +4,9 @@
 //! Worker for the prototype gRPC/ttrpc management endpoint.
 
 use self::vmservice::nic_config::Backend;
+use crate::cli_args::DEFAULT_NVME_POOL_NAMESPACES;
 use crate::serial_io::bind_serial;
+use crate::storage_builder::ram_namespace_pool;
 use crate::DEFAULT_MMIO_GAPS;
 use anyhow::anyhow;
 use anyhow::bail;
@@ -18,14 +20,19 @@
 use hvlite_defs::config::HypervisorConfig;
 use hvlite_defs::config::LoadMode;
 use hvlite_defs::config::MemoryConfig;
+use hvlite_defs::config::PcieRootComplexConfig;
 use hvlite_defs::config::ProcessorTopologyConfig;
 use hvlite_defs::config::VirtioBus;
 use hvlite_defs::config::VmbusConfig;
+use hvlite_defs::config::DEFAULT_PCIE_BUS_COUNT;
 use hvlite_defs::rpc::VmRpc;
 use hvlite_defs::worker::VmWorkerParameters;
 use hvlite_defs::worker::VM_WORKER;
 use hvlite_helpers::disk::open_disk_type;
 use hvlite_ttrpc_vmservice as vmservice;
+use hyperv_ic_resources::heartbeat::HeartbeatRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatState;
+use hyperv_ic_resources::kvp::KvpRpc;
 use inspect::Inspect;
 use inspect::InspectionBuilder;
 use inspect_proto::InspectResponse2;
@@ -43,6 +50,8 @@
 use mesh_worker::WorkerId;
 use mesh_worker::WorkerRpc;
 use netvsp_resources::NetvspHandle;
+use nvme_resources::NamespaceDefinition;
+use nvme_resources::NvmeControllerHandle;
 use pal_async::task::Spawn;
 use pal_async::DefaultDriver;
 use pal_async::DefaultPool;
@@ -56,7 +65,9 @@
 use storvsp_resources::ScsiControllerRequest;
 use storvsp_resources::ScsiDeviceAndPath;
 use unix_socket::UnixListener;
+use virtio_resources::balloon::BalloonRpc;
 use vm_manifest_builder::VmManifestBuilder;
+use vm_resource::kind::PciDeviceHandleKind;
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_resource::IntoResource;
 use vm_resource::Resource;
@@ -260,6 +271,11 @@ fn start_rpc<F, R>(
 struct Vm {
     worker_rpc: mesh::Sender<VmRpc>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
+    balloon_rpc: Option<mesh::Sender<BalloonRpc>>,
+    heartbeat_rpc: mesh::Sender<HeartbeatRpc>,
+    kvp_rpc: mesh::Sender<KvpRpc>,
+    /// The configured guest memory size, in bytes.
+    mem_size: u64,
     notify_recv: Mutex<Option<mesh::Receiver<HaltReason>>>,
 }
 
@@ -336,8 +352,32 @@ async fn handle(&mut self, ctx: mesh::CancelContext, request: vmservice::Vm) ->
                         self.start_rpc(response, r);
                     }
 
-                    r @ vmservice::Vm::CapabilitiesVm(_, _)
-                    | r @ vmservice::Vm::PropertiesVm(_, _) => {
+                    vmservice::Vm::PropertiesVm(request, response) => {
+                        let r = self.properties_vm(&vm, request);
+                        self.start_rpc(response, r);
+                    }
+
+                    vmservice::Vm::KvpSet(request, response) => {
+                        let r = self.kvp_set(&vm, request);
+                        self.start_rpc(response, r);
+                    }
+
+                    vmservice::Vm::KvpGet(request, response) => {
+                        let r = self.kvp_get(&vm, request);
+                        self.start_rpc(response, r);
+                    }
+
+                    vmservice::Vm::KvpDelete(request, response) => {
+                        let r = self.kvp_delete(&vm, request);
+                        self.start_rpc(response, r);
+                    }
+
+                    vmservice::Vm::KvpEnumerate(request, response) => {
+                        let r = self.kvp_enumerate(&vm, request);
+                        self.start_rpc(response, r);
+                    }
+
+                    r @ vmservice::Vm::CapabilitiesVm(_, _) => {
                         r.fail(grpc_error(anyhow!("not supported")))
                     }
 
@@ -451,8 +491,22 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             })?);
         }
 
+        // Root ports live on the generic PCI bus, which the Hyper-V chipset
+        // does not have.
+        let pcie_root_port_count: u8 = req_config
+            .devices_config
+            .as_ref()
+            .map_or(0, |c| c.pcie_root_port_count)
+            .try_into()
+            .ok()
+            .context("pcie root port count out of range")?;
+
         let chipset = VmManifestBuilder::new(
-            vm_manifest_builder::BaseChipsetType::HyperVGen2LinuxDirect,
+            if pcie_root_port_count > 0 {
+                vm_manifest_builder::BaseChipsetType::UnenlightenedLinuxDirect
+            } else {
+                vm_manifest_builder::BaseChipsetType::HyperVGen2LinuxDirect
+            },
             vm_manifest_builder::MachineArch::X86_64,
         )
         .with_serial(ports)
@@ -477,6 +531,10 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
                 prefetch_memory: false,
             },
             chipset: chipset.chipset,
+            pcie_root_complex: (pcie_root_port_count > 0).then_some(PcieRootComplexConfig {
+                bus_count: DEFAULT_PCIE_BUS_COUNT,
+                root_port_count: pcie_root_port_count,
+            }),
             processor_topology: ProcessorTopologyConfig {
                 proc_count: req_config
                     .processor_config
@@ -514,6 +572,41 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             generation_id_recv: None,
         };
 
+        // Reclaim memory from overcommitted VMs with a balloon, whose size is
+        // set by modifying the memory resource.
+        let mut balloon_rpc = None;
+        if req_config
+            .memory_config
+            .as_ref()
+            .is_some_and(|c| c.allow_overcommit)
+        {
+            let (send, recv) = mesh::channel();
+            config.virtio_devices.push((
+                VirtioBus::Auto,
+                virtio_resources::balloon::VirtioBalloonHandle { recv }.into_resource(),
+            ));
+            balloon_rpc = Some(send);
+        }
+
+        // Track guest liveness for PropertiesVM.
+        let (heartbeat_rpc, recv) = mesh::channel();
+        config.vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::heartbeat::HeartbeatIcHandle { recv }.into_resource(),
+        ));
+
+        let (kvp_rpc, recv) = mesh::channel();
+        config.vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::kvp::KvpIcHandle { recv }.into_resource(),
+        ));
+
+        // Keep the guest's clock in sync across pause and resume.
+        config.vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
+        ));
+
         let mut scsi_rpc = None;
         if let Some(devices_config) = req_config.devices_config {
             if !devices_config.scsi_disks.is_empty() {
@@ -562,6 +655,7 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             config.vmbus.as_mut().unwrap().vsock_path = Some(hvsocket_config.path);
         }
 
+        let mem_size = config.memory.mem_size;
         let (send, recv) = mesh::channel();
         let (notify_send, notify_recv) = mesh::channel();
 
@@ -586,6 +680,10 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
         self.worker_handle = Some(worker);
         self.vm = Some(Arc::new(Vm {
             scsi_rpc,
+            balloon_rpc,
+            heartbeat_rpc,
+            kvp_rpc,
+            mem_size,
             notify_recv: Mutex::new(Some(notify_recv)),
             worker_rpc: send,
         }));
@@ -637,6 +735,117 @@ fn wait_vm(
         })
     }
 
+    fn properties_vm(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::PropertiesVmRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<vmservice::PropertiesVmResponse>>> {
+        use vmservice::properties_vm_request::PropertiesType;
+
+        let mut heartbeat = None;
+        for &ty in &request.types {
+            if ty == PropertiesType::Heartbeat as i32 {
+                heartbeat = Some(vm.heartbeat_rpc.call(HeartbeatRpc::Query, ()));
+            } else {
+                anyhow::bail!("unsupported property type {ty}");
+            }
+        }
+        Ok(async move {
+            let heartbeat_stats = if let Some(recv) = heartbeat {
+                use vmservice::heartbeat_stats::State;
+
+                let status = recv.await?;
+                let state = match status.state {
+                    HeartbeatState::NoContact => State::NoContact,
+                    HeartbeatState::Healthy => State::Healthy,
+                    HeartbeatState::Critical => State::Critical,
+                    HeartbeatState::Stopped => State::Stopped,
+                    HeartbeatState::LostCommunication => State::LostCommunication,
+                };
+                Some(vmservice::HeartbeatStats {
+                    state: state as i32,
+                    sequence_number: status.sequence_number,
+                    last_heartbeat_age_ms: status
+                        .last_heartbeat_age
+                        .map_or(0, |age| age.as_millis() as u64),
+                })
+            } else {
+                None
+            };
+            Ok(vmservice::PropertiesVmResponse {
+                heartbeat_stats,
+                ..Default::default()
+            })
+        })
+    }
+
+    fn kvp_set(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::KvpSetRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>> {
+        let params = hyperv_ic_resources::kvp::SetParams {
+            pool: kvp_pool(request.pool)?,
+            key: request.key,
+            value: kvp_value_from_proto(request.value)?,
+        };
+        let recv = vm.kvp_rpc.call_failable(KvpRpc::Set, params);
+        Ok(async move { recv.await.map_err(anyhow::Error::from) })
+    }
+
+    fn kvp_get(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::KvpGetRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<vmservice::KvpGetResponse>>> {
+        let params = hyperv_ic_resources::kvp::GetParams {
+            pool: kvp_pool(request.pool)?,
+            key: request.key,
+        };
+        let recv = vm.kvp_rpc.call_failable(KvpRpc::Get, params);
+        Ok(async move {
+            let value = recv.await?;
+            Ok(vmservice::KvpGetResponse {
+                value: Some(kvp_value_to_proto(value)),
+            })
+        })
+    }
+
+    fn kvp_delete(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::KvpDeleteRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>> {
+        let params = hyperv_ic_resources::kvp::DeleteParams {
+            pool: kvp_pool(request.pool)?,
+            key: request.key,
+        };
+        let recv = vm.kvp_rpc.call_failable(KvpRpc::Delete, params);
+        Ok(async move { recv.await.map_err(anyhow::Error::from) })
+    }
+
+    fn kvp_enumerate(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::KvpEnumerateRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<vmservice::KvpEnumerateResponse>>> {
+        let params = hyperv_ic_resources::kvp::EnumerateParams {
+            pool: kvp_pool(request.pool)?,
+        };
+        let recv = vm.kvp_rpc.call_failable(KvpRpc::Enumerate, params);
+        Ok(async move {
+            let entries = recv
+                .await?
+                .into_iter()
+                .map(|entry| vmservice::kvp_enumerate_response::Entry {
+                    key: entry.key,
+                    value: Some(kvp_value_to_proto(entry.value)),
+                })
+                .collect();
+            Ok(vmservice::KvpEnumerateResponse { entries })
+        })
+    }
+
     fn modify_resource(
         &mut self,
         vm: &Vm,
@@ -681,15 +890,93 @@ fn modify_resource(
                 let recv = vm.worker_rpc.call_failable(VmRpc::AddVmbusDevice, config);
                 Ok(async move { recv.await.map_err(anyhow::Error::from) }.boxed())
             }
+            Resource::NvmeDisk(disk) => {
+                let port: u8 = disk
+                    .root_port
+                    .try_into()
+                    .ok()
+                    .context("root port out of range")?;
+
+                if request.r#type == vmservice::ModifyType::Add as i32 {
+                    let device = make_nvme_disk_config(disk)?;
+                    let recv = vm
+                        .worker_rpc
+                        .call_failable(VmRpc::AddPcieDevice, (port, device));
+                    Ok(async move { recv.await.map_err(anyhow::Error::from) }.boxed())
+                } else if request.r#type == vmservice::ModifyType::Remove as i32 {
+                    // This is a surprise removal, which does not wait for the
+                    // guest to release the device.
+                    let recv = vm.worker_rpc.call_failable(VmRpc::RemovePcieDevice, port);
+                    Ok(async move { recv.await.map_err(anyhow::Error::from) }.boxed())
+                } else {
+                    anyhow::bail!("unsupported request type {}", request.r#type);
+                }
+            }
             Resource::VpmemDisk(_) => anyhow::bail!("vpmem not supported"),
             Resource::WindowsDevice(_) => anyhow::bail!("device assignment not supported"),
-            Resource::Processor(_) | Resource::ProcessorConfig(_) | Resource::Memory(_) => {
-                anyhow::bail!("processor and memory resources not supported")
+            Resource::Memory(memory) => {
+                if request.r#type != vmservice::ModifyType::Update as i32 {
+                    anyhow::bail!("unsupported request type {}", request.r#type);
+                }
+                // Reclaim the memory above the requested size.
+                let target = memory
+                    .memory_mb
+                    .checked_mul(0x100000)
+                    .and_then(|size| vm.mem_size.checked_sub(size))
+                    .context("invalid memory size")?;
+                let recv = vm
+                    .balloon_rpc
+                    .as_ref()
+                    .context("no virtio balloon")?
+                    .call(BalloonRpc::SetTarget, target);
+                Ok(async move { recv.await.map_err(anyhow::Error::from) }.boxed())
+            }
+            Resource::Processor(_) | Resource::ProcessorConfig(_) => {
+                anyhow::bail!("processor resources not supported")
             }
         }
     }
 }
 
+fn kvp_pool(pool: i32) -> anyhow::Result<hyperv_ic_resources::kvp::KvpPool> {
+    use hyperv_ic_resources::kvp::KvpPool;
+
+    let pool = match vmservice::KvpPool::from_i32(pool).context("invalid kvp pool")? {
+        vmservice::KvpPool::External => KvpPool::External,
+        vmservice::KvpPool::Guest => KvpPool::Guest,
+        vmservice::KvpPool::Auto => KvpPool::Auto,
+        vmservice::KvpPool::AutoExternal => KvpPool::AutoExternal,
+        vmservice::KvpPool::AutoInternal => KvpPool::AutoInternal,
+    };
+    Ok(pool)
+}
+
+fn kvp_value_from_proto(
+    value: Option<vmservice::KvpValue>,
+) -> anyhow::Result<hyperv_ic_resources::kvp::Value> {
+    use hyperv_ic_resources::kvp::Value;
+    use vmservice::kvp_value::Value as ProtoValue;
+
+    let value = match value.and_then(|v| v.value).context("missing value")? {
+        ProtoValue::StringValue(s) => Value::String(s),
+        ProtoValue::U32Value(n) => Value::U32(n),
+        ProtoValue::U64Value(n) => Value::U64(n),
+    };
+    Ok(value)
+}
+
+fn kvp_value_to_proto(value: hyperv_ic_resources::kvp::Value) -> vmservice::KvpValue {
+    use hyperv_ic_resources::kvp::Value;
+    use vmservice::kvp_value::Value as ProtoValue;
+
+    let value = match value {
+        Value::String(s) => ProtoValue::StringValue(s),
+        Value::U32(n) => ProtoValue::U32Value(n),
+        Value::U64(n) => ProtoValue::U64Value(n),
+    };
+    vmservice::KvpValue { value: Some(value) }
+}
+
 fn parse_nic_config(
     nic: vmservice::NicConfig,
 ) -> anyhow::Result<(DeviceVtl, Resource<VmbusDeviceHandleKind>)> {
@@ -711,9 +998,15 @@ fn parse_nic_config(
         }
         .into_resource(),
         #[cfg(unix)]
-        Backend::Tap(tap) => {
-            net_backend_resources::tap::TapHandle { name: tap.name }.into_resource()
+        Backend::Tap(tap) => net_backend_resources::tap::TapHandle {
+            name: tap.name,
+            queues: tap
+                .queues
+                .max(1)
+                .try_into()
+                .context("too many tap queues")?,
         }
+        .into_resource(),
         _ => anyhow::bail!("unsupported backend"),
     };
     let cfg = NetvspHandle {
@@ -746,3 +1039,34 @@ fn make_disk_config(disk: vmservice::ScsiDisk) -> anyhow::Result<ScsiDeviceAndPa
         .into_resource(),
     })
 }
+
+fn make_nvme_disk_config(
+    disk: vmservice::NvmeDisk,
+) -> anyhow::Result<Resource<PciDeviceHandleKind>> {
+    let namespace_pool = disk
+        .namespace_pool
+        .map(|pool| {
+            if pool.capacity == 0 {
+                bail!("namespace pool capacity must be nonzero");
+            }
+            let max_namespaces = match pool.max_namespaces {
+                0 => DEFAULT_NVME_POOL_NAMESPACES,
+                n => n,
+            };
+            Ok(ram_namespace_pool(pool.capacity, max_namespaces))
+        })
+        .transpose()?;
+    Ok(NvmeControllerHandle {
+        subsystem_id: Guid::new_random(),
+        namespaces: vec![NamespaceDefinition {
+            nsid: 1,
+            read_only: disk.read_only,
+            disk: open_disk_type(disk.host_path.as_ref(), disk.read_only)
+                .with_context(|| format!("failed to open {}", disk.host_path))?,
+        }],
+        max_io_queues: 64,
+        msix_count: 64,
+        namespace_pool,
+    }
+    .into_resource())
+}
@@ -34,18 +34,28 @@
 
     // Network backends
     net_backend::null::NullResolver,
+    #[cfg(unix)]
+    net_switch::resolver::SwitchResolver,
     #[cfg(feature = "net_consomme")]
     net_consomme::resolver::ConsommeResolver,
     #[cfg(all(feature = "net_tap", target_os = "linux"))]
     net_tap::resolver::TapResolver,
+    #[cfg(all(feature = "net_af_packet", target_os = "linux"))]
+    net_af_packet::resolver::AfPacketResolver,
     #[cfg(windows)]
     net_dio::resolver::DioResolver,
+    net_packet_capture::resolver::PacketCaptureResolver,
 
     // Disks
     disk_ramdisk::resolver::RamDiskResolver,
     disk_file::FileDiskResolver,
+    disk_filediff::resolver::FileDiffDiskResolver,
     disk_prwrap::DiskWithReservationsResolver,
+    disk_qcow2::resolver::Qcow2DiskResolver,
+    disk_throttle::ThrottleDiskResolver,
     disk_vhd1::Vhd1Resolver,
+    disk_vhd1::DynamicVhd1Resolver,
+    disk_vhdx::resolver::VhdxDiskResolver,
     #[cfg(windows)]
     disk_vhdmp::VhdmpDiskResolver,
     #[cfg(feature = "disk_blob")]
@@ -54,6 +64,7 @@
     // PCI devices
     gdma::resolver::GdmaDeviceResolver,
     nvme::resolver::NvmeControllerResolver,
+    nvme::resolver::RamNamespaceDiskTemplateResolver,
 
     // SCSI
     scsidisk::resolver::SimpleScsiResolver,
@@ -63,8 +74,12 @@
     virtiofs::resolver::VirtioFsResolver,
     #[cfg(any(windows, target_os = "linux"))]
     virtio_p9::resolver::VirtioPlan9Resolver,
+    virtio_balloon::resolver::VirtioBalloonResolver,
+    virtio_blk::resolver::VirtioBlkResolver,
     virtio_net::resolver::VirtioNetResolver,
     virtio_pmem::resolver::VirtioPmemResolver,
+    virtio_rng::resolver::VirtioRngResolver,
+    virtio_vsock::resolver::VirtioVsockResolver,
 
     // Vmbus devices
     guest_crash_device::resolver::GuestCrashDeviceResolver,
@@ -294,6 +294,7 @@ pub fn new(
             // Base chipset
             chipset: chipset.chipset,
             chipset_devices: chipset.chipset_devices,
+            pcie_root_complex: None,
 
             // Basic virtualization device support
             hypervisor: HypervisorConfig {
@@ -822,6 +823,7 @@ fn load_boot_disk(
                             .into_resource(),
                             read_only: false,
                         }],
+                        namespace_pool: None,
                     }
                     .into_resource(),
                 })]);
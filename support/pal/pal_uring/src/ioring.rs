@@ -368,6 +368,18 @@ pub fn probe(&self, opcode: u8) -> bool {
         probe.is_supported(opcode)
     }
 
+    /// Registers `iovecs` as fixed buffers, for use by `ReadFixed` and
+    /// `WriteFixed` IOs.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the buffers remain valid until they are
+    /// unregistered or the ring is dropped.
+    pub unsafe fn register_buffers(&self, iovecs: &[libc::iovec]) -> io::Result<()> {
+        // SAFETY: guaranteed by caller.
+        unsafe { self.inner.ring.submitter().register_buffers(iovecs) }
+    }
+
     /// Polls an IO for completion.
     ///
     /// If the IO is completed, returns the status and associated memory object.
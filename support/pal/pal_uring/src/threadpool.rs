@@ -73,11 +73,19 @@ pub fn client(&self) -> &PoolClient {
         &self.client
     }
 
-    /// Runs the pool until it is shut down.
+    /// Runs the pool until it is shut down, which happens once every
+    /// initiator and task spawned on the pool has been dropped.
     ///
     /// Typically this is called on a dedicated thread.
-    pub fn run(mut self) {
-        self.worker.run(self.completion_ring, self.queue.run())
+    pub fn run(self) {
+        let Self {
+            client,
+            worker,
+            completion_ring,
+            mut queue,
+        } = self;
+        drop(client);
+        worker.run(completion_ring, queue.run())
     }
 }
 
@@ -349,6 +357,19 @@ pub fn probe(&self, opcode: u8) -> bool {
         self.client.worker.io_ring.probe(opcode)
     }
 
+    /// Registers `iovecs` as the ring's fixed buffers, for use by `ReadFixed`
+    /// and `WriteFixed` IOs. Buffer indexes in IOs refer to the position in
+    /// `iovecs`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the buffers remain valid for the
+    /// lifetime of the ring.
+    pub unsafe fn register_buffers(&self, iovecs: &[libc::iovec]) -> io::Result<()> {
+        // SAFETY: guaranteed by caller.
+        unsafe { self.client.worker.io_ring.register_buffers(iovecs) }
+    }
+
     /// Issues an IO described by `f`, referencing IO memory in `io_mem`.
     ///
     /// The submission queue entry for the IO is provided by `f` so that the IO
@@ -14,6 +14,7 @@
 pub mod windows;
 
 pub use sys::alloc_shared_memory;
+pub use sys::discard_shared_memory;
 pub use sys::new_mappable_from_file;
 pub use sys::AsMappableRef;
 pub use sys::Mappable;
@@ -377,3 +377,33 @@ pub fn alloc_shared_memory(size: usize) -> io::Result<OwnedFd> {
     fd.set_len(size as u64)?;
     Ok(fd.into())
 }
+
+/// Releases the memory backing `offset..offset + len` of a shared memory
+/// object allocated with [`alloc_shared_memory`]. The range reads as zero
+/// afterward, in this and all other mappings of the object.
+pub fn discard_shared_memory(
+    mappable: impl AsMappableRef,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: calling fallocate on a valid fd has no memory safety
+        // requirements. Mappings of the range will fault in zero pages.
+        unsafe {
+            libc::fallocate(
+                mappable.as_fd().as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as i64,
+                len as i64,
+            )
+            .syscall_result()?;
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (mappable, offset, len);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
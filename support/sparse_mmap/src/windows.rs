@@ -691,6 +691,18 @@ pub fn alloc_shared_memory(size: usize) -> io::Result<OwnedHandle> {
     }
 }
 
+/// Releases the memory backing `offset..offset + len` of a shared memory
+/// object allocated with [`alloc_shared_memory`].
+///
+/// This is not supported for section objects, so this always fails.
+pub fn discard_shared_memory(
+    _mappable: impl AsMappableRef,
+    _offset: u64,
+    _len: u64,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::alloc_shared_memory;
@@ -0,0 +1,190 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A token bucket for rate limiting.
+//!
+//! The bucket is implemented as a generic cell rate algorithm: rather than
+//! tracking the tokens in the bucket, it tracks the time at which the bucket
+//! will be full again. This makes it cheap to update and allows callers to
+//! reserve more tokens than are available, paying for them by waiting.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+pub mod test_helpers;
+
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// The time source used to wait for tokens.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Waits until `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn '_ + Send + Future<Output = ()>>>;
+}
+
+/// A [`Clock`] using the host's monotonic time and a driver's timers.
+pub struct DriverClock(Box<dyn Driver>);
+
+impl DriverClock {
+    /// Returns a clock that waits using `driver`'s timers.
+    pub fn new(driver: impl Driver) -> Self {
+        Self(Box::new(driver))
+    }
+}
+
+impl Clock for DriverClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn '_ + Send + Future<Output = ()>>> {
+        Box::pin(async move {
+            PolledTimer::new(self.0.as_ref())
+                .sleep_until(deadline)
+                .await;
+        })
+    }
+}
+
+/// A token bucket that refills at a sustained rate, up to a burst size.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    /// The time, in nanoseconds, at which the bucket will be full again once
+    /// all tokens taken so far have been paid for.
+    full_at: u64,
+}
+
+impl TokenBucket {
+    /// Returns a full bucket that refills at `rate` tokens per second and
+    /// holds up to `burst` tokens.
+    ///
+    /// A rate of zero means the bucket is unlimited.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate,
+            burst,
+            full_at: 0,
+        }
+    }
+
+    /// Returns the sustained rate, in tokens per second, or zero if unlimited.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Sets the sustained rate, in tokens per second, or zero for unlimited.
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+    }
+
+    /// Returns the bucket size, in tokens.
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Sets the bucket size, in tokens.
+    pub fn set_burst(&mut self, burst: u64) {
+        self.burst = burst;
+    }
+
+    /// Returns how long it takes to refill `tokens` tokens, in nanoseconds.
+    fn nanos(&self, tokens: u64) -> u64 {
+        (tokens as u128 * 1_000_000_000 / self.rate as u128)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Takes `cost` tokens from the bucket at time `now`, even if that
+    /// overdraws it. Returns how long the caller must wait before using them.
+    pub fn reserve(&mut self, now: Instant, cost: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let now = now.as_nanos();
+        self.full_at = self.full_at.max(now).saturating_add(self.nanos(cost));
+        Duration::from_nanos((self.full_at - now).saturating_sub(self.nanos(self.burst)))
+    }
+
+    /// Takes up to `want` tokens from the bucket at time `now`, without
+    /// overdrawing it. If the bucket is empty, returns how long until `want`
+    /// tokens, or a full bucket, are available instead.
+    pub fn take(&mut self, now: Instant, want: u64) -> Result<u64, Duration> {
+        if self.rate == 0 {
+            return Ok(want);
+        }
+        // A bucket must hold at least one token to make progress.
+        let burst = self.burst.max(1);
+        let now = now.as_nanos();
+        let debt = self.full_at.saturating_sub(now);
+        let used = (debt as u128 * self.rate as u128).div_ceil(1_000_000_000);
+        let available = (burst as u128).saturating_sub(used) as u64;
+        if available == 0 {
+            let need = want.min(burst);
+            return Err(Duration::from_nanos(
+                debt.saturating_sub(self.nanos(burst - need)),
+            ));
+        }
+        let n = want.min(available);
+        self.full_at = self.full_at.max(now).saturating_add(self.nanos(n));
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use pal_async::timer::Instant;
+    use std::time::Duration;
+
+    fn ms(n: u64) -> Instant {
+        Instant::from_nanos(n * 1_000_000)
+    }
+
+    #[test]
+    fn reserve() {
+        let mut bucket = TokenBucket::new(100, 3);
+        // The burst is available immediately.
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(ms(1000), 1), Duration::ZERO);
+        }
+        // Then tokens are available every 10ms.
+        assert_eq!(bucket.reserve(ms(1000), 1), Duration::from_millis(10));
+        assert_eq!(bucket.reserve(ms(1000), 1), Duration::from_millis(20));
+        assert_eq!(bucket.reserve(ms(1025), 1), Duration::from_millis(5));
+        // The bucket refills while idle.
+        assert_eq!(bucket.reserve(ms(2000), 2), Duration::ZERO);
+        // Costs larger than the burst wait for the difference.
+        assert_eq!(bucket.reserve(ms(3000), 5), Duration::from_millis(20));
+
+        let mut unlimited = TokenBucket::new(0, 0);
+        assert_eq!(unlimited.reserve(ms(0), u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn take() {
+        let mut bucket = TokenBucket::new(1000, 1000);
+        // A full bucket is available immediately.
+        assert_eq!(bucket.take(ms(1000), 600), Ok(600));
+        assert_eq!(bucket.take(ms(1000), 600), Ok(400));
+        assert_eq!(bucket.take(ms(1000), 100), Err(Duration::from_millis(100)));
+        // Tokens accumulate over time, up to the burst.
+        assert_eq!(bucket.take(ms(1050), 100), Ok(50));
+        assert_eq!(bucket.take(ms(5000), 5000), Ok(1000));
+        assert_eq!(
+            bucket.take(ms(5000), 5000),
+            Err(Duration::from_millis(1000))
+        );
+
+        let mut unlimited = TokenBucket::new(0, 0);
+        assert_eq!(unlimited.take(ms(0), 5000), Ok(5000));
+    }
+}
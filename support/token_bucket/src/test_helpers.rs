@@ -0,0 +1,27 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers for testing rate-limited code.
+
+use crate::Clock;
+use pal_async::timer::Instant;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// A [`Clock`] that starts at zero and advances only when waited on, so that
+/// waits complete immediately.
+#[derive(Debug, Default)]
+pub struct MockClock(AtomicU64);
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        Instant::from_nanos(self.0.load(Ordering::SeqCst))
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn '_ + Send + Future<Output = ()>>> {
+        self.0.fetch_max(deadline.as_nanos(), Ordering::SeqCst);
+        Box::pin(std::future::ready(()))
+    }
+}
@@ -332,8 +332,6 @@ pub fn add_pci(
     ) {
         let mut pci0 = Device::new(b"\\_SB.PCI0");
         pci0.add_object(&NamedObject::new(b"_HID", &EisaId(*b"PNP0A03")));
-        // FUTURE: when implementing PCIe, switch _HID over to "PNP0A08", and a
-        // _CID for "PNP0A03"
 
         // OS negotiation for control of the bus. See https://uefi.org/specs/ACPI/6.4/06_Device_Configuration/Device_Configuration.html#osc-operating-system-capabilities
         // TODO: Lots of work needed for _OSC.
@@ -343,6 +341,178 @@ pub fn add_pci(
             result: Buffer(0x10u64.to_le_bytes()).to_bytes(),
         });
         pci0.add_object(&empty_os_method);
+        Self::add_pci_root_resources(&mut pci0, low, high, 1, legacy_interrupts);
+        self.add_object(&pci0);
+    }
+
+    /// Adds a PCI Express root complex with the specified MMIO ranges, whose
+    /// configuration space is accessible through the `ecam` region.
+    ///
+    /// The ECAM region decodes `bus_count` buses starting at bus 0, and must
+    /// not overlap `low` or `high`. It is also reported as a motherboard
+    /// resource, as some operating systems refuse to use an ECAM region from
+    /// the MCFG that is not reserved this way.
+    ///
+    /// ```text
+    /// Device(\_SB.PCI0)
+    /// {
+    ///     Name(_HID, PNP0A08)
+    ///     Name(_CID, PNP0A03)
+    ///     Method(_OSC, 4, Serialized) { ... } // See `pcie_osc`
+    ///     Name(_CRS, ResourceTemplate()
+    ///     {
+    ///         WordBusNumber(...) // Buses decoded by the ECAM region
+    ///         IO(Decode16, 0xcf8, 0xcf8) // IO port
+    ///         QWordMemory() // Low gap
+    ///         QWordMemory() // High gap
+    ///     })
+    ///     // PCI routing table
+    ///     Name(_PRT, Package{
+    ///         Package{<address>, <PCI pin>, 0, <interrupt>},
+    ///         ...
+    ///     })
+    /// }
+    ///
+    /// Device(\_SB.ECAM)
+    /// {
+    ///     Name(_HID, PNP0C02)
+    ///     Name(_UID, 0)
+    ///     Name(_CRS, ResourceTemplate()
+    ///     {
+    ///         QWordMemory() // ECAM region
+    ///     })
+    /// }
+    /// ```
+    pub fn add_pcie(
+        &mut self,
+        low: MemoryRange,
+        high: MemoryRange,
+        ecam: MemoryRange,
+        bus_count: u16,
+        // array of ((device, function), line)
+        legacy_interrupts: &[((u8, Option<u8>), u32)],
+    ) {
+        assert!((1..=256).contains(&bus_count));
+        assert!(!ecam.overlaps(&low) && !ecam.overlaps(&high));
+
+        let mut pci0 = Device::new(b"\\_SB.PCI0");
+        pci0.add_object(&NamedObject::new(b"_HID", &EisaId(*b"PNP0A08")));
+        pci0.add_object(&NamedObject::new(b"_CID", &EisaId(*b"PNP0A03")));
+        pci0.add_object(&Self::pcie_osc());
+        Self::add_pci_root_resources(&mut pci0, low, high, bus_count, legacy_interrupts);
+        self.add_object(&pci0);
+
+        let mut ecam_res = Device::new(b"\\_SB.ECAM");
+        ecam_res.add_object(&NamedObject::new(b"_HID", &EisaId(*b"PNP0C02")));
+        ecam_res.add_object(&NamedInteger::new(b"_UID", 0));
+        let mut crs = CurrentResourceSettings::new();
+        crs.add_resource(&QwordMemory::new(ecam.start(), ecam.len()));
+        ecam_res.add_object(&crs);
+        self.add_object(&ecam_res);
+    }
+
+    /// Builds the `_OSC` method of a PCI Express root complex, which grants
+    /// the OS control of the features the root complex implements: native
+    /// hot-plug and the PCI Express capability structure. Requests for
+    /// anything else are masked, and other UUIDs are rejected.
+    ///
+    /// ```text
+    /// Method(_OSC, 4, Serialized)
+    /// {
+    ///     CreateDWordField(Arg3, 0, CDW1)
+    ///     If (LEqual(Arg0, ToUUID("33db4d5b-1ff7-401c-9657-7441c03dd766")))
+    ///     {
+    ///         CreateDWordField(Arg3, 8, CDW3)
+    ///         If (And(CDW3, Not(CONTROL)))
+    ///         {
+    ///             Or(CDW1, 0x10, CDW1) // Capabilities masked
+    ///         }
+    ///         And(CDW3, CONTROL, CDW3)
+    ///         Return(Arg3)
+    ///     }
+    ///     Or(CDW1, 0x4, CDW1) // Unrecognized UUID
+    ///     Return(Arg3)
+    /// }
+    /// ```
+    fn pcie_osc() -> Method {
+        /// The PCI host bridge `_OSC` UUID, 33db4d5b-1ff7-401c-9657-7441c03dd766,
+        /// in its ACPI buffer encoding.
+        const PCI_HOST_BRIDGE_UUID: [u8; 16] = [
+            0x5b, 0x4d, 0xdb, 0x33, 0xf7, 0x1f, 0x1c, 0x40, 0x96, 0x57, 0x74, 0x41, 0xc0, 0x3d,
+            0xd7, 0x66,
+        ];
+        const OSC_UNRECOGNIZED_UUID: u64 = 1 << 2;
+        const OSC_CAPABILITIES_MASKED: u64 = 1 << 4;
+        const OSC_CONTROL_NATIVE_HOT_PLUG: u32 = 1 << 0;
+        const OSC_CONTROL_PCI_EXPRESS_CAPABILITY: u32 = 1 << 4;
+        const OSC_CONTROL: u32 = OSC_CONTROL_NATIVE_HOT_PLUG | OSC_CONTROL_PCI_EXPRESS_CAPABILITY;
+
+        // The method creates named fields, so it must not be reentered.
+        let mut osc = Method::new(b"_OSC");
+        osc.set_arg_count(4);
+        osc.is_serialized = true;
+        osc.add_operation(&CreateDWordFieldOp {
+            source_buffer: encode_arg(3),
+            byte_index: encode_integer(0),
+            name: b"CDW1".to_vec(),
+        });
+
+        let mut pci_uuid = IfOp {
+            predicate: LEqualOp {
+                operand1: encode_arg(0),
+                operand2: Buffer(PCI_HOST_BRIDGE_UUID).to_bytes(),
+            }
+            .to_bytes(),
+            body: vec![],
+        };
+        pci_uuid.add_operation(&CreateDWordFieldOp {
+            source_buffer: encode_arg(3),
+            byte_index: encode_integer(8),
+            name: b"CDW3".to_vec(),
+        });
+        let mut masked = IfOp {
+            predicate: AndOp {
+                operand1: b"CDW3".to_vec(),
+                operand2: encode_integer((!OSC_CONTROL).into()),
+                target_name: vec![0],
+            }
+            .to_bytes(),
+            body: vec![],
+        };
+        masked.add_operation(&OrOp {
+            operand1: b"CDW1".to_vec(),
+            operand2: encode_integer(OSC_CAPABILITIES_MASKED),
+            target_name: b"CDW1".to_vec(),
+        });
+        pci_uuid.add_operation(&masked);
+        pci_uuid.add_operation(&AndOp {
+            operand1: b"CDW3".to_vec(),
+            operand2: encode_integer(OSC_CONTROL.into()),
+            target_name: b"CDW3".to_vec(),
+        });
+        pci_uuid.add_operation(&ReturnOp {
+            result: encode_arg(3),
+        });
+        osc.add_operation(&pci_uuid);
+
+        osc.add_operation(&OrOp {
+            operand1: b"CDW1".to_vec(),
+            operand2: encode_integer(OSC_UNRECOGNIZED_UUID),
+            target_name: b"CDW1".to_vec(),
+        });
+        osc.add_operation(&ReturnOp {
+            result: encode_arg(3),
+        });
+        osc
+    }
+
+    fn add_pci_root_resources(
+        pci0: &mut Device,
+        low: MemoryRange,
+        high: MemoryRange,
+        bus_count: u16,
+        legacy_interrupts: &[((u8, Option<u8>), u32)],
+    ) {
         let mut prt = PciRoutingTable::new();
         for &((device, function), line) in legacy_interrupts {
             prt.add_entry(PciRoutingTableEntry {
@@ -354,12 +524,11 @@ pub fn add_pci(
         }
         pci0.add_object(&prt);
         let mut crs = CurrentResourceSettings::new();
-        crs.add_resource(&BusNumber::new(0, 1));
+        crs.add_resource(&BusNumber::new(0, bus_count));
         crs.add_resource(&IoPort::new(0xcf8, 0xcf8, 8));
         crs.add_resource(&QwordMemory::new(low.start(), low.end() - low.start()));
         crs.add_resource(&QwordMemory::new(high.start(), high.end() - high.start()));
         pci0.add_object(&crs);
-        self.add_object(&pci0);
     }
 
     /// Add a VMBUS device to the DSDT.
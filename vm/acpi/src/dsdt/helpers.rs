@@ -142,6 +142,12 @@ pub fn encode_string(value: &[u8]) -> Vec<u8> {
     byte_stream
 }
 
+/// Encodes a reference to the method argument `ArgN`.
+pub fn encode_arg(index: u8) -> Vec<u8> {
+    assert!(index < 7, "methods take at most 7 arguments");
+    vec![0x68 + index]
+}
+
 pub fn char_to_hex(value: u8) -> u8 {
     match value {
         b'0'..=b'9' => value - b'0',
@@ -1,6 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use super::helpers::encode_package_len;
+
 pub trait OperationObject {
     fn append_to_vec(&self, byte_stream: &mut Vec<u8>);
 
@@ -52,9 +54,58 @@ fn append_to_vec(&self, byte_stream: &mut Vec<u8>) {
     }
 }
 
+pub struct IfOp {
+    pub predicate: Vec<u8>,
+    pub body: Vec<u8>,
+}
+
+impl IfOp {
+    pub fn add_operation(&mut self, op: &impl OperationObject) {
+        op.append_to_vec(&mut self.body);
+    }
+}
+
+impl OperationObject for IfOp {
+    fn append_to_vec(&self, byte_stream: &mut Vec<u8>) {
+        byte_stream.push(0xa0);
+        byte_stream.extend_from_slice(&encode_package_len(self.predicate.len() + self.body.len()));
+        byte_stream.extend_from_slice(&self.predicate);
+        byte_stream.extend_from_slice(&self.body);
+    }
+}
+
+pub struct LEqualOp {
+    pub operand1: Vec<u8>,
+    pub operand2: Vec<u8>,
+}
+
+impl OperationObject for LEqualOp {
+    fn append_to_vec(&self, byte_stream: &mut Vec<u8>) {
+        byte_stream.push(0x93);
+        byte_stream.extend_from_slice(&self.operand1);
+        byte_stream.extend_from_slice(&self.operand2);
+    }
+}
+
+pub struct CreateDWordFieldOp {
+    pub source_buffer: Vec<u8>,
+    pub byte_index: Vec<u8>,
+    pub name: Vec<u8>,
+}
+
+impl OperationObject for CreateDWordFieldOp {
+    fn append_to_vec(&self, byte_stream: &mut Vec<u8>) {
+        byte_stream.push(0x8a);
+        byte_stream.extend_from_slice(&self.source_buffer);
+        byte_stream.extend_from_slice(&self.byte_index);
+        byte_stream.extend_from_slice(&self.name);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dsdt::encode_arg;
     use crate::dsdt::encode_integer;
     use crate::dsdt::tests::verify_expected_bytes;
 
@@ -98,4 +149,38 @@ fn verify_return_operation() {
         let bytes = op.to_bytes();
         verify_expected_bytes(&bytes, &[0xa4, b'S', b'T', b'A', b'_']);
     }
+
+    #[test]
+    fn verify_if_operation() {
+        let mut op = IfOp {
+            predicate: vec![b'S', b'T', b'A', b'_'],
+            body: vec![],
+        };
+        op.add_operation(&ReturnOp {
+            result: encode_integer(1),
+        });
+        let bytes = op.to_bytes();
+        verify_expected_bytes(&bytes, &[0xa0, 7, b'S', b'T', b'A', b'_', 0xa4, 1]);
+    }
+
+    #[test]
+    fn verify_lequal_operation() {
+        let op = LEqualOp {
+            operand1: vec![b'S', b'T', b'A', b'_'],
+            operand2: encode_integer(13),
+        };
+        let bytes = op.to_bytes();
+        verify_expected_bytes(&bytes, &[0x93, b'S', b'T', b'A', b'_', 0x0a, 0x0d]);
+    }
+
+    #[test]
+    fn verify_create_dword_field_operation() {
+        let op = CreateDWordFieldOp {
+            source_buffer: encode_arg(3),
+            byte_index: encode_integer(8),
+            name: vec![b'C', b'D', b'W', b'3'],
+        };
+        let bytes = op.to_bytes();
+        verify_expected_bytes(&bytes, &[0x8a, 0x6b, 0x0a, 0x08, b'C', b'D', b'W', b'3']);
+    }
 }
@@ -11,6 +11,7 @@
 pub mod aspt;
 pub mod fadt;
 pub mod madt;
+pub mod mcfg;
 pub mod pptt;
 pub mod srat;
 
@@ -43,4 +43,38 @@ pub trait PciConfigSpace: ChipsetDevice {
     fn suggested_bdf(&mut self) -> Option<(u8, u8, u8)> {
         None
     }
+
+    /// Dispatch a PCI config space read to a function on a bus behind this
+    /// device, if this device is a PCI-to-PCI bridge (e.g: a PCI Express root
+    /// port).
+    ///
+    /// `device_function` holds the device number in bits 7:3 and the function
+    /// number in bits 2:0, as in a configuration address.
+    ///
+    /// Returns `None` if `bus` is not one of the buses behind this device.
+    fn pci_cfg_read_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: &mut u32,
+    ) -> Option<IoResult> {
+        let _ = (bus, device_function, offset, value);
+        None
+    }
+
+    /// Dispatch a PCI config space write to a function on a bus behind this
+    /// device, if this device is a PCI-to-PCI bridge.
+    ///
+    /// Returns `None` if `bus` is not one of the buses behind this device.
+    fn pci_cfg_write_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: u32,
+    ) -> Option<IoResult> {
+        let _ = (bus, device_function, offset, value);
+        None
+    }
 }
@@ -0,0 +1,128 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Protocol helpers shared by the ICs.
+
+use hyperv_ic_protocol::Version;
+use std::io::IoSlice;
+use thiserror::Error;
+use vmbus_async::async_dgram::AsyncRecvExt;
+use vmbus_async::async_dgram::AsyncSendExt;
+use vmbus_async::pipe::MessagePipe;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+use zerocopy_helpers::FromBytesExt;
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("ring buffer error")]
+    Ring(#[source] std::io::Error),
+    #[error("truncated message")]
+    TruncatedMessage,
+    #[error("invalid version response")]
+    InvalidVersionResponse,
+    #[error("no supported versions")]
+    NoSupportedVersions,
+}
+
+/// Sends a version negotiation request offering `framework_versions` and
+/// `message_versions`.
+pub(crate) async fn send_version_request(
+    pipe: &mut MessagePipe<GpadlRingMem>,
+    framework_versions: &[Version],
+    message_versions: &[Version],
+) -> Result<(), Error> {
+    let message = hyperv_ic_protocol::NegotiateMessage {
+        framework_version_count: framework_versions.len() as u16,
+        message_version_count: message_versions.len() as u16,
+        ..FromZeroes::new_zeroed()
+    };
+
+    let header = hyperv_ic_protocol::Header {
+        message_type: hyperv_ic_protocol::MessageType::VERSION_NEGOTIATION,
+        message_size: (size_of_val(&message)
+            + size_of_val(framework_versions)
+            + size_of_val(message_versions)) as u16,
+        status: 0,
+        transaction_id: 0,
+        flags: hyperv_ic_protocol::HeaderFlags::new()
+            .with_transaction(true)
+            .with_request(true),
+        ..FromZeroes::new_zeroed()
+    };
+
+    pipe.send_vectored(&[
+        IoSlice::new(header.as_bytes()),
+        IoSlice::new(message.as_bytes()),
+        IoSlice::new(framework_versions.as_bytes()),
+        IoSlice::new(message_versions.as_bytes()),
+    ])
+    .await
+    .map_err(Error::Ring)
+}
+
+/// Reads the guest's response to a version negotiation request, returning the
+/// chosen framework and message versions.
+pub(crate) async fn read_version_response(
+    pipe: &mut MessagePipe<GpadlRingMem>,
+) -> Result<(Version, Version), Error> {
+    let (_result, buf) = read_response(pipe).await?;
+    let (message, rest) =
+        hyperv_ic_protocol::NegotiateMessage::read_from_prefix_split(buf.as_slice())
+            .ok_or(Error::TruncatedMessage)?;
+    if message.framework_version_count != 1 || message.message_version_count != 1 {
+        return Err(Error::NoSupportedVersions);
+    }
+    let [framework_version, message_version] =
+        <[Version; 2]>::read_from_prefix(rest).ok_or(Error::TruncatedMessage)?;
+    Ok((framework_version, message_version))
+}
+
+/// Sends a request message of type `message_type` with payload `message`.
+pub(crate) async fn send_request(
+    pipe: &mut MessagePipe<GpadlRingMem>,
+    framework_version: Version,
+    message_version: Version,
+    message_type: hyperv_ic_protocol::MessageType,
+    message: &[u8],
+) -> Result<(), Error> {
+    let header = hyperv_ic_protocol::Header {
+        framework_version,
+        message_type,
+        message_size: message.len() as u16,
+        message_version,
+        status: 0,
+        transaction_id: 0,
+        flags: hyperv_ic_protocol::HeaderFlags::new()
+            .with_transaction(true)
+            .with_request(true),
+        ..FromZeroes::new_zeroed()
+    };
+
+    pipe.send_vectored(&[IoSlice::new(header.as_bytes()), IoSlice::new(message)])
+        .await
+        .map_err(Error::Ring)
+}
+
+/// Reads a response message, returning its status and payload.
+pub(crate) async fn read_response(
+    pipe: &mut MessagePipe<GpadlRingMem>,
+) -> Result<(u32, Vec<u8>), Error> {
+    let mut buf = vec![0; hyperv_ic_protocol::MAX_MESSAGE_SIZE];
+    let n = pipe.recv(&mut buf).await.map_err(Error::Ring)?;
+    let buf = &buf[..n];
+    let (header, rest) =
+        hyperv_ic_protocol::Header::read_from_prefix_split(buf).ok_or(Error::TruncatedMessage)?;
+
+    if header.transaction_id != 0 || !header.flags.transaction() || !header.flags.response() {
+        return Err(Error::InvalidVersionResponse);
+    }
+
+    let rest = rest
+        .get(..header.message_size as usize)
+        .ok_or(Error::TruncatedMessage)?;
+
+    Ok((header.status, rest.to_vec()))
+}
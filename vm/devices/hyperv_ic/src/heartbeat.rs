@@ -0,0 +1,605 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The heartbeat IC.
+//!
+//! The host sends a heartbeat request to the guest every second. The guest
+//! responds with an incremented sequence number and, for newer guests, its
+//! application state. A guest that stops responding is reported as having
+//! lost communication.
+
+use crate::common::read_response;
+use crate::common::read_version_response;
+use crate::common::send_request;
+use crate::common::send_version_request;
+use crate::common::Error;
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures_concurrency::future::Race;
+use hyperv_ic_protocol::heartbeat::ApplicationState;
+use hyperv_ic_protocol::heartbeat::HeartbeatMessage;
+use hyperv_ic_protocol::heartbeat::FRAMEWORK_VERSIONS;
+use hyperv_ic_protocol::heartbeat::HEARTBEAT_VERSIONS;
+use hyperv_ic_resources::heartbeat::HeartbeatRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatState;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
+use inspect::Inspect;
+use inspect::InspectMut;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_async::pipe::MessagePipe;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_channel::RawAsyncChannel;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// The interval between heartbeat requests.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The time after the last heartbeat at which the guest is considered to have
+/// lost communication.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A heartbeat IC device.
+pub struct HeartbeatIc {
+    driver: VmTaskDriver,
+    shared: Arc<Mutex<Shared>>,
+    _rpc_task: Task<()>,
+}
+
+/// The guest's liveness, shared with the RPC task so that it can be queried
+/// while the channel is closed.
+#[derive(Default)]
+struct Shared {
+    sequence_number: u64,
+    last_heartbeat: Option<Instant>,
+    application_state: Option<ApplicationState>,
+    /// When the device was stopped, if it is stopped. The last heartbeat does
+    /// not age while the VM is paused, since the guest cannot respond.
+    stopped_at: Option<Instant>,
+}
+
+impl Shared {
+    /// Forgets the guest's liveness, keeping whether the device is stopped.
+    fn reset(&mut self) {
+        *self = Self {
+            stopped_at: self.stopped_at,
+            ..Default::default()
+        };
+    }
+
+    fn heartbeat(&mut self, now: Instant, sequence_number: u64, state: Option<ApplicationState>) {
+        self.sequence_number = sequence_number;
+        self.last_heartbeat = Some(now);
+        self.application_state = state;
+    }
+
+    fn stop(&mut self, now: Instant) {
+        self.stopped_at.get_or_insert(now);
+    }
+
+    fn start(&mut self, now: Instant) {
+        if let Some(stopped_at) = self.stopped_at.take() {
+            if let Some(last_heartbeat) = &mut self.last_heartbeat {
+                *last_heartbeat += now.saturating_duration_since(stopped_at);
+            }
+        }
+    }
+
+    fn status(&self) -> HeartbeatStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> HeartbeatStatus {
+        let now = self.stopped_at.unwrap_or(now);
+        let last_heartbeat_age = self
+            .last_heartbeat
+            .map(|t| now.saturating_duration_since(t));
+        let state = match last_heartbeat_age {
+            None => HeartbeatState::NoContact,
+            Some(age) if age > HEARTBEAT_TIMEOUT => HeartbeatState::LostCommunication,
+            Some(_) => match self.application_state {
+                Some(ApplicationState::CRITICAL) => HeartbeatState::Critical,
+                Some(ApplicationState::STOPPED) => HeartbeatState::Stopped,
+                // Older guests do not report their state, so any response
+                // means the guest is healthy.
+                _ => HeartbeatState::Healthy,
+            },
+        };
+        HeartbeatStatus {
+            state,
+            sequence_number: self.sequence_number,
+            last_heartbeat_age,
+        }
+    }
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct HeartbeatChannel {
+    #[inspect(mut)]
+    pipe: MessagePipe<GpadlRingMem>,
+    state: ChannelState,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    #[inspect(skip)]
+    shared: Arc<Mutex<Shared>>,
+    /// The sequence number of the next request.
+    sequence_number: u64,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    SendVersion,
+    WaitVersion,
+    Ready {
+        #[inspect(display)]
+        framework_version: hyperv_ic_protocol::Version,
+        #[inspect(display)]
+        message_version: hyperv_ic_protocol::Version,
+        state: ReadyState,
+    },
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    SendHeartbeat,
+    WaitHeartbeat,
+    Idle,
+}
+
+impl HeartbeatIc {
+    /// Returns a new heartbeat IC, using `recv` to receive status requests.
+    pub fn new(driver_source: &VmTaskDriverSource, recv: mesh::Receiver<HeartbeatRpc>) -> Self {
+        let driver = driver_source.simple();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let rpc_task = driver.spawn("heartbeat-ic-rpc", handle_rpcs(recv, shared.clone()));
+        Self {
+            driver,
+            shared,
+            _rpc_task: rpc_task,
+        }
+    }
+
+    fn open_channel(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        restore_state: Option<(ChannelState, u64)>,
+    ) -> Result<HeartbeatChannel, ChannelOpenError> {
+        let pipe = MessagePipe::new(channel)?;
+        let (state, sequence_number) = restore_state.unwrap_or((ChannelState::SendVersion, 0));
+        Ok(HeartbeatChannel {
+            pipe,
+            state,
+            timer: PolledTimer::new(&self.driver),
+            shared: self.shared.clone(),
+            sequence_number,
+        })
+    }
+}
+
+async fn handle_rpcs(mut recv: mesh::Receiver<HeartbeatRpc>, shared: Arc<Mutex<Shared>>) {
+    while let Some(rpc) = recv.next().await {
+        match rpc {
+            HeartbeatRpc::Query(rpc) => rpc.handle_sync(|()| shared.lock().status()),
+        }
+    }
+}
+
+impl HeartbeatChannel {
+    async fn process(&mut self) -> Result<(), Error> {
+        loop {
+            self.process_state_machine().await?;
+        }
+    }
+
+    async fn process_state_machine(&mut self) -> Result<(), Error> {
+        match self.state {
+            ChannelState::SendVersion => {
+                send_version_request(&mut self.pipe, FRAMEWORK_VERSIONS, HEARTBEAT_VERSIONS)
+                    .await?;
+
+                self.state = ChannelState::WaitVersion;
+            }
+            ChannelState::WaitVersion => {
+                let (framework_version, message_version) =
+                    read_version_response(&mut self.pipe).await?;
+
+                self.state = ChannelState::Ready {
+                    framework_version,
+                    message_version,
+                    state: ReadyState::SendHeartbeat,
+                };
+            }
+            ChannelState::Ready {
+                ref mut state,
+                framework_version,
+                message_version,
+            } => match state {
+                ReadyState::SendHeartbeat => {
+                    let message = HeartbeatMessage {
+                        sequence_number: self.sequence_number,
+                        ..FromZeroes::new_zeroed()
+                    };
+                    send_request(
+                        &mut self.pipe,
+                        framework_version,
+                        message_version,
+                        hyperv_ic_protocol::MessageType::HEARTBEAT,
+                        message.as_bytes(),
+                    )
+                    .await?;
+
+                    self.sequence_number = self.sequence_number.wrapping_add(1);
+                    *state = ReadyState::WaitHeartbeat;
+                }
+                ReadyState::WaitHeartbeat => {
+                    let response = (
+                        read_response(&mut self.pipe).map(Some),
+                        self.timer.sleep(HEARTBEAT_INTERVAL).map(|()| None),
+                    )
+                        .race()
+                        .await;
+
+                    match response.transpose()? {
+                        Some((status, buf)) => {
+                            if status != 0 {
+                                tracelimit::warn_ratelimited!(status, "heartbeat failed");
+                            } else {
+                                let message = HeartbeatMessage::read_from_prefix(buf.as_slice())
+                                    .ok_or(Error::TruncatedMessage)?;
+                                // The application state was added in version 3.1.
+                                let application_state =
+                                    ((message_version.major, message_version.minor) >= (3, 1))
+                                        .then_some(message.application_state);
+                                self.shared.lock().heartbeat(
+                                    Instant::now(),
+                                    message.sequence_number,
+                                    application_state,
+                                );
+                            }
+                            *state = ReadyState::Idle;
+                        }
+                        None => {
+                            // The guest did not respond in time. Send another
+                            // request; a late response still counts as a
+                            // heartbeat.
+                            *state = ReadyState::SendHeartbeat;
+                        }
+                    }
+                }
+                ReadyState::Idle => {
+                    self.timer.sleep(HEARTBEAT_INTERVAL).await;
+                    *state = ReadyState::SendHeartbeat;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for HeartbeatIc {
+    type SavedState = save_restore::state::SavedState;
+    type Runner = HeartbeatChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "heartbeat_ic".to_owned(),
+            instance_id: hyperv_ic_protocol::heartbeat::INSTANCE_ID,
+            interface_id: hyperv_ic_protocol::heartbeat::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        let status = self.shared.lock().status();
+        req.respond()
+            .field("state", inspect::AsDebug(status.state))
+            .field("sequence_number", status.sequence_number)
+            .field(
+                "last_heartbeat_age_ms",
+                status.last_heartbeat_age.map(|age| age.as_millis() as u64),
+            )
+            .merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        self.open_channel(channel, None)
+    }
+
+    async fn close(&mut self) {
+        // The guest is no longer participating.
+        self.shared.lock().reset();
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        self.shared.lock().start(Instant::now());
+        let result = stop
+            .until_stopped(async {
+                match runner.process().await {
+                    Ok(()) => {}
+                    Err(err) => {
+                        tracing::error!(
+                            error = &err as &dyn std::error::Error,
+                            "heartbeat ic error"
+                        )
+                    }
+                }
+            })
+            .await;
+        if result.is_err() {
+            self.shared.lock().stop(Instant::now());
+        }
+        result
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        Some(self)
+    }
+}
+
+mod save_restore {
+    use super::*;
+
+    pub mod state {
+        use mesh::payload::Protobuf;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Copy, Clone, Eq, PartialEq, Protobuf)]
+        #[mesh(package = "heartbeat_ic")]
+        pub struct Version {
+            #[mesh(1)]
+            pub major: u16,
+            #[mesh(2)]
+            pub minor: u16,
+        }
+
+        impl From<hyperv_ic_protocol::Version> for Version {
+            fn from(version: hyperv_ic_protocol::Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        impl From<Version> for hyperv_ic_protocol::Version {
+            fn from(version: Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "heartbeat_ic")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub version: Option<(Version, Version)>,
+            #[mesh(2)]
+            pub waiting_on_version: bool,
+            #[mesh(3)]
+            pub waiting_on_heartbeat: bool,
+            #[mesh(4)]
+            pub sequence_number: u64,
+        }
+    }
+
+    impl ChannelState {
+        pub(super) fn save(&self, sequence_number: u64) -> state::SavedState {
+            let (version, waiting_on_heartbeat) = if let ChannelState::Ready {
+                framework_version,
+                message_version,
+                state,
+            } = self
+            {
+                (
+                    Some(((*framework_version).into(), (*message_version).into())),
+                    matches!(state, ReadyState::WaitHeartbeat),
+                )
+            } else {
+                (None, false)
+            };
+            let waiting_on_version = matches!(self, ChannelState::WaitVersion);
+            state::SavedState {
+                version,
+                waiting_on_version,
+                waiting_on_heartbeat,
+                sequence_number,
+            }
+        }
+
+        pub(super) fn restore(saved_state: &state::SavedState) -> Self {
+            if let Some((framework, message)) = saved_state.version {
+                let state = if saved_state.waiting_on_heartbeat {
+                    ReadyState::WaitHeartbeat
+                } else {
+                    ReadyState::SendHeartbeat
+                };
+                ChannelState::Ready {
+                    framework_version: framework.into(),
+                    message_version: message.into(),
+                    state,
+                }
+            } else if saved_state.waiting_on_version {
+                ChannelState::WaitVersion
+            } else {
+                ChannelState::SendVersion
+            }
+        }
+    }
+
+    impl SaveRestoreSimpleVmbusDevice for HeartbeatIc {
+        fn save_open(&mut self, runner: &Self::Runner) -> state::SavedState {
+            runner.state.save(runner.sequence_number)
+        }
+
+        fn restore_open(
+            &mut self,
+            saved_state: Self::SavedState,
+            channel: RawAsyncChannel<GpadlRingMem>,
+        ) -> Result<Self::Runner, ChannelOpenError> {
+            // The guest's liveness is not saved, so it is learned again from
+            // the next heartbeat.
+            self.shared.lock().reset();
+            let state = ChannelState::restore(&saved_state);
+            self.open_channel(channel, Some((state, saved_state.sequence_number)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChannelState;
+    use super::ReadyState;
+    use super::Shared;
+    use super::HEARTBEAT_TIMEOUT;
+    use hyperv_ic_protocol::heartbeat::ApplicationState;
+    use hyperv_ic_resources::heartbeat::HeartbeatState;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn status() {
+        let t0 = Instant::now();
+        let mut shared = Shared::default();
+        assert_eq!(shared.status_at(t0).state, HeartbeatState::NoContact);
+        assert_eq!(shared.status_at(t0).last_heartbeat_age, None);
+
+        // Older guests do not report their state.
+        shared.heartbeat(t0, 5, None);
+        let status = shared.status_at(t0 + Duration::from_secs(1));
+        assert_eq!(status.state, HeartbeatState::Healthy);
+        assert_eq!(status.sequence_number, 5);
+        assert_eq!(status.last_heartbeat_age, Some(Duration::from_secs(1)));
+
+        shared.heartbeat(t0, 6, Some(ApplicationState::CRITICAL));
+        assert_eq!(shared.status_at(t0).state, HeartbeatState::Critical);
+        shared.heartbeat(t0, 7, Some(ApplicationState::STOPPED));
+        assert_eq!(shared.status_at(t0).state, HeartbeatState::Stopped);
+        shared.heartbeat(t0, 8, Some(ApplicationState::HEALTHY));
+        assert_eq!(shared.status_at(t0).state, HeartbeatState::Healthy);
+
+        let late = t0 + HEARTBEAT_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(
+            shared.status_at(late).state,
+            HeartbeatState::LostCommunication
+        );
+
+        shared.reset();
+        assert_eq!(shared.status_at(late).state, HeartbeatState::NoContact);
+        assert_eq!(shared.status_at(late).sequence_number, 0);
+    }
+
+    #[test]
+    fn no_aging_while_stopped() {
+        let t0 = Instant::now();
+        let secs = |n| t0 + Duration::from_secs(n);
+        let mut shared = Shared::default();
+        shared.heartbeat(t0, 1, None);
+
+        // A long pause does not count against the guest.
+        shared.stop(secs(1));
+        let status = shared.status_at(secs(60));
+        assert_eq!(status.state, HeartbeatState::Healthy);
+        assert_eq!(status.last_heartbeat_age, Some(Duration::from_secs(1)));
+
+        // Resetting, as when the channel closes, keeps the device stopped.
+        shared.reset();
+        shared.heartbeat(secs(1), 2, None);
+        assert_eq!(
+            shared.status_at(secs(60)).last_heartbeat_age,
+            Some(Duration::ZERO)
+        );
+        shared.reset();
+        shared.heartbeat(t0, 1, None);
+
+        // Aging resumes when the device starts again.
+        shared.start(secs(60));
+        assert_eq!(
+            shared.status_at(secs(62)).last_heartbeat_age,
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            shared.status_at(secs(70)).state,
+            HeartbeatState::LostCommunication
+        );
+
+        // Starting without having stopped changes nothing.
+        shared.start(secs(70));
+        assert_eq!(
+            shared.status_at(secs(62)).last_heartbeat_age,
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn save_restore() {
+        let version = hyperv_ic_protocol::Version { major: 3, minor: 1 };
+        let ready = |state| ChannelState::Ready {
+            framework_version: version,
+            message_version: version,
+            state,
+        };
+
+        let restored = ChannelState::restore(&ChannelState::SendVersion.save(0));
+        assert!(matches!(restored, ChannelState::SendVersion));
+        let restored = ChannelState::restore(&ChannelState::WaitVersion.save(0));
+        assert!(matches!(restored, ChannelState::WaitVersion));
+
+        let saved = ready(ReadyState::WaitHeartbeat).save(9);
+        assert_eq!(saved.sequence_number, 9);
+        assert!(matches!(
+            ChannelState::restore(&saved),
+            ChannelState::Ready {
+                framework_version,
+                message_version,
+                state: ReadyState::WaitHeartbeat,
+            } if (framework_version.major, framework_version.minor) == (3, 1)
+                && (message_version.major, message_version.minor) == (3, 1)
+        ));
+
+        // An idle channel sends its next heartbeat right away.
+        for state in [ReadyState::SendHeartbeat, ReadyState::Idle] {
+            assert!(matches!(
+                ChannelState::restore(&ready(state).save(0)),
+                ChannelState::Ready {
+                    state: ReadyState::SendHeartbeat,
+                    ..
+                }
+            ));
+        }
+    }
+}
@@ -0,0 +1,895 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The KVP (key-value pair) exchange IC.
+//!
+//! The guest maintains several pools of string keys and values. The host can
+//! set and delete values in the external pool to pass configuration to the
+//! guest, and can read values from the other pools, including the auto pool
+//! in which the guest reports information such as its OS version and IP
+//! addresses.
+
+use crate::common::read_response;
+use crate::common::read_version_response;
+use crate::common::send_request;
+use crate::common::send_version_request;
+use crate::common::Error;
+use async_trait::async_trait;
+use futures::stream::once;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures_concurrency::future::Race;
+use futures_concurrency::stream::Merge;
+use hyperv_ic_protocol::kvp;
+use hyperv_ic_protocol::kvp::FRAMEWORK_VERSIONS;
+use hyperv_ic_protocol::kvp::KVP_VERSIONS;
+use hyperv_ic_resources::kvp::KeyValue;
+use hyperv_ic_resources::kvp::KvpPool;
+use hyperv_ic_resources::kvp::KvpRpc;
+use hyperv_ic_resources::kvp::Value;
+use inspect::Inspect;
+use inspect::InspectMut;
+use mesh::error::RemoteError;
+use mesh::rpc::Rpc;
+use mesh::OneshotSender;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+use task_control::Cancelled;
+use task_control::StopTask;
+use thiserror::Error;
+use vmbus_async::pipe::MessagePipe;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_channel::RawAsyncChannel;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// The maximum number of entries to read when enumerating a pool, to bound
+/// the work done for a misbehaving guest.
+const MAX_ENUMERATE_ENTRIES: usize = 4096;
+
+/// The time to wait for the guest to respond to a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A KVP IC device.
+pub struct KvpIc {
+    driver: VmTaskDriver,
+    /// Requests forwarded by the RPC task while the channel is open.
+    recv: mesh::Receiver<Request>,
+    /// Whether the channel is open, shared with the RPC task so that it can
+    /// fail requests while the channel is closed.
+    open: Arc<Mutex<bool>>,
+    _rpc_task: Task<()>,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct KvpChannel {
+    #[inspect(mut)]
+    pipe: MessagePipe<GpadlRingMem>,
+    state: ChannelState,
+    #[inspect(with = "VecDeque::len")]
+    queue: VecDeque<Request>,
+    /// The request sent to the guest, if any. This is `None` while waiting
+    /// for a response that is to be discarded, after restore or after the
+    /// request timed out.
+    #[inspect(with = "Option::is_some")]
+    in_flight: Option<Request>,
+    #[inspect(skip)]
+    timer: PolledTimer,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    SendVersion,
+    WaitVersion,
+    Ready {
+        #[inspect(display)]
+        framework_version: hyperv_ic_protocol::Version,
+        #[inspect(display)]
+        message_version: hyperv_ic_protocol::Version,
+        state: ReadyState,
+    },
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    Ready,
+    SendRequest(#[inspect(skip)] Vec<u8>),
+    WaitResponse {
+        #[inspect(skip)]
+        deadline: Instant,
+    },
+}
+
+#[derive(Debug, Error)]
+enum RequestError {
+    #[error("the KVP IC is not ready")]
+    NotReady,
+    #[error("the KVP channel was closed")]
+    Closed,
+    #[error("the guest did not respond in time")]
+    Timeout,
+    #[error("the key is too long")]
+    KeyTooLong,
+    #[error("the value is too long")]
+    ValueTooLong,
+    #[error("the guest failed the request with status {0:#x}")]
+    Guest(u32),
+    #[error("truncated response")]
+    TruncatedResponse,
+}
+
+/// A request to the guest.
+struct Request {
+    pool: kvp::KvpPool,
+    operation: Operation,
+}
+
+enum Operation {
+    Set {
+        value: Box<kvp::Value>,
+        send: OneshotSender<Result<(), RemoteError>>,
+    },
+    Get {
+        value: Box<kvp::Value>,
+        send: OneshotSender<Result<Value, RemoteError>>,
+    },
+    Delete {
+        value: Box<kvp::Value>,
+        send: OneshotSender<Result<(), RemoteError>>,
+    },
+    Enumerate {
+        index: u32,
+        entries: Vec<KeyValue>,
+        send: OneshotSender<Result<Vec<KeyValue>, RemoteError>>,
+    },
+}
+
+impl KvpIc {
+    /// Returns a new KVP IC, using `recv` to receive requests.
+    pub fn new(driver_source: &VmTaskDriverSource, recv: mesh::Receiver<KvpRpc>) -> Self {
+        let driver = driver_source.simple();
+        let (send, request_recv) = mesh::channel();
+        let open = Arc::new(Mutex::new(false));
+        let rpc_task = driver.spawn("kvp-ic-rpc", handle_rpcs(recv, send, open.clone()));
+        Self {
+            driver,
+            recv: request_recv,
+            open,
+            _rpc_task: rpc_task,
+        }
+    }
+
+    fn open_channel(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        restore_state: Option<ChannelState>,
+    ) -> Result<KvpChannel, ChannelOpenError> {
+        let pipe = MessagePipe::new(channel)?;
+        *self.open.lock() = true;
+        Ok(KvpChannel {
+            pipe,
+            state: restore_state.unwrap_or(ChannelState::SendVersion),
+            queue: VecDeque::new(),
+            in_flight: None,
+            timer: PolledTimer::new(&self.driver),
+        })
+    }
+}
+
+async fn handle_rpcs(
+    mut recv: mesh::Receiver<KvpRpc>,
+    send: mesh::Sender<Request>,
+    open: Arc<Mutex<bool>>,
+) {
+    while let Some(rpc) = recv.next().await {
+        if let Some(request) = Request::new(rpc) {
+            // Hold the lock while sending so that `close` sees the request.
+            let is_open = open.lock();
+            if *is_open {
+                send.send(request);
+            } else {
+                request.fail(RequestError::NotReady);
+            }
+        }
+    }
+}
+
+fn fail<T>(send: OneshotSender<Result<T, RemoteError>>, err: RequestError) {
+    send.send(Err(RemoteError::new(err)));
+}
+
+fn protocol_pool(pool: KvpPool) -> kvp::KvpPool {
+    match pool {
+        KvpPool::External => kvp::KvpPool::EXTERNAL,
+        KvpPool::Guest => kvp::KvpPool::GUEST,
+        KvpPool::Auto => kvp::KvpPool::AUTO,
+        KvpPool::AutoExternal => kvp::KvpPool::AUTO_EXTERNAL,
+        KvpPool::AutoInternal => kvp::KvpPool::AUTO_INTERNAL,
+    }
+}
+
+/// Writes `s` to `buf` as null-terminated UTF-16, returning the size in bytes.
+fn encode_string(s: &str, buf: &mut [u8]) -> Option<u32> {
+    let mut len = 0;
+    for c in s.encode_utf16().chain([0]) {
+        buf.get_mut(len..len + 2)?.copy_from_slice(&c.to_le_bytes());
+        len += 2;
+    }
+    Some(len as u32)
+}
+
+fn decode_string(buf: &[u8], size: u32) -> String {
+    let buf = &buf[..(size as usize).min(buf.len())];
+    let chars = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&chars)
+}
+
+fn encode_value(key: &str, value: Option<&Value>) -> Result<Box<kvp::Value>, RequestError> {
+    let mut v = Box::new(kvp::Value::new_zeroed());
+    v.key_size = encode_string(key, &mut v.key).ok_or(RequestError::KeyTooLong)?;
+    v.value_type = kvp::ValueType::STRING;
+    match value {
+        None => {}
+        Some(Value::String(s)) => {
+            v.value_size = encode_string(s, &mut v.value).ok_or(RequestError::ValueTooLong)?;
+        }
+        Some(&Value::U32(n)) => {
+            v.value_type = kvp::ValueType::U32;
+            v.value_size = size_of_val(&n) as u32;
+            n.write_to_prefix(&mut v.value[..]).unwrap();
+        }
+        Some(&Value::U64(n)) => {
+            v.value_type = kvp::ValueType::U64;
+            v.value_size = size_of_val(&n) as u32;
+            n.write_to_prefix(&mut v.value[..]).unwrap();
+        }
+    }
+    Ok(v)
+}
+
+fn decode_value(v: &kvp::Value) -> KeyValue {
+    let value = match v.value_type {
+        kvp::ValueType::U32 => Value::U32(u32::read_from_prefix(&v.value[..]).unwrap()),
+        kvp::ValueType::U64 => Value::U64(u64::read_from_prefix(&v.value[..]).unwrap()),
+        _ => Value::String(decode_string(&v.value, v.value_size)),
+    };
+    KeyValue {
+        key: decode_string(&v.key, v.key_size),
+        value,
+    }
+}
+
+impl Request {
+    /// Returns the request for `rpc`, or fails `rpc` if it is invalid.
+    fn new(rpc: KvpRpc) -> Option<Self> {
+        let (pool, operation) = match rpc {
+            KvpRpc::Set(Rpc(params, send)) => {
+                match encode_value(&params.key, Some(&params.value)) {
+                    Ok(value) => (params.pool, Operation::Set { value, send }),
+                    Err(err) => {
+                        fail(send, err);
+                        return None;
+                    }
+                }
+            }
+            KvpRpc::Get(Rpc(params, send)) => match encode_value(&params.key, None) {
+                Ok(value) => (params.pool, Operation::Get { value, send }),
+                Err(err) => {
+                    fail(send, err);
+                    return None;
+                }
+            },
+            KvpRpc::Delete(Rpc(params, send)) => match encode_value(&params.key, None) {
+                Ok(value) => (params.pool, Operation::Delete { value, send }),
+                Err(err) => {
+                    fail(send, err);
+                    return None;
+                }
+            },
+            KvpRpc::Enumerate(Rpc(params, send)) => (
+                params.pool,
+                Operation::Enumerate {
+                    index: 0,
+                    entries: Vec::new(),
+                    send,
+                },
+            ),
+        };
+        Some(Self {
+            pool: protocol_pool(pool),
+            operation,
+        })
+    }
+
+    fn fail(self, err: RequestError) {
+        match self.operation {
+            Operation::Set { send, .. } | Operation::Delete { send, .. } => fail(send, err),
+            Operation::Get { send, .. } => fail(send, err),
+            Operation::Enumerate { send, .. } => fail(send, err),
+        }
+    }
+
+    /// Returns the message to send to the guest.
+    fn message(&self) -> Vec<u8> {
+        let mut buf = vec![0; kvp::MESSAGE_SIZE];
+        let operation = match &self.operation {
+            Operation::Set { .. } => kvp::KvpOperation::SET,
+            Operation::Get { .. } => kvp::KvpOperation::GET,
+            Operation::Delete { .. } => kvp::KvpOperation::DELETE,
+            Operation::Enumerate { .. } => kvp::KvpOperation::ENUMERATE,
+        };
+        let header = kvp::KvpHeader {
+            operation,
+            pool: self.pool,
+            pad: 0,
+        };
+        header.write_to_prefix(&mut buf).unwrap();
+        let body = &mut buf[size_of::<kvp::KvpHeader>()..];
+        match &self.operation {
+            Operation::Set { value, .. } | Operation::Get { value, .. } => {
+                value.write_to_prefix(body).unwrap();
+            }
+            Operation::Delete { value, .. } => {
+                value.key_size.write_to_prefix(body).unwrap();
+                body[size_of::<u32>()..][..kvp::MAX_KEY_SIZE].copy_from_slice(&value.key);
+            }
+            Operation::Enumerate { index, .. } => {
+                index.write_to_prefix(body).unwrap();
+            }
+        }
+        buf
+    }
+
+    /// Handles the guest's response, returning the request if it needs to be
+    /// sent again.
+    fn complete(self, status: u32, buf: &[u8]) -> Option<Self> {
+        match self.operation {
+            Operation::Set { send, .. } | Operation::Delete { send, .. } => {
+                if status == 0 {
+                    send.send(Ok(()));
+                } else {
+                    fail(send, RequestError::Guest(status));
+                }
+            }
+            Operation::Get { send, .. } => {
+                if status != 0 {
+                    fail(send, RequestError::Guest(status));
+                } else if let Some(message) = kvp::KvpMessage::read_from_prefix(buf) {
+                    send.send(Ok(decode_value(&message.value).value));
+                } else {
+                    fail(send, RequestError::TruncatedResponse);
+                }
+            }
+            Operation::Enumerate {
+                index,
+                mut entries,
+                send,
+            } => {
+                if status != 0 {
+                    // Guests report errors other than the documented one at
+                    // the end of some pools, so only fail if no entries
+                    // were found.
+                    if index == 0 && status != kvp::STATUS_NO_MORE_ITEMS {
+                        fail(send, RequestError::Guest(status));
+                    } else {
+                        send.send(Ok(entries));
+                    }
+                } else if let Some(message) = kvp::EnumerateMessage::read_from_prefix(buf) {
+                    entries.push(decode_value(&message.value));
+                    if entries.len() >= MAX_ENUMERATE_ENTRIES {
+                        send.send(Ok(entries));
+                    } else {
+                        return Some(Self {
+                            pool: self.pool,
+                            operation: Operation::Enumerate {
+                                index: index + 1,
+                                entries,
+                                send,
+                            },
+                        });
+                    }
+                } else {
+                    fail(send, RequestError::TruncatedResponse);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Drop for KvpChannel {
+    fn drop(&mut self) {
+        for request in self
+            .in_flight
+            .take()
+            .into_iter()
+            .chain(self.queue.drain(..))
+        {
+            request.fail(RequestError::Closed);
+        }
+    }
+}
+
+impl KvpChannel {
+    async fn process(&mut self, ic: &mut KvpIc) -> Result<(), Error> {
+        enum Event {
+            StateMachine(Result<(), Error>),
+            Request(Request),
+        }
+
+        loop {
+            let event = pin!((
+                once(self.process_state_machine().map(Event::StateMachine)),
+                (&mut ic.recv).map(Event::Request),
+            )
+                .merge())
+            .next()
+            .await
+            .unwrap();
+            match event {
+                Event::StateMachine(r) => {
+                    r?;
+                }
+                Event::Request(request) => match self.state {
+                    ChannelState::SendVersion | ChannelState::WaitVersion => {
+                        request.fail(RequestError::NotReady)
+                    }
+                    ChannelState::Ready { .. } => self.queue.push_back(request),
+                },
+            }
+        }
+    }
+
+    async fn process_state_machine(&mut self) -> Result<(), Error> {
+        match self.state {
+            ChannelState::SendVersion => {
+                send_version_request(&mut self.pipe, FRAMEWORK_VERSIONS, KVP_VERSIONS).await?;
+
+                self.state = ChannelState::WaitVersion;
+            }
+            ChannelState::WaitVersion => {
+                let (framework_version, message_version) =
+                    read_version_response(&mut self.pipe).await?;
+
+                self.state = ChannelState::Ready {
+                    framework_version,
+                    message_version,
+                    state: ReadyState::Ready,
+                };
+            }
+            ChannelState::Ready {
+                ref mut state,
+                framework_version,
+                message_version,
+            } => match state {
+                ReadyState::Ready => {
+                    let Some(request) = self.queue.pop_front() else {
+                        return std::future::pending().await;
+                    };
+                    *state = ReadyState::SendRequest(request.message());
+                    self.in_flight = Some(request);
+                }
+                ReadyState::SendRequest(message) => {
+                    send_request(
+                        &mut self.pipe,
+                        framework_version,
+                        message_version,
+                        hyperv_ic_protocol::MessageType::KVP_EXCHANGE,
+                        message,
+                    )
+                    .await?;
+
+                    *state = ReadyState::WaitResponse {
+                        deadline: Instant::now() + REQUEST_TIMEOUT,
+                    };
+                }
+                ReadyState::WaitResponse { deadline } => {
+                    let response = (
+                        read_response(&mut self.pipe).map(Some),
+                        self.timer.sleep_until(*deadline).map(|()| None),
+                    )
+                        .race()
+                        .await;
+
+                    match response.transpose()? {
+                        Some((status, buf)) => {
+                            *state = match self
+                                .in_flight
+                                .take()
+                                .and_then(|r| r.complete(status, &buf))
+                            {
+                                Some(request) => {
+                                    let message = request.message();
+                                    self.in_flight = Some(request);
+                                    ReadyState::SendRequest(message)
+                                }
+                                None => ReadyState::Ready,
+                            };
+                        }
+                        None => {
+                            if let Some(request) = self.in_flight.take() {
+                                // Wait a while longer to discard a late
+                                // response rather than mistaking it for the
+                                // response to the next request.
+                                request.fail(RequestError::Timeout);
+                                *deadline = Instant::now() + REQUEST_TIMEOUT;
+                            } else {
+                                *state = ReadyState::Ready;
+                            }
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for KvpIc {
+    type SavedState = save_restore::state::SavedState;
+    type Runner = KvpChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "kvp_ic".to_owned(),
+            instance_id: kvp::INSTANCE_ID,
+            interface_id: kvp::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        self.open_channel(channel, None)
+    }
+
+    async fn close(&mut self) {
+        *self.open.lock() = false;
+        // Fail the requests forwarded before the channel closed.
+        while let Ok(request) = self.recv.try_recv() {
+            request.fail(RequestError::Closed);
+        }
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            match runner.process(self).await {
+                Ok(()) => {}
+                Err(err) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "kvp ic error")
+                }
+            }
+        })
+        .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        Some(self)
+    }
+}
+
+mod save_restore {
+    use super::*;
+
+    pub mod state {
+        use mesh::payload::Protobuf;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Copy, Clone, Eq, PartialEq, Protobuf)]
+        #[mesh(package = "kvp_ic")]
+        pub struct Version {
+            #[mesh(1)]
+            pub major: u16,
+            #[mesh(2)]
+            pub minor: u16,
+        }
+
+        impl From<hyperv_ic_protocol::Version> for Version {
+            fn from(version: hyperv_ic_protocol::Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        impl From<Version> for hyperv_ic_protocol::Version {
+            fn from(version: Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        /// Requests in flight are not saved; their callers see the
+        /// requests fail.
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "kvp_ic")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub version: Option<(Version, Version)>,
+            #[mesh(2)]
+            pub waiting_on_version: bool,
+            #[mesh(3)]
+            pub waiting_on_response: bool,
+        }
+    }
+
+    impl SaveRestoreSimpleVmbusDevice for KvpIc {
+        fn save_open(&mut self, runner: &Self::Runner) -> state::SavedState {
+            let (version, waiting_on_response) = if let ChannelState::Ready {
+                framework_version,
+                message_version,
+                state,
+            } = &runner.state
+            {
+                (
+                    Some(((*framework_version).into(), (*message_version).into())),
+                    matches!(state, ReadyState::WaitResponse { .. }),
+                )
+            } else {
+                (None, false)
+            };
+            let waiting_on_version = matches!(runner.state, ChannelState::WaitVersion);
+            state::SavedState {
+                version,
+                waiting_on_version,
+                waiting_on_response,
+            }
+        }
+
+        fn restore_open(
+            &mut self,
+            saved_state: Self::SavedState,
+            channel: RawAsyncChannel<GpadlRingMem>,
+        ) -> Result<Self::Runner, ChannelOpenError> {
+            let state = if let Some((framework, message)) = saved_state.version {
+                // The response to the request in flight, if any, is
+                // discarded.
+                let state = if saved_state.waiting_on_response {
+                    ReadyState::WaitResponse {
+                        deadline: Instant::now() + REQUEST_TIMEOUT,
+                    }
+                } else {
+                    ReadyState::Ready
+                };
+                ChannelState::Ready {
+                    framework_version: framework.into(),
+                    message_version: message.into(),
+                    state,
+                }
+            } else if saved_state.waiting_on_version {
+                ChannelState::WaitVersion
+            } else {
+                ChannelState::SendVersion
+            };
+            self.open_channel(channel, Some(state))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_string;
+    use super::decode_value;
+    use super::encode_string;
+    use super::encode_value;
+    use super::Request;
+    use super::RequestError;
+    use super::MAX_ENUMERATE_ENTRIES;
+    use hyperv_ic_protocol::kvp;
+    use hyperv_ic_resources::kvp::EnumerateParams;
+    use hyperv_ic_resources::kvp::GetParams;
+    use hyperv_ic_resources::kvp::KvpPool;
+    use hyperv_ic_resources::kvp::KvpRpc;
+    use hyperv_ic_resources::kvp::Value;
+    use mesh::rpc::Rpc;
+    use pal_async::async_test;
+    use zerocopy::AsBytes;
+    use zerocopy::FromBytes;
+    use zerocopy::FromZeroes;
+
+    #[test]
+    fn strings() {
+        let mut buf = [0xff; 16];
+        assert_eq!(encode_string("héllo", &mut buf), Some(12));
+        assert_eq!(&buf[10..12], &[0, 0]);
+        assert_eq!(decode_string(&buf, 12), "héllo");
+        // Decoding stops at the null terminator or the reported size.
+        assert_eq!(decode_string(&buf, 16), "héllo");
+        assert_eq!(decode_string(&buf, 4), "hé");
+        assert_eq!(decode_string(&buf, 100), "héllo");
+
+        // The terminator must fit.
+        assert_eq!(encode_string("abcdefgh", &mut buf), None);
+        assert_eq!(encode_string("abcdefg", &mut buf), Some(16));
+    }
+
+    #[test]
+    fn values() {
+        for value in [
+            Value::String("value".into()),
+            Value::U32(0x12345678),
+            Value::U64(0x123456789abcdef0),
+        ] {
+            let encoded = encode_value("key", Some(&value)).unwrap();
+            let decoded = decode_value(&encoded);
+            assert_eq!(decoded.key, "key");
+            assert_eq!(decoded.value, value);
+        }
+
+        let encoded = encode_value("key", None).unwrap();
+        assert_eq!(encoded.value_size, 0);
+
+        let max_key = "k".repeat(kvp::MAX_KEY_SIZE / 2 - 1);
+        assert!(encode_value(&max_key, None).is_ok());
+        assert!(matches!(
+            encode_value(&format!("{max_key}k"), None),
+            Err(RequestError::KeyTooLong)
+        ));
+        let max_value = "v".repeat(kvp::MAX_VALUE_SIZE / 2 - 1);
+        assert!(encode_value("key", Some(&Value::String(max_value.clone()))).is_ok());
+        assert!(matches!(
+            encode_value("key", Some(&Value::String(format!("{max_value}v")))),
+            Err(RequestError::ValueTooLong)
+        ));
+    }
+
+    #[test]
+    fn delete_message() {
+        let (send, _recv) = mesh::oneshot();
+        let request = Request::new(KvpRpc::Delete(Rpc(
+            hyperv_ic_resources::kvp::DeleteParams {
+                pool: KvpPool::Guest,
+                key: "key".into(),
+            },
+            send,
+        )))
+        .unwrap();
+        let message = request.message();
+        assert_eq!(message.len(), kvp::MESSAGE_SIZE);
+        let message = kvp::DeleteMessage::read_from_prefix(&message[..]).unwrap();
+        assert_eq!(message.header.operation, kvp::KvpOperation::DELETE);
+        assert_eq!(message.header.pool, kvp::KvpPool::GUEST);
+        assert_eq!(decode_string(&message.key, message.key_size), "key");
+    }
+
+    fn enumerate_response(index: u32, key: &str, value: Value) -> Vec<u8> {
+        let message = kvp::EnumerateMessage {
+            header: kvp::KvpHeader {
+                operation: kvp::KvpOperation::ENUMERATE,
+                pool: kvp::KvpPool::AUTO,
+                pad: 0,
+            },
+            index,
+            value: *encode_value(key, Some(&value)).unwrap(),
+        };
+        message.as_bytes().to_vec()
+    }
+
+    #[async_test]
+    async fn enumerate() {
+        let (send, recv) = mesh::oneshot();
+        let mut request = Request::new(KvpRpc::Enumerate(Rpc(
+            EnumerateParams {
+                pool: KvpPool::Auto,
+            },
+            send,
+        )))
+        .unwrap();
+
+        // Each entry is requested in turn until the guest reports the end of
+        // the pool.
+        for (index, key) in ["a", "b"].into_iter().enumerate() {
+            let message = request.message();
+            let message = kvp::EnumerateMessage::read_from_prefix(&message[..]).unwrap();
+            assert_eq!(message.header.operation, kvp::KvpOperation::ENUMERATE);
+            assert_eq!(message.index, index as u32);
+            request = request
+                .complete(0, &enumerate_response(index as u32, key, Value::U32(1)))
+                .unwrap();
+        }
+        assert!(request.complete(kvp::STATUS_NO_MORE_ITEMS, &[]).is_none());
+        let entries = recv.await.unwrap().unwrap();
+        let keys = entries.iter().map(|e| e.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "b"]);
+    }
+
+    #[async_test]
+    async fn enumerate_limits() {
+        // Other errors fail the request only if there are no entries.
+        let (send, recv) = mesh::oneshot();
+        let request = Request::new(KvpRpc::Enumerate(Rpc(
+            EnumerateParams {
+                pool: KvpPool::Auto,
+            },
+            send,
+        )))
+        .unwrap();
+        assert!(request.complete(0x80004005, &[]).is_none());
+        assert!(recv.await.unwrap().is_err());
+
+        // Enumeration stops after the maximum number of entries.
+        let (send, recv) = mesh::oneshot();
+        let mut request = Request::new(KvpRpc::Enumerate(Rpc(
+            EnumerateParams {
+                pool: KvpPool::Auto,
+            },
+            send,
+        )))
+        .unwrap();
+        let response = enumerate_response(0, "key", Value::U32(1));
+        for _ in 1..MAX_ENUMERATE_ENTRIES {
+            request = request.complete(0, &response).unwrap();
+        }
+        assert!(request.complete(0, &response).is_none());
+        assert_eq!(recv.await.unwrap().unwrap().len(), MAX_ENUMERATE_ENTRIES);
+    }
+
+    #[async_test]
+    async fn get() {
+        let get = |send| {
+            Request::new(KvpRpc::Get(Rpc(
+                GetParams {
+                    pool: KvpPool::External,
+                    key: "key".into(),
+                },
+                send,
+            )))
+            .unwrap()
+        };
+
+        let (send, recv) = mesh::oneshot();
+        let mut response = kvp::KvpMessage::new_zeroed();
+        response.value = *encode_value("key", Some(&Value::String("value".into()))).unwrap();
+        assert!(get(send).complete(0, response.as_bytes()).is_none());
+        assert_eq!(recv.await.unwrap().unwrap(), Value::String("value".into()));
+
+        let (send, recv) = mesh::oneshot();
+        assert!(get(send).complete(0, &[0; 4]).is_none());
+        assert!(recv.await.unwrap().is_err());
+
+        let (send, recv) = mesh::oneshot();
+        assert!(get(send).complete(1, response.as_bytes()).is_none());
+        assert!(recv.await.unwrap().is_err());
+    }
+}
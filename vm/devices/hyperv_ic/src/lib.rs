@@ -16,5 +16,9 @@
 #![warn(missing_docs)]
 #![forbid(unsafe_code)]
 
+mod common;
+pub mod heartbeat;
+pub mod kvp;
 pub mod resolver;
 pub mod shutdown;
+pub mod timesync;
@@ -3,15 +3,23 @@
 
 //! Resource resolvers for the ICs.
 
+use crate::heartbeat::HeartbeatIc;
+use crate::kvp::KvpIc;
 use crate::shutdown::ShutdownIc;
+use crate::timesync::TimesyncIc;
+use hyperv_ic_resources::heartbeat::HeartbeatIcHandle;
+use hyperv_ic_resources::kvp::KvpIcHandle;
 use hyperv_ic_resources::shutdown::ShutdownIcHandle;
+use hyperv_ic_resources::timesync::TimesyncIcHandle;
 use std::convert::Infallible;
+use std::sync::Arc;
 use vm_resource::declare_static_resolver;
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_resource::ResolveResource;
 use vmbus_channel::resources::ResolveVmbusDeviceHandleParams;
 use vmbus_channel::resources::ResolvedVmbusDevice;
 use vmbus_channel::simple::SimpleDeviceWrapper;
+use vmcore::reference_time_source::VmTimeReferenceTimeSource;
 
 /// Resource resolver for the ICs.
 pub struct IcResolver;
@@ -19,6 +27,9 @@
 declare_static_resolver! {
     IcResolver,
     (VmbusDeviceHandleKind, ShutdownIcHandle),
+    (VmbusDeviceHandleKind, HeartbeatIcHandle),
+    (VmbusDeviceHandleKind, KvpIcHandle),
+    (VmbusDeviceHandleKind, TimesyncIcHandle),
 }
 
 impl ResolveResource<VmbusDeviceHandleKind, ShutdownIcHandle> for IcResolver {
@@ -36,3 +47,55 @@ fn resolve(
         )
     }
 }
+
+impl ResolveResource<VmbusDeviceHandleKind, HeartbeatIcHandle> for IcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: HeartbeatIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(SimpleDeviceWrapper::new(
+            input.driver_source.simple(),
+            HeartbeatIc::new(input.driver_source, resource.recv),
+        )
+        .into())
+    }
+}
+
+impl ResolveResource<VmbusDeviceHandleKind, KvpIcHandle> for IcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: KvpIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(SimpleDeviceWrapper::new(
+            input.driver_source.simple(),
+            KvpIc::new(input.driver_source, resource.recv),
+        )
+        .into())
+    }
+}
+
+impl ResolveResource<VmbusDeviceHandleKind, TimesyncIcHandle> for IcResolver {
+    type Output = ResolvedVmbusDevice;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        TimesyncIcHandle: TimesyncIcHandle,
+        input: ResolveVmbusDeviceHandleParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let ref_time = Arc::new(VmTimeReferenceTimeSource::new(input.vmtime));
+        Ok(SimpleDeviceWrapper::new(
+            input.driver_source.simple(),
+            TimesyncIc::new(input.driver_source, ref_time),
+        )
+        .into())
+    }
+}
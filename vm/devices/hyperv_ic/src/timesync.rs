@@ -0,0 +1,473 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The timesync IC.
+//!
+//! The host periodically sends the guest samples of its UTC time, which the
+//! guest uses to keep its clock in sync. When the guest's clock is known to be
+//! wrong, such as after the channel is opened, after restore, or after the VM
+//! has been paused, the host instead asks the guest to set its clock
+//! immediately.
+
+use crate::common::read_response;
+use crate::common::read_version_response;
+use crate::common::send_request;
+use crate::common::send_version_request;
+use crate::common::Error;
+use async_trait::async_trait;
+use hyperv_ic_protocol::timesync::TimesyncFlags;
+use hyperv_ic_protocol::timesync::TimesyncMessage;
+use hyperv_ic_protocol::timesync::TimesyncMessageV4;
+use hyperv_ic_protocol::timesync::EPOCH_DELTA_100NS;
+use hyperv_ic_protocol::timesync::FRAMEWORK_VERSIONS;
+use hyperv_ic_protocol::timesync::TIMESYNC_VERSIONS;
+use hyperv_ic_protocol::timesync::TIMESYNC_VERSION_4;
+use inspect::Inspect;
+use inspect::InspectMut;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_async::pipe::MessagePipe;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_channel::RawAsyncChannel;
+use vmcore::reference_time_source::ReferenceTimeSource;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::AsBytes;
+use zerocopy::FromZeroes;
+
+/// The interval between time samples.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The difference between elapsed host time and elapsed reference time since
+/// the last sample above which the guest's clock is assumed to have stopped,
+/// e.g. because the VM was paused.
+const SYNC_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// A timesync IC device.
+pub struct TimesyncIc {
+    driver: VmTaskDriver,
+    ref_time: Arc<dyn ReferenceTimeSource>,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct TimesyncChannel {
+    #[inspect(mut)]
+    pipe: MessagePipe<GpadlRingMem>,
+    state: ChannelState,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    #[inspect(skip)]
+    ref_time: Arc<dyn ReferenceTimeSource>,
+    #[inspect(flatten)]
+    sync: SyncState,
+}
+
+/// Tracks whether the guest's clock must be set rather than adjusted.
+#[derive(Inspect)]
+struct SyncState {
+    /// Whether the next message should ask the guest to set its clock.
+    sync_pending: bool,
+    /// The host time and reference time at the last sample.
+    #[inspect(skip)]
+    last_sample: Option<(Instant, u64)>,
+}
+
+impl SyncState {
+    /// Returns the state for a newly opened or restored channel, for which
+    /// the guest's clock is unknown.
+    fn new() -> Self {
+        Self {
+            sync_pending: true,
+            last_sample: None,
+        }
+    }
+
+    /// Returns the flags for a sample taken at host time `host_now` and
+    /// reference time `ref_now`.
+    fn flags(&mut self, host_now: Instant, ref_now: u64) -> TimesyncFlags {
+        // If the reference time did not advance with the host time, then the
+        // VM was paused and the guest's clock is behind.
+        if let Some((last_host, last_ref)) = self.last_sample {
+            let host_elapsed = host_now - last_host;
+            let ref_elapsed =
+                Duration::from_nanos(ref_now.wrapping_sub(last_ref).wrapping_mul(100));
+            if host_elapsed.abs_diff(ref_elapsed) > SYNC_THRESHOLD {
+                self.sync_pending = true;
+            }
+        }
+        TimesyncFlags::new()
+            .with_sync(self.sync_pending)
+            .with_sample(!self.sync_pending)
+    }
+
+    /// Records that the sample was sent to the guest.
+    fn sent(&mut self, host_now: Instant, ref_now: u64) {
+        if self.sync_pending {
+            tracing::debug!("requested guest time sync");
+        }
+        self.sync_pending = false;
+        self.last_sample = Some((host_now, ref_now));
+    }
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    SendVersion,
+    WaitVersion,
+    Ready {
+        #[inspect(display)]
+        framework_version: hyperv_ic_protocol::Version,
+        #[inspect(display)]
+        message_version: hyperv_ic_protocol::Version,
+        state: ReadyState,
+    },
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    SendSample,
+    WaitResponse,
+    Idle,
+}
+
+impl TimesyncIc {
+    /// Returns a new timesync IC, using `ref_time` as the guest's reference
+    /// time.
+    pub fn new(driver_source: &VmTaskDriverSource, ref_time: Arc<dyn ReferenceTimeSource>) -> Self {
+        Self {
+            driver: driver_source.simple(),
+            ref_time,
+        }
+    }
+
+    fn open_channel(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        restore_state: Option<ChannelState>,
+    ) -> Result<TimesyncChannel, ChannelOpenError> {
+        let pipe = MessagePipe::new(channel)?;
+        Ok(TimesyncChannel {
+            pipe,
+            state: restore_state.unwrap_or(ChannelState::SendVersion),
+            timer: PolledTimer::new(&self.driver),
+            ref_time: self.ref_time.clone(),
+            sync: SyncState::new(),
+        })
+    }
+}
+
+/// Returns the host's UTC time, in 100ns units since the Windows epoch.
+fn host_time_100ns() -> u64 {
+    let since_unix_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    EPOCH_DELTA_100NS + (since_unix_epoch.as_nanos() / 100) as u64
+}
+
+impl TimesyncChannel {
+    async fn process(&mut self) -> Result<(), Error> {
+        loop {
+            self.process_state_machine().await?;
+        }
+    }
+
+    async fn process_state_machine(&mut self) -> Result<(), Error> {
+        match self.state {
+            ChannelState::SendVersion => {
+                send_version_request(&mut self.pipe, FRAMEWORK_VERSIONS, TIMESYNC_VERSIONS).await?;
+
+                self.state = ChannelState::WaitVersion;
+            }
+            ChannelState::WaitVersion => {
+                let (framework_version, message_version) =
+                    read_version_response(&mut self.pipe).await?;
+
+                self.state = ChannelState::Ready {
+                    framework_version,
+                    message_version,
+                    state: ReadyState::SendSample,
+                };
+            }
+            ChannelState::Ready {
+                ref mut state,
+                framework_version,
+                message_version,
+            } => match state {
+                ReadyState::SendSample => {
+                    let host_now = Instant::now();
+                    let ref_now = self.ref_time.now_100ns();
+                    let parent_time = host_time_100ns();
+                    let flags = self.sync.flags(host_now, ref_now);
+
+                    let message = if (message_version.major, message_version.minor)
+                        >= (TIMESYNC_VERSION_4.major, TIMESYNC_VERSION_4.minor)
+                    {
+                        TimesyncMessageV4 {
+                            parent_time,
+                            vm_reference_time: ref_now,
+                            flags,
+                            ..FromZeroes::new_zeroed()
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    } else {
+                        TimesyncMessage {
+                            parent_time,
+                            flags,
+                            ..FromZeroes::new_zeroed()
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    };
+                    send_request(
+                        &mut self.pipe,
+                        framework_version,
+                        message_version,
+                        hyperv_ic_protocol::MessageType::TIME_SYNC,
+                        &message,
+                    )
+                    .await?;
+
+                    self.sync.sent(host_now, ref_now);
+                    *state = ReadyState::WaitResponse;
+                }
+                ReadyState::WaitResponse => {
+                    let (status, _) = read_response(&mut self.pipe).await?;
+                    if status != 0 {
+                        tracelimit::warn_ratelimited!(status, "time sync failed");
+                    }
+                    *state = ReadyState::Idle;
+                }
+                ReadyState::Idle => {
+                    // Sample immediately if a sync is pending or if the
+                    // deadline passed while the VM was stopped.
+                    if !self.sync.sync_pending {
+                        if let Some((last_host, _)) = self.sync.last_sample {
+                            self.timer.sleep_until(last_host + SAMPLE_INTERVAL).await;
+                        }
+                    }
+                    *state = ReadyState::SendSample;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for TimesyncIc {
+    type SavedState = save_restore::state::SavedState;
+    type Runner = TimesyncChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "timesync_ic".to_owned(),
+            instance_id: hyperv_ic_protocol::timesync::INSTANCE_ID,
+            interface_id: hyperv_ic_protocol::timesync::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        self.open_channel(channel, None)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            match runner.process().await {
+                Ok(()) => {}
+                Err(err) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "timesync ic error")
+                }
+            }
+        })
+        .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        Some(self)
+    }
+}
+
+mod save_restore {
+    use super::*;
+
+    pub mod state {
+        use mesh::payload::Protobuf;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Copy, Clone, Eq, PartialEq, Protobuf)]
+        #[mesh(package = "timesync_ic")]
+        pub struct Version {
+            #[mesh(1)]
+            pub major: u16,
+            #[mesh(2)]
+            pub minor: u16,
+        }
+
+        impl From<hyperv_ic_protocol::Version> for Version {
+            fn from(version: hyperv_ic_protocol::Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        impl From<Version> for hyperv_ic_protocol::Version {
+            fn from(version: Version) -> Self {
+                Self {
+                    major: version.major,
+                    minor: version.minor,
+                }
+            }
+        }
+
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "timesync_ic")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub version: Option<(Version, Version)>,
+            #[mesh(2)]
+            pub waiting_on_version: bool,
+            #[mesh(3)]
+            pub waiting_on_response: bool,
+        }
+    }
+
+    impl SaveRestoreSimpleVmbusDevice for TimesyncIc {
+        fn save_open(&mut self, runner: &Self::Runner) -> state::SavedState {
+            let (version, waiting_on_response) = if let ChannelState::Ready {
+                framework_version,
+                message_version,
+                state,
+            } = &runner.state
+            {
+                (
+                    Some(((*framework_version).into(), (*message_version).into())),
+                    matches!(state, ReadyState::WaitResponse),
+                )
+            } else {
+                (None, false)
+            };
+            let waiting_on_version = matches!(runner.state, ChannelState::WaitVersion);
+            state::SavedState {
+                version,
+                waiting_on_version,
+                waiting_on_response,
+            }
+        }
+
+        fn restore_open(
+            &mut self,
+            saved_state: Self::SavedState,
+            channel: RawAsyncChannel<GpadlRingMem>,
+        ) -> Result<Self::Runner, ChannelOpenError> {
+            let state = if let Some((framework, message)) = saved_state.version {
+                // The runner forces a sync once the pending response, if
+                // any, arrives.
+                let state = if saved_state.waiting_on_response {
+                    ReadyState::WaitResponse
+                } else {
+                    ReadyState::SendSample
+                };
+                ChannelState::Ready {
+                    framework_version: framework.into(),
+                    message_version: message.into(),
+                    state,
+                }
+            } else if saved_state.waiting_on_version {
+                ChannelState::WaitVersion
+            } else {
+                ChannelState::SendVersion
+            };
+            self.open_channel(channel, Some(state))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncState;
+    use super::SAMPLE_INTERVAL;
+    use pal_async::timer::Instant;
+    use std::time::Duration;
+
+    /// Returns the reference time after `d`, in 100ns units.
+    fn ref_time(d: Duration) -> u64 {
+        (d.as_nanos() / 100) as u64
+    }
+
+    #[test]
+    fn sync_after_open() {
+        // The guest's clock is unknown after open and after restore, so the
+        // first message sets it.
+        let mut sync = SyncState::new();
+        let t0 = Instant::from_nanos(1_000_000_000);
+        let flags = sync.flags(t0, 0);
+        assert!(flags.sync() && !flags.sample());
+
+        // The flag stays set until the message is sent.
+        let flags = sync.flags(t0, 0);
+        assert!(flags.sync());
+        sync.sent(t0, 0);
+
+        let t1 = t0 + SAMPLE_INTERVAL;
+        let flags = sync.flags(t1, ref_time(SAMPLE_INTERVAL));
+        assert!(!flags.sync() && flags.sample());
+    }
+
+    #[test]
+    fn sync_after_pause() {
+        let mut sync = SyncState::new();
+        let t0 = Instant::from_nanos(1_000_000_000);
+        sync.flags(t0, 0);
+        sync.sent(t0, 0);
+
+        // Small differences between the clocks are corrected by samples.
+        let elapsed = SAMPLE_INTERVAL + Duration::from_millis(500);
+        let flags = sync.flags(t0 + elapsed, ref_time(SAMPLE_INTERVAL));
+        assert!(!flags.sync());
+        sync.sent(t0 + elapsed, ref_time(SAMPLE_INTERVAL));
+
+        // Stopping the VM stops the reference time but not the host's, so
+        // the next message after the VM starts again sets the clock.
+        let t1 = t0 + elapsed + Duration::from_secs(60);
+        let flags = sync.flags(t1, ref_time(SAMPLE_INTERVAL * 2));
+        assert!(flags.sync() && !flags.sample());
+        sync.sent(t1, ref_time(SAMPLE_INTERVAL * 2));
+
+        let t2 = t1 + SAMPLE_INTERVAL;
+        let flags = sync.flags(t2, ref_time(SAMPLE_INTERVAL * 3));
+        assert!(!flags.sync() && flags.sample());
+    }
+}
@@ -116,12 +116,27 @@ pub struct NegotiateMessage {
 
 /// Heartbeat component protocol.
 pub mod heartbeat {
+    use crate::Version;
+    use guid::Guid;
     use open_enum::open_enum;
     use zerocopy::AsBytes;
     use zerocopy::FromBytes;
     use zerocopy::FromZeroes;
 
-    /// Heartbeat message from guest to host.
+    /// The unique vmbus interface ID of the heartbeat IC.
+    pub const INTERFACE_ID: Guid = Guid::from_static_str("57164f39-9115-4e78-ab55-382f3bd5422d");
+    /// The unique vmbus instance ID of the heartbeat IC.
+    pub const INSTANCE_ID: Guid = Guid::from_static_str("fedf5f6e-a6a4-4a46-a06a-0fa8e9ec2d61");
+
+    /// Supported framework versions.
+    pub const FRAMEWORK_VERSIONS: &[Version] = &[Version::new(1, 0), Version::new(3, 0)];
+
+    /// Supported message versions.
+    pub const HEARTBEAT_VERSIONS: &[Version] =
+        &[Version::new(1, 0), Version::new(3, 0), Version::new(3, 1)];
+
+    /// Heartbeat message from host to guest, which the guest returns with an
+    /// incremented sequence number and its current state.
     #[repr(C)]
     #[derive(AsBytes, FromBytes, FromZeroes)]
     pub struct HeartbeatMessage {
@@ -149,6 +164,155 @@ pub enum ApplicationState: u32 {
     }
 }
 
+/// Protocol for the KVP (key-value pair) exchange IC.
+pub mod kvp {
+    use crate::Version;
+    use guid::Guid;
+    use open_enum::open_enum;
+    use zerocopy::AsBytes;
+    use zerocopy::FromBytes;
+    use zerocopy::FromZeroes;
+
+    /// The unique vmbus interface ID of the KVP IC.
+    pub const INTERFACE_ID: Guid = Guid::from_static_str("a9a0f4e7-5a45-4d96-b827-8a841e8c03e6");
+    /// The unique vmbus instance ID of the KVP IC.
+    pub const INSTANCE_ID: Guid = Guid::from_static_str("242ff919-07db-4180-9c2e-b86cb68c8c55");
+
+    /// Supported framework versions.
+    pub const FRAMEWORK_VERSIONS: &[Version] = &[Version::new(1, 0), Version::new(3, 0)];
+
+    /// Supported message versions.
+    pub const KVP_VERSIONS: &[Version] = &[Version::new(3, 0), Version::new(4, 0)];
+
+    /// The maximum size of a key, in bytes, including the null terminator.
+    pub const MAX_KEY_SIZE: usize = 512;
+    /// The maximum size of a value, in bytes, including the null terminator
+    /// of string values.
+    pub const MAX_VALUE_SIZE: usize = 2048;
+
+    /// Status returned by the guest when an enumeration index is past the end
+    /// of the pool.
+    pub const STATUS_NO_MORE_ITEMS: u32 = 0x80070103;
+
+    open_enum! {
+        /// A KVP operation.
+        #[derive(AsBytes, FromBytes, FromZeroes)]
+        pub enum KvpOperation: u8 {
+            /// Get the value of a key.
+            GET = 0,
+            /// Set the value of a key.
+            SET = 1,
+            /// Delete a key.
+            DELETE = 2,
+            /// Get the key and value at an index.
+            ENUMERATE = 3,
+            /// Get the IP configuration of a guest network adapter.
+            GET_IP_INFO = 4,
+            /// Set the IP configuration of a guest network adapter.
+            SET_IP_INFO = 5,
+        }
+    }
+
+    open_enum! {
+        /// A pool of key-value pairs.
+        #[derive(AsBytes, FromBytes, FromZeroes)]
+        pub enum KvpPool: u8 {
+            /// Values set by the host.
+            EXTERNAL = 0,
+            /// Values set by the guest.
+            GUEST = 1,
+            /// Values about the guest, maintained by the guest IC.
+            AUTO = 2,
+            /// Values about the host, maintained by the host.
+            AUTO_EXTERNAL = 3,
+            /// Reserved.
+            AUTO_INTERNAL = 4,
+        }
+    }
+
+    open_enum! {
+        /// The type of a value.
+        #[derive(AsBytes, FromBytes, FromZeroes)]
+        pub enum ValueType: u32 {
+            /// A null-terminated UTF-16 string.
+            STRING = 1,
+            /// A null-terminated UTF-16 string with environment variable
+            /// references.
+            EXPAND_STRING = 2,
+            /// A 32-bit integer.
+            U32 = 4,
+            /// A 64-bit integer.
+            U64 = 8,
+        }
+    }
+
+    /// The header of all KVP messages.
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes, FromZeroes, Debug)]
+    pub struct KvpHeader {
+        /// The operation.
+        pub operation: KvpOperation,
+        /// The pool the operation applies to.
+        pub pool: KvpPool,
+        /// Padding.
+        pub pad: u16,
+    }
+
+    /// A key and value.
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct Value {
+        /// The type of the value.
+        pub value_type: ValueType,
+        /// The size of the key, in bytes.
+        pub key_size: u32,
+        /// The size of the value, in bytes.
+        pub value_size: u32,
+        /// The key, as UTF-16.
+        pub key: [u8; MAX_KEY_SIZE],
+        /// The value.
+        pub value: [u8; MAX_VALUE_SIZE],
+    }
+
+    /// A message for [`KvpOperation::GET`] or [`KvpOperation::SET`].
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct KvpMessage {
+        /// The header.
+        pub header: KvpHeader,
+        /// The key and value.
+        pub value: Value,
+    }
+
+    /// A message for [`KvpOperation::ENUMERATE`].
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct EnumerateMessage {
+        /// The header.
+        pub header: KvpHeader,
+        /// The index of the key to get.
+        pub index: u32,
+        /// The key and value at the index, filled in by the guest.
+        pub value: Value,
+    }
+
+    /// A message for [`KvpOperation::DELETE`].
+    #[repr(C)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct DeleteMessage {
+        /// The header.
+        pub header: KvpHeader,
+        /// The size of the key, in bytes.
+        pub key_size: u32,
+        /// The key, as UTF-16.
+        pub key: [u8; MAX_KEY_SIZE],
+    }
+
+    /// The size of the message buffer, which guests expect to be large
+    /// enough for any of the messages above.
+    pub const MESSAGE_SIZE: usize = size_of::<EnumerateMessage>();
+}
+
 /// Protocol for shutdown IC.
 pub mod shutdown {
     use crate::Version;
@@ -207,3 +371,82 @@ pub struct ShutdownFlags {
     /// Reason code for '[ShutdownMessage]', from Windows SDK.
     pub const SHTDN_REASON_FLAG_PLANNED: u32 = 0x80000000;
 }
+
+/// Protocol for the time synchronization IC.
+pub mod timesync {
+    use crate::Version;
+    use bitfield_struct::bitfield;
+    use guid::Guid;
+    use zerocopy::AsBytes;
+    use zerocopy::FromBytes;
+    use zerocopy::FromZeroes;
+
+    /// The unique vmbus interface ID of the timesync IC.
+    pub const INTERFACE_ID: Guid = Guid::from_static_str("9527e630-d0ae-497b-adce-e80ab0175caf");
+    /// The unique vmbus instance ID of the timesync IC.
+    pub const INSTANCE_ID: Guid = Guid::from_static_str("2dd1ce17-079e-403c-b352-a1921ee207ee");
+
+    /// Supported framework versions.
+    pub const FRAMEWORK_VERSIONS: &[Version] = &[Version::new(1, 0), Version::new(3, 0)];
+
+    /// Supported message versions.
+    pub const TIMESYNC_VERSIONS: &[Version] =
+        &[Version::new(1, 0), Version::new(3, 0), Version::new(4, 0)];
+
+    /// The first message version that uses [`TimesyncMessageV4`].
+    pub const TIMESYNC_VERSION_4: Version = Version::new(4, 0);
+
+    /// The offset between the Windows epoch (1601-01-01), used for
+    /// `parent_time`, and the Unix epoch, in 100ns units.
+    pub const EPOCH_DELTA_100NS: u64 = 116_444_736_000_000_000;
+
+    /// Time sync message for message versions before 4.0.
+    #[repr(C, packed)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct TimesyncMessage {
+        /// The host's UTC time, in 100ns units since the Windows epoch.
+        pub parent_time: u64,
+        /// Unused.
+        pub child_time: u64,
+        /// Unused.
+        pub round_trip_time: u64,
+        /// Flags.
+        pub flags: TimesyncFlags,
+        /// Reserved -- must be zero.
+        pub reserved: [u8; 3],
+    }
+
+    /// Time sync message for message version 4.0 and later.
+    #[repr(C, packed)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct TimesyncMessageV4 {
+        /// The host's UTC time, in 100ns units since the Windows epoch.
+        pub parent_time: u64,
+        /// The guest's reference time at the moment `parent_time` was
+        /// sampled, which the guest uses to account for the message's
+        /// latency.
+        pub vm_reference_time: u64,
+        /// Flags.
+        pub flags: TimesyncFlags,
+        /// Leap second indicator.
+        pub leap_flags: u8,
+        /// The stratum of the host's clock.
+        pub stratum: u8,
+        /// Reserved -- must be zero.
+        pub reserved: [u8; 3],
+    }
+
+    /// Flags for time sync messages.
+    #[bitfield(u8)]
+    #[derive(AsBytes, FromBytes, FromZeroes)]
+    pub struct TimesyncFlags {
+        /// The guest should set its clock to the host time immediately,
+        /// rather than slewing towards it.
+        pub sync: bool,
+        /// The message is a periodic sample of the host time.
+        pub sample: bool,
+        /// Reserved -- must be zero.
+        #[bits(6)]
+        _reserved: u8,
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the heartbeat IC.
+
+use mesh::rpc::Rpc;
+use mesh::MeshPayload;
+use std::time::Duration;
+use vm_resource::kind::VmbusDeviceHandleKind;
+use vm_resource::ResourceId;
+
+/// A handle to a heartbeat IC.
+#[derive(MeshPayload)]
+pub struct HeartbeatIcHandle {
+    /// The channel by which to receive status requests.
+    pub recv: mesh::Receiver<HeartbeatRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for HeartbeatIcHandle {
+    const ID: &'static str = "heartbeat_ic";
+}
+
+/// An RPC request to the heartbeat IC.
+#[derive(MeshPayload)]
+pub enum HeartbeatRpc {
+    /// Get the guest's liveness.
+    Query(Rpc<(), HeartbeatStatus>),
+}
+
+/// The guest's liveness, as reported by the heartbeat IC.
+#[derive(Debug, MeshPayload)]
+pub struct HeartbeatStatus {
+    /// The guest's state.
+    pub state: HeartbeatState,
+    /// The sequence number of the last heartbeat received from the guest.
+    pub sequence_number: u64,
+    /// The time since the last heartbeat was received from the guest, or
+    /// `None` if no heartbeat has been received since the channel opened.
+    pub last_heartbeat_age: Option<Duration>,
+}
+
+/// The guest's state, as reported by the heartbeat IC.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, MeshPayload)]
+pub enum HeartbeatState {
+    /// The guest has not opened the heartbeat channel or has not yet
+    /// responded to a heartbeat.
+    NoContact,
+    /// The guest is responding to heartbeats.
+    Healthy,
+    /// The guest is responding to heartbeats but reports a critical error.
+    Critical,
+    /// The guest reports that it is no longer running.
+    Stopped,
+    /// The guest previously responded to heartbeats but has stopped doing so.
+    LostCommunication,
+}
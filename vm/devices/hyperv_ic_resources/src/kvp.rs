@@ -0,0 +1,115 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the KVP (key-value pair) exchange IC.
+
+use mesh::rpc::FailableRpc;
+use mesh::MeshPayload;
+use vm_resource::kind::VmbusDeviceHandleKind;
+use vm_resource::ResourceId;
+
+/// A handle to a KVP IC.
+#[derive(MeshPayload)]
+pub struct KvpIcHandle {
+    /// The channel by which to receive KVP requests.
+    pub recv: mesh::Receiver<KvpRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for KvpIcHandle {
+    const ID: &'static str = "kvp_ic";
+}
+
+/// An RPC request to the KVP IC.
+#[derive(MeshPayload)]
+pub enum KvpRpc {
+    /// Set a key's value in a guest pool.
+    Set(FailableRpc<SetParams, ()>),
+    /// Get a key's value from a guest pool.
+    Get(FailableRpc<GetParams, Value>),
+    /// Delete a key from a guest pool.
+    Delete(FailableRpc<DeleteParams, ()>),
+    /// Get all the keys and values in a guest pool.
+    Enumerate(FailableRpc<EnumerateParams, Vec<KeyValue>>),
+}
+
+/// A guest pool of key-value pairs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, MeshPayload)]
+pub enum KvpPool {
+    /// Values set by the host.
+    External,
+    /// Values set by the guest.
+    Guest,
+    /// Values about the guest, such as its OS version and IP addresses,
+    /// maintained by the guest IC.
+    Auto,
+    /// Values about the host, maintained by the host.
+    AutoExternal,
+    /// Reserved for internal use.
+    AutoInternal,
+}
+
+/// A KVP value.
+#[derive(Debug, Clone, PartialEq, Eq, MeshPayload)]
+pub enum Value {
+    /// A string.
+    String(String),
+    /// A 32-bit integer.
+    U32(u32),
+    /// A 64-bit integer.
+    U64(u64),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(s) => f.write_str(s),
+            Value::U32(n) => write!(f, "{n}"),
+            Value::U64(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+/// A key and its value.
+#[derive(Debug, Clone, MeshPayload)]
+pub struct KeyValue {
+    /// The key.
+    pub key: String,
+    /// The value.
+    pub value: Value,
+}
+
+/// Parameters for [`KvpRpc::Set`].
+#[derive(Debug, MeshPayload)]
+pub struct SetParams {
+    /// The pool to modify.
+    pub pool: KvpPool,
+    /// The key.
+    pub key: String,
+    /// The new value.
+    pub value: Value,
+}
+
+/// Parameters for [`KvpRpc::Get`].
+#[derive(Debug, MeshPayload)]
+pub struct GetParams {
+    /// The pool to query.
+    pub pool: KvpPool,
+    /// The key.
+    pub key: String,
+}
+
+/// Parameters for [`KvpRpc::Delete`].
+#[derive(Debug, MeshPayload)]
+pub struct DeleteParams {
+    /// The pool to modify.
+    pub pool: KvpPool,
+    /// The key.
+    pub key: String,
+}
+
+/// Parameters for [`KvpRpc::Enumerate`].
+#[derive(Debug, MeshPayload)]
+pub struct EnumerateParams {
+    /// The pool to query.
+    pub pool: KvpPool,
+}
@@ -6,4 +6,7 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod heartbeat;
+pub mod kvp;
 pub mod shutdown;
+pub mod timesync;
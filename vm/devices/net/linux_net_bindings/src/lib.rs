@@ -8,9 +8,11 @@
 // UNSAFETY: bindgen generated code.
 #![allow(unsafe_code)]
 
+use nix::ioctl_write_int_bad;
 use nix::ioctl_write_ptr_bad;
 use nix::request_code_write;
 use std::os::raw::c_int;
+use std::os::raw::c_uint;
 
 // Generated using:
 //
@@ -40,3 +42,16 @@
     request_code_write!(b'T', 202, size_of::<c_int>()),
     gen_if::ifreq
 );
+
+// #define TUNSETOFFLOAD  _IOW('T', 208, unsigned int)
+ioctl_write_int_bad!(
+    tun_set_offload,
+    request_code_write!(b'T', 208, size_of::<c_uint>())
+);
+
+// #define TUNSETQUEUE  _IOW('T', 217, int)
+ioctl_write_ptr_bad!(
+    tun_set_queue,
+    request_code_write!(b'T', 217, size_of::<c_int>()),
+    gen_if::ifreq
+);
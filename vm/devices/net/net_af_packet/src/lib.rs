@@ -0,0 +1,307 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An endpoint that shares a host network interface via an `AF_PACKET` socket.
+//!
+//! Like a macvtap device, the endpoint sends the guest's frames directly out of
+//! the host interface and receives the frames addressed to the guest's MAC
+//! address, without requiring a bridge. The interface is put into promiscuous
+//! mode while the endpoint is in use. As with macvtap, the guest cannot reach
+//! the host itself through this interface.
+//!
+//! Frames that the host coalesced with GRO beyond the interface MTU are
+//! dropped rather than passed to the guest, so GRO should be disabled on the
+//! interface (`ethtool -K <name> gro off`).
+
+#![cfg(target_os = "linux")]
+
+pub mod resolver;
+mod socket;
+
+use async_trait::async_trait;
+use inspect::InspectMut;
+use net_backend::linearize;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxId;
+use net_backend::TxSegment;
+use net_backend_resources::mac_address::MacAddress;
+use pal_async::driver::Driver;
+use pal_async::interest::InterestSlot;
+use pal_async::interest::PollEvents;
+use pal_async::socket::PolledSocket;
+use parking_lot::Mutex;
+use socket::PacketSocket;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("packet socket error")]
+    PacketSocket(#[source] socket::Error),
+}
+
+/// An endpoint attached to a host interface via an `AF_PACKET` socket.
+pub struct AfPacketEndpoint {
+    socket: Arc<Mutex<Option<PacketSocket>>>,
+    mac_address: MacAddress,
+}
+
+impl AfPacketEndpoint {
+    /// Attaches to the host interface `name`, delivering to the guest the
+    /// frames sent to `mac_address` and to broadcast and multicast addresses.
+    pub fn new(name: &str, mac_address: MacAddress) -> Result<Self, Error> {
+        let socket = PacketSocket::open(name).map_err(Error::PacketSocket)?;
+        Ok(Self {
+            socket: Arc::new(Mutex::new(Some(socket))),
+            mac_address,
+        })
+    }
+}
+
+impl InspectMut for AfPacketEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond().field("mac_address", self.mac_address);
+    }
+}
+
+#[async_trait]
+impl Endpoint for AfPacketEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "af_packet"
+    }
+
+    async fn get_queues(
+        &mut self,
+        config: Vec<QueueConfig<'_>>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        assert_eq!(config.len(), 1);
+        let config = config.into_iter().next().unwrap();
+        queues.push(Box::new(AfPacketQueue::new(
+            config.driver.as_ref(),
+            self.socket.clone(),
+            self.mac_address.to_bytes(),
+            config.pool,
+            config.initial_rx,
+        )?));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        assert!(self.socket.lock().is_some(), "queue has not been dropped");
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+struct AfPacketQueue {
+    slot: Arc<Mutex<Option<PacketSocket>>>,
+    socket: Option<PacketSocket>,
+    /// A duplicate of the socket's file descriptor, polled for readiness.
+    polled: PolledSocket<socket2::Socket>,
+    mac_address: [u8; 6],
+    pool: Box<dyn BufferAccess>,
+    rx_free: VecDeque<RxId>,
+    rx_ready: VecDeque<RxId>,
+    buffer: Box<[u8]>,
+}
+
+impl InspectMut for AfPacketQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        if let Some(socket) = &mut self.socket {
+            resp.counter("rx_oversized", socket.ring_mut().oversized());
+        }
+    }
+}
+
+impl Drop for AfPacketQueue {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            *self.slot.lock() = Some(socket);
+        }
+    }
+}
+
+impl AfPacketQueue {
+    fn new(
+        driver: &dyn Driver,
+        slot: Arc<Mutex<Option<PacketSocket>>>,
+        mac_address: [u8; 6],
+        pool: Box<dyn BufferAccess>,
+        initial_rx: &[RxId],
+    ) -> io::Result<Self> {
+        let socket = slot.lock().take().expect("queue is already in use");
+        let polled = match socket
+            .try_clone_socket()
+            .and_then(|dup| PolledSocket::new(driver, dup))
+        {
+            Ok(polled) => polled,
+            Err(err) => {
+                *slot.lock() = Some(socket);
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            slot,
+            socket: Some(socket),
+            polled,
+            mac_address,
+            pool,
+            rx_free: initial_rx.iter().copied().collect(),
+            rx_ready: VecDeque::new(),
+            // Leave room for a VLAN tag stripped by the host.
+            buffer: Box::new([0; 65535 + 4]),
+        })
+    }
+}
+
+/// Returns true if a frame sent to `dest` should be delivered to a guest with
+/// MAC address `mac_address`.
+fn accepts(mac_address: [u8; 6], dest: &[u8]) -> bool {
+    // Deliver unicast frames for the guest and all group-addressed frames.
+    dest == mac_address || dest.first().is_some_and(|b| b & 1 != 0)
+}
+
+impl Queue for AfPacketQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.rx_ready.is_empty() {
+            return Poll::Ready(());
+        }
+
+        let Some(socket) = &mut self.socket else {
+            return Poll::Pending;
+        };
+        let ring = socket.ring_mut();
+
+        while let Some(&rx) = self.rx_free.front() {
+            let Some(frame) = ring.next_frame() else {
+                // Wait for the kernel to hand over another block.
+                match self
+                    .polled
+                    .poll_io(cx, InterestSlot::Read, PollEvents::IN, |_| {
+                        if ring.is_ready() {
+                            Ok(())
+                        } else {
+                            Err(ErrorKind::WouldBlock.into())
+                        }
+                    }) {
+                    Poll::Ready(Ok(())) => continue,
+                    Poll::Ready(Err(err)) => {
+                        tracing::warn!(
+                            error = &err as &dyn std::error::Error,
+                            "packet socket rx error"
+                        );
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            };
+            let len = frame.copy_to(&mut self.buffer);
+            let frame = &self.buffer[..len];
+            if !frame
+                .get(..6)
+                .is_some_and(|dest| accepts(self.mac_address, dest))
+            {
+                continue;
+            }
+            self.pool.write_packet(
+                rx,
+                &RxMetadata {
+                    offset: 0,
+                    len,
+                    ..Default::default()
+                },
+                frame,
+            );
+            self.rx_ready.push_back(rx);
+            self.rx_free.pop_front();
+        }
+
+        if !self.rx_ready.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn rx_avail(&mut self, done: &[RxId]) {
+        self.rx_free.extend(done);
+    }
+
+    fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
+        let n = std::cmp::min(self.rx_ready.len(), packets.len());
+        for (done, id) in packets[..n].iter_mut().zip(self.rx_ready.drain(..n)) {
+            *done = id;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(&mut self, mut segments: &[TxSegment]) -> anyhow::Result<(bool, usize)> {
+        let n = segments.len();
+        // Synchronously send the packets out of the host interface.
+        if self.socket.is_some() {
+            while !segments.is_empty() {
+                let packet = linearize(self.pool.as_ref(), &mut segments)?;
+                // N.B. This is a non-blocking send because `PolledSocket::new`
+                // puts the socket into nonblocking mode.
+                match self.polled.get().send(&packet) {
+                    Ok(_) => {}
+                    Err(err)
+                        if err.kind() == ErrorKind::WouldBlock
+                            || err.raw_os_error() == Some(libc::ENOBUFS) =>
+                    {
+                        // dropped packet: buffer is full
+                    }
+                    Err(err) if err.raw_os_error() == Some(libc::ENETDOWN) => {
+                        // dropped packet: interface is not up
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            error = &err as &dyn std::error::Error,
+                            "write to packet socket failed"
+                        );
+                    }
+                }
+            }
+        }
+        Ok((true, n))
+    }
+
+    fn tx_poll(&mut self, _done: &mut [TxId]) -> anyhow::Result<usize> {
+        // Packets are sent synchronously.
+        Ok(0)
+    }
+
+    fn buffer_access(&mut self) -> Option<&mut dyn BufferAccess> {
+        Some(self.pool.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accepts;
+
+    #[test]
+    fn accepts_frames() {
+        let mac = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x56];
+        assert!(accepts(mac, &mac));
+        assert!(accepts(mac, &[0xff; 6]));
+        assert!(accepts(mac, &[0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb]));
+        assert!(!accepts(mac, &[0x00, 0x15, 0x5d, 0x12, 0x34, 0x57]));
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::AfPacketEndpoint;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::af_packet::AfPacketHandle;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+use vm_resource::ResolveResource;
+
+pub struct AfPacketResolver;
+
+declare_static_resolver! {
+    AfPacketResolver,
+    (NetEndpointHandleKind, AfPacketHandle),
+}
+
+impl ResolveResource<NetEndpointHandleKind, AfPacketHandle> for AfPacketResolver {
+    type Output = ResolvedEndpoint;
+    type Error = super::Error;
+
+    fn resolve(
+        &self,
+        resource: AfPacketHandle,
+        input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let endpoint = AfPacketEndpoint::new(&resource.interface, input.mac_address)?;
+        Ok(endpoint.into())
+    }
+}
@@ -0,0 +1,630 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An `AF_PACKET` socket bound to a host interface, with a `TPACKET_V3` receive
+//! ring shared with the kernel.
+
+// UNSAFETY: Calling socket ioctls and accessing the memory-mapped packet ring.
+#![allow(unsafe_code)]
+
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use thiserror::Error;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+// These correspond to definitions in linux/if_packet.h.
+const PACKET_ADD_MEMBERSHIP: libc::c_int = 1;
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_IGNORE_OUTGOING: libc::c_int = 23;
+const PACKET_MR_PROMISC: u16 = 1;
+const TPACKET_V3: libc::c_int = 2;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1 << 0;
+const TP_STATUS_VLAN_VALID: u32 = 1 << 4;
+const TP_STATUS_VLAN_TPID_VALID: u32 = 1 << 6;
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_8021Q: u16 = 0x8100;
+
+/// The size of each block of the receive ring. Each block holds many packets
+/// and is handed back and forth between the kernel and user mode as a unit.
+const BLOCK_SIZE: u32 = 0x20000;
+/// The number of blocks in the receive ring.
+const BLOCK_COUNT: u32 = 32;
+/// The nominal frame size, which only determines the maximum packet size for
+/// `TPACKET_V3`.
+const FRAME_SIZE: u32 = 0x800;
+/// How long the kernel waits before handing a partially filled block to user
+/// mode, in milliseconds. This bounds the receive latency.
+const BLOCK_TIMEOUT_MS: u32 = 1;
+
+/// The offset of `block_status` in `struct tpacket_block_desc`.
+const BLOCK_STATUS_OFFSET: usize = 8;
+/// The offset of the block header fields following `block_status`, which are
+/// only written by the kernel before it hands the block to user mode.
+const BLOCK_HEADER_OFFSET: usize = BLOCK_STATUS_OFFSET + 4;
+
+/// The length of an Ethernet header without a VLAN tag.
+const ETH_HLEN: usize = 14;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("interface name conversion to C string failed")]
+    InterfaceName(#[source] std::ffi::NulError),
+    #[error("no such interface: {0}")]
+    NoSuchInterface(String),
+    #[error("failed to create packet socket")]
+    Socket(#[source] io::Error),
+    #[error("failed to set packet socket option {0}")]
+    SetOption(&'static str, #[source] io::Error),
+    #[error("failed to map the receive ring")]
+    MapRing(#[source] io::Error),
+    #[error("failed to bind packet socket to the interface")]
+    Bind(#[source] io::Error),
+    #[error("failed to query the interface MTU")]
+    Mtu(#[source] io::Error),
+}
+
+/// `struct tpacket_req3`.
+#[repr(C)]
+#[derive(AsBytes)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32,
+}
+
+/// `struct packet_mreq`.
+#[repr(C)]
+#[derive(AsBytes)]
+struct PacketMreq {
+    mr_ifindex: i32,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+/// The fields of `struct tpacket_block_desc` (with its `tpacket_hdr_v1`)
+/// following the block status.
+#[repr(C)]
+#[derive(FromBytes, FromZeroes)]
+struct BlockHeader {
+    num_pkts: u32,
+    offset_to_first_pkt: u32,
+}
+
+/// `struct tpacket3_hdr`.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct Tpacket3Hdr {
+    tp_next_offset: u32,
+    _tp_sec: u32,
+    _tp_nsec: u32,
+    tp_snaplen: u32,
+    tp_len: u32,
+    tp_status: u32,
+    tp_mac: u16,
+    _tp_net: u16,
+    _tp_rxhash: u32,
+    tp_vlan_tci: u32,
+    tp_vlan_tpid: u16,
+    _tp_padding: [u8; 10],
+}
+
+/// A packet socket bound to a host interface.
+pub struct PacketSocket {
+    socket: socket2::Socket,
+    ring: RxRing,
+}
+
+impl PacketSocket {
+    /// Opens a packet socket that receives all frames on interface `name`,
+    /// placing the interface into promiscuous mode while the socket is open.
+    ///
+    /// Frames transmitted by the host, including those sent on this socket,
+    /// are not received.
+    pub fn open(name: &str) -> Result<Self, Error> {
+        let name_cstr = CString::new(name).map_err(Error::InterfaceName)?;
+        // SAFETY: passing a valid nul-terminated string.
+        let ifindex = unsafe { libc::if_nametoindex(name_cstr.as_ptr()) };
+        if ifindex == 0 {
+            return Err(Error::NoSuchInterface(name.to_owned()));
+        }
+
+        // Create the socket with no protocol so that it does not receive
+        // frames until it is bound to the interface.
+        //
+        // SAFETY: calling socket with no pointer arguments; the result is
+        // validated before being wrapped.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::Socket(io::Error::last_os_error()));
+        }
+        // SAFETY: the fd was just created and is owned by no one else.
+        let socket = socket2::Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+        set_option(&socket, PACKET_VERSION, &TPACKET_V3)
+            .map_err(|err| Error::SetOption("PACKET_VERSION", err))?;
+
+        // Older kernels do not support this option, in which case the guest
+        // sees its own frames reflected back, which is harmless.
+        if let Err(err) = set_option(&socket, PACKET_IGNORE_OUTGOING, &1i32) {
+            tracing::debug!(
+                error = &err as &dyn std::error::Error,
+                "PACKET_IGNORE_OUTGOING not supported"
+            );
+        }
+
+        let req = TpacketReq3 {
+            tp_block_size: BLOCK_SIZE,
+            tp_block_nr: BLOCK_COUNT,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: BLOCK_SIZE / FRAME_SIZE * BLOCK_COUNT,
+            tp_retire_blk_tov: BLOCK_TIMEOUT_MS,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        set_option(&socket, PACKET_RX_RING, &req)
+            .map_err(|err| Error::SetOption("PACKET_RX_RING", err))?;
+        let mtu = interface_mtu(&socket, &name_cstr).map_err(Error::Mtu)?;
+        let ring = RxRing::new(&socket, ETH_HLEN + mtu).map_err(Error::MapRing)?;
+
+        let mreq = PacketMreq {
+            mr_ifindex: ifindex as i32,
+            mr_type: PACKET_MR_PROMISC,
+            mr_alen: 0,
+            mr_address: [0; 8],
+        };
+        set_option(&socket, PACKET_ADD_MEMBERSHIP, &mreq)
+            .map_err(|err| Error::SetOption("PACKET_ADD_MEMBERSHIP", err))?;
+
+        // SAFETY: sockaddr_ll is a plain C struct for which zero is valid.
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_ALL.to_be();
+        addr.sll_ifindex = ifindex as i32;
+        // SAFETY: passing a valid sockaddr_ll and its length.
+        let r = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                std::ptr::from_ref(&addr).cast(),
+                size_of::<libc::sockaddr_ll>() as u32,
+            )
+        };
+        if r < 0 {
+            return Err(Error::Bind(io::Error::last_os_error()));
+        }
+
+        Ok(Self { socket, ring })
+    }
+
+    /// Returns a new socket sharing the underlying file descriptor, for
+    /// polling and sending.
+    pub fn try_clone_socket(&self) -> io::Result<socket2::Socket> {
+        self.socket.try_clone()
+    }
+
+    pub fn ring_mut(&mut self) -> &mut RxRing {
+        &mut self.ring
+    }
+}
+
+/// Returns the MTU of the interface `name`.
+fn interface_mtu(socket: &socket2::Socket, name: &CString) -> io::Result<usize> {
+    // SAFETY: ifreq is a plain C struct for which zero is valid.
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    let name = name.as_bytes_with_nul();
+    if name.len() > req.ifr_name.len() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    for (dest, &src) in req.ifr_name.iter_mut().zip(name) {
+        *dest = src as libc::c_char;
+    }
+    // SAFETY: passing a valid ifreq for SIOCGIFMTU to fill in.
+    let r = unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU, &mut req) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: SIOCGIFMTU fills in the MTU member of the union.
+    let mtu = unsafe { req.ifr_ifru.ifru_mtu };
+    Ok(mtu.try_into().unwrap_or(0))
+}
+
+fn set_option<T: AsBytes>(
+    socket: &socket2::Socket,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let value = value.as_bytes();
+    // SAFETY: passing a valid buffer and its length.
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_PACKET,
+            name,
+            value.as_ptr().cast(),
+            value.len() as u32,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A frame read from the receive ring.
+pub struct RxFrame<'a> {
+    /// The captured frame, with any VLAN tag stripped by the host.
+    pub data: &'a [u8],
+    /// The VLAN tag stripped from the frame, as `(tpid, tci)`.
+    pub vlan: Option<(u16, u16)>,
+}
+
+impl RxFrame<'_> {
+    /// Copies the frame to `buf`, reinserting the VLAN tag if there was one,
+    /// and returns the copied length.
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        match self.vlan {
+            Some((tpid, tci)) if self.data.len() >= 12 => {
+                let (addrs, rest) = self.data.split_at(12);
+                let len = self.data.len() + 4;
+                buf[..12].copy_from_slice(addrs);
+                buf[12..14].copy_from_slice(&tpid.to_be_bytes());
+                buf[14..16].copy_from_slice(&tci.to_be_bytes());
+                buf[16..len].copy_from_slice(rest);
+                len
+            }
+            _ => {
+                buf[..self.data.len()].copy_from_slice(self.data);
+                self.data.len()
+            }
+        }
+    }
+}
+
+/// The memory-mapped `TPACKET_V3` receive ring.
+pub struct RxRing {
+    addr: *mut u8,
+    /// The longest frame to return, not counting a stripped VLAN tag.
+    max_frame_len: usize,
+    /// The number of frames dropped for exceeding `max_frame_len`.
+    oversized: u64,
+    /// The index of the current block.
+    block: usize,
+    /// The number of packets consumed from the current block.
+    packet: u32,
+    /// The offset of the next packet in the current block.
+    offset: usize,
+}
+
+// SAFETY: The ring is a plain memory mapping that may be used from any thread.
+unsafe impl Send for RxRing {}
+// SAFETY: The ring is a plain memory mapping that may be used from any thread.
+unsafe impl Sync for RxRing {}
+
+impl RxRing {
+    const LEN: usize = BLOCK_SIZE as usize * BLOCK_COUNT as usize;
+
+    fn new(socket: &socket2::Socket, max_frame_len: usize) -> io::Result<Self> {
+        // SAFETY: mapping the ring configured on the socket, at a new address.
+        // The result is validated.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                Self::LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                socket.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            addr: addr.cast(),
+            max_frame_len,
+            oversized: 0,
+            block: 0,
+            packet: 0,
+            offset: 0,
+        })
+    }
+
+    fn block_status(&self) -> &AtomicU32 {
+        // SAFETY: the status is an aligned u32 within the mapping, which is
+        // only accessed atomically since it is shared with the kernel.
+        unsafe {
+            &*self
+                .addr
+                .add(self.block * BLOCK_SIZE as usize + BLOCK_STATUS_OFFSET)
+                .cast::<AtomicU32>()
+        }
+    }
+
+    /// Returns the current block following its status, which must be owned by
+    /// user mode. The status is excluded since it is only accessed atomically.
+    fn block_data(&self) -> &[u8] {
+        // SAFETY: the range is within the mapping and does not overlap the
+        // status, and the kernel does not write to it while the block is owned
+        // by user mode.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.addr
+                    .add(self.block * BLOCK_SIZE as usize + BLOCK_HEADER_OFFSET),
+                BLOCK_SIZE as usize - BLOCK_HEADER_OFFSET,
+            )
+        }
+    }
+
+    /// Returns the number of frames dropped for exceeding the interface MTU.
+    pub fn oversized(&self) -> u64 {
+        self.oversized
+    }
+
+    /// Returns true if there is a block owned by user mode, from which frames
+    /// can be read.
+    pub fn is_ready(&self) -> bool {
+        self.block_status().load(Ordering::Acquire) & TP_STATUS_USER != 0
+    }
+
+    /// Returns the next received frame, or `None` if the kernel has not yet
+    /// handed over any more blocks.
+    pub fn next_frame(&mut self) -> Option<RxFrame<'_>> {
+        while self.is_ready() {
+            let block = self.block_data();
+            let header = BlockHeader::read_from_prefix(block).unwrap();
+            if self.packet == 0 {
+                self.offset = header.offset_to_first_pkt as usize;
+            }
+            let hdr = if self.packet < header.num_pkts {
+                self.offset
+                    .checked_sub(BLOCK_HEADER_OFFSET)
+                    .and_then(|offset| block.get(offset..))
+                    .and_then(Tpacket3Hdr::read_from_prefix)
+            } else {
+                None
+            };
+            let Some(hdr) = hdr else {
+                // The block is exhausted or malformed. Return it to the kernel
+                // and move on to the next one.
+                self.block_status()
+                    .store(TP_STATUS_KERNEL, Ordering::Release);
+                self.block = (self.block + 1) % BLOCK_COUNT as usize;
+                self.packet = 0;
+                continue;
+            };
+
+            let start = self.offset + hdr.tp_mac as usize;
+            let len = hdr.tp_snaplen as usize;
+            self.packet += 1;
+            self.offset += hdr.tp_next_offset as usize;
+            // Skip frames that were truncated to fit in the ring.
+            if start < BLOCK_HEADER_OFFSET
+                || start + len > BLOCK_SIZE as usize
+                || hdr.tp_snaplen != hdr.tp_len
+            {
+                continue;
+            }
+            // Skip frames that the host coalesced with GRO beyond the MTU,
+            // which the guest is not prepared to receive.
+            if len > self.max_frame_len {
+                self.oversized += 1;
+                continue;
+            }
+            let vlan = (hdr.tp_status & TP_STATUS_VLAN_VALID != 0).then(|| {
+                let tpid = if hdr.tp_status & TP_STATUS_VLAN_TPID_VALID != 0 {
+                    hdr.tp_vlan_tpid
+                } else {
+                    ETH_P_8021Q
+                };
+                (tpid, hdr.tp_vlan_tci as u16)
+            });
+            // SAFETY: the frame is within the current block, which remains
+            // owned by user mode until it is exhausted by a later call.
+            let data = unsafe {
+                std::slice::from_raw_parts(
+                    self.addr.add(self.block * BLOCK_SIZE as usize + start),
+                    len,
+                )
+            };
+            return Some(RxFrame { data, vlan });
+        }
+        None
+    }
+}
+
+impl Drop for RxRing {
+    fn drop(&mut self) {
+        // SAFETY: unmapping the mapping created in `new`, which is no longer
+        // referenced.
+        unsafe {
+            libc::munmap(self.addr.cast::<c_void>(), Self::LEN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RxFrame;
+    use super::RxRing;
+    use super::Tpacket3Hdr;
+    use super::BLOCK_SIZE;
+    use super::ETH_HLEN;
+    use super::ETH_P_8021Q;
+    use super::TP_STATUS_KERNEL;
+    use super::TP_STATUS_USER;
+    use super::TP_STATUS_VLAN_VALID;
+    use std::sync::atomic::Ordering;
+    use zerocopy::AsBytes;
+    use zerocopy::FromZeroes;
+
+    /// The offset of the first packet in a block, following `struct
+    /// tpacket_block_desc`.
+    const FIRST_PACKET: usize = 48;
+    /// The offset of the frame from the start of its packet header.
+    const MAC_OFFSET: usize = 64;
+
+    struct TestPacket<'a> {
+        frame: &'a [u8],
+        len: usize,
+        vlan_tci: Option<u16>,
+    }
+
+    impl<'a> TestPacket<'a> {
+        fn new(frame: &'a [u8]) -> Self {
+            Self {
+                frame,
+                len: frame.len(),
+                vlan_tci: None,
+            }
+        }
+    }
+
+    /// Returns a ring backed by anonymous memory instead of a packet socket.
+    fn test_ring() -> RxRing {
+        // SAFETY: creating a new anonymous mapping. The result is validated.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                RxRing::LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        RxRing {
+            addr: addr.cast(),
+            max_frame_len: ETH_HLEN + 1500,
+            oversized: 0,
+            block: 0,
+            packet: 0,
+            offset: 0,
+        }
+    }
+
+    /// Fills in `block` as the kernel would and hands it to user mode.
+    fn fill_block(ring: &RxRing, block: usize, packets: &[TestPacket<'_>]) {
+        // SAFETY: the block is within the mapping, and the ring does not
+        // access it until it is handed over below.
+        let data = unsafe {
+            std::slice::from_raw_parts_mut(
+                ring.addr.add(block * BLOCK_SIZE as usize),
+                BLOCK_SIZE as usize,
+            )
+        };
+        data[12..16].copy_from_slice(&(packets.len() as u32).to_ne_bytes());
+        data[16..20].copy_from_slice(&(FIRST_PACKET as u32).to_ne_bytes());
+        let mut offset = FIRST_PACKET;
+        for packet in packets {
+            let next = (MAC_OFFSET + packet.frame.len()).next_multiple_of(16);
+            let hdr = Tpacket3Hdr {
+                tp_next_offset: next as u32,
+                tp_snaplen: packet.frame.len() as u32,
+                tp_len: packet.len as u32,
+                tp_status: if packet.vlan_tci.is_some() {
+                    TP_STATUS_VLAN_VALID
+                } else {
+                    0
+                },
+                tp_mac: MAC_OFFSET as u16,
+                tp_vlan_tci: packet.vlan_tci.unwrap_or(0).into(),
+                ..Tpacket3Hdr::new_zeroed()
+            };
+            data[offset..offset + size_of::<Tpacket3Hdr>()].copy_from_slice(hdr.as_bytes());
+            data[offset + MAC_OFFSET..][..packet.frame.len()].copy_from_slice(packet.frame);
+            offset += next;
+        }
+        data[8..12].copy_from_slice(&TP_STATUS_USER.to_ne_bytes());
+    }
+
+    #[test]
+    fn ring() {
+        let mut ring = test_ring();
+        assert!(!ring.is_ready());
+        assert!(ring.next_frame().is_none());
+
+        let frame = [0x5a; 60];
+        let tagged = [0xa5; 64];
+        let oversized = [0; ETH_HLEN + 1501];
+        fill_block(
+            &ring,
+            0,
+            &[
+                TestPacket::new(&frame),
+                TestPacket {
+                    vlan_tci: Some(5),
+                    ..TestPacket::new(&tagged)
+                },
+                // Truncated to fit in the ring.
+                TestPacket {
+                    len: 200,
+                    ..TestPacket::new(&frame)
+                },
+                // Coalesced by GRO beyond the MTU.
+                TestPacket::new(&oversized),
+                TestPacket::new(&frame[..20]),
+            ],
+        );
+        fill_block(&ring, 1, &[]);
+
+        assert!(ring.is_ready());
+        let rx = ring.next_frame().unwrap();
+        assert_eq!(rx.data, frame);
+        assert_eq!(rx.vlan, None);
+        let rx = ring.next_frame().unwrap();
+        assert_eq!(rx.data, tagged);
+        assert_eq!(rx.vlan, Some((ETH_P_8021Q, 5)));
+        let rx = ring.next_frame().unwrap();
+        assert_eq!(rx.data, &frame[..20]);
+        assert_eq!(ring.oversized(), 1);
+
+        // Both blocks are returned to the kernel once exhausted.
+        assert!(ring.next_frame().is_none());
+        assert_eq!(ring.block, 2);
+        for block in 0..2 {
+            ring.block = block;
+            assert_eq!(
+                ring.block_status().load(Ordering::Relaxed),
+                TP_STATUS_KERNEL
+            );
+        }
+    }
+
+    #[test]
+    fn copy_vlan() {
+        let data: Vec<u8> = (0..20).collect();
+        let mut buf = [0; 64];
+        let frame = RxFrame {
+            data: &data,
+            vlan: None,
+        };
+        assert_eq!(frame.copy_to(&mut buf), 20);
+        assert_eq!(buf[..20], data);
+
+        let frame = RxFrame {
+            data: &data,
+            vlan: Some((ETH_P_8021Q, 0x1234)),
+        };
+        assert_eq!(frame.copy_to(&mut buf), 24);
+        assert_eq!(buf[..12], data[..12]);
+        assert_eq!(buf[12..16], [0x81, 0x00, 0x12, 0x34]);
+        assert_eq!(buf[16..24], data[12..]);
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Internet checksum helpers shared by endpoints and devices that complete
+//! checksum offloads in software.
+
+/// The offset of the checksum in the TCP header.
+pub const TCP_CHECKSUM_OFFSET: u16 = 16;
+/// The offset of the checksum in the UDP header.
+pub const UDP_CHECKSUM_OFFSET: u16 = 6;
+
+/// Adds `data`, as big-endian 16-bit words, to the ones' complement sum `sum`.
+pub fn add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u64;
+    }
+    if let [b] = chunks.remainder() {
+        sum += (*b as u64) << 8;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` back into its low 16 bits.
+pub fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Returns the internet checksum of `frame` from `csum_start` to the end,
+/// including any partial checksum seeded at `field`, or `None` if the offsets
+/// do not fit in the frame.
+///
+/// This completes a checksum offload the way a host does for a virtio header
+/// with `VIRTIO_NET_HDR_F_NEEDS_CSUM`.
+pub fn complete(frame: &[u8], csum_start: usize, field: usize) -> Option<u16> {
+    if field.checked_add(2)? > frame.len() || field < csum_start {
+        return None;
+    }
+    // A zero checksum means "no checksum" for UDP, so use its ones' complement
+    // equivalent instead.
+    match !fold(add(0, &frame[csum_start..])) {
+        0 => Some(0xffff),
+        checksum => Some(checksum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::add;
+    use super::complete;
+    use super::fold;
+
+    #[test]
+    fn odd_length() {
+        assert_eq!(add(0, &[0x12, 0x34, 0x56]), 0x1234 + 0x5600);
+        assert_eq!(fold(0x1_fffe), 0xffff);
+        assert_eq!(fold(0xffff_ffff), 0xffff);
+    }
+
+    #[test]
+    fn software_checksum() {
+        // An ICMP echo request, checksummed from the start of the ICMP header
+        // with the field zeroed, as in RFC 1071.
+        let mut frame = vec![0; 14];
+        frame.extend([8, 0, 0, 0, 0x12, 0x34, 0, 1, b'a', b'b', b'c']);
+        let checksum = complete(&frame, 14, 16).unwrap();
+        frame[16..18].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(complete(&frame, 14, 16), Some(0xffff));
+        assert_eq!(checksum, !(0x0800u16 + 0x1234 + 0x0001 + 0x6162 + 0x6300));
+
+        // The seeded partial checksum is included in the sum.
+        frame[16..18].copy_from_slice(&0x0102u16.to_be_bytes());
+        assert_eq!(complete(&frame, 14, 16), Some(checksum - 0x0102));
+
+        // A sum that folds to zero is sent as 0xffff.
+        assert_eq!(complete(&[0xff, 0xff, 0, 0], 0, 2), Some(0xffff));
+
+        // Offsets outside the frame.
+        assert_eq!(complete(&frame, 14, frame.len() - 1), None);
+        assert_eq!(complete(&frame, 18, 16), None);
+    }
+}
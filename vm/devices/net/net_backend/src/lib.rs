@@ -4,6 +4,7 @@
 //! This module defines a trait and implementations thereof for network
 //! backends.
 
+pub mod checksum;
 pub mod loopback;
 pub mod null;
 pub mod resolve;
@@ -109,7 +110,8 @@ fn link_speed(&self) -> u64 {
 pub struct MultiQueueSupport {
     /// The number of supported queues.
     pub max_queues: u16,
-    /// The size of the RSS indirection table.
+    /// The size of the RSS indirection table, or zero if RSS is not supported
+    /// and the endpoint instead spreads packets across queues itself.
     pub indirection_table_size: u16,
 }
 
@@ -66,8 +66,11 @@ fn capacity(&self, _id: RxId) -> u32 {
         2048
     }
 
-    fn write_data(&mut self, _id: RxId, _: &[u8]) {
-        unimplemented!()
+    fn write_data(&mut self, id: RxId, data: &[u8]) {
+        self.inner
+            .guest_memory
+            .write_at(id.0 as u64 * 2048, data)
+            .unwrap();
     }
 
     fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
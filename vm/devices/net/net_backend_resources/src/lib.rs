@@ -37,6 +37,33 @@ pub mod consomme {
     pub struct ConsommeHandle {
         /// The CIDR of the network to use.
         pub cidr: Option<String>,
+        /// The IPv6 /64 prefix to advertise to the guest.
+        pub ipv6_prefix: Option<String>,
+        /// Host ports to forward to the guest.
+        pub port_forwards: Vec<ConsommePortForward>,
+    }
+
+    /// A host port to forward to a guest port.
+    #[derive(MeshPayload, Debug, Clone)]
+    pub struct ConsommePortForward {
+        /// The protocol to forward.
+        pub protocol: ConsommeProtocol,
+        /// The host address to listen on, or all IPv4 addresses if `None`.
+        /// IPv6 addresses are only supported for TCP.
+        pub host_addr: Option<String>,
+        /// The host port to listen on.
+        pub host_port: u16,
+        /// The guest port to forward to.
+        pub guest_port: u16,
+    }
+
+    /// A protocol for [`ConsommePortForward`].
+    #[derive(MeshPayload, Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ConsommeProtocol {
+        /// TCP.
+        Tcp,
+        /// UDP.
+        Udp,
     }
 
     impl ResourceId<NetEndpointHandleKind> for ConsommeHandle {
@@ -85,9 +112,100 @@ pub struct TapHandle {
         ///
         /// FUTURE: change this to a pre-opened `File`.
         pub name: String,
+        /// The number of queues to open. More than one requires a multiqueue
+        /// TAP device.
+        pub queues: u16,
     }
 
     impl ResourceId<NetEndpointHandleKind> for TapHandle {
         const ID: &'static str = "tap";
     }
 }
+
+/// User-mode switch backend.
+pub mod switch {
+    use mesh::MeshPayload;
+    use vm_resource::kind::NetEndpointHandleKind;
+    use vm_resource::ResourceId;
+
+    /// A handle to a port on a user-mode L2 switch, shared by all endpoints
+    /// attached to the same directory.
+    #[derive(MeshPayload)]
+    pub struct SwitchHandle {
+        /// The directory of the switch.
+        pub path: String,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for SwitchHandle {
+        const ID: &'static str = "switch";
+    }
+}
+
+/// Linux `AF_PACKET` backend.
+pub mod af_packet {
+    use mesh::MeshPayload;
+    use vm_resource::kind::NetEndpointHandleKind;
+    use vm_resource::ResourceId;
+
+    /// A handle to an existing host network interface, shared with the guest
+    /// via an `AF_PACKET` socket.
+    #[derive(MeshPayload)]
+    pub struct AfPacketHandle {
+        /// The name of the host interface.
+        pub interface: String,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for AfPacketHandle {
+        const ID: &'static str = "af_packet";
+    }
+}
+
+/// Packet capture wrapper for another backend.
+pub mod packet_capture {
+    use mesh::rpc::FailableRpc;
+    use mesh::MeshPayload;
+    use vm_resource::kind::NetEndpointHandleKind;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+
+    /// A handle to an endpoint whose traffic can be captured to pcapng files
+    /// while the VM is running.
+    #[derive(MeshPayload)]
+    pub struct PacketCaptureHandle {
+        /// The name used to identify the endpoint in traces.
+        pub name: String,
+        /// The endpoint to capture.
+        pub endpoint: Resource<NetEndpointHandleKind>,
+        /// The channel used to start and stop captures.
+        pub control: mesh::Receiver<PacketCaptureRequest>,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for PacketCaptureHandle {
+        const ID: &'static str = "packet_capture";
+    }
+
+    /// A request to control a packet capture.
+    #[derive(MeshPayload)]
+    pub enum PacketCaptureRequest {
+        /// Starts a capture, replacing any capture in progress.
+        Start(FailableRpc<PacketCaptureConfig, ()>),
+        /// Stops the capture in progress.
+        Stop(FailableRpc<(), ()>),
+    }
+
+    /// The configuration of a packet capture.
+    #[derive(MeshPayload, Debug, Clone)]
+    pub struct PacketCaptureConfig {
+        /// The path of the pcapng file to write.
+        pub path: String,
+        /// The maximum number of bytes of each packet to capture.
+        pub snaplen: u32,
+        /// A BPF-style expression selecting the packets to capture.
+        pub filter: Option<String>,
+        /// The maximum size of each capture file, in bytes.
+        pub max_file_size: Option<u64>,
+        /// The number of files to rotate through once a file reaches
+        /// `max_file_size`. With one file, the capture stops instead.
+        pub max_files: u32,
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Forwarding of host ports to guest ports.
+
+use super::Access;
+use super::Client;
+use super::DropReason;
+use super::PortForward;
+use smoltcp::wire::IpProtocol;
+use socket2::Domain;
+use socket2::Socket;
+use socket2::Type;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+/// Binds the host socket for a port forward.
+pub(crate) fn bind(rule: &PortForward) -> Result<Socket, DropReason> {
+    let ty = match rule.protocol {
+        IpProtocol::Tcp => Type::STREAM,
+        IpProtocol::Udp => Type::DGRAM,
+        p => return Err(DropReason::UnsupportedIpProtocol(p)),
+    };
+    let addr = SocketAddr::from((
+        rule.host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+        rule.host_port,
+    ));
+    // Datagrams from IPv6 peers could not be mapped back to their senders.
+    if rule.protocol == IpProtocol::Udp && addr.is_ipv6() {
+        return Err(DropReason::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "udp port forwards require an ipv4 host address",
+        )));
+    }
+    let socket = Socket::new(Domain::for_address(addr), ty, None).map_err(DropReason::Io)?;
+    if let Err(err) = socket.bind(&addr.into()) {
+        tracing::warn!(
+            ?addr,
+            error = &err as &dyn std::error::Error,
+            "port forward bind error"
+        );
+        return Err(DropReason::Io(err));
+    }
+    if rule.protocol == IpProtocol::Tcp {
+        socket.listen(10).map_err(DropReason::Io)?;
+    }
+    Ok(socket)
+}
+
+impl<T: Client> Access<'_, T> {
+    pub(crate) fn add_port_forward(&mut self, rule: &PortForward) -> Result<(), DropReason> {
+        let socket = bind(rule)?;
+        self.start_port_forward(rule, socket)
+    }
+
+    pub(crate) fn remove_port_forward(
+        &mut self,
+        protocol: IpProtocol,
+        host_port: u16,
+    ) -> Result<(), DropReason> {
+        match protocol {
+            IpProtocol::Tcp => self.remove_tcp_forward(host_port),
+            IpProtocol::Udp => self.remove_udp_forward(host_port),
+            p => Err(DropReason::UnsupportedIpProtocol(p)),
+        }
+    }
+
+    /// Starts the port forwards that were bound before there was a client to
+    /// poll them.
+    pub(crate) fn start_pending_port_forwards(&mut self) {
+        for (rule, socket) in std::mem::take(&mut self.inner.pending_forwards) {
+            if let Err(err) = self.start_port_forward(&rule, socket) {
+                tracing::error!(
+                    ?rule,
+                    error = &err as &dyn std::error::Error,
+                    "failed to start port forward"
+                );
+            }
+        }
+    }
+
+    fn start_port_forward(&mut self, rule: &PortForward, socket: Socket) -> Result<(), DropReason> {
+        match rule.protocol {
+            IpProtocol::Tcp => self.forward_tcp_port(rule.host_port, rule.guest_port, socket),
+            IpProtocol::Udp => {
+                self.forward_udp_port(rule.host_port, rule.guest_port, socket.into())
+            }
+            p => Err(DropReason::UnsupportedIpProtocol(p)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_state;
+    use crate::tests::TestClient;
+    use crate::Consomme;
+    use crate::DropReason;
+    use crate::PortForward;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use smoltcp::wire::EthernetFrame;
+    use smoltcp::wire::EthernetProtocol;
+    use smoltcp::wire::IpProtocol;
+    use smoltcp::wire::Ipv4Packet;
+    use smoltcp::wire::TcpPacket;
+    use smoltcp::wire::UdpPacket;
+    use std::future::poll_fn;
+    use std::net::Ipv4Addr;
+    use std::net::Ipv6Addr;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::net::UdpSocket;
+    use std::task::Poll;
+
+    const GUEST_PORT: u16 = 22;
+
+    /// Returns a host port that is likely to be free.
+    fn free_port(protocol: IpProtocol) -> u16 {
+        match protocol {
+            IpProtocol::Tcp => TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port(),
+            IpProtocol::Udp => UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port(),
+            p => panic!("unsupported protocol {p}"),
+        }
+    }
+
+    fn forward(protocol: IpProtocol) -> PortForward {
+        PortForward {
+            protocol,
+            host_addr: Some(Ipv4Addr::LOCALHOST.into()),
+            host_port: free_port(protocol),
+            guest_port: GUEST_PORT,
+        }
+    }
+
+    /// Polls until the guest receives an IPv4 packet of `protocol`, returning
+    /// the frame.
+    async fn recv_ipv4(
+        consomme: &mut Consomme,
+        client: &mut TestClient,
+        protocol: IpProtocol,
+    ) -> Vec<u8> {
+        poll_fn(|cx| {
+            consomme.access(client).poll(cx);
+            match client.rx.iter().position(|frame| {
+                let frame = EthernetFrame::new_unchecked(&frame[..]);
+                frame.ethertype() == EthernetProtocol::Ipv4
+                    && Ipv4Packet::new_checked(frame.payload())
+                        .is_ok_and(|ipv4| ipv4.protocol() == protocol)
+            }) {
+                Some(i) => Poll::Ready(client.rx.remove(i)),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    #[async_test]
+    async fn forward_tcp(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let rule = forward(IpProtocol::Tcp);
+        // Forwards added before the first poll start then.
+        consomme.add_port_forward(rule.clone()).unwrap();
+
+        let _stream = TcpStream::connect((Ipv4Addr::LOCALHOST, rule.host_port)).unwrap();
+        let frame = recv_ipv4(&mut consomme, &mut client, IpProtocol::Tcp).await;
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv4.dst_addr(), consomme.state.client_ip);
+        let tcp = TcpPacket::new_checked(ipv4.payload()).unwrap();
+        assert!(tcp.syn());
+        assert_eq!(tcp.dst_port(), GUEST_PORT);
+
+        let mut access = consomme.access(&mut client);
+        access
+            .remove_port_forward(IpProtocol::Tcp, rule.host_port)
+            .unwrap();
+        assert!(matches!(
+            access.remove_port_forward(IpProtocol::Tcp, rule.host_port),
+            Err(DropReason::PortNotBound)
+        ));
+        // The host port is free again.
+        TcpListener::bind((Ipv4Addr::LOCALHOST, rule.host_port)).unwrap();
+    }
+
+    #[async_test]
+    async fn forward_tcp_ipv6(driver: DefaultDriver) {
+        let Ok(listener) = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)) else {
+            println!("Test case skipped (no IPv6 loopback)");
+            return;
+        };
+        let host_port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let rule = PortForward {
+            protocol: IpProtocol::Tcp,
+            host_addr: Some(Ipv6Addr::LOCALHOST.into()),
+            host_port,
+            guest_port: GUEST_PORT,
+        };
+        consomme
+            .access(&mut client)
+            .add_port_forward(&rule)
+            .unwrap();
+
+        // The connection reaches the guest over IPv4, from the gateway.
+        let _stream = TcpStream::connect((Ipv6Addr::LOCALHOST, host_port)).unwrap();
+        let frame = recv_ipv4(&mut consomme, &mut client, IpProtocol::Tcp).await;
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv4.src_addr(), consomme.state.gateway_ip);
+        assert_eq!(ipv4.dst_addr(), consomme.state.client_ip);
+        let tcp = TcpPacket::new_checked(ipv4.payload()).unwrap();
+        assert!(tcp.syn());
+        assert_eq!(tcp.dst_port(), GUEST_PORT);
+    }
+
+    #[async_test]
+    async fn forward_udp(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let rule = forward(IpProtocol::Udp);
+        consomme
+            .access(&mut client)
+            .add_port_forward(&rule)
+            .unwrap();
+
+        let host = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        host.send_to(b"hello", (Ipv4Addr::LOCALHOST, rule.host_port))
+            .unwrap();
+        let frame = recv_ipv4(&mut consomme, &mut client, IpProtocol::Udp).await;
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv4.src_addr(), Ipv4Addr::LOCALHOST.into());
+        assert_eq!(ipv4.dst_addr(), consomme.state.client_ip);
+        let udp = UdpPacket::new_checked(ipv4.payload()).unwrap();
+        assert_eq!(udp.src_port(), host.local_addr().unwrap().port());
+        assert_eq!(udp.dst_port(), GUEST_PORT);
+        assert_eq!(udp.payload(), b"hello");
+
+        let mut access = consomme.access(&mut client);
+        // A second forward to the same guest port is rejected, as are IPv6
+        // host addresses.
+        assert!(matches!(
+            access.add_port_forward(&PortForward {
+                host_addr: Some(Ipv6Addr::LOCALHOST.into()),
+                ..forward(IpProtocol::Udp)
+            }),
+            Err(DropReason::Io(_))
+        ));
+        assert!(matches!(
+            access.add_port_forward(&forward(IpProtocol::Udp)),
+            Err(DropReason::Io(_))
+        ));
+        access
+            .remove_port_forward(IpProtocol::Udp, rule.host_port)
+            .unwrap();
+        assert!(matches!(
+            access.remove_port_forward(IpProtocol::Udp, rule.host_port),
+            Err(DropReason::PortNotBound)
+        ));
+    }
+
+    #[async_test]
+    async fn forwards_separate_from_binds(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let mut access = consomme.access(&mut client);
+
+        let rule = forward(IpProtocol::Tcp);
+        access.add_port_forward(&rule).unwrap();
+        let bound_port = free_port(IpProtocol::Tcp);
+        access
+            .bind_tcp_port(Some(Ipv4Addr::LOCALHOST), bound_port)
+            .unwrap();
+
+        // Unbinding doesn't remove forwards, and vice versa.
+        assert!(matches!(
+            access.unbind_tcp_port(rule.host_port),
+            Err(DropReason::PortNotBound)
+        ));
+        assert!(matches!(
+            access.remove_port_forward(IpProtocol::Tcp, bound_port),
+            Err(DropReason::PortNotBound)
+        ));
+        access
+            .remove_port_forward(IpProtocol::Tcp, rule.host_port)
+            .unwrap();
+        access.unbind_tcp_port(bound_port).unwrap();
+    }
+
+    #[async_test]
+    async fn forward_unsupported_protocol(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let rule = PortForward {
+            protocol: IpProtocol::Icmp,
+            host_addr: None,
+            host_port: 0,
+            guest_port: 0,
+        };
+        assert!(matches!(
+            consomme.access(&mut client).add_port_forward(&rule),
+            Err(DropReason::UnsupportedIpProtocol(IpProtocol::Icmp))
+        ));
+        assert!(matches!(
+            consomme
+                .access(&mut client)
+                .remove_port_forward(IpProtocol::Icmp, 0),
+            Err(DropReason::UnsupportedIpProtocol(IpProtocol::Icmp))
+        ));
+    }
+}
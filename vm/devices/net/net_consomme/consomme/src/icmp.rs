@@ -0,0 +1,793 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! ICMP echo proxying via unprivileged host ICMP sockets, and ICMP error
+//! generation for failed connections.
+//!
+//! Unprivileged ICMP sockets (`SOCK_DGRAM` with `IPPROTO_ICMP` or
+//! `IPPROTO_ICMPV6`) are supported on Linux, subject to the
+//! `net.ipv4.ping_group_range` sysctl. The host kernel assigns each socket its
+//! own echo identifier, so there is a socket per guest address and identifier,
+//! and replies are rewritten to carry the guest's identifier.
+
+use super::Access;
+use super::Client;
+use super::ConsommeState;
+use super::DropReason;
+use super::SocketAddress;
+use crate::emit_headers;
+use crate::ChecksumState;
+use crate::IpAddresses;
+use inspect::Inspect;
+use inspect::InspectMut;
+use inspect_counters::Counter;
+use pal_async::interest::InterestSlot;
+use pal_async::interest::PollEvents;
+use pal_async::socket::PolledSocket;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::EthernetAddress;
+use smoltcp::wire::EthernetRepr;
+use smoltcp::wire::Icmpv4DstUnreachable;
+use smoltcp::wire::Icmpv4Message;
+use smoltcp::wire::Icmpv4Packet;
+use smoltcp::wire::Icmpv4Repr;
+use smoltcp::wire::Icmpv6DstUnreachable;
+use smoltcp::wire::Icmpv6Message;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::Icmpv6Repr;
+use smoltcp::wire::IpProtocol;
+use smoltcp::wire::Ipv4Address;
+use smoltcp::wire::Ipv4Repr;
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Repr;
+use smoltcp::wire::ETHERNET_HEADER_LEN;
+use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::UdpSocket;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long an echo socket is kept after its last request.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The number of bytes of the original packet's transport header included in
+/// ICMP errors.
+const ERROR_DATA_LEN: usize = 8;
+
+pub(crate) struct Icmp {
+    /// Echo sockets, keyed by the guest address and echo identifier (in place
+    /// of the port).
+    connections: HashMap<SocketAddress, IcmpConnection>,
+}
+
+impl Icmp {
+    pub fn new() -> Self {
+        Self {
+            connections: HashMap::new(),
+        }
+    }
+}
+
+impl InspectMut for Icmp {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        for (addr, conn) in &mut self.connections {
+            resp.field_mut(&format!("{}:{}", addr.ip, addr.port), conn);
+        }
+    }
+}
+
+#[derive(InspectMut)]
+struct IcmpConnection {
+    #[inspect(skip)]
+    socket: Option<PolledSocket<UdpSocket>>,
+    #[inspect(display)]
+    guest_mac: EthernetAddress,
+    #[inspect(debug)]
+    last_active: Instant,
+    stats: Stats,
+}
+
+#[derive(Inspect, Default)]
+struct Stats {
+    tx_packets: Counter,
+    tx_dropped: Counter,
+    tx_errors: Counter,
+    rx_packets: Counter,
+}
+
+impl IcmpConnection {
+    fn poll_conn(
+        &mut self,
+        cx: &mut Context<'_>,
+        guest_addr: &SocketAddress,
+        state: &mut ConsommeState,
+        client: &mut impl Client,
+    ) -> bool {
+        if self.last_active.elapsed() >= IDLE_TIMEOUT {
+            return false;
+        }
+
+        let header_len = ETHERNET_HEADER_LEN
+            + match guest_addr.ip {
+                IpAddr::V4(_) => IPV4_HEADER_LEN,
+                IpAddr::V6(_) => IPV6_HEADER_LEN,
+            };
+        loop {
+            if client.rx_mtu() == 0 {
+                break true;
+            }
+            match self.socket.as_mut().unwrap().poll_io(
+                cx,
+                InterestSlot::Read,
+                PollEvents::IN,
+                |socket| socket.get().recv_from(&mut state.buffer[header_len..]),
+            ) {
+                Poll::Ready(Ok((n, src_addr))) => {
+                    let protocol = match guest_addr.ip {
+                        IpAddr::V4(_) => IpProtocol::Icmp,
+                        IpAddr::V6(_) => IpProtocol::Icmpv6,
+                    };
+                    if emit_headers(
+                        &mut state.buffer,
+                        state.gateway_mac,
+                        self.guest_mac,
+                        src_addr.ip(),
+                        guest_addr.ip,
+                        protocol,
+                        n,
+                    )
+                    .is_none()
+                    {
+                        continue;
+                    }
+                    let buffer = &mut state.buffer[..header_len + n];
+                    if !set_echo_reply(
+                        &mut buffer[header_len..],
+                        src_addr.ip(),
+                        guest_addr.ip,
+                        guest_addr.port,
+                    ) {
+                        continue;
+                    }
+                    client.recv(buffer, &checksum_state(guest_addr.ip));
+                    self.stats.rx_packets.increment();
+                }
+                Poll::Ready(Err(err)) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "recv error");
+                    break false;
+                }
+                Poll::Pending => break true,
+            }
+        }
+    }
+}
+
+/// Turns the ICMP message in `data` into an echo reply to the guest with echo
+/// identifier `ident`, returning false if it is not an echo message.
+fn set_echo_reply(data: &mut [u8], src_addr: IpAddr, dst_addr: IpAddr, ident: u16) -> bool {
+    match dst_addr {
+        IpAddr::V4(_) => {
+            let Ok(mut packet) = Icmpv4Packet::new_checked(data) else {
+                return false;
+            };
+            if !matches!(
+                packet.msg_type(),
+                Icmpv4Message::EchoRequest | Icmpv4Message::EchoReply
+            ) {
+                return false;
+            }
+            packet.set_msg_type(Icmpv4Message::EchoReply);
+            packet.set_echo_ident(ident);
+            packet.fill_checksum();
+        }
+        IpAddr::V6(_) => {
+            let Ok(mut packet) = Icmpv6Packet::new_checked(data) else {
+                return false;
+            };
+            if !matches!(
+                packet.msg_type(),
+                Icmpv6Message::EchoRequest | Icmpv6Message::EchoReply
+            ) {
+                return false;
+            }
+            packet.set_msg_type(Icmpv6Message::EchoReply);
+            packet.set_echo_ident(ident);
+            packet.fill_checksum(&src_addr.into(), &dst_addr.into());
+        }
+    }
+    true
+}
+
+fn checksum_state(addr: IpAddr) -> ChecksumState {
+    if addr.is_ipv4() {
+        ChecksumState::IPV4_ONLY
+    } else {
+        ChecksumState::NONE
+    }
+}
+
+/// The reason a destination is unreachable.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Unreachable {
+    Network,
+    Host,
+    Port,
+}
+
+impl Unreachable {
+    /// Returns the reason corresponding to a host socket error, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        if err.kind() == ErrorKind::ConnectionRefused {
+            return Some(Self::Port);
+        }
+        let code = err.raw_os_error()?;
+        #[cfg(unix)]
+        let (network, host) = (libc::ENETUNREACH, libc::EHOSTUNREACH);
+        #[cfg(windows)]
+        let (network, host) = {
+            use windows_sys::Win32::Networking::WinSock;
+            (WinSock::WSAENETUNREACH, WinSock::WSAEHOSTUNREACH)
+        };
+        if code == network {
+            Some(Self::Network)
+        } else if code == host {
+            Some(Self::Host)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends an ICMP destination unreachable error to the guest for a packet it
+/// sent from `src_addr` to `dst_addr`, whose transport header begins with
+/// `transport`.
+pub(crate) fn send_unreachable(
+    client: &mut impl Client,
+    state: &mut ConsommeState,
+    guest_mac: EthernetAddress,
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    protocol: IpProtocol,
+    transport: &[u8],
+    reason: Unreachable,
+) {
+    let data = &transport[..transport.len().min(ERROR_DATA_LEN)];
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            // Port unreachable errors come from the destination itself, others
+            // from the gateway.
+            let error_src = match reason {
+                Unreachable::Port => dst_addr.into(),
+                Unreachable::Network | Unreachable::Host => state.gateway_ip,
+            };
+            let icmp = Icmpv4Repr::DstUnreachable {
+                reason: match reason {
+                    Unreachable::Network => Icmpv4DstUnreachable::NetUnreachable,
+                    Unreachable::Host => Icmpv4DstUnreachable::HostUnreachable,
+                    Unreachable::Port => Icmpv4DstUnreachable::PortUnreachable,
+                },
+                header: Ipv4Repr {
+                    src_addr: src_addr.into(),
+                    dst_addr: dst_addr.into(),
+                    protocol,
+                    payload_len: data.len(),
+                    hop_limit: 64,
+                },
+                data,
+            };
+            let Some(header_len) = emit_headers(
+                &mut state.buffer,
+                state.gateway_mac,
+                guest_mac,
+                IpAddr::V4(error_src.into()),
+                IpAddr::V4(src_addr),
+                IpProtocol::Icmp,
+                icmp.buffer_len(),
+            ) else {
+                return;
+            };
+            let buffer = &mut state.buffer[..header_len + icmp.buffer_len()];
+            icmp.emit(
+                &mut Icmpv4Packet::new_unchecked(&mut buffer[header_len..]),
+                &ChecksumCapabilities::default(),
+            );
+            client.recv(buffer, &ChecksumState::IPV4_ONLY);
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            let error_src = match reason {
+                Unreachable::Port => dst_addr.into(),
+                Unreachable::Network | Unreachable::Host => state.gateway_link_local(),
+            };
+            let icmp = Icmpv6Repr::DstUnreachable {
+                reason: match reason {
+                    Unreachable::Network => Icmpv6DstUnreachable::NoRoute,
+                    Unreachable::Host => Icmpv6DstUnreachable::AddrUnreachable,
+                    Unreachable::Port => Icmpv6DstUnreachable::PortUnreachable,
+                },
+                header: Ipv6Repr {
+                    src_addr: src_addr.into(),
+                    dst_addr: dst_addr.into(),
+                    next_header: protocol,
+                    payload_len: data.len(),
+                    hop_limit: 64,
+                },
+                data,
+            };
+            let Some(header_len) = emit_headers(
+                &mut state.buffer,
+                state.gateway_mac,
+                guest_mac,
+                IpAddr::V6(Ipv6Addr::from(error_src)),
+                IpAddr::V6(src_addr),
+                IpProtocol::Icmpv6,
+                icmp.buffer_len(),
+            ) else {
+                return;
+            };
+            let buffer = &mut state.buffer[..header_len + icmp.buffer_len()];
+            icmp.emit(
+                &error_src.into(),
+                &Ipv6Address::from(src_addr).into(),
+                &mut Icmpv6Packet::new_unchecked(&mut buffer[header_len..]),
+                &ChecksumCapabilities::default(),
+            );
+            client.recv(buffer, &ChecksumState::NONE);
+        }
+        // A packet can't have addresses of different families.
+        _ => {}
+    }
+}
+
+impl<T: Client> Access<'_, T> {
+    pub(crate) fn poll_icmp(&mut self, cx: &mut Context<'_>) {
+        self.inner.icmp.connections.retain(|guest_addr, conn| {
+            conn.poll_conn(cx, guest_addr, &mut self.inner.state, self.client)
+        });
+    }
+
+    pub(crate) fn refresh_icmp_driver(&mut self) {
+        self.inner.icmp.connections.retain(|_, conn| {
+            let socket = conn.socket.take().unwrap().into_inner();
+            match PolledSocket::new(self.client.driver(), socket) {
+                Ok(socket) => {
+                    conn.socket = Some(socket);
+                    true
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to update driver for icmp connection"
+                    );
+                    false
+                }
+            }
+        });
+    }
+
+    pub(crate) fn handle_icmp(
+        &mut self,
+        frame: &EthernetRepr,
+        addresses: &IpAddresses,
+        payload: &[u8],
+    ) -> Result<(), DropReason> {
+        let icmp = Icmpv4Repr::parse(
+            &Icmpv4Packet::new_checked(payload)?,
+            &ChecksumCapabilities::default(),
+        )?;
+        match icmp {
+            Icmpv4Repr::EchoRequest { ident, .. } => {
+                self.handle_echo(frame, addresses, ident, payload)
+            }
+            _ => Err(DropReason::UnsupportedIcmp),
+        }
+    }
+
+    pub(crate) fn handle_icmpv6(
+        &mut self,
+        frame: &EthernetRepr,
+        addresses: &IpAddresses,
+        hop_limit: u8,
+        payload: &[u8],
+    ) -> Result<(), DropReason> {
+        let icmp = Icmpv6Repr::parse(
+            &addresses.src_addr.into(),
+            &addresses.dst_addr.into(),
+            &Icmpv6Packet::new_checked(payload)?,
+            &ChecksumCapabilities::default(),
+        )?;
+        match icmp {
+            Icmpv6Repr::EchoRequest { ident, .. } => {
+                self.handle_echo(frame, addresses, ident, payload)
+            }
+            Icmpv6Repr::Ndisc(ndisc) => {
+                let IpAddr::V6(src_addr) = addresses.src_addr else {
+                    return Err(DropReason::Packet(smoltcp::Error::Malformed));
+                };
+                self.handle_ndisc(frame, src_addr.into(), hop_limit, ndisc)
+            }
+            _ => Err(DropReason::UnsupportedIcmpv6),
+        }
+    }
+
+    fn handle_echo(
+        &mut self,
+        frame: &EthernetRepr,
+        addresses: &IpAddresses,
+        ident: u16,
+        payload: &[u8],
+    ) -> Result<(), DropReason> {
+        // Answer pings to the gateway directly.
+        let is_gateway = match addresses.dst_addr {
+            IpAddr::V4(addr) => Ipv4Address::from(addr) == self.inner.state.gateway_ip,
+            IpAddr::V6(addr) => Ipv6Address::from(addr) == self.inner.state.gateway_link_local(),
+        };
+        if is_gateway {
+            let state = &mut self.inner.state;
+            let protocol = match addresses.src_addr {
+                IpAddr::V4(_) => IpProtocol::Icmp,
+                IpAddr::V6(_) => IpProtocol::Icmpv6,
+            };
+            let header_len = emit_headers(
+                &mut state.buffer,
+                state.gateway_mac,
+                frame.src_addr,
+                addresses.dst_addr,
+                addresses.src_addr,
+                protocol,
+                payload.len(),
+            )
+            .ok_or(DropReason::Packet(smoltcp::Error::Malformed))?;
+            let buffer = &mut state.buffer[..header_len + payload.len()];
+            buffer[header_len..].copy_from_slice(payload);
+            set_echo_reply(
+                &mut buffer[header_len..],
+                addresses.dst_addr,
+                addresses.src_addr,
+                ident,
+            );
+            self.client
+                .recv(buffer, &checksum_state(addresses.src_addr));
+            return Ok(());
+        }
+
+        let guest_addr = SocketAddress {
+            ip: addresses.src_addr,
+            port: ident,
+        };
+        let conn = match self.inner.icmp.connections.entry(guest_addr) {
+            hash_map::Entry::Occupied(conn) => conn.into_mut(),
+            hash_map::Entry::Vacant(e) => {
+                let (domain, protocol) = match guest_addr.ip {
+                    IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+                    IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+                };
+                let socket =
+                    Socket::new(domain, Type::DGRAM, Some(protocol)).map_err(DropReason::Io)?;
+                let socket = PolledSocket::new(self.client.driver(), UdpSocket::from(socket))
+                    .map_err(DropReason::Io)?;
+                e.insert(IcmpConnection {
+                    socket: Some(socket),
+                    guest_mac: frame.src_addr,
+                    last_active: Instant::now(),
+                    stats: Default::default(),
+                })
+            }
+        };
+
+        conn.last_active = Instant::now();
+        // The host stack replaces the identifier and checksum.
+        match conn
+            .socket
+            .as_mut()
+            .unwrap()
+            .get()
+            .send_to(payload, (addresses.dst_addr, 0))
+        {
+            Ok(_) => {
+                conn.stats.tx_packets.increment();
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                conn.stats.tx_dropped.increment();
+                Err(DropReason::SendBufferFull)
+            }
+            Err(err) => {
+                conn.stats.tx_errors.increment();
+                Err(DropReason::Io(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Unreachable;
+    use crate::tests::ipv4_frame;
+    use crate::tests::ipv6_frame;
+    use crate::tests::test_state;
+    use crate::tests::TestClient;
+    use crate::ChecksumState;
+    use crate::Consomme;
+    use crate::DropReason;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::EthernetFrame;
+    use smoltcp::wire::EthernetProtocol;
+    use smoltcp::wire::Icmpv4DstUnreachable;
+    use smoltcp::wire::Icmpv4Packet;
+    use smoltcp::wire::Icmpv4Repr;
+    use smoltcp::wire::Icmpv6Packet;
+    use smoltcp::wire::Icmpv6Repr;
+    use smoltcp::wire::IpProtocol;
+    use smoltcp::wire::Ipv4Address;
+    use smoltcp::wire::Ipv4Packet;
+    use smoltcp::wire::Ipv6Address;
+    use smoltcp::wire::TcpControl;
+    use smoltcp::wire::TcpPacket;
+    use smoltcp::wire::TcpRepr;
+    use smoltcp::wire::TcpSeqNumber;
+    use std::future::poll_fn;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::task::Poll;
+
+    /// Builds a SYN from the guest to `dst_addr`:`dst_port`.
+    fn syn(consomme: &Consomme, dst_addr: Ipv4Address, dst_port: u16) -> Vec<u8> {
+        let src_addr = consomme.state.client_ip;
+        let tcp = TcpRepr {
+            src_port: 5000,
+            dst_port,
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None, None, None],
+            payload: &[],
+        };
+        ipv4_frame(
+            src_addr,
+            dst_addr,
+            consomme.state.gateway_mac,
+            IpProtocol::Tcp,
+            tcp.header_len(),
+            |buf| {
+                tcp.emit(
+                    &mut TcpPacket::new_unchecked(buf),
+                    &src_addr.into(),
+                    &dst_addr.into(),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        )
+    }
+
+    #[test]
+    fn unreachable_from_io_error() {
+        assert!(matches!(
+            Unreachable::from_io_error(&ErrorKind::ConnectionRefused.into()),
+            Some(Unreachable::Port)
+        ));
+        #[cfg(unix)]
+        {
+            assert!(matches!(
+                Unreachable::from_io_error(&io::Error::from_raw_os_error(libc::ENETUNREACH)),
+                Some(Unreachable::Network)
+            ));
+            assert!(matches!(
+                Unreachable::from_io_error(&io::Error::from_raw_os_error(libc::EHOSTUNREACH)),
+                Some(Unreachable::Host)
+            ));
+        }
+        assert!(Unreachable::from_io_error(&ErrorKind::TimedOut.into()).is_none());
+    }
+
+    #[async_test]
+    async fn ping_gateway(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let src_addr = consomme.state.client_ip;
+        let dst_addr = consomme.state.gateway_ip;
+        let icmp = Icmpv4Repr::EchoRequest {
+            ident: 0x1234,
+            seq_no: 7,
+            data: b"ping",
+        };
+        let frame = ipv4_frame(
+            src_addr,
+            dst_addr,
+            consomme.state.gateway_mac,
+            IpProtocol::Icmp,
+            icmp.buffer_len(),
+            |buf| {
+                icmp.emit(
+                    &mut Icmpv4Packet::new_unchecked(buf),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        );
+        consomme
+            .access(&mut client)
+            .send(&frame, &ChecksumState::NONE)
+            .unwrap();
+
+        let frame = client.rx.pop().unwrap();
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv4.src_addr(), dst_addr);
+        assert_eq!(ipv4.dst_addr(), src_addr);
+        let reply = Icmpv4Repr::parse(
+            &Icmpv4Packet::new_checked(ipv4.payload()).unwrap(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            Icmpv4Repr::EchoReply {
+                ident: 0x1234,
+                seq_no: 7,
+                data: b"ping",
+            }
+        );
+    }
+
+    #[async_test]
+    async fn unsupported_icmp(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+
+        let icmp = Icmpv4Repr::EchoReply {
+            ident: 0,
+            seq_no: 0,
+            data: &[],
+        };
+        let frame = ipv4_frame(
+            consomme.state.client_ip,
+            consomme.state.gateway_ip,
+            consomme.state.gateway_mac,
+            IpProtocol::Icmp,
+            icmp.buffer_len(),
+            |buf| {
+                icmp.emit(
+                    &mut Icmpv4Packet::new_unchecked(buf),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        );
+        assert!(matches!(
+            consomme
+                .access(&mut client)
+                .send(&frame, &ChecksumState::NONE),
+            Err(DropReason::UnsupportedIcmp)
+        ));
+
+        let src_addr = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let dst_addr = consomme.state.gateway_link_local();
+        let icmp = Icmpv6Repr::EchoReply {
+            ident: 0,
+            seq_no: 0,
+            data: &[],
+        };
+        let frame = ipv6_frame(
+            src_addr,
+            dst_addr,
+            consomme.state.gateway_mac,
+            IpProtocol::Icmpv6,
+            64,
+            icmp.buffer_len(),
+            |buf| {
+                icmp.emit(
+                    &src_addr.into(),
+                    &dst_addr.into(),
+                    &mut Icmpv6Packet::new_unchecked(buf),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        );
+        assert!(matches!(
+            consomme
+                .access(&mut client)
+                .send(&frame, &ChecksumState::NONE),
+            Err(DropReason::UnsupportedIcmpv6)
+        ));
+        assert!(client.rx.is_empty());
+    }
+
+    /// Linux fails TCP connects to multicast addresses synchronously with
+    /// ENETUNREACH, which should be reported to the guest as an ICMP error
+    /// rather than a reset.
+    #[cfg(target_os = "linux")]
+    #[async_test]
+    async fn tcp_connect_unreachable(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let dst_addr = Ipv4Address::new(224, 0, 0, 1);
+        let frame = syn(&consomme, dst_addr, 80);
+        assert!(matches!(
+            consomme
+                .access(&mut client)
+                .send(&frame, &ChecksumState::NONE),
+            Err(DropReason::Io(_))
+        ));
+
+        let frame = client.rx.pop().unwrap();
+        assert!(client.rx.is_empty());
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv4.src_addr(), consomme.state.gateway_ip);
+        assert_eq!(ipv4.dst_addr(), consomme.state.client_ip);
+        assert_eq!(ipv4.protocol(), IpProtocol::Icmp);
+        let Icmpv4Repr::DstUnreachable {
+            reason,
+            header,
+            data,
+        } = Icmpv4Repr::parse(
+            &Icmpv4Packet::new_checked(ipv4.payload()).unwrap(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap()
+        else {
+            panic!("expected destination unreachable");
+        };
+        assert_eq!(reason, Icmpv4DstUnreachable::NetUnreachable);
+        assert_eq!(header.dst_addr, dst_addr);
+        assert_eq!(header.protocol, IpProtocol::Tcp);
+        // The ports and sequence number of the SYN.
+        assert_eq!(data[..2], 5000u16.to_be_bytes());
+        assert_eq!(data[2..4], 80u16.to_be_bytes());
+        assert_eq!(data[4..8], 1000u32.to_be_bytes());
+    }
+
+    #[async_test]
+    async fn tcp_connect_refused(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        // Find a port that nothing is listening on.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let frame = syn(&consomme, Ipv4Address::new(127, 0, 0, 1), port);
+        consomme
+            .access(&mut client)
+            .send(&frame, &ChecksumState::NONE)
+            .unwrap();
+
+        // Wait for the reset, ignoring router advertisements.
+        let frame = poll_fn(|cx| {
+            consomme.access(&mut client).poll(cx);
+            match client.rx.iter().position(|frame| {
+                EthernetFrame::new_checked(&frame[..])
+                    .is_ok_and(|eth| eth.ethertype() == EthernetProtocol::Ipv4)
+            }) {
+                Some(i) => Poll::Ready(client.rx.remove(i)),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        let ipv4 = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        let tcp = TcpPacket::new_checked(ipv4.payload()).unwrap();
+        assert!(tcp.rst());
+        assert_eq!(tcp.src_port(), port);
+        assert_eq!(tcp.dst_port(), 5000);
+        assert_eq!(tcp.ack_number(), TcpSeqNumber(1001));
+    }
+}
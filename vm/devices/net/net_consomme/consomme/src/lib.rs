@@ -12,7 +12,9 @@
 //! essentially causing this stack to act as a NAT implementation, providing
 //! guest OS networking by leveraging the host's network stack.
 //!
-//! This implementation includes a small DHCP server for address assignment.
+//! This implementation includes a small DHCP server for IPv4 address
+//! assignment and sends IPv6 router advertisements for stateless address
+//! autoconfiguration and DNS server discovery.
 
 #![warn(missing_docs)]
 
@@ -21,6 +23,9 @@
 #[cfg_attr(unix, path = "dns_unix.rs")]
 #[cfg_attr(windows, path = "dns_windows.rs")]
 mod dns;
+mod forward;
+mod icmp;
+mod ndp;
 mod tcp;
 mod udp;
 mod windows;
@@ -39,9 +44,18 @@
 use smoltcp::wire::IpProtocol;
 use smoltcp::wire::Ipv4Address;
 use smoltcp::wire::Ipv4Packet;
+use smoltcp::wire::Ipv4Repr;
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Cidr;
+use smoltcp::wire::Ipv6Packet;
+use smoltcp::wire::Ipv6Repr;
+use smoltcp::wire::ETHERNET_HEADER_LEN;
 use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
-use std::net::SocketAddrV4;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::task::Context;
 use std::task::Poll;
 use thiserror::Error;
@@ -66,9 +80,28 @@ struct MessageBindPort {
     port: u16,
 }
 
+/// A rule forwarding a host port to a guest port.
+#[derive(Debug, Clone)]
+pub struct PortForward {
+    /// The protocol to forward, either TCP or UDP.
+    pub protocol: IpProtocol,
+    /// The host address to listen on, or all IPv4 addresses if `None`.
+    ///
+    /// The guest is always reached over IPv4, so TCP connections from IPv6
+    /// peers appear to the guest to come from the gateway. UDP forwards only
+    /// support IPv4 host addresses.
+    pub host_addr: Option<IpAddr>,
+    /// The host port to listen on.
+    pub host_port: u16,
+    /// The guest port to forward to.
+    pub guest_port: u16,
+}
+
 enum ConsommeMessage {
     BindPort(Rpc<MessageBindPort, Result<(), DropReason>>),
     UnbindPort(Rpc<MessageBindPort, Result<(), DropReason>>),
+    AddPortForward(Rpc<PortForward, Result<(), DropReason>>),
+    RemovePortForward(Rpc<(IpProtocol, u16), Result<(), DropReason>>),
     UpdateState(Rpc<ConsommeStateUpdateFn, ()>),
 }
 
@@ -119,6 +152,29 @@ pub async fn unbind_port(
             .map_err(ConsommeMessageError::Network)
     }
 
+    /// Forwards a host port to a guest port.
+    pub async fn add_port_forward(&self, rule: PortForward) -> Result<(), ConsommeMessageError> {
+        self.send
+            .call(ConsommeMessage::AddPortForward, rule)
+            .await
+            .map_err(ConsommeMessageError::Mesh)?
+            .map_err(ConsommeMessageError::Network)
+    }
+
+    /// Removes the forward of a host port, added with add_port_forward() or
+    /// [`Consomme::add_port_forward`].
+    pub async fn remove_port_forward(
+        &self,
+        protocol: IpProtocol,
+        host_port: u16,
+    ) -> Result<(), ConsommeMessageError> {
+        self.send
+            .call(ConsommeMessage::RemovePortForward, (protocol, host_port))
+            .await
+            .map_err(ConsommeMessageError::Mesh)?
+            .map_err(ConsommeMessageError::Network)
+    }
+
     /// Updates dynamic network state
     pub async fn update_state(&self, f: ConsommeStateUpdateFn) -> Result<(), ConsommeMessageError> {
         self.send
@@ -134,13 +190,17 @@ pub struct Consomme {
     recv: Option<mesh::Receiver<ConsommeMessage>>,
     tcp: tcp::Tcp,
     udp: udp::Udp,
+    icmp: icmp::Icmp,
+    ndp: ndp::Ndp,
+    pending_forwards: Vec<(PortForward, socket2::Socket)>,
 }
 
 impl InspectMut for Consomme {
     fn inspect_mut(&mut self, req: inspect::Request<'_>) {
         req.respond()
             .field("tcp", &self.tcp)
-            .field_mut("udp", &mut self.udp);
+            .field_mut("udp", &mut self.udp)
+            .field_mut("icmp", &mut self.icmp);
     }
 }
 
@@ -158,6 +218,12 @@ pub struct ConsommeState {
     pub client_mac: EthernetAddress,
     /// Current list of DNS resolvers.
     pub nameservers: Vec<Ipv4Address>,
+    /// Current list of IPv6 DNS resolvers, advertised to the guest in router
+    /// advertisements.
+    pub ipv6_nameservers: Vec<Ipv6Address>,
+    /// Current IPv6 prefix, advertised to the guest for stateless address
+    /// autoconfiguration. This is always a /64.
+    pub ipv6_prefix: Ipv6Cidr,
     /// Buffer for packet processing
     buffer: Box<[u8]>,
 }
@@ -171,18 +237,32 @@ impl ConsommeState {
     /// Create default dynamic network state. The default state is
     ///     IP address: 10.0.0.2 / 24
     ///     gateway: 10.0.0.1 with MAC address 52-55-10-0-0-1
-    ///     no DNS resolvers
+    ///     IPv6 prefix: fd00::/64
+    ///     the host's DNS resolvers
     pub fn new() -> Result<Self, Error> {
-        let nameservers = dns::nameservers()?;
-        Ok(Self {
+        Ok(Self::with_nameservers(dns::nameservers()?))
+    }
+
+    fn with_nameservers(nameservers: Vec<IpAddr>) -> Self {
+        let mut ipv4_nameservers = Vec::new();
+        let mut ipv6_nameservers = Vec::new();
+        for addr in nameservers {
+            match addr {
+                IpAddr::V4(addr) => ipv4_nameservers.push(addr.into()),
+                IpAddr::V6(addr) => ipv6_nameservers.push(addr.into()),
+            }
+        }
+        Self {
             gateway_ip: Ipv4Address::new(10, 0, 0, 1),
             gateway_mac: EthernetAddress([0x52, 0x55, 10, 0, 0, 1]),
             client_ip: Ipv4Address::new(10, 0, 0, 2),
             client_mac: EthernetAddress([0x0, 0x0, 0x0, 0x0, 0x1, 0x0]),
             net_mask: Ipv4Address::new(255, 255, 255, 0),
-            nameservers,
+            nameservers: ipv4_nameservers,
+            ipv6_nameservers,
+            ipv6_prefix: Ipv6Cidr::new(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0), 64),
             buffer: Box::new([0; 65535]),
-        })
+        }
     }
 
     /// Sets the cidr for the network.
@@ -199,6 +279,38 @@ pub fn set_cidr(&mut self, cidr: &str) -> Result<(), InvalidCidr> {
         self.net_mask = cidr.netmask();
         Ok(())
     }
+
+    /// Sets the IPv6 prefix for the network.
+    ///
+    /// The prefix must be a /64, since guests derive their addresses from it
+    /// via stateless address autoconfiguration. Any host bits are ignored.
+    pub fn set_ipv6_prefix(&mut self, prefix: &str) -> Result<(), InvalidCidr> {
+        let cidr: Ipv6Cidr = prefix.parse().map_err(|()| InvalidCidr)?;
+        if cidr.prefix_len() != 64 {
+            return Err(InvalidCidr);
+        }
+        let network = u128::from(Ipv6Addr::from(cidr.address())) & !(u64::MAX as u128);
+        self.ipv6_prefix = Ipv6Cidr::new(Ipv6Addr::from(network).into(), 64);
+        Ok(())
+    }
+
+    /// The gateway's IPv6 link-local address, derived from its MAC address.
+    fn gateway_link_local(&self) -> Ipv6Address {
+        let mac = self.gateway_mac.0;
+        let mut addr = [0; 16];
+        addr[..2].copy_from_slice(&[0xfe, 0x80]);
+        addr[8..].copy_from_slice(&[
+            mac[0] ^ 2,
+            mac[1],
+            mac[2],
+            0xff,
+            0xfe,
+            mac[3],
+            mac[4],
+            mac[5],
+        ]);
+        Ipv6Address(addr)
+    }
 }
 
 /// An accessor for consomme.
@@ -282,6 +394,18 @@ impl ChecksumState {
         udp: true,
         tso: None,
     };
+    const TCP6: Self = Self {
+        ipv4: false,
+        tcp: true,
+        udp: false,
+        tso: None,
+    };
+    const UDP6: Self = Self {
+        ipv4: false,
+        tcp: false,
+        udp: true,
+        tso: None,
+    };
 
     fn caps(&self) -> ChecksumCapabilities {
         let mut caps = ChecksumCapabilities::default();
@@ -304,19 +428,19 @@ fn caps(&self) -> ChecksumCapabilities {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 struct SocketAddress {
-    ip: Ipv4Address,
+    ip: IpAddr,
     port: u16,
 }
 
-impl From<SocketAddress> for SocketAddrV4 {
+impl From<SocketAddress> for SocketAddr {
     fn from(addr: SocketAddress) -> Self {
-        Self::new(addr.ip.into(), addr.port)
+        Self::new(addr.ip, addr.port)
     }
 }
 
 impl From<SocketAddress> for socket2::SockAddr {
     fn from(addr: SocketAddress) -> Self {
-        socket2::SockAddr::from(SocketAddrV4::from(addr))
+        socket2::SockAddr::from(SocketAddr::from(addr))
     }
 }
 
@@ -344,6 +468,12 @@ pub enum DropReason {
     /// The ARP type is unsupported.
     #[error("unsupported arp type")]
     UnsupportedArp,
+    /// The ICMP message type is unsupported.
+    #[error("unsupported icmp message")]
+    UnsupportedIcmp,
+    /// The ICMPv6 message type is unsupported.
+    #[error("unsupported icmpv6 message")]
+    UnsupportedIcmpv6,
     /// The IPv4 checksum was invalid.
     #[error("ipv4 checksum failure")]
     Ipv4Checksum,
@@ -370,9 +500,58 @@ pub enum Error {
 }
 
 #[derive(Debug)]
-struct Ipv4Addresses {
-    src_addr: Ipv4Address,
-    dst_addr: Ipv4Address,
+struct IpAddresses {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+}
+
+/// Writes the Ethernet and IP headers for a packet from `src_addr` to
+/// `dst_addr` into `buffer`, returning the offset of the IP payload.
+///
+/// Returns `None` if the addresses are of different families, since such a
+/// packet cannot be represented.
+fn emit_headers(
+    buffer: &mut [u8],
+    src_mac: EthernetAddress,
+    dst_mac: EthernetAddress,
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    protocol: IpProtocol,
+    payload_len: usize,
+) -> Option<usize> {
+    let mut eth = EthernetFrame::new_unchecked(buffer);
+    eth.set_src_addr(src_mac);
+    eth.set_dst_addr(dst_mac);
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            eth.set_ethertype(EthernetProtocol::Ipv4);
+            Ipv4Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                protocol,
+                payload_len,
+                hop_limit: 64,
+            }
+            .emit(
+                &mut Ipv4Packet::new_unchecked(eth.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            Some(ETHERNET_HEADER_LEN + IPV4_HEADER_LEN)
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            eth.set_ethertype(EthernetProtocol::Ipv6);
+            Ipv6Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: protocol,
+                payload_len,
+                hop_limit: 64,
+            }
+            .emit(&mut Ipv6Packet::new_unchecked(eth.payload_mut()));
+            Some(ETHERNET_HEADER_LEN + IPV6_HEADER_LEN)
+        }
+        _ => None,
+    }
 }
 
 impl Consomme {
@@ -389,6 +568,9 @@ pub fn new_with_state(state: ConsommeState) -> Self {
             recv: None,
             tcp: tcp::Tcp::new(),
             udp: udp::Udp::new(),
+            icmp: icmp::Icmp::new(),
+            ndp: ndp::Ndp::new(),
+            pending_forwards: Vec::new(),
         }
     }
 
@@ -400,11 +582,24 @@ pub fn new_dynamic(state: ConsommeState) -> (Self, ConsommeControl) {
             recv: Some(recv),
             tcp: tcp::Tcp::new(),
             udp: udp::Udp::new(),
+            icmp: icmp::Icmp::new(),
+            ndp: ndp::Ndp::new(),
+            pending_forwards: Vec::new(),
         };
         let control = ConsommeControl { send };
         (this, control)
     }
 
+    /// Forwards a host port to a guest port.
+    ///
+    /// The host socket is bound immediately, but the forward does not start
+    /// until the instance is first polled.
+    pub fn add_port_forward(&mut self, rule: PortForward) -> Result<(), DropReason> {
+        let socket = forward::bind(&rule)?;
+        self.pending_forwards.push((rule, socket));
+        Ok(())
+    }
+
     /// Pairs the client with this instance to operate on the consomme instance.
     pub fn access<'a, T: Client>(&'a mut self, client: &'a mut T) -> Access<'a, T> {
         Access {
@@ -431,6 +626,14 @@ fn process_message(&mut self, message: ConsommeMessage) {
                     p => unimplemented!("Listen not supported for protocol {}", p),
                 });
             }
+            ConsommeMessage::AddPortForward(rpc) => {
+                rpc.handle_sync(|rule| self.add_port_forward(&rule));
+            }
+            ConsommeMessage::RemovePortForward(rpc) => {
+                rpc.handle_sync(|(protocol, host_port)| {
+                    self.remove_port_forward(protocol, host_port)
+                });
+            }
             ConsommeMessage::UpdateState(rpc) => {
                 rpc.handle_sync(|f| f(&mut self.inner.state));
             }
@@ -457,8 +660,11 @@ fn poll_message(&mut self, cx: &mut Context<'_>) {
 
     /// Polls for work, transmitting any ready packets to the client.
     pub fn poll(&mut self, cx: &mut Context<'_>) {
+        self.start_pending_port_forwards();
         self.poll_udp(cx);
         self.poll_tcp(cx);
+        self.poll_icmp(cx);
+        self.poll_ndp(cx);
         self.poll_message(cx);
     }
 
@@ -468,6 +674,8 @@ pub fn poll(&mut self, cx: &mut Context<'_>) {
     pub fn refresh_driver(&mut self) {
         self.refresh_tcp_driver();
         self.refresh_udp_driver();
+        self.refresh_icmp_driver();
+        self.refresh_ndp_driver();
     }
 
     /// Sends an Ethernet frame to the network.
@@ -504,6 +712,7 @@ pub fn send(&mut self, data: &[u8], checksum: &ChecksumState) -> Result<(), Drop
         let frame = EthernetRepr::parse(&frame_packet)?;
         match frame.ethertype {
             EthernetProtocol::Ipv4 => self.handle_ipv4(&frame, frame_packet.payload(), checksum)?,
+            EthernetProtocol::Ipv6 => self.handle_ipv6(&frame, frame_packet.payload(), checksum)?,
             EthernetProtocol::Arp => self.handle_arp(&frame, frame_packet.payload())?,
             _ => return Err(DropReason::UnsupportedEthertype(frame.ethertype)),
         }
@@ -542,9 +751,9 @@ fn handle_ipv4(
             return Err(DropReason::Ipv4Checksum);
         }
 
-        let addresses = Ipv4Addresses {
-            src_addr: ipv4.src_addr(),
-            dst_addr: ipv4.dst_addr(),
+        let addresses = IpAddresses {
+            src_addr: Ipv4Addr::from(ipv4.src_addr()).into(),
+            dst_addr: Ipv4Addr::from(ipv4.dst_addr()).into(),
         };
 
         let inner = &payload[ipv4.header_len().into()..total_len];
@@ -552,8 +761,264 @@ fn handle_ipv4(
         match ipv4.protocol() {
             IpProtocol::Tcp => self.handle_tcp(&addresses, inner, checksum)?,
             IpProtocol::Udp => self.handle_udp(frame, &addresses, inner, checksum)?,
+            IpProtocol::Icmp => self.handle_icmp(frame, &addresses, inner)?,
+            p => return Err(DropReason::UnsupportedIpProtocol(p)),
+        };
+        Ok(())
+    }
+
+    fn handle_ipv6(
+        &mut self,
+        frame: &EthernetRepr,
+        payload: &[u8],
+        checksum: &ChecksumState,
+    ) -> Result<(), DropReason> {
+        let ipv6 = Ipv6Packet::new_unchecked(payload);
+        if payload.len() < IPV6_HEADER_LEN
+            || ipv6.version() != 6
+            || payload.len() < IPV6_HEADER_LEN + usize::from(ipv6.payload_len())
+        {
+            return Err(DropReason::Packet(smoltcp::Error::Malformed));
+        }
+
+        let payload_len = if checksum.tso.is_some() {
+            payload.len() - IPV6_HEADER_LEN
+        } else {
+            ipv6.payload_len().into()
+        };
+
+        let addresses = IpAddresses {
+            src_addr: Ipv6Addr::from(ipv6.src_addr()).into(),
+            dst_addr: Ipv6Addr::from(ipv6.dst_addr()).into(),
+        };
+
+        let inner = &payload[IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len];
+
+        // Extension headers are not supported, so the next header must be the
+        // upper-layer protocol.
+        match ipv6.next_header() {
+            IpProtocol::Tcp => self.handle_tcp(&addresses, inner, checksum)?,
+            IpProtocol::Udp => self.handle_udp(frame, &addresses, inner, checksum)?,
+            IpProtocol::Icmpv6 => self.handle_icmpv6(frame, &addresses, ipv6.hop_limit(), inner)?,
             p => return Err(DropReason::UnsupportedIpProtocol(p)),
         };
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use smoltcp::wire::UdpPacket;
+    use smoltcp::wire::UdpRepr;
+    use std::future::poll_fn;
+    use std::net::UdpSocket;
+
+    /// A client that collects the packets sent to the guest.
+    pub(crate) struct TestClient {
+        driver: DefaultDriver,
+        pub rx: Vec<Vec<u8>>,
+    }
+
+    impl TestClient {
+        pub fn new(driver: DefaultDriver) -> Self {
+            Self {
+                driver,
+                rx: Vec::new(),
+            }
+        }
+    }
+
+    impl Client for TestClient {
+        fn driver(&self) -> &dyn Driver {
+            &self.driver
+        }
+
+        fn recv(&mut self, data: &[u8], _checksum: &ChecksumState) {
+            self.rx.push(data.to_vec());
+        }
+
+        fn rx_mtu(&mut self) -> usize {
+            MIN_MTU
+        }
+    }
+
+    pub(crate) fn test_state() -> ConsommeState {
+        ConsommeState::with_nameservers(Vec::new())
+    }
+
+    /// Builds a frame from the guest containing an IPv4 packet.
+    pub(crate) fn ipv4_frame(
+        src_addr: Ipv4Address,
+        dst_addr: Ipv4Address,
+        dst_mac: EthernetAddress,
+        protocol: IpProtocol,
+        payload_len: usize,
+        emit_payload: impl FnOnce(&mut [u8]),
+    ) -> Vec<u8> {
+        let state = test_state();
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + payload_len];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+        EthernetRepr {
+            src_addr: state.client_mac,
+            dst_addr: dst_mac,
+            ethertype: EthernetProtocol::Ipv4,
+        }
+        .emit(&mut eth);
+        let mut ipv4 = Ipv4Packet::new_unchecked(eth.payload_mut());
+        Ipv4Repr {
+            src_addr,
+            dst_addr,
+            protocol,
+            payload_len,
+            hop_limit: 64,
+        }
+        .emit(&mut ipv4, &ChecksumCapabilities::default());
+        emit_payload(ipv4.payload_mut());
+        frame
+    }
+
+    /// Builds a frame from the guest containing an IPv6 packet.
+    pub(crate) fn ipv6_frame(
+        src_addr: Ipv6Address,
+        dst_addr: Ipv6Address,
+        dst_mac: EthernetAddress,
+        next_header: IpProtocol,
+        hop_limit: u8,
+        payload_len: usize,
+        emit_payload: impl FnOnce(&mut [u8]),
+    ) -> Vec<u8> {
+        let state = test_state();
+        let mut frame = vec![0; ETHERNET_HEADER_LEN + IPV6_HEADER_LEN + payload_len];
+        let mut eth = EthernetFrame::new_unchecked(&mut frame[..]);
+        EthernetRepr {
+            src_addr: state.client_mac,
+            dst_addr: dst_mac,
+            ethertype: EthernetProtocol::Ipv6,
+        }
+        .emit(&mut eth);
+        let mut ipv6 = Ipv6Packet::new_unchecked(eth.payload_mut());
+        Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header,
+            payload_len,
+            hop_limit,
+        }
+        .emit(&mut ipv6);
+        emit_payload(ipv6.payload_mut());
+        frame
+    }
+
+    #[test]
+    fn ipv6_prefix() {
+        let mut state = test_state();
+        state.set_ipv6_prefix("2001:db8:1:2::5/64").unwrap();
+        assert_eq!(
+            state.ipv6_prefix,
+            Ipv6Cidr::new(Ipv6Address::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0), 64)
+        );
+        state.set_ipv6_prefix("2001:db8::/48").unwrap_err();
+        state.set_ipv6_prefix("10.0.0.0/24").unwrap_err();
+        assert_eq!(
+            state.ipv6_prefix,
+            Ipv6Cidr::new(Ipv6Address::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0), 64)
+        );
+    }
+
+    #[test]
+    fn nameservers() {
+        let state = ConsommeState::with_nameservers(vec![
+            Ipv4Addr::new(192, 168, 0, 1).into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        ]);
+        assert_eq!(state.nameservers, [Ipv4Address::new(192, 168, 0, 1)]);
+        assert_eq!(
+            state.ipv6_nameservers,
+            [Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn emit_headers_mismatched() {
+        let mut buffer = [0; MIN_MTU];
+        assert!(emit_headers(
+            &mut buffer,
+            EthernetAddress::BROADCAST,
+            EthernetAddress::BROADCAST,
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv6Addr::LOCALHOST.into(),
+            IpProtocol::Udp,
+            0,
+        )
+        .is_none());
+    }
+
+    #[async_test]
+    async fn udp6(driver: DefaultDriver) {
+        let host = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let host_addr = host.local_addr().unwrap();
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+
+        let guest_ip = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let udp = UdpRepr {
+            src_port: 5000,
+            dst_port: host_addr.port(),
+        };
+        let frame = ipv6_frame(
+            guest_ip,
+            Ipv6Addr::LOCALHOST.into(),
+            consomme.state.gateway_mac,
+            IpProtocol::Udp,
+            64,
+            udp.header_len() + 4,
+            |buf| {
+                udp.emit(
+                    &mut UdpPacket::new_unchecked(buf),
+                    &guest_ip.into(),
+                    &Ipv6Address::LOOPBACK.into(),
+                    4,
+                    |payload| payload.copy_from_slice(b"ping"),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        );
+        consomme
+            .access(&mut client)
+            .send(&frame, &ChecksumState::NONE)
+            .unwrap();
+
+        let mut buf = [0; 16];
+        let (n, peer) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        host.send_to(b"pong", peer).unwrap();
+
+        // Wait for the reply, ignoring router advertisements.
+        let frame = poll_fn(|cx| {
+            consomme.access(&mut client).poll(cx);
+            match client.rx.iter().position(|frame| {
+                Ipv6Packet::new_checked(&frame[ETHERNET_HEADER_LEN..])
+                    .is_ok_and(|ipv6| ipv6.next_header() == IpProtocol::Udp)
+            }) {
+                Some(i) => Poll::Ready(client.rx.remove(i)),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        let frame = EthernetFrame::new_checked(&frame[..]).unwrap();
+        assert_eq!(frame.ethertype(), EthernetProtocol::Ipv6);
+        let ipv6 = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv6.src_addr(), Ipv6Address::LOOPBACK);
+        assert_eq!(ipv6.dst_addr(), guest_ip);
+        assert_eq!(ipv6.next_header(), IpProtocol::Udp);
+        let udp = UdpPacket::new_checked(ipv6.payload()).unwrap();
+        assert!(udp.verify_checksum(&ipv6.src_addr().into(), &ipv6.dst_addr().into()));
+        assert_eq!(udp.src_port(), host_addr.port());
+        assert_eq!(udp.dst_port(), 5000);
+        assert_eq!(udp.payload(), b"pong");
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! IPv6 neighbor discovery, enough for the guest to resolve the gateway and to
+//! configure an address, default route, and DNS servers via router
+//! advertisements.
+
+use super::Access;
+use super::Client;
+use super::DropReason;
+use crate::ChecksumState;
+use crate::MIN_MTU;
+use futures::FutureExt;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::time::Duration;
+use smoltcp::wire::EthernetAddress;
+use smoltcp::wire::EthernetFrame;
+use smoltcp::wire::EthernetProtocol;
+use smoltcp::wire::EthernetRepr;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::Icmpv6Repr;
+use smoltcp::wire::IpProtocol;
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Packet;
+use smoltcp::wire::Ipv6Repr;
+use smoltcp::wire::NdiscNeighborFlags;
+use smoltcp::wire::NdiscPrefixInfoFlags;
+use smoltcp::wire::NdiscPrefixInformation;
+use smoltcp::wire::NdiscRepr;
+use smoltcp::wire::NdiscRouterFlags;
+use std::task::Context;
+use std::task::Poll;
+
+/// The hop limit required on all neighbor discovery messages.
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// The interval between unsolicited router advertisements.
+const ROUTER_ADVERT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// The advertised lifetime of the default route. This must exceed
+/// [`ROUTER_ADVERT_INTERVAL`] so that the route is refreshed before it expires.
+const ROUTER_LIFETIME_SECS: u64 = 1800;
+
+/// The Ethernet address for the link-local all-nodes multicast group.
+const ALL_NODES_MAC: EthernetAddress = EthernetAddress([0x33, 0x33, 0, 0, 0, 1]);
+
+/// The recursive DNS server option type, from RFC 8106.
+const NDISC_OPTION_RDNSS: u8 = 25;
+
+/// The maximum number of DNS servers to advertise, matching DHCP.
+const MAX_RDNSS_SERVERS: usize = 3;
+
+pub(crate) struct Ndp {
+    timer: Option<PolledTimer>,
+    next_router_advert: Instant,
+}
+
+impl Ndp {
+    pub fn new() -> Self {
+        Self {
+            timer: None,
+            next_router_advert: Instant::from_nanos(0),
+        }
+    }
+}
+
+impl<T: Client> Access<'_, T> {
+    pub(crate) fn poll_ndp(&mut self, cx: &mut Context<'_>) {
+        loop {
+            let ndp = &mut self.inner.ndp;
+            let timer = ndp
+                .timer
+                .get_or_insert_with(|| PolledTimer::new(self.client.driver()));
+            let Poll::Ready(now) = timer.sleep_until(ndp.next_router_advert).poll_unpin(cx) else {
+                break;
+            };
+            ndp.next_router_advert = now + ROUTER_ADVERT_INTERVAL;
+            self.send_router_advert(ALL_NODES_MAC, Ipv6Address::LINK_LOCAL_ALL_NODES);
+        }
+    }
+
+    pub(crate) fn refresh_ndp_driver(&mut self) {
+        // The timer is recreated on the new driver at the next poll.
+        self.inner.ndp.timer = None;
+    }
+
+    pub(crate) fn handle_ndisc(
+        &mut self,
+        frame: &EthernetRepr,
+        src_addr: Ipv6Address,
+        hop_limit: u8,
+        ndisc: NdiscRepr<'_>,
+    ) -> Result<(), DropReason> {
+        // Neighbor discovery messages that may have been forwarded by a router
+        // must be ignored.
+        if hop_limit != NDP_HOP_LIMIT {
+            return Err(DropReason::UnsupportedIcmpv6);
+        }
+
+        match ndisc {
+            NdiscRepr::RouterSolicit { .. } => {
+                // Respond directly to the solicitor if it has an address yet.
+                if src_addr.is_unspecified() {
+                    self.send_router_advert(ALL_NODES_MAC, Ipv6Address::LINK_LOCAL_ALL_NODES);
+                } else {
+                    self.send_router_advert(frame.src_addr, src_addr);
+                }
+            }
+            NdiscRepr::NeighborSolicit { target_addr, .. }
+                if target_addr == self.inner.state.gateway_link_local()
+                    && !src_addr.is_unspecified() =>
+            {
+                self.send_ndisc(
+                    frame.src_addr,
+                    src_addr,
+                    NdiscRepr::NeighborAdvert {
+                        flags: NdiscNeighborFlags::ROUTER
+                            | NdiscNeighborFlags::SOLICITED
+                            | NdiscNeighborFlags::OVERRIDE,
+                        target_addr,
+                        lladdr: Some(self.inner.state.gateway_mac.into()),
+                    },
+                    &[],
+                );
+            }
+            _ => return Err(DropReason::UnsupportedIcmpv6),
+        }
+        Ok(())
+    }
+
+    fn send_router_advert(&mut self, dst_mac: EthernetAddress, dst_addr: Ipv6Address) {
+        // Lifetimes of all ones are infinite.
+        let prefix_lifetime = Duration::from_secs(u32::MAX.into());
+        let ndisc = NdiscRepr::RouterAdvert {
+            hop_limit: 64,
+            flags: NdiscRouterFlags::empty(),
+            router_lifetime: Duration::from_secs(ROUTER_LIFETIME_SECS),
+            reachable_time: Duration::from_secs(0),
+            retrans_time: Duration::from_secs(0),
+            lladdr: Some(self.inner.state.gateway_mac.into()),
+            mtu: None,
+            prefix_info: Some(NdiscPrefixInformation {
+                prefix_len: self.inner.state.ipv6_prefix.prefix_len(),
+                flags: NdiscPrefixInfoFlags::ON_LINK | NdiscPrefixInfoFlags::ADDRCONF,
+                valid_lifetime: prefix_lifetime,
+                preferred_lifetime: prefix_lifetime,
+                prefix: self.inner.state.ipv6_prefix.address(),
+            }),
+        };
+
+        // smoltcp does not support the RDNSS option, so append it manually.
+        let mut options = Vec::new();
+        let nameservers = &self.inner.state.ipv6_nameservers;
+        if !nameservers.is_empty() {
+            let nameservers = &nameservers[..nameservers.len().min(MAX_RDNSS_SERVERS)];
+            // The option length is in units of 8 bytes.
+            options.extend([NDISC_OPTION_RDNSS, 1 + 2 * nameservers.len() as u8, 0, 0]);
+            options.extend((ROUTER_LIFETIME_SECS as u32).to_be_bytes());
+            for addr in nameservers {
+                options.extend(addr.as_bytes());
+            }
+        }
+        self.send_ndisc(dst_mac, dst_addr, ndisc, &options);
+    }
+
+    /// Sends a neighbor discovery message, followed by the already encoded
+    /// `options`.
+    fn send_ndisc(
+        &mut self,
+        dst_mac: EthernetAddress,
+        dst_addr: Ipv6Address,
+        ndisc: NdiscRepr<'_>,
+        options: &[u8],
+    ) {
+        let src_addr = self.inner.state.gateway_link_local();
+        let icmp_repr = Icmpv6Repr::Ndisc(ndisc);
+        let icmp_len = icmp_repr.buffer_len() + options.len();
+        let ipv6_repr = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_len,
+            hop_limit: NDP_HOP_LIMIT,
+        };
+        let e_repr = EthernetRepr {
+            src_addr: self.inner.state.gateway_mac,
+            dst_addr: dst_mac,
+            ethertype: EthernetProtocol::Ipv6,
+        };
+
+        let mut buffer = [0; MIN_MTU];
+        let mut eth_packet = EthernetFrame::new_unchecked(&mut buffer);
+        e_repr.emit(&mut eth_packet);
+        let mut ipv6_packet = Ipv6Packet::new_unchecked(eth_packet.payload_mut());
+        ipv6_repr.emit(&mut ipv6_packet);
+        let icmp_buffer = &mut ipv6_packet.payload_mut()[..icmp_len];
+        icmp_repr.emit(
+            &src_addr.into(),
+            &dst_addr.into(),
+            &mut Icmpv6Packet::new_unchecked(&mut *icmp_buffer),
+            &ChecksumCapabilities::default(),
+        );
+        icmp_buffer[icmp_repr.buffer_len()..].copy_from_slice(options);
+        Icmpv6Packet::new_unchecked(icmp_buffer).fill_checksum(&src_addr.into(), &dst_addr.into());
+        let len = e_repr.buffer_len() + ipv6_repr.buffer_len() + icmp_len;
+        self.client.recv(&buffer[..len], &ChecksumState::NONE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::ipv6_frame;
+    use crate::tests::test_state;
+    use crate::tests::TestClient;
+    use crate::ChecksumState;
+    use crate::Consomme;
+    use crate::DropReason;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::EthernetAddress;
+    use smoltcp::wire::EthernetFrame;
+    use smoltcp::wire::Icmpv6Message;
+    use smoltcp::wire::Icmpv6Packet;
+    use smoltcp::wire::Icmpv6Repr;
+    use smoltcp::wire::IpProtocol;
+    use smoltcp::wire::Ipv6Address;
+    use smoltcp::wire::Ipv6Packet;
+    use smoltcp::wire::NdiscNeighborFlags;
+    use smoltcp::wire::NdiscRepr;
+    use std::future::poll_fn;
+    use std::task::Poll;
+
+    const GUEST_LINK_LOCAL: Ipv6Address = Ipv6Address([
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0xff, 0xfe, 0, 0x01, 0,
+    ]);
+
+    /// Sends an NDP message from the guest to the gateway.
+    fn send_ndisc(
+        consomme: &mut Consomme,
+        client: &mut TestClient,
+        src_addr: Ipv6Address,
+        dst_addr: Ipv6Address,
+        hop_limit: u8,
+        ndisc: NdiscRepr<'_>,
+    ) -> Result<(), DropReason> {
+        let icmp = Icmpv6Repr::Ndisc(ndisc);
+        let frame = ipv6_frame(
+            src_addr,
+            dst_addr,
+            consomme.state.gateway_mac,
+            IpProtocol::Icmpv6,
+            hop_limit,
+            icmp.buffer_len(),
+            |buf| {
+                icmp.emit(
+                    &src_addr.into(),
+                    &dst_addr.into(),
+                    &mut Icmpv6Packet::new_unchecked(buf),
+                    &ChecksumCapabilities::default(),
+                )
+            },
+        );
+        consomme.access(client).send(&frame, &ChecksumState::NONE)
+    }
+
+    /// Parses an NDP message sent to the guest, returning its destination
+    /// MAC and IPv6 addresses and its ICMPv6 packet.
+    fn parse_ndisc(frame: &[u8]) -> (EthernetAddress, Ipv6Address, Vec<u8>) {
+        let frame = EthernetFrame::new_checked(frame).unwrap();
+        let ipv6 = Ipv6Packet::new_checked(frame.payload()).unwrap();
+        assert_eq!(ipv6.next_header(), IpProtocol::Icmpv6);
+        assert_eq!(ipv6.hop_limit(), 255);
+        assert_eq!(ipv6.src_addr(), test_state().gateway_link_local());
+        let icmp = Icmpv6Packet::new_checked(ipv6.payload()).unwrap();
+        assert!(icmp.verify_checksum(&ipv6.src_addr().into(), &ipv6.dst_addr().into()));
+        (frame.dst_addr(), ipv6.dst_addr(), ipv6.payload().to_vec())
+    }
+
+    /// Returns the type and body of each option in a router advertisement.
+    fn router_advert_options(icmp: &[u8]) -> Vec<(u8, &[u8])> {
+        assert_eq!(
+            Icmpv6Packet::new_checked(icmp).unwrap().msg_type(),
+            Icmpv6Message::RouterAdvert
+        );
+        // Skip the ICMPv6 header and the fixed router advertisement fields.
+        let mut options = &icmp[16..];
+        let mut result = Vec::new();
+        while !options.is_empty() {
+            let len = usize::from(options[1]) * 8;
+            result.push((options[0], &options[2..len]));
+            options = &options[len..];
+        }
+        result
+    }
+
+    #[async_test]
+    async fn router_solicit(driver: DefaultDriver) {
+        let mut state = test_state();
+        let nameserver = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53);
+        state.ipv6_nameservers = vec![nameserver];
+        let mut consomme = Consomme::new_with_state(state);
+        let mut client = TestClient::new(driver);
+
+        // A solicitation without a source address is answered by multicast.
+        send_ndisc(
+            &mut consomme,
+            &mut client,
+            Ipv6Address::UNSPECIFIED,
+            Ipv6Address::LINK_LOCAL_ALL_ROUTERS,
+            255,
+            NdiscRepr::RouterSolicit { lladdr: None },
+        )
+        .unwrap();
+        let (dst_mac, dst_addr, _) = parse_ndisc(&client.rx.pop().unwrap());
+        assert_eq!(dst_mac, EthernetAddress([0x33, 0x33, 0, 0, 0, 1]));
+        assert_eq!(dst_addr, Ipv6Address::LINK_LOCAL_ALL_NODES);
+
+        // Otherwise, the solicitor gets a unicast response.
+        send_ndisc(
+            &mut consomme,
+            &mut client,
+            GUEST_LINK_LOCAL,
+            Ipv6Address::LINK_LOCAL_ALL_ROUTERS,
+            255,
+            NdiscRepr::RouterSolicit { lladdr: None },
+        )
+        .unwrap();
+        let (dst_mac, dst_addr, icmp) = parse_ndisc(&client.rx.pop().unwrap());
+        assert_eq!(dst_mac, consomme.state.client_mac);
+        assert_eq!(dst_addr, GUEST_LINK_LOCAL);
+        assert!(client.rx.is_empty());
+
+        let options = router_advert_options(&icmp);
+        let prefix = options.iter().find(|(ty, _)| *ty == 3).unwrap().1;
+        // The prefix length and on-link and autonomous flags.
+        assert_eq!(prefix[..2], [64, 0xc0]);
+        assert_eq!(
+            prefix[14..],
+            *Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0).as_bytes()
+        );
+        let rdnss = options.iter().find(|(ty, _)| *ty == 25).unwrap().1;
+        assert_eq!(rdnss[2..6], 1800u32.to_be_bytes());
+        assert_eq!(rdnss[6..], *nameserver.as_bytes());
+    }
+
+    #[async_test]
+    async fn neighbor_solicit(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+        let gateway = consomme.state.gateway_link_local();
+        let client_mac = consomme.state.client_mac;
+        let solicit = |target_addr| NdiscRepr::NeighborSolicit {
+            target_addr,
+            lladdr: Some(client_mac.into()),
+        };
+
+        send_ndisc(
+            &mut consomme,
+            &mut client,
+            GUEST_LINK_LOCAL,
+            gateway.solicited_node(),
+            255,
+            solicit(gateway),
+        )
+        .unwrap();
+        let (dst_mac, dst_addr, icmp) = parse_ndisc(&client.rx.pop().unwrap());
+        assert_eq!(dst_mac, consomme.state.client_mac);
+        assert_eq!(dst_addr, GUEST_LINK_LOCAL);
+        let icmp = Icmpv6Repr::parse(
+            &gateway.into(),
+            &GUEST_LINK_LOCAL.into(),
+            &Icmpv6Packet::new_checked(&icmp[..]).unwrap(),
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        let Icmpv6Repr::Ndisc(NdiscRepr::NeighborAdvert {
+            flags,
+            target_addr,
+            lladdr,
+        }) = icmp
+        else {
+            panic!("unexpected message {icmp:?}");
+        };
+        assert_eq!(
+            flags,
+            NdiscNeighborFlags::ROUTER
+                | NdiscNeighborFlags::SOLICITED
+                | NdiscNeighborFlags::OVERRIDE
+        );
+        assert_eq!(target_addr, gateway);
+        assert_eq!(lladdr, Some(consomme.state.gateway_mac.into()));
+
+        // Solicitations for other addresses are ignored.
+        let other = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1234);
+        assert!(matches!(
+            send_ndisc(
+                &mut consomme,
+                &mut client,
+                GUEST_LINK_LOCAL,
+                other.solicited_node(),
+                255,
+                solicit(other),
+            ),
+            Err(DropReason::UnsupportedIcmpv6)
+        ));
+
+        // As are messages that may have been forwarded by a router.
+        assert!(matches!(
+            send_ndisc(
+                &mut consomme,
+                &mut client,
+                GUEST_LINK_LOCAL,
+                gateway.solicited_node(),
+                64,
+                solicit(gateway),
+            ),
+            Err(DropReason::UnsupportedIcmpv6)
+        ));
+        assert!(client.rx.is_empty());
+    }
+
+    #[async_test]
+    async fn unsolicited_router_advert(driver: DefaultDriver) {
+        let mut consomme = Consomme::new_with_state(test_state());
+        let mut client = TestClient::new(driver);
+
+        // The first advertisement is sent immediately, and the next not until
+        // the interval elapses.
+        for _ in 0..2 {
+            poll_fn(|cx| {
+                consomme.access(&mut client).poll(cx);
+                Poll::Ready(())
+            })
+            .await;
+        }
+        assert_eq!(client.rx.len(), 1);
+        let (dst_mac, dst_addr, icmp) = parse_ndisc(&client.rx[0]);
+        assert_eq!(dst_mac, EthernetAddress([0x33, 0x33, 0, 0, 0, 1]));
+        assert_eq!(dst_addr, Ipv6Address::LINK_LOCAL_ALL_NODES);
+        // Without IPv6 nameservers, there is no RDNSS option.
+        assert!(router_advert_options(&icmp).iter().all(|(ty, _)| *ty != 25));
+    }
+}
@@ -9,8 +9,10 @@
 use super::DropReason;
 use super::FourTuple;
 use super::SocketAddress;
+use crate::emit_headers;
+use crate::icmp;
 use crate::ChecksumState;
-use crate::Ipv4Addresses;
+use crate::IpAddresses;
 use futures::AsyncRead;
 use futures::AsyncWrite;
 use inspect::Inspect;
@@ -18,17 +20,14 @@
 use pal_async::socket::PollReady;
 use pal_async::socket::PolledSocket;
 use smoltcp::phy::ChecksumCapabilities;
-use smoltcp::wire::EthernetFrame;
-use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::IpProtocol;
-use smoltcp::wire::Ipv4Packet;
-use smoltcp::wire::Ipv4Repr;
 use smoltcp::wire::TcpControl;
 use smoltcp::wire::TcpPacket;
 use smoltcp::wire::TcpRepr;
 use smoltcp::wire::TcpSeqNumber;
 use smoltcp::wire::ETHERNET_HEADER_LEN;
 use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::SockAddr;
@@ -41,9 +40,10 @@
 use std::io::ErrorKind;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Shutdown;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
@@ -51,7 +51,10 @@
 
 pub(crate) struct Tcp {
     connections: HashMap<FourTuple, TcpConnection>,
+    /// Listeners for ports bound with `bind_tcp_port`, by port.
     listeners: HashMap<u16, TcpListener>,
+    /// Listeners for port forwards, by host port.
+    forwards: HashMap<u16, TcpListener>,
 }
 
 #[derive(Debug, Error)]
@@ -85,6 +88,9 @@ fn inspect(&self, req: inspect::Request<'_>) {
         for port in self.listeners.keys() {
             resp.field("listening port", port);
         }
+        for (port, listener) in &self.forwards {
+            resp.field(&format!("forwarded port {port}"), listener.guest_port);
+        }
     }
 }
 
@@ -93,6 +99,7 @@ pub fn new() -> Self {
         Self {
             connections: HashMap::new(),
             listeners: HashMap::new(),
+            forwards: HashMap::new(),
         }
     }
 }
@@ -144,6 +151,7 @@ struct TcpConnection {
 struct TcpListener {
     #[inspect(skip)]
     socket: PolledSocket<Socket>,
+    guest_port: u16,
 }
 
 #[derive(Debug, PartialEq, Eq, Inspect)]
@@ -196,16 +204,21 @@ fn rx_fin(&self) -> bool {
 impl<T: Client> Access<'_, T> {
     pub(crate) fn poll_tcp(&mut self, cx: &mut Context<'_>) {
         // Check for any new incoming connections
-        self.inner
-            .tcp
-            .listeners
-            .retain(|port, listener| match listener.poll_listener(cx) {
+        let tcp = &mut self.inner.tcp;
+        for listeners in [&mut tcp.listeners, &mut tcp.forwards] {
+            listeners.retain(|port, listener| match listener.poll_listener(cx) {
                 Ok(result) => {
                     if let Some((socket, mut other_addr)) = result {
+                        // The guest is reached over IPv4, so connections from
+                        // IPv6 peers, accepted by forwards listening on IPv6
+                        // addresses, appear to come from the gateway.
+                        if other_addr.ip.is_ipv6() {
+                            other_addr.ip = Ipv4Addr::from(self.inner.state.gateway_ip).into();
+                        }
                         // Check for loopback requests and replace the dest port.
                         // This supports a guest owning both the sending and receiving ports.
                         if other_addr.ip.is_loopback() {
-                            for (other_ft, connection) in self.inner.tcp.connections.iter() {
+                            for (other_ft, connection) in tcp.connections.iter() {
                                 if connection.state == TcpState::Connecting && other_ft.dst.port == *port {
                                     if let LoopbackPortInfo::ProxyForGuestPort{sending_port, guest_port} = connection.loopback_port {
                                         if sending_port == other_addr.port {
@@ -218,11 +231,11 @@ pub(crate) fn poll_tcp(&mut self, cx: &mut Context<'_>) {
                         }
 
                         let ft = FourTuple { dst: other_addr, src: SocketAddress {
-                            ip: self.inner.state.client_ip,
-                            port: *port,
+                            ip: Ipv4Addr::from(self.inner.state.client_ip).into(),
+                            port: listener.guest_port,
                         } };
 
-                        match self.inner.tcp.connections.entry(ft) {
+                        match tcp.connections.entry(ft) {
                             hash_map::Entry::Vacant(e) => {
                                 let mut sender = Sender {
                                     ft: &ft,
@@ -254,6 +267,7 @@ pub(crate) fn poll_tcp(&mut self, cx: &mut Context<'_>) {
                 }
                 Err(_) => false,
             });
+        }
         // Check for any new incoming data
         self.inner.tcp.connections.retain(|ft, conn| {
             conn.poll_conn(
@@ -291,7 +305,7 @@ pub(crate) fn refresh_tcp_driver(&mut self) {
 
     pub(crate) fn handle_tcp(
         &mut self,
-        addresses: &Ipv4Addresses,
+        addresses: &IpAddresses,
         payload: &[u8],
         checksum: &ChecksumState,
     ) -> Result<(), DropReason> {
@@ -379,6 +393,27 @@ pub(crate) fn bind_tcp_port(
         Ok(())
     }
 
+    pub(crate) fn forward_tcp_port(
+        &mut self,
+        host_port: u16,
+        guest_port: u16,
+        socket: Socket,
+    ) -> Result<(), DropReason> {
+        let hash_map::Entry::Vacant(e) = self.inner.tcp.forwards.entry(host_port) else {
+            return Err(DropReason::Io(ErrorKind::AddrInUse.into()));
+        };
+        let socket = PolledSocket::new(self.client.driver(), socket).map_err(DropReason::Io)?;
+        e.insert(TcpListener { socket, guest_port });
+        Ok(())
+    }
+
+    pub(crate) fn remove_tcp_forward(&mut self, host_port: u16) -> Result<(), DropReason> {
+        match self.inner.tcp.forwards.remove(&host_port) {
+            Some(_) => Ok(()),
+            None => Err(DropReason::PortNotBound),
+        }
+    }
+
     pub(crate) fn unbind_tcp_port(&mut self, port: u16) -> Result<(), DropReason> {
         match self.inner.tcp.listeners.entry(port) {
             hash_map::Entry::Occupied(e) => {
@@ -398,21 +433,21 @@ struct Sender<'a, T> {
 
 impl<T: Client> Sender<'_, T> {
     fn send_packet(&mut self, tcp: &TcpRepr<'_>, payload: Option<ring::View<'_>>) {
-        let buffer = &mut self.state.buffer;
-        let mut eth_packet = EthernetFrame::new_unchecked(&mut buffer[..]);
-        eth_packet.set_ethertype(EthernetProtocol::Ipv4);
-        eth_packet.set_dst_addr(self.state.client_mac);
-        eth_packet.set_src_addr(self.state.gateway_mac);
-        let mut ipv4_packet = Ipv4Packet::new_unchecked(eth_packet.payload_mut());
-        let ipv4 = Ipv4Repr {
-            src_addr: self.ft.dst.ip,
-            dst_addr: self.ft.src.ip,
-            protocol: IpProtocol::Tcp,
-            payload_len: tcp.header_len() + payload.as_ref().map_or(0, |p| p.len()),
-            hop_limit: 64,
+        let tcp_len = tcp.header_len() + payload.as_ref().map_or(0, |p| p.len());
+        // Both ends of a connection are always of the same family.
+        let Some(header_len) = emit_headers(
+            &mut self.state.buffer,
+            self.state.gateway_mac,
+            self.state.client_mac,
+            self.ft.dst.ip,
+            self.ft.src.ip,
+            IpProtocol::Tcp,
+            tcp_len,
+        ) else {
+            return;
         };
-        ipv4.emit(&mut ipv4_packet, &ChecksumCapabilities::default());
-        let mut tcp_packet = TcpPacket::new_unchecked(ipv4_packet.payload_mut());
+        let buffer = &mut self.state.buffer[..header_len + tcp_len];
+        let mut tcp_packet = TcpPacket::new_unchecked(&mut buffer[header_len..]);
         tcp.emit(
             &mut tcp_packet,
             &self.ft.dst.ip.into(),
@@ -425,8 +460,40 @@ fn send_packet(&mut self, tcp: &TcpRepr<'_>, payload: Option<ring::View<'_>>) {
             }
         }
         tcp_packet.fill_checksum(&self.ft.dst.ip.into(), &self.ft.src.ip.into());
-        let n = ETHERNET_HEADER_LEN + ipv4_packet.total_len() as usize;
-        self.client.recv(&buffer[..n], &ChecksumState::TCP4);
+        let checksum = if self.ft.src.ip.is_ipv4() {
+            &ChecksumState::TCP4
+        } else {
+            &ChecksumState::TCP6
+        };
+        self.client.recv(buffer, checksum);
+    }
+
+    /// Sends an ICMP error for the guest's SYN with sequence number `seq`.
+    fn unreachable(&mut self, reason: icmp::Unreachable, seq: TcpSeqNumber) {
+        // Reconstruct the start of the SYN's header, which is all that the
+        // guest needs to match the error to the connection.
+        let mut header = [0; 8];
+        header[0..2].copy_from_slice(&self.ft.src.port.to_be_bytes());
+        header[2..4].copy_from_slice(&self.ft.dst.port.to_be_bytes());
+        header[4..8].copy_from_slice(&seq.0.to_be_bytes());
+        let guest_mac = self.state.client_mac;
+        icmp::send_unreachable(
+            self.client,
+            self.state,
+            guest_mac,
+            self.ft.src.ip,
+            self.ft.dst.ip,
+            IpProtocol::Tcp,
+            &header,
+            reason,
+        );
+    }
+
+    fn ip_header_len(&self) -> usize {
+        match self.ft.src.ip {
+            IpAddr::V4(_) => IPV4_HEADER_LEN,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        }
     }
 
     fn rst(&mut self, seq: TcpSeqNumber, ack: Option<TcpSeqNumber>) {
@@ -498,8 +565,13 @@ fn new(sender: &mut Sender<'_, impl Client>, tcp: &TcpRepr<'_>) -> Result<Self,
         let mut this = Self::default();
         this.initialize_from_first_client_packet(tcp)?;
 
-        let socket =
-            Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).map_err(DropReason::Io)?;
+        let dst_addr = SocketAddr::from(sender.ft.dst);
+        let socket = Socket::new(
+            Domain::for_address(dst_addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )
+        .map_err(DropReason::Io)?;
 
         // On Windows the default behavior for non-existent loopback sockets is
         // to wait and try again. This is different than the Linux behavior of
@@ -512,23 +584,18 @@ fn new(sender: &mut Sender<'_, impl Client>, tcp: &TcpRepr<'_>) -> Result<Self,
         }
 
         let socket = PolledSocket::new(sender.client.driver(), socket).map_err(DropReason::Io)?;
-        match socket
-            .get()
-            .connect(&SockAddr::from(SocketAddrV4::from(sender.ft.dst)))
-        {
+        match socket.get().connect(&SockAddr::from(dst_addr)) {
             Ok(_) => unreachable!(),
             Err(err) if is_connect_incomplete_error(&err) => (),
             Err(err) => {
-                tracing::warn!(
-                    error = &err as &dyn std::error::Error,
-                    "socket connect error"
-                );
-                sender.rst(TcpSeqNumber(0), Some(tcp.seq_number + tcp.segment_len()));
+                // Some failures, such as a missing route, are reported
+                // synchronously.
+                this.connect_failed(sender, &err);
                 return Err(DropReason::Io(err));
             }
         }
         if let Ok(addr) = socket.get().local_addr() {
-            if let Some(addr) = addr.as_socket_ipv4() {
+            if let Some(addr) = addr.as_socket() {
                 if addr.ip().is_loopback() {
                     this.loopback_port = LoopbackPortInfo::ProxyForGuestPort {
                         sending_port: addr.port(),
@@ -556,6 +623,48 @@ fn new_from_accept(
         Ok(this)
     }
 
+    /// Reports a failed connection attempt to the guest, which is still waiting
+    /// for a response to its SYN.
+    fn connect_failed(&self, sender: &mut Sender<'_, impl Client>, err: &io::Error) {
+        let reset = match err.kind() {
+            ErrorKind::TimedOut => {
+                // Avoid resetting so that the guest doesn't think there is a
+                // responding TCP stack at this address. The guest will time out
+                // on its own.
+                tracing::debug!(error = err as &dyn std::error::Error, "connect timed out");
+                false
+            }
+            ErrorKind::ConnectionRefused => {
+                // Presumably the remote TCP stack send a RST. Send a reset but
+                // don't log anything.
+                tracing::debug!(error = err as &dyn std::error::Error, "connection refused");
+                true
+            }
+            _ => {
+                if let Some(reason) = icmp::Unreachable::from_io_error(err) {
+                    // Report the failure the way a router would, rather than
+                    // with a reset.
+                    tracing::debug!(
+                        error = err as &dyn std::error::Error,
+                        "destination unreachable"
+                    );
+                    sender.unreachable(reason, self.rx_seq - 1);
+                    false
+                } else {
+                    // Something unexpected happened. Log and reset.
+                    tracing::warn!(
+                        error = err as &dyn std::error::Error,
+                        "unhandled connect failure"
+                    );
+                    true
+                }
+            }
+        };
+        if reset {
+            sender.rst(self.tx_send, Some(self.rx_seq));
+        }
+    }
+
     fn initialize_from_first_client_packet(&mut self, tcp: &TcpRepr<'_>) -> Result<(), DropReason> {
         // The TCPv4 default maximum segment size is 536. This can be bigger for
         // IPv6.
@@ -601,43 +710,7 @@ fn poll_conn(&mut self, cx: &mut Context<'_>, sender: &mut Sender<'_, impl Clien
                 Poll::Ready(r) => {
                     if r.has_err() {
                         let err = take_socket_error(self.socket.as_mut().unwrap());
-                        let reset = match err.kind() {
-                            ErrorKind::TimedOut => {
-                                // Avoid resetting so that the guest doesn't
-                                // think there is a responding TCP stack at this
-                                // address. The guest will time out on its own.
-                                tracing::debug!(
-                                    error = &err as &dyn std::error::Error,
-                                    "connect timed out"
-                                );
-                                false
-                            }
-                            ErrorKind::ConnectionRefused => {
-                                // Presumably the remote TCP stack send a RST.
-                                // Send a reset but don't log anything.
-                                tracing::debug!(
-                                    error = &err as &dyn std::error::Error,
-                                    "connection refused"
-                                );
-                                true
-                            }
-                            _ => {
-                                // Something unexpected happened. Log and reset.
-                                //
-                                // FUTURE: Handle more cases, especially
-                                // ENETUNREACH and similar, once we figure out
-                                // the right behavior for these. They might
-                                // require sending ICMP packets.
-                                tracing::warn!(
-                                    error = &err as &dyn std::error::Error,
-                                    "unhandled connect failure"
-                                );
-                                true
-                            }
-                        };
-                        if reset {
-                            sender.rst(self.tx_send, Some(self.rx_seq));
-                        }
+                        self.connect_failed(sender, &err);
                         return false;
                     }
 
@@ -836,7 +909,7 @@ fn send_data(&mut self, sender: &mut Sender<'_, impl Client>) {
             // 3. The configured maximum segment size.
             // 4. The client MTU.
             let tx_segment_end = {
-                let header_len = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + tcp.header_len();
+                let header_len = ETHERNET_HEADER_LEN + sender.ip_header_len() + tcp.header_len();
                 let mtu = rx_mtu.min(sender.state.buffer.len());
                 seq_min([
                     tx_payload_end,
@@ -1155,7 +1228,10 @@ pub fn new(sender: &mut Sender<'_, impl Client>) -> Result<Self, DropReason> {
             );
             return Err(DropReason::Io(err));
         }
-        Ok(Self { socket })
+        Ok(Self {
+            socket,
+            guest_port: sender.ft.src.port,
+        })
     }
 
     fn poll_listener(
@@ -1165,19 +1241,13 @@ fn poll_listener(
         match self.socket.poll_accept(cx) {
             Poll::Ready(r) => match r {
                 Ok((socket, address)) => match address.as_socket() {
-                    Some(addr) => match address.as_socket_ipv4() {
-                        Some(src_address) => Ok(Some((
-                            socket,
-                            SocketAddress {
-                                ip: (*src_address.ip()).into(),
-                                port: addr.port(),
-                            },
-                        ))),
-                        None => {
-                            tracing::warn!(?address, "Not an IPv4 address from accept");
-                            Ok(None)
-                        }
-                    },
+                    Some(addr) => Ok(Some((
+                        socket,
+                        SocketAddress {
+                            ip: addr.ip().to_canonical(),
+                            port: addr.port(),
+                        },
+                    ))),
                     None => {
                         tracing::warn!(?address, "Unknown address from accept");
                         Ok(None)
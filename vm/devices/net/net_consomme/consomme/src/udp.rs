@@ -7,32 +7,32 @@
 use super::ConsommeState;
 use super::DropReason;
 use super::SocketAddress;
+use crate::emit_headers;
+use crate::icmp;
 use crate::ChecksumState;
-use crate::Ipv4Addresses;
+use crate::IpAddresses;
 use inspect::Inspect;
 use inspect::InspectMut;
 use inspect_counters::Counter;
 use pal_async::interest::InterestSlot;
 use pal_async::interest::PollEvents;
 use pal_async::socket::PolledSocket;
-use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::EthernetAddress;
-use smoltcp::wire::EthernetFrame;
-use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::EthernetRepr;
 use smoltcp::wire::IpProtocol;
-use smoltcp::wire::Ipv4Packet;
-use smoltcp::wire::Ipv4Repr;
+use smoltcp::wire::Ipv4Address;
 use smoltcp::wire::UdpPacket;
 use smoltcp::wire::UdpRepr;
 use smoltcp::wire::ETHERNET_HEADER_LEN;
 use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
 use smoltcp::wire::UDP_HEADER_LEN;
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::UdpSocket;
 use std::task::Context;
 use std::task::Poll;
@@ -67,6 +67,8 @@ struct UdpConnection {
     stats: Stats,
     #[inspect(mut)]
     recycle: bool,
+    /// The host port forwarded to this guest port, if any.
+    host_port: Option<u16>,
 }
 
 #[derive(Inspect, Default)]
@@ -89,7 +91,11 @@ fn poll_conn(
             return false;
         }
 
-        let mut eth = EthernetFrame::new_unchecked(&mut state.buffer);
+        let header_len = ETHERNET_HEADER_LEN
+            + match dst_addr.ip {
+                IpAddr::V4(_) => IPV4_HEADER_LEN,
+                IpAddr::V6(_) => IPV6_HEADER_LEN,
+            };
         loop {
             // Receive UDP packets while there are receive buffers available. This
             // means we won't drop UDP packets at this level--instead, we only drop
@@ -106,34 +112,39 @@ fn poll_conn(
                 |socket| {
                     socket
                         .get()
-                        .recv_from(&mut eth.payload_mut()[IPV4_HEADER_LEN + UDP_HEADER_LEN..])
+                        .recv_from(&mut state.buffer[header_len + UDP_HEADER_LEN..])
                 },
             ) {
                 Poll::Ready(Ok((n, src_addr))) => {
-                    let src_ip = if let IpAddr::V4(ip) = src_addr.ip() {
-                        ip
-                    } else {
-                        unreachable!()
-                    };
-                    eth.set_ethertype(EthernetProtocol::Ipv4);
-                    eth.set_src_addr(state.gateway_mac);
-                    eth.set_dst_addr(self.guest_mac);
-                    let mut ipv4 = Ipv4Packet::new_unchecked(eth.payload_mut());
-                    Ipv4Repr {
-                        src_addr: src_ip.into(),
-                        dst_addr: dst_addr.ip,
-                        protocol: IpProtocol::Udp,
-                        payload_len: UDP_HEADER_LEN + n,
-                        hop_limit: 64,
+                    let udp_len = UDP_HEADER_LEN + n;
+                    // Dual-stack sockets may receive from IPv4-mapped
+                    // addresses, which can't be relayed to an IPv6 guest
+                    // address.
+                    if emit_headers(
+                        &mut state.buffer,
+                        state.gateway_mac,
+                        self.guest_mac,
+                        src_addr.ip(),
+                        dst_addr.ip,
+                        IpProtocol::Udp,
+                        udp_len,
+                    )
+                    .is_none()
+                    {
+                        continue;
                     }
-                    .emit(&mut ipv4, &ChecksumCapabilities::default());
-                    let mut udp = UdpPacket::new_unchecked(ipv4.payload_mut());
+                    let buffer = &mut state.buffer[..header_len + udp_len];
+                    let mut udp = UdpPacket::new_unchecked(&mut buffer[header_len..]);
                     udp.set_src_port(src_addr.port());
                     udp.set_dst_port(dst_addr.port);
-                    udp.set_len((UDP_HEADER_LEN + n) as u16);
-                    udp.fill_checksum(&src_ip.into(), &dst_addr.ip.into());
-                    let len = ETHERNET_HEADER_LEN + ipv4.total_len() as usize;
-                    client.recv(&eth.as_ref()[..len], &ChecksumState::UDP4);
+                    udp.set_len(udp_len as u16);
+                    udp.fill_checksum(&src_addr.ip().into(), &dst_addr.ip.into());
+                    let checksum = if dst_addr.ip.is_ipv4() {
+                        &ChecksumState::UDP4
+                    } else {
+                        &ChecksumState::UDP6
+                    };
+                    client.recv(buffer, checksum);
                     self.stats.rx_packets.increment();
                 }
                 Poll::Ready(Err(err)) => {
@@ -175,7 +186,7 @@ pub(crate) fn refresh_udp_driver(&mut self) {
     pub(crate) fn handle_udp(
         &mut self,
         frame: &EthernetRepr,
-        addresses: &Ipv4Addresses,
+        addresses: &IpAddresses,
         payload: &[u8],
         checksum: &ChecksumState,
     ) -> Result<(), DropReason> {
@@ -187,9 +198,12 @@ pub(crate) fn handle_udp(
             &checksum.caps(),
         )?;
 
-        if addresses.dst_addr == self.inner.state.gateway_ip || addresses.dst_addr.is_broadcast() {
-            if self.handle_gateway_udp(&udp_packet)? {
-                return Ok(());
+        if let IpAddr::V4(dst_addr) = addresses.dst_addr {
+            if Ipv4Address::from(dst_addr) == self.inner.state.gateway_ip || dst_addr.is_broadcast()
+            {
+                if self.handle_gateway_udp(&udp_packet)? {
+                    return Ok(());
+                }
             }
         }
 
@@ -199,10 +213,13 @@ pub(crate) fn handle_udp(
         };
 
         let conn = self.get_or_insert(guest_addr, None, Some(frame.src_addr))?;
-        match conn.socket.as_mut().unwrap().get().send_to(
-            udp_packet.payload(),
-            (Ipv4Addr::from(addresses.dst_addr), udp.dst_port),
-        ) {
+        match conn
+            .socket
+            .as_mut()
+            .unwrap()
+            .get()
+            .send_to(udp_packet.payload(), (addresses.dst_addr, udp.dst_port))
+        {
             Ok(_) => {
                 conn.stats.tx_packets.increment();
                 Ok(())
@@ -213,6 +230,18 @@ pub(crate) fn handle_udp(
             }
             Err(err) => {
                 conn.stats.tx_errors.increment();
+                if let Some(reason) = icmp::Unreachable::from_io_error(&err) {
+                    icmp::send_unreachable(
+                        self.client,
+                        &mut self.inner.state,
+                        frame.src_addr,
+                        addresses.src_addr,
+                        addresses.dst_addr,
+                        IpProtocol::Udp,
+                        payload,
+                        reason,
+                    );
+                }
                 Err(DropReason::Io(err))
             }
         }
@@ -221,15 +250,18 @@ pub(crate) fn handle_udp(
     fn get_or_insert(
         &mut self,
         guest_addr: SocketAddress,
-        host_addr: Option<Ipv4Addr>,
+        host_addr: Option<IpAddr>,
         guest_mac: Option<EthernetAddress>,
     ) -> Result<&mut UdpConnection, DropReason> {
         let entry = self.inner.udp.connections.entry(guest_addr);
         match entry {
             hash_map::Entry::Occupied(conn) => Ok(conn.into_mut()),
             hash_map::Entry::Vacant(e) => {
-                let socket = UdpSocket::bind((host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED), 0))
-                    .map_err(DropReason::Io)?;
+                let host_addr = host_addr.unwrap_or(match guest_addr.ip {
+                    IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                });
+                let socket = UdpSocket::bind((host_addr, 0)).map_err(DropReason::Io)?;
                 let socket =
                     PolledSocket::new(self.client.driver(), socket).map_err(DropReason::Io)?;
                 let conn = UdpConnection {
@@ -237,6 +269,7 @@ fn get_or_insert(
                     guest_mac: guest_mac.unwrap_or(self.inner.state.client_mac),
                     stats: Default::default(),
                     recycle: false,
+                    host_port: None,
                 };
                 Ok(e.insert(conn))
             }
@@ -263,7 +296,53 @@ pub(crate) fn bind_udp_port(
             ip: ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
             port,
         };
-        let _ = self.get_or_insert(guest_addr, ip_addr, None)?;
+        let _ = self.get_or_insert(guest_addr, ip_addr.map(IpAddr::V4), None)?;
+        Ok(())
+    }
+
+    pub(crate) fn forward_udp_port(
+        &mut self,
+        host_port: u16,
+        guest_port: u16,
+        socket: UdpSocket,
+    ) -> Result<(), DropReason> {
+        let guest_addr = SocketAddress {
+            ip: Ipv4Addr::from(self.inner.state.client_ip).into(),
+            port: guest_port,
+        };
+        // Replace any connection the guest initiated from this port, but not
+        // another forward.
+        let connections = &mut self.inner.udp.connections;
+        if connections
+            .values()
+            .any(|conn| conn.host_port == Some(host_port))
+            || connections
+                .get(&guest_addr)
+                .is_some_and(|conn| conn.host_port.is_some())
+        {
+            return Err(DropReason::Io(ErrorKind::AddrInUse.into()));
+        }
+        let socket = PolledSocket::new(self.client.driver(), socket).map_err(DropReason::Io)?;
+        connections.insert(
+            guest_addr,
+            UdpConnection {
+                socket: Some(socket),
+                guest_mac: self.inner.state.client_mac,
+                stats: Default::default(),
+                recycle: false,
+                host_port: Some(host_port),
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn remove_udp_forward(&mut self, host_port: u16) -> Result<(), DropReason> {
+        let connections = &mut self.inner.udp.connections;
+        let len = connections.len();
+        connections.retain(|_, conn| conn.host_port != Some(host_port));
+        if connections.len() == len {
+            return Err(DropReason::PortNotBound);
+        }
         Ok(())
     }
 
@@ -46,6 +46,12 @@ pub fn new_with_state(state: ConsommeState) -> Self {
         }
     }
 
+    pub fn new_with_consomme(consomme: Consomme) -> Self {
+        Self {
+            consomme: Arc::new(Mutex::new(Some(consomme))),
+        }
+    }
+
     pub fn new_dynamic(state: ConsommeState) -> (Self, ConsommeControl) {
         let (consomme, control) = Consomme::new_dynamic(state);
         (
@@ -191,7 +197,9 @@ fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
                     consomme::DropReason::UnsupportedEthertype(_)
                     | consomme::DropReason::UnsupportedIpProtocol(_)
                     | consomme::DropReason::UnsupportedDhcp(_)
-                    | consomme::DropReason::UnsupportedArp => self.stats.tx_unknown.increment(),
+                    | consomme::DropReason::UnsupportedArp
+                    | consomme::DropReason::UnsupportedIcmp
+                    | consomme::DropReason::UnsupportedIcmpv6 => self.stats.tx_unknown.increment(),
                     consomme::DropReason::Packet(_)
                     | consomme::DropReason::Ipv4Checksum
                     | consomme::DropReason::Io(_)
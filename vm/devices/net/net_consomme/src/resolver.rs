@@ -2,10 +2,14 @@
 // Licensed under the MIT License.
 
 use crate::ConsommeEndpoint;
+use consomme::Consomme;
 use consomme::ConsommeState;
+use consomme::PortForward;
 use net_backend::resolve::ResolveEndpointParams;
 use net_backend::resolve::ResolvedEndpoint;
 use net_backend_resources::consomme::ConsommeHandle;
+use net_backend_resources::consomme::ConsommeProtocol;
+use smoltcp::wire::IpProtocol;
 use thiserror::Error;
 use vm_resource::declare_static_resolver;
 use vm_resource::kind::NetEndpointHandleKind;
@@ -24,6 +28,12 @@ pub enum ResolveConsommeError {
     Consomme(consomme::Error),
     #[error(transparent)]
     InvalidCidr(consomme::InvalidCidr),
+    #[error("invalid ipv6 prefix {0}, must be a /64")]
+    InvalidIpv6Prefix(String, #[source] consomme::InvalidCidr),
+    #[error("invalid port forward host address {0}")]
+    InvalidHostAddress(String, #[source] std::net::AddrParseError),
+    #[error("failed to forward host port {0}")]
+    PortForward(u16, #[source] consomme::DropReason),
 }
 
 impl ResolveResource<NetEndpointHandleKind, ConsommeHandle> for ConsommeResolver {
@@ -42,7 +52,33 @@ fn resolve(
                 .set_cidr(cidr)
                 .map_err(ResolveConsommeError::InvalidCidr)?;
         }
-        let endpoint = ConsommeEndpoint::new_with_state(state);
+        if let Some(prefix) = &resource.ipv6_prefix {
+            state
+                .set_ipv6_prefix(prefix)
+                .map_err(|err| ResolveConsommeError::InvalidIpv6Prefix(prefix.clone(), err))?;
+        }
+        let mut consomme = Consomme::new_with_state(state);
+        for rule in resource.port_forwards {
+            let host_addr = rule
+                .host_addr
+                .map(|addr| {
+                    addr.parse()
+                        .map_err(|err| ResolveConsommeError::InvalidHostAddress(addr, err))
+                })
+                .transpose()?;
+            consomme
+                .add_port_forward(PortForward {
+                    protocol: match rule.protocol {
+                        ConsommeProtocol::Tcp => IpProtocol::Tcp,
+                        ConsommeProtocol::Udp => IpProtocol::Udp,
+                    },
+                    host_addr,
+                    host_port: rule.host_port,
+                    guest_port: rule.guest_port,
+                })
+                .map_err(|err| ResolveConsommeError::PortForward(rule.host_port, err))?;
+        }
+        let endpoint = ConsommeEndpoint::new_with_consomme(consomme);
         Ok(endpoint.into())
     }
 }
@@ -0,0 +1,528 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Capture filters using a subset of the `pcap-filter` (BPF) expression syntax.
+//!
+//! Supported primitives are `ether [src|dst] host <mac>`, `broadcast`,
+//! `multicast`, `vlan [<id>]`, `arp`, `ip`, `ip6`, `tcp`, `udp`, `icmp`,
+//! `icmp6`, `[src|dst] host <addr>`, `[src|dst] net <addr>/<len>`, and
+//! `[src|dst] port <port>`, combined with `and` (`&&`), `or` (`||`), `not`
+//! (`!`), and parentheses.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use thiserror::Error;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+
+// IPv6 extension headers skipped to find the upper-layer protocol.
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DEST_OPTIONS: u8 = 60;
+
+/// An error parsing a filter expression.
+#[derive(Debug, Error)]
+#[error("invalid filter expression: {0}")]
+pub struct FilterError(String);
+
+/// A compiled capture filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Primitive(Primitive),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Either,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Primitive {
+    EtherHost(Dir, [u8; 6]),
+    Broadcast,
+    Multicast,
+    Vlan(Option<u16>),
+    EtherType(u16),
+    IpProtocol(u8),
+    Host(Dir, IpAddr),
+    Net(Dir, IpAddr, u8),
+    Port(Dir, u16),
+}
+
+impl Filter {
+    /// Returns true if the Ethernet frame `frame` matches the filter.
+    pub fn matches(&self, frame: &[u8]) -> bool {
+        self.expr.matches(&Headers::parse(frame))
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(FilterError(format!("unexpected '{token}'")));
+        }
+        Ok(Self {
+            text: s.trim().to_owned(),
+            expr,
+        })
+    }
+}
+
+fn tokenize(s: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+        let len = if rest.starts_with("&&") || rest.starts_with("||") {
+            2
+        } else if matches!(c, '(' | ')' | '!') {
+            1
+        } else {
+            rest.find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '!'))
+                .unwrap_or(rest.len())
+        };
+        let (token, next) = rest.split_at(len);
+        tokens.push(token);
+        rest = next;
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, FilterError> {
+        let token = self
+            .peek()
+            .ok_or_else(|| FilterError("unexpected end of expression".into()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, options: &[&str]) -> bool {
+        if self.peek().is_some_and(|t| options.contains(&t)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_and()?;
+        while self.eat(&["or", "||"]) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.parse_unary()?;
+        while self.eat(&["and", "&&"]) {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, FilterError> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat(&["("]) {
+            let expr = self.parse_or()?;
+            if !self.eat(&[")"]) {
+                return Err(FilterError("missing ')'".into()));
+            }
+            return Ok(expr);
+        }
+        self.parse_primitive().map(Expr::Primitive)
+    }
+
+    fn parse_primitive(&mut self) -> Result<Primitive, FilterError> {
+        let token = self.next()?;
+        let primitive = match token {
+            "ether" => {
+                let dir = self.parse_dir();
+                self.eat(&["host"]);
+                let mac = self.next()?;
+                Primitive::EtherHost(dir, parse_mac(mac)?)
+            }
+            "broadcast" => Primitive::Broadcast,
+            "multicast" => Primitive::Multicast,
+            "vlan" => {
+                let id = match self.peek().map(str::parse::<u16>) {
+                    Some(Ok(id)) if id < 4096 => {
+                        self.pos += 1;
+                        Some(id)
+                    }
+                    _ => None,
+                };
+                Primitive::Vlan(id)
+            }
+            "arp" => Primitive::EtherType(ETHERTYPE_ARP),
+            "ip" => Primitive::EtherType(ETHERTYPE_IPV4),
+            "ip6" => Primitive::EtherType(ETHERTYPE_IPV6),
+            "tcp" => Primitive::IpProtocol(IPPROTO_TCP),
+            "udp" => Primitive::IpProtocol(IPPROTO_UDP),
+            "icmp" => Primitive::IpProtocol(IPPROTO_ICMP),
+            "icmp6" => Primitive::IpProtocol(IPPROTO_ICMPV6),
+            _ => {
+                self.pos -= 1;
+                let dir = self.parse_dir();
+                match self.next()? {
+                    "host" => {
+                        let addr = self.next()?;
+                        let addr = addr
+                            .parse()
+                            .map_err(|_| FilterError(format!("invalid address '{addr}'")))?;
+                        Primitive::Host(dir, addr)
+                    }
+                    "net" => {
+                        let net = self.next()?;
+                        let (addr, prefix_len) = parse_net(net)
+                            .ok_or_else(|| FilterError(format!("invalid network '{net}'")))?;
+                        Primitive::Net(dir, addr, prefix_len)
+                    }
+                    "port" => {
+                        let port = self.next()?;
+                        let port = port
+                            .parse()
+                            .map_err(|_| FilterError(format!("invalid port '{port}'")))?;
+                        Primitive::Port(dir, port)
+                    }
+                    token => return Err(FilterError(format!("unknown primitive '{token}'"))),
+                }
+            }
+        };
+        Ok(primitive)
+    }
+
+    fn parse_dir(&mut self) -> Dir {
+        if self.eat(&["src"]) {
+            Dir::Src
+        } else if self.eat(&["dst"]) {
+            Dir::Dst
+        } else {
+            Dir::Either
+        }
+    }
+}
+
+fn parse_mac(s: &str) -> Result<[u8; 6], FilterError> {
+    let mut mac = [0; 6];
+    let mut parts = s.split([':', '-']);
+    for b in &mut mac {
+        *b = parts
+            .next()
+            .and_then(|p| u8::from_str_radix(p, 16).ok())
+            .ok_or_else(|| FilterError(format!("invalid MAC address '{s}'")))?;
+    }
+    if parts.next().is_some() {
+        return Err(FilterError(format!("invalid MAC address '{s}'")));
+    }
+    Ok(mac)
+}
+
+fn parse_net(s: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = s.split_once('/')?;
+    let addr: IpAddr = addr.parse().ok()?;
+    let len: u8 = len.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    (len <= max).then_some((addr, len))
+}
+
+/// The headers of a frame that filters can match against.
+#[derive(Default)]
+struct Headers {
+    ether_src: [u8; 6],
+    ether_dst: [u8; 6],
+    vlans: Vec<u16>,
+    ethertype: Option<u16>,
+    ip_src: Option<IpAddr>,
+    ip_dst: Option<IpAddr>,
+    ip_protocol: Option<u8>,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+}
+
+impl Headers {
+    fn parse(frame: &[u8]) -> Self {
+        let mut headers = Self::default();
+        let Some((eth, mut rest)) = frame.split_first_chunk::<14>() else {
+            return headers;
+        };
+        headers.ether_dst.copy_from_slice(&eth[0..6]);
+        headers.ether_src.copy_from_slice(&eth[6..12]);
+        let mut ethertype = u16::from_be_bytes([eth[12], eth[13]]);
+        while matches!(ethertype, ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+            let Some((tag, next)) = rest.split_first_chunk::<4>() else {
+                return headers;
+            };
+            headers
+                .vlans
+                .push(u16::from_be_bytes([tag[0], tag[1]]) & 0xfff);
+            ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+            rest = next;
+        }
+        headers.ethertype = Some(ethertype);
+
+        let (protocol, l4) = match ethertype {
+            ETHERTYPE_IPV4 => {
+                let Some(ip) = rest.first_chunk::<20>() else {
+                    return headers;
+                };
+                headers.ip_src =
+                    Some(Ipv4Addr::from(<[u8; 4]>::try_from(&ip[12..16]).unwrap()).into());
+                headers.ip_dst =
+                    Some(Ipv4Addr::from(<[u8; 4]>::try_from(&ip[16..20]).unwrap()).into());
+                let header_len = (ip[0] & 0xf) as usize * 4;
+                // Only the first fragment has the upper-layer header.
+                let fragment_offset = u16::from_be_bytes([ip[6], ip[7]]) & 0x1fff;
+                let l4 = (fragment_offset == 0)
+                    .then(|| rest.get(header_len..))
+                    .flatten();
+                (ip[9], l4)
+            }
+            ETHERTYPE_IPV6 => {
+                let Some((ip, mut l4)) = rest.split_first_chunk::<40>() else {
+                    return headers;
+                };
+                headers.ip_src =
+                    Some(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[8..24]).unwrap()).into());
+                headers.ip_dst =
+                    Some(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[24..40]).unwrap()).into());
+                let mut next_header = ip[6];
+                loop {
+                    match next_header {
+                        IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS => {
+                            let Some(&[next, len, ..]) = l4.first_chunk::<8>() else {
+                                break (next_header, None);
+                            };
+                            next_header = next;
+                            l4 = l4.get((len as usize + 1) * 8..).unwrap_or_default();
+                        }
+                        IPV6_FRAGMENT => {
+                            let Some(&[next, _, offset_hi, offset_lo, ..]) = l4.first_chunk::<8>()
+                            else {
+                                break (next_header, None);
+                            };
+                            next_header = next;
+                            // Only the first fragment has the upper-layer
+                            // header.
+                            if u16::from_be_bytes([offset_hi, offset_lo]) >> 3 != 0 {
+                                break (next_header, None);
+                            }
+                            l4 = &l4[8..];
+                        }
+                        _ => break (next_header, Some(l4)),
+                    }
+                }
+            }
+            _ => return headers,
+        };
+        headers.ip_protocol = Some(protocol);
+        if matches!(protocol, IPPROTO_TCP | IPPROTO_UDP) {
+            if let Some(ports) = l4.and_then(|l4| l4.first_chunk::<4>()) {
+                headers.src_port = Some(u16::from_be_bytes([ports[0], ports[1]]));
+                headers.dst_port = Some(u16::from_be_bytes([ports[2], ports[3]]));
+            }
+        }
+        headers
+    }
+}
+
+impl Expr {
+    fn matches(&self, headers: &Headers) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(headers) && b.matches(headers),
+            Expr::Or(a, b) => a.matches(headers) || b.matches(headers),
+            Expr::Not(a) => !a.matches(headers),
+            Expr::Primitive(p) => p.matches(headers),
+        }
+    }
+}
+
+impl Primitive {
+    fn matches(&self, headers: &Headers) -> bool {
+        fn check<T: PartialEq>(dir: Dir, src: T, dst: T, f: impl Fn(T) -> bool) -> bool {
+            match dir {
+                Dir::Src => f(src),
+                Dir::Dst => f(dst),
+                Dir::Either => f(src) || f(dst),
+            }
+        }
+
+        match *self {
+            Primitive::EtherHost(dir, mac) => {
+                check(dir, headers.ether_src, headers.ether_dst, |addr| {
+                    addr == mac
+                })
+            }
+            Primitive::Broadcast => headers.ether_dst == [0xff; 6],
+            Primitive::Multicast => headers.ether_dst[0] & 1 != 0,
+            Primitive::Vlan(id) => match id {
+                Some(id) => headers.vlans.first() == Some(&id),
+                None => !headers.vlans.is_empty(),
+            },
+            Primitive::EtherType(ethertype) => headers.ethertype == Some(ethertype),
+            Primitive::IpProtocol(protocol) => headers.ip_protocol == Some(protocol),
+            Primitive::Host(dir, host) => check(dir, headers.ip_src, headers.ip_dst, |addr| {
+                addr == Some(host)
+            }),
+            Primitive::Net(dir, net, prefix_len) => {
+                check(dir, headers.ip_src, headers.ip_dst, |addr| {
+                    addr.is_some_and(|addr| in_net(addr, net, prefix_len))
+                })
+            }
+            Primitive::Port(dir, port) => {
+                check(dir, headers.src_port, headers.dst_port, |p| p == Some(port))
+            }
+        }
+    }
+}
+
+fn in_net(addr: IpAddr, net: IpAddr, prefix_len: u8) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+
+    const MAC_A: [u8; 6] = [0x00, 0x15, 0x5d, 0x00, 0x00, 0x01];
+    const MAC_B: [u8; 6] = [0x00, 0x15, 0x5d, 0x00, 0x00, 0x02];
+
+    fn ipv4_udp(vlan: Option<u16>, src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(MAC_B);
+        frame.extend(MAC_A);
+        if let Some(vlan) = vlan {
+            frame.extend(0x8100u16.to_be_bytes());
+            frame.extend(vlan.to_be_bytes());
+        }
+        frame.extend(0x0800u16.to_be_bytes());
+        frame.extend([0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0]);
+        frame.extend(src);
+        frame.extend(dst);
+        frame.extend(sport.to_be_bytes());
+        frame.extend(dport.to_be_bytes());
+        frame.extend([0, 8, 0, 0]);
+        frame
+    }
+
+    fn matches(filter: &str, frame: &[u8]) -> bool {
+        filter.parse::<Filter>().unwrap().matches(frame)
+    }
+
+    #[test]
+    fn primitives() {
+        let frame = ipv4_udp(None, [10, 0, 0, 1], [10, 0, 1, 2], 68, 67);
+        assert!(matches("ip", &frame));
+        assert!(matches("udp", &frame));
+        assert!(!matches("tcp", &frame));
+        assert!(!matches("ip6", &frame));
+        assert!(matches("host 10.0.0.1", &frame));
+        assert!(matches("src host 10.0.0.1", &frame));
+        assert!(!matches("dst host 10.0.0.1", &frame));
+        assert!(matches("dst net 10.0.1.0/24", &frame));
+        assert!(!matches("src net 10.0.1.0/24", &frame));
+        assert!(matches("port 67", &frame));
+        assert!(matches("src port 68", &frame));
+        assert!(!matches("src port 67", &frame));
+        assert!(matches("ether src 00:15:5d:00:00:01", &frame));
+        assert!(matches("ether host 00-15-5d-00-00-02", &frame));
+        assert!(!matches("broadcast", &frame));
+        assert!(!matches("vlan", &frame));
+    }
+
+    #[test]
+    fn vlan() {
+        let frame = ipv4_udp(Some(100), [10, 0, 0, 1], [10, 0, 1, 2], 68, 67);
+        assert!(matches("vlan", &frame));
+        assert!(matches("vlan 100 and udp port 67", &frame));
+        assert!(!matches("vlan 200", &frame));
+    }
+
+    #[test]
+    fn operators() {
+        let frame = ipv4_udp(None, [10, 0, 0, 1], [10, 0, 1, 2], 68, 67);
+        assert!(matches("udp and port 67", &frame));
+        assert!(matches("tcp or udp", &frame));
+        assert!(!matches("not udp", &frame));
+        assert!(matches("!tcp && (port 53 || port 67)", &frame));
+        assert!(!matches("udp and not (port 67 or port 68)", &frame));
+    }
+
+    #[test]
+    fn errors() {
+        for filter in [
+            "",
+            "udp and",
+            "(udp",
+            "port x",
+            "host 1.2.3",
+            "bogus",
+            "udp)",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{filter}");
+        }
+    }
+}
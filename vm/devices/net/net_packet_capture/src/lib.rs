@@ -2,7 +2,20 @@
 // Licensed under the MIT License.
 
 //! `pcapng` compatible packet capture endpoint implementation.
+//!
+//! Captures can be controlled at runtime through the endpoint's `capture`
+//! inspect node or through a [`PacketCaptureRequest`] channel, and can be
+//! limited by size, rotated across several files, and filtered with BPF-style
+//! expressions (see [`Filter`]).
 
+mod filter;
+pub mod resolver;
+mod rotate;
+
+pub use filter::Filter;
+pub use filter::FilterError;
+
+use anyhow::Context as _;
 use async_trait::async_trait;
 use futures::lock::Mutex;
 use futures::FutureExt;
@@ -25,15 +38,20 @@
 use net_backend::TxId;
 use net_backend::TxOffloadSupport;
 use net_backend::TxSegment;
+use net_backend_resources::packet_capture::PacketCaptureConfig;
+use net_backend_resources::packet_capture::PacketCaptureRequest;
 use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
 use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
 use pcap_file::pcapng::PcapNgWriter;
 use pcap_file::DataLink;
 use pcap_file::PcapError;
 use pcap_file::PcapResult;
+use rotate::RotatingPcapWriter;
 use std::borrow::Cow;
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -103,6 +121,16 @@ struct PacketCaptureOptions {
     operation: PacketCaptureOperation,
     snaplen: usize,
     writer: Option<Box<dyn PcapWriter>>,
+    filter: Option<Filter>,
+    file: Option<FileSettings>,
+}
+
+/// The settings of a capture written to files by this endpoint.
+#[derive(Debug, Clone)]
+struct FileSettings {
+    path: PathBuf,
+    max_file_size: Option<u64>,
+    max_files: u32,
 }
 
 impl PacketCaptureOptions {
@@ -119,20 +147,60 @@ fn new_with_start<W: Write + Send + Sync + 'static>(snaplen: u32, writer: W) ->
             operation: PacketCaptureOperation::Start,
             snaplen: snaplen as usize,
             writer: Some(Box::new(local_writer)),
+            filter: None,
+            file: None,
         }
     }
 
+    fn new_with_file(
+        snaplen: usize,
+        filter: Option<Filter>,
+        file: FileSettings,
+    ) -> anyhow::Result<Self> {
+        let writer = RotatingPcapWriter::new(&file.path, file.max_file_size, file.max_files)
+            .with_context(|| format!("failed to create {}", file.path.display()))?;
+        Ok(Self {
+            operation: PacketCaptureOperation::Start,
+            snaplen,
+            writer: Some(Box::new(writer)),
+            filter,
+            file: Some(file),
+        })
+    }
+
+    fn new_with_config(config: PacketCaptureConfig) -> anyhow::Result<Self> {
+        let filter = config
+            .filter
+            .filter(|filter| !filter.trim().is_empty())
+            .map(|filter| filter.parse())
+            .transpose()?;
+        Self::new_with_file(
+            config.snaplen as usize,
+            filter,
+            FileSettings {
+                path: config.path.into(),
+                max_file_size: config.max_file_size,
+                max_files: config.max_files,
+            },
+        )
+    }
+
     fn new_with_stop() -> Self {
         Self {
             operation: PacketCaptureOperation::Stop,
             snaplen: 0,
             writer: None,
+            filter: None,
+            file: None,
         }
     }
 }
 
 enum PacketCaptureEndpointCommand {
     PacketCapture(FailableRpc<PacketCaptureOptions, ()>),
+    /// An inspect update of the capture file, which starts a capture to the
+    /// new path or stops the capture if it is empty.
+    SetFile(inspect::DeferredUpdate),
 }
 
 pub struct PacketCaptureEndpointControl {
@@ -186,12 +254,16 @@ pub struct PacketCaptureEndpoint {
     id: String,
     endpoint: Box<dyn Endpoint>,
     control_rx: Arc<Mutex<mesh::Receiver<PacketCaptureEndpointCommand>>>,
+    external_control: Option<mesh::Receiver<PacketCaptureRequest>>,
     pcap: Arc<Pcap>,
 }
 
 impl InspectMut for PacketCaptureEndpoint {
     fn inspect_mut(&mut self, req: inspect::Request<'_>) {
-        self.current_mut().inspect_mut(req)
+        let pcap = self.pcap.clone();
+        req.respond()
+            .merge(self.current_mut())
+            .child("capture", |req| pcap.inspect(req));
     }
 }
 
@@ -208,12 +280,20 @@ pub fn new(endpoint: Box<dyn Endpoint>, id: String) -> (Self, PacketCaptureEndpo
                 id,
                 endpoint,
                 control_rx: Arc::new(Mutex::new(control_rx)),
+                external_control: None,
                 pcap,
             },
             control,
         )
     }
 
+    /// Accepts capture requests from `control` in addition to the returned
+    /// [`PacketCaptureEndpointControl`].
+    pub fn with_external_control(mut self, control: mesh::Receiver<PacketCaptureRequest>) -> Self {
+        self.external_control = Some(control);
+        self
+    }
+
     fn current(&self) -> &dyn Endpoint {
         self.endpoint.as_ref()
     }
@@ -287,66 +367,106 @@ async fn get_data_path_to_guest_vf(&self) -> anyhow::Result<bool> {
     async fn wait_for_endpoint_action(&mut self) -> EndpointAction {
         enum Message {
             PacketCaptureEndpointCommand(PacketCaptureEndpointCommand),
+            PacketCaptureRequest(PacketCaptureRequest),
             UpdateFromEndpoint(EndpointAction),
         }
         loop {
             let receiver = self.control_rx.clone();
             let mut receive_update = receiver.lock().await;
+            // Closed channels never produce another message.
             let update = async {
                 match receive_update.next().await {
                     Some(m) => Message::PacketCaptureEndpointCommand(m),
-                    None => {
-                        std::future::pending::<()>().await;
-                        unreachable!()
-                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let external_control = &mut self.external_control;
+            let external_update = async {
+                match external_control {
+                    Some(control) => match control.next().await {
+                        Some(m) => Message::PacketCaptureRequest(m),
+                        None => std::future::pending().await,
+                    },
+                    None => std::future::pending().await,
                 }
             };
             let ep_update = self
-                .current_mut()
+                .endpoint
                 .wait_for_endpoint_action()
                 .map(Message::UpdateFromEndpoint);
-            let m = (update, ep_update).race().await;
-            match m {
+            let m = (update, external_update, ep_update).race().await;
+            let restart_required = match m {
                 Message::PacketCaptureEndpointCommand(
                     PacketCaptureEndpointCommand::PacketCapture(rpc),
                 ) => {
-                    let options = rpc.0;
-                    let result = async {
-                        let id = &self.id;
-                        let start = match options.operation {
-                            PacketCaptureOperation::Start => {
-                                tracing::info!(id, "starting trace");
-                                true
-                            }
-                            PacketCaptureOperation::Stop => {
-                                tracing::info!(id, "stopping trace");
-                                false
-                            }
-                            _ => Err(anyhow::anyhow!("Unexpected packet capture option {id}"))?,
-                        };
-
-                        // Keep the lock until all values are being set to make the update atomic.
-                        let mut pcap_writer = self.pcap.pcap_writer.lock();
-                        let restart_required = start != self.pcap.enabled.load(Ordering::Relaxed);
-                        self.pcap.snaplen.store(options.snaplen, Ordering::Relaxed);
-                        self.pcap
-                            .interface_descriptor_written
-                            .store(false, Ordering::Relaxed);
-                        self.pcap.enabled.store(start, Ordering::Relaxed);
-                        *pcap_writer = options.writer;
-                        anyhow::Ok(restart_required)
-                    }
-                    .await;
+                    let result = self.pcap.configure(&self.id, rpc.0);
                     let (result, restart_required) = match result {
                         Err(e) => (Err(e), false),
                         Ok(value) => (Ok(()), value),
                     };
                     rpc.1.send(result.map_err(RemoteError::new));
-                    if restart_required {
-                        break EndpointAction::RestartRequired;
+                    restart_required
+                }
+                Message::PacketCaptureEndpointCommand(PacketCaptureEndpointCommand::SetFile(
+                    update,
+                )) => {
+                    let path = update.new_value().to_owned();
+                    let result = if path.is_empty() {
+                        self.pcap
+                            .configure(&self.id, PacketCaptureOptions::new_with_stop())
+                    } else {
+                        let settings = FileSettings {
+                            path: (&path).into(),
+                            ..self.pcap.file.lock().clone()
+                        };
+                        let snaplen = self.pcap.snaplen.load(Ordering::Relaxed);
+                        let filter = self.pcap.filter.read().clone();
+                        blocking::unblock(move || {
+                            PacketCaptureOptions::new_with_file(snaplen, filter, settings)
+                        })
+                        .await
+                        .and_then(|options| self.pcap.configure(&self.id, options))
+                    };
+                    match result {
+                        Ok(restart_required) => {
+                            update.succeed(path.into());
+                            restart_required
+                        }
+                        Err(err) => {
+                            update.fail(err);
+                            false
+                        }
                     }
                 }
+                Message::PacketCaptureRequest(request) => {
+                    let (result, response) = match request {
+                        PacketCaptureRequest::Start(rpc) => {
+                            let (config, response) = (rpc.0, rpc.1);
+                            // Creating the file blocks on the file system.
+                            let result = blocking::unblock(move || {
+                                PacketCaptureOptions::new_with_config(config)
+                            })
+                            .await
+                            .and_then(|options| self.pcap.configure(&self.id, options));
+                            (result, response)
+                        }
+                        PacketCaptureRequest::Stop(rpc) => (
+                            self.pcap
+                                .configure(&self.id, PacketCaptureOptions::new_with_stop()),
+                            rpc.1,
+                        ),
+                    };
+                    let (result, restart_required) = match result {
+                        Err(e) => (Err(e), false),
+                        Ok(value) => (Ok(()), value),
+                    };
+                    response.send(result.map_err(RemoteError::new));
+                    restart_required
+                }
                 Message::UpdateFromEndpoint(update) => break update,
+            };
+            if restart_required {
+                break EndpointAction::RestartRequired;
             }
         }
     }
@@ -363,6 +483,13 @@ struct Pcap {
     interface_descriptor_written: AtomicBool,
     enabled: AtomicBool,
     snaplen: AtomicUsize,
+    filter: parking_lot::RwLock<Option<Filter>>,
+    /// The file settings of the current capture, or the settings to use for
+    /// the next capture started via inspect.
+    file: parking_lot::Mutex<FileSettings>,
+    file_active: AtomicBool,
+    packets: AtomicU64,
+    filtered: AtomicU64,
     endpoint_control: Arc<mesh::Sender<PacketCaptureEndpointCommand>>,
 }
 
@@ -373,10 +500,125 @@ fn new(endpoint_control: Arc<mesh::Sender<PacketCaptureEndpointCommand>>) -> Sel
             snaplen: AtomicUsize::new(65535),
             pcap_writer: parking_lot::Mutex::new(None),
             interface_descriptor_written: AtomicBool::new(false),
+            filter: parking_lot::RwLock::new(None),
+            file: parking_lot::Mutex::new(FileSettings {
+                path: PathBuf::new(),
+                max_file_size: None,
+                max_files: 1,
+            }),
+            file_active: AtomicBool::new(false),
+            packets: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
             endpoint_control,
         }
     }
 
+    /// Applies `options`, returning whether the queues must be restarted.
+    fn configure(&self, id: &str, options: PacketCaptureOptions) -> anyhow::Result<bool> {
+        let start = match options.operation {
+            PacketCaptureOperation::Start => {
+                tracing::info!(id, "starting trace");
+                true
+            }
+            PacketCaptureOperation::Stop => {
+                tracing::info!(id, "stopping trace");
+                false
+            }
+            _ => anyhow::bail!("Unexpected packet capture option {id}"),
+        };
+
+        // Keep the lock until all values are being set to make the update atomic.
+        let mut pcap_writer = self.pcap_writer.lock();
+        let restart_required = start != self.enabled.load(Ordering::Relaxed);
+        self.snaplen.store(options.snaplen, Ordering::Relaxed);
+        self.interface_descriptor_written
+            .store(false, Ordering::Relaxed);
+        self.enabled.store(start, Ordering::Relaxed);
+        if start {
+            *self.filter.write() = options.filter;
+            self.packets.store(0, Ordering::Relaxed);
+            self.filtered.store(0, Ordering::Relaxed);
+        }
+        if let Some(file) = options.file {
+            *self.file.lock() = file;
+        }
+        self.file_active
+            .store(start && options.writer.is_some(), Ordering::Relaxed);
+        *pcap_writer = options.writer;
+        Ok(restart_required)
+    }
+
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        resp.field("enabled", self.enabled.load(Ordering::Relaxed))
+            .counter("packets", self.packets.load(Ordering::Relaxed))
+            .counter("filtered", self.filtered.load(Ordering::Relaxed))
+            .field_mut_with("snaplen", |v| {
+                if let Some(v) = v {
+                    let snaplen: u32 = v.parse()?;
+                    if snaplen == 0 {
+                        anyhow::bail!("snaplen must be nonzero");
+                    }
+                    // Takes effect immediately. The interface description
+                    // of the current file keeps the old value, which only
+                    // serves as a hint to readers.
+                    self.snaplen.store(snaplen as usize, Ordering::Relaxed);
+                }
+                anyhow::Ok(self.snaplen.load(Ordering::Relaxed))
+            })
+            .field_mut_with("filter", |v| {
+                if let Some(v) = v {
+                    let filter = if v.trim().is_empty() {
+                        None
+                    } else {
+                        Some(v.parse::<Filter>()?)
+                    };
+                    *self.filter.write() = filter;
+                }
+                anyhow::Ok(
+                    self.filter
+                        .read()
+                        .as_ref()
+                        .map_or_else(String::new, |filter| filter.to_string()),
+                )
+            });
+
+        // The file settings apply to the next capture started via inspect.
+        let mut file = self.file.lock();
+        resp.field_mut_with("max_file_size", |v| {
+            if let Some(v) = v {
+                file.max_file_size = match v.parse()? {
+                    0 => None,
+                    n => Some(n),
+                };
+            }
+            anyhow::Ok(file.max_file_size.unwrap_or(0))
+        })
+        .field_mut_with("max_files", |v| {
+            if let Some(v) = v {
+                let max_files: u32 = v.parse()?;
+                file.max_files = max_files.max(1);
+            }
+            anyhow::Ok(file.max_files)
+        })
+        // Starting a capture creates the file, so the endpoint completes the
+        // update once it has done so.
+        .child("file", |req| match req.update() {
+            Ok(update) => {
+                self.endpoint_control
+                    .send(PacketCaptureEndpointCommand::SetFile(update.defer()));
+            }
+            Err(req) => {
+                let path = if self.file_active.load(Ordering::Relaxed) {
+                    file.path.display().to_string()
+                } else {
+                    String::new()
+                };
+                req.value(path.into());
+            }
+        });
+    }
+
     fn write_packet(
         &self,
         buf: &[u8],
@@ -389,6 +631,13 @@ fn write_packet(
             return false;
         };
 
+        if let Some(filter) = &*self.filter.read() {
+            if !filter.matches(buf) {
+                self.filtered.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+
         let handle_write_result = |r: PcapResult<usize>| match r {
             // Writer gone unexpectedly; disable packet capture.
             Err(PcapError::IoError(_)) => {
@@ -435,10 +684,32 @@ fn write_packet(
             return false;
         }
 
+        self.packets.fetch_add(1, Ordering::Relaxed);
         true
     }
 }
 
+/// Copies the start of a packet from the guest memory `segments`, each a
+/// `(gpa, len)` pair, into `buf`, returning the number of bytes copied.
+fn read_segments(
+    mem: &GuestMemory,
+    segments: impl IntoIterator<Item = (u64, u32)>,
+    buf: &mut [u8],
+) -> usize {
+    let mut len = 0;
+    for (gpa, segment_len) in segments {
+        if len == buf.len() {
+            break;
+        }
+
+        // Read only this segment, which may be followed by unrelated memory.
+        let copy_length = std::cmp::min(buf.len() - len, segment_len as usize);
+        let _ = mem.read_at(gpa, &mut buf[len..len + copy_length]);
+        len += copy_length;
+    }
+    len
+}
+
 struct PacketCaptureQueue {
     queue: Box<dyn Queue>,
     mem: GuestMemory,
@@ -471,18 +742,13 @@ fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
                 let snaplen = self.pcap.snaplen.load(Ordering::Relaxed);
                 for id in &packets[..n] {
                     let mut buf = vec![0; snaplen];
-                    let mut len = 0;
-                    let mut pkt_len = 0;
-                    for segment in pool.guest_addresses(*id).iter() {
-                        pkt_len += segment.len;
-                        if len == buf.len() {
-                            continue;
-                        }
-
-                        let copy_length = std::cmp::min(buf.len() - len, segment.len as usize);
-                        let _ = self.mem.read_at(segment.gpa, &mut buf[len..]);
-                        len += copy_length;
-                    }
+                    let segments = pool.guest_addresses(*id);
+                    let pkt_len = segments.iter().map(|segment| segment.len).sum();
+                    let len = read_segments(
+                        &self.mem,
+                        segments.iter().map(|segment| (segment.gpa, segment.len)),
+                        &mut buf,
+                    );
 
                     if len == 0 {
                         continue;
@@ -514,16 +780,11 @@ fn tx_avail(&mut self, segments: &[TxSegment]) -> anyhow::Result<(bool, usize)>
                     continue;
                 }
                 let mut buf = vec![0; snaplen];
-                let mut len = 0;
-                for segment in this {
-                    if len == buf.len() {
-                        break;
-                    }
-
-                    let copy_length = std::cmp::min(buf.len() - len, segment.len as usize);
-                    let _ = self.mem.read_at(segment.gpa, &mut buf[len..]);
-                    len += copy_length;
-                }
+                let len = read_segments(
+                    &self.mem,
+                    this.iter().map(|segment| (segment.gpa, segment.len)),
+                    &mut buf,
+                );
 
                 if len == 0 {
                     continue;
@@ -556,3 +817,26 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>) {
         self.current_mut().inspect_mut(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::read_segments;
+    use guestmem::GuestMemory;
+
+    #[test]
+    fn read_multiple_segments() {
+        let mem = GuestMemory::allocate(0x4000);
+        mem.write_at(0x1000, &[1; 8]).unwrap();
+        mem.write_at(0x3000, &[2; 8]).unwrap();
+        let segments = [(0x1000, 4), (0x3000, 4)];
+
+        let mut buf = [0; 16];
+        assert_eq!(read_segments(&mem, segments, &mut buf), 8);
+        assert_eq!(buf[..8], [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        // Truncated to the snap length.
+        let mut buf = [0; 6];
+        assert_eq!(read_segments(&mem, segments, &mut buf), 6);
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2]);
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::PacketCaptureEndpoint;
+use async_trait::async_trait;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::packet_capture::PacketCaptureHandle;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveError;
+use vm_resource::ResourceResolver;
+
+pub struct PacketCaptureResolver;
+
+declare_static_async_resolver! {
+    PacketCaptureResolver,
+    (NetEndpointHandleKind, PacketCaptureHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<NetEndpointHandleKind, PacketCaptureHandle> for PacketCaptureResolver {
+    type Output = ResolvedEndpoint;
+    type Error = ResolveError;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: PacketCaptureHandle,
+        input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let endpoint = resolver.resolve(resource.endpoint, input).await?;
+        // Captures are controlled through the handle's channel and inspect, so
+        // the local control object is not needed.
+        let (endpoint, _control) = PacketCaptureEndpoint::new(endpoint.0, resource.name);
+        Ok(endpoint.with_external_control(resource.control).into())
+    }
+}
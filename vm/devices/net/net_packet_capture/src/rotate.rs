@@ -0,0 +1,315 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A pcapng writer for files limited in size, optionally rotating through a
+//! ring of files.
+
+use crate::PcapWriter;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::DataLink;
+use pcap_file::PcapError;
+use pcap_file::PcapResult;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often buffered packets are flushed, keeping the file current for live
+/// viewing without writing to it for every packet.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes pcapng files of up to `max_file_size` bytes.
+///
+/// With `max_files` greater than one, a full file is closed and a new one
+/// started, deleting the oldest so that at most `max_files` remain. The files
+/// are named after the base path with a sequence number, e.g.
+/// `capture_00003.pcapng`. Numbering continues after the files of an earlier
+/// capture to the same path, which count towards the limit. Otherwise, the
+/// capture ends when the single file is full.
+pub struct RotatingPcapWriter {
+    path: PathBuf,
+    max_file_size: Option<u64>,
+    max_files: u32,
+    index: u64,
+    written: u64,
+    interface: Option<(DataLink, u32)>,
+    writer: PcapNgWriter<BufWriter<File>>,
+    last_flush: Instant,
+}
+
+impl RotatingPcapWriter {
+    /// Creates the first file of the capture.
+    ///
+    /// This blocks on the file system.
+    pub fn new(path: &Path, max_file_size: Option<u64>, max_files: u32) -> io::Result<Self> {
+        let max_files = max_files.max(1);
+        let index = if max_files > 1 {
+            Self::start_index(path, max_files)
+        } else {
+            0
+        };
+        let file_path = Self::file_path(path, max_files, index);
+        Ok(Self {
+            path: path.to_owned(),
+            max_file_size,
+            max_files,
+            index,
+            written: 0,
+            interface: None,
+            writer: Self::create(&file_path)?,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Returns the index following the files of an earlier capture to `path`,
+    /// removing those files that fall outside the ring that starts there.
+    fn start_index(path: &Path, max_files: u32) -> u64 {
+        let existing = Self::existing_indices(path);
+        let index = existing.iter().max().map_or(0, |&i| i + 1);
+        for i in existing {
+            if i + u64::from(max_files) <= index {
+                Self::remove(&Self::file_path(path, max_files, i));
+            }
+        }
+        index
+    }
+
+    /// Returns the indices of the rotated files for `path` that exist.
+    fn existing_indices(path: &Path) -> Vec<u64> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let prefix = format!("{stem}_");
+        let suffix = path
+            .extension()
+            .map(|ext| format!(".{}", ext.to_string_lossy()))
+            .unwrap_or_default();
+        entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name();
+                let index = name
+                    .to_str()?
+                    .strip_prefix(&prefix)?
+                    .strip_suffix(suffix.as_str())?;
+                if index.len() < 5 || !index.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                index.parse().ok()
+            })
+            .collect()
+    }
+
+    fn remove(path: &Path) {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::warn!(
+                error = &err as &dyn std::error::Error,
+                path = %path.display(),
+                "failed to remove old capture file"
+            );
+        }
+    }
+
+    fn file_path(path: &Path, max_files: u32, index: u64) -> PathBuf {
+        if max_files == 1 {
+            return path.to_owned();
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) => format!("{stem}_{index:05}.{}", ext.to_string_lossy()),
+            None => format!("{stem}_{index:05}"),
+        };
+        path.with_file_name(name)
+    }
+
+    fn create(path: &Path) -> io::Result<PcapNgWriter<BufWriter<File>>> {
+        let file = BufWriter::new(File::create(path)?);
+        PcapNgWriter::with_endianness(file, pcap_file::Endianness::Big).map_err(|err| match err {
+            PcapError::IoError(err) => err,
+            err => io::Error::other(err),
+        })
+    }
+
+    /// Starts the next file if the current one is full.
+    fn rotate_if_full(&mut self) -> PcapResult<()> {
+        let Some(max_file_size) = self.max_file_size else {
+            return Ok(());
+        };
+        if self.written < max_file_size {
+            return Ok(());
+        }
+        if self.max_files == 1 {
+            return Err(PcapError::IoError(io::Error::other(
+                "capture file size limit reached",
+            )));
+        }
+
+        self.index += 1;
+        self.written = 0;
+        let path = Self::file_path(&self.path, self.max_files, self.index);
+        self.writer = Self::create(&path)?;
+        if let Some(expired) = self.index.checked_sub(self.max_files.into()) {
+            Self::remove(&Self::file_path(&self.path, self.max_files, expired));
+        }
+        // Each file needs its own interface description.
+        if let Some((linktype, snaplen)) = self.interface {
+            self.written += self.writer.write_pcapng_block(InterfaceDescriptionBlock {
+                linktype,
+                snaplen,
+                options: vec![],
+            })? as u64;
+        }
+        Ok(())
+    }
+}
+
+impl PcapWriter for RotatingPcapWriter {
+    fn write_pcapng_block_eb(&mut self, block: EnhancedPacketBlock<'_>) -> PcapResult<usize> {
+        self.rotate_if_full()?;
+        let n = self.writer.write_pcapng_block(block)?;
+        self.written += n as u64;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.writer.get_mut().flush().map_err(PcapError::IoError)?;
+            self.last_flush = Instant::now();
+        }
+        Ok(n)
+    }
+
+    fn write_pcapng_block_id(&mut self, block: InterfaceDescriptionBlock<'_>) -> PcapResult<usize> {
+        self.interface = Some((block.linktype, block.snaplen));
+        let n = self.writer.write_pcapng_block(block)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RotatingPcapWriter;
+    use crate::PcapWriter;
+    use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+    use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+    use pcap_file::DataLink;
+    use std::borrow::Cow;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn write_interface(writer: &mut RotatingPcapWriter) {
+        writer
+            .write_pcapng_block_id(InterfaceDescriptionBlock {
+                linktype: DataLink::ETHERNET,
+                snaplen: 65535,
+                options: vec![],
+            })
+            .unwrap();
+    }
+
+    fn write_packet(writer: &mut RotatingPcapWriter) -> bool {
+        writer
+            .write_pcapng_block_eb(EnhancedPacketBlock {
+                interface_id: 0,
+                timestamp: Duration::ZERO,
+                original_len: 4,
+                data: Cow::Borrowed(&[1, 2, 3, 4]),
+                options: vec![],
+            })
+            .is_ok()
+    }
+
+    fn existing(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn file_names() {
+        let path = Path::new("/captures/nic0.pcapng");
+        assert_eq!(RotatingPcapWriter::file_path(path, 1, 3), path);
+        assert_eq!(
+            RotatingPcapWriter::file_path(path, 4, 3),
+            Path::new("/captures/nic0_00003.pcapng")
+        );
+        assert_eq!(
+            RotatingPcapWriter::file_path(Path::new("/captures/nic0"), 4, 123456),
+            Path::new("/captures/nic0_123456")
+        );
+    }
+
+    #[test]
+    fn single_file_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nic0.pcapng");
+        let mut writer = RotatingPcapWriter::new(&path, Some(1), 1).unwrap();
+        write_interface(&mut writer);
+        // The file is already over the limit, so the capture ends.
+        assert!(!write_packet(&mut writer));
+        assert_eq!(existing(dir.path()), ["nic0.pcapng"]);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nic0.pcapng");
+        let mut writer = RotatingPcapWriter::new(&path, Some(1), 2).unwrap();
+        write_interface(&mut writer);
+        assert_eq!(existing(dir.path()), ["nic0_00000.pcapng"]);
+
+        // Each packet fills a file, and the oldest files are removed.
+        assert!(write_packet(&mut writer));
+        assert_eq!(
+            existing(dir.path()),
+            ["nic0_00000.pcapng", "nic0_00001.pcapng"]
+        );
+        assert!(write_packet(&mut writer));
+        assert_eq!(
+            existing(dir.path()),
+            ["nic0_00001.pcapng", "nic0_00002.pcapng"]
+        );
+        drop(writer);
+
+        // A new capture continues the numbering and removes the files of the
+        // earlier one that no longer fit in the ring.
+        std::fs::write(dir.path().join("nic0_notes.pcapng"), b"").unwrap();
+        let mut writer = RotatingPcapWriter::new(&path, Some(1), 2).unwrap();
+        assert_eq!(
+            existing(dir.path()),
+            [
+                "nic0_00002.pcapng",
+                "nic0_00003.pcapng",
+                "nic0_notes.pcapng"
+            ]
+        );
+        write_interface(&mut writer);
+        assert!(write_packet(&mut writer));
+        assert_eq!(
+            existing(dir.path()),
+            [
+                "nic0_00003.pcapng",
+                "nic0_00004.pcapng",
+                "nic0_notes.pcapng"
+            ]
+        );
+
+        // Each rotated file starts with its own interface description.
+        drop(writer);
+        let data = std::fs::read(dir.path().join("nic0_00004.pcapng")).unwrap();
+        let mut reader = pcap_file::pcapng::PcapNgReader::new(&data[..]).unwrap();
+        assert!(reader.next_block().unwrap().is_ok());
+        assert!(reader.next_block().unwrap().is_ok());
+        assert_eq!(reader.interfaces().len(), 1);
+    }
+}
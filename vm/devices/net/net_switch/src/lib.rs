@@ -0,0 +1,677 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! User-mode L2 switch endpoint, which connects the NICs of multiple VMs (in
+//! one or more processes) to the same Ethernet segment without requiring any
+//! privileges.
+//!
+//! The switch is a directory. Each attached endpoint binds a Unix datagram
+//! socket in it, named after its MAC address, and exchanges one frame per
+//! datagram with the other sockets. There is no central process: each endpoint
+//! learns which peer each source MAC address was received from, sends unicast
+//! frames for a learned address only to that peer, and floods all other frames
+//! to every socket in the directory.
+//!
+//! The directory is only read by a background refresh of the peer list, so
+//! transmits never wait on the file system.
+
+#![cfg(unix)]
+
+pub mod resolver;
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use inspect::InspectMut;
+use net_backend::linearize;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxId;
+use net_backend::TxSegment;
+use net_backend_resources::mac_address::MacAddress;
+use pal_async::driver::Driver;
+use pal_async::driver::PollImpl;
+use pal_async::interest::InterestSlot;
+use pal_async::interest::PollEvents;
+use pal_async::socket::PolledSocket;
+use pal_async::timer::PollTimer;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+use thiserror::Error;
+
+/// How long a learned MAC address is remembered without receiving frames from
+/// it, matching the default of most hardware switches.
+const MAC_AGING_TIME: Duration = Duration::from_secs(300);
+
+/// The maximum number of learned MAC addresses.
+const MAX_MAC_ENTRIES: usize = 4096;
+
+/// How often the directory is read again to find the peers to flood frames
+/// to.
+const PEER_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest frame that can be exchanged. Larger frames are dropped rather
+/// than truncated.
+const MAX_FRAME_LEN: usize = 65535;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to create switch directory {}", .0.display())]
+    CreateDir(PathBuf, #[source] io::Error),
+    #[error("failed to bind switch socket {}", .0.display())]
+    Bind(PathBuf, #[source] io::Error),
+    #[error("MAC address {0} is already attached to the switch")]
+    MacInUse(MacAddress),
+}
+
+/// An endpoint attached to a user-mode switch.
+pub struct SwitchEndpoint {
+    port: Arc<Port>,
+}
+
+impl SwitchEndpoint {
+    /// Attaches to the switch in directory `dir`, creating the directory if
+    /// necessary.
+    ///
+    /// Fails if another endpoint with the same MAC address is attached.
+    pub fn new(dir: &Path, mac_address: MacAddress) -> Result<Self, Error> {
+        std::fs::create_dir_all(dir).map_err(|err| Error::CreateDir(dir.to_owned(), err))?;
+        let path = dir.join(format!("{mac_address}.sock"));
+        let socket = bind(&path, mac_address)?;
+        Ok(Self {
+            port: Arc::new(Port {
+                dir: dir.to_owned(),
+                path,
+                socket,
+                state: Mutex::new(PortState {
+                    macs: MacTable::default(),
+                    peers: Vec::new(),
+                    dead: Vec::new(),
+                }),
+            }),
+        })
+    }
+}
+
+fn bind(path: &Path, mac_address: MacAddress) -> Result<UnixDatagram, Error> {
+    let bind_err = |err| Error::Bind(path.to_owned(), err);
+    let socket = match UnixDatagram::bind(path) {
+        Ok(socket) => socket,
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            // Replace the socket left behind by an endpoint that exited
+            // without cleaning up, but not one that is still attached.
+            match UnixDatagram::unbound().map_err(bind_err)?.connect(path) {
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path).map_err(bind_err)?;
+                    UnixDatagram::bind(path).map_err(bind_err)?
+                }
+                _ => return Err(Error::MacInUse(mac_address)),
+            }
+        }
+        Err(err) => return Err(bind_err(err)),
+    };
+    // Never block transmits on a slow peer.
+    socket.set_nonblocking(true).map_err(bind_err)?;
+    Ok(socket)
+}
+
+impl InspectMut for SwitchEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let state = self.port.state.lock();
+        req.respond()
+            .field("path", self.port.path.display().to_string())
+            .field("peers", state.peers.len())
+            .field("learned_macs", state.macs.entries.len());
+    }
+}
+
+#[async_trait]
+impl Endpoint for SwitchEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "switch"
+    }
+
+    async fn get_queues(
+        &mut self,
+        config: Vec<QueueConfig<'_>>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        assert_eq!(config.len(), 1);
+        let config = config.into_iter().next().unwrap();
+        let socket = PolledSocket::new(config.driver.as_ref(), self.port.socket.try_clone()?)?;
+        // Drop the frames that arrived while the endpoint was stopped, and
+        // find the peers before the first frame is flooded.
+        self.port.discard_received();
+        let port = self.port.clone();
+        let peers = blocking::unblock(move || port.refresh_peers()).await;
+        self.port.state.lock().peers = peers;
+        queues.push(Box::new(SwitchQueue {
+            port: self.port.clone(),
+            socket,
+            timer: config.driver.new_dyn_timer(),
+            next_refresh: pal_async::timer::Instant::now() + PEER_REFRESH_INTERVAL,
+            refresh: None,
+            pool: config.pool,
+            rx_free: config.initial_rx.iter().copied().collect(),
+            rx_ready: VecDeque::new(),
+            // Leave room to detect frames that are too large.
+            buffer: vec![0; MAX_FRAME_LEN + 1].into(),
+        }));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        // Peers keep sending to the socket while the endpoint is stopped.
+        // Release the frames that were never delivered to the guest rather
+        // than holding them until the endpoint is restarted.
+        self.port.discard_received();
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+struct Port {
+    dir: PathBuf,
+    path: PathBuf,
+    socket: UnixDatagram,
+    state: Mutex<PortState>,
+}
+
+struct PortState {
+    macs: MacTable,
+    /// The sockets of the other endpoints, as of the last refresh.
+    peers: Vec<PathBuf>,
+    /// Sockets left behind by peers that exited, to be removed by the next
+    /// refresh.
+    dead: Vec<PathBuf>,
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Port {
+    /// Learns that `src` is reachable through the peer at `peer`.
+    fn learn(&self, src: [u8; 6], peer: &Path) {
+        // Only unicast addresses can be sources.
+        if src[0] & 1 == 0 {
+            self.state.lock().macs.learn(src, peer, Instant::now());
+        }
+    }
+
+    /// Sends `frame` to the peer that owns its destination address, or to all
+    /// peers if the destination is unknown or a group address.
+    fn send(&self, frame: &[u8]) {
+        let Some(&dest) = frame.first_chunk::<6>() else {
+            return;
+        };
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let unicast_peer = if dest[0] & 1 == 0 {
+            state.macs.lookup(dest, Instant::now())
+        } else {
+            None
+        };
+        let mut gone = Vec::new();
+        if let Some(peer) = unicast_peer {
+            if let Err(dead) = self.send_to(frame, peer) {
+                gone.push((peer.to_owned(), dead));
+            }
+        } else {
+            for peer in &state.peers {
+                if let Err(dead) = self.send_to(frame, peer) {
+                    gone.push((peer.clone(), dead));
+                }
+            }
+        }
+        for (peer, dead) in gone {
+            state.macs.forget_peer(&peer);
+            state.peers.retain(|p| p != &peer);
+            if dead {
+                state.dead.push(peer);
+            }
+        }
+    }
+
+    /// Sends `frame` to `peer`. Fails if the peer is no longer attached, with
+    /// `true` if it left its socket behind.
+    fn send_to(&self, frame: &[u8], peer: &Path) -> Result<(), bool> {
+        match self.socket.send_to(frame, peer) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => Err(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(false),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                // dropped packet: the peer's receive buffer is full
+                Ok(())
+            }
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    peer = %peer.display(),
+                    "failed to send frame to switch peer"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Discards any frames waiting on the socket.
+    fn discard_received(&self) {
+        // A zero-length receive consumes a whole datagram.
+        while self.socket.recv(&mut []).is_ok() {}
+    }
+
+    /// Removes the sockets left behind by peers that exited and returns the
+    /// sockets of all other endpoints attached to the switch.
+    ///
+    /// This blocks on the file system.
+    fn refresh_peers(&self) -> Vec<PathBuf> {
+        let dead = std::mem::take(&mut self.state.lock().dead);
+        for peer in dead {
+            let _ = std::fs::remove_file(peer);
+        }
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to read switch directory"
+                );
+                return Vec::new();
+            }
+        };
+        entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                entry
+                    .file_type()
+                    .ok()?
+                    .is_socket()
+                    .then(|| entry.path())
+                    .filter(|path| path != &self.path)
+            })
+            .collect()
+    }
+}
+
+/// The learned mapping from MAC addresses to the peers they are reachable
+/// through.
+#[derive(Default)]
+struct MacTable {
+    entries: HashMap<[u8; 6], MacEntry>,
+}
+
+struct MacEntry {
+    peer: PathBuf,
+    last_seen: Instant,
+}
+
+impl MacTable {
+    fn learn(&mut self, mac: [u8; 6], peer: &Path, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&mac) {
+            // The address may have moved to another peer.
+            if entry.peer != peer {
+                entry.peer = peer.to_owned();
+            }
+            entry.last_seen = now;
+            return;
+        }
+        if self.entries.len() >= MAX_MAC_ENTRIES {
+            self.entries
+                .retain(|_, entry| now.duration_since(entry.last_seen) < MAC_AGING_TIME);
+            if self.entries.len() >= MAX_MAC_ENTRIES {
+                return;
+            }
+        }
+        self.entries.insert(
+            mac,
+            MacEntry {
+                peer: peer.to_owned(),
+                last_seen: now,
+            },
+        );
+    }
+
+    fn lookup(&self, mac: [u8; 6], now: Instant) -> Option<&Path> {
+        self.entries
+            .get(&mac)
+            .filter(|entry| now.duration_since(entry.last_seen) < MAC_AGING_TIME)
+            .map(|entry| entry.peer.as_path())
+    }
+
+    fn forget_peer(&mut self, peer: &Path) {
+        self.entries.retain(|_, entry| entry.peer != peer);
+    }
+}
+
+struct SwitchQueue {
+    port: Arc<Port>,
+    socket: PolledSocket<UnixDatagram>,
+    timer: PollImpl<dyn PollTimer>,
+    next_refresh: pal_async::timer::Instant,
+    refresh: Option<blocking::Task<Vec<PathBuf>>>,
+    pool: Box<dyn BufferAccess>,
+    rx_free: VecDeque<RxId>,
+    rx_ready: VecDeque<RxId>,
+    buffer: Box<[u8]>,
+}
+
+impl InspectMut for SwitchQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond();
+    }
+}
+
+impl SwitchQueue {
+    /// Periodically refreshes the peer list on a blocking thread.
+    fn poll_refresh(&mut self, cx: &mut Context<'_>) {
+        loop {
+            if let Some(refresh) = &mut self.refresh {
+                let Poll::Ready(peers) = refresh.poll_unpin(cx) else {
+                    return;
+                };
+                self.refresh = None;
+                self.port.state.lock().peers = peers;
+                self.next_refresh = pal_async::timer::Instant::now() + PEER_REFRESH_INTERVAL;
+            }
+            if self
+                .timer
+                .poll_timer(cx, Some(self.next_refresh))
+                .is_pending()
+            {
+                return;
+            }
+            let port = self.port.clone();
+            self.refresh = Some(blocking::unblock(move || port.refresh_peers()));
+        }
+    }
+}
+
+impl Queue for SwitchQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_refresh(cx);
+
+        while let Some(&rx) = self.rx_free.front() {
+            let (len, addr) =
+                match self
+                    .socket
+                    .poll_io(cx, InterestSlot::Read, PollEvents::IN, |socket| {
+                        socket.get().recv_from(&mut self.buffer)
+                    }) {
+                    Poll::Ready(Ok(r)) => r,
+                    Poll::Ready(Err(err)) => {
+                        tracelimit::warn_ratelimited!(
+                            error = &err as &dyn std::error::Error,
+                            "switch rx error"
+                        );
+                        break;
+                    }
+                    Poll::Pending => break,
+                };
+            // Drop frames that were truncated to fit the buffer.
+            if len > MAX_FRAME_LEN {
+                tracelimit::warn_ratelimited!(len, "dropping oversized frame from switch peer");
+                continue;
+            }
+            let frame = &self.buffer[..len];
+            // Drop runt frames.
+            let Some(src) = frame.get(6..12) else {
+                continue;
+            };
+            if let Some(peer) = addr.as_pathname() {
+                self.port.learn(src.try_into().unwrap(), peer);
+            }
+            self.pool.write_packet(
+                rx,
+                &RxMetadata {
+                    offset: 0,
+                    len,
+                    ..Default::default()
+                },
+                frame,
+            );
+            self.rx_ready.push_back(rx);
+            self.rx_free.pop_front();
+        }
+
+        if !self.rx_ready.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn rx_avail(&mut self, done: &[RxId]) {
+        self.rx_free.extend(done);
+    }
+
+    fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
+        let n = packets.len().min(self.rx_ready.len());
+        for (d, s) in packets.iter_mut().zip(self.rx_ready.drain(..n)) {
+            *d = s;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(&mut self, mut segments: &[TxSegment]) -> anyhow::Result<(bool, usize)> {
+        let n = segments.len();
+        while !segments.is_empty() {
+            let packet = linearize(self.pool.as_ref(), &mut segments)?;
+            if packet.len() > MAX_FRAME_LEN {
+                // dropped packet: too large for the switch
+                continue;
+            }
+            self.port.send(&packet);
+        }
+        Ok((true, n))
+    }
+
+    fn tx_poll(&mut self, _done: &mut [TxId]) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn buffer_access(&mut self) -> Option<&mut dyn BufferAccess> {
+        Some(self.pool.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MacTable;
+    use super::SwitchEndpoint;
+    use super::MAC_AGING_TIME;
+    use futures::future::poll_fn;
+    use guestmem::GuestMemory;
+    use net_backend::tests::Bufs;
+    use net_backend::Endpoint;
+    use net_backend::Queue;
+    use net_backend::QueueConfig;
+    use net_backend::RxId;
+    use net_backend::TxId;
+    use net_backend::TxMetadata;
+    use net_backend::TxSegment;
+    use net_backend::TxSegmentType;
+    use net_backend_resources::mac_address::MacAddress;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use std::path::Path;
+    use std::time::Instant;
+
+    const MAC_A: [u8; 6] = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x56];
+    const MAC_B: [u8; 6] = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x57];
+
+    /// The guest address transmitted frames are written to, above the receive
+    /// buffers.
+    const TX_GPA: u64 = 0x8000;
+
+    #[test]
+    fn mac_learning() {
+        let mut table = MacTable::default();
+        let now = Instant::now();
+        let a = Path::new("/switch/a.sock");
+        let b = Path::new("/switch/b.sock");
+
+        assert_eq!(table.lookup(MAC_A, now), None);
+        table.learn(MAC_A, a, now);
+        assert_eq!(table.lookup(MAC_A, now), Some(a));
+
+        // The address moved.
+        table.learn(MAC_A, b, now);
+        assert_eq!(table.lookup(MAC_A, now), Some(b));
+
+        // The entry ages out.
+        assert_eq!(table.lookup(MAC_A, now + MAC_AGING_TIME), None);
+
+        table.forget_peer(b);
+        assert_eq!(table.lookup(MAC_A, now), None);
+    }
+
+    struct TestNic {
+        endpoint: SwitchEndpoint,
+        queue: Box<dyn Queue>,
+        mem: GuestMemory,
+    }
+
+    impl TestNic {
+        async fn new(driver: &DefaultDriver, dir: &Path, mac: [u8; 6]) -> Self {
+            let mut endpoint = SwitchEndpoint::new(dir, MacAddress::new(mac)).unwrap();
+            let mem = GuestMemory::allocate(0x10000);
+            let mut queues = Vec::new();
+            endpoint
+                .get_queues(
+                    vec![QueueConfig {
+                        pool: Box::new(Bufs::new(mem.clone())),
+                        initial_rx: &[RxId(1), RxId(2)],
+                        driver: Box::new(driver.clone()),
+                    }],
+                    None,
+                    &mut queues,
+                )
+                .await
+                .unwrap();
+            Self {
+                endpoint,
+                queue: queues.pop().unwrap(),
+                mem,
+            }
+        }
+
+        fn send(&mut self, frame: &[u8]) {
+            self.mem.write_at(TX_GPA, frame).unwrap();
+            self.queue
+                .tx_avail(&[TxSegment {
+                    ty: TxSegmentType::Head(TxMetadata {
+                        id: TxId(1),
+                        segment_count: 1,
+                        len: frame.len(),
+                        ..Default::default()
+                    }),
+                    gpa: TX_GPA,
+                    len: frame.len() as u32,
+                }])
+                .unwrap();
+        }
+
+        async fn recv(&mut self) -> Vec<u8> {
+            let mut packets = [RxId(0)];
+            loop {
+                poll_fn(|cx| self.queue.poll_ready(cx)).await;
+                if self.queue.rx_poll(&mut packets).unwrap() != 0 {
+                    break;
+                }
+            }
+            let mut frame = vec![0; 64];
+            self.mem
+                .read_at(packets[0].0 as u64 * 2048, &mut frame)
+                .unwrap();
+            self.queue.rx_avail(&packets);
+            frame
+        }
+    }
+
+    fn frame(dest: [u8; 6], src: [u8; 6], fill: u8) -> Vec<u8> {
+        let mut frame = vec![fill; 64];
+        frame[..6].copy_from_slice(&dest);
+        frame[6..12].copy_from_slice(&src);
+        frame
+    }
+
+    #[async_test]
+    async fn two_endpoints(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = TestNic::new(&driver, dir.path(), MAC_A).await;
+        let mut b = TestNic::new(&driver, dir.path(), MAC_B).await;
+
+        // A second endpoint cannot use the same MAC address.
+        assert!(SwitchEndpoint::new(dir.path(), MacAddress::new(MAC_A)).is_err());
+
+        // B found A when it started, so its broadcast is flooded to A.
+        let broadcast = frame([0xff; 6], MAC_B, 1);
+        b.send(&broadcast);
+        assert_eq!(a.recv().await, broadcast);
+
+        // A learned B's address from the broadcast, so this is unicast.
+        let unicast = frame(MAC_B, MAC_A, 2);
+        a.send(&unicast);
+        assert_eq!(b.recv().await, unicast);
+        assert!(a
+            .endpoint
+            .port
+            .state
+            .lock()
+            .macs
+            .lookup(MAC_B, Instant::now())
+            .is_some());
+
+        // B learned A's address in turn.
+        let reply = frame(MAC_A, MAC_B, 3);
+        b.send(&reply);
+        assert_eq!(a.recv().await, reply);
+        assert!(b
+            .endpoint
+            .port
+            .state
+            .lock()
+            .macs
+            .lookup(MAC_A, Instant::now())
+            .is_some());
+
+        // Once B detaches, its socket is gone and A forgets it.
+        drop(b.queue);
+        b.endpoint.stop().await;
+        drop(b.endpoint);
+        a.send(&unicast);
+        assert!(a
+            .endpoint
+            .port
+            .state
+            .lock()
+            .macs
+            .lookup(MAC_B, Instant::now())
+            .is_none());
+
+        drop(a.queue);
+        a.endpoint.stop().await;
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::SwitchEndpoint;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::switch::SwitchHandle;
+use std::path::Path;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+use vm_resource::ResolveResource;
+
+pub struct SwitchResolver;
+
+declare_static_resolver! {
+    SwitchResolver,
+    (NetEndpointHandleKind, SwitchHandle),
+}
+
+impl ResolveResource<NetEndpointHandleKind, SwitchHandle> for SwitchResolver {
+    type Output = ResolvedEndpoint;
+    type Error = super::Error;
+
+    fn resolve(
+        &self,
+        resource: SwitchHandle,
+        input: ResolveEndpointParams,
+    ) -> Result<Self::Output, Self::Error> {
+        let endpoint = SwitchEndpoint::new(Path::new(&resource.path), input.mac_address)?;
+        Ok(endpoint.into())
+    }
+}
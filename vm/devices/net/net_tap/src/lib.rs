@@ -7,30 +7,41 @@
 
 pub mod resolver;
 mod tap;
+mod vnet;
 
+use anyhow::Context as _;
 use async_trait::async_trait;
 use futures::io::AsyncRead;
 use inspect::InspectMut;
 use net_backend::linearize;
+use net_backend::next_packet;
 use net_backend::BufferAccess;
 use net_backend::Endpoint;
+use net_backend::MultiQueueSupport;
 use net_backend::Queue;
 use net_backend::QueueConfig;
 use net_backend::RssConfig;
+use net_backend::RxChecksumState;
 use net_backend::RxId;
 use net_backend::RxMetadata;
 use net_backend::TxId;
+use net_backend::TxOffloadSupport;
 use net_backend::TxSegment;
 use pal_async::driver::Driver;
 use parking_lot::Mutex;
 use std::collections::VecDeque;
 use std::io::ErrorKind;
+use std::io::IoSlice;
 use std::io::Write;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use thiserror::Error;
+use vnet::VirtioNetHdr;
+use vnet::VNET_HDR_LEN;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -40,14 +51,20 @@ pub enum Error {
 
 /// An endpoint based on a TAP interface.
 pub struct TapEndpoint {
-    tap: Arc<Mutex<Option<tap::Tap>>>,
+    /// One slot per queue of the TAP interface.
+    taps: Vec<Arc<Mutex<Option<tap::Tap>>>>,
 }
 
 impl TapEndpoint {
-    pub fn new(name: &str) -> Result<Self, Error> {
-        let tap = tap::Tap::new(name).map_err(Error::TapInterface)?;
+    /// Opens the TAP interface `name` with `queues` queues. With more than one
+    /// queue, the interface must support `IFF_MULTI_QUEUE`.
+    pub fn new(name: &str, queues: u16) -> Result<Self, Error> {
+        let taps = tap::Tap::open(name, queues).map_err(Error::TapInterface)?;
         Ok(Self {
-            tap: Arc::new(Mutex::new(Some(tap))),
+            taps: taps
+                .into_iter()
+                .map(|tap| Arc::new(Mutex::new(Some(tap))))
+                .collect(),
         })
     }
 }
@@ -66,29 +83,58 @@ fn endpoint_type(&self) -> &'static str {
 
     async fn get_queues(
         &mut self,
-        mut config: Vec<QueueConfig<'_>>,
+        config: Vec<QueueConfig<'_>>,
         _rss: Option<&RssConfig<'_>>,
         queues: &mut Vec<Box<dyn Queue>>,
     ) -> anyhow::Result<()> {
-        assert_eq!(config.len(), 1);
-        let config = config.drain(..).next().unwrap();
-
-        queues.push(Box::new(TapQueue::new(
-            config.driver.as_ref(),
-            self.tap.clone(),
-            config.pool,
-            config.initial_rx,
-        )?));
+        assert!(config.len() <= self.taps.len());
+        let unused = self.taps[config.len()..].iter();
+        for (config, slot) in config.into_iter().zip(&self.taps) {
+            queues.push(Box::new(TapQueue::new(
+                config.driver.as_ref(),
+                slot.clone(),
+                config.pool,
+                config.initial_rx,
+            )?));
+        }
+        // The kernel steers flows across all attached queues, so detach the
+        // unused ones to avoid dropping their packets.
+        for slot in unused {
+            slot.lock()
+                .as_mut()
+                .expect("queue has not been dropped")
+                .set_attached(false)
+                .context("failed to detach unused TAP queue")?;
+        }
         Ok(())
     }
 
     async fn stop(&mut self) {
-        assert!(self.tap.lock().is_some(), "queue has not been dropped");
+        for slot in &self.taps {
+            assert!(slot.lock().is_some(), "queue has not been dropped");
+        }
     }
 
     fn is_ordered(&self) -> bool {
         true
     }
+
+    fn tx_offload_support(&self) -> TxOffloadSupport {
+        TxOffloadSupport {
+            ipv4_header: true,
+            tcp: true,
+            udp: true,
+            tso: true,
+        }
+    }
+
+    fn multiqueue_support(&self) -> MultiQueueSupport {
+        // RSS is not supported; the kernel picks the queue for each flow.
+        MultiQueueSupport {
+            max_queues: self.taps.len() as u16,
+            indirection_table_size: 0,
+        }
+    }
 }
 
 struct TapQueue {
@@ -125,7 +171,11 @@ fn new(
         pool: Box<dyn BufferAccess>,
         initial_rx: &[RxId],
     ) -> anyhow::Result<Self> {
-        let tap = slot.lock().take().expect("queue is already in use");
+        let mut tap = slot.lock().take().expect("queue is already in use");
+        if let Err(err) = tap.set_attached(true) {
+            *slot.lock() = Some(tap);
+            return Err(err).context("failed to attach TAP queue");
+        }
         let tap = tap.polled(driver)?;
         Ok(Self {
             slot,
@@ -135,7 +185,7 @@ fn new(
                 rx_free: initial_rx.iter().copied().collect(),
                 rx_ready: VecDeque::new(),
             },
-            buffer: Box::new([0; 65535]),
+            buffer: Box::new([0; VNET_HDR_LEN + 65535]),
         })
     }
 }
@@ -155,15 +205,21 @@ fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
         while let Some(&rx) = self.inner.rx_free.front() {
             match Pin::new(&mut *tap).poll_read(cx, &mut self.buffer) {
                 Poll::Ready(Ok(read_len)) => {
-                    self.inner.pool.write_packet(
-                        rx,
-                        &RxMetadata {
-                            offset: 0,
-                            len: read_len,
-                            ..Default::default()
-                        },
-                        &self.buffer[..read_len],
-                    );
+                    let Some((hdr, frame)) = self.buffer[..read_len].split_at_checked(VNET_HDR_LEN)
+                    else {
+                        continue;
+                    };
+                    let hdr = VirtioNetHdr::read_from(hdr).unwrap();
+                    let mut meta = RxMetadata {
+                        offset: 0,
+                        len: frame.len(),
+                        ..Default::default()
+                    };
+                    if let Some(protocol) = vnet::rx_validated_protocol(&hdr, frame) {
+                        meta.l4_protocol = protocol;
+                        meta.l4_checksum = RxChecksumState::Good;
+                    }
+                    self.inner.pool.write_packet(rx, &meta, frame);
 
                     self.inner.rx_ready.push_back(rx);
                     self.inner.rx_free.pop_front();
@@ -201,12 +257,14 @@ fn tx_avail(&mut self, mut segments: &[TxSegment]) -> anyhow::Result<(bool, usiz
         // Synchronously send packets received from the guest to host's network.
         if let Some(tap) = self.tap.as_mut() {
             while !segments.is_empty() {
-                let packet = linearize(self.inner.pool.as_ref(), &mut segments)?;
-                match tap.write(&packet) {
+                let (meta, _, _) = next_packet(segments);
+                let mut packet = linearize(self.inner.pool.as_ref(), &mut segments)?;
+                let hdr = vnet::tx_header(meta, &mut packet);
+                match tap.write_vectored(&[IoSlice::new(hdr.as_bytes()), IoSlice::new(&packet)]) {
                     Ok(bytes_written) => {
                         assert_eq!(
                             bytes_written,
-                            packet.len(),
+                            VNET_HDR_LEN + packet.len(),
                             "TAP should never partial write"
                         );
                     }
@@ -25,7 +25,7 @@ fn resolve(
         resource: TapHandle,
         _input: ResolveEndpointParams,
     ) -> Result<Self::Output, Self::Error> {
-        let endpoint = TapEndpoint::new(&resource.name)?;
+        let endpoint = TapEndpoint::new(&resource.name, resource.queues)?;
         Ok(endpoint.into())
     }
 }
@@ -10,11 +10,14 @@
 use linux_net_bindings::gen_if;
 use linux_net_bindings::gen_if_tun;
 use linux_net_bindings::tun_set_iff;
+use linux_net_bindings::tun_set_offload;
+use linux_net_bindings::tun_set_queue;
 use pal_async::driver::Driver;
 use pal_async::pipe::PolledPipe;
 use std::ffi::CString;
 use std::fs::File;
 use std::io;
+use std::io::IoSlice;
 use std::io::Write;
 use std::os::raw::c_short;
 use std::os::unix::prelude::AsRawFd;
@@ -31,23 +34,65 @@ pub enum Error {
     OpenTunFailed(#[source] io::Error),
     #[error("TUNSETIFF ioctl failed")]
     SetTapAttributes(#[source] io::Error),
+    #[error("TUNSETOFFLOAD ioctl failed")]
+    SetOffload(#[source] io::Error),
+    #[error("TUNSETQUEUE ioctl failed")]
+    SetQueue(#[source] io::Error),
     #[error("TAP name conversion to C string failed")]
     TapNameConversion(#[source] std::ffi::NulError),
 }
 
-/// Structure corresponding to a TAP interface.
+/// Structure corresponding to a queue of a TAP interface.
 #[derive(Debug)]
 pub struct Tap {
     tap: File,
+    multi_queue: bool,
+    attached: bool,
 }
 
 impl Tap {
-    pub fn new(name: &str) -> Result<Self, Error> {
-        let tap = Self::open_tap_interface(name)?;
-        Ok(Self { tap })
+    /// Opens `queues` queues of the TAP interface `name`.
+    ///
+    /// Frames read from and written to each queue are prefixed with a
+    /// `virtio_net_hdr`.
+    pub fn open(name: &str, queues: u16) -> Result<Vec<Self>, Error> {
+        let multi_queue = queues > 1;
+        (0..queues.max(1))
+            .map(|_| {
+                let tap = Self::open_tap_interface(name, multi_queue)?;
+                Ok(Self {
+                    tap,
+                    multi_queue,
+                    attached: true,
+                })
+            })
+            .collect()
     }
 
-    fn open_tap_interface(tap_name: &str) -> Result<File, Error> {
+    /// Attaches or detaches this queue from the interface. The kernel does not
+    /// steer packets to detached queues.
+    ///
+    /// This does nothing for single-queue interfaces.
+    pub fn set_attached(&mut self, attached: bool) -> Result<(), Error> {
+        if !self.multi_queue || self.attached == attached {
+            return Ok(());
+        }
+        let mut ifreq: gen_if::ifreq = Default::default();
+        ifreq.ifr_ifru.ifru_flags = if attached {
+            gen_if_tun::IFF_ATTACH_QUEUE
+        } else {
+            gen_if_tun::IFF_DETACH_QUEUE
+        } as c_short;
+        // SAFETY: calling the ioctl according to implementation requirements.
+        unsafe {
+            tun_set_queue(self.tap.as_raw_fd(), &ifreq)
+                .map_err(|_e| Error::SetQueue(io::Error::last_os_error()))?;
+        }
+        self.attached = attached;
+        Ok(())
+    }
+
+    fn open_tap_interface(tap_name: &str, multi_queue: bool) -> Result<File, Error> {
         // Open the TUN/TAP interface.
         //
         // - Packets received from this TAP interface (i.e., fom host's network)
@@ -83,13 +128,27 @@ fn open_tap_interface(tap_name: &str) -> Result<File, Error> {
             for i in 0..tap_name_length {
                 name_slice[i] = tap_name_bytes[i] as libc::c_char;
             }
-            ifreq.ifr_ifru.ifru_flags = (gen_if_tun::IFF_TAP | gen_if_tun::IFF_NO_PI) as c_short;
+            let mut flags = gen_if_tun::IFF_TAP | gen_if_tun::IFF_NO_PI | gen_if_tun::IFF_VNET_HDR;
+            if multi_queue {
+                flags |= gen_if_tun::IFF_MULTI_QUEUE;
+            }
+            ifreq.ifr_ifru.ifru_flags = flags as c_short;
 
             // SAFETY: calling the ioctl according to implementation requirements.
             unsafe {
                 tun_set_iff(tap_file.as_raw_fd(), &ifreq)
                     .map_err(|_e| Error::SetTapAttributes(io::Error::last_os_error()))?;
             };
+
+            // Don't accept partially checksummed or segmented packets from the
+            // host, since there is no way to pass them on to the guest. This
+            // resets any offloads left enabled on a persistent interface.
+            //
+            // SAFETY: calling the ioctl according to implementation requirements.
+            unsafe {
+                tun_set_offload(tap_file.as_raw_fd(), 0)
+                    .map_err(|_e| Error::SetOffload(io::Error::last_os_error()))?;
+            };
             Ok(tap_file)
         }
     }
@@ -97,6 +156,8 @@ fn open_tap_interface(tap_name: &str) -> Result<File, Error> {
     pub fn polled(self, driver: &(impl Driver + ?Sized)) -> io::Result<PolledTap> {
         Ok(PolledTap {
             tap: PolledPipe::new(driver, self.tap)?,
+            multi_queue: self.multi_queue,
+            attached: self.attached,
         })
     }
 }
@@ -104,12 +165,16 @@ pub fn polled(self, driver: &(impl Driver + ?Sized)) -> io::Result<PolledTap> {
 /// A version of [`Tap`] that implements [`AsyncRead`].
 pub struct PolledTap {
     tap: PolledPipe,
+    multi_queue: bool,
+    attached: bool,
 }
 
 impl PolledTap {
     pub fn into_inner(self) -> Tap {
         Tap {
             tap: self.tap.into_inner(),
+            multi_queue: self.multi_queue,
+            attached: self.attached,
         }
     }
 }
@@ -131,6 +196,12 @@ fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.tap.get().write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        // The TAP interface treats each write as a single frame, including
+        // vectored writes.
+        self.tap.get().write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
@@ -0,0 +1,343 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The `virtio_net_hdr` prefixed to each frame on a TAP interface opened with
+//! `IFF_VNET_HDR`, carrying checksum and segmentation offload metadata.
+
+use net_backend::checksum;
+use net_backend::checksum::TCP_CHECKSUM_OFFSET;
+use net_backend::checksum::UDP_CHECKSUM_OFFSET;
+use net_backend::L3Protocol;
+use net_backend::L4Protocol;
+use net_backend::TxMetadata;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// The length of [`VirtioNetHdr`], which is the default `TUNSETVNETHDRSZ`.
+pub const VNET_HDR_LEN: usize = size_of::<VirtioNetHdr>();
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// `struct virtio_net_hdr`, in native byte order.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+/// Returns the L4 protocol of a received frame if the host has already
+/// validated its checksum.
+pub fn rx_validated_protocol(hdr: &VirtioNetHdr, frame: &[u8]) -> Option<L4Protocol> {
+    if hdr.flags & VIRTIO_NET_HDR_F_DATA_VALID == 0 {
+        return None;
+    }
+    let ethertype = u16::from_be_bytes(frame.get(12..14)?.try_into().unwrap());
+    let protocol = match ethertype {
+        ETHERTYPE_IPV4 => *frame.get(14 + 9)?,
+        ETHERTYPE_IPV6 => *frame.get(14 + 6)?,
+        _ => return None,
+    };
+    match protocol {
+        IPPROTO_TCP => Some(L4Protocol::Tcp),
+        IPPROTO_UDP => Some(L4Protocol::Udp),
+        _ => None,
+    }
+}
+
+/// Builds the header for transmitting `frame` with the offloads requested in
+/// `meta`.
+///
+/// The IPv4 header checksum, which cannot be offloaded to the host, is computed
+/// here, and the frame's L4 checksum field is seeded with the pseudo-header
+/// checksum as the host expects. Offloads with inconsistent header lengths, or
+/// on frames too large for the header's 16-bit offsets and IP length fields,
+/// are ignored.
+pub fn tx_header(meta: &TxMetadata, frame: &mut [u8]) -> VirtioNetHdr {
+    let mut hdr = VirtioNetHdr::new_zeroed();
+    if frame.len() > u16::MAX as usize {
+        return hdr;
+    }
+    let l2_len = meta.l2_len as usize;
+    let l4_start = l2_len + meta.l3_len as usize;
+    let ipv4 = match meta.l3_protocol {
+        L3Protocol::Ipv4 => true,
+        L3Protocol::Ipv6 => false,
+        L3Protocol::Unknown => return hdr,
+    };
+    let min_l3_len = if ipv4 { 20 } else { 40 };
+    if (meta.l3_len as usize) < min_l3_len || frame.len() < l4_start {
+        return hdr;
+    }
+
+    let (protocol, csum_offset) = if meta.offload_tcp_segmentation || meta.offload_tcp_checksum {
+        (IPPROTO_TCP, TCP_CHECKSUM_OFFSET as usize)
+    } else if meta.offload_udp_checksum {
+        (IPPROTO_UDP, UDP_CHECKSUM_OFFSET as usize)
+    } else {
+        (0, 0)
+    };
+
+    if meta.offload_tcp_segmentation && frame.len() >= l4_start + meta.l4_len as usize {
+        // The guest may leave the IP length of the unsegmented packet unset.
+        // This fits since the whole frame does.
+        let ip_len = (frame.len() - l2_len) as u16;
+        let l3 = &mut frame[l2_len..];
+        if ipv4 {
+            l3[2..4].copy_from_slice(&ip_len.to_be_bytes());
+        } else {
+            l3[4..6].copy_from_slice(&(ip_len - 40).to_be_bytes());
+        }
+        hdr.gso_type = if ipv4 {
+            VIRTIO_NET_HDR_GSO_TCPV4
+        } else {
+            VIRTIO_NET_HDR_GSO_TCPV6
+        };
+        hdr.gso_size = meta.max_tcp_segment_size;
+        hdr.hdr_len = (l4_start + meta.l4_len as usize) as u16;
+    }
+
+    if ipv4 && (meta.offload_ip_header_checksum || meta.offload_tcp_segmentation) {
+        let l3 = &mut frame[l2_len..l4_start];
+        l3[10..12].fill(0);
+        let checksum = !checksum::fold(checksum::add(0, l3));
+        l3[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    if protocol != 0 && frame.len() >= l4_start + csum_offset + 2 {
+        let l4_len = (frame.len() - l4_start) as u64;
+        let (l3, l4) = frame[l2_len..].split_at_mut(l4_start - l2_len);
+        let addrs = if ipv4 { &l3[12..20] } else { &l3[8..40] };
+        let sum = checksum::add(l4_len + protocol as u64, addrs);
+        l4[csum_offset..csum_offset + 2].copy_from_slice(&checksum::fold(sum).to_be_bytes());
+        hdr.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        hdr.csum_start = l4_start as u16;
+        hdr.csum_offset = csum_offset as u16;
+    }
+    hdr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_IPV4: [u8; 4] = [10, 0, 0, 1];
+    const DST_IPV4: [u8; 4] = [10, 0, 0, 2];
+
+    /// Builds an Ethernet frame with an IPv4 or IPv6 header and a zeroed L4
+    /// header and payload of `l4_len` bytes.
+    fn build_frame(ipv4: bool, protocol: u8, l4_len: usize) -> Vec<u8> {
+        let mut frame = vec![0; 14];
+        if ipv4 {
+            frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            let mut ip = [0; 20];
+            ip[0] = 0x45;
+            ip[2..4].copy_from_slice(&((20 + l4_len) as u16).to_be_bytes());
+            ip[8] = 64;
+            ip[9] = protocol;
+            ip[12..16].copy_from_slice(&SRC_IPV4);
+            ip[16..20].copy_from_slice(&DST_IPV4);
+            frame.extend(ip);
+        } else {
+            frame[12..14].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+            let mut ip = [0; 40];
+            ip[0] = 0x60;
+            ip[4..6].copy_from_slice(&(l4_len as u16).to_be_bytes());
+            ip[6] = protocol;
+            ip[7] = 64;
+            ip[8] = 0xfd;
+            ip[23] = 1;
+            ip[24] = 0xfd;
+            ip[39] = 2;
+            frame.extend(ip);
+        }
+        frame.extend((0..l4_len).map(|i| i as u8));
+        frame
+    }
+
+    fn tx_meta(ipv4: bool) -> TxMetadata {
+        TxMetadata {
+            l3_protocol: if ipv4 {
+                L3Protocol::Ipv4
+            } else {
+                L3Protocol::Ipv6
+            },
+            l2_len: 14,
+            l3_len: if ipv4 { 20 } else { 40 },
+            ..Default::default()
+        }
+    }
+
+    /// Completes the checksum as the host would for a header with
+    /// `VIRTIO_NET_HDR_F_NEEDS_CSUM`.
+    fn complete_checksum(hdr: &VirtioNetHdr, frame: &mut [u8]) {
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        let start = hdr.csum_start as usize;
+        let offset = start + hdr.csum_offset as usize;
+        let checksum = !checksum::fold(checksum::add(0, &frame[start..]));
+        frame[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Validates the L4 checksum the way a receiver would, summing the
+    /// pseudo-header and the L4 data.
+    fn l4_checksum_valid(ipv4: bool, frame: &[u8]) -> bool {
+        let (l3, l4) = frame[14..].split_at(if ipv4 { 20 } else { 40 });
+        let (protocol, addrs) = if ipv4 {
+            (l3[9], &l3[12..20])
+        } else {
+            (l3[6], &l3[8..40])
+        };
+        let mut sum = protocol as u64 + l4.len() as u64;
+        for chunk in addrs.chunks(2).chain(l4.chunks(2)) {
+            sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u64;
+        }
+        checksum::fold(sum) == 0xffff
+    }
+
+    #[test]
+    fn tcp_checksum() {
+        for ipv4 in [true, false] {
+            let mut frame = build_frame(ipv4, IPPROTO_TCP, 41);
+            let meta = TxMetadata {
+                offload_tcp_checksum: true,
+                ..tx_meta(ipv4)
+            };
+            let hdr = tx_header(&meta, &mut frame);
+            let l4_start = if ipv4 { 34 } else { 54 };
+            assert_eq!(hdr.csum_start, l4_start);
+            assert_eq!(hdr.csum_offset, TCP_CHECKSUM_OFFSET);
+            assert_eq!(hdr.gso_type, 0);
+            assert!(!l4_checksum_valid(ipv4, &frame));
+            complete_checksum(&hdr, &mut frame);
+            assert!(l4_checksum_valid(ipv4, &frame));
+        }
+    }
+
+    #[test]
+    fn udp_checksum() {
+        for ipv4 in [true, false] {
+            let mut frame = build_frame(ipv4, IPPROTO_UDP, 30);
+            let meta = TxMetadata {
+                offload_udp_checksum: true,
+                ..tx_meta(ipv4)
+            };
+            let hdr = tx_header(&meta, &mut frame);
+            assert_eq!(hdr.csum_offset, UDP_CHECKSUM_OFFSET);
+            complete_checksum(&hdr, &mut frame);
+            assert!(l4_checksum_valid(ipv4, &frame));
+        }
+    }
+
+    #[test]
+    fn ipv4_header_checksum() {
+        let mut frame = build_frame(true, IPPROTO_UDP, 8);
+        let meta = TxMetadata {
+            offload_ip_header_checksum: true,
+            ..tx_meta(true)
+        };
+        let hdr = tx_header(&meta, &mut frame);
+        // Only the IP header checksum is computed, and there is nothing for
+        // the host to do.
+        assert_eq!(hdr.flags, 0);
+        assert_eq!(checksum::fold(checksum::add(0, &frame[14..34])), 0xffff);
+    }
+
+    #[test]
+    fn tso() {
+        for ipv4 in [true, false] {
+            let mut frame = build_frame(ipv4, IPPROTO_TCP, 20 + 3000);
+            let l3 = &mut frame[14..];
+            // Leave the IP length unset, as guests may.
+            if ipv4 {
+                l3[2..4].fill(0);
+            } else {
+                l3[4..6].fill(0);
+            }
+            let meta = TxMetadata {
+                offload_tcp_segmentation: true,
+                offload_tcp_checksum: true,
+                l4_len: 20,
+                max_tcp_segment_size: 1000,
+                ..tx_meta(ipv4)
+            };
+            let hdr = tx_header(&meta, &mut frame);
+            let l4_start = if ipv4 { 34 } else { 54 };
+            assert_eq!(
+                hdr.gso_type,
+                if ipv4 {
+                    VIRTIO_NET_HDR_GSO_TCPV4
+                } else {
+                    VIRTIO_NET_HDR_GSO_TCPV6
+                }
+            );
+            assert_eq!(hdr.gso_size, 1000);
+            assert_eq!(hdr.hdr_len, l4_start + 20);
+            assert_eq!(hdr.csum_start, l4_start);
+            let l3 = &frame[14..];
+            if ipv4 {
+                assert_eq!(l3[2..4], (20u16 + 20 + 3000).to_be_bytes());
+                assert_eq!(checksum::fold(checksum::add(0, &l3[..20])), 0xffff);
+            } else {
+                assert_eq!(l3[4..6], (20u16 + 3000).to_be_bytes());
+            }
+            complete_checksum(&hdr, &mut frame);
+            assert!(l4_checksum_valid(ipv4, &frame));
+        }
+    }
+
+    #[test]
+    fn invalid_offloads() {
+        // Header lengths that are too short.
+        let mut frame = build_frame(true, IPPROTO_TCP, 20);
+        let meta = TxMetadata {
+            offload_tcp_checksum: true,
+            l3_len: 10,
+            ..tx_meta(true)
+        };
+        assert_eq!(tx_header(&meta, &mut frame).as_bytes(), [0; VNET_HDR_LEN]);
+
+        // Frames too large for the IP length.
+        let mut frame = build_frame(true, IPPROTO_TCP, u16::MAX as usize);
+        let meta = TxMetadata {
+            offload_tcp_segmentation: true,
+            offload_tcp_checksum: true,
+            l4_len: 20,
+            max_tcp_segment_size: 1000,
+            ..tx_meta(true)
+        };
+        let original = frame.clone();
+        assert_eq!(tx_header(&meta, &mut frame).as_bytes(), [0; VNET_HDR_LEN]);
+        assert_eq!(frame, original);
+    }
+
+    #[test]
+    fn rx_validated() {
+        let mut hdr = VirtioNetHdr::new_zeroed();
+        let tcp4 = build_frame(true, IPPROTO_TCP, 20);
+        let udp6 = build_frame(false, IPPROTO_UDP, 8);
+        assert_eq!(rx_validated_protocol(&hdr, &tcp4), None);
+        hdr.flags = VIRTIO_NET_HDR_F_DATA_VALID;
+        assert_eq!(rx_validated_protocol(&hdr, &tcp4), Some(L4Protocol::Tcp));
+        assert_eq!(rx_validated_protocol(&hdr, &udp6), Some(L4Protocol::Udp));
+        assert_eq!(
+            rx_validated_protocol(&hdr, &build_frame(true, 1, 8)),
+            None,
+            "icmp"
+        );
+        assert_eq!(rx_validated_protocol(&hdr, &tcp4[..20]), None, "truncated");
+    }
+}
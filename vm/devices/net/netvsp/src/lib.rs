@@ -3307,6 +3307,13 @@ fn oid_set_rss_parameters(
             return Ok(());
         }
 
+        // Without an indirection table, the endpoint spreads packets across
+        // all queues itself, so there is nothing to configure.
+        if self.indirection_table_size == 0 {
+            primary.rss_state = None;
+            return Ok(());
+        }
+
         if params.hash_secret_key_size != 40 {
             return Err(OidError::InvalidInput("hash_secret_key_size"));
         }
@@ -24,6 +24,9 @@
 use chipset_device::io::deferred::DeferredWrite;
 use chipset_device::io::IoError;
 use chipset_device::io::IoResult;
+use chipset_device::mmio::ControlMmioIntercept;
+use chipset_device::mmio::MmioIntercept;
+use chipset_device::mmio::RegisterMmioIntercept;
 use chipset_device::pio::ControlPortIoIntercept;
 use chipset_device::pio::PortIoIntercept;
 use chipset_device::pio::RegisterPortIoIntercept;
@@ -49,6 +52,10 @@ pub mod standard_x86_io_ports {
     pub const DATA_END: u16 = 0xCFF;
 }
 
+/// Size of the ECAM region decoded for each PCI bus (32 devices * 8 functions *
+/// 4KB of configuration space).
+pub const ECAM_BUS_SIZE: u64 = 1 << 20;
+
 /// An abstract interface for a PCI device accessed via the [`GenericPciBus`].
 ///
 /// This trait is nearly identical to [`chipset_device::pci::PciConfigSpace`],
@@ -71,6 +78,38 @@ pub trait GenericPciBusDevice: 'static + Send {
 
     /// Dispatch a PCI config space write to the device with the given address.
     fn pci_cfg_write(&mut self, offset: u16, value: u32) -> Option<IoResult>;
+
+    /// Dispatch a PCI config space read to a function on a bus behind this
+    /// device, if it is a bridge.
+    ///
+    /// Returns `None` if the bus is not behind this device, or if the backing
+    /// device is no longer responding to accesses.
+    fn pci_cfg_read_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: &mut u32,
+    ) -> Option<IoResult> {
+        let _ = (bus, device_function, offset, value);
+        None
+    }
+
+    /// Dispatch a PCI config space write to a function on a bus behind this
+    /// device, if it is a bridge.
+    ///
+    /// Returns `None` if the bus is not behind this device, or if the backing
+    /// device is no longer responding to accesses.
+    fn pci_cfg_write_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: u32,
+    ) -> Option<IoResult> {
+        let _ = (bus, device_function, offset, value);
+        None
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Inspect)]
@@ -81,6 +120,13 @@ struct PciAddr {
     function: u8,
 }
 
+impl PciAddr {
+    /// The device and function numbers, encoded as in a configuration address.
+    fn device_function(&self) -> u8 {
+        self.device << 3 | self.function
+    }
+}
+
 impl std::fmt::Display for PciAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Use standard-ish BDF notation (bb:dd.f).
@@ -121,7 +167,7 @@ enum DeferredAction {
         #[inspect(skip)]
         bus_read: DeferredRead,
         read_len: usize,
-        io_port: u16,
+        byte_offset: u16,
         address: PciAddr,
     },
     ReadForWrite {
@@ -130,9 +176,10 @@ enum DeferredAction {
         #[inspect(skip)]
         bus_write: DeferredWrite,
         write_len: usize,
-        io_port: u16,
+        byte_offset: u16,
         new_value: u32,
         address: PciAddr,
+        register: u16,
     },
     Write {
         #[inspect(skip)]
@@ -150,6 +197,7 @@ pub struct GenericPciBus {
     // Runtime glue
     pio_addr: Box<dyn ControlPortIoIntercept>,
     pio_data: Box<dyn ControlPortIoIntercept>,
+    ecam: Option<Box<dyn ControlMmioIntercept>>,
     #[inspect(with = "|x| inspect::iter_by_key(x).map_value(|(name, _)| name)")]
     pci_devices: BTreeMap<PciAddr, (Arc<str>, Box<dyn GenericPciBusDevice>)>,
 
@@ -176,6 +224,7 @@ pub fn new(
         GenericPciBus {
             pio_addr: addr_control,
             pio_data: data_control,
+            ecam: None,
             pci_devices: BTreeMap::new(),
 
             waker: None,
@@ -187,6 +236,26 @@ pub fn new(
         }
     }
 
+    /// Additionally expose configuration space via ECAM (the PCI Express
+    /// Enhanced Configuration Access Mechanism), decoding `bus_count` buses
+    /// starting at bus 0 from an MMIO region at `base`.
+    ///
+    /// Unlike the port IO mechanism, ECAM gives access to the full 4KB
+    /// extended configuration space of each function.
+    pub fn with_ecam(
+        mut self,
+        register_mmio: &mut dyn RegisterMmioIntercept,
+        base: u64,
+        bus_count: u16,
+    ) -> Self {
+        assert!((1..=256).contains(&bus_count));
+        assert!(base % ECAM_BUS_SIZE == 0);
+        let mut control = register_mmio.new_io_region("ecam", bus_count as u64 * ECAM_BUS_SIZE);
+        control.map(base);
+        self.ecam = Some(control);
+        self
+    }
+
     /// Try to add a PCI device, returning (device, existing_device_name) if the
     /// slot is already occupied.
     pub fn add_pci_device<D: GenericPciBusDevice>(
@@ -241,10 +310,15 @@ fn handle_data_read(&mut self, value: &mut u32) -> IoResult {
         }
 
         let address = self.state.pio_addr_reg.address();
+        let register = self.state.pio_addr_reg.register().into();
+        self.cfg_read(address, register, value)
+    }
 
+    /// Dispatch a config space read of the (dword aligned) `offset` to the
+    /// device at `address`.
+    fn cfg_read(&mut self, address: PciAddr, offset: u16, value: &mut u32) -> IoResult {
         match self.pci_devices.get_mut(&address) {
             Some((name, device)) => {
-                let offset = self.state.pio_addr_reg.register().into();
                 let res = device.pci_cfg_read(offset, value);
                 if let Some(result) = res {
                     tracing::trace!(
@@ -270,6 +344,30 @@ fn handle_data_read(&mut self, value: &mut u32) -> IoResult {
                 }
             }
             None => {
+                // The function may be behind a bridge. A bridge only claims
+                // accesses to the buses behind it, never to the bus it sits
+                // on.
+                for (addr, (name, device)) in &mut self.pci_devices {
+                    if addr.bus == address.bus {
+                        continue;
+                    }
+                    if let Some(result) = device.pci_cfg_read_forward(
+                        address.bus,
+                        address.device_function(),
+                        offset,
+                        value,
+                    ) {
+                        tracing::trace!(
+                            bridge = &**name,
+                            %address,
+                            offset,
+                            value,
+                            "forwarded cfg space read"
+                        );
+                        return result;
+                    }
+                }
+
                 tracing::trace!(%address, "no device found - returning F's");
                 *value = !0;
                 IoResult::Ok
@@ -277,19 +375,11 @@ fn handle_data_read(&mut self, value: &mut u32) -> IoResult {
         }
     }
 
-    /// Handler a write to the DATA register
-    fn handle_data_write(&mut self, data: u32) -> IoResult {
-        tracing::trace!(%self.state.pio_addr_reg, "data write");
-
-        if !self.state.pio_addr_reg.enabled() {
-            tracelimit::warn_ratelimited!("addr enable bit is set to disabled");
-            return IoResult::Ok;
-        }
-
-        let address = self.state.pio_addr_reg.address();
+    /// Dispatch a config space write of the (dword aligned) `offset` to the
+    /// device at `address`.
+    fn cfg_write(&mut self, address: PciAddr, offset: u16, data: u32) -> IoResult {
         match self.pci_devices.get_mut(&address) {
             Some((name, device)) => {
-                let offset = self.state.pio_addr_reg.register().into();
                 let res = device.pci_cfg_write(offset, data);
                 if let Some(result) = res {
                     tracing::trace!(
@@ -314,34 +404,165 @@ fn handle_data_write(&mut self, data: u32) -> IoResult {
                 }
             }
             None => {
+                for (addr, (name, device)) in &mut self.pci_devices {
+                    if addr.bus == address.bus {
+                        continue;
+                    }
+                    if let Some(result) = device.pci_cfg_write_forward(
+                        address.bus,
+                        address.device_function(),
+                        offset,
+                        data,
+                    ) {
+                        tracing::trace!(
+                            bridge = &**name,
+                            %address,
+                            offset,
+                            data,
+                            "forwarded cfg space write"
+                        );
+                        return result;
+                    }
+                }
+
                 tracing::debug!(%address, "no device found");
                 IoResult::Ok
             }
         }
     }
 
-    fn trace_error(&self, e: IoError, operation: &'static str) {
+    fn trace_error(&self, address: PciAddr, e: IoError, operation: &'static str) {
         let error = match e {
             IoError::InvalidRegister => "offset not supported",
             IoError::InvalidAccessSize => "invalid access size",
             IoError::UnalignedAccess => "unaligned access",
         };
         tracelimit::warn_ratelimited!(
-            address = %self.state.pio_addr_reg.address(),
+            %address,
             "pci config space {} operation error: {}",
             operation,
             error
         );
     }
 
-    fn trace_recv_error(&self, e: mesh::RecvError, operation: &'static str) {
+    fn trace_recv_error(&self, address: PciAddr, e: mesh::RecvError, operation: &'static str) {
         tracelimit::warn_ratelimited!(
-            address = %self.state.pio_addr_reg.address(),
+            %address,
             "pci config space {} operation recv error: {:?}",
             operation,
             e,
         );
     }
+
+    /// Finish a (possibly undersized) config space read, given the result of
+    /// reading the dword containing `byte_offset`.
+    fn complete_cfg_read(
+        &mut self,
+        res: IoResult,
+        address: PciAddr,
+        byte_offset: u16,
+        value: u32,
+        data: &mut [u8],
+    ) -> IoResult {
+        match res {
+            IoResult::Ok => {
+                let value = shift_read_value(byte_offset, data.len(), value);
+                data.copy_from_slice(&value.as_bytes()[..data.len()]);
+                IoResult::Ok
+            }
+            IoResult::Err(e) => {
+                self.trace_error(address, e, "read");
+                // Regardless of the pci error that occurred we return all zeros.
+                // This is technically device-specific behavior, but it's what all
+                // hyper-v devices do and it's worked for us so far.
+                data.zero();
+                IoResult::Ok
+            }
+            IoResult::Defer(deferred_device_read) => {
+                let (bus_read, bus_token) = defer_read();
+                assert!(self.deferred_action.is_none());
+                self.deferred_action = Some(DeferredAction::Read {
+                    deferred_device_read,
+                    bus_read,
+                    read_len: data.len(),
+                    byte_offset,
+                    address,
+                });
+                if let Some(waker) = self.waker.take() {
+                    waker.wake();
+                }
+                IoResult::Defer(bus_token)
+            }
+        }
+    }
+
+    /// Perform a (possibly undersized) config space write of `new_value` at
+    /// `byte_offset` within the dword `register` of the device at `address`.
+    fn cfg_access_write(
+        &mut self,
+        address: PciAddr,
+        register: u16,
+        byte_offset: u16,
+        len: usize,
+        new_value: u32,
+    ) -> IoResult {
+        let merged_value = if len == 4 {
+            new_value
+        } else {
+            // If the access isn't a double word, read in the old data
+            // to form a full word.
+            //
+            // Note that this isn't *really* correct, because reading
+            // bits may have a side-effect. Also, writing to bits that
+            // weren't actually written to may have side-effects...
+            //
+            // However, this technique appears to work fine for
+            // everything we've encountered so far ¯\_(ツ)_/¯
+            let mut old_value = 0;
+            match self.cfg_read(address, register, &mut old_value) {
+                IoResult::Ok => combine_old_new_values(byte_offset, old_value, new_value, len),
+                IoResult::Err(e) => {
+                    self.trace_error(address, e, "read for undersized write");
+                    // Regardless of the pci error that occurred, we return all zeros.
+                    // This is technically device-specific behavior, but it's what all
+                    // hyper-v devices do and it's worked for us so far.
+                    0
+                }
+                IoResult::Defer(deferred_device_read) => {
+                    let (bus_write, bus_token) = defer_write();
+                    assert!(self.deferred_action.is_none());
+                    self.deferred_action = Some(DeferredAction::ReadForWrite {
+                        deferred_device_read,
+                        bus_write,
+                        write_len: len,
+                        byte_offset,
+                        new_value,
+                        address,
+                        register,
+                    });
+                    if let Some(waker) = self.waker.take() {
+                        waker.wake();
+                    }
+                    return IoResult::Defer(bus_token);
+                }
+            }
+        };
+
+        let write_result = self.cfg_write(address, register, merged_value);
+        match write_result {
+            IoResult::Err(e) => {
+                self.trace_error(address, e, "write");
+                IoResult::Ok
+            }
+            IoResult::Ok | IoResult::Defer(_) => {
+                // If the write was successful we're all set.
+                // If the write is deferred we have no extra work to do after
+                // it resolves, unlike with read, so we can just return it and
+                // let the motherboard poll.
+                write_result
+            }
+        }
+    }
 }
 
 impl ChangeDeviceState for GenericPciBus {
@@ -359,13 +580,17 @@ fn supports_pio(&mut self) -> Option<&mut dyn PortIoIntercept> {
         Some(self)
     }
 
+    fn supports_mmio(&mut self) -> Option<&mut dyn MmioIntercept> {
+        Some(self)
+    }
+
     fn supports_poll_device(&mut self) -> Option<&mut dyn PollDevice> {
         Some(self)
     }
 }
 
-fn shift_read_value(io_port: u16, len: usize, value: u32) -> u32 {
-    let shift = (io_port & 0x3) * 8;
+fn shift_read_value(byte_offset: u16, len: usize, value: u32) -> u32 {
+    let shift = (byte_offset & 0x3) * 8;
     match len {
         4 => value,
         2 => value >> shift & 0xFFFF,
@@ -374,12 +599,23 @@ fn shift_read_value(io_port: u16, len: usize, value: u32) -> u32 {
     }
 }
 
-fn combine_old_new_values(io_port: u16, old_value: u32, new_value: u32, len: usize) -> u32 {
-    let shift = (io_port & 0x3) * 8;
+fn combine_old_new_values(byte_offset: u16, old_value: u32, new_value: u32, len: usize) -> u32 {
+    let shift = (byte_offset & 0x3) * 8;
     let mask = (1 << (len * 8)) - 1;
     (old_value & !(mask << shift)) | (new_value << shift)
 }
 
+/// Decode an offset into the ECAM region into the addressed function and the
+/// dword aligned register within its configuration space.
+fn ecam_decode(offset: u64) -> (PciAddr, u16) {
+    let address = PciAddr {
+        bus: (offset >> 20) as u8,
+        device: (offset >> 15 & 0x1f) as u8,
+        function: (offset >> 12 & 0x7) as u8,
+    };
+    (address, (offset & 0xffc) as u16)
+}
+
 impl PortIoIntercept for GenericPciBus {
     fn io_read(&mut self, io_port: u16, data: &mut [u8]) -> IoResult {
         if !matches!(data.len(), 1 | 2 | 4) {
@@ -404,36 +640,7 @@ fn io_read(&mut self, io_port: u16, data: &mut [u8]) -> IoResult {
 
         tracing::trace!(?io_port, ?res, ?data, "io port read");
 
-        match res {
-            IoResult::Ok => {
-                let value = shift_read_value(io_port, data.len(), value);
-                data.copy_from_slice(&value.as_bytes()[..data.len()]);
-                IoResult::Ok
-            }
-            IoResult::Err(e) => {
-                self.trace_error(e, "read");
-                // Regardless of the pci error that occurred we return all zeros.
-                // This is technically device-specific behavior, but it's what all
-                // hyper-v devices do and it's worked for us so far.
-                data.zero();
-                IoResult::Ok
-            }
-            IoResult::Defer(deferred_device_read) => {
-                let (bus_read, bus_token) = defer_read();
-                assert!(self.deferred_action.is_none());
-                self.deferred_action = Some(DeferredAction::Read {
-                    deferred_device_read,
-                    bus_read,
-                    read_len: data.len(),
-                    io_port,
-                    address: self.state.pio_addr_reg.address(),
-                });
-                if let Some(waker) = self.waker.take() {
-                    waker.wake();
-                }
-                IoResult::Defer(bus_token)
-            }
-        }
+        self.complete_cfg_read(res, self.state.pio_addr_reg.address(), io_port, value, data)
     }
 
     fn io_write(&mut self, io_port: u16, data: &[u8]) -> IoResult {
@@ -469,69 +676,70 @@ fn io_write(&mut self, io_port: u16, data: &[u8]) -> IoResult {
                 self.handle_addr_write(v)
             }
             _ if self.pio_data.offset_of(io_port).is_some() => {
-                let merged_value = if data.len() == 4 {
-                    new_value
-                } else {
-                    // If the access isn't a double word, read in the old data
-                    // to form a full word.
-                    //
-                    // Note that this isn't *really* correct, because reading
-                    // bits may have a side-effect. Also, writing to bits that
-                    // weren't actually written to may have side-effects...
-                    //
-                    // However, this technique appears to work fine for
-                    // everything we've encountered so far ¯\_(ツ)_/¯
-                    let mut old_value = 0;
-                    match self.handle_data_read(&mut old_value) {
-                        IoResult::Ok => {
-                            combine_old_new_values(io_port, old_value, new_value, data.len())
-                        }
-                        IoResult::Err(e) => {
-                            self.trace_error(e, "read for undersized write");
-                            // Regardless of the pci error that occurred, we return all zeros.
-                            // This is technically device-specific behavior, but it's what all
-                            // hyper-v devices do and it's worked for us so far.
-                            0
-                        }
-                        IoResult::Defer(deferred_device_read) => {
-                            let (bus_write, bus_token) = defer_write();
-                            assert!(self.deferred_action.is_none());
-                            self.deferred_action = Some(DeferredAction::ReadForWrite {
-                                deferred_device_read,
-                                bus_write,
-                                write_len: data.len(),
-                                io_port,
-                                new_value,
-                                address: self.state.pio_addr_reg.address(),
-                            });
-                            if let Some(waker) = self.waker.take() {
-                                waker.wake();
-                            }
-                            return IoResult::Defer(bus_token);
-                        }
-                    }
-                };
+                tracing::trace!(%self.state.pio_addr_reg, "data write");
 
-                let write_result = self.handle_data_write(merged_value);
-                match write_result {
-                    IoResult::Err(e) => {
-                        self.trace_error(e, "write");
-                        IoResult::Ok
-                    }
-                    IoResult::Ok | IoResult::Defer(_) => {
-                        // If the write was successful we're all set.
-                        // If the write is deferred we have no extra work to do after
-                        // it resolves, unlike with read, so we can just return it and
-                        // let the motherboard poll.
-                        write_result
-                    }
+                if !self.state.pio_addr_reg.enabled() {
+                    tracelimit::warn_ratelimited!("addr enable bit is set to disabled");
+                    return IoResult::Ok;
                 }
+
+                let address = self.state.pio_addr_reg.address();
+                let register = self.state.pio_addr_reg.register().into();
+                self.cfg_access_write(address, register, io_port, data.len(), new_value)
             }
             _ => IoResult::Err(IoError::InvalidRegister),
         }
     }
 }
 
+impl MmioIntercept for GenericPciBus {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) -> IoResult {
+        if !matches!(data.len(), 1 | 2 | 4) {
+            return IoResult::Err(IoError::InvalidAccessSize);
+        }
+
+        if addr % data.len() as u64 != 0 {
+            return IoResult::Err(IoError::UnalignedAccess);
+        }
+
+        let Some(offset) = self.ecam.as_ref().and_then(|ecam| ecam.offset_of(addr)) else {
+            return IoResult::Err(IoError::InvalidRegister);
+        };
+
+        let (address, register) = ecam_decode(offset);
+        tracing::trace!(%address, register, "ecam read");
+
+        let mut value = 0;
+        let res = self.cfg_read(address, register, &mut value);
+        self.complete_cfg_read(res, address, offset as u16, value, data)
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) -> IoResult {
+        if !matches!(data.len(), 1 | 2 | 4) {
+            return IoResult::Err(IoError::InvalidAccessSize);
+        }
+
+        if addr % data.len() as u64 != 0 {
+            return IoResult::Err(IoError::UnalignedAccess);
+        }
+
+        let Some(offset) = self.ecam.as_ref().and_then(|ecam| ecam.offset_of(addr)) else {
+            return IoResult::Err(IoError::InvalidRegister);
+        };
+
+        let new_value = {
+            let mut temp: u32 = 0;
+            temp.as_bytes_mut()[..data.len()].copy_from_slice(data);
+            temp
+        };
+
+        let (address, register) = ecam_decode(offset);
+        tracing::trace!(%address, register, data = ?new_value, "ecam write");
+
+        self.cfg_access_write(address, register, offset as u16, data.len(), new_value)
+    }
+}
+
 impl PollDevice for GenericPciBus {
     fn poll_device(&mut self, cx: &mut Context<'_>) {
         self.waker = Some(cx.waker().clone());
@@ -541,7 +749,7 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                     mut deferred_device_read,
                     bus_read,
                     read_len,
-                    io_port,
+                    byte_offset,
                     address,
                 } => {
                     let mut buf = 0;
@@ -550,18 +758,18 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                         let value = match res {
                             Ok(()) => buf,
                             Err(e) => {
-                                self.trace_recv_error(e, "deferred read");
+                                self.trace_recv_error(address, e, "deferred read");
                                 0
                             }
                         };
-                        let value = shift_read_value(io_port, read_len, value);
+                        let value = shift_read_value(byte_offset, read_len, value);
                         bus_read.complete(&value.as_bytes()[..read_len]);
                     } else {
                         self.deferred_action = Some(DeferredAction::Read {
                             deferred_device_read,
                             bus_read,
                             read_len,
-                            io_port,
+                            byte_offset,
                             address,
                         });
                     }
@@ -570,9 +778,10 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                     mut deferred_device_read,
                     bus_write,
                     write_len,
-                    io_port,
+                    byte_offset,
                     new_value,
                     address,
+                    register,
                 } => {
                     let mut buf = 0;
                     if let Poll::Ready(res) = deferred_device_read.poll_read(cx, buf.as_bytes_mut())
@@ -580,18 +789,18 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                         let old_value = match res {
                             Ok(()) => buf,
                             Err(e) => {
-                                self.trace_recv_error(e, "deferred read for write");
+                                self.trace_recv_error(address, e, "deferred read for write");
                                 0
                             }
                         };
                         let merged_value =
-                            combine_old_new_values(io_port, old_value, new_value, write_len);
-                        match self.handle_data_write(merged_value) {
+                            combine_old_new_values(byte_offset, old_value, new_value, write_len);
+                        match self.cfg_write(address, register, merged_value) {
                             IoResult::Ok => {
                                 bus_write.complete();
                             }
                             IoResult::Err(e) => {
-                                self.trace_error(e, "write");
+                                self.trace_error(address, e, "write");
                                 bus_write.complete();
                             }
                             IoResult::Defer(deferred_device_write) => {
@@ -609,9 +818,10 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                             deferred_device_read,
                             bus_write,
                             write_len,
-                            io_port,
+                            byte_offset,
                             new_value,
                             address,
+                            register,
                         });
                     }
                 }
@@ -625,7 +835,7 @@ fn poll_device(&mut self, cx: &mut Context<'_>) {
                         match res {
                             Ok(()) => {}
                             Err(e) => {
-                                self.trace_recv_error(e, "deferred write");
+                                self.trace_recv_error(address, e, "deferred write");
                             }
                         }
                         bus_write.complete();
@@ -745,3 +955,172 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chipset_device::pio::ExternallyManagedPortIoIntercepts;
+    use std::sync::Mutex;
+
+    const ECAM_BASE: u64 = 0xe000_0000;
+
+    /// Maps the ECAM region, so that the bus can decode addresses in it.
+    struct TestMmio;
+
+    impl RegisterMmioIntercept for TestMmio {
+        fn new_io_region(&mut self, _region_name: &str, len: u64) -> Box<dyn ControlMmioIntercept> {
+            Box::new(TestRegion { len, addr: None })
+        }
+    }
+
+    struct TestRegion {
+        len: u64,
+        addr: Option<u64>,
+    }
+
+    impl ControlMmioIntercept for TestRegion {
+        fn region_name(&self) -> &str {
+            "ecam"
+        }
+
+        fn map(&mut self, addr: u64) {
+            self.addr = Some(addr);
+        }
+
+        fn unmap(&mut self) {
+            self.addr = None;
+        }
+
+        fn addr(&self) -> Option<u64> {
+            self.addr
+        }
+
+        fn len(&self) -> u64 {
+            self.len
+        }
+
+        fn offset_of(&self, addr: u64) -> Option<u64> {
+            let offset = addr.checked_sub(self.addr?)?;
+            (offset < self.len).then_some(offset)
+        }
+    }
+
+    /// A function whose 4KB configuration space is plain memory.
+    struct TestDevice(Arc<Mutex<Vec<u32>>>);
+
+    impl GenericPciBusDevice for TestDevice {
+        fn pci_cfg_read(&mut self, offset: u16, value: &mut u32) -> Option<IoResult> {
+            *value = self.0.lock().unwrap()[offset as usize / 4];
+            Some(IoResult::Ok)
+        }
+
+        fn pci_cfg_write(&mut self, offset: u16, value: u32) -> Option<IoResult> {
+            self.0.lock().unwrap()[offset as usize / 4] = value;
+            Some(IoResult::Ok)
+        }
+    }
+
+    fn ecam_address(bus: u8, device: u8, function: u8, register: u16) -> u64 {
+        ECAM_BASE
+            + ((bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12)
+            + register as u64
+    }
+
+    fn new_bus() -> (GenericPciBus, Arc<Mutex<Vec<u32>>>) {
+        let mut bus = GenericPciBus::new(
+            &mut ExternallyManagedPortIoIntercepts,
+            standard_x86_io_ports::ADDR_START,
+            standard_x86_io_ports::DATA_START,
+        )
+        .with_ecam(&mut TestMmio, ECAM_BASE, 2);
+        let cfg = Arc::new(Mutex::new((0..1024).map(|i| 0x1000_0000 | i).collect()));
+        assert!(bus
+            .add_pci_device(1, 2, 3, "test", TestDevice(cfg.clone()))
+            .is_ok());
+        (bus, cfg)
+    }
+
+    #[test]
+    fn ecam_decode_offsets() {
+        let decode = |offset| {
+            let (address, register) = ecam_decode(offset);
+            (address.bus, address.device, address.function, register)
+        };
+        assert_eq!(decode(0), (0, 0, 0, 0));
+        assert_eq!(
+            decode(1 << 20 | 3 << 15 | 5 << 12 | 0x104),
+            (1, 3, 5, 0x104)
+        );
+        // Registers are dword aligned.
+        assert_eq!(decode(0x107), (0, 0, 0, 0x104));
+        assert_eq!(
+            decode(0xff << 20 | 0x1f << 15 | 7 << 12 | 0xfff),
+            (0xff, 0x1f, 7, 0xffc)
+        );
+    }
+
+    #[test]
+    fn ecam_read() {
+        let (mut bus, _cfg) = new_bus();
+
+        // The extended configuration space is reachable.
+        let mut data = [0; 4];
+        bus.mmio_read(ecam_address(1, 2, 3, 0x104), &mut data)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1000_0041);
+
+        let mut data = [0; 2];
+        bus.mmio_read(ecam_address(1, 2, 3, 0x106), &mut data)
+            .unwrap();
+        assert_eq!(u16::from_le_bytes(data), 0x1000);
+
+        let mut data = [0; 1];
+        bus.mmio_read(ecam_address(1, 2, 3, 0x104), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x41]);
+
+        // An empty slot reads as all ones.
+        let mut data = [0; 4];
+        bus.mmio_read(ecam_address(0, 2, 3, 0x104), &mut data)
+            .unwrap();
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn ecam_write() {
+        let (mut bus, cfg) = new_bus();
+
+        bus.mmio_write(ecam_address(1, 2, 3, 0x200), &0x1234_5678u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(cfg.lock().unwrap()[0x80], 0x1234_5678);
+
+        // Undersized writes are merged with the rest of the dword.
+        bus.mmio_write(ecam_address(1, 2, 3, 0x105), &[0xaa])
+            .unwrap();
+        assert_eq!(cfg.lock().unwrap()[0x41], 0x1000_aa41);
+        bus.mmio_write(ecam_address(1, 2, 3, 0x106), &0xbbccu16.to_le_bytes())
+            .unwrap();
+        assert_eq!(cfg.lock().unwrap()[0x41], 0xbbcc_aa41);
+    }
+
+    #[test]
+    fn ecam_invalid_access() {
+        let (mut bus, _cfg) = new_bus();
+
+        // Only the configured buses are decoded.
+        let mut data = [0; 4];
+        assert!(matches!(
+            bus.mmio_read(ecam_address(2, 0, 0, 0), &mut data),
+            IoResult::Err(IoError::InvalidRegister)
+        ));
+        assert!(matches!(
+            bus.mmio_read(ecam_address(1, 2, 3, 0x102), &mut data),
+            IoResult::Err(IoError::UnalignedAccess)
+        ));
+        let mut data = [0; 8];
+        assert!(matches!(
+            bus.mmio_read(ecam_address(1, 2, 3, 0x100), &mut data),
+            IoResult::Err(IoError::InvalidAccessSize)
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! PCI Express Capability.
+
+use super::PciCapability;
+use crate::spec::caps::pci_express::DeviceControl;
+use crate::spec::caps::pci_express::DevicePortType;
+use crate::spec::caps::pci_express::LinkCapabilities;
+use crate::spec::caps::pci_express::LinkControl;
+use crate::spec::caps::pci_express::LinkStatus;
+use crate::spec::caps::pci_express::PciExpressCapabilities;
+use crate::spec::caps::pci_express::PciExpressCapabilityHeader;
+use crate::spec::caps::pci_express::SlotCapabilities;
+use crate::spec::caps::pci_express::SlotControl;
+use crate::spec::caps::pci_express::SlotStatus;
+use crate::spec::caps::pci_express::LINK_SPEED_2_5_GT;
+use crate::spec::caps::pci_express::PCI_EXPRESS_CAPABILITY_SIZE;
+use crate::spec::caps::pci_express::PCI_EXPRESS_CAPABILITY_VERSION;
+use crate::spec::caps::CapabilityId;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Bits of the Device Control register that the guest may change.
+///
+/// Function level reset is not supported, so `INITIATE_FLR` always reads as
+/// zero.
+const DEVICE_CONTROL_WRITABLE: DeviceControl = DeviceControl::from_bits_truncate(
+    DeviceControl::all().bits() & !DeviceControl::INITIATE_FLR.bits(),
+);
+
+/// Bits of the Link Control register that the guest may change.
+///
+/// The emulated link is always up, so `LINK_DISABLE` and `RETRAIN_LINK` are
+/// accepted but have no effect, and `RETRAIN_LINK` always reads as zero.
+const LINK_CONTROL_WRITABLE: LinkControl =
+    LinkControl::from_bits_truncate(LinkControl::all().bits() & !LinkControl::RETRAIN_LINK.bits());
+
+/// Bits of the Slot Control register that the guest may change.
+///
+/// The slot has no attention button, power controller, indicators, MRL sensor
+/// or interlock, so the controls for those read as zero.
+const SLOT_CONTROL_WRITABLE: SlotControl = SlotControl::from_bits_truncate(
+    SlotControl::PRESENCE_DETECT_CHANGED_ENABLE.bits()
+        | SlotControl::COMMAND_COMPLETED_INTERRUPT_ENABLE.bits()
+        | SlotControl::HOT_PLUG_INTERRUPT_ENABLE.bits()
+        | SlotControl::DATA_LINK_LAYER_STATE_CHANGED_ENABLE.bits(),
+);
+
+/// Bits of the Slot Status register that latch events, and are cleared by
+/// writing 1.
+const SLOT_STATUS_RW1C: SlotStatus = SlotStatus::from_bits_truncate(
+    SlotStatus::PRESENCE_DETECT_CHANGED.bits() | SlotStatus::DATA_LINK_LAYER_STATE_CHANGED.bits(),
+);
+
+/// A hot-plug slot below a PCI Express downstream port.
+///
+/// The slot is shared between the port's [`PciExpressCapability`], which
+/// exposes it to the guest, and the port's device model, which reports cards
+/// being inserted and removed and raises the hot-plug interrupt.
+///
+/// The slot models surprise hot-plug: there is no power controller or
+/// attention button, and a card is usable as soon as it is present.
+#[derive(Clone, Debug, Inspect)]
+pub struct PciExpressSlot {
+    slot_number: u16,
+    #[inspect(flatten)]
+    state: Arc<Mutex<SlotState>>,
+}
+
+#[derive(Debug, Inspect)]
+struct SlotState {
+    present: bool,
+    #[inspect(hex)]
+    control: u16,
+    #[inspect(hex)]
+    status: u16,
+}
+
+impl PciExpressSlot {
+    /// Create a new, empty slot with the given physical slot number.
+    pub fn new(slot_number: u16) -> Self {
+        assert!(slot_number <= 0x1fff);
+        Self {
+            slot_number,
+            state: Arc::new(Mutex::new(SlotState {
+                present: false,
+                control: 0,
+                status: 0,
+            })),
+        }
+    }
+
+    /// Report a card being inserted into or removed from the slot.
+    ///
+    /// The change is latched in the slot status register. Call
+    /// [`interrupt_pending`](Self::interrupt_pending) afterwards to find out
+    /// whether the guest should be interrupted.
+    pub fn set_present(&self, present: bool) {
+        let mut state = self.state.lock();
+        if state.present != present {
+            state.present = present;
+            state.status |= SLOT_STATUS_RW1C.bits();
+        }
+    }
+
+    /// Returns true if a card is present in the slot.
+    pub fn is_present(&self) -> bool {
+        self.state.lock().present
+    }
+
+    /// Returns true if the guest has enabled hot-plug interrupts and there
+    /// is an unacknowledged event it asked to be notified of.
+    pub fn interrupt_pending(&self) -> bool {
+        let state = self.state.lock();
+        let control = SlotControl::from_bits_truncate(state.control);
+        let status = SlotStatus::from_bits_truncate(state.status);
+        control.contains(SlotControl::HOT_PLUG_INTERRUPT_ENABLE)
+            && ((control.contains(SlotControl::PRESENCE_DETECT_CHANGED_ENABLE)
+                && status.contains(SlotStatus::PRESENCE_DETECT_CHANGED))
+                || (control.contains(SlotControl::DATA_LINK_LAYER_STATE_CHANGED_ENABLE)
+                    && status.contains(SlotStatus::DATA_LINK_LAYER_STATE_CHANGED)))
+    }
+
+    fn capabilities(&self) -> SlotCapabilities {
+        SlotCapabilities::from_bits_truncate((self.slot_number as u32) << 19)
+            | SlotCapabilities::HOT_PLUG_SURPRISE
+            | SlotCapabilities::HOT_PLUG_CAPABLE
+            | SlotCapabilities::NO_COMMAND_COMPLETED_SUPPORT
+    }
+
+    fn read_control_status(&self) -> u32 {
+        let state = self.state.lock();
+        let mut status = SlotStatus::from_bits_truncate(state.status);
+        if state.present {
+            status |= SlotStatus::PRESENCE_DETECT_STATE;
+        }
+        state.control as u32 | (status.bits() as u32) << 16
+    }
+
+    fn write_control_status(&self, val: u32) {
+        let mut state = self.state.lock();
+        state.control =
+            (SlotControl::from_bits_truncate(val as u16) & SLOT_CONTROL_WRITABLE).bits();
+        state.status &=
+            !(SlotStatus::from_bits_truncate((val >> 16) as u16) & SLOT_STATUS_RW1C).bits();
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.control = 0;
+        state.status = 0;
+    }
+}
+
+/// A PCI Express Capability structure, describing an emulated device as a
+/// PCI Express function.
+///
+/// The emulated link never reports errors, and only goes down when a port's
+/// slot is empty. Other than the slot, only the control registers hold
+/// guest-visible state.
+#[derive(Debug, Inspect)]
+pub struct PciExpressCapability {
+    #[inspect(debug)]
+    device_port_type: DevicePortType,
+    slot: Option<PciExpressSlot>,
+    state: PciExpressCapabilityState,
+}
+
+#[derive(Debug, Inspect)]
+struct PciExpressCapabilityState {
+    #[inspect(hex)]
+    device_control: u16,
+    #[inspect(hex)]
+    link_control: u16,
+}
+
+impl PciExpressCapabilityState {
+    fn new() -> Self {
+        Self {
+            device_control: 0,
+            link_control: 0,
+        }
+    }
+}
+
+impl PciExpressCapability {
+    /// Create a new PCI Express capability for a function of the given
+    /// device/port type.
+    pub fn new(device_port_type: DevicePortType) -> Self {
+        Self {
+            device_port_type,
+            slot: None,
+            state: PciExpressCapabilityState::new(),
+        }
+    }
+
+    /// Implement the slot registers, backed by `slot`.
+    ///
+    /// Only root ports and downstream switch ports can have a slot. The link
+    /// below the port is only reported as active while a card is present.
+    pub fn with_slot(mut self, slot: PciExpressSlot) -> Self {
+        assert!(matches!(
+            self.device_port_type,
+            DevicePortType::ROOT_PORT | DevicePortType::DOWNSTREAM_SWITCH_PORT
+        ));
+        self.slot = Some(slot);
+        self
+    }
+
+    /// Returns true if this function sits at the downstream end of a link,
+    /// and should therefore implement the link registers.
+    ///
+    /// Root complex integrated endpoints and event collectors have no link.
+    fn has_link(&self) -> bool {
+        !matches!(
+            self.device_port_type,
+            DevicePortType::ROOT_COMPLEX_INTEGRATED_ENDPOINT
+                | DevicePortType::ROOT_COMPLEX_EVENT_COLLECTOR
+        )
+    }
+
+    fn capabilities(&self) -> PciExpressCapabilities {
+        let mut caps = PciExpressCapabilities::from_bits_truncate(
+            PCI_EXPRESS_CAPABILITY_VERSION | (self.device_port_type.0 as u16) << 4,
+        );
+        if self.slot.is_some() {
+            caps |= PciExpressCapabilities::SLOT_IMPLEMENTED;
+        }
+        caps
+    }
+
+    fn link_capabilities(&self) -> LinkCapabilities {
+        if self.has_link() {
+            // A single 2.5 GT/s lane, which is all any guest needs to know
+            // about an emulated link.
+            LinkCapabilities::from_bits_truncate(
+                LINK_SPEED_2_5_GT
+                    | 1 << 4
+                    | LinkCapabilities::DATA_LINK_LAYER_ACTIVE_REPORTING.bits(),
+            )
+        } else {
+            LinkCapabilities::empty()
+        }
+    }
+
+    fn link_status(&self) -> LinkStatus {
+        let link_up = self.slot.as_ref().map_or(true, |slot| slot.is_present());
+        if self.has_link() && link_up {
+            LinkStatus::from_bits_truncate(LINK_SPEED_2_5_GT as u16 | 1 << 4)
+                | LinkStatus::DATA_LINK_LAYER_LINK_ACTIVE
+        } else {
+            LinkStatus::empty()
+        }
+    }
+}
+
+impl PciCapability for PciExpressCapability {
+    fn label(&self) -> &str {
+        "pci-express"
+    }
+
+    fn len(&self) -> usize {
+        PCI_EXPRESS_CAPABILITY_SIZE
+    }
+
+    fn read_u32(&self, offset: u16) -> u32 {
+        match PciExpressCapabilityHeader(offset) {
+            PciExpressCapabilityHeader::CONTROL_CAPS => {
+                CapabilityId::PCI_EXPRESS.0 as u32 | (self.capabilities().bits() as u32) << 16
+            }
+            // No optional device features are supported, and the maximum
+            // payload size is the minimum of 128 bytes.
+            PciExpressCapabilityHeader::DEVICE_CAPS => 0,
+            PciExpressCapabilityHeader::DEVICE_CTL_STS => self.state.device_control as u32,
+            PciExpressCapabilityHeader::LINK_CAPS => self.link_capabilities().bits(),
+            PciExpressCapabilityHeader::LINK_CTL_STS => {
+                if self.has_link() {
+                    self.state.link_control as u32 | (self.link_status().bits() as u32) << 16
+                } else {
+                    0
+                }
+            }
+            PciExpressCapabilityHeader::SLOT_CAPS => self
+                .slot
+                .as_ref()
+                .map_or(0, |slot| slot.capabilities().bits()),
+            PciExpressCapabilityHeader::SLOT_CTL_STS => self
+                .slot
+                .as_ref()
+                .map_or(0, |slot| slot.read_control_status()),
+            // Root port error reporting and the version 2 registers are not
+            // implemented, and read as zero.
+            PciExpressCapabilityHeader::ROOT_CTL_CAPS
+            | PciExpressCapabilityHeader::ROOT_STS
+            | PciExpressCapabilityHeader::DEVICE_CAPS_2
+            | PciExpressCapabilityHeader::DEVICE_CTL_STS_2
+            | PciExpressCapabilityHeader::LINK_CAPS_2
+            | PciExpressCapabilityHeader::LINK_CTL_STS_2
+            | PciExpressCapabilityHeader::SLOT_CAPS_2
+            | PciExpressCapabilityHeader::SLOT_CTL_STS_2 => 0,
+            _ => panic!("Unreachable read offset {}", offset),
+        }
+    }
+
+    fn write_u32(&mut self, offset: u16, val: u32) {
+        match PciExpressCapabilityHeader(offset) {
+            PciExpressCapabilityHeader::DEVICE_CTL_STS => {
+                // The status bits are RW1C, but no errors are ever reported,
+                // so there is nothing to clear.
+                let control = DeviceControl::from_bits_truncate(val as u16);
+                if control.contains(DeviceControl::INITIATE_FLR) {
+                    tracelimit::warn_ratelimited!("function level reset is not supported");
+                }
+                self.state.device_control = (control & DEVICE_CONTROL_WRITABLE).bits();
+            }
+            PciExpressCapabilityHeader::LINK_CTL_STS => {
+                if self.has_link() {
+                    self.state.link_control = (LinkControl::from_bits_truncate(val as u16)
+                        & LINK_CONTROL_WRITABLE)
+                        .bits();
+                }
+            }
+            PciExpressCapabilityHeader::SLOT_CTL_STS if self.slot.is_some() => {
+                self.slot.as_ref().unwrap().write_control_status(val);
+            }
+            PciExpressCapabilityHeader::CONTROL_CAPS
+            | PciExpressCapabilityHeader::DEVICE_CAPS
+            | PciExpressCapabilityHeader::LINK_CAPS
+            | PciExpressCapabilityHeader::SLOT_CAPS
+            | PciExpressCapabilityHeader::SLOT_CTL_STS
+            | PciExpressCapabilityHeader::ROOT_CTL_CAPS
+            | PciExpressCapabilityHeader::ROOT_STS
+            | PciExpressCapabilityHeader::DEVICE_CAPS_2
+            | PciExpressCapabilityHeader::DEVICE_CTL_STS_2
+            | PciExpressCapabilityHeader::LINK_CAPS_2
+            | PciExpressCapabilityHeader::LINK_CTL_STS_2
+            | PciExpressCapabilityHeader::SLOT_CAPS_2
+            | PciExpressCapabilityHeader::SLOT_CTL_STS_2 => {
+                tracelimit::warn_ratelimited!(
+                    "Unexpected write offset {:?}",
+                    PciExpressCapabilityHeader(offset)
+                )
+            }
+            _ => panic!("Unreachable write offset {}", offset),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = PciExpressCapabilityState::new();
+        if let Some(slot) = &self.slot {
+            slot.reset();
+        }
+    }
+}
+
+mod save_restore {
+    use super::*;
+    use vmcore::save_restore::RestoreError;
+    use vmcore::save_restore::SaveError;
+    use vmcore::save_restore::SaveRestore;
+
+    mod state {
+        use mesh::payload::Protobuf;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Debug, Protobuf, SavedStateRoot)]
+        #[mesh(package = "pci.caps.pci_express")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub device_control: u16,
+            #[mesh(2)]
+            pub link_control: u16,
+            #[mesh(3)]
+            pub slot_control: u16,
+            #[mesh(4)]
+            pub slot_status: u16,
+        }
+    }
+
+    impl SaveRestore for PciExpressCapability {
+        type SavedState = state::SavedState;
+
+        fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+            let PciExpressCapabilityState {
+                device_control,
+                link_control,
+            } = self.state;
+
+            let (slot_control, slot_status) = self.slot.as_ref().map_or((0, 0), |slot| {
+                let state = slot.state.lock();
+                (state.control, state.status)
+            });
+
+            Ok(state::SavedState {
+                device_control,
+                link_control,
+                slot_control,
+                slot_status,
+            })
+        }
+
+        fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
+            let state::SavedState {
+                device_control,
+                link_control,
+                slot_control,
+                slot_status,
+            } = state;
+
+            self.state = PciExpressCapabilityState {
+                device_control: device_control & DEVICE_CONTROL_WRITABLE.bits(),
+                link_control: link_control & LINK_CONTROL_WRITABLE.bits(),
+            };
+
+            if let Some(slot) = &self.slot {
+                let mut state = slot.state.lock();
+                state.control = slot_control & SLOT_CONTROL_WRITABLE.bits();
+                state.status = slot_status & SLOT_STATUS_RW1C.bits();
+            }
+
+            Ok(())
+        }
+    }
+}
@@ -58,6 +58,15 @@ pub fn set_level(&self, high: bool) {
         }
     }
 
+    fn pin_register(&self) -> u8 {
+        match self.pin {
+            PciInterruptPin::IntA => 1,
+            PciInterruptPin::IntB => 2,
+            PciInterruptPin::IntC => 3,
+            PciInterruptPin::IntD => 4,
+        }
+    }
+
     fn set_disabled(&self, disabled: bool) {
         tracing::debug!(
             disabled = ?self.interrupt_disabled,
@@ -78,6 +87,71 @@ fn set_disabled(&self, disabled: bool) {
     }
 }
 
+/// Builds the status register, which is read-only and shared by all header
+/// types.
+fn status_register(
+    capabilities: &[Box<dyn PciCapability>],
+    intx_interrupt: Option<&IntxInterrupt>,
+) -> cfg_space::Status {
+    let mut status = cfg_space::Status::empty();
+    if !capabilities.is_empty() {
+        status |= cfg_space::Status::CAPABILITIES_LIST;
+    }
+
+    if let Some(intx_interrupt) = intx_interrupt {
+        if intx_interrupt.interrupt_status.load(Ordering::SeqCst) {
+            status |= cfg_space::Status::INTERRUPT_STATUS;
+        }
+    }
+
+    status
+}
+
+fn get_capability_index_and_offset(
+    capabilities: &[Box<dyn PciCapability>],
+    offset: u16,
+) -> Option<(usize, u16)> {
+    let mut cap_offset = 0;
+    for i in 0..capabilities.len() {
+        let cap_size = capabilities[i].len() as u16;
+        if offset < cap_offset + cap_size {
+            return Some((i, offset - cap_offset));
+        }
+        cap_offset += cap_size;
+    }
+    None
+}
+
+/// Reads from the capability list, which starts at offset 0x40 for all
+/// header types. Returns `None` if no capability covers `offset`.
+fn read_capabilities(capabilities: &[Box<dyn PciCapability>], offset: u16) -> Option<u32> {
+    let (cap_index, cap_offset) = get_capability_index_and_offset(capabilities, offset - 0x40)?;
+    let mut value = capabilities[cap_index].read_u32(cap_offset);
+    if cap_offset == 0 {
+        let next = if cap_index < capabilities.len() - 1 {
+            offset as u32 + capabilities[cap_index].len() as u32
+        } else {
+            0
+        };
+        assert!(value & 0xff00 == 0);
+        value |= next << 8;
+    }
+    Some(value)
+}
+
+/// Writes to the capability list. Returns false if no capability covers
+/// `offset`.
+fn write_capabilities(capabilities: &mut [Box<dyn PciCapability>], offset: u16, val: u32) -> bool {
+    if let Some((cap_index, cap_offset)) =
+        get_capability_index_and_offset(capabilities, offset - 0x40)
+    {
+        capabilities[cap_index].write_u32(cap_offset, val);
+        true
+    } else {
+        false
+    }
+}
+
 #[derive(Debug, Inspect)]
 struct ConfigSpaceType0EmulatorState {
     /// The command register
@@ -107,8 +181,8 @@ fn new() -> Self {
 
 /// Emulator for the standard Type 0 PCI configuration space header.
 //
-// TODO: split + share shared registers with other (yet unimplemented)
-// header types
+// TODO: share more of the common header registers with
+// `ConfigSpaceType1Emulator`
 #[derive(Inspect)]
 pub struct ConfigSpaceType0Emulator {
     // Fixed configuration
@@ -311,18 +385,6 @@ pub fn reset(&mut self) {
         }
     }
 
-    fn get_capability_index_and_offset(&self, offset: u16) -> Option<(usize, u16)> {
-        let mut cap_offset = 0;
-        for i in 0..self.capabilities.len() {
-            let cap_size = self.capabilities[i].len() as u16;
-            if offset < cap_offset + cap_size {
-                return Some((i, offset - cap_offset));
-            }
-            cap_offset += cap_size;
-        }
-        None
-    }
-
     /// Read from the config space. `offset` must be 32-bit aligned.
     pub fn read_u32(&self, offset: u16, value: &mut u32) -> IoResult {
         use cfg_space::HeaderType00;
@@ -332,17 +394,7 @@ pub fn read_u32(&self, offset: u16, value: &mut u32) -> IoResult {
                 (self.hardware_ids.device_id as u32) << 16 | self.hardware_ids.vendor_id as u32
             }
             HeaderType00::STATUS_COMMAND => {
-                let mut status = cfg_space::Status::empty();
-                if !self.capabilities.is_empty() {
-                    status |= cfg_space::Status::CAPABILITIES_LIST;
-                }
-
-                if let Some(intx_interrupt) = &self.intx_interrupt {
-                    if intx_interrupt.interrupt_status.load(Ordering::SeqCst) {
-                        status |= cfg_space::Status::INTERRUPT_STATUS;
-                    }
-                }
-
+                let status = status_register(&self.capabilities, self.intx_interrupt.as_deref());
                 (status.bits() as u32) << 16 | self.state.command.bits() as u32
             }
             HeaderType00::CLASS_REVISION => {
@@ -382,49 +434,26 @@ pub fn read_u32(&self, offset: u16, value: &mut u32) -> IoResult {
             }
             HeaderType00::RESERVED => 0,
             HeaderType00::LATENCY_INTERRUPT => {
-                let interrupt_pin = if let Some(intx_interrupt) = &self.intx_interrupt {
-                    match intx_interrupt.pin {
-                        PciInterruptPin::IntA => 1,
-                        PciInterruptPin::IntB => 2,
-                        PciInterruptPin::IntC => 3,
-                        PciInterruptPin::IntD => 4,
-                    }
-                } else {
-                    0
-                };
+                let interrupt_pin = self
+                    .intx_interrupt
+                    .as_ref()
+                    .map_or(0, |intx_interrupt| intx_interrupt.pin_register());
                 self.state.interrupt_line as u32 | (interrupt_pin as u32) << 8
             }
             // rest of the range is reserved for extended device capabilities
             _ if (0x40..0x100).contains(&offset) => {
-                if let Some((cap_index, cap_offset)) =
-                    self.get_capability_index_and_offset(offset - 0x40)
-                {
-                    let mut value = self.capabilities[cap_index].read_u32(cap_offset);
-                    if cap_offset == 0 {
-                        let next = if cap_index < self.capabilities.len() - 1 {
-                            offset as u32 + self.capabilities[cap_index].len() as u32
-                        } else {
-                            0
-                        };
-                        assert!(value & 0xff00 == 0);
-                        value |= next << 8;
-                    }
+                if let Some(value) = read_capabilities(&self.capabilities, offset) {
                     value
                 } else {
                     tracelimit::warn_ratelimited!(offset, "unhandled config space read");
                     return IoResult::Err(IoError::InvalidRegister);
                 }
             }
-            _ if (0x100..0x1000).contains(&offset) => {
-                // TODO: properly support extended pci express configuration space
-                if offset == 0x100 {
-                    tracelimit::warn_ratelimited!(offset, "unexpected pci express probe");
-                    0x000ffff
-                } else {
-                    tracelimit::warn_ratelimited!(offset, "unhandled extended config space read");
-                    return IoResult::Err(IoError::InvalidRegister);
-                }
-            }
+            // No extended capabilities are implemented. A zero header at the
+            // start of the extended configuration space terminates the
+            // (empty) extended capability list, and the rest of the space is
+            // reserved.
+            _ if (0x100..0x1000).contains(&offset) => 0,
             _ => {
                 tracelimit::warn_ratelimited!(offset, "unexpected config space read");
                 return IoResult::Err(IoError::InvalidRegister);
@@ -538,11 +567,7 @@ pub fn write_u32(&mut self, offset: u16, val: u32) -> IoResult {
             _ if offset < 0x40 && offset % 4 == 0 => (),
             // rest of the range is reserved for extended device capabilities
             _ if (0x40..0x100).contains(&offset) => {
-                if let Some((cap_index, cap_offset)) =
-                    self.get_capability_index_and_offset(offset - 0x40)
-                {
-                    self.capabilities[cap_index].write_u32(cap_offset, val);
-                } else {
+                if !write_capabilities(&mut self.capabilities, offset, val) {
                     tracelimit::warn_ratelimited!(
                         offset,
                         value = val,
@@ -552,7 +577,6 @@ pub fn write_u32(&mut self, offset: u16, val: u32) -> IoResult {
                 }
             }
             _ if (0x100..0x1000).contains(&offset) => {
-                // TODO: properly support extended pci express configuration space
                 tracelimit::warn_ratelimited!(
                     offset,
                     value = val,
@@ -575,12 +599,286 @@ pub fn find_bar(&self, address: u64) -> Option<(u8, u16)> {
     }
 }
 
+#[derive(Debug, Inspect)]
+struct ConfigSpaceType1EmulatorState {
+    /// The command register
+    command: cfg_space::Command,
+    /// The bus number of the bus the bridge sits on
+    primary_bus: u8,
+    /// The bus number of the bus directly behind the bridge
+    secondary_bus: u8,
+    /// The highest bus number behind the bridge
+    subordinate_bus: u8,
+    /// Memory window base, in the register's 1MB granularity encoding
+    #[inspect(hex)]
+    memory_base: u16,
+    /// Memory window limit, in the register's 1MB granularity encoding
+    #[inspect(hex)]
+    memory_limit: u16,
+    /// The bridge control register
+    bridge_control: cfg_space::BridgeControl,
+    /// Scratch space for firmware to communicate IRQ assignments to the OS.
+    interrupt_line: u8,
+}
+
+impl ConfigSpaceType1EmulatorState {
+    fn new() -> Self {
+        Self {
+            command: cfg_space::Command::empty(),
+            primary_bus: 0,
+            secondary_bus: 0,
+            subordinate_bus: 0,
+            memory_base: 0,
+            memory_limit: 0,
+            bridge_control: cfg_space::BridgeControl::empty(),
+            interrupt_line: 0,
+        }
+    }
+}
+
+/// The bits of the memory base and limit registers that hold address bits
+/// 31:20. The low four bits are read-only zero.
+const MEMORY_RANGE_MASK: u16 = 0xfff0;
+
+/// Emulator for the standard Type 1 (PCI-to-PCI bridge) configuration space
+/// header.
+///
+/// The bridge has no BARs, I/O window or prefetchable memory window. Only the
+/// bus numbers and the non-prefetchable memory window are configurable, which
+/// is enough for the guest to enumerate and map devices behind the bridge.
+///
+/// The emulator only stores the configuration; routing config space and MMIO
+/// accesses to devices behind the bridge is up to the bridge device model.
+#[derive(Inspect)]
+pub struct ConfigSpaceType1Emulator {
+    // Fixed configuration
+    hardware_ids: HardwareIds,
+
+    // Runtime glue
+    #[inspect(with = "|x| inspect::iter_by_key(x.iter().map(|cap| (cap.label(), cap)))")]
+    capabilities: Vec<Box<dyn PciCapability>>,
+    intx_interrupt: Option<Arc<IntxInterrupt>>,
+
+    // Volatile state
+    state: ConfigSpaceType1EmulatorState,
+}
+
+impl ConfigSpaceType1Emulator {
+    /// Create a new [`ConfigSpaceType1Emulator`]
+    pub fn new(hardware_ids: HardwareIds, capabilities: Vec<Box<dyn PciCapability>>) -> Self {
+        Self {
+            hardware_ids,
+            capabilities,
+            intx_interrupt: None,
+            state: ConfigSpaceType1EmulatorState::new(),
+        }
+    }
+
+    /// If using legacy INT#x interrupts: wire a LineInterrupt to one of the 4
+    /// INT#x pins, returning an object that manages configuration space bits
+    /// when the bridge sets the interrupt level.
+    pub fn set_interrupt_pin(
+        &mut self,
+        pin: PciInterruptPin,
+        line: LineInterrupt,
+    ) -> Arc<IntxInterrupt> {
+        let intx_interrupt = Arc::new(IntxInterrupt {
+            pin,
+            line,
+            interrupt_disabled: AtomicBool::new(false),
+            interrupt_status: AtomicBool::new(false),
+        });
+        self.intx_interrupt = Some(intx_interrupt.clone());
+        intx_interrupt
+    }
+
+    /// Resets the configuration space state.
+    pub fn reset(&mut self) {
+        self.state = ConfigSpaceType1EmulatorState::new();
+
+        self.update_intx_disable(self.state.command);
+
+        for cap in &mut self.capabilities {
+            cap.reset();
+        }
+
+        if let Some(intx) = &mut self.intx_interrupt {
+            intx.set_level(false);
+        }
+    }
+
+    /// Returns the range of bus numbers the guest has assigned to the
+    /// hierarchy behind the bridge.
+    ///
+    /// The range is empty until the guest has assigned a secondary bus
+    /// number.
+    pub fn assigned_bus_range(&self) -> std::ops::RangeInclusive<u8> {
+        if self.state.secondary_bus == 0 {
+            #[allow(clippy::reversed_empty_ranges)]
+            return 1..=0;
+        }
+        self.state.secondary_bus..=self.state.subordinate_bus
+    }
+
+    /// Read from the config space. `offset` must be 32-bit aligned.
+    pub fn read_u32(&self, offset: u16, value: &mut u32) -> IoResult {
+        use cfg_space::HeaderType01;
+
+        *value = match HeaderType01(offset) {
+            HeaderType01::DEVICE_VENDOR => {
+                (self.hardware_ids.device_id as u32) << 16 | self.hardware_ids.vendor_id as u32
+            }
+            HeaderType01::STATUS_COMMAND => {
+                let status = status_register(&self.capabilities, self.intx_interrupt.as_deref());
+                (status.bits() as u32) << 16 | self.state.command.bits() as u32
+            }
+            HeaderType01::CLASS_REVISION => {
+                (u8::from(self.hardware_ids.base_class) as u32) << 24
+                    | (u8::from(self.hardware_ids.sub_class) as u32) << 16
+                    | (u8::from(self.hardware_ids.prog_if) as u32) << 8
+                    | self.hardware_ids.revision_id as u32
+            }
+            // Header type 01h. The latency timer is read-only zero on PCI
+            // Express.
+            HeaderType01::BIST_HEADER => 0x01 << 16,
+            HeaderType01::BAR0 | HeaderType01::BAR1 => 0,
+            // The secondary latency timer is read-only zero on PCI Express.
+            HeaderType01::LATENCY_BUS_NUMBERS => {
+                self.state.primary_bus as u32
+                    | (self.state.secondary_bus as u32) << 8
+                    | (self.state.subordinate_bus as u32) << 16
+            }
+            // No I/O window, and the secondary side never reports errors.
+            HeaderType01::SEC_STATUS_IO_RANGE => 0,
+            HeaderType01::MEMORY_RANGE => {
+                self.state.memory_base as u32 | (self.state.memory_limit as u32) << 16
+            }
+            // No prefetchable memory window.
+            HeaderType01::PREFETCH_MEMORY_RANGE
+            | HeaderType01::PREFETCH_BASE_UPPER
+            | HeaderType01::PREFETCH_LIMIT_UPPER
+            | HeaderType01::IO_RANGE_UPPER => 0,
+            HeaderType01::RESERVED_CAP_PTR => {
+                if self.capabilities.is_empty() {
+                    0
+                } else {
+                    0x40
+                }
+            }
+            HeaderType01::EXPANSION_ROM_BASE => 0,
+            HeaderType01::BRIDGE_CONTROL_INTERRUPT => {
+                let interrupt_pin = self
+                    .intx_interrupt
+                    .as_ref()
+                    .map_or(0, |intx_interrupt| intx_interrupt.pin_register());
+                self.state.interrupt_line as u32
+                    | (interrupt_pin as u32) << 8
+                    | (self.state.bridge_control.bits() as u32) << 16
+            }
+            // rest of the range is reserved for extended device capabilities
+            _ if (0x40..0x100).contains(&offset) => {
+                if let Some(value) = read_capabilities(&self.capabilities, offset) {
+                    value
+                } else {
+                    tracelimit::warn_ratelimited!(offset, "unhandled config space read");
+                    return IoResult::Err(IoError::InvalidRegister);
+                }
+            }
+            // No extended capabilities are implemented.
+            _ if (0x100..0x1000).contains(&offset) => 0,
+            _ => {
+                tracelimit::warn_ratelimited!(offset, "unexpected config space read");
+                return IoResult::Err(IoError::InvalidRegister);
+            }
+        };
+
+        IoResult::Ok
+    }
+
+    fn update_intx_disable(&mut self, command: cfg_space::Command) {
+        if let Some(intx_interrupt) = &self.intx_interrupt {
+            intx_interrupt.set_disabled(command.contains(cfg_space::Command::INTX_DISABLE))
+        }
+    }
+
+    /// Write to the config space. `offset` must be 32-bit aligned.
+    pub fn write_u32(&mut self, offset: u16, val: u32) -> IoResult {
+        use cfg_space::HeaderType01;
+
+        match HeaderType01(offset) {
+            HeaderType01::STATUS_COMMAND => {
+                let command = match cfg_space::Command::from_bits(val as u16) {
+                    Some(command) => command,
+                    None => {
+                        tracelimit::warn_ratelimited!(offset, val, "setting invalid command bits");
+                        // still do our best
+                        cfg_space::Command::from_bits_truncate(val as u16)
+                    }
+                };
+
+                if self
+                    .state
+                    .command
+                    .contains(cfg_space::Command::INTX_DISABLE)
+                    != command.contains(cfg_space::Command::INTX_DISABLE)
+                {
+                    self.update_intx_disable(command)
+                }
+
+                self.state.command = command;
+            }
+            HeaderType01::LATENCY_BUS_NUMBERS => {
+                self.state.primary_bus = val as u8;
+                self.state.secondary_bus = (val >> 8) as u8;
+                self.state.subordinate_bus = (val >> 16) as u8;
+            }
+            HeaderType01::MEMORY_RANGE => {
+                self.state.memory_base = val as u16 & MEMORY_RANGE_MASK;
+                self.state.memory_limit = (val >> 16) as u16 & MEMORY_RANGE_MASK;
+            }
+            HeaderType01::BRIDGE_CONTROL_INTERRUPT => {
+                self.state.interrupt_line = val as u8;
+                self.state.bridge_control =
+                    cfg_space::BridgeControl::from_bits_truncate((val >> 16) as u16);
+            }
+            // all other base regs are noops
+            _ if offset < 0x40 && offset % 4 == 0 => (),
+            // rest of the range is reserved for extended device capabilities
+            _ if (0x40..0x100).contains(&offset) => {
+                if !write_capabilities(&mut self.capabilities, offset, val) {
+                    tracelimit::warn_ratelimited!(
+                        offset,
+                        value = val,
+                        "unhandled config space write"
+                    );
+                    return IoResult::Err(IoError::InvalidRegister);
+                }
+            }
+            _ if (0x100..0x1000).contains(&offset) => {
+                tracelimit::warn_ratelimited!(
+                    offset,
+                    value = val,
+                    "unhandled extended config space write"
+                );
+                return IoResult::Err(IoError::InvalidRegister);
+            }
+            _ => {
+                tracelimit::warn_ratelimited!(offset, value = val, "unexpected config space write");
+                return IoResult::Err(IoError::InvalidRegister);
+            }
+        }
+
+        IoResult::Ok
+    }
+}
+
 mod save_restore {
     use super::*;
     use thiserror::Error;
     use vmcore::save_restore::RestoreError;
     use vmcore::save_restore::SaveError;
     use vmcore::save_restore::SaveRestore;
+    use vmcore::save_restore::SavedStateBlob;
 
     mod state {
         use mesh::payload::Protobuf;
@@ -601,6 +899,29 @@ pub struct SavedState {
             #[mesh(5)]
             pub capabilities: Vec<(String, SavedStateBlob)>,
         }
+
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "pci.cfg_space_emu")]
+        pub struct Type1SavedState {
+            #[mesh(1)]
+            pub command: u16,
+            #[mesh(2)]
+            pub primary_bus: u8,
+            #[mesh(3)]
+            pub secondary_bus: u8,
+            #[mesh(4)]
+            pub subordinate_bus: u8,
+            #[mesh(5)]
+            pub memory_base: u16,
+            #[mesh(6)]
+            pub memory_limit: u16,
+            #[mesh(7)]
+            pub bridge_control: u16,
+            #[mesh(8)]
+            pub interrupt_line: u8,
+            #[mesh(9)]
+            pub capabilities: Vec<(String, SavedStateBlob)>,
+        }
     }
 
     #[derive(Debug, Error)]
@@ -611,6 +932,46 @@ enum ConfigSpaceRestoreError {
         InvalidCap(String),
     }
 
+    fn save_capabilities(
+        capabilities: &mut [Box<dyn PciCapability>],
+    ) -> Result<Vec<(String, SavedStateBlob)>, SaveError> {
+        capabilities
+            .iter_mut()
+            .map(|cap| {
+                let id = cap.label().to_owned();
+                Ok((id, cap.save()?))
+            })
+            .collect()
+    }
+
+    fn restore_capabilities(
+        capabilities: &mut [Box<dyn PciCapability>],
+        saved: Vec<(String, SavedStateBlob)>,
+    ) -> Result<(), RestoreError> {
+        for (id, entry) in saved {
+            tracing::debug!(save_id = id.as_str(), "restoring pci capability");
+
+            // yes, yes, this is O(n^2), but devices never have more than a
+            // handful of caps, so it's totally fine.
+            let mut restored = false;
+            for cap in capabilities.iter_mut() {
+                if cap.label() == id {
+                    cap.restore(entry)?;
+                    restored = true;
+                    break;
+                }
+            }
+
+            if !restored {
+                return Err(RestoreError::InvalidSavedState(
+                    ConfigSpaceRestoreError::InvalidCap(id).into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     impl SaveRestore for ConfigSpaceType0Emulator {
         type SavedState = state::SavedState;
 
@@ -627,14 +988,7 @@ fn save(&mut self) -> Result<Self::SavedState, SaveError> {
                 base_addresses,
                 interrupt_line,
                 latency_timer,
-                capabilities: self
-                    .capabilities
-                    .iter_mut()
-                    .map(|cap| {
-                        let id = cap.label().to_owned();
-                        Ok((id, cap.save()?))
-                    })
-                    .collect::<Result<_, _>>()?,
+                capabilities: save_capabilities(&mut self.capabilities)?,
             };
 
             Ok(saved_state)
@@ -661,28 +1015,72 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
             };
 
             self.sync_command_register(self.state.command);
-            for (id, entry) in capabilities {
-                tracing::debug!(save_id = id.as_str(), "restoring pci capability");
-
-                // yes, yes, this is O(n^2), but devices never have more than a
-                // handful of caps, so it's totally fine.
-                let mut restored = false;
-                for cap in self.capabilities.iter_mut() {
-                    if cap.label() == id {
-                        cap.restore(entry)?;
-                        restored = true;
-                        break;
-                    }
-                }
+            restore_capabilities(&mut self.capabilities, capabilities)
+        }
+    }
 
-                if !restored {
-                    return Err(RestoreError::InvalidSavedState(
-                        ConfigSpaceRestoreError::InvalidCap(id).into(),
-                    ));
-                }
-            }
+    impl SaveRestore for ConfigSpaceType1Emulator {
+        type SavedState = state::Type1SavedState;
+
+        fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+            let ConfigSpaceType1EmulatorState {
+                command,
+                primary_bus,
+                secondary_bus,
+                subordinate_bus,
+                memory_base,
+                memory_limit,
+                bridge_control,
+                interrupt_line,
+            } = self.state;
+
+            Ok(state::Type1SavedState {
+                command: command.bits(),
+                primary_bus,
+                secondary_bus,
+                subordinate_bus,
+                memory_base,
+                memory_limit,
+                bridge_control: bridge_control.bits(),
+                interrupt_line,
+                capabilities: save_capabilities(&mut self.capabilities)?,
+            })
+        }
+
+        fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
+            let state::Type1SavedState {
+                command,
+                primary_bus,
+                secondary_bus,
+                subordinate_bus,
+                memory_base,
+                memory_limit,
+                bridge_control,
+                interrupt_line,
+                capabilities,
+            } = state;
+
+            self.state = ConfigSpaceType1EmulatorState {
+                command: cfg_space::Command::from_bits(command).ok_or(
+                    RestoreError::InvalidSavedState(
+                        ConfigSpaceRestoreError::InvalidConfigBits.into(),
+                    ),
+                )?,
+                primary_bus,
+                secondary_bus,
+                subordinate_bus,
+                memory_base: memory_base & MEMORY_RANGE_MASK,
+                memory_limit: memory_limit & MEMORY_RANGE_MASK,
+                bridge_control: cfg_space::BridgeControl::from_bits(bridge_control).ok_or(
+                    RestoreError::InvalidSavedState(
+                        ConfigSpaceRestoreError::InvalidConfigBits.into(),
+                    ),
+                )?,
+                interrupt_line,
+            };
 
-            Ok(())
+            self.update_intx_disable(self.state.command);
+            restore_capabilities(&mut self.capabilities, capabilities)
         }
     }
 }
@@ -124,9 +124,10 @@ pub enum Subclass: u8 {
             NETWORK_CONTROLLER_ETHERNET = 0x00,
 
             // Bridge (Class code: 0x06)
-            // Other values: 0x02 - 0x0A
+            // Other values: 0x02 - 0x03, 0x05 - 0x0A
             BRIDGE_HOST = 0x00,
             BRIDGE_ISA = 0x01,
+            BRIDGE_PCI_TO_PCI = 0x04,
             BRIDGE_OTHER = 0x80,
 
             // Base System Peripheral (Class code: 0x08)
@@ -236,6 +237,68 @@ pub enum HeaderType00: u16 {
 
     pub const HEADER_TYPE_00_SIZE: u16 = 0x40;
 
+    open_enum::open_enum! {
+        /// Offsets into the type 01h (PCI-to-PCI bridge) configuration space
+        /// header.
+        ///
+        /// Sources: PCI-to-PCI Bridge Architecture Spec 1.2 - 3.2
+        ///
+        /// | Offset | Bits 31-24                       | Bits 23-16      | Bits 15-8                | Bits 7-0             |
+        /// |--------|----------------------------------|-----------------|--------------------------|----------------------|
+        /// | 0x0    | Device ID                        |                 | Vendor ID                |                      |
+        /// | 0x4    | Status                           |                 | Command                  |                      |
+        /// | 0x8    | Class code                       |                 |                          | Revision ID          |
+        /// | 0xC    | BIST                             | Header type     | Latency Timer            | Cache Line Size      |
+        /// | 0x10   | Base address #0 (BAR0)           |                 |                          |                      |
+        /// | 0x14   | Base address #1 (BAR1)           |                 |                          |                      |
+        /// | 0x18   | Secondary Latency Timer          | Subordinate Bus | Secondary Bus            | Primary Bus          |
+        /// | 0x1C   | Secondary Status                 |                 | I/O Limit                | I/O Base             |
+        /// | 0x20   | Memory Limit                     |                 | Memory Base              |                      |
+        /// | 0x24   | Prefetchable Memory Limit        |                 | Prefetchable Memory Base |                      |
+        /// | 0x28   | Prefetchable Base Upper 32 Bits  |                 |                          |                      |
+        /// | 0x2C   | Prefetchable Limit Upper 32 Bits |                 |                          |                      |
+        /// | 0x30   | I/O Limit Upper 16 Bits          |                 | I/O Base Upper 16 Bits   |                      |
+        /// | 0x34   | Reserved                         |                 |                          | Capabilities Pointer |
+        /// | 0x38   | Expansion ROM base address       |                 |                          |                      |
+        /// | 0x3C   | Bridge Control                   |                 | Interrupt PIN            | Interrupt Line       |
+        pub enum HeaderType01: u16 {
+            DEVICE_VENDOR            = 0x00,
+            STATUS_COMMAND           = 0x04,
+            CLASS_REVISION           = 0x08,
+            BIST_HEADER              = 0x0C,
+            BAR0                     = 0x10,
+            BAR1                     = 0x14,
+            LATENCY_BUS_NUMBERS      = 0x18,
+            SEC_STATUS_IO_RANGE      = 0x1C,
+            MEMORY_RANGE             = 0x20,
+            PREFETCH_MEMORY_RANGE    = 0x24,
+            PREFETCH_BASE_UPPER      = 0x28,
+            PREFETCH_LIMIT_UPPER     = 0x2C,
+            IO_RANGE_UPPER           = 0x30,
+            RESERVED_CAP_PTR         = 0x34,
+            EXPANSION_ROM_BASE       = 0x38,
+            BRIDGE_CONTROL_INTERRUPT = 0x3C,
+        }
+    }
+
+    pub const HEADER_TYPE_01_SIZE: u16 = 0x40;
+
+    bitflags::bitflags! {
+        /// Bridge Control Register
+        #[derive(Inspect)]
+        #[inspect(debug)]
+        pub struct BridgeControl: u16 {
+            const PARITY_ERROR_RESPONSE = 1 << 0;
+            const ENABLE_SERR           = 1 << 1;
+            const ISA_ENABLE            = 1 << 2;
+            const VGA_ENABLE            = 1 << 3;
+            const VGA_16_BIT_DECODE     = 1 << 4;
+            const MASTER_ABORT_MODE     = 1 << 5;
+            const SECONDARY_BUS_RESET   = 1 << 6;
+            // rest of bits are not applicable to PCI Express, or reserved
+        }
+    }
+
     bitflags::bitflags! {
         /// BAR in-band encoding bits.
         ///
@@ -309,6 +372,7 @@ pub mod caps {
         pub enum CapabilityId: u8 {
             #![allow(missing_docs)] // self explanatory variants
             VENDOR_SPECIFIC = 0x09,
+            PCI_EXPRESS     = 0x10,
             MSIX            = 0x11,
         }
     }
@@ -343,4 +407,228 @@ pub enum MsixTableEntryIdx: u16 {
             }
         }
     }
+
+    /// PCI Express
+    ///
+    /// Sources: PCI Express Base Spec 4.0 - 7.5.3
+    #[allow(missing_docs)] // primarily enums/structs with self-explanatory variants
+    pub mod pci_express {
+        open_enum::open_enum! {
+            /// Offsets into the PCI Express Capability structure
+            ///
+            /// | Offset    | Bits 31-16             | Bits 15-8    | Bits 7-0              |
+            /// |-----------|------------------------|--------------|-----------------------|
+            /// | Cap + 0x0 | PCIe Capabilities      | Next Pointer | Capability ID (0x10)  |
+            /// | Cap + 0x4 | Device Capabilities    |              |                       |
+            /// | Cap + 0x8 | Device Status          | Device Control                       |
+            /// | Cap + 0xC | Link Capabilities      |              |                       |
+            /// | Cap + 0x10| Link Status            | Link Control                         |
+            /// | Cap + 0x14| Slot Capabilities      |              |                       |
+            /// | Cap + 0x18| Slot Status            | Slot Control                         |
+            /// | Cap + 0x1C| Root Capabilities      | Root Control                         |
+            /// | Cap + 0x20| Root Status            |              |                       |
+            /// | Cap + 0x24| Device Capabilities 2  |              |                       |
+            /// | Cap + 0x28| Device Status 2        | Device Control 2                     |
+            /// | Cap + 0x2C| Link Capabilities 2    |              |                       |
+            /// | Cap + 0x30| Link Status 2          | Link Control 2                       |
+            /// | Cap + 0x34| Slot Capabilities 2    |              |                       |
+            /// | Cap + 0x38| Slot Status 2          | Slot Control 2                       |
+            pub enum PciExpressCapabilityHeader: u16 {
+                CONTROL_CAPS       = 0x00,
+                DEVICE_CAPS        = 0x04,
+                DEVICE_CTL_STS     = 0x08,
+                LINK_CAPS          = 0x0C,
+                LINK_CTL_STS       = 0x10,
+                SLOT_CAPS          = 0x14,
+                SLOT_CTL_STS       = 0x18,
+                ROOT_CTL_CAPS      = 0x1C,
+                ROOT_STS           = 0x20,
+                DEVICE_CAPS_2      = 0x24,
+                DEVICE_CTL_STS_2   = 0x28,
+                LINK_CAPS_2        = 0x2C,
+                LINK_CTL_STS_2     = 0x30,
+                SLOT_CAPS_2        = 0x34,
+                SLOT_CTL_STS_2     = 0x38,
+            }
+        }
+
+        /// Size of a version 2 PCI Express Capability structure.
+        pub const PCI_EXPRESS_CAPABILITY_SIZE: usize = 0x3C;
+
+        /// Capability version reported in the PCI Express Capabilities
+        /// register.
+        pub const PCI_EXPRESS_CAPABILITY_VERSION: u16 = 2;
+
+        open_enum::open_enum! {
+            /// Device/Port Type, reported in bits 7:4 of the PCI Express
+            /// Capabilities register.
+            pub enum DevicePortType: u8 {
+                ENDPOINT                         = 0x0,
+                LEGACY_ENDPOINT                  = 0x1,
+                ROOT_PORT                        = 0x4,
+                UPSTREAM_SWITCH_PORT             = 0x5,
+                DOWNSTREAM_SWITCH_PORT           = 0x6,
+                PCIE_TO_PCI_BRIDGE               = 0x7,
+                PCI_TO_PCIE_BRIDGE               = 0x8,
+                ROOT_COMPLEX_INTEGRATED_ENDPOINT = 0x9,
+                ROOT_COMPLEX_EVENT_COLLECTOR     = 0xA,
+            }
+        }
+
+        bitflags::bitflags! {
+            /// PCI Express Capabilities Register (upper 16 bits of the
+            /// capability header)
+            pub struct PciExpressCapabilities: u16 {
+                const VERSION           = 0b1111 << 0;
+                const DEVICE_PORT_TYPE  = 0b1111 << 4;
+                const SLOT_IMPLEMENTED  = 1 << 8;
+                const INTERRUPT_MESSAGE = 0b11111 << 9;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Device Capabilities Register
+            pub struct DeviceCapabilities: u32 {
+                const MAX_PAYLOAD_SIZE_SUPPORTED = 0b111 << 0;
+                const PHANTOM_FUNCTIONS          = 0b11 << 3;
+                const EXTENDED_TAG_FIELD         = 1 << 5;
+                const ENDPOINT_L0S_LATENCY       = 0b111 << 6;
+                const ENDPOINT_L1_LATENCY        = 0b111 << 9;
+                const ROLE_BASED_ERROR_REPORTING = 1 << 15;
+                const SLOT_POWER_LIMIT_VALUE     = 0xff << 18;
+                const SLOT_POWER_LIMIT_SCALE     = 0b11 << 26;
+                const FUNCTION_LEVEL_RESET       = 1 << 28;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Device Control Register
+            pub struct DeviceControl: u16 {
+                const CORRECTABLE_ERROR_REPORTING = 1 << 0;
+                const NON_FATAL_ERROR_REPORTING   = 1 << 1;
+                const FATAL_ERROR_REPORTING       = 1 << 2;
+                const UNSUPPORTED_REQUEST_REPORTING = 1 << 3;
+                const RELAXED_ORDERING            = 1 << 4;
+                const MAX_PAYLOAD_SIZE            = 0b111 << 5;
+                const EXTENDED_TAG_FIELD          = 1 << 8;
+                const PHANTOM_FUNCTIONS           = 1 << 9;
+                const AUX_POWER_PM                = 1 << 10;
+                const NO_SNOOP                    = 1 << 11;
+                const MAX_READ_REQUEST_SIZE       = 0b111 << 12;
+                const INITIATE_FLR                = 1 << 15;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Device Status Register
+            pub struct DeviceStatus: u16 {
+                const CORRECTABLE_ERROR_DETECTED  = 1 << 0;
+                const NON_FATAL_ERROR_DETECTED    = 1 << 1;
+                const FATAL_ERROR_DETECTED        = 1 << 2;
+                const UNSUPPORTED_REQUEST_DETECTED = 1 << 3;
+                const AUX_POWER_DETECTED          = 1 << 4;
+                const TRANSACTIONS_PENDING        = 1 << 5;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Link Capabilities Register
+            pub struct LinkCapabilities: u32 {
+                const MAX_LINK_SPEED                 = 0b1111 << 0;
+                const MAX_LINK_WIDTH                 = 0b111111 << 4;
+                const ASPM_SUPPORT                   = 0b11 << 10;
+                const L0S_EXIT_LATENCY               = 0b111 << 12;
+                const L1_EXIT_LATENCY                = 0b111 << 15;
+                const CLOCK_POWER_MANAGEMENT         = 1 << 18;
+                const SURPRISE_DOWN_ERROR_REPORTING  = 1 << 19;
+                const DATA_LINK_LAYER_ACTIVE_REPORTING = 1 << 20;
+                const LINK_BANDWIDTH_NOTIFICATION    = 1 << 21;
+                const ASPM_OPTIONALITY_COMPLIANCE    = 1 << 22;
+                const PORT_NUMBER                    = 0xff << 24;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Link Control Register
+            pub struct LinkControl: u16 {
+                const ASPM_CONTROL                   = 0b11 << 0;
+                const READ_COMPLETION_BOUNDARY       = 1 << 3;
+                const LINK_DISABLE                   = 1 << 4;
+                const RETRAIN_LINK                   = 1 << 5;
+                const COMMON_CLOCK_CONFIGURATION     = 1 << 6;
+                const EXTENDED_SYNCH                 = 1 << 7;
+                const ENABLE_CLOCK_POWER_MANAGEMENT  = 1 << 8;
+                const HARDWARE_AUTONOMOUS_WIDTH_DISABLE = 1 << 9;
+                const LINK_BANDWIDTH_MANAGEMENT_INTERRUPT = 1 << 10;
+                const LINK_AUTONOMOUS_BANDWIDTH_INTERRUPT = 1 << 11;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Link Status Register
+            pub struct LinkStatus: u16 {
+                const CURRENT_LINK_SPEED             = 0b1111 << 0;
+                const NEGOTIATED_LINK_WIDTH          = 0b111111 << 4;
+                const LINK_TRAINING                  = 1 << 11;
+                const SLOT_CLOCK_CONFIGURATION       = 1 << 12;
+                const DATA_LINK_LAYER_LINK_ACTIVE    = 1 << 13;
+                const LINK_BANDWIDTH_MANAGEMENT_STATUS = 1 << 14;
+                const LINK_AUTONOMOUS_BANDWIDTH_STATUS = 1 << 15;
+            }
+        }
+
+        /// Link speed encoding for 2.5 GT/s, used in the Link Capabilities and
+        /// Link Status registers.
+        pub const LINK_SPEED_2_5_GT: u32 = 0x1;
+
+        bitflags::bitflags! {
+            /// Slot Capabilities Register
+            pub struct SlotCapabilities: u32 {
+                const ATTENTION_BUTTON_PRESENT       = 1 << 0;
+                const POWER_CONTROLLER_PRESENT       = 1 << 1;
+                const MRL_SENSOR_PRESENT             = 1 << 2;
+                const ATTENTION_INDICATOR_PRESENT    = 1 << 3;
+                const POWER_INDICATOR_PRESENT        = 1 << 4;
+                const HOT_PLUG_SURPRISE              = 1 << 5;
+                const HOT_PLUG_CAPABLE               = 1 << 6;
+                const SLOT_POWER_LIMIT_VALUE         = 0xff << 7;
+                const SLOT_POWER_LIMIT_SCALE         = 0b11 << 15;
+                const ELECTROMECHANICAL_INTERLOCK_PRESENT = 1 << 17;
+                const NO_COMMAND_COMPLETED_SUPPORT   = 1 << 18;
+                const PHYSICAL_SLOT_NUMBER           = 0x1fff << 19;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Slot Control Register
+            pub struct SlotControl: u16 {
+                const ATTENTION_BUTTON_PRESSED_ENABLE = 1 << 0;
+                const POWER_FAULT_DETECTED_ENABLE    = 1 << 1;
+                const MRL_SENSOR_CHANGED_ENABLE      = 1 << 2;
+                const PRESENCE_DETECT_CHANGED_ENABLE = 1 << 3;
+                const COMMAND_COMPLETED_INTERRUPT_ENABLE = 1 << 4;
+                const HOT_PLUG_INTERRUPT_ENABLE      = 1 << 5;
+                const ATTENTION_INDICATOR_CONTROL    = 0b11 << 6;
+                const POWER_INDICATOR_CONTROL        = 0b11 << 8;
+                const POWER_CONTROLLER_CONTROL       = 1 << 10;
+                const ELECTROMECHANICAL_INTERLOCK_CONTROL = 1 << 11;
+                const DATA_LINK_LAYER_STATE_CHANGED_ENABLE = 1 << 12;
+            }
+        }
+
+        bitflags::bitflags! {
+            /// Slot Status Register
+            pub struct SlotStatus: u16 {
+                const ATTENTION_BUTTON_PRESSED       = 1 << 0;
+                const POWER_FAULT_DETECTED           = 1 << 1;
+                const MRL_SENSOR_CHANGED             = 1 << 2;
+                const PRESENCE_DETECT_CHANGED        = 1 << 3;
+                const COMMAND_COMPLETED              = 1 << 4;
+                const MRL_SENSOR_STATE               = 1 << 5;
+                const PRESENCE_DETECT_STATE          = 1 << 6;
+                const ELECTROMECHANICAL_INTERLOCK_STATUS = 1 << 7;
+                const DATA_LINK_LAYER_STATE_CHANGED  = 1 << 8;
+            }
+        }
+    }
 }
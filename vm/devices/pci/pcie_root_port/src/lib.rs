@@ -0,0 +1,616 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An emulated PCI Express root port with a hot-plug slot.
+//!
+//! Each root port exposes a single slot, into which one emulated PCI device
+//! can be inserted or removed at runtime. Insertion and removal are reported
+//! to the guest through the slot status register and the port's hot-plug
+//! interrupt, which guests with native PCI Express hot-plug support (e.g:
+//! Linux's `pciehp`) use to enumerate and tear down the device.
+
+#![warn(missing_docs)]
+#![forbid(unsafe_code)]
+
+use chipset_device::io::IoResult;
+use chipset_device::mmio::MmioIntercept;
+use chipset_device::pci::PciConfigSpace;
+use chipset_device::poll_device::PollDevice;
+use chipset_device::ChipsetDevice;
+use chipset_device_resources::ErasedChipsetDevice;
+use inspect::InspectMut;
+use pci_core::capabilities::pci_express::PciExpressCapability;
+use pci_core::capabilities::pci_express::PciExpressSlot;
+use pci_core::cfg_space_emu::ConfigSpaceType1Emulator;
+use pci_core::cfg_space_emu::IntxInterrupt;
+use pci_core::spec::caps::pci_express::DevicePortType;
+use pci_core::spec::hwid::ClassCode;
+use pci_core::spec::hwid::HardwareIds;
+use pci_core::spec::hwid::ProgrammingInterface;
+use pci_core::spec::hwid::Subclass;
+use pci_core::PciInterruptPin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Waker;
+use thiserror::Error;
+use vmcore::device_state::ChangeDeviceState;
+use vmcore::line_interrupt::LineInterrupt;
+
+/// Error returned when attaching a device to an occupied slot.
+#[derive(Debug, Error)]
+#[error("slot is occupied by device {0}")]
+pub struct SlotOccupied(Arc<str>);
+
+/// An emulated PCI Express root port.
+///
+/// Devices behind the port register their BARs with the port's own MMIO
+/// registration object, so that MMIO accesses are routed to the port and
+/// forwarded from there to the device.
+pub struct PcieRootPort {
+    cfg_space: ConfigSpaceType1Emulator,
+    slot: PciExpressSlot,
+    intx: Arc<IntxInterrupt>,
+    device: Option<(Arc<str>, ErasedChipsetDevice)>,
+    waker: Option<Waker>,
+    running: bool,
+}
+
+impl InspectMut for PcieRootPort {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        resp.field("cfg_space", &self.cfg_space)
+            .field("running", self.running);
+        if let Some((name, device)) = &mut self.device {
+            resp.field("device_name", name.as_ref())
+                .field_mut("device", device);
+        }
+    }
+}
+
+impl PcieRootPort {
+    /// Creates a new, empty root port.
+    ///
+    /// `slot_number` is the physical slot number reported to the guest, which
+    /// should be unique across the VM. `line` is the interrupt line wired to
+    /// the port's INTA# pin, used for hot-plug notifications.
+    pub fn new(slot_number: u16, line: LineInterrupt) -> Self {
+        let slot = PciExpressSlot::new(slot_number);
+        let mut cfg_space = ConfigSpaceType1Emulator::new(
+            HardwareIds {
+                vendor_id: 0x1414,
+                device_id: 0xc030,
+                revision_id: 0,
+                prog_if: ProgrammingInterface::NONE,
+                sub_class: Subclass::BRIDGE_PCI_TO_PCI,
+                base_class: ClassCode::BRIDGE,
+                type0_sub_vendor_id: 0,
+                type0_sub_system_id: 0,
+            },
+            vec![Box::new(
+                PciExpressCapability::new(DevicePortType::ROOT_PORT).with_slot(slot.clone()),
+            )],
+        );
+        let intx = cfg_space.set_interrupt_pin(PciInterruptPin::IntA, line);
+
+        Self {
+            cfg_space,
+            slot,
+            intx,
+            device: None,
+            waker: None,
+            running: false,
+        }
+    }
+
+    /// Returns true if a device is attached to the port's slot.
+    pub fn is_occupied(&self) -> bool {
+        self.device.is_some()
+    }
+
+    /// Inserts `device` into the port's slot, and notifies the guest.
+    ///
+    /// The device must have been created with this port's MMIO registration
+    /// object. It is started if the port is running.
+    pub fn attach(
+        &mut self,
+        name: impl Into<Arc<str>>,
+        mut device: ErasedChipsetDevice,
+    ) -> Result<(), SlotOccupied> {
+        if let Some((name, _)) = &self.device {
+            return Err(SlotOccupied(name.clone()));
+        }
+
+        let name = name.into();
+        tracing::info!(device = name.as_ref(), "attaching device to root port");
+        if self.running {
+            device.start();
+        }
+        self.device = Some((name, device));
+        self.slot.set_present(true);
+        self.update_interrupt();
+
+        // Poll the new device.
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        Ok(())
+    }
+
+    /// Removes the device from the port's slot, and notifies the guest.
+    ///
+    /// The device is removed by surprise, without waiting for the guest to
+    /// release it. The caller is responsible for stopping the returned
+    /// device.
+    pub fn detach(&mut self) -> Option<ErasedChipsetDevice> {
+        let (name, device) = self.device.take()?;
+        tracing::info!(device = name.as_ref(), "detaching device from root port");
+        self.slot.set_present(false);
+        self.update_interrupt();
+        Some(device)
+    }
+
+    fn update_interrupt(&self) {
+        self.intx.set_level(self.slot.interrupt_pending());
+    }
+
+    fn device_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        self.device
+            .as_mut()
+            .and_then(|(_, device)| device.supports_pci())
+    }
+}
+
+impl ChangeDeviceState for PcieRootPort {
+    fn start(&mut self) {
+        self.running = true;
+        if let Some((_, device)) = &mut self.device {
+            device.start();
+        }
+    }
+
+    async fn stop(&mut self) {
+        self.running = false;
+        if let Some((_, device)) = &mut self.device {
+            device.stop().await;
+        }
+    }
+
+    async fn reset(&mut self) {
+        if let Some((_, device)) = &mut self.device {
+            device.reset().await;
+        }
+        self.cfg_space.reset();
+        self.update_interrupt();
+    }
+}
+
+impl ChipsetDevice for PcieRootPort {
+    fn supports_mmio(&mut self) -> Option<&mut dyn MmioIntercept> {
+        Some(self)
+    }
+
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+
+    fn supports_poll_device(&mut self) -> Option<&mut dyn PollDevice> {
+        Some(self)
+    }
+}
+
+impl MmioIntercept for PcieRootPort {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) -> IoResult {
+        // The only MMIO regions routed to the port are the BARs of the device
+        // behind it.
+        if let Some(mmio) = self
+            .device
+            .as_mut()
+            .and_then(|(_, device)| device.supports_mmio())
+        {
+            mmio.mmio_read(addr, data)
+        } else {
+            data.fill(!0);
+            IoResult::Ok
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) -> IoResult {
+        if let Some(mmio) = self
+            .device
+            .as_mut()
+            .and_then(|(_, device)| device.supports_mmio())
+        {
+            mmio.mmio_write(addr, data)
+        } else {
+            IoResult::Ok
+        }
+    }
+}
+
+impl PollDevice for PcieRootPort {
+    fn poll_device(&mut self, cx: &mut Context<'_>) {
+        self.waker = Some(cx.waker().clone());
+        if let Some(poll) = self
+            .device
+            .as_mut()
+            .and_then(|(_, device)| device.supports_poll_device())
+        {
+            poll.poll_device(cx);
+        }
+    }
+}
+
+impl PciConfigSpace for PcieRootPort {
+    fn pci_cfg_read(&mut self, offset: u16, value: &mut u32) -> IoResult {
+        self.cfg_space.read_u32(offset, value)
+    }
+
+    fn pci_cfg_write(&mut self, offset: u16, value: u32) -> IoResult {
+        let result = self.cfg_space.write_u32(offset, value);
+        // The guest may have acknowledged a slot event or changed which
+        // events it wants to be interrupted for.
+        self.update_interrupt();
+        result
+    }
+
+    fn pci_cfg_read_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: &mut u32,
+    ) -> Option<IoResult> {
+        let buses = self.cfg_space.assigned_bus_range();
+        if !buses.contains(&bus) {
+            return None;
+        }
+
+        // The link below a root port leads to device 0 on the secondary bus.
+        let result = if bus == *buses.start() && device_function == 0 {
+            self.device_pci().map(|pci| pci.pci_cfg_read(offset, value))
+        } else {
+            self.device_pci()
+                .and_then(|pci| pci.pci_cfg_read_forward(bus, device_function, offset, value))
+        };
+
+        Some(result.unwrap_or_else(|| {
+            *value = !0;
+            IoResult::Ok
+        }))
+    }
+
+    fn pci_cfg_write_forward(
+        &mut self,
+        bus: u8,
+        device_function: u8,
+        offset: u16,
+        value: u32,
+    ) -> Option<IoResult> {
+        let buses = self.cfg_space.assigned_bus_range();
+        if !buses.contains(&bus) {
+            return None;
+        }
+
+        let result = if bus == *buses.start() && device_function == 0 {
+            self.device_pci()
+                .map(|pci| pci.pci_cfg_write(offset, value))
+        } else {
+            self.device_pci()
+                .and_then(|pci| pci.pci_cfg_write_forward(bus, device_function, offset, value))
+        };
+
+        Some(result.unwrap_or(IoResult::Ok))
+    }
+}
+
+mod save_restore {
+    use super::*;
+    use vmcore::save_restore::RestoreError;
+    use vmcore::save_restore::SaveError;
+    use vmcore::save_restore::SaveRestore;
+
+    mod state {
+        use mesh::payload::Protobuf;
+        use pci_core::cfg_space_emu::ConfigSpaceType1Emulator;
+        use vmcore::save_restore::SaveRestore;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "pci.pcie_root_port")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub cfg_space: <ConfigSpaceType1Emulator as SaveRestore>::SavedState,
+        }
+    }
+
+    impl SaveRestore for PcieRootPort {
+        type SavedState = state::SavedState;
+
+        fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+            // Hot-plugged devices are not part of the VM's configuration, so
+            // there would be nothing to restore their state into.
+            if self.device.is_some() {
+                return Err(SaveError::NotSupported);
+            }
+
+            Ok(state::SavedState {
+                cfg_space: self.cfg_space.save()?,
+            })
+        }
+
+        fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
+            let state::SavedState { cfg_space } = state;
+            self.cfg_space.restore(cfg_space)?;
+            self.update_interrupt();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chipset_device_resources::ResolvedChipsetDevice;
+    use pci_core::spec::caps::pci_express::LinkStatus;
+    use pci_core::spec::caps::pci_express::PciExpressCapabilityHeader;
+    use pci_core::spec::caps::pci_express::SlotControl;
+    use pci_core::spec::caps::pci_express::SlotStatus;
+    use pci_core::spec::cfg_space::HeaderType01;
+    use vmcore::line_interrupt::test_helpers::TestLineInterruptTarget;
+    use vmcore::save_restore::NoSavedState;
+    use vmcore::save_restore::RestoreError;
+    use vmcore::save_restore::SaveError;
+    use vmcore::save_restore::SaveRestore;
+
+    const VECTOR: u32 = 5;
+    const DEVICE_VENDOR: u32 = 0x1234_5678;
+    /// The PCI Express capability is the port's first capability.
+    const PCI_EXPRESS_CAP: u16 = 0x40;
+    const LINK_CTL_STS: u16 = PCI_EXPRESS_CAP + PciExpressCapabilityHeader::LINK_CTL_STS.0;
+    const SLOT_CTL_STS: u16 = PCI_EXPRESS_CAP + PciExpressCapabilityHeader::SLOT_CTL_STS.0;
+
+    /// A function that only implements its vendor and device IDs.
+    struct TestDevice;
+
+    impl InspectMut for TestDevice {
+        fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+            req.respond();
+        }
+    }
+
+    impl ChangeDeviceState for TestDevice {
+        fn start(&mut self) {}
+
+        async fn stop(&mut self) {}
+
+        async fn reset(&mut self) {}
+    }
+
+    impl ChipsetDevice for TestDevice {
+        fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+            Some(self)
+        }
+    }
+
+    impl PciConfigSpace for TestDevice {
+        fn pci_cfg_read(&mut self, offset: u16, value: &mut u32) -> IoResult {
+            *value = if offset == 0 { DEVICE_VENDOR } else { 0 };
+            IoResult::Ok
+        }
+
+        fn pci_cfg_write(&mut self, _offset: u16, _value: u32) -> IoResult {
+            IoResult::Ok
+        }
+    }
+
+    impl SaveRestore for TestDevice {
+        type SavedState = NoSavedState;
+
+        fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+            Ok(NoSavedState)
+        }
+
+        fn restore(&mut self, NoSavedState: Self::SavedState) -> Result<(), RestoreError> {
+            Ok(())
+        }
+    }
+
+    fn new_port() -> (PcieRootPort, Arc<TestLineInterruptTarget>) {
+        let target = TestLineInterruptTarget::new_arc();
+        let line = LineInterrupt::new_with_target("hotplug", target.clone(), VECTOR);
+        (PcieRootPort::new(3, line), target)
+    }
+
+    fn test_device() -> ErasedChipsetDevice {
+        ResolvedChipsetDevice::from(TestDevice).0
+    }
+
+    fn read(port: &mut PcieRootPort, offset: u16) -> u32 {
+        let mut value = 0;
+        port.pci_cfg_read(offset, &mut value).unwrap();
+        value
+    }
+
+    fn write(port: &mut PcieRootPort, offset: u16, value: u32) {
+        port.pci_cfg_write(offset, value).unwrap();
+    }
+
+    fn slot_status(port: &mut PcieRootPort) -> SlotStatus {
+        SlotStatus::from_bits_truncate((read(port, SLOT_CTL_STS) >> 16) as u16)
+    }
+
+    fn clear_slot_status(port: &mut PcieRootPort, status: SlotStatus) {
+        let control = read(port, SLOT_CTL_STS) & 0xffff;
+        write(port, SLOT_CTL_STS, control | (status.bits() as u32) << 16);
+    }
+
+    fn enable_hotplug_interrupts(port: &mut PcieRootPort) {
+        let control = SlotControl::HOT_PLUG_INTERRUPT_ENABLE
+            | SlotControl::PRESENCE_DETECT_CHANGED_ENABLE
+            | SlotControl::DATA_LINK_LAYER_STATE_CHANGED_ENABLE;
+        write(port, SLOT_CTL_STS, control.bits().into());
+    }
+
+    fn link_active(port: &mut PcieRootPort) -> bool {
+        LinkStatus::from_bits_truncate((read(port, LINK_CTL_STS) >> 16) as u16)
+            .contains(LinkStatus::DATA_LINK_LAYER_LINK_ACTIVE)
+    }
+
+    fn set_bus_numbers(port: &mut PcieRootPort, secondary: u8, subordinate: u8) {
+        write(
+            port,
+            HeaderType01::LATENCY_BUS_NUMBERS.0,
+            (secondary as u32) << 8 | (subordinate as u32) << 16,
+        );
+    }
+
+    fn forward_read(port: &mut PcieRootPort, bus: u8, device_function: u8) -> Option<u32> {
+        let mut value = 0;
+        port.pci_cfg_read_forward(bus, device_function, 0, &mut value)?
+            .unwrap();
+        Some(value)
+    }
+
+    #[test]
+    fn attach_detach() {
+        let (mut port, target) = new_port();
+        enable_hotplug_interrupts(&mut port);
+        assert!(slot_status(&mut port).is_empty());
+        assert!(!link_active(&mut port));
+        assert!(!target.is_high(VECTOR));
+
+        // Insertion latches both the presence and link changes.
+        port.attach("test", test_device()).unwrap();
+        assert!(port.is_occupied());
+        assert_eq!(
+            slot_status(&mut port),
+            SlotStatus::PRESENCE_DETECT_CHANGED
+                | SlotStatus::DATA_LINK_LAYER_STATE_CHANGED
+                | SlotStatus::PRESENCE_DETECT_STATE
+        );
+        assert!(link_active(&mut port));
+        assert!(target.is_high(VECTOR));
+        assert!(port.attach("other", test_device()).is_err());
+
+        // Acknowledging the events deasserts the interrupt.
+        clear_slot_status(
+            &mut port,
+            SlotStatus::PRESENCE_DETECT_CHANGED | SlotStatus::DATA_LINK_LAYER_STATE_CHANGED,
+        );
+        assert_eq!(slot_status(&mut port), SlotStatus::PRESENCE_DETECT_STATE);
+        assert!(!target.is_high(VECTOR));
+
+        // So does removal.
+        assert!(port.detach().is_some());
+        assert!(!port.is_occupied());
+        assert_eq!(
+            slot_status(&mut port),
+            SlotStatus::PRESENCE_DETECT_CHANGED | SlotStatus::DATA_LINK_LAYER_STATE_CHANGED
+        );
+        assert!(!link_active(&mut port));
+        assert!(target.is_high(VECTOR));
+        assert!(port.detach().is_none());
+    }
+
+    #[test]
+    fn slot_status_rw1c() {
+        let (mut port, target) = new_port();
+
+        // Events are latched even while interrupts are disabled.
+        port.attach("test", test_device()).unwrap();
+        assert!(!target.is_high(VECTOR));
+        let changed =
+            SlotStatus::PRESENCE_DETECT_CHANGED | SlotStatus::DATA_LINK_LAYER_STATE_CHANGED;
+        assert!(slot_status(&mut port).contains(changed));
+
+        // Writing zeroes, or to the read-only presence state, clears nothing.
+        clear_slot_status(&mut port, SlotStatus::empty());
+        clear_slot_status(&mut port, SlotStatus::PRESENCE_DETECT_STATE);
+        assert_eq!(
+            slot_status(&mut port),
+            changed | SlotStatus::PRESENCE_DETECT_STATE
+        );
+
+        // Enabling interrupts with events pending raises the interrupt.
+        enable_hotplug_interrupts(&mut port);
+        assert!(target.is_high(VECTOR));
+
+        // Each bit is cleared individually, and the interrupt stays asserted
+        // until both are.
+        clear_slot_status(&mut port, SlotStatus::PRESENCE_DETECT_CHANGED);
+        assert_eq!(
+            slot_status(&mut port),
+            SlotStatus::DATA_LINK_LAYER_STATE_CHANGED | SlotStatus::PRESENCE_DETECT_STATE
+        );
+        assert!(target.is_high(VECTOR));
+        clear_slot_status(&mut port, SlotStatus::DATA_LINK_LAYER_STATE_CHANGED);
+        assert_eq!(slot_status(&mut port), SlotStatus::PRESENCE_DETECT_STATE);
+        assert!(!target.is_high(VECTOR));
+    }
+
+    #[test]
+    fn forwarding() {
+        let (mut port, _target) = new_port();
+
+        // Nothing is forwarded until the guest assigns bus numbers.
+        assert_eq!(forward_read(&mut port, 1, 0), None);
+
+        set_bus_numbers(&mut port, 1, 2);
+        assert_eq!(forward_read(&mut port, 0, 0), None);
+        assert_eq!(forward_read(&mut port, 3, 0), None);
+
+        // An empty slot reads as all ones.
+        assert_eq!(forward_read(&mut port, 1, 0), Some(!0));
+
+        // The device is device 0 on the secondary bus. Other functions, and
+        // buses further down, are claimed but have nothing behind them.
+        port.attach("test", test_device()).unwrap();
+        assert_eq!(forward_read(&mut port, 1, 0), Some(DEVICE_VENDOR));
+        assert_eq!(forward_read(&mut port, 1, 1 << 3), Some(!0));
+        assert_eq!(forward_read(&mut port, 2, 0), Some(!0));
+        assert!(port.pci_cfg_write_forward(1, 0, 4, 0).is_some());
+        assert!(port.pci_cfg_write_forward(3, 0, 4, 0).is_none());
+    }
+
+    #[test]
+    fn type1_registers() {
+        let (mut port, _target) = new_port();
+
+        // A PCI-to-PCI bridge header with the PCI Express capability and the
+        // hot-plug interrupt on INTA#.
+        assert_eq!(read(&mut port, HeaderType01::BIST_HEADER.0) >> 16 & 0x7f, 1);
+        assert_eq!(
+            read(&mut port, HeaderType01::CLASS_REVISION.0) >> 8,
+            0x060400
+        );
+        assert_eq!(read(&mut port, HeaderType01::RESERVED_CAP_PTR.0), 0x40);
+        assert_eq!(
+            read(&mut port, HeaderType01::BRIDGE_CONTROL_INTERRUPT.0) >> 8 & 0xff,
+            1
+        );
+
+        // Bus numbers.
+        write(&mut port, HeaderType01::LATENCY_BUS_NUMBERS.0, 0xff03_0201);
+        assert_eq!(
+            read(&mut port, HeaderType01::LATENCY_BUS_NUMBERS.0),
+            0x0003_0201
+        );
+        assert_eq!(port.cfg_space.assigned_bus_range(), 2..=3);
+
+        // The memory window is 1MB granular.
+        write(&mut port, HeaderType01::MEMORY_RANGE.0, 0xfedc_ba98);
+        assert_eq!(read(&mut port, HeaderType01::MEMORY_RANGE.0), 0xfed0_ba90);
+
+        // There is no I/O or prefetchable memory window.
+        for register in [
+            HeaderType01::SEC_STATUS_IO_RANGE,
+            HeaderType01::PREFETCH_MEMORY_RANGE,
+            HeaderType01::PREFETCH_BASE_UPPER,
+            HeaderType01::PREFETCH_LIMIT_UPPER,
+            HeaderType01::IO_RANGE_UPPER,
+        ] {
+            write(&mut port, register.0, !0);
+            assert_eq!(read(&mut port, register.0), 0);
+        }
+    }
+}
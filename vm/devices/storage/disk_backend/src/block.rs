@@ -0,0 +1,121 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers for disk formats that map the disk in fixed-size, power-of-two
+//! blocks (or clusters), such as VHD, VHDX, and QCOW2.
+
+use crate::DiskError;
+use std::ops::Range;
+
+/// The part of a disk IO that falls within a single block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChunk {
+    /// The index of the block.
+    pub block: u64,
+    /// The byte offset of the chunk within the block.
+    pub block_offset: u64,
+    /// The byte range of the chunk within the IO buffer.
+    pub range: Range<usize>,
+}
+
+/// Splits the byte range `offset..offset + len` into per-block chunks, for
+/// blocks of `1 << block_bits` bytes.
+pub fn chunks(offset: u64, len: usize, block_bits: u32) -> impl Iterator<Item = BlockChunk> {
+    let block_size = 1u64 << block_bits;
+    let end = offset + len as u64;
+    let mut pos = offset;
+    std::iter::from_fn(move || {
+        if pos >= end {
+            return None;
+        }
+        let block_offset = pos & (block_size - 1);
+        let n = (block_size - block_offset).min(end - pos);
+        let start = (pos - offset) as usize;
+        let chunk = BlockChunk {
+            block: pos >> block_bits,
+            block_offset,
+            range: start..start + n as usize,
+        };
+        pos += n;
+        Some(chunk)
+    })
+}
+
+/// Validates that the byte range `offset..offset + len` is within a disk of
+/// `disk_size` bytes.
+pub fn check_range(offset: u64, len: usize, disk_size: u64) -> Result<(), DiskError> {
+    if offset
+        .checked_add(len as u64)
+        .is_none_or(|end| end > disk_size)
+    {
+        return Err(DiskError::IllegalBlock);
+    }
+    Ok(())
+}
+
+/// Appends the buffer range `range` to `ranges`, merging it with the last
+/// range if they are adjacent.
+pub fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_range;
+    use super::chunks;
+    use super::push_range;
+    use super::BlockChunk;
+    use crate::DiskError;
+
+    #[test]
+    fn split_chunks() {
+        assert_eq!(chunks(0, 0, 12).count(), 0);
+        assert_eq!(
+            chunks(4000, 5000, 12).collect::<Vec<_>>(),
+            [
+                BlockChunk {
+                    block: 0,
+                    block_offset: 4000,
+                    range: 0..96,
+                },
+                BlockChunk {
+                    block: 1,
+                    block_offset: 0,
+                    range: 96..4192,
+                },
+                BlockChunk {
+                    block: 2,
+                    block_offset: 0,
+                    range: 4192..5000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn range_check() {
+        check_range(0, 4096, 4096).unwrap();
+        check_range(4096, 0, 4096).unwrap();
+        assert!(matches!(
+            check_range(512, 4096, 4096),
+            Err(DiskError::IllegalBlock)
+        ));
+        assert!(matches!(
+            check_range(u64::MAX, 1, 4096),
+            Err(DiskError::IllegalBlock)
+        ));
+    }
+
+    #[test]
+    fn merge_ranges() {
+        let mut ranges = Vec::new();
+        push_range(&mut ranges, 0..512);
+        push_range(&mut ranges, 512..1024);
+        push_range(&mut ranges, 2048..4096);
+        push_range(&mut ranges, 4096..4608);
+        assert_eq!(ranges, [0..1024, 2048..4608]);
+    }
+}
@@ -13,9 +13,12 @@
 
 #![forbid(unsafe_code)]
 
+pub mod block;
 pub mod pr;
 pub mod resolve;
 pub mod sync_wrapper;
+#[cfg(any(feature = "test_utilities", test))]
+pub mod test_utilities;
 pub mod zerodisk;
 
 use guestmem::AccessError;
@@ -382,4 +385,9 @@ fn unmap(
     ) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }>;
 
     fn optimal_unmap_sectors(&self) -> u32;
+
+    /// Returns whether unmapped sectors are guaranteed to read back as zero.
+    fn unmap_reads_zero(&self) -> bool {
+        false
+    }
 }
@@ -7,6 +7,7 @@
 use std::sync::Arc;
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::CanResolveTo;
+use vmcore::vm_task::VmTaskDriverSource;
 
 impl CanResolveTo<ResolvedSimpleDisk> for DiskHandleKind {
     type Input<'a> = ResolveDiskParameters<'a>;
@@ -17,11 +18,8 @@ impl CanResolveTo<ResolvedSimpleDisk> for DiskHandleKind {
 pub struct ResolveDiskParameters<'a> {
     /// Whether the disk is being opened for read-only use.
     pub read_only: bool,
-    #[doc(hidden)]
-    // Workaround for async_trait not working well with GAT input parameters
-    // with missing lifetimes. Remove once we stop using async_trait for async
-    // resolvers.
-    pub _async_trait_workaround: &'a (),
+    /// The driver source for any tasks or timers the disk needs.
+    pub driver_source: &'a VmTaskDriverSource,
 }
 
 /// A resolved [`SimpleDisk`].
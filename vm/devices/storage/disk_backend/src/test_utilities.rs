@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers for testing disk backends.
+//!
+//! These address the disk in 512-byte sectors, so they are only suitable for
+//! disks with a 512-byte logical sector size.
+
+use crate::SimpleDisk;
+use guestmem::GuestMemory;
+use scsi_buffers::OwnedRequestBuffers;
+
+/// The sector size assumed by these helpers.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Returns `count` sectors of test data for the disk starting at `sector`.
+///
+/// The data differs per sector and per `seed`, so that misplaced or stale
+/// sectors are detected.
+pub fn pattern(sector: u64, count: usize, seed: u8) -> Vec<u8> {
+    (0..count * SECTOR_SIZE)
+        .map(|i| {
+            let s = sector + (i / SECTOR_SIZE) as u64;
+            (s.wrapping_mul(0x9e3779b97f4a7c15) >> 56) as u8 ^ i as u8 ^ seed
+        })
+        .collect()
+}
+
+/// Reads `count` sectors from `disk` starting at `sector`, panicking on
+/// failure.
+pub async fn read(disk: &dyn SimpleDisk, sector: u64, count: usize) -> Vec<u8> {
+    let mem = GuestMemory::allocate(count * SECTOR_SIZE);
+    disk.read_vectored(
+        &OwnedRequestBuffers::linear(0, count * SECTOR_SIZE, true).buffer(&mem),
+        sector,
+    )
+    .await
+    .unwrap();
+    let mut buf = vec![0; count * SECTOR_SIZE];
+    mem.read_at(0, &mut buf).unwrap();
+    buf
+}
+
+/// Writes `data` to `disk` starting at `sector`, panicking on failure.
+pub async fn write(disk: &dyn SimpleDisk, sector: u64, data: &[u8]) {
+    write_with_fua(disk, sector, data, false).await
+}
+
+/// Writes `data` to `disk` starting at `sector` with force unit access,
+/// panicking on failure.
+pub async fn write_fua(disk: &dyn SimpleDisk, sector: u64, data: &[u8]) {
+    write_with_fua(disk, sector, data, true).await
+}
+
+async fn write_with_fua(disk: &dyn SimpleDisk, sector: u64, data: &[u8], fua: bool) {
+    let mem = GuestMemory::allocate(data.len());
+    mem.write_at(0, data).unwrap();
+    disk.write_vectored(
+        &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+        sector,
+        fua,
+    )
+    .await
+    .unwrap();
+}
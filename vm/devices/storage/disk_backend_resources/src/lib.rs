@@ -39,6 +39,22 @@ impl ResourceId<DiskHandleKind> for RamDiffDiskHandle {
     const ID: &'static str = "ramdiff";
 }
 
+/// File-backed diff disk handle.
+///
+/// Writes are stored in the file, and reads of unwritten sectors go to the
+/// lower disk. An empty file is initialized as a new diff disk.
+#[derive(MeshPayload)]
+pub struct FileDiffDiskHandle {
+    /// The diff disk file.
+    pub file: std::fs::File,
+    /// The lower disk resource.
+    pub lower: Resource<DiskHandleKind>,
+}
+
+impl ResourceId<DiskHandleKind> for FileDiffDiskHandle {
+    const ID: &'static str = "filediff";
+}
+
 /// File-backed disk handle.
 #[derive(MeshPayload)]
 pub struct FileDiskHandle(pub std::fs::File);
@@ -63,6 +79,82 @@ impl ResourceId<DiskHandleKind> for FixedVhd1DiskHandle {
     const ID: &'static str = "fixed_vhd1";
 }
 
+/// Disk handle for a dynamic or differencing VHD1 disk.
+#[derive(MeshPayload)]
+pub struct DynamicVhd1DiskHandle {
+    /// The VHD file.
+    pub file: std::fs::File,
+    /// The parent disk, for differencing disks.
+    pub parent: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for DynamicVhd1DiskHandle {
+    const ID: &'static str = "dynamic_vhd1";
+}
+
+/// Disk handle for a QCOW2 image.
+#[derive(MeshPayload)]
+pub struct Qcow2DiskHandle {
+    /// The image file.
+    pub file: std::fs::File,
+    /// The backing disk, for images that have a backing file.
+    pub backing: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for Qcow2DiskHandle {
+    const ID: &'static str = "qcow2";
+}
+
+/// Disk handle for a VHDX file.
+#[derive(MeshPayload)]
+pub struct VhdxDiskHandle {
+    /// The VHDX file.
+    pub file: std::fs::File,
+    /// The parent disk, for differencing disks.
+    pub parent: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskHandleKind> for VhdxDiskHandle {
+    const ID: &'static str = "vhdx";
+}
+
+/// Disk handle for a disk that limits the IO rate of an inner disk.
+#[derive(MeshPayload)]
+pub struct ThrottleDiskHandle {
+    /// The inner disk resource.
+    pub disk: Resource<DiskHandleKind>,
+    /// The limits to enforce.
+    pub limits: ThrottleLimits,
+}
+
+impl ResourceId<DiskHandleKind> for ThrottleDiskHandle {
+    const ID: &'static str = "throttle";
+}
+
+/// IO rate limits for [`ThrottleDiskHandle`]. Limits that are not set are not
+/// enforced.
+#[derive(MeshPayload, Debug, Clone, Default)]
+pub struct ThrottleLimits {
+    /// Read operations per second.
+    pub read_iops: Option<RateLimit>,
+    /// Write operations per second.
+    pub write_iops: Option<RateLimit>,
+    /// Read bytes per second.
+    pub read_bytes: Option<RateLimit>,
+    /// Write bytes per second.
+    pub write_bytes: Option<RateLimit>,
+}
+
+/// A token bucket rate limit.
+#[derive(MeshPayload, Debug, Copy, Clone)]
+pub struct RateLimit {
+    /// The sustained rate, in units per second.
+    pub rate: u64,
+    /// The number of units that can be consumed at once after the limit has
+    /// been idle.
+    pub burst: u64,
+}
+
 /// Disk configuration for a striped disk.
 #[derive(MeshPayload)]
 pub struct StripedDiskHandle {
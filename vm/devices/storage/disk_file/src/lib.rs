@@ -1,9 +1,11 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-#![forbid(unsafe_code)]
+#![cfg_attr(not(target_os = "linux"), forbid(unsafe_code))]
 
-mod readwriteat;
+pub mod readwriteat;
+#[cfg(target_os = "linux")]
+mod uring;
 
 use self::readwriteat::ReadWriteAt;
 use blocking::unblock;
@@ -12,6 +14,8 @@
 use disk_backend::AsyncDisk;
 use disk_backend::DiskError;
 use disk_backend::SimpleDisk;
+#[cfg(target_os = "linux")]
+use disk_backend::Unmap;
 use disk_backend::ASYNC_DISK_STACK_SIZE;
 use disk_backend_resources::FileDiskHandle;
 use guestmem::MemoryRead;
@@ -46,6 +50,10 @@ pub struct FileDisk {
     file: Arc<fs::File>,
     metadata: Metadata,
     sector_shift: u32,
+    /// The io_uring IO path, if io_uring is available. Otherwise IOs block
+    /// threads from the `blocking` pool.
+    #[cfg(target_os = "linux")]
+    uring: Option<uring::UringFile>,
 }
 
 #[derive(Debug, Inspect)]
@@ -57,6 +65,11 @@ pub struct Metadata {
 }
 
 impl FileDisk {
+    /// Opens the disk.
+    ///
+    /// On Linux, IOs are issued with io_uring when it is available, and
+    /// aligned IOs bypass the page cache when the file system supports
+    /// `O_DIRECT`.
     pub fn open(file: fs::File, read_only: bool) -> Result<Self, std::io::Error> {
         let metadata = Metadata {
             disk_size: file.metadata()?.len(),
@@ -77,6 +90,8 @@ pub fn with_metadata(file: fs::File, metadata: Metadata) -> Self {
         assert!(metadata.sector_size >= 512);
         let sector_shift = metadata.sector_size.trailing_zeros();
         FileDisk {
+            #[cfg(target_os = "linux")]
+            uring: uring::UringFile::new(&file, metadata.read_only),
             file: Arc::new(file),
             metadata,
             sector_shift,
@@ -91,9 +106,13 @@ pub fn into_inner(self) -> fs::File {
 impl FileDisk {
     pub async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
         assert!(((sector << self.sector_shift) + buffers.len() as u64) <= self.metadata.disk_size);
+        let offset = sector << self.sector_shift;
+        #[cfg(target_os = "linux")]
+        if let Some(uring) = &self.uring {
+            return uring.read(&self.file, buffers, offset).await;
+        }
         let mut buffer = vec![0; buffers.len()];
         let file = self.file.clone();
-        let offset = sector << self.sector_shift;
         let buffer = unblock(move || -> Result<_, std::io::Error> {
             file.read_at(&mut buffer, offset)?;
             Ok(buffer)
@@ -108,13 +127,17 @@ pub async fn write(
         &self,
         buffers: &RequestBuffers<'_>,
         sector: u64,
-        _fua: bool,
+        fua: bool,
     ) -> Result<(), DiskError> {
         assert!(((sector << self.sector_shift) + buffers.len() as u64) <= self.metadata.disk_size);
+        let offset = sector << self.sector_shift;
+        #[cfg(target_os = "linux")]
+        if let Some(uring) = &self.uring {
+            return uring.write(&self.file, buffers, offset, fua).await;
+        }
         let mut buffer = vec![0; buffers.len()];
         let file = self.file.clone();
         buffers.reader().read(&mut buffer)?;
-        let offset = sector << self.sector_shift;
         unblock(move || file.write_at(&buffer, offset))
             .await
             .map_err(DiskError::Io)?;
@@ -122,6 +145,10 @@ pub async fn write(
     }
 
     pub async fn flush(&self) -> Result<(), DiskError> {
+        #[cfg(target_os = "linux")]
+        if let Some(uring) = &self.uring {
+            return uring.flush(&self.file).await;
+        }
         let file = self.file.clone();
         unblock(move || file.sync_all())
             .await
@@ -156,8 +183,17 @@ fn physical_sector_size(&self) -> u32 {
     }
 
     fn is_fua_respected(&self) -> bool {
+        // FUA writes are issued with RWF_DSYNC on the io_uring path.
+        #[cfg(target_os = "linux")]
+        return self.uring.is_some();
+        #[cfg(not(target_os = "linux"))]
         false
     }
+
+    #[cfg(target_os = "linux")]
+    fn unmap(&self) -> Option<&dyn Unmap> {
+        (!self.metadata.read_only && self.uring.as_ref()?.supports_unmap()).then_some(self)
+    }
 }
 
 impl AsyncDisk for FileDisk {
@@ -166,7 +202,7 @@ fn read_vectored<'a>(
         buffers: &'a RequestBuffers<'a>,
         sector: u64,
     ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
-        StackFuture::from(async move { self.read(buffers, sector).await })
+        StackFuture::from_or_box(async move { self.read(buffers, sector).await })
     }
 
     fn write_vectored<'a>(
@@ -175,10 +211,113 @@ fn write_vectored<'a>(
         sector: u64,
         fua: bool,
     ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
-        StackFuture::from(async move { self.write(buffers, sector, fua).await })
+        StackFuture::from_or_box(async move { self.write(buffers, sector, fua).await })
     }
 
     fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
-        StackFuture::from(self.flush())
+        StackFuture::from_or_box(self.flush())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Unmap for FileDisk {
+    fn unmap(
+        &self,
+        sector_offset: u64,
+        sector_count: u64,
+        _block_level_only: bool,
+    ) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(async move {
+            let offset = sector_offset << self.sector_shift;
+            let len = sector_count << self.sector_shift;
+            assert!(offset + len <= self.metadata.disk_size);
+            let uring = self.uring.as_ref().expect("unmap requires io_uring");
+            uring.unmap(&self.file, offset, len).await
+        })
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        self.metadata.physical_sector_size >> self.sector_shift
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileDisk;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::test_utilities::write_fua;
+    use disk_backend::test_utilities::SECTOR_SIZE;
+    use disk_backend::AsyncDisk;
+    use disk_backend::SimpleDisk;
+    use pal_async::async_test;
+
+    #[async_test]
+    async fn read_write() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(4 << 20).unwrap();
+        let disk = FileDisk::open(file, false).unwrap();
+
+        // Cover IOs that are split across bounce buffers and unaligned IOs.
+        let large = pattern(8, 2048, 1);
+        write(&disk, 8, &large).await;
+        let small = pattern(4001, 3, 2);
+        write_fua(&disk, 4001, &small).await;
+        disk.sync_cache().await.unwrap();
+
+        assert_eq!(read(&disk, 8, 2048).await, large);
+        assert_eq!(read(&disk, 4001, 3).await, small);
+        assert_eq!(read(&disk, 0, 8).await, vec![0; 8 * SECTOR_SIZE]);
+
+        let file = disk.into_inner();
+        let disk = FileDisk::open(file, true).unwrap();
+        assert_eq!(read(&disk, 8, 2048).await, large);
+        assert!(disk.unmap().is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_test]
+    async fn unmap() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(1 << 20).unwrap();
+        let disk = FileDisk::open(file, false).unwrap();
+        let Some(unmap) = disk.unmap() else {
+            println!("Test case skipped (no io_uring fallocate support)");
+            return;
+        };
+
+        let data = pattern(0, 128, 3);
+        write(&disk, 0, &data).await;
+        unmap.unmap(16, 64, false).await.unwrap();
+
+        let mut expected = data;
+        expected[16 * SECTOR_SIZE..80 * SECTOR_SIZE].fill(0);
+        assert_eq!(read(&disk, 0, 128).await, expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_test]
+    async fn drop_in_flight() {
+        use guestmem::GuestMemory;
+        use scsi_buffers::OwnedRequestBuffers;
+
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(8 << 20).unwrap();
+        let disk = FileDisk::open(file, false).unwrap();
+        let sectors = (8 << 20) / SECTOR_SIZE;
+        let data = pattern(0, sectors, 4);
+        write(&disk, 0, &data).await;
+
+        // Drop a read that uses every bounce buffer while it is in flight. The
+        // buffers must be returned to the pool once the IOs complete.
+        let mem = GuestMemory::allocate(data.len());
+        let buffers = OwnedRequestBuffers::linear(0, data.len(), true);
+        let buffers = buffers.buffer(&mem);
+        let mut io = Box::pin(disk.read_vectored(&buffers, 0));
+        assert!(futures::poll!(io.as_mut()).is_pending());
+        drop(io);
+
+        assert_eq!(read(&disk, 0, sectors).await, data);
     }
 }
@@ -4,6 +4,7 @@
 //! Helpers for doing IO at a given offset.
 
 use std::fs;
+use std::io;
 use std::io::Result;
 
 /// A unified extension trait for [`std::fs::File`] for reading/writing at a
@@ -37,3 +38,56 @@ fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         std::os::unix::fs::FileExt::read_at(self, buf, offset)
     }
 }
+
+/// Reads exactly `buf.len()` bytes at `offset`, failing if the end of the file
+/// is reached first.
+pub fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes at `offset`, zero filling anything past the end of
+/// the file.
+pub fn read_at_or_zero(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+    while !buf.is_empty() {
+        match file.read_at(buf, offset) {
+            Ok(0) => {
+                buf.fill(0);
+                break;
+            }
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Writes all of `buf` at `offset`.
+pub fn write_all_at(file: &fs::File, mut buf: &[u8], mut offset: u64) -> Result<()> {
+    while !buf.is_empty() {
+        match file.write_at(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,439 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An io_uring IO path for file disks.
+//!
+//! All file disks in the process share one ring, run by a dedicated thread,
+//! and one pool of bounce buffers, which are registered with the ring when the
+//! memory lock limit allows it. Both are created when the first disk is
+//! opened and live until the process exits. The pool is `SLOT_COUNT *
+//! SLOT_SIZE` (8 MiB) of memory, which is locked when registered, regardless
+//! of the number of disks.
+//!
+//! IOs are split across the bounce buffers, so the size of the pool bounds
+//! the combined queue depth of the disks, but IOs do not tie up a thread each
+//! as the blocking path does.
+//!
+//! Aligned IOs are issued to a handle opened with `O_DIRECT`, when the file
+//! system supports it, so that they bypass the page cache.
+
+// UNSAFETY: Issuing IOs and registering IO buffers with io_uring.
+#![allow(unsafe_code)]
+
+use disk_backend::DiskError;
+use event_listener::Event;
+use futures::future::join_all;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use inspect::Inspect;
+use io_uring::opcode;
+use io_uring::squeue;
+use io_uring::types;
+use io_uring::types::RwFlags;
+use pal_uring::Io;
+use pal_uring::IoInitiator;
+use pal_uring::IoUringPool;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use std::cell::UnsafeCell;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+/// The size of each bounce buffer. Larger IOs are split across buffers.
+const SLOT_SIZE: usize = 128 * 1024;
+/// The number of bounce buffers, which bounds the number of IOs in flight
+/// across all files.
+const SLOT_COUNT: usize = 64;
+/// The size of the ring, which leaves room for flushes and unmaps beyond the
+/// IOs using bounce buffers.
+const RING_SIZE: u32 = SLOT_COUNT as u32 * 2;
+/// The offset and length alignment required to use a file opened with
+/// `O_DIRECT`.
+const DIRECT_IO_ALIGNMENT: u64 = 4096;
+
+// Documented in Linux manual page: https://man7.org/linux/man-pages/man2/readv.2.html
+// It's only defined in linux_gnu but not in linux_musl. So we have to define it.
+const RWF_DSYNC: RwFlags = 0x00000002;
+
+#[repr(C, align(4096))]
+struct Page(UnsafeCell<[u8; 4096]>);
+
+/// The pool of bounce buffers.
+struct BufferPool {
+    free_slots: Mutex<Vec<usize>>,
+    slot_freed: Event,
+    /// The bounce buffer memory, `SLOT_COUNT * SLOT_SIZE` bytes.
+    buffers: Box<[Page]>,
+}
+
+// SAFETY: the buffer memory is only accessed through a `Slot`, which has
+// exclusive access to its part of the buffer.
+unsafe impl Sync for BufferPool {}
+
+impl BufferPool {
+    fn new() -> Self {
+        Self {
+            free_slots: Mutex::new((0..SLOT_COUNT).collect()),
+            slot_freed: Event::new(),
+            buffers: (0..SLOT_COUNT * SLOT_SIZE / size_of::<Page>())
+                .map(|_| Page(UnsafeCell::new([0; 4096])))
+                .collect(),
+        }
+    }
+
+    /// Waits for a bounce buffer to become available.
+    async fn acquire(self: &Arc<Self>) -> Slot {
+        loop {
+            let listener = self.slot_freed.listen();
+            if let Some(index) = self.free_slots.lock().pop() {
+                break Slot {
+                    pool: self.clone(),
+                    index,
+                };
+            }
+            listener.await;
+        }
+    }
+}
+
+/// An acquired bounce buffer.
+///
+/// The slot is the IO memory of the IOs that use it, so if an IO is dropped
+/// while in flight, the slot is only returned to the pool once the IO
+/// completes.
+struct Slot {
+    pool: Arc<BufferPool>,
+    index: usize,
+}
+
+impl Slot {
+    fn ptr(&self) -> *mut u8 {
+        // The memory is in `UnsafeCell`s, so it can be written through a
+        // pointer derived from a shared reference.
+        self.pool
+            .buffers
+            .as_ptr()
+            .cast::<u8>()
+            .cast_mut()
+            .wrapping_add(self.index * SLOT_SIZE)
+    }
+
+    fn buf(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= SLOT_SIZE);
+        // SAFETY: this slot has exclusive access to its part of the buffer
+        // memory, and no IO is in flight since the IO owns the slot until it
+        // completes.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr(), len) }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.pool.free_slots.lock().push(self.index);
+        self.pool.slot_freed.notify(1);
+    }
+}
+
+/// The ring and bounce buffers shared by all file disks.
+#[derive(Inspect)]
+struct SharedRing {
+    #[inspect(skip)]
+    initiator: IoInitiator,
+    #[inspect(skip)]
+    pool: Arc<BufferPool>,
+    /// Whether the bounce buffers are registered with the ring as fixed
+    /// buffer 0.
+    registered: bool,
+    /// Whether the ring supports fallocate, for unmap.
+    fallocate: bool,
+}
+
+impl SharedRing {
+    /// Returns the shared ring, starting it if this is the first call, or
+    /// `None` if io_uring is not available.
+    fn get() -> Option<&'static Self> {
+        static RING: OnceLock<Option<SharedRing>> = OnceLock::new();
+        RING.get_or_init(|| {
+            Self::new()
+                .inspect_err(|err| {
+                    tracing::info!(
+                        error = err as &dyn std::error::Error,
+                        "io_uring unavailable, file disks will use blocking IO"
+                    )
+                })
+                .ok()
+        })
+        .as_ref()
+    }
+
+    fn new() -> io::Result<Self> {
+        let pool = Arc::new(BufferPool::new());
+        let ring = IoUringPool::new("disk_file", RING_SIZE)?;
+        let initiator = ring.client().initiator().clone();
+        for code in [opcode::Read::CODE, opcode::Write::CODE, opcode::Fsync::CODE] {
+            if !initiator.probe(code) {
+                return Err(io::ErrorKind::Unsupported.into());
+            }
+        }
+
+        let registered = initiator.probe(opcode::ReadFixed::CODE)
+            && initiator.probe(opcode::WriteFixed::CODE)
+            && {
+                let iovec = libc::iovec {
+                    iov_base: pool.buffers.as_ptr().cast_mut().cast(),
+                    iov_len: SLOT_COUNT * SLOT_SIZE,
+                };
+                // SAFETY: the ring thread keeps a reference to the pool until
+                // the ring stops running, and slots keep it alive while their
+                // IOs are in flight.
+                unsafe { initiator.register_buffers(&[iovec]) }
+                    .inspect_err(|err| {
+                        tracing::info!(
+                            error = err as &dyn std::error::Error,
+                            "failed to register io_uring buffers, using unregistered buffers"
+                        )
+                    })
+                    .is_ok()
+            };
+
+        let fallocate = initiator.probe(opcode::Fallocate::CODE);
+
+        let ring_pool = pool.clone();
+        std::thread::Builder::new()
+            .name("disk_file".into())
+            .spawn(move || {
+                ring.run();
+                drop(ring_pool);
+            })?;
+
+        Ok(Self {
+            initiator,
+            pool,
+            registered,
+            fallocate,
+        })
+    }
+}
+
+/// Issues IOs for a file disk through the shared ring.
+#[derive(Inspect)]
+pub struct UringFile {
+    #[inspect(flatten)]
+    ring: &'static SharedRing,
+    /// A handle to the file opened with `O_DIRECT`, for aligned IOs, if the
+    /// file itself was not.
+    #[inspect(skip)]
+    direct: Option<fs::File>,
+    /// Whether aligned IOs bypass the page cache.
+    direct_io: bool,
+    /// A handle to the file without `O_DIRECT`, for IOs that are not aligned
+    /// for direct IO, if the file itself was opened with `O_DIRECT`.
+    #[inspect(skip)]
+    buffered: Option<fs::File>,
+}
+
+impl Debug for UringFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringFile")
+            .field("registered", &self.ring.registered)
+            .field("fallocate", &self.ring.fallocate)
+            .field("direct_io", &self.direct_io)
+            .finish()
+    }
+}
+
+/// Reopens `file` through procfs with the given status `flags`.
+fn reopen(file: &fs::File, read_only: bool, flags: i32) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .custom_flags(flags)
+        .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+impl UringFile {
+    /// Returns an IO path for `file`, or `None` if io_uring is not available.
+    pub fn new(file: &fs::File, read_only: bool) -> Option<Self> {
+        let ring = SharedRing::get()?;
+
+        // SAFETY: the fd is valid for the lifetime of `file`, and F_GETFL has
+        // no side effects.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        let (direct, buffered) = if flags >= 0 && flags & libc::O_DIRECT != 0 {
+            let buffered = reopen(file, read_only, 0)
+                .inspect_err(|err| {
+                    tracing::warn!(
+                        error = err as &dyn std::error::Error,
+                        "failed to reopen file for buffered IO, unaligned IOs will fail"
+                    )
+                })
+                .ok();
+            (None, buffered)
+        } else {
+            // Not all file systems support direct IO (e.g. tmpfs), in which
+            // case all IOs go through the page cache.
+            let direct = reopen(file, read_only, libc::O_DIRECT)
+                .inspect_err(|err| {
+                    tracing::debug!(
+                        error = err as &dyn std::error::Error,
+                        "direct io unavailable, using buffered IO"
+                    )
+                })
+                .ok();
+            (direct, None)
+        };
+        let direct_io = direct.is_some() || buffered.is_some();
+
+        Some(Self {
+            ring,
+            direct,
+            direct_io,
+            buffered,
+        })
+    }
+
+    pub fn supports_unmap(&self) -> bool {
+        self.ring.fallocate
+    }
+
+    fn fd_for(&self, file: &fs::File, offset: u64, len: usize) -> RawFd {
+        let handle = if (offset | len as u64) % DIRECT_IO_ALIGNMENT == 0 {
+            self.direct.as_ref()
+        } else {
+            self.buffered.as_ref()
+        };
+        handle.unwrap_or(file).as_raw_fd()
+    }
+
+    /// Reads or writes the first `len` bytes of `slot` at `offset` in `fd`,
+    /// returning the slot once the IO completes.
+    async fn io(
+        &self,
+        mut slot: Slot,
+        fd: RawFd,
+        offset: u64,
+        len: usize,
+        write: bool,
+        flags: RwFlags,
+    ) -> (io::Result<()>, Slot) {
+        assert!(len <= SLOT_SIZE);
+        let fd = types::Fd(fd);
+        let ptr = slot.buf(len).as_mut_ptr();
+        let n = len as u32;
+        let sqe: squeue::Entry = match (write, self.ring.registered) {
+            (false, true) => opcode::ReadFixed::new(fd, ptr, n, 0).offset(offset).build(),
+            (false, false) => opcode::Read::new(fd, ptr, n).offset(offset).build(),
+            (true, true) => opcode::WriteFixed::new(fd, ptr, n, 0)
+                .offset(offset)
+                .rw_flags(flags)
+                .build(),
+            (true, false) => opcode::Write::new(fd, ptr, n)
+                .offset(offset)
+                .rw_flags(flags)
+                .build(),
+        };
+        // SAFETY: the IO only references the slot's part of the buffer
+        // memory, and the slot is owned by the IO until it completes, even
+        // if the IO is dropped.
+        let mut io = unsafe { Io::new(&self.ring.initiator, sqe, slot) };
+        let r = (&mut io).await;
+        let slot = io.into_mem();
+        let r = r.and_then(|n| {
+            if n as usize != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(())
+        });
+        (r, slot)
+    }
+
+    pub async fn read(
+        &self,
+        file: &fs::File,
+        buffers: &RequestBuffers<'_>,
+        offset: u64,
+    ) -> Result<(), DiskError> {
+        // Wait for every part of the IO, even after a failure, so that no
+        // part is still in flight when the IO completes.
+        let results = join_all((0..buffers.len()).step_by(SLOT_SIZE).map(|pos| async move {
+            let len = (buffers.len() - pos).min(SLOT_SIZE);
+            let offset = offset + pos as u64;
+            let slot = self.ring.pool.acquire().await;
+            let (r, mut slot) = self
+                .io(slot, self.fd_for(file, offset, len), offset, len, false, 0)
+                .await;
+            r.map_err(DiskError::Io)?;
+            buffers.subrange(pos, len).writer().write(slot.buf(len))?;
+            Ok(())
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+
+    pub async fn write(
+        &self,
+        file: &fs::File,
+        buffers: &RequestBuffers<'_>,
+        offset: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        let flags = if fua { RWF_DSYNC } else { 0 };
+        let results = join_all((0..buffers.len()).step_by(SLOT_SIZE).map(|pos| async move {
+            let len = (buffers.len() - pos).min(SLOT_SIZE);
+            let offset = offset + pos as u64;
+            let mut slot = self.ring.pool.acquire().await;
+            buffers.subrange(pos, len).reader().read(slot.buf(len))?;
+            let (r, _slot) = self
+                .io(
+                    slot,
+                    self.fd_for(file, offset, len),
+                    offset,
+                    len,
+                    true,
+                    flags,
+                )
+                .await;
+            r.map_err(DiskError::Io)
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+
+    pub async fn flush(&self, file: &fs::File) -> Result<(), DiskError> {
+        // SAFETY: no data buffers.
+        let (r, ()) = unsafe {
+            self.ring.initiator.issue_io((), |_| {
+                opcode::Fsync::new(types::Fd(file.as_raw_fd())).build()
+            })
+        }
+        .await;
+        r.map_err(DiskError::Io)?;
+        Ok(())
+    }
+
+    /// Deallocates the byte range, which then reads as zero.
+    pub async fn unmap(&self, file: &fs::File, offset: u64, len: u64) -> Result<(), DiskError> {
+        assert!(self.ring.fallocate);
+        // SAFETY: no data buffers.
+        let (r, ()) = unsafe {
+            self.ring.initiator.issue_io((), |_| {
+                opcode::Fallocate::new(types::Fd(file.as_raw_fd()), len)
+                    .offset(offset)
+                    .mode(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE)
+                    .build()
+            })
+        }
+        .await;
+        match r {
+            Ok(_) => Ok(()),
+            // Unmap is advisory, so ignore file systems that cannot punch
+            // holes.
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            Err(err) => Err(DiskError::Io(err)),
+        }
+    }
+}
@@ -0,0 +1,646 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A file-backed copy-on-write diff disk.
+//!
+//! Writes are stored in a sparse overlay file, and reads of blocks that have
+//! not been written go to the lower disk, which is never modified. The overlay
+//! persists across restarts, and its contents can later be committed into the
+//! lower disk or discarded.
+//!
+//! The overlay file consists of a header, a map with the file offset of each
+//! allocated block (or zero), and the allocated blocks. A block's data is
+//! flushed before its map entry is written, so a torn write can only leak an
+//! unreferenced block.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use blocking::unblock;
+use disk_backend::block;
+use disk_backend::block::BlockChunk;
+use disk_backend::AsyncDisk;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_file::readwriteat::read_exact_at;
+use disk_file::readwriteat::write_all_at;
+use guestmem::GuestMemory;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::OwnedRequestBuffers;
+use scsi_buffers::RequestBuffers;
+use stackfuture::StackFuture;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+use zerocopy::LE;
+use zerocopy::U32;
+use zerocopy::U64;
+
+/// The size of the blocks that are copied up from the lower disk.
+const BLOCK_SIZE: u32 = 1 << 20;
+/// The offset of the block map in the file.
+const MAP_OFFSET: u64 = 4096;
+/// Upper bound on the map size, to avoid huge allocations for corrupt files.
+const MAX_MAP_ENTRIES: u64 = 1 << 24;
+
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+struct Header {
+    signature: U64<LE>,
+    version: U32<LE>,
+    sector_size: U32<LE>,
+    sector_count: U64<LE>,
+    block_size: U32<LE>,
+    reserved: U32<LE>,
+    map_offset: U64<LE>,
+    /// The disk ID of the lower disk when the overlay was created, or zero if
+    /// it has none.
+    lower_disk_id: [u8; 16],
+}
+
+impl Header {
+    const SIGNATURE: u64 = u64::from_le_bytes(*b"VMDIFF\0\0");
+    const VERSION: u32 = 1;
+}
+
+/// An error encountered while opening or creating a diff disk.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("not a diff disk file")]
+    InvalidSignature,
+    #[error("unsupported diff disk version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid sector size: {0}")]
+    InvalidSectorSize(u32),
+    #[error("invalid block size: {0:#x}")]
+    InvalidBlockSize(u32),
+    #[error("invalid disk size: {0} sectors")]
+    InvalidDiskSize(u64),
+    #[error("invalid block map")]
+    InvalidMap,
+    #[error("diff disk was created over a different lower disk")]
+    LowerMismatch,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Geometry {
+    sector_shift: u32,
+    disk_size: u64,
+    block_bits: u32,
+    block_size: u64,
+    map_offset: u64,
+    /// The offset of the first block, after the map.
+    data_offset: u64,
+}
+
+impl Geometry {
+    fn new(header: &Header) -> Result<Self, OpenError> {
+        let sector_size = header.sector_size.get();
+        let sector_count = header.sector_count.get();
+        let block_size = header.block_size.get();
+        let map_offset = header.map_offset.get();
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(OpenError::InvalidSectorSize(sector_size));
+        }
+        if !block_size.is_power_of_two() || block_size < sector_size {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        let sector_shift = sector_size.trailing_zeros();
+        let disk_size = sector_count
+            .checked_shl(sector_shift)
+            .filter(|&size| size != 0 && size >> sector_shift == sector_count)
+            .ok_or(OpenError::InvalidDiskSize(sector_count))?;
+        let blocks = disk_size.div_ceil(block_size.into());
+        if blocks > MAX_MAP_ENTRIES {
+            return Err(OpenError::InvalidDiskSize(sector_count));
+        }
+        if map_offset < size_of::<Header>() as u64 || map_offset % 8 != 0 {
+            return Err(OpenError::InvalidMap);
+        }
+        Ok(Self {
+            sector_shift,
+            disk_size,
+            block_bits: block_size.trailing_zeros(),
+            block_size: block_size.into(),
+            map_offset,
+            data_offset: (map_offset + blocks * 8).next_multiple_of(block_size.into()),
+        })
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk_size.div_ceil(self.block_size)
+    }
+
+    /// The size of `block`, which is smaller than the block size for the last
+    /// block if the disk size is not block aligned.
+    fn block_len(&self, block: u64) -> usize {
+        (self.disk_size - (block << self.block_bits)).min(self.block_size) as usize
+    }
+
+    /// Splits the guest byte range `offset..offset + len` into per-block
+    /// chunks.
+    fn chunks(&self, offset: u64, len: usize) -> impl Iterator<Item = BlockChunk> {
+        block::chunks(offset, len, self.block_bits)
+    }
+}
+
+fn read_header(file: &File) -> Result<(Header, Geometry), OpenError> {
+    let mut header = Header::new_zeroed();
+    read_exact_at(file, header.as_bytes_mut(), 0)?;
+    if header.signature.get() != Header::SIGNATURE {
+        return Err(OpenError::InvalidSignature);
+    }
+    if header.version.get() != Header::VERSION {
+        return Err(OpenError::UnsupportedVersion(header.version.get()));
+    }
+    let geometry = Geometry::new(&header)?;
+    Ok((header, geometry))
+}
+
+/// Discards all the changes in the diff disk in `file`.
+pub fn discard(file: &File) -> Result<(), OpenError> {
+    let (_, geometry) = read_header(file)?;
+    let map_len = geometry.data_offset - geometry.map_offset;
+    // Zero the map before truncating so that a torn discard never leaves
+    // entries referring past the end of the file.
+    write_all_at(file, &vec![0; map_len as usize], geometry.map_offset)?;
+    file.sync_data()?;
+    file.set_len(geometry.data_offset)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// A disk that stores writes in an overlay file on top of a lower disk.
+pub struct FileDiffDisk {
+    inner: Arc<Inner>,
+    geometry: Geometry,
+    lower: Arc<dyn SimpleDisk>,
+    read_only: bool,
+    /// Lock used to serialize writes, which may allocate blocks.
+    write_lock: futures::lock::Mutex<()>,
+}
+
+struct Inner {
+    file: File,
+    geometry: Geometry,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The file offset of each block, or zero if the block reads from the
+    /// lower disk.
+    map: Vec<u64>,
+    /// The end of the file, where new blocks are allocated.
+    file_end: u64,
+}
+
+impl Inner {
+    /// Reads the allocated parts of the guest range from the file, returning
+    /// the data and the ranges of the buffer that must be read from the lower
+    /// disk.
+    fn read(&self, offset: u64, len: usize) -> io::Result<(Vec<u8>, Vec<Range<usize>>)> {
+        let mut data = vec![0; len];
+        let mut unallocated: Vec<Range<usize>> = Vec::new();
+        let reads = {
+            let state = self.state.lock();
+            let mut reads = Vec::new();
+            for chunk in self.geometry.chunks(offset, len) {
+                match state.map[chunk.block as usize] {
+                    0 => match unallocated.last_mut() {
+                        Some(last) if last.end == chunk.range.start => last.end = chunk.range.end,
+                        _ => unallocated.push(chunk.range),
+                    },
+                    host => reads.push((chunk.range, host + chunk.block_offset)),
+                }
+            }
+            reads
+        };
+        for (range, host) in reads {
+            read_exact_at(&self.file, &mut data[range], host)?;
+        }
+        Ok((data, unallocated))
+    }
+
+    fn lookup_range(&self, offset: u64, len: usize) -> Vec<u64> {
+        let state = self.state.lock();
+        self.geometry
+            .chunks(offset, len)
+            .map(|chunk| state.map[chunk.block as usize])
+            .collect()
+    }
+
+    /// Writes `data` at guest `offset`, given the current file offsets of the
+    /// blocks in the range and the lower disk contents of any partially
+    /// written, unallocated blocks.
+    ///
+    /// Must be called with the write lock held.
+    fn write(
+        &self,
+        offset: u64,
+        data: &[u8],
+        hosts: &[u64],
+        lower_blocks: &mut HashMap<u64, Vec<u8>>,
+        fua: bool,
+    ) -> io::Result<()> {
+        for (chunk, &host) in self.geometry.chunks(offset, data.len()).zip(hosts) {
+            let buf = &data[chunk.range.clone()];
+            if host != 0 {
+                write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                continue;
+            }
+            let host = {
+                let mut state = self.state.lock();
+                let host = state.file_end;
+                state.file_end += self.geometry.block_size;
+                host
+            };
+            match lower_blocks.remove(&chunk.block) {
+                Some(mut block) => {
+                    block[chunk.block_offset as usize..][..buf.len()].copy_from_slice(buf);
+                    write_all_at(&self.file, &block, host)?;
+                }
+                None => write_all_at(&self.file, buf, host)?,
+            }
+            // The data must be durable before the map references it.
+            self.file.sync_data()?;
+            write_all_at(
+                &self.file,
+                &host.to_le_bytes(),
+                self.geometry.map_offset + chunk.block * 8,
+            )?;
+            self.state.lock().map[chunk.block as usize] = host;
+        }
+        if fua {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl FileDiffDisk {
+    /// Initializes `file` as an empty diff disk for `lower`.
+    pub fn create(file: &File, lower: &dyn SimpleDisk) -> Result<(), OpenError> {
+        let header = Header {
+            signature: Header::SIGNATURE.into(),
+            version: Header::VERSION.into(),
+            sector_size: lower.sector_size().into(),
+            sector_count: lower.sector_count().into(),
+            block_size: BLOCK_SIZE.into(),
+            reserved: 0.into(),
+            map_offset: MAP_OFFSET.into(),
+            lower_disk_id: lower.disk_id().unwrap_or_default(),
+        };
+        let geometry = Geometry::new(&header)?;
+        file.set_len(0)?;
+        write_all_at(file, header.as_bytes(), 0)?;
+        file.set_len(geometry.data_offset)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Opens the diff disk in `file` on top of `lower`, which must have the
+    /// same geometry as when the diff disk was created.
+    pub fn open(
+        file: File,
+        lower: Arc<dyn SimpleDisk>,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let (header, geometry) = read_header(&file)?;
+        if lower.sector_size() != header.sector_size.get()
+            || lower.sector_count() != header.sector_count.get()
+            || (header.lower_disk_id != [0; 16]
+                && lower.disk_id().is_some_and(|id| id != header.lower_disk_id))
+        {
+            return Err(OpenError::LowerMismatch);
+        }
+
+        let file_len = file.metadata()?.len();
+        let mut map = vec![0u64; geometry.block_count() as usize];
+        read_exact_at(&file, map.as_bytes_mut(), geometry.map_offset)?;
+        for host in &mut map {
+            *host = u64::from_le(*host);
+            if *host != 0
+                && (*host < geometry.data_offset
+                    || *host % geometry.block_size != 0
+                    || *host >= file_len)
+            {
+                return Err(OpenError::InvalidMap);
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                file,
+                geometry,
+                state: Mutex::new(State {
+                    map,
+                    file_end: file_len.next_multiple_of(geometry.block_size),
+                }),
+            }),
+            geometry,
+            lower,
+            read_only,
+            write_lock: Default::default(),
+        })
+    }
+
+    /// Writes all the changes in the diff disk to the lower disk, which must
+    /// be writable, and then discards them.
+    pub async fn commit(&self) -> Result<(), DiskError> {
+        if self.read_only || self.lower.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let _write_lock = self.write_lock.lock().await;
+        let blocks = self
+            .inner
+            .state
+            .lock()
+            .map
+            .iter()
+            .enumerate()
+            .filter(|&(_, &host)| host != 0)
+            .map(|(block, &host)| (block as u64, host))
+            .collect::<Vec<_>>();
+
+        let sector_shift = self.geometry.sector_shift;
+        for (block, host) in blocks {
+            let len = self.geometry.block_len(block);
+            let inner = self.inner.clone();
+            let data = unblock(move || {
+                let mut data = vec![0; len];
+                read_exact_at(&inner.file, &mut data, host)?;
+                Ok(data)
+            })
+            .await
+            .map_err(DiskError::Io)?;
+            let mem = GuestMemory::allocate(len);
+            let buffers = OwnedRequestBuffers::linear(0, len, true);
+            let buffers = buffers.buffer(&mem);
+            buffers.writer().write(&data)?;
+            self.lower
+                .write_vectored(
+                    &buffers,
+                    (block << self.geometry.block_bits) >> sector_shift,
+                    false,
+                )
+                .await?;
+        }
+        self.lower.sync_cache().await?;
+
+        let inner = self.inner.clone();
+        unblock(move || {
+            discard(&inner.file).map_err(|err| match err {
+                OpenError::Io(err) => err,
+                err => io::Error::new(io::ErrorKind::InvalidData, err),
+            })?;
+            let mut state = inner.state.lock();
+            state.map.fill(0);
+            state.file_end = inner.geometry.data_offset;
+            Ok(())
+        })
+        .await
+        .map_err(DiskError::Io)
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), DiskError> {
+        block::check_range(offset, len, self.geometry.disk_size)
+    }
+
+    async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        let sector_shift = self.geometry.sector_shift;
+        let offset = sector << sector_shift;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let inner = self.inner.clone();
+        let (data, unallocated) = unblock(move || inner.read(offset, len))
+            .await
+            .map_err(DiskError::Io)?;
+        buffers.writer().write(&data)?;
+        for range in unallocated {
+            let start = offset + range.start as u64;
+            self.lower
+                .read_vectored(
+                    &buffers.subrange(range.start, range.len()),
+                    start >> sector_shift,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads a block from the lower disk.
+    async fn read_lower_block(&self, block: u64) -> Result<Vec<u8>, DiskError> {
+        let len = self.geometry.block_len(block);
+        let mem = GuestMemory::allocate(len);
+        let buffers = OwnedRequestBuffers::linear(0, len, true);
+        let buffers = buffers.buffer(&mem);
+        self.lower
+            .read_vectored(
+                &buffers,
+                (block << self.geometry.block_bits) >> self.geometry.sector_shift,
+            )
+            .await?;
+        let mut data = vec![0; len];
+        buffers.reader().read(&mut data)?;
+        Ok(data)
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = sector << self.geometry.sector_shift;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let data = buffers.reader().read_all()?;
+
+        let _write_lock = self.write_lock.lock().await;
+        let hosts = self.inner.lookup_range(offset, len);
+
+        // Partially written, unallocated blocks need the rest of their
+        // contents from the lower disk.
+        let mut lower_blocks = HashMap::new();
+        for (chunk, &host) in self.geometry.chunks(offset, len).zip(&hosts) {
+            if host == 0 && chunk.range.len() != self.geometry.block_len(chunk.block) {
+                let block = self.read_lower_block(chunk.block).await?;
+                lower_blocks.insert(chunk.block, block);
+            }
+        }
+
+        let inner = self.inner.clone();
+        unblock(move || inner.write(offset, &data, &hosts, &mut lower_blocks, fua))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn flush(&self) -> Result<(), DiskError> {
+        let inner = self.inner.clone();
+        unblock(move || inner.file.sync_all())
+            .await
+            .map_err(DiskError::Io)
+    }
+}
+
+impl Inspect for FileDiffDisk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .hex("block_size", self.geometry.block_size)
+            .field("read_only", self.read_only)
+            .field_with("allocated_blocks", || {
+                self.inner
+                    .state
+                    .lock()
+                    .map
+                    .iter()
+                    .filter(|&&host| host != 0)
+                    .count()
+            })
+            .field_with("file_end", || self.inner.state.lock().file_end)
+            .field("lower_type", self.lower.disk_type())
+            .field("lower", &self.lower);
+    }
+}
+
+impl SimpleDisk for FileDiffDisk {
+    fn disk_type(&self) -> &str {
+        "filediff"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geometry.disk_size >> self.geometry.sector_shift
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << self.geometry.sector_shift
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.lower.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.lower.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+}
+
+impl AsyncDisk for FileDiffDisk {
+    fn read_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.read(buffers, sector))
+    }
+
+    fn write_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+        fua: bool,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.write(buffers, sector, fua))
+    }
+
+    fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileDiffDisk;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::test_utilities::SECTOR_SIZE as SECTOR_USIZE;
+    use disk_backend::SimpleDisk;
+    use disk_ramdisk::RamDisk;
+    use pal_async::async_test;
+    use std::sync::Arc;
+
+    /// A disk size that is not a multiple of the block size.
+    const DISK_SIZE: u64 = (3 << 20) + 64 * 1024;
+
+    async fn lower() -> Arc<dyn SimpleDisk> {
+        let lower = RamDisk::new(DISK_SIZE, false).unwrap();
+        let sectors = DISK_SIZE as usize / SECTOR_USIZE;
+        write(&lower, 0, &pattern(0, sectors, 1)).await;
+        Arc::new(lower)
+    }
+
+    #[async_test]
+    async fn write_reopen_commit() {
+        let lower = lower().await;
+        let file = tempfile::tempfile().unwrap();
+        FileDiffDisk::create(&file, lower.as_ref()).unwrap();
+        let disk = FileDiffDisk::open(file.try_clone().unwrap(), lower.clone(), false).unwrap();
+        assert_eq!(read(&disk, 10, 4).await, pattern(10, 4, 1));
+
+        // Write across a block boundary, and into the short last block.
+        write(&disk, 2046, &pattern(2046, 4, 2)).await;
+        let last = DISK_SIZE / SECTOR_USIZE as u64 - 3;
+        write(&disk, last, &pattern(last, 3, 2)).await;
+        drop(disk);
+
+        let disk = FileDiffDisk::open(file.try_clone().unwrap(), lower.clone(), false).unwrap();
+        let data = read(&disk, 2040, 16).await;
+        assert_eq!(data[..6 * SECTOR_USIZE], pattern(2040, 6, 1));
+        assert_eq!(
+            data[6 * SECTOR_USIZE..10 * SECTOR_USIZE],
+            pattern(2046, 4, 2)
+        );
+        assert_eq!(data[10 * SECTOR_USIZE..], pattern(2050, 6, 1));
+        assert_eq!(
+            read(&disk, last - 1, 4).await[SECTOR_USIZE..],
+            pattern(last, 3, 2)
+        );
+        // The lower disk is untouched.
+        assert_eq!(read(lower.as_ref(), 2046, 4).await, pattern(2046, 4, 1));
+
+        disk.commit().await.unwrap();
+        assert_eq!(read(lower.as_ref(), 2040, 16).await, data);
+        assert_eq!(read(lower.as_ref(), last, 3).await, pattern(last, 3, 2));
+        assert_eq!(read(&disk, 2040, 16).await, data);
+        assert!(disk.inner.state.lock().map.iter().all(|&host| host == 0));
+    }
+
+    #[async_test]
+    async fn discard() {
+        let lower = lower().await;
+        let file = tempfile::tempfile().unwrap();
+        FileDiffDisk::create(&file, lower.as_ref()).unwrap();
+        let disk = FileDiffDisk::open(file.try_clone().unwrap(), lower.clone(), false).unwrap();
+        write(&disk, 100, &pattern(100, 8, 3)).await;
+        drop(disk);
+
+        super::discard(&file).unwrap();
+        let disk = FileDiffDisk::open(file, lower, false).unwrap();
+        assert_eq!(read(&disk, 100, 8).await, pattern(100, 8, 1));
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for file-backed diff disks.
+
+use super::FileDiffDisk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedSimpleDisk;
+use disk_backend_resources::FileDiffDiskHandle;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+
+/// Resolver for a [`FileDiffDiskHandle`].
+pub struct FileDiffDiskResolver;
+
+declare_static_async_resolver!(FileDiffDiskResolver, (DiskHandleKind, FileDiffDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, FileDiffDiskHandle> for FileDiffDiskResolver {
+    type Output = ResolvedSimpleDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: FileDiffDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let lower = resolver
+            .resolve(
+                rsrc.lower,
+                ResolveDiskParameters {
+                    read_only: true,
+                    driver_source: input.driver_source,
+                },
+            )
+            .await?;
+        // An empty file is initialized as a new diff disk.
+        if !input.read_only && rsrc.file.metadata()?.len() == 0 {
+            FileDiffDisk::create(&rsrc.file, lower.0.as_ref())?;
+        }
+        Ok(FileDiffDisk::open(rsrc.file, lower.0, input.read_only)?.into())
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! QCOW2 on-disk format definitions.
+//!
+//! See the QEMU `docs/interop/qcow2.txt` specification. All multi-byte fields
+//! are big endian.
+
+use self::packed_nums::*;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+#[allow(non_camel_case_types)]
+mod packed_nums {
+    pub type u32_be = zerocopy::U32<zerocopy::BigEndian>;
+    pub type u64_be = zerocopy::U64<zerocopy::BigEndian>;
+}
+
+/// The QCOW2 image header, including the version 3 fields.
+///
+/// Version 2 images only have the first [`Header::V2_LEN`] bytes; the
+/// remaining fields take their implied defaults.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+pub struct Header {
+    pub magic: u32_be,
+    pub version: u32_be,
+    pub backing_file_offset: u64_be,
+    pub backing_file_size: u32_be,
+    pub cluster_bits: u32_be,
+    pub size: u64_be,
+    pub crypt_method: u32_be,
+    pub l1_size: u32_be,
+    pub l1_table_offset: u64_be,
+    pub refcount_table_offset: u64_be,
+    pub refcount_table_clusters: u32_be,
+    pub nb_snapshots: u32_be,
+    pub snapshots_offset: u64_be,
+    // Version 3 and later.
+    pub incompatible_features: u64_be,
+    pub compatible_features: u64_be,
+    pub autoclear_features: u64_be,
+    pub refcount_order: u32_be,
+    pub header_length: u32_be,
+}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_be_bytes(*b"QFI\xfb");
+    pub const V2_LEN: u32 = 72;
+    pub const V3_LEN: u32 = size_of::<Self>() as u32;
+
+    /// The offset of the L1 table fields (`l1_size`, `l1_table_offset`).
+    pub const L1_OFFSET: u64 = 36;
+    /// The offset of the refcount table fields (`refcount_table_offset`,
+    /// `refcount_table_clusters`).
+    pub const REFCOUNT_TABLE_OFFSET: u64 = 48;
+    /// The offset of `autoclear_features`.
+    pub const AUTOCLEAR_OFFSET: u64 = 88;
+
+    /// The refcount order implied for version 2 images (16-bit refcounts).
+    pub const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+}
+
+pub const MIN_CLUSTER_BITS: u32 = 9;
+pub const MAX_CLUSTER_BITS: u32 = 21;
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+/// Incompatible feature bits.
+pub mod incompatible {
+    pub const DIRTY: u64 = 1 << 0;
+    pub const CORRUPT: u64 = 1 << 1;
+    pub const EXTERNAL_DATA_FILE: u64 = 1 << 2;
+    pub const COMPRESSION_TYPE: u64 = 1 << 3;
+    pub const EXTENDED_L2: u64 = 1 << 4;
+}
+
+/// Header extension types.
+pub mod extension {
+    pub const END: u32 = 0;
+    pub const BACKING_FORMAT: u32 = 0xe2792aca;
+}
+
+/// A header extension header, followed by `len` bytes of data padded to a
+/// multiple of 8 bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, AsBytes, FromBytes, FromZeroes)]
+pub struct HeaderExtension {
+    pub extension_type: u32_be,
+    pub len: u32_be,
+}
+
+/// The host cluster offset in an L1 table entry.
+pub const L1E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The host cluster offset in a standard L2 table entry.
+pub const L2E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The refcount block offset in a refcount table entry.
+pub const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+/// Set in L1 and L2 entries whose target has a refcount of exactly one, and
+/// so may be written in place.
+pub const OFLAG_COPIED: u64 = 1 << 63;
+/// Set in L2 entries describing compressed clusters.
+pub const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Set in (version 3) L2 entries for clusters that read as zero.
+pub const OFLAG_ZERO: u64 = 1 << 0;
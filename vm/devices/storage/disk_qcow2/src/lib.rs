@@ -0,0 +1,1237 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A QCOW2 disk implementation.
+//!
+//! Supports version 2 and 3 images, including zero clusters, unmap, and
+//! read-through to a backing disk. Compressed clusters, encryption, external
+//! data files, and extended L2 entries are not supported. Images with internal
+//! snapshots can only be opened read-only.
+
+#![forbid(unsafe_code)]
+
+mod format;
+pub mod resolver;
+
+use self::format::Header;
+use self::format::HeaderExtension;
+use blocking::unblock;
+use disk_backend::block;
+use disk_backend::block::BlockChunk;
+use disk_backend::AsyncDisk;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use disk_backend::Unmap;
+use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_file::readwriteat::read_at_or_zero;
+use disk_file::readwriteat::read_exact_at;
+use disk_file::readwriteat::write_all_at;
+use guestmem::GuestMemory;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::OwnedRequestBuffers;
+use scsi_buffers::RequestBuffers;
+use stackfuture::StackFuture;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use zerocopy::AsBytes;
+use zerocopy::FromZeroes;
+
+const SECTOR_SIZE: u32 = 512;
+const SECTOR_SHIFT: u32 = SECTOR_SIZE.trailing_zeros();
+
+/// The maximum number of L2 tables to keep cached.
+const L2_CACHE_TABLES: usize = 64;
+/// The maximum number of refcount blocks to keep cached.
+const REFCOUNT_CACHE_BLOCKS: usize = 16;
+
+/// Upper bounds on table sizes, to avoid huge allocations for corrupt images.
+/// These match QEMU's limits.
+const MAX_L1_BYTES: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 << 20;
+
+/// An error encountered while opening or creating a QCOW2 image.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("not a QCOW2 image")]
+    InvalidMagic,
+    #[error("unsupported QCOW2 version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid header length: {0}")]
+    InvalidHeaderLength(u32),
+    #[error("invalid cluster bits: {0}")]
+    InvalidClusterBits(u32),
+    #[error("invalid disk size: {0}")]
+    InvalidDiskSize(u64),
+    #[error("encrypted images are not supported")]
+    Encrypted,
+    #[error("unsupported incompatible features: {0:#x}")]
+    UnsupportedFeatures(u64),
+    #[error("image is marked corrupt")]
+    Corrupt,
+    #[error("image was not closed cleanly and must be repaired before writing")]
+    Dirty,
+    #[error("unsupported refcount order: {0}")]
+    UnsupportedRefcountOrder(u32),
+    #[error("invalid L1 table")]
+    InvalidL1Table,
+    #[error("invalid refcount table")]
+    InvalidRefcountTable,
+    #[error("images with internal snapshots can only be opened read-only")]
+    SnapshotsNotWritable,
+    #[error("image has a backing file but no backing disk was provided")]
+    MissingBacking,
+    #[error("a backing disk was provided but the image has no backing file")]
+    UnexpectedBacking,
+    #[error("unsupported backing disk sector size: {0}")]
+    UnsupportedBackingSectorSize(u32),
+    #[error("invalid backing file name")]
+    InvalidBackingFileName,
+}
+
+/// The backing file reference stored in a QCOW2 image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackingFile {
+    /// The backing file name, which is relative to the directory containing
+    /// the image unless it is an absolute path.
+    pub name: String,
+    /// The backing file format (e.g. `raw` or `qcow2`), if specified.
+    pub format: Option<String>,
+}
+
+/// Returns whether `file` starts with the QCOW2 magic number.
+pub fn is_qcow2(file: &File) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match read_exact_at(file, &mut magic, 0) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == Header::MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the backing file reference from the QCOW2 image in `file`, if there
+/// is one.
+pub fn read_backing_file(file: &File) -> Result<Option<BackingFile>, OpenError> {
+    let header = read_header(file)?;
+    let offset = header.backing_file_offset.get();
+    if offset == 0 {
+        return Ok(None);
+    }
+    let size = header.backing_file_size.get();
+    if size == 0 || size > 1023 {
+        return Err(OpenError::InvalidBackingFileName);
+    }
+    let mut name = vec![0; size as usize];
+    read_exact_at(file, &mut name, offset)?;
+    let name = String::from_utf8(name).map_err(|_| OpenError::InvalidBackingFileName)?;
+
+    // Look for the backing format in the header extensions, which end at the
+    // end of the first cluster.
+    let cluster_size = 1u64 << header.cluster_bits.get();
+    let mut backing_format = None;
+    let mut pos = header.header_length.get() as u64;
+    while pos + size_of::<HeaderExtension>() as u64 <= cluster_size {
+        let mut ext = HeaderExtension::new_zeroed();
+        read_exact_at(file, ext.as_bytes_mut(), pos)?;
+        pos += size_of::<HeaderExtension>() as u64;
+        let len = ext.len.get() as u64;
+        match ext.extension_type.get() {
+            format::extension::END => break,
+            format::extension::BACKING_FORMAT => {
+                if pos + len > cluster_size {
+                    break;
+                }
+                let mut data = vec![0; len as usize];
+                read_exact_at(file, &mut data, pos)?;
+                backing_format = String::from_utf8(data).ok();
+            }
+            _ => {}
+        }
+        pos += len.next_multiple_of(8);
+    }
+
+    Ok(Some(BackingFile {
+        name,
+        format: backing_format,
+    }))
+}
+
+/// Reads and validates the fixed part of the header, filling in the version 3
+/// fields for version 2 images.
+fn read_header(file: &File) -> Result<Header, OpenError> {
+    let mut header = Header::new_zeroed();
+    read_exact_at(file, header.as_bytes_mut(), 0)?;
+    if header.magic.get() != Header::MAGIC {
+        return Err(OpenError::InvalidMagic);
+    }
+    match header.version.get() {
+        2 => {
+            header.as_bytes_mut()[Header::V2_LEN as usize..].fill(0);
+            header.refcount_order = Header::DEFAULT_REFCOUNT_ORDER.into();
+            header.header_length = Header::V2_LEN.into();
+        }
+        3 => {
+            let len = header.header_length.get();
+            if len < Header::V3_LEN || len % 8 != 0 {
+                return Err(OpenError::InvalidHeaderLength(len));
+            }
+        }
+        version => return Err(OpenError::UnsupportedVersion(version)),
+    }
+    let cluster_bits = header.cluster_bits.get();
+    if !(format::MIN_CLUSTER_BITS..=format::MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        return Err(OpenError::InvalidClusterBits(cluster_bits));
+    }
+    Ok(header)
+}
+
+/// Reads a table of big-endian 64-bit entries.
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0; entries * 8];
+    read_at_or_zero(file, &mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+/// An open QCOW2 disk.
+pub struct Qcow2Disk {
+    inner: Arc<Inner>,
+    geometry: Geometry,
+    backing: Option<Arc<dyn SimpleDisk>>,
+    read_only: bool,
+    /// Lock used to serialize operations that change cluster mappings.
+    write_lock: futures::lock::Mutex<()>,
+}
+
+struct Inner {
+    file: File,
+    geometry: Geometry,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Geometry {
+    version: u32,
+    cluster_bits: u32,
+    cluster_size: u64,
+    disk_size: u64,
+    refcount_order: u32,
+}
+
+/// The mapping of a guest cluster to the image file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mapping {
+    /// The cluster is not allocated, so it reads from the backing disk, or as
+    /// zero if there is no backing disk.
+    Unallocated,
+    /// The cluster reads as zero. If `host` is set, then the cluster has a
+    /// preallocated host cluster that can be written in place.
+    Zero { host: Option<u64> },
+    /// The cluster is allocated at `host`. If `copied` is false, then the host
+    /// cluster is shared and must be copied before being written.
+    Data { host: u64, copied: bool },
+    /// The cluster is compressed.
+    Compressed,
+}
+
+impl Geometry {
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size / 8
+    }
+
+    fn refcount_block_entries(&self) -> u64 {
+        (self.cluster_size * 8) >> self.refcount_order
+    }
+
+    fn refcount_bytes(&self) -> usize {
+        1 << (self.refcount_order - 3)
+    }
+
+    /// Splits the guest byte range `offset..offset + len` into per-cluster
+    /// chunks.
+    fn chunks(&self, offset: u64, len: usize) -> impl Iterator<Item = BlockChunk> {
+        block::chunks(offset, len, self.cluster_bits)
+    }
+
+    fn mapping(&self, entry: u64) -> io::Result<Mapping> {
+        if entry & format::OFLAG_COMPRESSED != 0 {
+            return Ok(Mapping::Compressed);
+        }
+        let host = entry & format::L2E_OFFSET_MASK;
+        if host & (self.cluster_size - 1) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unaligned cluster offset {host:#x}"),
+            ));
+        }
+        let copied = entry & format::OFLAG_COPIED != 0;
+        let mapping = if self.version >= 3 && entry & format::OFLAG_ZERO != 0 {
+            Mapping::Zero {
+                host: (host != 0 && copied).then_some(host),
+            }
+        } else if host == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data { host, copied }
+        };
+        Ok(mapping)
+    }
+}
+
+/// Mutable image metadata. Caches are write-through, so they can be dropped at
+/// any time.
+struct State {
+    geometry: Geometry,
+    l1_table: Vec<u64>,
+    l1_table_offset: u64,
+    l2_cache: HashMap<u64, Box<[u64]>>,
+    refcount_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_cache: HashMap<u64, Box<[u8]>>,
+    /// The first cluster past the end of the image, for allocating new
+    /// clusters.
+    next_cluster: u64,
+    /// Clusters whose refcount dropped to zero while the image was open.
+    free_clusters: BTreeSet<u64>,
+}
+
+impl State {
+    fn l2_table(&mut self, file: &File, offset: u64) -> io::Result<&mut [u64]> {
+        if !self.l2_cache.contains_key(&offset) {
+            let table = read_table(file, offset, self.geometry.l2_entries() as usize)?;
+            self.cache_l2_table(offset, table.into());
+        }
+        Ok(self.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    fn cache_l2_table(&mut self, offset: u64, table: Box<[u64]>) {
+        if self.l2_cache.len() >= L2_CACHE_TABLES {
+            if let Some(&evict) = self.l2_cache.keys().next() {
+                self.l2_cache.remove(&evict);
+            }
+        }
+        self.l2_cache.insert(offset, table);
+    }
+
+    fn refcount_block(&mut self, file: &File, offset: u64) -> io::Result<&mut [u8]> {
+        if !self.refcount_cache.contains_key(&offset) {
+            let mut block = vec![0; self.geometry.cluster_size as usize];
+            read_at_or_zero(file, &mut block, offset)?;
+            self.cache_refcount_block(offset, block.into());
+        }
+        Ok(self.refcount_cache.get_mut(&offset).unwrap())
+    }
+
+    fn cache_refcount_block(&mut self, offset: u64, block: Box<[u8]>) {
+        if self.refcount_cache.len() >= REFCOUNT_CACHE_BLOCKS {
+            if let Some(&evict) = self.refcount_cache.keys().next() {
+                self.refcount_cache.remove(&evict);
+            }
+        }
+        self.refcount_cache.insert(offset, block);
+    }
+
+    fn split_guest_cluster(&self, guest_cluster: u64) -> (usize, usize) {
+        let l2_entries = self.geometry.l2_entries();
+        (
+            (guest_cluster / l2_entries) as usize,
+            (guest_cluster % l2_entries) as usize,
+        )
+    }
+
+    /// Looks up the mapping for a guest cluster.
+    fn lookup(&mut self, file: &File, guest_cluster: u64) -> io::Result<Mapping> {
+        let (l1_index, l2_index) = self.split_guest_cluster(guest_cluster);
+        let l2_offset = self.l1_table[l1_index] & format::L1E_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.l2_table(file, l2_offset)?[l2_index];
+        self.geometry.mapping(entry)
+    }
+
+    /// Updates the L2 entry for a guest cluster, allocating the L2 table if
+    /// necessary.
+    fn set_l2_entry(&mut self, file: &File, guest_cluster: u64, entry: u64) -> io::Result<()> {
+        let (l1_index, l2_index) = self.split_guest_cluster(guest_cluster);
+        let mut l2_offset = self.l1_table[l1_index] & format::L1E_OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster(file)?;
+            write_all_at(
+                file,
+                &vec![0; self.geometry.cluster_size as usize],
+                l2_offset,
+            )?;
+            self.cache_l2_table(
+                l2_offset,
+                vec![0; self.geometry.l2_entries() as usize].into(),
+            );
+            let l1_entry = l2_offset | format::OFLAG_COPIED;
+            write_all_at(
+                file,
+                &l1_entry.to_be_bytes(),
+                self.l1_table_offset + l1_index as u64 * 8,
+            )?;
+            self.l1_table[l1_index] = l1_entry;
+        }
+        self.l2_table(file, l2_offset)?[l2_index] = entry;
+        write_all_at(file, &entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+    }
+
+    fn refcount(&mut self, file: &File, cluster: u64) -> io::Result<u64> {
+        let block_entries = self.geometry.refcount_block_entries();
+        let Some(&entry) = self.refcount_table.get((cluster / block_entries) as usize) else {
+            return Ok(0);
+        };
+        let block_offset = entry & format::REFT_OFFSET_MASK;
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        let width = self.geometry.refcount_bytes();
+        let index = (cluster % block_entries) as usize * width;
+        let block = self.refcount_block(file, block_offset)?;
+        Ok(block[index..index + width]
+            .iter()
+            .fold(0, |v, &b| (v << 8) | b as u64))
+    }
+
+    fn set_refcount(&mut self, file: &File, cluster: u64, refcount: u64) -> io::Result<()> {
+        let block_entries = self.geometry.refcount_block_entries();
+        let block_offset = self.refcount_block_for(file, (cluster / block_entries) as usize)?;
+        let width = self.geometry.refcount_bytes();
+        let index = (cluster % block_entries) as usize * width;
+        let bytes = refcount.to_be_bytes();
+        let bytes = &bytes[8 - width..];
+        self.refcount_block(file, block_offset)?[index..index + width].copy_from_slice(bytes);
+        write_all_at(file, bytes, block_offset + index as u64)
+    }
+
+    /// Returns the offset of the refcount block for `table_index`, allocating
+    /// the block (and growing the refcount table) as necessary.
+    fn refcount_block_for(&mut self, file: &File, table_index: usize) -> io::Result<u64> {
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(file, table_index + 1)?;
+        }
+        let offset = self.refcount_table[table_index] & format::REFT_OFFSET_MASK;
+        if offset != 0 {
+            return Ok(offset);
+        }
+
+        // Allocate the new block at the end of the file. If the block
+        // describes its own cluster, then set its refcount in place; otherwise
+        // set it in whichever block covers it.
+        let block_entries = self.geometry.refcount_block_entries();
+        let cluster = self.next_cluster;
+        self.next_cluster += 1;
+        let offset = cluster << self.geometry.cluster_bits;
+        let mut block = vec![0; self.geometry.cluster_size as usize];
+        let covers_self = (cluster / block_entries) as usize == table_index;
+        if covers_self {
+            let width = self.geometry.refcount_bytes();
+            let index = (cluster % block_entries) as usize * width;
+            block[index + width - 1] = 1;
+        }
+        write_all_at(file, &block, offset)?;
+        self.cache_refcount_block(offset, block.into());
+        if !covers_self {
+            self.set_refcount(file, cluster, 1)?;
+        }
+
+        // Only link the block into the table once it is initialized.
+        write_all_at(
+            file,
+            &offset.to_be_bytes(),
+            self.refcount_table_offset + table_index as u64 * 8,
+        )?;
+        self.refcount_table[table_index] = offset;
+        Ok(offset)
+    }
+
+    /// Moves the refcount table to the end of the file, growing it to at
+    /// least `min_entries` entries.
+    fn grow_refcount_table(&mut self, file: &File, min_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = self.geometry.l2_entries() as usize;
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = self.refcount_table.len().div_ceil(entries_per_cluster) as u64;
+        let new_entries = min_entries
+            .max(self.refcount_table.len() * 2)
+            .next_multiple_of(entries_per_cluster);
+        let new_clusters = (new_entries / entries_per_cluster) as u64;
+        if new_entries as u64 * 8 > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(io::Error::other("refcount table too large"));
+        }
+
+        tracing::debug!(old_clusters, new_clusters, "growing qcow2 refcount table");
+
+        let first_cluster = self.next_cluster;
+        self.next_cluster += new_clusters;
+        self.refcount_table.resize(new_entries, 0);
+        self.refcount_table_offset = first_cluster << self.geometry.cluster_bits;
+        for cluster in first_cluster..first_cluster + new_clusters {
+            self.set_refcount(file, cluster, 1)?;
+        }
+
+        // Write the new table, then point the header at it.
+        let table = self
+            .refcount_table
+            .iter()
+            .flat_map(|entry| entry.to_be_bytes())
+            .collect::<Vec<_>>();
+        write_all_at(file, &table, self.refcount_table_offset)?;
+        file.sync_data()?;
+        let mut header = [0; 12];
+        header[..8].copy_from_slice(&self.refcount_table_offset.to_be_bytes());
+        header[8..].copy_from_slice(&(new_clusters as u32).to_be_bytes());
+        write_all_at(file, &header, Header::REFCOUNT_TABLE_OFFSET)?;
+        file.sync_data()?;
+
+        for i in 0..old_clusters {
+            self.release_cluster(file, old_offset + (i << self.geometry.cluster_bits))?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a host cluster with a refcount of one, returning its offset.
+    fn allocate_cluster(&mut self, file: &File) -> io::Result<u64> {
+        let cluster = match self.free_clusters.pop_first() {
+            Some(cluster) => cluster,
+            None => {
+                let cluster = self.next_cluster;
+                self.next_cluster += 1;
+                cluster
+            }
+        };
+        self.set_refcount(file, cluster, 1)?;
+        Ok(cluster << self.geometry.cluster_bits)
+    }
+
+    /// Drops a reference to the host cluster at `offset`.
+    fn release_cluster(&mut self, file: &File, offset: u64) -> io::Result<()> {
+        let cluster = offset >> self.geometry.cluster_bits;
+        let refcount = self.refcount(file, cluster)?;
+        if refcount == 0 {
+            tracing::warn!(offset, "releasing qcow2 cluster with zero refcount");
+            return Ok(());
+        }
+        self.set_refcount(file, cluster, refcount - 1)?;
+        if refcount == 1 {
+            self.free_clusters.insert(cluster);
+        }
+        Ok(())
+    }
+}
+
+fn compressed_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed qcow2 clusters are not supported",
+    )
+}
+
+impl Inner {
+    /// Reads the allocated parts of the guest range from the image, returning
+    /// the data and the ranges of the buffer that must be read from the backing
+    /// disk.
+    fn read(&self, offset: u64, len: usize) -> io::Result<(Vec<u8>, Vec<Range<usize>>)> {
+        let mut data = vec![0; len];
+        let mut unallocated: Vec<Range<usize>> = Vec::new();
+        let mut reads: Vec<(Range<usize>, u64)> = Vec::new();
+        {
+            let mut state = self.state.lock();
+            for chunk in self.geometry.chunks(offset, len) {
+                match state.lookup(&self.file, chunk.block)? {
+                    Mapping::Unallocated => match unallocated.last_mut() {
+                        Some(last) if last.end == chunk.range.start => {
+                            last.end = chunk.range.end;
+                        }
+                        _ => unallocated.push(chunk.range),
+                    },
+                    Mapping::Zero { .. } => {}
+                    Mapping::Data { host, .. } => {
+                        let host = host + chunk.block_offset;
+                        match reads.last_mut() {
+                            Some((last, last_host))
+                                if last.end == chunk.range.start
+                                    && *last_host + last.len() as u64 == host =>
+                            {
+                                last.end = chunk.range.end;
+                            }
+                            _ => reads.push((chunk.range, host)),
+                        }
+                    }
+                    Mapping::Compressed => return Err(compressed_error()),
+                }
+            }
+        }
+        for (range, host) in reads {
+            read_at_or_zero(&self.file, &mut data[range], host)?;
+        }
+        Ok((data, unallocated))
+    }
+
+    fn lookup_range(&self, offset: u64, len: usize) -> io::Result<Vec<Mapping>> {
+        let mut state = self.state.lock();
+        self.geometry
+            .chunks(offset, len)
+            .map(|chunk| state.lookup(&self.file, chunk.block))
+            .collect()
+    }
+
+    /// Writes `data` at guest `offset`, given the current `mappings` of the
+    /// clusters in the range and the backing disk contents of any partially
+    /// written unallocated clusters.
+    ///
+    /// Must be called with the write lock held.
+    fn write(
+        &self,
+        offset: u64,
+        data: &[u8],
+        mappings: &[Mapping],
+        backing_clusters: &mut HashMap<u64, Vec<u8>>,
+        fua: bool,
+    ) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size as usize;
+        for (chunk, &mapping) in self.geometry.chunks(offset, data.len()).zip(mappings) {
+            let buf = &data[chunk.range.clone()];
+            let (host, old_host) = match mapping {
+                Mapping::Data { host, copied: true } => {
+                    write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                    continue;
+                }
+                Mapping::Compressed => return Err(compressed_error()),
+                Mapping::Zero { host: Some(host) } => (host, None),
+                Mapping::Data {
+                    host,
+                    copied: false,
+                } => (self.state.lock().allocate_cluster(&self.file)?, Some(host)),
+                Mapping::Unallocated | Mapping::Zero { host: None } => {
+                    (self.state.lock().allocate_cluster(&self.file)?, None)
+                }
+            };
+
+            // Write the full cluster, merging in the existing contents if
+            // this is a partial write.
+            if buf.len() == cluster_size {
+                write_all_at(&self.file, buf, host)?;
+            } else {
+                let mut cluster = match mapping {
+                    Mapping::Data { host, .. } => {
+                        let mut cluster = vec![0; cluster_size];
+                        read_at_or_zero(&self.file, &mut cluster, host)?;
+                        cluster
+                    }
+                    Mapping::Unallocated => backing_clusters
+                        .remove(&chunk.block)
+                        .unwrap_or_else(|| vec![0; cluster_size]),
+                    Mapping::Zero { .. } | Mapping::Compressed => vec![0; cluster_size],
+                };
+                cluster[chunk.block_offset as usize..][..buf.len()].copy_from_slice(buf);
+                write_all_at(&self.file, &cluster, host)?;
+            }
+
+            // Point the guest cluster at the new data only once the data has
+            // been written.
+            let mut state = self.state.lock();
+            state.set_l2_entry(&self.file, chunk.block, host | format::OFLAG_COPIED)?;
+            if let Some(old_host) = old_host {
+                state.release_cluster(&self.file, old_host)?;
+            }
+        }
+        if fua {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Deallocates the clusters fully covered by the guest range.
+    ///
+    /// If `has_backing`, then the clusters are marked as zero so that they do
+    /// not expose the backing disk's contents. This is only possible for
+    /// version 3 images.
+    ///
+    /// Must be called with the write lock held.
+    fn unmap(&self, offset: u64, len: usize, has_backing: bool) -> io::Result<()> {
+        if has_backing && self.geometry.version < 3 {
+            return Ok(());
+        }
+        let new_entry = if has_backing { format::OFLAG_ZERO } else { 0 };
+        let mut state = self.state.lock();
+        for chunk in self.geometry.chunks(offset, len) {
+            if chunk.range.len() as u64 != self.geometry.cluster_size {
+                continue;
+            }
+            let old_host = match state.lookup(&self.file, chunk.block)? {
+                Mapping::Unallocated if has_backing => None,
+                Mapping::Zero { host: Some(host) } => Some(host),
+                Mapping::Data { host, .. } => Some(host),
+                Mapping::Unallocated | Mapping::Zero { host: None } | Mapping::Compressed => {
+                    continue
+                }
+            };
+            state.set_l2_entry(&self.file, chunk.block, new_entry)?;
+            if let Some(old_host) = old_host {
+                state.release_cluster(&self.file, old_host)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Qcow2Disk {
+    /// Creates a new, empty version 3 QCOW2 image of `disk_size` bytes in
+    /// `file`, with clusters of `1 << cluster_bits` bytes.
+    ///
+    /// If `backing` is set, the image will reference it as its backing file.
+    pub fn create(
+        file: &File,
+        disk_size: u64,
+        cluster_bits: u32,
+        backing: Option<&BackingFile>,
+    ) -> Result<(), OpenError> {
+        if !(format::MIN_CLUSTER_BITS..=format::MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(OpenError::InvalidClusterBits(cluster_bits));
+        }
+        if disk_size == 0 || disk_size % SECTOR_SIZE as u64 != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let geometry = Geometry {
+            version: 3,
+            cluster_bits,
+            cluster_size: 1 << cluster_bits,
+            disk_size,
+            refcount_order: Header::DEFAULT_REFCOUNT_ORDER,
+        };
+        let cluster_size = geometry.cluster_size;
+        let l1_size = disk_size.div_ceil(cluster_size * geometry.l2_entries());
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+        if l1_size * 8 > MAX_L1_BYTES {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+
+        // Lay out the header, the refcount table, the first refcount block,
+        // and then the L1 table. All of these must be covered by the first
+        // refcount block.
+        let refcount_table_offset = cluster_size;
+        let refcount_block_offset = cluster_size * 2;
+        let l1_table_offset = cluster_size * 3;
+        let total_clusters = 3 + l1_clusters;
+        if total_clusters > geometry.refcount_block_entries() {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+
+        let mut header = Header {
+            magic: Header::MAGIC.into(),
+            version: 3u32.into(),
+            cluster_bits: cluster_bits.into(),
+            size: disk_size.into(),
+            l1_size: (l1_size as u32).into(),
+            l1_table_offset: l1_table_offset.into(),
+            refcount_table_offset: refcount_table_offset.into(),
+            refcount_table_clusters: 1u32.into(),
+            refcount_order: geometry.refcount_order.into(),
+            header_length: Header::V3_LEN.into(),
+            ..FromZeroes::new_zeroed()
+        };
+
+        let mut header_cluster = vec![0; cluster_size as usize];
+        let mut pos = Header::V3_LEN as usize;
+        if let Some(BackingFile {
+            name,
+            format: backing_format,
+        }) = backing
+        {
+            if let Some(backing_format) = backing_format {
+                let ext = HeaderExtension {
+                    extension_type: format::extension::BACKING_FORMAT.into(),
+                    len: (backing_format.len() as u32).into(),
+                };
+                let ext_len =
+                    size_of::<HeaderExtension>() + backing_format.len().next_multiple_of(8);
+                let Some(dest) = header_cluster.get_mut(pos..pos + ext_len) else {
+                    return Err(OpenError::InvalidBackingFileName);
+                };
+                dest[..size_of::<HeaderExtension>()].copy_from_slice(ext.as_bytes());
+                dest[size_of::<HeaderExtension>()..][..backing_format.len()]
+                    .copy_from_slice(backing_format.as_bytes());
+                pos += ext_len;
+            }
+            // Skip the end-of-extensions marker.
+            pos += size_of::<HeaderExtension>();
+            if name.is_empty() || name.len() > 1023 {
+                return Err(OpenError::InvalidBackingFileName);
+            }
+            let Some(dest) = header_cluster.get_mut(pos..pos + name.len()) else {
+                return Err(OpenError::InvalidBackingFileName);
+            };
+            dest.copy_from_slice(name.as_bytes());
+            header.backing_file_offset = (pos as u64).into();
+            header.backing_file_size = (name.len() as u32).into();
+        }
+        header_cluster[..Header::V3_LEN as usize].copy_from_slice(header.as_bytes());
+
+        let mut refcount_table = vec![0; cluster_size as usize];
+        refcount_table[..8].copy_from_slice(&refcount_block_offset.to_be_bytes());
+        let mut refcount_block = vec![0; cluster_size as usize];
+        for i in 0..total_clusters as usize {
+            refcount_block[i * 2..i * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+
+        file.set_len(0)?;
+        write_all_at(file, &header_cluster, 0)?;
+        write_all_at(file, &refcount_table, refcount_table_offset)?;
+        write_all_at(file, &refcount_block, refcount_block_offset)?;
+        file.set_len(total_clusters * cluster_size)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Opens a QCOW2 image.
+    ///
+    /// `backing` must be provided if and only if the image has a backing
+    /// file. It is only read from.
+    pub fn open(
+        file: File,
+        backing: Option<Arc<dyn SimpleDisk>>,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let mut header = read_header(&file)?;
+
+        if header.crypt_method.get() != 0 {
+            return Err(OpenError::Encrypted);
+        }
+        let incompatible = header.incompatible_features.get();
+        if incompatible & format::incompatible::CORRUPT != 0 {
+            return Err(OpenError::Corrupt);
+        }
+        if incompatible & !format::incompatible::DIRTY != 0 {
+            return Err(OpenError::UnsupportedFeatures(incompatible));
+        }
+        if !read_only && incompatible & format::incompatible::DIRTY != 0 {
+            return Err(OpenError::Dirty);
+        }
+        if !read_only && header.nb_snapshots.get() != 0 {
+            return Err(OpenError::SnapshotsNotWritable);
+        }
+        let refcount_order = header.refcount_order.get();
+        if !(3..=6).contains(&refcount_order) {
+            return Err(OpenError::UnsupportedRefcountOrder(refcount_order));
+        }
+
+        let cluster_bits = header.cluster_bits.get();
+        let geometry = Geometry {
+            version: header.version.get(),
+            cluster_bits,
+            cluster_size: 1 << cluster_bits,
+            disk_size: header.size.get(),
+            refcount_order,
+        };
+        let cluster_size = geometry.cluster_size;
+        if geometry.disk_size % SECTOR_SIZE as u64 != 0 {
+            return Err(OpenError::InvalidDiskSize(geometry.disk_size));
+        }
+
+        let l1_size = header.l1_size.get() as u64;
+        let l1_table_offset = header.l1_table_offset.get();
+        if l1_size
+            < geometry
+                .disk_size
+                .div_ceil(cluster_size * geometry.l2_entries())
+            || l1_size * 8 > MAX_L1_BYTES
+            || (l1_size != 0 && (l1_table_offset == 0 || l1_table_offset % cluster_size != 0))
+        {
+            return Err(OpenError::InvalidL1Table);
+        }
+
+        let refcount_table_offset = header.refcount_table_offset.get();
+        let refcount_table_bytes = header.refcount_table_clusters.get() as u64 * cluster_size;
+        if refcount_table_bytes == 0
+            || refcount_table_bytes > MAX_REFCOUNT_TABLE_BYTES
+            || refcount_table_offset == 0
+            || refcount_table_offset % cluster_size != 0
+        {
+            return Err(OpenError::InvalidRefcountTable);
+        }
+
+        match (&backing, header.backing_file_offset.get() != 0) {
+            (Some(backing), true) => {
+                if backing.sector_size() != SECTOR_SIZE {
+                    return Err(OpenError::UnsupportedBackingSectorSize(
+                        backing.sector_size(),
+                    ));
+                }
+            }
+            (None, false) => {}
+            (None, true) => return Err(OpenError::MissingBacking),
+            (Some(_), false) => return Err(OpenError::UnexpectedBacking),
+        }
+
+        let l1_table = read_table(&file, l1_table_offset, l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            refcount_table_offset,
+            (refcount_table_bytes / 8) as usize,
+        )?;
+
+        // Clear any autoclear features, since this implementation will not
+        // keep their associated data (e.g. dirty bitmaps) up to date.
+        if !read_only && header.autoclear_features.get() != 0 {
+            header.autoclear_features = 0u64.into();
+            write_all_at(
+                &file,
+                header.autoclear_features.as_bytes(),
+                Header::AUTOCLEAR_OFFSET,
+            )?;
+        }
+
+        let next_cluster = file.metadata()?.len().div_ceil(cluster_size);
+
+        let state = State {
+            geometry,
+            l1_table,
+            l1_table_offset,
+            l2_cache: HashMap::new(),
+            refcount_table,
+            refcount_table_offset,
+            refcount_cache: HashMap::new(),
+            next_cluster,
+            free_clusters: BTreeSet::new(),
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                file,
+                geometry,
+                state: Mutex::new(state),
+            }),
+            geometry,
+            backing,
+            read_only,
+            write_lock: Default::default(),
+        })
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), DiskError> {
+        block::check_range(offset, len, self.geometry.disk_size)
+    }
+
+    async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        let offset = sector << SECTOR_SHIFT;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let inner = self.inner.clone();
+        let (data, unallocated) = unblock(move || inner.read(offset, len))
+            .await
+            .map_err(DiskError::Io)?;
+        buffers.writer().write(&data)?;
+
+        if let Some(backing) = &self.backing {
+            let backing_size = backing.sector_count() << SECTOR_SHIFT;
+            for range in unallocated {
+                let start = offset + range.start as u64;
+                if start >= backing_size {
+                    continue;
+                }
+                let len = range.len().min((backing_size - start) as usize);
+                backing
+                    .read_vectored(&buffers.subrange(range.start, len), start >> SECTOR_SHIFT)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a full guest cluster from the backing disk, zero-extending past
+    /// the end of the backing disk.
+    async fn read_backing_cluster(
+        &self,
+        backing: &dyn SimpleDisk,
+        guest_cluster: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let cluster_size = self.geometry.cluster_size;
+        let offset = guest_cluster << self.geometry.cluster_bits;
+        let backing_size = backing.sector_count() << SECTOR_SHIFT;
+        let len = cluster_size.min(backing_size.saturating_sub(offset)) as usize;
+        let mut data = vec![0; cluster_size as usize];
+        if len > 0 {
+            let mem = GuestMemory::allocate(len);
+            let buffers = OwnedRequestBuffers::linear(0, len, true);
+            let buffers = buffers.buffer(&mem);
+            backing
+                .read_vectored(&buffers, offset >> SECTOR_SHIFT)
+                .await?;
+            buffers.reader().read(&mut data[..len])?;
+        }
+        Ok(data)
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = sector << SECTOR_SHIFT;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let data = buffers.reader().read_all()?;
+
+        let _write_lock = self.write_lock.lock().await;
+        let inner = self.inner.clone();
+        let mappings = unblock(move || inner.lookup_range(offset, len))
+            .await
+            .map_err(DiskError::Io)?;
+
+        // Partially written, unallocated clusters need the rest of their
+        // contents from the backing disk.
+        let mut backing_clusters = HashMap::new();
+        if let Some(backing) = &self.backing {
+            for (chunk, &mapping) in self.geometry.chunks(offset, len).zip(&mappings) {
+                if mapping == Mapping::Unallocated
+                    && chunk.range.len() as u64 != self.geometry.cluster_size
+                {
+                    let cluster = self
+                        .read_backing_cluster(backing.as_ref(), chunk.block)
+                        .await?;
+                    backing_clusters.insert(chunk.block, cluster);
+                }
+            }
+        }
+
+        let inner = self.inner.clone();
+        unblock(move || inner.write(offset, &data, &mappings, &mut backing_clusters, fua))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn flush(&self) -> Result<(), DiskError> {
+        let inner = self.inner.clone();
+        unblock(move || inner.file.sync_all())
+            .await
+            .map_err(DiskError::Io)
+    }
+}
+
+impl Inspect for Qcow2Disk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("version", self.geometry.version)
+            .hex("cluster_size", self.geometry.cluster_size)
+            .field("disk_size", self.geometry.disk_size)
+            .field("refcount_bits", 1u32 << self.geometry.refcount_order)
+            .field("read_only", self.read_only)
+            .field_with("next_cluster", || self.inner.state.lock().next_cluster)
+            .field_with("free_clusters", || {
+                self.inner.state.lock().free_clusters.len()
+            })
+            .field("backing", &self.backing);
+    }
+}
+
+impl SimpleDisk for Qcow2Disk {
+    fn disk_type(&self) -> &str {
+        "qcow2"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geometry.disk_size >> SECTOR_SHIFT
+    }
+
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.geometry.cluster_size.min(4096) as u32
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+
+    fn unmap(&self) -> Option<&dyn Unmap> {
+        (!self.read_only).then_some(self)
+    }
+}
+
+impl AsyncDisk for Qcow2Disk {
+    fn read_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.read(buffers, sector))
+    }
+
+    fn write_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+        fua: bool,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.write(buffers, sector, fua))
+    }
+
+    fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.flush())
+    }
+}
+
+impl Unmap for Qcow2Disk {
+    fn unmap(
+        &self,
+        sector_offset: u64,
+        sector_count: u64,
+        _block_level_only: bool,
+    ) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(async move {
+            if self.read_only {
+                return Err(DiskError::ReadOnly);
+            }
+            let offset = sector_offset << SECTOR_SHIFT;
+            let len = (sector_count << SECTOR_SHIFT) as usize;
+            self.check_range(offset, len)?;
+            let _write_lock = self.write_lock.lock().await;
+            let inner = self.inner.clone();
+            let has_backing = self.backing.is_some();
+            unblock(move || inner.unmap(offset, len, has_backing))
+                .await
+                .map_err(DiskError::Io)
+        })
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.geometry.cluster_size >> SECTOR_SHIFT) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackingFile;
+    use super::Mapping;
+    use super::Qcow2Disk;
+    use super::SECTOR_SIZE;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::SimpleDisk;
+    use disk_ramdisk::RamDisk;
+    use pal_async::async_test;
+    use std::sync::Arc;
+
+    const SECTOR_USIZE: usize = SECTOR_SIZE as usize;
+
+    #[async_test]
+    async fn write_read_reopen() {
+        let file = tempfile::tempfile().unwrap();
+        // Use small clusters so that the refcount table has to grow.
+        Qcow2Disk::create(&file, 128 << 20, 9, None).unwrap();
+        let disk = Qcow2Disk::open(file.try_clone().unwrap(), None, false).unwrap();
+        let initial_refcount_table_len = disk.inner.state.lock().refcount_table.len();
+
+        assert_eq!(read(&disk, 100, 4).await, vec![0; 4 * SECTOR_USIZE]);
+        for i in 0..5000 {
+            write(&disk, i * 37, &pattern(i * 37, 3, 1)).await;
+        }
+        for i in 0..5000 {
+            assert_eq!(read(&disk, i * 37, 3).await, pattern(i * 37, 3, 1));
+        }
+        assert!(disk.inner.state.lock().refcount_table.len() > initial_refcount_table_len);
+        drop(disk);
+
+        let disk = Qcow2Disk::open(file, None, true).unwrap();
+        for i in 0..5000 {
+            assert_eq!(read(&disk, i * 37, 3).await, pattern(i * 37, 3, 1));
+        }
+        assert_eq!(read(&disk, 1, 1).await, vec![0; SECTOR_USIZE]);
+    }
+
+    #[async_test]
+    async fn backing_read_through() {
+        const SIZE: u64 = 1 << 20;
+        let lower = RamDisk::new(SIZE, false).unwrap();
+        write(&lower, 0, &pattern(0, SIZE as usize / SECTOR_USIZE, 7)).await;
+
+        let file = tempfile::tempfile().unwrap();
+        let backing = BackingFile {
+            name: "base.img".into(),
+            format: Some("raw".into()),
+        };
+        // Make the image larger than the backing disk.
+        Qcow2Disk::create(&file, SIZE * 2, 12, Some(&backing)).unwrap();
+        assert_eq!(super::read_backing_file(&file).unwrap(), Some(backing));
+        let disk = Qcow2Disk::open(file, Some(Arc::new(lower)), false).unwrap();
+
+        assert_eq!(read(&disk, 10, 20).await, pattern(10, 20, 7));
+
+        // Partially overwrite a cluster; the rest is copied from the backing
+        // disk into a newly allocated, unshared cluster.
+        write(&disk, 9, &pattern(9, 2, 3)).await;
+        let mapping = disk.inner.state.lock().lookup(&disk.inner.file, 1).unwrap();
+        assert!(matches!(mapping, Mapping::Data { copied: true, .. }));
+        let data = read(&disk, 8, 8).await;
+        assert_eq!(data[..SECTOR_USIZE], pattern(8, 1, 7));
+        assert_eq!(data[SECTOR_USIZE..3 * SECTOR_USIZE], pattern(9, 2, 3));
+        assert_eq!(data[3 * SECTOR_USIZE..], pattern(11, 5, 7));
+
+        // Past the end of the backing disk reads as zero.
+        let end = SIZE / SECTOR_SIZE as u64;
+        let data = read(&disk, end - 2, 4).await;
+        assert_eq!(data[..2 * SECTOR_USIZE], pattern(end - 2, 2, 7));
+        assert_eq!(data[2 * SECTOR_USIZE..], vec![0; 2 * SECTOR_USIZE]);
+    }
+
+    #[async_test]
+    async fn unmap_zeroes() {
+        const SIZE: u64 = 1 << 20;
+        let lower = RamDisk::new(SIZE, false).unwrap();
+        write(&lower, 0, &pattern(0, SIZE as usize / SECTOR_USIZE, 5)).await;
+
+        let file = tempfile::tempfile().unwrap();
+        let backing = BackingFile {
+            name: "base.img".into(),
+            format: None,
+        };
+        Qcow2Disk::create(&file, SIZE, 12, Some(&backing)).unwrap();
+        let disk = Qcow2Disk::open(file, Some(Arc::new(lower)), false).unwrap();
+
+        write(&disk, 0, &pattern(0, 16, 9)).await;
+        // Unmap the second and third clusters, plus a partial fourth cluster,
+        // which is left alone.
+        disk.unmap().unwrap().unmap(8, 20, false).await.unwrap();
+        let data = read(&disk, 0, 32).await;
+        assert_eq!(data[..8 * SECTOR_USIZE], pattern(0, 8, 9));
+        assert_eq!(
+            data[8 * SECTOR_USIZE..24 * SECTOR_USIZE],
+            vec![0; 16 * SECTOR_USIZE]
+        );
+        assert_eq!(data[24 * SECTOR_USIZE..], pattern(24, 8, 5));
+
+        // The freed cluster is reused.
+        assert_eq!(disk.inner.state.lock().free_clusters.len(), 1);
+        write(&disk, 40, &pattern(40, 8, 1)).await;
+        assert!(disk.inner.state.lock().free_clusters.is_empty());
+        assert_eq!(read(&disk, 40, 8).await, pattern(40, 8, 1));
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for QCOW2 disks.
+
+use super::Qcow2Disk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedSimpleDisk;
+use disk_backend_resources::Qcow2DiskHandle;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+
+/// Resolver for a [`Qcow2DiskHandle`].
+pub struct Qcow2DiskResolver;
+
+declare_static_async_resolver!(Qcow2DiskResolver, (DiskHandleKind, Qcow2DiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, Qcow2DiskHandle> for Qcow2DiskResolver {
+    type Output = ResolvedSimpleDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: Qcow2DiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let backing = if let Some(backing) = rsrc.backing {
+            let backing = resolver
+                .resolve(
+                    backing,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await?;
+            Some(backing.0)
+        } else {
+            None
+        };
+        Ok(Qcow2Disk::open(rsrc.file, backing, input.read_only)?.into())
+    }
+}
@@ -279,6 +279,11 @@ fn unmap(
     fn optimal_unmap_sectors(&self) -> u32 {
         1
     }
+
+    fn unmap_reads_zero(&self) -> bool {
+        // Only disks without a lower layer support unmap.
+        true
+    }
 }
 
 #[cfg(test)]
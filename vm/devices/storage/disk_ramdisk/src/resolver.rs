@@ -55,7 +55,7 @@ async fn resolve(
                 rsrc.lower,
                 ResolveDiskParameters {
                     read_only: true,
-                    _async_trait_workaround: &(),
+                    driver_source: input.driver_source,
                 },
             )
             .await?;
@@ -0,0 +1,309 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A disk wrapper that limits the IO rate of an inner disk.
+//!
+//! Each limit is a token bucket, implemented as a generic cell rate algorithm:
+//! an IO reserves its cost from the bucket up front and then waits until the
+//! reservation fits within the bucket's burst allowance. The limits can be
+//! changed at runtime through `inspect`.
+
+#![forbid(unsafe_code)]
+
+use async_trait::async_trait;
+use disk_backend::pr;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedSimpleDisk;
+use disk_backend::AsyncDisk;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_backend_resources::RateLimit;
+use disk_backend_resources::ThrottleDiskHandle;
+use disk_backend_resources::ThrottleLimits;
+use inspect::Inspect;
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use stackfuture::StackFuture;
+use std::future::Future;
+use std::num::ParseIntError;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use token_bucket::Clock;
+use token_bucket::DriverClock;
+use token_bucket::TokenBucket;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveError;
+use vm_resource::ResourceResolver;
+
+/// Resolves [`ThrottleDiskHandle`] by wrapping the resolved inner disk in a
+/// [`ThrottleDisk`].
+pub struct ThrottleDiskResolver;
+declare_static_async_resolver!(ThrottleDiskResolver, (DiskHandleKind, ThrottleDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, ThrottleDiskHandle> for ThrottleDiskResolver {
+    type Output = ResolvedSimpleDisk;
+    type Error = ResolveError;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: ThrottleDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner = resolver.resolve(rsrc.disk, input).await?;
+        Ok(ThrottleDisk::new(inner.0, &rsrc.limits, input.driver_source.simple()).into())
+    }
+}
+
+/// A disk wrapper that limits the read and write rates of an inner disk.
+#[derive(Inspect)]
+pub struct ThrottleDisk {
+    inner: Arc<dyn SimpleDisk>,
+    #[inspect(skip)]
+    clock: Arc<dyn Clock>,
+    read_iops: Bucket,
+    write_iops: Bucket,
+    read_bytes: Bucket,
+    write_bytes: Bucket,
+}
+
+impl ThrottleDisk {
+    /// Wraps `inner`, enforcing `limits`. `driver` is used to wait for
+    /// throttled IOs.
+    pub fn new(inner: Arc<dyn SimpleDisk>, limits: &ThrottleLimits, driver: impl Driver) -> Self {
+        Self::with_clock(inner, limits, Arc::new(DriverClock::new(driver)))
+    }
+
+    fn with_clock(
+        inner: Arc<dyn SimpleDisk>,
+        limits: &ThrottleLimits,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            inner,
+            clock,
+            read_iops: Bucket::new(limits.read_iops),
+            write_iops: Bucket::new(limits.write_iops),
+            read_bytes: Bucket::new(limits.read_bytes),
+            write_bytes: Bucket::new(limits.write_bytes),
+        }
+    }
+
+    /// Waits until an IO of `len` bytes is allowed by `iops` and `bytes`.
+    async fn throttle(&self, iops: &Bucket, bytes: &Bucket, len: usize) {
+        let now = self.clock.now();
+        let delay = iops.reserve(now, 1).max(bytes.reserve(now, len as u64));
+        if !delay.is_zero() {
+            self.clock.sleep_until(now.saturating_add(delay)).await;
+        }
+    }
+}
+
+/// A limit on the rate of IOs or bytes.
+struct Bucket(Mutex<BucketState>);
+
+struct BucketState {
+    bucket: TokenBucket,
+    /// The number of reservations that had to wait.
+    throttled: u64,
+}
+
+impl Bucket {
+    fn new(limit: Option<RateLimit>) -> Self {
+        let (rate, burst) = limit.map_or((0, 0), |limit| (limit.rate, limit.burst));
+        Self(Mutex::new(BucketState {
+            bucket: TokenBucket::new(rate, burst),
+            throttled: 0,
+        }))
+    }
+
+    /// Takes `cost` units from the bucket at time `now`. Returns how long the
+    /// caller must wait before using them.
+    fn reserve(&self, now: Instant, cost: u64) -> Duration {
+        let mut state = self.0.lock();
+        let wait = state.bucket.reserve(now, cost);
+        if !wait.is_zero() {
+            state.throttled += 1;
+        }
+        wait
+    }
+}
+
+impl Inspect for Bucket {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let mut state = self.0.lock();
+        req.respond()
+            .field_mut_with("rate", |v| -> Result<_, ParseIntError> {
+                if let Some(v) = v {
+                    state.bucket.set_rate(v.parse()?);
+                }
+                Ok(state.bucket.rate())
+            })
+            .field_mut_with("burst", |v| -> Result<_, ParseIntError> {
+                if let Some(v) = v {
+                    state.bucket.set_burst(v.parse()?);
+                }
+                Ok(state.bucket.burst())
+            })
+            .counter("throttled", state.throttled);
+    }
+}
+
+impl SimpleDisk for ThrottleDisk {
+    fn disk_type(&self) -> &str {
+        "throttle"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.inner.sector_size()
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.inner.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.inner.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        self.inner.is_fua_respected()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn unmap(&self) -> Option<&dyn disk_backend::Unmap> {
+        self.inner.unmap()
+    }
+
+    fn lba_status(&self) -> Option<&dyn disk_backend::GetLbaStatus> {
+        self.inner.lba_status()
+    }
+
+    fn pr(&self) -> Option<&dyn pr::PersistentReservation> {
+        self.inner.pr()
+    }
+}
+
+impl AsyncDisk for ThrottleDisk {
+    fn read_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(async move {
+            self.throttle(&self.read_iops, &self.read_bytes, buffers.len())
+                .await;
+            self.inner.read_vectored(buffers, sector).await
+        })
+    }
+
+    fn write_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+        fua: bool,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(async move {
+            self.throttle(&self.write_iops, &self.write_bytes, buffers.len())
+                .await;
+            self.inner.write_vectored(buffers, sector, fua).await
+        })
+    }
+
+    fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        self.inner.sync_cache()
+    }
+
+    fn wait_resize<'a>(
+        &'a self,
+        sector_count: u64,
+    ) -> Pin<Box<dyn 'a + Send + Future<Output = u64>>> {
+        self.inner.wait_resize(sector_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bucket;
+    use super::ThrottleDisk;
+    use disk_backend::AsyncDisk;
+    use disk_backend_resources::RateLimit;
+    use disk_backend_resources::ThrottleLimits;
+    use disk_ramdisk::RamDisk;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use pal_async::timer::Instant;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use token_bucket::test_helpers::MockClock;
+    use token_bucket::Clock;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn bucket() {
+        let bucket = Bucket::new(Some(RateLimit {
+            rate: 100,
+            burst: 3,
+        }));
+        let now = Instant::from_nanos(1000 * MS);
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(now, 1), Duration::ZERO);
+        }
+        assert_eq!(bucket.reserve(now, 1), Duration::from_millis(10));
+        assert_eq!(bucket.0.lock().throttled, 1);
+
+        let unlimited = Bucket::new(None);
+        assert_eq!(unlimited.reserve(now, u64::MAX), Duration::ZERO);
+        assert_eq!(unlimited.0.lock().throttled, 0);
+    }
+
+    #[async_test]
+    async fn throttle_writes() {
+        let limits = ThrottleLimits {
+            write_iops: Some(RateLimit { rate: 20, burst: 2 }),
+            ..Default::default()
+        };
+        let clock = Arc::new(MockClock::default());
+        let disk = ThrottleDisk::with_clock(
+            Arc::new(RamDisk::new(1 << 20, false).unwrap()),
+            &limits,
+            clock.clone(),
+        );
+
+        let mem = GuestMemory::allocate(4096);
+        let buffers = OwnedRequestBuffers::linear(0, 4096, false);
+        for i in 0..6 {
+            disk.write_vectored(&buffers.buffer(&mem), i * 8, false)
+                .await
+                .unwrap();
+        }
+        // Four writes beyond the burst at 50ms each.
+        assert_eq!(clock.now().as_nanos(), 200 * MS);
+        assert_eq!(disk.write_iops.0.lock().throttled, 4);
+
+        // Reads are not limited.
+        let buffers = OwnedRequestBuffers::linear(0, 4096, true);
+        for i in 0..6 {
+            disk.read_vectored(&buffers.buffer(&mem), i * 8)
+                .await
+                .unwrap();
+        }
+        assert_eq!(clock.now().as_nanos(), 200 * MS);
+    }
+}
@@ -0,0 +1,871 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Dynamic and differencing VHD1 disks.
+//!
+//! A dynamic VHD stores data in fixed-size blocks that are allocated on
+//! demand at the end of the file, as described by the block allocation table
+//! (BAT). Each block is preceded by a sector bitmap recording which of its
+//! sectors have been written. Unwritten sectors read as zero for dynamic
+//! disks, and from the parent disk for differencing disks.
+
+use super::read_footer;
+use super::OpenError;
+use super::DEFAULT_PHYSICAL_SECTOR_SIZE;
+use super::DEFAULT_SECTOR_SIZE;
+use blocking::unblock;
+use disk_backend::block;
+use disk_backend::block::BlockChunk;
+use disk_backend::AsyncDisk;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_file::readwriteat::read_at_or_zero;
+use disk_file::readwriteat::read_exact_at;
+use disk_file::readwriteat::write_all_at;
+use guid::Guid;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use stackfuture::StackFuture;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Component;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use vhd1_defs::VhdDynamicHeader;
+use vhd1_defs::VhdFooter;
+use vhd1_defs::VhdParentLocator;
+use vhd1_defs::BAT_ENTRY_UNALLOCATED;
+use zerocopy::AsBytes;
+use zerocopy::FromZeroes;
+
+const SECTOR_SIZE: u64 = DEFAULT_SECTOR_SIZE as u64;
+const SECTOR_SHIFT: u32 = DEFAULT_SECTOR_SIZE.trailing_zeros();
+
+/// The smallest supported block size, so that each block's sector bitmap is a
+/// whole number of bytes.
+const MIN_BLOCK_SIZE: u32 = 8 * DEFAULT_SECTOR_SIZE;
+const MAX_BLOCK_SIZE: u32 = 256 << 20;
+/// Upper bound on the BAT size, to avoid huge allocations for corrupt files.
+const MAX_TABLE_ENTRIES: u64 = 1 << 24;
+/// Upper bound on the size of a parent locator's data.
+const MAX_LOCATOR_LEN: u32 = 64 << 10;
+
+/// The maximum number of sector bitmaps to keep cached.
+const BITMAP_CACHE_BLOCKS: usize = 256;
+
+/// The location of a differencing disk's parent, as recorded in its dynamic
+/// header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentLocation {
+    /// The unique ID of the parent disk.
+    pub unique_id: Guid,
+    /// The parent's file name.
+    pub name: String,
+    /// The paths to the parent, in the order they appear in the header.
+    pub paths: Vec<ParentPath>,
+}
+
+/// A path to a differencing disk's parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParentPath {
+    /// A Windows-style path relative to the directory containing the child.
+    Relative(String),
+    /// An absolute path.
+    Absolute(String),
+}
+
+/// Reads the parent location from a differencing VHD.
+///
+/// Returns `None` if the file is not a differencing VHD.
+pub fn read_parent_location(file: &File) -> Result<Option<ParentLocation>, OpenError> {
+    let (footer, _) = read_footer(file)?;
+    if footer.disk_type.get() != VhdFooter::DISK_TYPE_DIFFERENCING {
+        return Ok(None);
+    }
+    let header = read_dynamic_header(file, &footer)?;
+
+    let name = decode_utf16(&header.parent_unicode_name, u16::from_be_bytes);
+    let mut paths = Vec::new();
+    for locator in &header.parent_locators {
+        let code = locator.platform_code.get();
+        if code == VhdParentLocator::PLATFORM_CODE_NONE {
+            continue;
+        }
+        let len = locator.platform_data_length.get();
+        if len > MAX_LOCATOR_LEN {
+            return Err(OpenError::InvalidParentLocator);
+        }
+        let mut data = vec![0; len as usize];
+        read_exact_at(file, &mut data, locator.platform_data_offset.get())?;
+        let path = match code {
+            VhdParentLocator::PLATFORM_CODE_W2RU => {
+                ParentPath::Relative(decode_utf16(&data, u16::from_le_bytes))
+            }
+            VhdParentLocator::PLATFORM_CODE_W2KU => {
+                ParentPath::Absolute(decode_utf16(&data, u16::from_le_bytes))
+            }
+            VhdParentLocator::PLATFORM_CODE_MACX => {
+                let url = String::from_utf8(data).map_err(|_| OpenError::InvalidParentLocator)?;
+                let url = url.trim_end_matches('\0');
+                ParentPath::Absolute(url.strip_prefix("file://").unwrap_or(url).to_owned())
+            }
+            _ => continue,
+        };
+        paths.push(path);
+    }
+
+    Ok(Some(ParentLocation {
+        unique_id: header.parent_unique_id,
+        name,
+        paths,
+    }))
+}
+
+/// Decodes a NUL-terminated UTF-16 string.
+fn decode_utf16(data: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| from_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&chars)
+}
+
+fn read_dynamic_header(file: &File, footer: &VhdFooter) -> Result<VhdDynamicHeader, OpenError> {
+    let mut header = VhdDynamicHeader::new_zeroed();
+    read_exact_at(file, header.as_bytes_mut(), footer.data_offset.get())?;
+    if header.cookie != VhdDynamicHeader::COOKIE_MAGIC {
+        return Err(OpenError::InvalidDynamicHeaderCookie);
+    }
+    if header.checksum.get() != header.compute_checksum() {
+        return Err(OpenError::InvalidDynamicHeaderChecksum);
+    }
+    if header.header_version.get() != VhdDynamicHeader::HEADER_VERSION {
+        return Err(OpenError::UnsupportedVersion(header.header_version.get()));
+    }
+    Ok(header)
+}
+
+/// Converts a time to a VHD time stamp, which counts seconds since January 1,
+/// 2000 UTC.
+fn vhd_time_stamp(time: SystemTime) -> Option<u32> {
+    const VHD_EPOCH: Duration = Duration::from_secs(946684800);
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH + VHD_EPOCH)
+        .ok()?
+        .as_secs();
+    secs.try_into().ok()
+}
+
+/// The parent information to write into a new differencing disk.
+struct NewParent {
+    unique_id: Guid,
+    time_stamp: u32,
+    name: String,
+    locators: Vec<(u32, Vec<u8>)>,
+}
+
+/// An open dynamic or differencing VHD1 disk.
+pub struct DynamicVhd1Disk {
+    inner: Arc<Inner>,
+    geometry: Geometry,
+    parent: Option<Arc<dyn SimpleDisk>>,
+    read_only: bool,
+    unique_id: Guid,
+}
+
+struct Inner {
+    file: File,
+    geometry: Geometry,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Geometry {
+    disk_size: u64,
+    block_bits: u32,
+    block_size: u64,
+    /// The size of the sector bitmap that precedes each block's data,
+    /// including padding.
+    bitmap_size: u64,
+    differencing: bool,
+}
+
+impl Geometry {
+    fn new(disk_size: u64, block_size: u32, differencing: bool) -> Self {
+        let block_size = block_size as u64;
+        Self {
+            disk_size,
+            block_bits: block_size.trailing_zeros(),
+            block_size,
+            bitmap_size: (block_size >> SECTOR_SHIFT)
+                .div_ceil(8)
+                .next_multiple_of(SECTOR_SIZE),
+            differencing,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk_size.div_ceil(self.block_size)
+    }
+
+    /// The number of bytes of the sector bitmap that are in use.
+    fn bitmap_len(&self) -> usize {
+        ((self.block_size >> SECTOR_SHIFT) / 8) as usize
+    }
+
+    /// Splits the guest byte range `offset..offset + len` into per-block
+    /// chunks.
+    fn chunks(&self, offset: u64, len: usize) -> impl Iterator<Item = BlockChunk> {
+        block::chunks(offset, len, self.block_bits)
+    }
+}
+
+/// Returns the byte index and mask for `sector` in a sector bitmap. Bitmaps
+/// are stored most significant bit first.
+fn bitmap_bit(sector: u64) -> (usize, u8) {
+    ((sector / 8) as usize, 0x80 >> (sector % 8))
+}
+
+/// Mutable disk metadata. Caches are write-through, so they can be dropped at
+/// any time.
+struct State {
+    geometry: Geometry,
+    bat: Vec<u32>,
+    bat_offset: u64,
+    bitmap_cache: HashMap<usize, Box<[u8]>>,
+    /// The footer, which is moved to the end of the file each time a block is
+    /// allocated.
+    footer: VhdFooter,
+    /// The file offset of the footer, which is where the next block is
+    /// allocated.
+    footer_offset: u64,
+}
+
+impl State {
+    /// Returns the file offset of `block`'s sector bitmap, if the block is
+    /// allocated.
+    fn block_offset(&self, block: usize) -> Option<u64> {
+        let entry = self.bat[block];
+        (entry != BAT_ENTRY_UNALLOCATED).then_some((entry as u64) << SECTOR_SHIFT)
+    }
+
+    fn bitmap(&mut self, file: &File, block: usize, host: u64) -> io::Result<&mut [u8]> {
+        if !self.bitmap_cache.contains_key(&block) {
+            let mut bitmap = vec![0; self.geometry.bitmap_len()];
+            read_at_or_zero(file, &mut bitmap, host)?;
+            self.cache_bitmap(block, bitmap.into());
+        }
+        Ok(self.bitmap_cache.get_mut(&block).unwrap())
+    }
+
+    fn cache_bitmap(&mut self, block: usize, bitmap: Box<[u8]>) {
+        if self.bitmap_cache.len() >= BITMAP_CACHE_BLOCKS {
+            self.bitmap_cache.clear();
+        }
+        self.bitmap_cache.insert(block, bitmap);
+    }
+
+    /// Allocates a new block at the end of the file, returning the offset of
+    /// its sector bitmap.
+    fn allocate_block(&mut self, file: &File, block: usize) -> io::Result<u64> {
+        let host = self.footer_offset;
+        let entry = u32::try_from(host >> SECTOR_SHIFT)
+            .ok()
+            .filter(|&entry| entry != BAT_ENTRY_UNALLOCATED)
+            .ok_or_else(|| io::Error::other("vhd file is too large"))?;
+        let footer_offset = host + self.geometry.bitmap_size + self.geometry.block_size;
+
+        // Move the footer first so that the file remains valid if the
+        // allocation is interrupted. Then clear the bitmap, which overwrites
+        // the old footer; the block data past it is implicitly zero.
+        write_all_at(file, self.footer.as_bytes(), footer_offset)?;
+        self.footer_offset = footer_offset;
+        write_all_at(file, &vec![0; self.geometry.bitmap_size as usize], host)?;
+        write_all_at(
+            file,
+            &entry.to_be_bytes(),
+            self.bat_offset + block as u64 * 4,
+        )?;
+        self.bat[block] = entry;
+        self.cache_bitmap(block, vec![0; self.geometry.bitmap_len()].into());
+        Ok(host)
+    }
+
+    /// Marks `sectors` of `block` as present in the file.
+    fn set_bitmap_bits(
+        &mut self,
+        file: &File,
+        block: usize,
+        host: u64,
+        sectors: Range<u64>,
+    ) -> io::Result<()> {
+        let bitmap = self.bitmap(file, block, host)?;
+        let mut changed = false;
+        for sector in sectors.clone() {
+            let (byte, mask) = bitmap_bit(sector);
+            changed |= bitmap[byte] & mask == 0;
+            bitmap[byte] |= mask;
+        }
+        if changed {
+            let bytes = bitmap_bit(sectors.start).0..bitmap_bit(sectors.end - 1).0 + 1;
+            let r = write_all_at(file, &bitmap[bytes.clone()], host + bytes.start as u64);
+            if r.is_err() {
+                // The cached bitmap no longer matches the file.
+                self.bitmap_cache.remove(&block);
+            }
+            r?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// Reads the present sectors of the guest range from the file, returning
+    /// the data and the ranges of the buffer that must be read from the parent
+    /// disk. For dynamic disks, these ranges read as zero.
+    fn read(&self, offset: u64, len: usize) -> io::Result<(Vec<u8>, Vec<Range<usize>>)> {
+        let mut data = vec![0; len];
+        let mut absent = Vec::new();
+        let mut reads: Vec<(Range<usize>, u64)> = Vec::new();
+        {
+            let mut state = self.state.lock();
+            for chunk in self.geometry.chunks(offset, len) {
+                let block = chunk.block as usize;
+                let Some(host) = state.block_offset(block) else {
+                    block::push_range(&mut absent, chunk.range);
+                    continue;
+                };
+                let bitmap = state.bitmap(&self.file, block, host)?;
+                let first_sector = chunk.block_offset >> SECTOR_SHIFT;
+                for (i, start) in chunk.range.step_by(SECTOR_SIZE as usize).enumerate() {
+                    let sector = first_sector + i as u64;
+                    let range = start..start + SECTOR_SIZE as usize;
+                    let (byte, mask) = bitmap_bit(sector);
+                    if bitmap[byte] & mask == 0 {
+                        block::push_range(&mut absent, range);
+                        continue;
+                    }
+                    let host = host + self.geometry.bitmap_size + (sector << SECTOR_SHIFT);
+                    match reads.last_mut() {
+                        Some((last, last_host))
+                            if last.end == range.start
+                                && *last_host + last.len() as u64 == host =>
+                        {
+                            last.end = range.end;
+                        }
+                        _ => reads.push((range, host)),
+                    }
+                }
+            }
+        }
+        for (range, host) in reads {
+            read_at_or_zero(&self.file, &mut data[range], host)?;
+        }
+        Ok((data, absent))
+    }
+
+    /// Writes `data` at guest `offset`, allocating blocks as needed.
+    fn write(&self, offset: u64, data: &[u8], fua: bool) -> io::Result<()> {
+        for chunk in self.geometry.chunks(offset, data.len()) {
+            let block = chunk.block as usize;
+            let host = {
+                let mut state = self.state.lock();
+                match state.block_offset(block) {
+                    Some(host) => host,
+                    None => state.allocate_block(&self.file, block)?,
+                }
+            };
+            write_all_at(
+                &self.file,
+                &data[chunk.range.clone()],
+                host + self.geometry.bitmap_size + chunk.block_offset,
+            )?;
+            // Mark the sectors present only once the data has been written.
+            let first_sector = chunk.block_offset >> SECTOR_SHIFT;
+            let sectors = first_sector..first_sector + (chunk.range.len() as u64 >> SECTOR_SHIFT);
+            self.state
+                .lock()
+                .set_bitmap_bits(&self.file, block, host, sectors)?;
+        }
+        if fua {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl DynamicVhd1Disk {
+    /// Creates a new, empty dynamic VHD of `disk_size` bytes in `file`, with
+    /// blocks of `block_size` bytes.
+    pub fn create(file: &File, disk_size: u64, block_size: u32) -> Result<(), OpenError> {
+        Self::create_inner(file, disk_size, block_size, None)
+    }
+
+    /// Creates a new, empty differencing VHD in `file` whose parent is the VHD
+    /// in `parent`, with blocks of `block_size` bytes.
+    ///
+    /// `parent_path` is recorded in the parent locators. If it is relative, it
+    /// must be relative to the directory containing `file`.
+    pub fn create_differencing(
+        file: &File,
+        parent: &File,
+        parent_path: &Path,
+        block_size: u32,
+    ) -> Result<(), OpenError> {
+        let (parent_footer, _) = read_footer(parent)?;
+        let time_stamp = parent
+            .metadata()?
+            .modified()
+            .ok()
+            .and_then(vhd_time_stamp)
+            .unwrap_or(0);
+
+        let name = parent_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(OpenError::InvalidParentPath)?
+            .to_owned();
+
+        let mut locators = Vec::new();
+        if parent_path.is_relative() {
+            let components = parent_path
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => Some(c.to_str()),
+                    Component::ParentDir => Some(Some("..")),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or(OpenError::InvalidParentPath)?;
+            let path = format!(".\\{}", components.join("\\"));
+            locators.push((VhdParentLocator::PLATFORM_CODE_W2RU, encode_utf16le(&path)));
+        } else {
+            let path = parent_path.to_str().ok_or(OpenError::InvalidParentPath)?;
+            if cfg!(windows) {
+                locators.push((VhdParentLocator::PLATFORM_CODE_W2KU, encode_utf16le(path)));
+            } else {
+                locators.push((
+                    VhdParentLocator::PLATFORM_CODE_MACX,
+                    format!("file://{path}").into_bytes(),
+                ));
+            }
+        }
+
+        Self::create_inner(
+            file,
+            parent_footer.current_size.get(),
+            block_size,
+            Some(NewParent {
+                unique_id: parent_footer.unique_id,
+                time_stamp,
+                name,
+                locators,
+            }),
+        )
+    }
+
+    fn create_inner(
+        file: &File,
+        disk_size: u64,
+        block_size: u32,
+        parent: Option<NewParent>,
+    ) -> Result<(), OpenError> {
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        if disk_size == 0 || disk_size % SECTOR_SIZE != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let geometry = Geometry::new(disk_size, block_size, parent.is_some());
+        let block_count = geometry.block_count();
+        if block_count > MAX_TABLE_ENTRIES {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+
+        // Lay out the footer copy, the dynamic header, the BAT, and then the
+        // parent locator data. Blocks are allocated after the locator data.
+        let header_offset = VhdFooter::LEN;
+        let table_offset = header_offset + VhdDynamicHeader::LEN;
+        let table_size = (block_count * 4).next_multiple_of(SECTOR_SIZE);
+        let mut offset = table_offset + table_size;
+
+        let mut header = VhdDynamicHeader {
+            cookie: VhdDynamicHeader::COOKIE_MAGIC,
+            data_offset: VhdDynamicHeader::DATA_OFFSET.into(),
+            table_offset: table_offset.into(),
+            header_version: VhdDynamicHeader::HEADER_VERSION.into(),
+            max_table_entries: (block_count as u32).into(),
+            block_size: block_size.into(),
+            ..FromZeroes::new_zeroed()
+        };
+        let mut locator_data = Vec::new();
+        let disk_type = if let Some(parent) = &parent {
+            header.parent_unique_id = parent.unique_id;
+            header.parent_time_stamp = parent.time_stamp.into();
+            for (dest, c) in header
+                .parent_unicode_name
+                .chunks_exact_mut(2)
+                .zip(parent.name.encode_utf16())
+            {
+                dest.copy_from_slice(&c.to_be_bytes());
+            }
+            for (locator, (code, data)) in header.parent_locators.iter_mut().zip(&parent.locators) {
+                let space = (data.len() as u64).next_multiple_of(SECTOR_SIZE);
+                // Hyper-V records the locator space in bytes, despite the
+                // specification calling for sectors.
+                *locator = VhdParentLocator {
+                    platform_code: (*code).into(),
+                    platform_data_space: (space as u32).into(),
+                    platform_data_length: (data.len() as u32).into(),
+                    reserved: 0u32.into(),
+                    platform_data_offset: offset.into(),
+                };
+                locator_data.push((offset, data));
+                offset += space;
+            }
+            VhdFooter::DISK_TYPE_DIFFERENCING
+        } else {
+            VhdFooter::DISK_TYPE_DYNAMIC
+        };
+        header.checksum = header.compute_checksum().into();
+
+        let footer =
+            VhdFooter::new_dynamic(disk_size, Guid::new_random(), disk_type, header_offset);
+
+        file.set_len(0)?;
+        write_all_at(file, footer.as_bytes(), 0)?;
+        write_all_at(file, header.as_bytes(), header_offset)?;
+        write_all_at(file, &vec![0xff; table_size as usize], table_offset)?;
+        for (data_offset, data) in locator_data {
+            write_all_at(file, data, data_offset)?;
+        }
+        write_all_at(file, footer.as_bytes(), offset)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Opens a dynamic or differencing VHD.
+    ///
+    /// `parent` must be provided if and only if the disk is a differencing
+    /// disk. It is only read from.
+    pub fn open(
+        file: File,
+        parent: Option<Arc<dyn SimpleDisk>>,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let (footer, file_len) = read_footer(&file)?;
+        let differencing = match footer.disk_type.get() {
+            VhdFooter::DISK_TYPE_DYNAMIC => false,
+            VhdFooter::DISK_TYPE_DIFFERENCING => true,
+            disk_type => return Err(OpenError::UnsupportedDiskType(disk_type)),
+        };
+        let header = read_dynamic_header(&file, &footer)?;
+
+        let disk_size = footer.current_size.get();
+        if disk_size == 0 || disk_size % SECTOR_SIZE != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let block_size = header.block_size.get();
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        let geometry = Geometry::new(disk_size, block_size, differencing);
+        let block_count = geometry.block_count();
+        let max_table_entries = header.max_table_entries.get() as u64;
+        if max_table_entries < block_count || block_count > MAX_TABLE_ENTRIES {
+            return Err(OpenError::InvalidBlockTable);
+        }
+
+        let bat_offset = header.table_offset.get();
+        let mut bat_bytes = vec![0; block_count as usize * 4];
+        read_exact_at(&file, &mut bat_bytes, bat_offset)?;
+        let bat = bat_bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        let footer_offset = file_len - VhdFooter::LEN;
+        if bat.iter().any(|&entry| {
+            entry != BAT_ENTRY_UNALLOCATED
+                && ((entry as u64) << SECTOR_SHIFT) + geometry.bitmap_size > footer_offset
+        }) {
+            return Err(OpenError::InvalidBlockTable);
+        }
+
+        match &parent {
+            None if differencing => return Err(OpenError::MissingParent),
+            Some(_) if !differencing => return Err(OpenError::UnexpectedParent),
+            Some(parent) => {
+                if parent
+                    .disk_id()
+                    .is_some_and(|id| id != <[u8; 16]>::from(header.parent_unique_id))
+                {
+                    return Err(OpenError::ParentMismatch);
+                }
+            }
+            None => {}
+        }
+
+        let state = State {
+            geometry,
+            bat,
+            bat_offset,
+            bitmap_cache: HashMap::new(),
+            footer,
+            footer_offset,
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                file,
+                geometry,
+                state: Mutex::new(state),
+            }),
+            geometry,
+            parent,
+            read_only,
+            unique_id: footer.unique_id,
+        })
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), DiskError> {
+        block::check_range(offset, len, self.geometry.disk_size)
+    }
+
+    async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        let offset = sector << SECTOR_SHIFT;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let inner = self.inner.clone();
+        let (data, absent) = unblock(move || inner.read(offset, len))
+            .await
+            .map_err(DiskError::Io)?;
+        buffers.writer().write(&data)?;
+
+        if let Some(parent) = &self.parent {
+            let parent_size = parent.sector_count() << SECTOR_SHIFT;
+            for range in absent {
+                let start = offset + range.start as u64;
+                if start >= parent_size {
+                    continue;
+                }
+                let len = range.len().min((parent_size - start) as usize);
+                parent
+                    .read_vectored(&buffers.subrange(range.start, len), start >> SECTOR_SHIFT)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = sector << SECTOR_SHIFT;
+        self.check_range(offset, buffers.len())?;
+        let data = buffers.reader().read_all()?;
+        let inner = self.inner.clone();
+        unblock(move || inner.write(offset, &data, fua))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn flush(&self) -> Result<(), DiskError> {
+        let inner = self.inner.clone();
+        unblock(move || inner.file.sync_all())
+            .await
+            .map_err(DiskError::Io)
+    }
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+impl Inspect for DynamicVhd1Disk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("unique_id", self.unique_id)
+            .field("differencing", self.geometry.differencing)
+            .hex("block_size", self.geometry.block_size)
+            .field("disk_size", self.geometry.disk_size)
+            .field("read_only", self.read_only)
+            .field_with("allocated_blocks", || {
+                let state = self.inner.state.lock();
+                state
+                    .bat
+                    .iter()
+                    .filter(|&&entry| entry != BAT_ENTRY_UNALLOCATED)
+                    .count()
+            })
+            .field("parent", &self.parent);
+    }
+}
+
+impl SimpleDisk for DynamicVhd1Disk {
+    fn disk_type(&self) -> &str {
+        "vhd1"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geometry.disk_size >> SECTOR_SHIFT
+    }
+
+    fn sector_size(&self) -> u32 {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        Some(self.unique_id.into())
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        DEFAULT_PHYSICAL_SECTOR_SIZE
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+}
+
+impl AsyncDisk for DynamicVhd1Disk {
+    fn read_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.read(buffers, sector))
+    }
+
+    fn write_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+        fua: bool,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.write(buffers, sector, fua))
+    }
+
+    fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bitmap_bit;
+    use super::DynamicVhd1Disk;
+    use super::ParentPath;
+    use super::SECTOR_SIZE;
+    use crate::Vhd1Disk;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::SimpleDisk;
+    use pal_async::async_test;
+    use std::path::Path;
+    use std::sync::Arc;
+    use vhd1_defs::VhdDynamicHeader;
+
+    const SECTOR_USIZE: usize = SECTOR_SIZE as usize;
+
+    #[async_test]
+    async fn dynamic_write_read_reopen() {
+        let file = tempfile::tempfile().unwrap();
+        DynamicVhd1Disk::create(&file, 64 << 20, 1 << 20).unwrap();
+        let disk = DynamicVhd1Disk::open(file.try_clone().unwrap(), None, false).unwrap();
+        let initial_len = file.metadata().unwrap().len();
+
+        assert_eq!(read(&disk, 100, 4).await, vec![0; 4 * SECTOR_USIZE]);
+        // Write across a block boundary.
+        write(&disk, 2046, &pattern(2046, 4, 1)).await;
+        for i in 0..100 {
+            write(&disk, i * 1237, &pattern(i * 1237, 3, 2)).await;
+        }
+        assert!(file.metadata().unwrap().len() > initial_len);
+        drop(disk);
+
+        let disk = DynamicVhd1Disk::open(file, None, true).unwrap();
+        for i in 0..100 {
+            assert_eq!(read(&disk, i * 1237, 3).await, pattern(i * 1237, 3, 2));
+        }
+        assert_eq!(read(&disk, 2046, 4).await, pattern(2046, 4, 1));
+        // Unwritten sectors in an allocated block read as zero.
+        assert_eq!(read(&disk, 2050, 1).await, vec![0; SECTOR_USIZE]);
+    }
+
+    #[async_test]
+    async fn differencing_read_through() {
+        const SIZE: u64 = 4 << 20;
+        let dir = tempfile::tempdir().unwrap();
+        let parent_path = dir.path().join("parent.vhd");
+        let parent_file = std::fs::File::create_new(&parent_path).unwrap();
+        parent_file.set_len(SIZE).unwrap();
+        let parent = Vhd1Disk::make_fixed(&parent_file)
+            .and_then(|()| Vhd1Disk::open_fixed(parent_file.try_clone().unwrap(), false))
+            .unwrap();
+        write(&parent, 0, &pattern(0, SIZE as usize / SECTOR_USIZE, 7)).await;
+
+        let child_file = tempfile::tempfile().unwrap();
+        DynamicVhd1Disk::create_differencing(
+            &child_file,
+            &parent_file,
+            Path::new("parent.vhd"),
+            VhdDynamicHeader::DEFAULT_BLOCK_SIZE,
+        )
+        .unwrap();
+        let location = super::read_parent_location(&child_file).unwrap().unwrap();
+        assert_eq!(
+            <[u8; 16]>::from(location.unique_id),
+            parent.disk_id().unwrap()
+        );
+        assert_eq!(location.name, "parent.vhd");
+        assert_eq!(
+            location.paths,
+            [ParentPath::Relative(".\\parent.vhd".into())]
+        );
+
+        assert!(matches!(
+            DynamicVhd1Disk::open(child_file.try_clone().unwrap(), None, false),
+            Err(crate::OpenError::MissingParent)
+        ));
+        let disk = DynamicVhd1Disk::open(child_file, Some(Arc::new(parent)), false).unwrap();
+        assert_eq!(read(&disk, 10, 20).await, pattern(10, 20, 7));
+
+        // Partially overwrite a block. Only the written sectors are marked
+        // present in the block's bitmap; the rest still come from the parent.
+        write(&disk, 9, &pattern(9, 2, 3)).await;
+        {
+            let mut state = disk.inner.state.lock();
+            let host = state.block_offset(0).unwrap();
+            let bitmap = state.bitmap(&disk.inner.file, 0, host).unwrap();
+            for sector in 0..16 {
+                let (byte, mask) = bitmap_bit(sector);
+                assert_eq!(bitmap[byte] & mask != 0, (9..11).contains(&sector));
+            }
+        }
+        let data = read(&disk, 8, 8).await;
+        assert_eq!(data[..SECTOR_USIZE], pattern(8, 1, 7));
+        assert_eq!(data[SECTOR_USIZE..3 * SECTOR_USIZE], pattern(9, 2, 3));
+        assert_eq!(data[3 * SECTOR_USIZE..], pattern(11, 5, 7));
+    }
+}
@@ -1,17 +1,28 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! A VHD1 disk implementation. Currently only supports fixed VHD1.
+//! A VHD1 disk implementation, supporting fixed, dynamic, and differencing
+//! disks.
 
 #![forbid(unsafe_code)]
 
+mod dynamic;
+
+pub use dynamic::read_parent_location;
+pub use dynamic::DynamicVhd1Disk;
+pub use dynamic::ParentLocation;
+pub use dynamic::ParentPath;
+
+use async_trait::async_trait;
 use disk_backend::resolve::ResolveDiskParameters;
 use disk_backend::resolve::ResolvedSimpleDisk;
 use disk_backend::AsyncDisk;
 use disk_backend::DiskError;
 use disk_backend::SimpleDisk;
 use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_backend_resources::DynamicVhd1DiskHandle;
 use disk_backend_resources::FixedVhd1DiskHandle;
+use disk_file::readwriteat::read_exact_at;
 use disk_file::FileDisk;
 use guid::Guid;
 use inspect::Inspect;
@@ -19,13 +30,15 @@
 use stackfuture::StackFuture;
 use std::fs::File;
 use std::io;
-use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
 use vhd1_defs::VhdFooter;
+use vm_resource::declare_static_async_resolver;
 use vm_resource::declare_static_resolver;
 use vm_resource::kind::DiskHandleKind;
+use vm_resource::AsyncResolveResource;
 use vm_resource::ResolveResource;
+use vm_resource::ResourceResolver;
 use zerocopy::AsBytes;
 use zerocopy::FromZeroes;
 
@@ -46,6 +59,38 @@ fn resolve(
     }
 }
 
+pub struct DynamicVhd1Resolver;
+declare_static_async_resolver!(DynamicVhd1Resolver, (DiskHandleKind, DynamicVhd1DiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, DynamicVhd1DiskHandle> for DynamicVhd1Resolver {
+    type Output = ResolvedSimpleDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: DynamicVhd1DiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let parent = if let Some(parent) = rsrc.parent {
+            let parent = resolver
+                .resolve(
+                    parent,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await?;
+            Some(parent.0)
+        } else {
+            None
+        };
+        Ok(DynamicVhd1Disk::open(rsrc.file, parent, input.read_only)?.into())
+    }
+}
+
 /// An open VHD1 disk.
 #[derive(Debug, Inspect)]
 pub struct Vhd1Disk {
@@ -65,20 +110,8 @@ struct Metadata {
 }
 
 impl Metadata {
-    /// Parses the essential metadata out of the footer.
+    /// Parses the essential metadata out of the footer of a fixed VHD.
     fn from_footer(footer: VhdFooter, file_size: u64) -> Result<Metadata, OpenError> {
-        if footer.cookie != VhdFooter::COOKIE_MAGIC {
-            return Err(OpenError::InvalidFooterCookie);
-        }
-        if footer.checksum != footer.compute_checksum().to_be_bytes() {
-            return Err(OpenError::InvalidFooterChecksum);
-        }
-        if footer.file_format_version != VhdFooter::FILE_FORMAT_VERSION_MAGIC.to_be_bytes() {
-            return Err(OpenError::UnsupportedVersion(
-                footer.file_format_version.into(),
-            ));
-        }
-        // FUTURE: support parsing non-fixed VHDs.
         if footer.disk_type != VhdFooter::DISK_TYPE_FIXED.to_be_bytes() {
             return Err(OpenError::NotFixed);
         }
@@ -97,6 +130,29 @@ fn from_footer(footer: VhdFooter, file_size: u64) -> Result<Metadata, OpenError>
     }
 }
 
+/// Reads and validates the footer at the end of `file`, returning it along
+/// with the file length.
+fn read_footer(file: &File) -> Result<(VhdFooter, u64), OpenError> {
+    let len = file.metadata()?.len();
+    if len < VhdFooter::LEN || len % VhdFooter::ALIGNMENT != 0 {
+        return Err(OpenError::InvalidFileSize(len));
+    }
+    let mut footer: VhdFooter = FromZeroes::new_zeroed();
+    read_exact_at(file, footer.as_bytes_mut(), len - VhdFooter::LEN)?;
+    if footer.cookie != VhdFooter::COOKIE_MAGIC {
+        return Err(OpenError::InvalidFooterCookie);
+    }
+    if footer.checksum != footer.compute_checksum().to_be_bytes() {
+        return Err(OpenError::InvalidFooterChecksum);
+    }
+    if footer.file_format_version != VhdFooter::FILE_FORMAT_VERSION_MAGIC.to_be_bytes() {
+        return Err(OpenError::UnsupportedVersion(
+            footer.file_format_version.into(),
+        ));
+    }
+    Ok((footer, len))
+}
+
 /// An error encountered while opening a VHD.
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -115,6 +171,26 @@ pub enum OpenError {
     UnsupportedVersion(u32),
     #[error("not a fixed VHD")]
     NotFixed,
+    #[error("unsupported VHD disk type: {0}")]
+    UnsupportedDiskType(u32),
+    #[error("VHD dynamic header is missing")]
+    InvalidDynamicHeaderCookie,
+    #[error("invalid VHD dynamic header checksum")]
+    InvalidDynamicHeaderChecksum,
+    #[error("invalid VHD block size: {0:#x}")]
+    InvalidBlockSize(u32),
+    #[error("invalid VHD block allocation table")]
+    InvalidBlockTable,
+    #[error("invalid VHD parent locator")]
+    InvalidParentLocator,
+    #[error("parent path cannot be stored in a VHD")]
+    InvalidParentPath,
+    #[error("differencing VHD opened without its parent")]
+    MissingParent,
+    #[error("parent provided for a VHD that is not a differencing disk")]
+    UnexpectedParent,
+    #[error("parent disk ID does not match the differencing VHD")]
+    ParentMismatch,
 }
 
 impl Vhd1Disk {
@@ -131,15 +207,8 @@ pub fn make_fixed(mut file: &File) -> Result<(), OpenError> {
     }
 
     /// Opens a fixed VHD.
-    pub fn open_fixed(mut file: File, read_only: bool) -> Result<Self, OpenError> {
-        let meta = file.metadata()?;
-        let len = meta.len();
-        if len < VhdFooter::LEN || len % VhdFooter::ALIGNMENT != 0 {
-            return Err(OpenError::InvalidFileSize(len));
-        }
-        file.seek(io::SeekFrom::End(-512))?;
-        let mut footer: VhdFooter = FromZeroes::new_zeroed();
-        file.read_exact(footer.as_bytes_mut())?;
+    pub fn open_fixed(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let (footer, len) = read_footer(&file)?;
         let metadata = Metadata::from_footer(footer, len)?;
 
         // Just wrap FileDisk for handling actual IO.
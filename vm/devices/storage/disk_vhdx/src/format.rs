@@ -0,0 +1,300 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VHDX on-disk format definitions.
+//!
+//! See the Microsoft VHDX format specification, version 1.00. All multi-byte
+//! fields are little endian.
+
+use guid::Guid;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+pub const KB: u64 = 1024;
+pub const MB: u64 = 1024 * KB;
+
+/// The file type identifier, at offset 0.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct FileIdentifier {
+    pub signature: u64,
+    /// The name of the creating application, in UTF-16.
+    pub creator: [u8; 512],
+}
+
+impl FileIdentifier {
+    pub const OFFSET: u64 = 0;
+    pub const SIGNATURE: u64 = u64::from_le_bytes(*b"vhdxfile");
+}
+
+/// One of the two file headers. The valid header with the largest sequence
+/// number is current.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct Header {
+    pub signature: u32,
+    pub checksum: u32,
+    pub sequence_number: u64,
+    pub file_write_guid: Guid,
+    pub data_write_guid: Guid,
+    pub log_guid: Guid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+    pub reserved: [u8; 4016],
+}
+
+impl Header {
+    pub const SIGNATURE: u32 = u32::from_le_bytes(*b"head");
+    pub const OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+    pub const VERSION: u16 = 1;
+    pub const LOG_VERSION: u16 = 0;
+}
+
+/// The header of one of the two identical region tables.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct RegionTableHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_count: u32,
+    pub reserved: u32,
+}
+
+impl RegionTableHeader {
+    pub const SIGNATURE: u32 = u32::from_le_bytes(*b"regi");
+    pub const OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+    /// The size of a region table, over which the checksum is computed.
+    pub const LEN: usize = 64 * KB as usize;
+    pub const MAX_ENTRIES: u32 = 2047;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct RegionTableEntry {
+    pub guid: Guid,
+    pub file_offset: u64,
+    pub length: u32,
+    pub required: u32,
+}
+
+pub const BAT_REGION: Guid = Guid::from_static_str("2dc27766-f623-4200-9d64-115e9bfd4a08");
+pub const METADATA_REGION: Guid = Guid::from_static_str("8b7ca206-4790-4b9a-b8fe-575f050f886e");
+
+/// The header of the metadata table, at the start of the metadata region.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct MetadataTableHeader {
+    pub signature: u64,
+    pub reserved: u16,
+    pub entry_count: u16,
+    pub reserved2: [u32; 5],
+}
+
+impl MetadataTableHeader {
+    pub const SIGNATURE: u64 = u64::from_le_bytes(*b"metadata");
+    /// The size of the metadata table. Metadata items start after it.
+    pub const LEN: u64 = 64 * KB;
+    pub const MAX_ENTRIES: u16 = 2047;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct MetadataTableEntry {
+    pub item_id: Guid,
+    /// The offset of the item relative to the start of the metadata region.
+    pub offset: u32,
+    pub length: u32,
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+/// Metadata table entry flags.
+pub mod metadata_flags {
+    pub const IS_VIRTUAL_DISK: u32 = 1 << 1;
+    pub const IS_REQUIRED: u32 = 1 << 2;
+}
+
+pub const FILE_PARAMETERS: Guid = Guid::from_static_str("caa16737-fa36-4d43-b3b6-33f0aa44e76b");
+pub const VIRTUAL_DISK_SIZE: Guid = Guid::from_static_str("2fa54224-cd1b-4876-b211-5dbed83bf4b8");
+pub const VIRTUAL_DISK_ID: Guid = Guid::from_static_str("beca12ab-b2e6-4523-93ef-c309e000c746");
+pub const LOGICAL_SECTOR_SIZE: Guid = Guid::from_static_str("8141bf1d-a96f-4709-ba47-f233a8faab5f");
+pub const PHYSICAL_SECTOR_SIZE: Guid =
+    Guid::from_static_str("cda348c7-445d-4471-9cc9-e9885251c556");
+pub const PARENT_LOCATOR: Guid = Guid::from_static_str("a8d35f2d-b30b-454d-abf7-d3d84834ab0c");
+
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct FileParameters {
+    pub block_size: u32,
+    pub flags: u32,
+}
+
+impl FileParameters {
+    pub const HAS_PARENT: u32 = 1 << 1;
+
+    pub const MIN_BLOCK_SIZE: u32 = MB as u32;
+    pub const MAX_BLOCK_SIZE: u32 = 256 * MB as u32;
+}
+
+/// The header of the parent locator metadata item, followed by
+/// `key_value_count` [`ParentLocatorEntry`]s.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct ParentLocatorHeader {
+    pub locator_type: Guid,
+    pub reserved: u16,
+    pub key_value_count: u16,
+}
+
+pub const VHDX_PARENT_LOCATOR_TYPE: Guid =
+    Guid::from_static_str("b04aefb7-d19e-4a81-b789-25b8e9445913");
+
+/// A key-value pair in the parent locator. The keys and values are UTF-16
+/// strings, at offsets relative to the start of the parent locator item.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct ParentLocatorEntry {
+    pub key_offset: u32,
+    pub value_offset: u32,
+    pub key_length: u16,
+    pub value_length: u16,
+}
+
+/// Parent locator keys.
+pub mod parent_keys {
+    pub const PARENT_LINKAGE: &str = "parent_linkage";
+    pub const PARENT_LINKAGE2: &str = "parent_linkage2";
+    pub const RELATIVE_PATH: &str = "relative_path";
+    pub const VOLUME_PATH: &str = "volume_path";
+    pub const ABSOLUTE_WIN32_PATH: &str = "absolute_win32_path";
+}
+
+/// The number of sectors described by each sector bitmap block.
+pub const SECTORS_PER_BITMAP_BLOCK: u64 = 1 << 23;
+/// The size of a sector bitmap block, in bytes.
+pub const SECTOR_BITMAP_BLOCK_SIZE: u64 = SECTORS_PER_BITMAP_BLOCK / 8;
+
+/// The state of a BAT entry, in its low bits.
+pub const BAT_STATE_MASK: u64 = 0x7;
+/// The file offset of a BAT entry's block, in MB.
+pub const BAT_FILE_OFFSET_SHIFT: u32 = 20;
+
+/// Payload block states.
+pub mod payload_state {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const UNDEFINED: u64 = 1;
+    pub const ZERO: u64 = 2;
+    pub const UNMAPPED: u64 = 3;
+    pub const FULLY_PRESENT: u64 = 6;
+    pub const PARTIALLY_PRESENT: u64 = 7;
+}
+
+/// Sector bitmap block states.
+pub mod bitmap_state {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const PRESENT: u64 = 6;
+}
+
+/// The header of a log entry.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct LogEntryHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_length: u32,
+    pub tail: u32,
+    pub sequence_number: u64,
+    pub descriptor_count: u32,
+    pub reserved: u32,
+    pub log_guid: Guid,
+    pub flushed_file_offset: u64,
+    pub last_file_offset: u64,
+}
+
+impl LogEntryHeader {
+    pub const SIGNATURE: u32 = u32::from_le_bytes(*b"loge");
+}
+
+/// The size of log sectors, and of the file sectors they describe.
+pub const LOG_SECTOR_SIZE: u64 = 4 * KB;
+
+/// A log descriptor. Data descriptors describe a 4KB sector of the file
+/// whose contents are split between the descriptor and a [`LogDataSector`];
+/// zero descriptors describe a range of the file to zero.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct LogDescriptor {
+    pub signature: u32,
+    /// For data descriptors, the last 4 bytes of the sector.
+    pub trailing_bytes: u32,
+    /// For data descriptors, the first 8 bytes of the sector. For zero
+    /// descriptors, the length to zero.
+    pub leading_bytes: u64,
+    pub file_offset: u64,
+    pub sequence_number: u64,
+}
+
+impl LogDescriptor {
+    pub const DATA_SIGNATURE: u32 = u32::from_le_bytes(*b"desc");
+    pub const ZERO_SIGNATURE: u32 = u32::from_le_bytes(*b"zero");
+}
+
+/// The middle of a sector written by a data descriptor.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct LogDataSector {
+    pub signature: u32,
+    pub sequence_high: u32,
+    pub data: [u8; 4084],
+    pub sequence_low: u32,
+}
+
+impl LogDataSector {
+    pub const SIGNATURE: u32 = u32::from_le_bytes(*b"data");
+}
+
+/// Computes the CRC-32C (Castagnoli) checksum of a structure whose 32-bit
+/// checksum field is at byte offset 4, treating the field as zero.
+pub fn checksum_at_4(data: &[u8]) -> u32 {
+    let crc = data[..4].iter().fold(!0, crc32c_step);
+    let crc = [0; 4].iter().fold(crc, crc32c_step);
+    !data[8..].iter().fold(crc, crc32c_step)
+}
+
+fn crc32c_step(crc: u32, b: &u8) -> u32 {
+    CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::checksum_at_4;
+
+    #[test]
+    fn checksum_ignores_field() {
+        assert_eq!(checksum_at_4(b"1234\0\0\0\09"), 0x6d901313);
+        assert_eq!(checksum_at_4(b"1234abcd9"), 0x6d901313);
+    }
+}
@@ -0,0 +1,1514 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A VHDX disk implementation.
+//!
+//! Supports fixed, dynamic, and differencing disks, including replaying the
+//! metadata log of files that were not closed cleanly. When part of a
+//! differencing disk's block is first written, the block is allocated as
+//! partially present, and the written sectors are marked in its sector bitmap;
+//! the rest keep reading from the parent.
+
+#![forbid(unsafe_code)]
+
+mod format;
+mod log;
+pub mod resolver;
+
+use self::format::FileIdentifier;
+use self::format::FileParameters;
+use self::format::Header;
+use self::format::MetadataTableEntry;
+use self::format::MetadataTableHeader;
+use self::format::ParentLocatorEntry;
+use self::format::ParentLocatorHeader;
+use self::format::RegionTableEntry;
+use self::format::RegionTableHeader;
+use self::format::MB;
+use self::log::LogWriter;
+use self::log::Replay;
+use blocking::unblock;
+use disk_backend::block;
+use disk_backend::block::BlockChunk;
+use disk_backend::AsyncDisk;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use disk_backend::ASYNC_DISK_STACK_SIZE;
+use disk_file::readwriteat::read_at_or_zero;
+use disk_file::readwriteat::read_exact_at;
+use disk_file::readwriteat::write_all_at;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use guid::Guid;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use stackfuture::StackFuture;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Component;
+use std::path::Path;
+use std::sync::Arc;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+/// The maximum virtual disk size allowed by the format.
+const MAX_DISK_SIZE: u64 = 64 << 40;
+/// Upper bound on the BAT size, to avoid huge allocations for corrupt files.
+const MAX_BAT_ENTRIES: u64 = 1 << 24;
+/// The maximum number of 4KB sector bitmap sectors to keep cached.
+const BITMAP_CACHE_SECTORS: usize = 64;
+
+const CREATOR: &str = "OpenVMM";
+
+/// An error encountered while opening or creating a VHDX.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("not a VHDX file")]
+    InvalidSignature,
+    #[error("no valid VHDX header")]
+    InvalidHeader,
+    #[error("unsupported VHDX version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("unsupported VHDX log version: {0}")]
+    UnsupportedLogVersion(u16),
+    #[error("invalid VHDX log")]
+    InvalidLog,
+    #[error("VHDX log must be replayed, but the file is read-only")]
+    LogReplayRequired,
+    #[error("no valid VHDX region table")]
+    InvalidRegionTable,
+    #[error("unsupported required VHDX region {0}")]
+    UnsupportedRegion(Guid),
+    #[error("invalid VHDX metadata")]
+    InvalidMetadata,
+    #[error("missing VHDX metadata item {0}")]
+    MissingMetadata(Guid),
+    #[error("unsupported required VHDX metadata item {0}")]
+    UnsupportedMetadata(Guid),
+    #[error("invalid VHDX block size: {0:#x}")]
+    InvalidBlockSize(u32),
+    #[error("invalid VHDX sector size: {0}")]
+    InvalidSectorSize(u32),
+    #[error("invalid VHDX disk size: {0}")]
+    InvalidDiskSize(u64),
+    #[error("invalid VHDX block allocation table")]
+    InvalidBlockTable,
+    #[error("invalid VHDX parent locator")]
+    InvalidParentLocator,
+    #[error("parent path cannot be stored in a VHDX")]
+    InvalidParentPath,
+    #[error("differencing VHDX opened without its parent")]
+    MissingParent,
+    #[error("parent provided for a VHDX that is not a differencing disk")]
+    UnexpectedParent,
+    #[error("parent disk sector size does not match the differencing VHDX")]
+    ParentMismatch,
+}
+
+/// The location of a differencing disk's parent, from its parent locator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentLocator {
+    /// The data write GUID of the parent.
+    pub linkage: Guid,
+    /// An alternate data write GUID of the parent.
+    pub linkage2: Option<Guid>,
+    /// A Windows-style path relative to the directory containing the child.
+    pub relative_path: Option<String>,
+    /// A Windows volume path.
+    pub volume_path: Option<String>,
+    /// An absolute path.
+    pub absolute_win32_path: Option<String>,
+}
+
+impl ParentLocator {
+    /// Returns whether the VHDX in `parent` is the parent that this locator
+    /// refers to.
+    pub fn matches(&self, parent: &File) -> Result<bool, OpenError> {
+        let guid = Layout::read(parent)?.header.data_write_guid;
+        Ok(guid == self.linkage || Some(guid) == self.linkage2)
+    }
+}
+
+/// Reads the parent locator from a differencing VHDX.
+///
+/// Returns `None` if the file is not a differencing VHDX.
+pub fn read_parent_locator(file: &File) -> Result<Option<ParentLocator>, OpenError> {
+    Ok(Layout::read(file)?.parent_locator)
+}
+
+fn decode_utf16le(data: &[u8]) -> Option<String> {
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    String::from_utf16(&chars).ok()
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn parse_parent_locator(data: &[u8]) -> Option<ParentLocator> {
+    let header = ParentLocatorHeader::read_from_prefix(data)?;
+    if header.locator_type != format::VHDX_PARENT_LOCATOR_TYPE {
+        return None;
+    }
+    let entries = &data[size_of::<ParentLocatorHeader>()..];
+    let mut values = HashMap::new();
+    for i in 0..header.key_value_count as usize {
+        let entry = ParentLocatorEntry::read_from_prefix(
+            entries.get(i * size_of::<ParentLocatorEntry>()..)?,
+        )?;
+        let string = |offset: u32, len: u16| {
+            decode_utf16le(data.get(offset as usize..offset as usize + len as usize)?)
+        };
+        values.insert(
+            string(entry.key_offset, entry.key_length)?,
+            string(entry.value_offset, entry.value_length)?,
+        );
+    }
+    let guid = |key| {
+        values
+            .get(key)
+            .map(|value: &String| value.parse::<Guid>().ok())
+    };
+    Some(ParentLocator {
+        linkage: guid(format::parent_keys::PARENT_LINKAGE)??,
+        linkage2: guid(format::parent_keys::PARENT_LINKAGE2).flatten(),
+        relative_path: values.remove(format::parent_keys::RELATIVE_PATH),
+        volume_path: values.remove(format::parent_keys::VOLUME_PATH),
+        absolute_win32_path: values.remove(format::parent_keys::ABSOLUTE_WIN32_PATH),
+    })
+}
+
+/// Builds a parent locator metadata item referring to the parent with data
+/// write GUID `linkage` at `path`.
+fn build_parent_locator(linkage: Guid, path: &Path) -> Result<Vec<u8>, OpenError> {
+    let mut values = vec![(
+        format::parent_keys::PARENT_LINKAGE,
+        format!("{{{linkage}}}").to_uppercase(),
+    )];
+    if path.is_relative() {
+        let components = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => Some(c.to_str()),
+                Component::ParentDir => Some(Some("..")),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(OpenError::InvalidParentPath)?;
+        values.push((
+            format::parent_keys::RELATIVE_PATH,
+            format!(".\\{}", components.join("\\")),
+        ));
+    } else {
+        let path = path.to_str().ok_or(OpenError::InvalidParentPath)?;
+        values.push((format::parent_keys::ABSOLUTE_WIN32_PATH, path.to_owned()));
+    }
+
+    let header = ParentLocatorHeader {
+        locator_type: format::VHDX_PARENT_LOCATOR_TYPE,
+        reserved: 0,
+        key_value_count: values.len() as u16,
+    };
+    let mut data = header.as_bytes().to_vec();
+    let mut strings = Vec::new();
+    let strings_offset = data.len() + values.len() * size_of::<ParentLocatorEntry>();
+    for (key, value) in values {
+        let key = encode_utf16le(key);
+        let value = encode_utf16le(&value);
+        let key_offset = strings_offset + strings.len();
+        let value_offset = key_offset + key.len();
+        let entry = ParentLocatorEntry {
+            key_offset: key_offset as u32,
+            value_offset: value_offset as u32,
+            key_length: key.len() as u16,
+            value_length: value.len() as u16,
+        };
+        data.extend_from_slice(entry.as_bytes());
+        strings.extend_from_slice(&key);
+        strings.extend_from_slice(&value);
+    }
+    data.extend_from_slice(&strings);
+    Ok(data)
+}
+
+/// The parsed structure of a VHDX file.
+struct Layout {
+    header: Header,
+    header_slot: usize,
+    /// The log entries that have not been applied to the file.
+    replay: Option<Replay>,
+    geometry: Geometry,
+    bat: Vec<u64>,
+    disk_id: Guid,
+    parent_locator: Option<ParentLocator>,
+}
+
+impl Layout {
+    fn read(file: &File) -> Result<Self, OpenError> {
+        let mut identifier = FileIdentifier::new_zeroed();
+        read_exact_at(file, identifier.as_bytes_mut(), FileIdentifier::OFFSET)?;
+        if identifier.signature != FileIdentifier::SIGNATURE {
+            return Err(OpenError::InvalidSignature);
+        }
+
+        let mut current: Option<(usize, Header)> = None;
+        for (slot, &offset) in Header::OFFSETS.iter().enumerate() {
+            let mut header = Header::new_zeroed();
+            read_exact_at(file, header.as_bytes_mut(), offset)?;
+            if header.signature != Header::SIGNATURE
+                || format::checksum_at_4(header.as_bytes()) != header.checksum
+            {
+                continue;
+            }
+            if current
+                .as_ref()
+                .is_none_or(|(_, h)| header.sequence_number > h.sequence_number)
+            {
+                current = Some((slot, header));
+            }
+        }
+        let (header_slot, header) = current.ok_or(OpenError::InvalidHeader)?;
+        if header.version != Header::VERSION {
+            return Err(OpenError::UnsupportedVersion(header.version));
+        }
+        if header.log_version != Header::LOG_VERSION {
+            return Err(OpenError::UnsupportedLogVersion(header.log_version));
+        }
+        if header.log_offset < MB
+            || header.log_offset % MB != 0
+            || header.log_length == 0
+            || header.log_length as u64 % MB != 0
+        {
+            return Err(OpenError::InvalidLog);
+        }
+
+        let replay = if header.log_guid.is_zero() {
+            None
+        } else {
+            log::read_log(file, header.log_offset, header.log_length, header.log_guid)?
+        };
+        if let Some(replay) = &replay {
+            if file.metadata()?.len() < replay.flushed_file_offset {
+                return Err(OpenError::InvalidLog);
+            }
+        }
+
+        // Metadata may have pending updates in the log, so read it through
+        // the replayed writes.
+        let read_metadata = |offset: u64, buf: &mut [u8]| -> io::Result<()> {
+            read_at_or_zero(file, buf, offset)?;
+            if let Some(replay) = &replay {
+                replay.patch(offset, buf);
+            }
+            Ok(())
+        };
+
+        let mut regions = None;
+        for offset in RegionTableHeader::OFFSETS {
+            let mut table = vec![0; RegionTableHeader::LEN];
+            read_metadata(offset, &mut table)?;
+            let header = RegionTableHeader::read_from_prefix(&table).unwrap();
+            if header.signature != RegionTableHeader::SIGNATURE
+                || format::checksum_at_4(&table) != header.checksum
+                || header.entry_count > RegionTableHeader::MAX_ENTRIES
+            {
+                continue;
+            }
+            regions = Some(
+                table[size_of::<RegionTableHeader>()..]
+                    .chunks_exact(size_of::<RegionTableEntry>())
+                    .take(header.entry_count as usize)
+                    .map(|entry| RegionTableEntry::read_from(entry).unwrap())
+                    .collect::<Vec<_>>(),
+            );
+            break;
+        }
+        let regions = regions.ok_or(OpenError::InvalidRegionTable)?;
+        let mut bat_region = None;
+        let mut metadata_region = None;
+        for region in &regions {
+            let slot = match region.guid {
+                format::BAT_REGION => &mut bat_region,
+                format::METADATA_REGION => &mut metadata_region,
+                guid if region.required & 1 != 0 => {
+                    return Err(OpenError::UnsupportedRegion(guid));
+                }
+                _ => continue,
+            };
+            if region.file_offset < MB
+                || region.file_offset % MB != 0
+                || region.length == 0
+                || region.length as u64 % MB != 0
+            {
+                return Err(OpenError::InvalidRegionTable);
+            }
+            *slot = Some((region.file_offset, region.length));
+        }
+        let (bat_offset, bat_length) = bat_region.ok_or(OpenError::InvalidRegionTable)?;
+        let (metadata_offset, metadata_length) =
+            metadata_region.ok_or(OpenError::InvalidRegionTable)?;
+
+        let mut table = vec![0; MetadataTableHeader::LEN as usize];
+        read_metadata(metadata_offset, &mut table)?;
+        let table_header = MetadataTableHeader::read_from_prefix(&table).unwrap();
+        if table_header.signature != MetadataTableHeader::SIGNATURE
+            || table_header.entry_count > MetadataTableHeader::MAX_ENTRIES
+        {
+            return Err(OpenError::InvalidMetadata);
+        }
+        let mut items = HashMap::new();
+        for entry in table[size_of::<MetadataTableHeader>()..]
+            .chunks_exact(size_of::<MetadataTableEntry>())
+            .take(table_header.entry_count as usize)
+        {
+            let entry = MetadataTableEntry::read_from(entry).unwrap();
+            match entry.item_id {
+                format::FILE_PARAMETERS
+                | format::VIRTUAL_DISK_SIZE
+                | format::VIRTUAL_DISK_ID
+                | format::LOGICAL_SECTOR_SIZE
+                | format::PHYSICAL_SECTOR_SIZE
+                | format::PARENT_LOCATOR => {}
+                guid if entry.flags & format::metadata_flags::IS_REQUIRED != 0 => {
+                    return Err(OpenError::UnsupportedMetadata(guid));
+                }
+                _ => continue,
+            }
+            if (entry.offset as u64) < MetadataTableHeader::LEN
+                || entry.offset as u64 + entry.length as u64 > metadata_length as u64
+            {
+                return Err(OpenError::InvalidMetadata);
+            }
+            items.insert(entry.item_id, (entry.offset, entry.length));
+        }
+        let item = |id: Guid| -> Result<Vec<u8>, OpenError> {
+            let &(offset, length) = items.get(&id).ok_or(OpenError::MissingMetadata(id))?;
+            let mut data = vec![0; length as usize];
+            read_metadata(metadata_offset + offset as u64, &mut data)?;
+            Ok(data)
+        };
+
+        let file_parameters = FileParameters::read_from_prefix(&item(format::FILE_PARAMETERS)?)
+            .ok_or(OpenError::InvalidMetadata)?;
+        let disk_size = u64::read_from_prefix(&item(format::VIRTUAL_DISK_SIZE)?)
+            .ok_or(OpenError::InvalidMetadata)?;
+        let disk_id = Guid::read_from_prefix(&item(format::VIRTUAL_DISK_ID)?)
+            .ok_or(OpenError::InvalidMetadata)?;
+        let sector_size = u32::read_from_prefix(&item(format::LOGICAL_SECTOR_SIZE)?)
+            .ok_or(OpenError::InvalidMetadata)?;
+        let physical_sector_size = u32::read_from_prefix(&item(format::PHYSICAL_SECTOR_SIZE)?)
+            .ok_or(OpenError::InvalidMetadata)?;
+        let has_parent = file_parameters.flags & FileParameters::HAS_PARENT != 0;
+
+        let geometry = Geometry::new(
+            disk_size,
+            file_parameters.block_size,
+            sector_size,
+            physical_sector_size,
+            has_parent,
+            bat_offset,
+        )?;
+
+        let parent_locator = if has_parent {
+            Some(
+                parse_parent_locator(&item(format::PARENT_LOCATOR)?)
+                    .ok_or(OpenError::InvalidParentLocator)?,
+            )
+        } else {
+            None
+        };
+
+        let bat_entries = geometry.bat_entries();
+        if bat_entries * 8 > bat_length as u64 {
+            return Err(OpenError::InvalidBlockTable);
+        }
+        let mut bat_bytes = vec![0; bat_entries as usize * 8];
+        read_metadata(bat_offset, &mut bat_bytes)?;
+        let bat = bat_bytes
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        for (index, &entry) in bat.iter().enumerate() {
+            if !geometry.is_valid_bat_entry(index, entry) {
+                return Err(OpenError::InvalidBlockTable);
+            }
+        }
+
+        Ok(Self {
+            header,
+            header_slot,
+            replay,
+            geometry,
+            bat,
+            disk_id,
+            parent_locator,
+        })
+    }
+}
+
+/// An open VHDX disk.
+pub struct VhdxDisk {
+    inner: Arc<Inner>,
+    geometry: Geometry,
+    parent: Option<Arc<dyn SimpleDisk>>,
+    read_only: bool,
+    disk_id: Guid,
+    /// Lock used to serialize writes, which may allocate blocks.
+    write_lock: futures::lock::Mutex<()>,
+}
+
+struct Inner {
+    file: File,
+    geometry: Geometry,
+    /// Log entries that could not be replayed because the file is read-only.
+    /// Metadata reads are patched with these.
+    replay: Option<Replay>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Geometry {
+    disk_size: u64,
+    block_bits: u32,
+    block_size: u64,
+    sector_shift: u32,
+    physical_sector_size: u32,
+    /// The number of payload blocks described by each sector bitmap block.
+    chunk_ratio: u64,
+    has_parent: bool,
+    bat_offset: u64,
+}
+
+/// The mapping of a guest block to the file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mapping {
+    /// The block reads from the parent disk.
+    Parent,
+    /// The block reads as zero.
+    Zero,
+    /// The block is fully present in the file at `host`.
+    Present { host: u64 },
+    /// The block is present in the file at `host`, but only the sectors
+    /// marked in the sector bitmap are valid; the rest read from the parent.
+    Partial { host: u64 },
+}
+
+impl Geometry {
+    fn new(
+        disk_size: u64,
+        block_size: u32,
+        sector_size: u32,
+        physical_sector_size: u32,
+        has_parent: bool,
+        bat_offset: u64,
+    ) -> Result<Self, OpenError> {
+        if !block_size.is_power_of_two()
+            || !(FileParameters::MIN_BLOCK_SIZE..=FileParameters::MAX_BLOCK_SIZE)
+                .contains(&block_size)
+        {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        for size in [sector_size, physical_sector_size] {
+            if size != 512 && size != 4096 {
+                return Err(OpenError::InvalidSectorSize(size));
+            }
+        }
+        if disk_size == 0 || disk_size > MAX_DISK_SIZE || disk_size % sector_size as u64 != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let geometry = Self {
+            disk_size,
+            block_bits: block_size.trailing_zeros(),
+            block_size: block_size as u64,
+            sector_shift: sector_size.trailing_zeros(),
+            physical_sector_size,
+            chunk_ratio: (format::SECTORS_PER_BITMAP_BLOCK * sector_size as u64)
+                / block_size as u64,
+            has_parent,
+            bat_offset,
+        };
+        if geometry.bat_entries() > MAX_BAT_ENTRIES {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        Ok(geometry)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk_size.div_ceil(self.block_size)
+    }
+
+    /// The number of BAT entries, which interleave a sector bitmap entry after
+    /// every `chunk_ratio` payload entries.
+    fn bat_entries(&self) -> u64 {
+        let blocks = self.block_count();
+        if self.has_parent {
+            blocks.div_ceil(self.chunk_ratio) * (self.chunk_ratio + 1)
+        } else {
+            blocks + (blocks - 1) / self.chunk_ratio
+        }
+    }
+
+    fn payload_index(&self, block: u64) -> usize {
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    fn bitmap_index(&self, chunk: u64) -> usize {
+        (chunk * (self.chunk_ratio + 1) + self.chunk_ratio) as usize
+    }
+
+    fn is_valid_bat_entry(&self, index: usize, entry: u64) -> bool {
+        let state = entry & format::BAT_STATE_MASK;
+        let offset = entry >> format::BAT_FILE_OFFSET_SHIFT;
+        if (index as u64 + 1) % (self.chunk_ratio + 1) == 0 {
+            match state {
+                format::bitmap_state::NOT_PRESENT => true,
+                format::bitmap_state::PRESENT => offset != 0,
+                _ => false,
+            }
+        } else {
+            match state {
+                format::payload_state::NOT_PRESENT
+                | format::payload_state::UNDEFINED
+                | format::payload_state::ZERO
+                | format::payload_state::UNMAPPED => true,
+                format::payload_state::FULLY_PRESENT => offset != 0,
+                format::payload_state::PARTIALLY_PRESENT => self.has_parent && offset != 0,
+                _ => false,
+            }
+        }
+    }
+
+    fn mapping(&self, entry: u64) -> Mapping {
+        let host = entry & !(MB - 1);
+        match entry & format::BAT_STATE_MASK {
+            format::payload_state::FULLY_PRESENT => Mapping::Present { host },
+            format::payload_state::PARTIALLY_PRESENT => Mapping::Partial { host },
+            format::payload_state::NOT_PRESENT if self.has_parent => Mapping::Parent,
+            _ => Mapping::Zero,
+        }
+    }
+
+    /// Splits the guest byte range `offset..offset + len` into per-block
+    /// chunks.
+    fn chunks(&self, offset: u64, len: usize) -> impl Iterator<Item = BlockChunk> {
+        block::chunks(offset, len, self.block_bits)
+    }
+
+    /// The guest sectors of `block`.
+    fn block_sectors(&self, block: u64) -> Range<u64> {
+        let start = block << self.block_bits;
+        let end = (start + self.block_size).min(self.disk_size);
+        start >> self.sector_shift..end >> self.sector_shift
+    }
+}
+
+/// Appends a read of `range` from file offset `host` to `reads`, merging it
+/// with the last read if they are contiguous.
+fn push_read(reads: &mut Vec<(Range<usize>, u64)>, range: Range<usize>, host: u64) {
+    match reads.last_mut() {
+        Some((last, last_host))
+            if last.end == range.start && *last_host + last.len() as u64 == host =>
+        {
+            last.end = range.end;
+        }
+        _ => reads.push((range, host)),
+    }
+}
+
+/// Mutable disk metadata. Caches are write-through, so they can be dropped at
+/// any time.
+struct State {
+    geometry: Geometry,
+    bat: Vec<u64>,
+    /// Cached 4KB sectors of sector bitmap blocks, by file offset.
+    bitmap_cache: HashMap<u64, Box<[u8]>>,
+    header: Header,
+    header_slot: usize,
+    /// Whether the file and data write GUIDs have been updated since the
+    /// file was opened.
+    write_guids_updated: bool,
+    /// The active log, if metadata has been updated since the last flush.
+    log: Option<LogWriter>,
+    /// The end of the file, where new blocks are allocated.
+    file_end: u64,
+}
+
+impl State {
+    fn mapping(&self, block: u64) -> Mapping {
+        self.geometry
+            .mapping(self.bat[self.geometry.payload_index(block)])
+    }
+
+    /// Writes the header to the non-current header location, making it
+    /// current.
+    fn write_header(&mut self, file: &File) -> io::Result<()> {
+        let mut header = self.header;
+        header.sequence_number += 1;
+        header.checksum = format::checksum_at_4(header.as_bytes());
+        let slot = 1 - self.header_slot;
+        write_all_at(file, header.as_bytes(), Header::OFFSETS[slot])?;
+        file.sync_data()?;
+        self.header = header;
+        self.header_slot = slot;
+        Ok(())
+    }
+
+    /// Updates the file and data write GUIDs before the first modification
+    /// to the file.
+    fn prepare_write(&mut self, file: &File) -> io::Result<()> {
+        if !self.write_guids_updated {
+            self.header.file_write_guid = Guid::new_random();
+            self.header.data_write_guid = Guid::new_random();
+            self.write_header(file)?;
+            self.write_guids_updated = true;
+        }
+        Ok(())
+    }
+
+    /// Durably writes a 4KB metadata sector through the log.
+    fn write_metadata_sector(&mut self, file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.log.is_none() {
+            let log = LogWriter::new(self.header.log_offset, self.header.log_length);
+            self.header.log_guid = log.guid;
+            self.write_header(file)?;
+            self.log = Some(log);
+        }
+        self.log
+            .as_mut()
+            .unwrap()
+            .write_entry(file, &[(offset, data)], self.file_end)?;
+        write_all_at(file, data, offset)?;
+        file.sync_data()
+    }
+
+    /// Marks the log as empty. All entries must have been applied.
+    fn close_log(&mut self, file: &File) -> io::Result<()> {
+        if self.log.is_some() {
+            self.header.log_guid = Guid::ZERO;
+            self.write_header(file)?;
+            self.log = None;
+        }
+        Ok(())
+    }
+
+    fn set_bat_entry(&mut self, file: &File, index: usize, entry: u64) -> io::Result<()> {
+        const ENTRIES_PER_SECTOR: usize = format::LOG_SECTOR_SIZE as usize / 8;
+        let first = index / ENTRIES_PER_SECTOR * ENTRIES_PER_SECTOR;
+        let mut sector = vec![0; format::LOG_SECTOR_SIZE as usize];
+        for (i, dest) in sector.chunks_exact_mut(8).enumerate() {
+            let value = match first + i {
+                n if n == index => entry,
+                n => self.bat.get(n).copied().unwrap_or(0),
+            };
+            dest.copy_from_slice(&value.to_le_bytes());
+        }
+        let offset = self.geometry.bat_offset + first as u64 * 8;
+        self.write_metadata_sector(file, offset, &sector)?;
+        self.bat[index] = entry;
+        Ok(())
+    }
+
+    /// Returns the file offset of the 4KB sector bitmap sector describing
+    /// guest `sector`, along with the byte index and mask of its bit.
+    fn bitmap_bit(&self, sector: u64) -> io::Result<(u64, usize, u8)> {
+        let chunk = sector / format::SECTORS_PER_BITMAP_BLOCK;
+        let bit = sector % format::SECTORS_PER_BITMAP_BLOCK;
+        let entry = self.bat[self.geometry.bitmap_index(chunk)];
+        if entry & format::BAT_STATE_MASK != format::bitmap_state::PRESENT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "partially present block has no sector bitmap",
+            ));
+        }
+        let byte = bit / 8;
+        let offset = (entry & !(MB - 1)) + byte / format::LOG_SECTOR_SIZE * format::LOG_SECTOR_SIZE;
+        Ok((
+            offset,
+            (byte % format::LOG_SECTOR_SIZE) as usize,
+            1 << (bit % 8),
+        ))
+    }
+
+    fn bitmap_sector(
+        &mut self,
+        file: &File,
+        replay: Option<&Replay>,
+        offset: u64,
+    ) -> io::Result<&mut [u8]> {
+        if !self.bitmap_cache.contains_key(&offset) {
+            let mut data = vec![0; format::LOG_SECTOR_SIZE as usize];
+            read_at_or_zero(file, &mut data, offset)?;
+            if let Some(replay) = replay {
+                replay.patch(offset, &mut data);
+            }
+            if self.bitmap_cache.len() >= BITMAP_CACHE_SECTORS {
+                self.bitmap_cache.clear();
+            }
+            self.bitmap_cache.insert(offset, data.into());
+        }
+        Ok(&mut self.bitmap_cache.get_mut(&offset).unwrap()[..])
+    }
+
+    fn is_sector_present(
+        &mut self,
+        file: &File,
+        replay: Option<&Replay>,
+        sector: u64,
+    ) -> io::Result<bool> {
+        let (offset, byte, mask) = self.bitmap_bit(sector)?;
+        Ok(self.bitmap_sector(file, replay, offset)?[byte] & mask != 0)
+    }
+
+    /// Allocates the sector bitmap block describing guest `sector` at the end
+    /// of the file, if it is not already present.
+    fn ensure_bitmap_block(&mut self, file: &File, sector: u64) -> io::Result<()> {
+        let index = self
+            .geometry
+            .bitmap_index(sector / format::SECTORS_PER_BITMAP_BLOCK);
+        if self.bat[index] & format::BAT_STATE_MASK == format::bitmap_state::PRESENT {
+            return Ok(());
+        }
+        // The new bitmap block is implicitly zero, so no sectors are present.
+        let host = self.file_end;
+        file.set_len(host + format::SECTOR_BITMAP_BLOCK_SIZE)?;
+        file.sync_data()?;
+        self.file_end += format::SECTOR_BITMAP_BLOCK_SIZE;
+        self.set_bat_entry(file, index, host | format::bitmap_state::PRESENT)
+    }
+
+    /// Sets the sector bitmap bit of each of guest `sectors` to
+    /// `present(sector)`.
+    fn set_sector_bits(
+        &mut self,
+        file: &File,
+        sectors: Range<u64>,
+        present: impl Fn(u64) -> bool,
+    ) -> io::Result<()> {
+        let mut pending: Option<(u64, Vec<u8>)> = None;
+        for sector in sectors {
+            let (offset, byte, mask) = self.bitmap_bit(sector)?;
+            if pending.as_ref().is_none_or(|(o, _)| *o != offset) {
+                if let Some((o, data)) = pending.take() {
+                    self.write_bitmap_sector(file, o, data)?;
+                }
+                let data = self.bitmap_sector(file, None, offset)?.to_vec();
+                pending = Some((offset, data));
+            }
+            let data = &mut pending.as_mut().unwrap().1;
+            if present(sector) {
+                data[byte] |= mask;
+            } else {
+                data[byte] &= !mask;
+            }
+        }
+        if let Some((offset, data)) = pending {
+            self.write_bitmap_sector(file, offset, data)?;
+        }
+        Ok(())
+    }
+
+    fn write_bitmap_sector(&mut self, file: &File, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        if self.bitmap_cache.get(&offset).map(|d| &d[..]) == Some(&data[..]) {
+            return Ok(());
+        }
+        // Drop the cached copy in case the write fails.
+        self.bitmap_cache.remove(&offset);
+        self.write_metadata_sector(file, offset, &data)?;
+        self.bitmap_cache.insert(offset, data.into());
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// Reads the present parts of the guest range from the file, returning
+    /// the data and the ranges of the buffer that must be read from the parent
+    /// disk.
+    fn read(&self, offset: u64, len: usize) -> io::Result<(Vec<u8>, Vec<Range<usize>>)> {
+        let sector_size = 1usize << self.geometry.sector_shift;
+        let mut data = vec![0; len];
+        let mut absent = Vec::new();
+        let mut reads = Vec::new();
+        {
+            let mut state = self.state.lock();
+            for chunk in self.geometry.chunks(offset, len) {
+                match state.mapping(chunk.block) {
+                    Mapping::Parent => block::push_range(&mut absent, chunk.range),
+                    Mapping::Zero => {}
+                    Mapping::Present { host } => {
+                        push_read(&mut reads, chunk.range, host + chunk.block_offset)
+                    }
+                    Mapping::Partial { host } => {
+                        for start in chunk.range.step_by(sector_size) {
+                            let guest = offset + start as u64;
+                            let range = start..start + sector_size;
+                            let sector = guest >> self.geometry.sector_shift;
+                            if state.is_sector_present(&self.file, self.replay.as_ref(), sector)? {
+                                let block_offset = guest & (self.geometry.block_size - 1);
+                                push_read(&mut reads, range, host + block_offset);
+                            } else {
+                                block::push_range(&mut absent, range);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for (range, host) in reads {
+            read_at_or_zero(&self.file, &mut data[range], host)?;
+        }
+        Ok((data, absent))
+    }
+
+    fn lookup_range(&self, offset: u64, len: usize) -> Vec<Mapping> {
+        let state = self.state.lock();
+        self.geometry
+            .chunks(offset, len)
+            .map(|chunk| state.mapping(chunk.block))
+            .collect()
+    }
+
+    /// Allocates a new, zeroed block at the end of the file, returning its
+    /// file offset.
+    fn allocate_block(&self) -> io::Result<u64> {
+        let mut state = self.state.lock();
+        let host = state.file_end;
+        self.file.set_len(host + self.geometry.block_size)?;
+        state.file_end += self.geometry.block_size;
+        Ok(host)
+    }
+
+    /// Writes `data` at guest `offset`, given the current `mappings` of the
+    /// blocks in the range.
+    ///
+    /// Must be called with the write lock held.
+    fn write(&self, offset: u64, data: &[u8], mappings: &[Mapping], fua: bool) -> io::Result<()> {
+        self.state.lock().prepare_write(&self.file)?;
+        let sector_shift = self.geometry.sector_shift;
+        for (chunk, &mapping) in self.geometry.chunks(offset, data.len()).zip(mappings) {
+            let buf = &data[chunk.range.clone()];
+            let first = (offset + chunk.range.start as u64) >> sector_shift;
+            let written = first..first + ((buf.len() as u64) >> sector_shift);
+            match mapping {
+                Mapping::Present { host } => {
+                    write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                }
+                Mapping::Partial { host } => {
+                    write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                    self.state
+                        .lock()
+                        .set_sector_bits(&self.file, written, |_| true)?;
+                }
+                Mapping::Parent if written != self.geometry.block_sectors(chunk.block) => {
+                    // Only the written sectors become present; the rest of the
+                    // block keeps reading from the parent.
+                    let host = self.allocate_block()?;
+                    write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                    self.file.sync_data()?;
+                    let mut state = self.state.lock();
+                    let block_sectors = self.geometry.block_sectors(chunk.block);
+                    state.ensure_bitmap_block(&self.file, block_sectors.start)?;
+                    // Clear the bits of the rest of the block, which may have
+                    // been left set by an interrupted allocation.
+                    state.set_sector_bits(&self.file, block_sectors, |sector| {
+                        written.contains(&sector)
+                    })?;
+                    let index = self.geometry.payload_index(chunk.block);
+                    state.set_bat_entry(
+                        &self.file,
+                        index,
+                        host | format::payload_state::PARTIALLY_PRESENT,
+                    )?;
+                }
+                Mapping::Parent | Mapping::Zero => {
+                    // The rest of the new block is implicitly zero.
+                    let host = self.allocate_block()?;
+                    write_all_at(&self.file, buf, host + chunk.block_offset)?;
+                    // The data must be durable before the BAT references it.
+                    self.file.sync_data()?;
+                    let mut state = self.state.lock();
+                    let index = self.geometry.payload_index(chunk.block);
+                    state.set_bat_entry(
+                        &self.file,
+                        index,
+                        host | format::payload_state::FULLY_PRESENT,
+                    )?;
+                }
+            }
+        }
+        if fua {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_all()?;
+        self.state.lock().close_log(&self.file)
+    }
+}
+
+struct NewDisk<'a> {
+    disk_size: u64,
+    block_size: u32,
+    sector_size: u32,
+    physical_sector_size: u32,
+    parent: Option<(Guid, &'a Path)>,
+}
+
+impl VhdxDisk {
+    /// Creates a new, empty dynamic VHDX of `disk_size` bytes in `file`, with
+    /// blocks of `block_size` bytes and logical sectors of `sector_size`
+    /// bytes.
+    pub fn create(
+        file: &File,
+        disk_size: u64,
+        block_size: u32,
+        sector_size: u32,
+    ) -> Result<(), OpenError> {
+        Self::create_inner(
+            file,
+            NewDisk {
+                disk_size,
+                block_size,
+                sector_size,
+                physical_sector_size: 4096,
+                parent: None,
+            },
+        )
+    }
+
+    /// Creates a new, empty differencing VHDX in `file` whose parent is the
+    /// VHDX in `parent`, with blocks of `block_size` bytes.
+    ///
+    /// `parent_path` is recorded in the parent locator. If it is relative, it
+    /// must be relative to the directory containing `file`.
+    pub fn create_differencing(
+        file: &File,
+        parent: &File,
+        parent_path: &Path,
+        block_size: u32,
+    ) -> Result<(), OpenError> {
+        let parent = Layout::read(parent)?;
+        Self::create_inner(
+            file,
+            NewDisk {
+                disk_size: parent.geometry.disk_size,
+                block_size,
+                sector_size: 1 << parent.geometry.sector_shift,
+                physical_sector_size: parent.geometry.physical_sector_size,
+                parent: Some((parent.header.data_write_guid, parent_path)),
+            },
+        )
+    }
+
+    fn create_inner(file: &File, disk: NewDisk<'_>) -> Result<(), OpenError> {
+        // Lay out the log, the metadata region, and the BAT after the headers
+        // and region tables in the first MB.
+        let log_offset = MB;
+        let log_length = MB;
+        let metadata_offset = 2 * MB;
+        let metadata_length = MB;
+        let bat_offset = 3 * MB;
+
+        let geometry = Geometry::new(
+            disk.disk_size,
+            disk.block_size,
+            disk.sector_size,
+            disk.physical_sector_size,
+            disk.parent.is_some(),
+            bat_offset,
+        )?;
+        let bat_length = (geometry.bat_entries() * 8).next_multiple_of(MB);
+
+        let mut file_parameters = FileParameters {
+            block_size: disk.block_size,
+            flags: 0,
+        };
+        let virtual_disk = format::metadata_flags::IS_VIRTUAL_DISK;
+        let required = format::metadata_flags::IS_REQUIRED;
+        let mut items = vec![
+            (
+                format::VIRTUAL_DISK_SIZE,
+                disk.disk_size.as_bytes().to_vec(),
+                virtual_disk | required,
+            ),
+            (
+                format::VIRTUAL_DISK_ID,
+                Guid::new_random().as_bytes().to_vec(),
+                virtual_disk | required,
+            ),
+            (
+                format::LOGICAL_SECTOR_SIZE,
+                disk.sector_size.as_bytes().to_vec(),
+                virtual_disk | required,
+            ),
+            (
+                format::PHYSICAL_SECTOR_SIZE,
+                disk.physical_sector_size.as_bytes().to_vec(),
+                virtual_disk | required,
+            ),
+        ];
+        if let Some((linkage, path)) = disk.parent {
+            file_parameters.flags |= FileParameters::HAS_PARENT;
+            items.push((
+                format::PARENT_LOCATOR,
+                build_parent_locator(linkage, path)?,
+                required,
+            ));
+        }
+        items.insert(
+            0,
+            (
+                format::FILE_PARAMETERS,
+                file_parameters.as_bytes().to_vec(),
+                required,
+            ),
+        );
+
+        let mut metadata = vec![0; metadata_length as usize];
+        let table_header = MetadataTableHeader {
+            signature: MetadataTableHeader::SIGNATURE,
+            reserved: 0,
+            entry_count: items.len() as u16,
+            reserved2: [0; 5],
+        };
+        metadata[..size_of::<MetadataTableHeader>()].copy_from_slice(table_header.as_bytes());
+        let mut item_offset = MetadataTableHeader::LEN as usize;
+        for (i, (item_id, data, flags)) in items.iter().enumerate() {
+            let entry = MetadataTableEntry {
+                item_id: *item_id,
+                offset: item_offset as u32,
+                length: data.len() as u32,
+                flags: *flags,
+                reserved: 0,
+            };
+            let entry_offset =
+                size_of::<MetadataTableHeader>() + i * size_of::<MetadataTableEntry>();
+            metadata[entry_offset..][..size_of::<MetadataTableEntry>()]
+                .copy_from_slice(entry.as_bytes());
+            metadata
+                .get_mut(item_offset..item_offset + data.len())
+                .ok_or(OpenError::InvalidParentPath)?
+                .copy_from_slice(data);
+            item_offset = (item_offset + data.len()).next_multiple_of(8);
+        }
+
+        let mut region_table = vec![0; RegionTableHeader::LEN];
+        let regions = [
+            RegionTableEntry {
+                guid: format::BAT_REGION,
+                file_offset: bat_offset,
+                length: bat_length as u32,
+                required: 1,
+            },
+            RegionTableEntry {
+                guid: format::METADATA_REGION,
+                file_offset: metadata_offset,
+                length: metadata_length as u32,
+                required: 1,
+            },
+        ];
+        let region_header = RegionTableHeader {
+            signature: RegionTableHeader::SIGNATURE,
+            checksum: 0,
+            entry_count: regions.len() as u32,
+            reserved: 0,
+        };
+        region_table[..size_of::<RegionTableHeader>()].copy_from_slice(region_header.as_bytes());
+        region_table[size_of::<RegionTableHeader>()..][..size_of_val(&regions)]
+            .copy_from_slice(regions.as_bytes());
+        let checksum = format::checksum_at_4(&region_table);
+        region_table[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+        let mut identifier = FileIdentifier {
+            signature: FileIdentifier::SIGNATURE,
+            creator: [0; 512],
+        };
+        let creator = encode_utf16le(CREATOR);
+        identifier.creator[..creator.len()].copy_from_slice(&creator);
+
+        let mut header = Header {
+            signature: Header::SIGNATURE,
+            checksum: 0,
+            sequence_number: 0,
+            file_write_guid: Guid::new_random(),
+            data_write_guid: Guid::new_random(),
+            log_guid: Guid::ZERO,
+            log_version: Header::LOG_VERSION,
+            version: Header::VERSION,
+            log_length: log_length as u32,
+            log_offset,
+            reserved: [0; 4016],
+        };
+
+        file.set_len(0)?;
+        write_all_at(file, identifier.as_bytes(), FileIdentifier::OFFSET)?;
+        for (i, &offset) in Header::OFFSETS.iter().enumerate() {
+            header.sequence_number = i as u64 + 1;
+            header.checksum = format::checksum_at_4(header.as_bytes());
+            write_all_at(file, header.as_bytes(), offset)?;
+        }
+        for offset in RegionTableHeader::OFFSETS {
+            write_all_at(file, &region_table, offset)?;
+        }
+        write_all_at(file, &metadata, metadata_offset)?;
+        file.set_len(bat_offset + bat_length)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Opens a VHDX.
+    ///
+    /// `parent` must be provided if and only if the disk is a differencing
+    /// disk. It is only read from.
+    ///
+    /// If the file was not closed cleanly, its log is replayed. If the disk is
+    /// opened read-only, the replayed updates are kept in memory instead.
+    pub fn open(
+        file: File,
+        parent: Option<Arc<dyn SimpleDisk>>,
+        read_only: bool,
+    ) -> Result<Self, OpenError> {
+        let Layout {
+            header,
+            header_slot,
+            mut replay,
+            geometry,
+            bat,
+            disk_id,
+            parent_locator: _,
+        } = Layout::read(&file)?;
+
+        match &parent {
+            None if geometry.has_parent => return Err(OpenError::MissingParent),
+            Some(_) if !geometry.has_parent => return Err(OpenError::UnexpectedParent),
+            Some(parent) => {
+                if parent.sector_size() != 1 << geometry.sector_shift {
+                    return Err(OpenError::ParentMismatch);
+                }
+            }
+            None => {}
+        }
+
+        let mut replayed = false;
+        if !read_only {
+            if let Some(replay) = replay.take() {
+                replay.apply(&file)?;
+                replayed = true;
+            }
+        }
+
+        let mut state = State {
+            geometry,
+            bat,
+            bitmap_cache: HashMap::new(),
+            header,
+            header_slot,
+            write_guids_updated: false,
+            log: None,
+            file_end: file.metadata()?.len().next_multiple_of(MB),
+        };
+        if replayed {
+            state.header.file_write_guid = Guid::new_random();
+            state.header.log_guid = Guid::ZERO;
+            state.write_header(&file)?;
+        }
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                file,
+                geometry,
+                replay,
+                state: Mutex::new(state),
+            }),
+            geometry,
+            parent,
+            read_only,
+            disk_id,
+            write_lock: Default::default(),
+        })
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), DiskError> {
+        block::check_range(offset, len, self.geometry.disk_size)
+    }
+
+    async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        let sector_shift = self.geometry.sector_shift;
+        let offset = sector << sector_shift;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let inner = self.inner.clone();
+        let (data, absent) = unblock(move || inner.read(offset, len))
+            .await
+            .map_err(DiskError::Io)?;
+        buffers.writer().write(&data)?;
+
+        if let Some(parent) = &self.parent {
+            let parent_size = parent.sector_count() << sector_shift;
+            for range in absent {
+                let start = offset + range.start as u64;
+                if start >= parent_size {
+                    continue;
+                }
+                let len = range.len().min((parent_size - start) as usize);
+                parent
+                    .read_vectored(&buffers.subrange(range.start, len), start >> sector_shift)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = sector << self.geometry.sector_shift;
+        let len = buffers.len();
+        self.check_range(offset, len)?;
+        let data = buffers.reader().read_all()?;
+
+        let _write_lock = self.write_lock.lock().await;
+        let mappings = self.inner.lookup_range(offset, len);
+        let inner = self.inner.clone();
+        unblock(move || inner.write(offset, &data, &mappings, fua))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn flush(&self) -> Result<(), DiskError> {
+        let _write_lock = self.write_lock.lock().await;
+        let inner = self.inner.clone();
+        unblock(move || inner.flush()).await.map_err(DiskError::Io)
+    }
+}
+
+impl Inspect for VhdxDisk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("disk_id", self.disk_id)
+            .hex("block_size", self.geometry.block_size)
+            .field("disk_size", self.geometry.disk_size)
+            .field("sector_size", 1u32 << self.geometry.sector_shift)
+            .field("read_only", self.read_only)
+            .field("pending_replay", self.inner.replay.is_some())
+            .field_with("file_end", || self.inner.state.lock().file_end)
+            .field_with("log_active", || self.inner.state.lock().log.is_some())
+            .field("parent", &self.parent);
+    }
+}
+
+impl SimpleDisk for VhdxDisk {
+    fn disk_type(&self) -> &str {
+        "vhdx"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geometry.disk_size >> self.geometry.sector_shift
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << self.geometry.sector_shift
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        Some(self.disk_id.into())
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.geometry.physical_sector_size
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        true
+    }
+}
+
+impl AsyncDisk for VhdxDisk {
+    fn read_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.read(buffers, sector))
+    }
+
+    fn write_vectored<'a>(
+        &'a self,
+        buffers: &'a RequestBuffers<'a>,
+        sector: u64,
+        fua: bool,
+    ) -> StackFuture<'a, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.write(buffers, sector, fua))
+    }
+
+    fn sync_cache(&self) -> StackFuture<'_, Result<(), DiskError>, { ASYNC_DISK_STACK_SIZE }> {
+        StackFuture::from_or_box(self.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use super::format::FileParameters;
+    use super::format::MB;
+    use super::VhdxDisk;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::test_utilities::SECTOR_SIZE as SECTOR_USIZE;
+    use disk_backend::AsyncDisk;
+    use disk_backend::SimpleDisk;
+    use pal_async::async_test;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[async_test]
+    async fn write_read_reopen() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxDisk::create(&file, 64 * MB, MB as u32, 512).unwrap();
+        let disk = VhdxDisk::open(file.try_clone().unwrap(), None, false).unwrap();
+
+        assert_eq!(read(&disk, 100, 4).await, vec![0; 4 * SECTOR_USIZE]);
+        // Write across a block boundary.
+        write(&disk, 2046, &pattern(2046, 4, 1)).await;
+        for i in 0..100 {
+            write(&disk, i * 1237, &pattern(i * 1237, 3, 2)).await;
+        }
+        disk.sync_cache().await.unwrap();
+        assert!(disk.inner.state.lock().log.is_none());
+        drop(disk);
+
+        let disk = VhdxDisk::open(file, None, true).unwrap();
+        assert!(disk.inner.replay.is_none());
+        for i in 0..100 {
+            assert_eq!(read(&disk, i * 1237, 3).await, pattern(i * 1237, 3, 2));
+        }
+        assert_eq!(read(&disk, 2046, 4).await, pattern(2046, 4, 1));
+        assert_eq!(read(&disk, 2050, 1).await, vec![0; SECTOR_USIZE]);
+    }
+
+    #[async_test]
+    async fn log_replay() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxDisk::create(&file, 16 * MB, MB as u32, 512).unwrap();
+        let disk = VhdxDisk::open(file.try_clone().unwrap(), None, false).unwrap();
+        write(&disk, 0, &pattern(0, 8, 3)).await;
+        let bat_offset = disk.geometry.bat_offset;
+        let entry = disk.inner.state.lock().bat[0];
+        assert!(disk.inner.state.lock().log.is_some());
+        // Simulate a torn BAT update by reverting it in place, without
+        // flushing the disk, which would empty the log.
+        drop(disk);
+        disk_file::readwriteat::write_all_at(&file, &0u64.to_le_bytes(), bat_offset).unwrap();
+
+        // A read-only open sees the update through the log.
+        let disk = VhdxDisk::open(file.try_clone().unwrap(), None, true).unwrap();
+        assert!(disk.inner.replay.is_some());
+        assert_eq!(disk.inner.state.lock().bat[0], entry);
+        assert_eq!(read(&disk, 0, 8).await, pattern(0, 8, 3));
+        drop(disk);
+
+        // A writable open replays the log into the file.
+        let disk = VhdxDisk::open(file.try_clone().unwrap(), None, false).unwrap();
+        assert!(disk.inner.replay.is_none());
+        assert_eq!(read(&disk, 0, 8).await, pattern(0, 8, 3));
+        drop(disk);
+        let disk = VhdxDisk::open(file, None, true).unwrap();
+        assert!(disk.inner.replay.is_none());
+        assert_eq!(read(&disk, 0, 8).await, pattern(0, 8, 3));
+    }
+
+    #[async_test]
+    async fn differencing_read_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent_path = dir.path().join("parent.vhdx");
+        let parent_file = std::fs::File::create_new(&parent_path).unwrap();
+        VhdxDisk::create(&parent_file, 4 * MB, MB as u32, 512).unwrap();
+        let parent = VhdxDisk::open(parent_file.try_clone().unwrap(), None, false).unwrap();
+        write(&parent, 0, &pattern(0, 4 * MB as usize / SECTOR_USIZE, 7)).await;
+        parent.sync_cache().await.unwrap();
+
+        let child_file = tempfile::tempfile().unwrap();
+        VhdxDisk::create_differencing(
+            &child_file,
+            &parent_file,
+            Path::new("parent.vhdx"),
+            FileParameters::MIN_BLOCK_SIZE,
+        )
+        .unwrap();
+        let locator = super::read_parent_locator(&child_file).unwrap().unwrap();
+        assert_eq!(locator.relative_path.as_deref(), Some(".\\parent.vhdx"));
+        assert!(locator.matches(&parent_file).unwrap());
+
+        assert!(matches!(
+            VhdxDisk::open(child_file.try_clone().unwrap(), None, false),
+            Err(super::OpenError::MissingParent)
+        ));
+        let parent: Arc<dyn SimpleDisk> = Arc::new(parent);
+        let disk =
+            VhdxDisk::open(child_file.try_clone().unwrap(), Some(parent.clone()), false).unwrap();
+        assert_eq!(read(&disk, 10, 20).await, pattern(10, 20, 7));
+
+        // Partially overwrite a block. The block is only partially present, so
+        // the rest still comes from the parent.
+        write(&disk, 9, &pattern(9, 2, 3)).await;
+        let block_state = |disk: &VhdxDisk, block: u64| {
+            let state = disk.inner.state.lock();
+            state.bat[disk.geometry.payload_index(block)] & format::BAT_STATE_MASK
+        };
+        assert_eq!(
+            block_state(&disk, 0),
+            format::payload_state::PARTIALLY_PRESENT
+        );
+        // Write more of the same block, which extends its sector bitmap.
+        write(&disk, 12, &pattern(12, 1, 4)).await;
+        // A full block write makes its block fully present.
+        let block_sectors = FileParameters::MIN_BLOCK_SIZE as usize / SECTOR_USIZE;
+        write(
+            &disk,
+            block_sectors as u64,
+            &pattern(block_sectors as u64, block_sectors, 5),
+        )
+        .await;
+        assert_eq!(block_state(&disk, 1), format::payload_state::FULLY_PRESENT);
+        disk.sync_cache().await.unwrap();
+        drop(disk);
+
+        let disk = VhdxDisk::open(child_file, Some(parent), true).unwrap();
+        let data = read(&disk, 8, 8).await;
+        assert_eq!(data[..SECTOR_USIZE], pattern(8, 1, 7));
+        assert_eq!(data[SECTOR_USIZE..3 * SECTOR_USIZE], pattern(9, 2, 3));
+        assert_eq!(data[3 * SECTOR_USIZE..4 * SECTOR_USIZE], pattern(11, 1, 7));
+        assert_eq!(data[4 * SECTOR_USIZE..5 * SECTOR_USIZE], pattern(12, 1, 4));
+        assert_eq!(data[5 * SECTOR_USIZE..], pattern(13, 3, 7));
+        assert_eq!(
+            read(&disk, block_sectors as u64, block_sectors).await,
+            pattern(block_sectors as u64, block_sectors, 5)
+        );
+        assert_eq!(
+            block_state(&disk, 0),
+            format::payload_state::PARTIALLY_PRESENT
+        );
+    }
+}
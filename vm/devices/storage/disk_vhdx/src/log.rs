@@ -0,0 +1,324 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The VHDX metadata log.
+//!
+//! Updates to metadata (the BAT, sector bitmaps, and the metadata region) are
+//! first written to the circular log and flushed, so that they can be replayed
+//! if the update is torn.
+//!
+//! The writer here makes each entry self-contained: an entry's tail points at
+//! itself, and each entry is applied and flushed before the next is written.
+//! So only the newest entry ever needs to be replayed.
+
+use crate::format;
+use crate::format::LogDataSector;
+use crate::format::LogDescriptor;
+use crate::format::LogEntryHeader;
+use crate::format::LOG_SECTOR_SIZE;
+use disk_file::readwriteat::read_exact_at;
+use disk_file::readwriteat::write_all_at;
+use guid::Guid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+const DESCRIPTOR_SIZE: usize = size_of::<LogDescriptor>();
+const SECTOR: usize = LOG_SECTOR_SIZE as usize;
+
+/// A write recovered from the log.
+#[derive(Debug, Clone)]
+pub enum LogWrite {
+    /// Write a 4KB sector.
+    Data { offset: u64, data: Box<[u8]> },
+    /// Zero a range of the file.
+    Zero { offset: u64, len: u64 },
+}
+
+/// The writes that must be replayed from the log.
+#[derive(Debug)]
+pub struct Replay {
+    pub writes: Vec<LogWrite>,
+    /// The file must be at least this long for the log to be valid.
+    pub flushed_file_offset: u64,
+    /// The file must be extended to at least this length after replay.
+    pub last_file_offset: u64,
+}
+
+impl Replay {
+    /// Applies the writes to `buf`, which contains the file contents at
+    /// `offset`.
+    pub fn patch(&self, offset: u64, buf: &mut [u8]) {
+        let end = offset + buf.len() as u64;
+        for write in &self.writes {
+            let (write_offset, write_len) = match write {
+                LogWrite::Data { offset, data } => (*offset, data.len() as u64),
+                LogWrite::Zero { offset, len } => (*offset, *len),
+            };
+            let start = write_offset.max(offset);
+            let stop = (write_offset + write_len).min(end);
+            if start >= stop {
+                continue;
+            }
+            let dest = &mut buf[(start - offset) as usize..(stop - offset) as usize];
+            match write {
+                LogWrite::Data { data, .. } => dest.copy_from_slice(
+                    &data[(start - write_offset) as usize..(stop - write_offset) as usize],
+                ),
+                LogWrite::Zero { .. } => dest.fill(0),
+            }
+        }
+    }
+
+    /// Applies the writes to the file.
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        for write in &self.writes {
+            match write {
+                LogWrite::Data { offset, data } => write_all_at(file, data, *offset)?,
+                LogWrite::Zero { offset, len } => {
+                    let zero = vec![0; SECTOR];
+                    let mut pos = 0;
+                    while pos < *len {
+                        let n = (*len - pos).min(SECTOR as u64) as usize;
+                        write_all_at(file, &zero[..n], offset + pos)?;
+                        pos += n as u64;
+                    }
+                }
+            }
+        }
+        if file.metadata()?.len() < self.last_file_offset {
+            file.set_len(self.last_file_offset)?;
+        }
+        file.sync_all()
+    }
+}
+
+/// A parsed, valid log entry.
+struct Entry {
+    len: u32,
+    tail: u32,
+    sequence_number: u64,
+    flushed_file_offset: u64,
+    last_file_offset: u64,
+    writes: Vec<LogWrite>,
+}
+
+/// Parses the entry at `offset` in the log, which is stored twice in `log` so
+/// that entries that wrap around the end can be read contiguously.
+fn parse_entry(log: &[u8], log_length: u32, offset: u32, log_guid: Guid) -> Option<Entry> {
+    let data = &log[offset as usize..];
+    let header = LogEntryHeader::read_from_prefix(data)?;
+    if header.signature != LogEntryHeader::SIGNATURE
+        || header.log_guid != log_guid
+        || header.sequence_number == 0
+        || header.entry_length == 0
+        || header.entry_length % SECTOR as u32 != 0
+        || header.entry_length > log_length
+        || header.tail % SECTOR as u32 != 0
+        || header.tail >= log_length
+    {
+        return None;
+    }
+    let data = &data[..header.entry_length as usize];
+    if format::checksum_at_4(data) != header.checksum {
+        return None;
+    }
+
+    let count = header.descriptor_count as usize;
+    let descriptor_area =
+        (size_of::<LogEntryHeader>() + count * DESCRIPTOR_SIZE).checked_next_multiple_of(SECTOR)?;
+    let descriptors = data.get(size_of::<LogEntryHeader>()..)?;
+    let mut data_sectors = data.get(descriptor_area..)?.chunks_exact(SECTOR);
+    let mut writes = Vec::with_capacity(count);
+    for i in 0..count {
+        let desc = LogDescriptor::read_from_prefix(&descriptors[i * DESCRIPTOR_SIZE..])?;
+        if desc.sequence_number != header.sequence_number || desc.file_offset % LOG_SECTOR_SIZE != 0
+        {
+            return None;
+        }
+        let write = match desc.signature {
+            LogDescriptor::DATA_SIGNATURE => {
+                let sector = LogDataSector::read_from(data_sectors.next()?)?;
+                if sector.signature != LogDataSector::SIGNATURE
+                    || sector.sequence_high != (header.sequence_number >> 32) as u32
+                    || sector.sequence_low != header.sequence_number as u32
+                {
+                    return None;
+                }
+                let mut data = Vec::with_capacity(SECTOR);
+                data.extend_from_slice(&desc.leading_bytes.to_le_bytes());
+                data.extend_from_slice(&sector.data);
+                data.extend_from_slice(&desc.trailing_bytes.to_le_bytes());
+                LogWrite::Data {
+                    offset: desc.file_offset,
+                    data: data.into(),
+                }
+            }
+            LogDescriptor::ZERO_SIGNATURE => {
+                if desc.leading_bytes % LOG_SECTOR_SIZE != 0 {
+                    return None;
+                }
+                LogWrite::Zero {
+                    offset: desc.file_offset,
+                    len: desc.leading_bytes,
+                }
+            }
+            _ => return None,
+        };
+        writes.push(write);
+    }
+
+    Some(Entry {
+        len: header.entry_length,
+        tail: header.tail,
+        sequence_number: header.sequence_number,
+        flushed_file_offset: header.flushed_file_offset,
+        last_file_offset: header.last_file_offset,
+        writes,
+    })
+}
+
+/// Reads the log and finds the active sequence of entries.
+///
+/// Returns `None` if there are no entries to replay.
+pub fn read_log(
+    file: &File,
+    log_offset: u64,
+    log_length: u32,
+    log_guid: Guid,
+) -> io::Result<Option<Replay>> {
+    let mut log = vec![0; log_length as usize * 2];
+    read_exact_at(file, &mut log[..log_length as usize], log_offset)?;
+    log.copy_within(..log_length as usize, log_length as usize);
+
+    let entries: HashMap<u32, Entry> = (0..log_length)
+        .step_by(SECTOR)
+        .filter_map(|offset| Some((offset, parse_entry(&log, log_length, offset, log_guid)?)))
+        .collect();
+
+    // The active sequence ends at the entry with the largest sequence number
+    // whose tail leads to it through entries with consecutive sequence
+    // numbers.
+    let mut heads = entries.iter().collect::<Vec<_>>();
+    heads.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.sequence_number));
+    for (&head_offset, head) in heads {
+        let mut sequence = Vec::new();
+        let mut offset = head.tail;
+        let valid = loop {
+            let Some(entry) = entries.get(&offset) else {
+                break false;
+            };
+            if let Some(prev) = sequence.last().map(|e: &&Entry| e.sequence_number) {
+                if entry.sequence_number != prev + 1 {
+                    break false;
+                }
+            }
+            sequence.push(entry);
+            if offset == head_offset {
+                break entry.sequence_number == head.sequence_number;
+            }
+            if sequence.len() >= entries.len() {
+                break false;
+            }
+            offset = (offset + entry.len) % log_length;
+        };
+        if valid {
+            return Ok(Some(Replay {
+                writes: sequence
+                    .iter()
+                    .flat_map(|entry| entry.writes.iter().cloned())
+                    .collect(),
+                flushed_file_offset: head.flushed_file_offset,
+                last_file_offset: head.last_file_offset,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes entries to the log.
+pub struct LogWriter {
+    pub guid: Guid,
+    log_offset: u64,
+    log_length: u32,
+    position: u32,
+    sequence_number: u64,
+}
+
+impl LogWriter {
+    pub fn new(log_offset: u64, log_length: u32) -> Self {
+        Self {
+            guid: Guid::new_random(),
+            log_offset,
+            log_length,
+            position: 0,
+            sequence_number: 1,
+        }
+    }
+
+    /// Writes an entry describing the 4KB `sectors` to the log and flushes
+    /// it. `file_size` is the current length of the file.
+    pub fn write_entry(
+        &mut self,
+        file: &File,
+        sectors: &[(u64, &[u8])],
+        file_size: u64,
+    ) -> io::Result<()> {
+        let descriptor_area = (size_of::<LogEntryHeader>() + sectors.len() * DESCRIPTOR_SIZE)
+            .next_multiple_of(SECTOR);
+        let len = descriptor_area + sectors.len() * SECTOR;
+        if len > self.log_length as usize {
+            return Err(io::Error::other("vhdx log entry does not fit in the log"));
+        }
+        if self.position as usize + len > self.log_length as usize {
+            self.position = 0;
+        }
+
+        let sequence_number = self.sequence_number;
+        let mut entry = vec![0; len];
+        let mut header = LogEntryHeader {
+            signature: LogEntryHeader::SIGNATURE,
+            checksum: 0,
+            entry_length: len as u32,
+            tail: self.position,
+            sequence_number,
+            descriptor_count: sectors.len() as u32,
+            reserved: 0,
+            log_guid: self.guid,
+            flushed_file_offset: file_size,
+            last_file_offset: file_size,
+        };
+        for (i, &(offset, data)) in sectors.iter().enumerate() {
+            assert_eq!(data.len(), SECTOR);
+            let desc = LogDescriptor {
+                signature: LogDescriptor::DATA_SIGNATURE,
+                trailing_bytes: u32::from_le_bytes(data[SECTOR - 4..].try_into().unwrap()),
+                leading_bytes: u64::from_le_bytes(data[..8].try_into().unwrap()),
+                file_offset: offset,
+                sequence_number,
+            };
+            let mut sector = LogDataSector {
+                signature: LogDataSector::SIGNATURE,
+                sequence_high: (sequence_number >> 32) as u32,
+                data: FromZeroes::new_zeroed(),
+                sequence_low: sequence_number as u32,
+            };
+            sector.data.copy_from_slice(&data[8..SECTOR - 4]);
+            let desc_offset = size_of::<LogEntryHeader>() + i * DESCRIPTOR_SIZE;
+            entry[desc_offset..desc_offset + DESCRIPTOR_SIZE].copy_from_slice(desc.as_bytes());
+            entry[descriptor_area + i * SECTOR..][..SECTOR].copy_from_slice(sector.as_bytes());
+        }
+        entry[..size_of::<LogEntryHeader>()].copy_from_slice(header.as_bytes());
+        header.checksum = format::checksum_at_4(&entry);
+        entry[..size_of::<LogEntryHeader>()].copy_from_slice(header.as_bytes());
+
+        write_all_at(file, &entry, self.log_offset + self.position as u64)?;
+        file.sync_data()?;
+        self.position += len as u32;
+        self.sequence_number += 1;
+        Ok(())
+    }
+}
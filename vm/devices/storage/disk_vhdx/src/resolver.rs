@@ -0,0 +1,48 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for VHDX disks.
+
+use super::VhdxDisk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedSimpleDisk;
+use disk_backend_resources::VhdxDiskHandle;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+
+/// Resolver for a [`VhdxDiskHandle`].
+pub struct VhdxDiskResolver;
+
+declare_static_async_resolver!(VhdxDiskResolver, (DiskHandleKind, VhdxDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, VhdxDiskHandle> for VhdxDiskResolver {
+    type Output = ResolvedSimpleDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: VhdxDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let parent = if let Some(parent) = rsrc.parent {
+            let parent = resolver
+                .resolve(
+                    parent,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await?;
+            Some(parent.0)
+        } else {
+            None
+        };
+        Ok(VhdxDisk::open(rsrc.file, parent, input.read_only)?.into())
+    }
+}
@@ -9,6 +9,7 @@
 mod error;
 mod namespace;
 mod pci;
+mod pool;
 mod prp;
 mod queue;
 pub mod resolver;
@@ -19,6 +20,10 @@
 
 pub use pci::NvmeController;
 pub use pci::NvmeControllerCaps;
+pub use pool::NamespaceDiskFactory;
+pub use pool::NamespacePool;
+pub use pool::ResolvedNamespaceDiskFactory;
+pub use pool::POOL_SECTOR_SIZE;
 pub use workers::NsidConflict;
 pub use workers::NvmeControllerClient;
 
@@ -10,15 +10,22 @@
 use crate::prp::PrpRange;
 use crate::spec;
 use crate::spec::nvm;
+use crate::PAGE_SIZE;
 use disk_backend::SimpleDisk;
+use guestmem::ranges::PagedRange;
 use guestmem::GuestMemory;
 use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::OwnedRequestBuffers;
 use scsi_buffers::RequestBuffers;
 use std::sync::Arc;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
+/// The number of pages of zeroes written per request when zeroing blocks.
+const ZERO_PAGES: usize = 64;
+
 /// An NVMe namespace built on top of a [`SimpleDisk`].
 #[derive(Inspect)]
 pub struct Namespace {
@@ -27,6 +34,29 @@ pub struct Namespace {
     mem: GuestMemory,
     block_shift: u32,
     pr: bool,
+    /// A single page of zeroes, used as the source buffer for zeroing blocks.
+    #[inspect(skip)]
+    zero_page: GuestMemory,
+    /// Buffers from a previous compare command, for reuse by the next one.
+    #[inspect(skip)]
+    compare_buffers: Mutex<Option<CompareBuffers>>,
+}
+
+/// Buffers for comparing the disk's data with the guest's.
+struct CompareBuffers {
+    bounce: GuestMemory,
+    disk_data: Vec<u8>,
+    guest_data: Vec<u8>,
+}
+
+impl CompareBuffers {
+    fn new(len: usize) -> Self {
+        Self {
+            bounce: GuestMemory::allocate(len),
+            disk_data: vec![0; len],
+            guest_data: vec![0; len],
+        }
+    }
 }
 
 impl Namespace {
@@ -37,9 +67,17 @@ pub fn new(mem: GuestMemory, nsid: u32, disk: Arc<dyn SimpleDisk>) -> Self {
             mem,
             disk,
             nsid,
+            zero_page: GuestMemory::allocate(PAGE_SIZE),
+            compare_buffers: Mutex::new(None),
         }
     }
 
+    /// Returns the disk's unmap implementation if unmapped blocks read back as
+    /// zero, so that deallocating blocks also zeroes them.
+    fn zeroing_unmap(&self) -> Option<&dyn disk_backend::Unmap> {
+        self.disk.unmap().filter(|unmap| unmap.unmap_reads_zero())
+    }
+
     pub fn identify(&self, buf: &mut [u8]) {
         let id = nvm::IdentifyNamespace::mut_from_prefix(buf).unwrap();
         let size = self.disk.sector_count();
@@ -64,6 +102,13 @@ pub fn identify(&self, buf: &mut [u8]) {
             nlbaf: 0,
             flbas: nvm::Flbas::new().with_low_index(0),
             rescap,
+            dlfeat: if self.zeroing_unmap().is_some() {
+                nvm::Dlfeat::new()
+                    .with_read_value(1)
+                    .with_write_zeroes_deallocate(true)
+            } else {
+                nvm::Dlfeat::new()
+            },
             ..FromZeroes::new_zeroed()
         };
         id.lbaf[0] = nvm::Lbaf::new().with_lbads(self.block_shift as u8);
@@ -172,6 +217,64 @@ pub async fn nvm_command(
                     .await
                     .map_err(map_disk_error)?;
             }
+            nvm::NvmOpcode::COMPARE => {
+                let cdw10 = nvm::Cdw10ReadWrite::from(command.cdw10);
+                let cdw11 = nvm::Cdw11ReadWrite::from(command.cdw11);
+                let cdw12 = nvm::Cdw12ReadWrite::from(command.cdw12);
+                let lba = cdw10.sbla_low() as u64 | ((cdw11.sbla_high() as u64) << 32);
+                let count = cdw12.nlb_z() as usize + 1;
+                let byte_count = count << self.block_shift;
+                if byte_count > max_data_transfer_size {
+                    return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+                }
+                let range = PrpRange::parse(&self.mem, byte_count, command.dptr)?;
+
+                let disk_sector_count = self.disk.sector_count();
+                if disk_sector_count < lba || disk_sector_count - lba < count as u64 {
+                    return Err(spec::Status::LBA_OUT_OF_RANGE.into());
+                }
+
+                tracing::trace!(nsid = self.nsid, lba, count, byte_count, "compare");
+
+                let mut buffers = self
+                    .compare_buffers
+                    .lock()
+                    .take()
+                    .unwrap_or_else(|| CompareBuffers::new(max_data_transfer_size));
+                let result = self.compare(&mut buffers, &range, lba, byte_count).await;
+                *self.compare_buffers.lock() = Some(buffers);
+                result?;
+            }
+            nvm::NvmOpcode::WRITE_ZEROES => {
+                let cdw10 = nvm::Cdw10ReadWrite::from(command.cdw10);
+                let cdw11 = nvm::Cdw11ReadWrite::from(command.cdw11);
+                let cdw12 = nvm::Cdw12WriteZeroes::from(command.cdw12);
+                let lba = cdw10.sbla_low() as u64 | ((cdw11.sbla_high() as u64) << 32);
+                let count = cdw12.nlb_z() as u64 + 1;
+
+                let disk_sector_count = self.disk.sector_count();
+                if disk_sector_count < lba || disk_sector_count - lba < count {
+                    return Err(spec::Status::LBA_OUT_OF_RANGE.into());
+                }
+
+                tracing::trace!(
+                    nsid = self.nsid,
+                    lba,
+                    count,
+                    deac = cdw12.deac(),
+                    "write zeroes"
+                );
+
+                // Only deallocate the blocks if they then read back as zero,
+                // as reported in DLFEAT.
+                match self.zeroing_unmap().filter(|_| cdw12.deac()) {
+                    Some(unmap) => unmap
+                        .unmap(lba, count, false)
+                        .await
+                        .map_err(map_disk_error)?,
+                    None => self.write_zeroes(lba, count, cdw12.fua()).await?,
+                }
+            }
             nvm::NvmOpcode::FLUSH => {
                 tracing::debug!(nsid = self.nsid, "flush");
                 if !self.disk.is_read_only() {
@@ -220,6 +323,90 @@ pub async fn nvm_command(
         }
         Ok(Default::default())
     }
+
+    /// Formats the namespace with the only supported LBA format, erasing
+    /// user data as requested by `ses`.
+    pub async fn format(&self, ses: spec::SecureEraseSettings) -> Result<(), NvmeError> {
+        tracing::info!(nsid = self.nsid, ?ses, "format");
+        let sector_count = self.disk.sector_count();
+        match ses {
+            spec::SecureEraseSettings::NONE => {
+                // The contents of the blocks are indeterminate after a format
+                // without secure erase, so deallocating them is sufficient.
+                if let Some(unmap) = self.disk.unmap() {
+                    unmap
+                        .unmap(0, sector_count, false)
+                        .await
+                        .map_err(map_disk_error)?;
+                }
+            }
+            spec::SecureEraseSettings::USER_DATA_ERASE => match self.zeroing_unmap() {
+                Some(unmap) => unmap
+                    .unmap(0, sector_count, false)
+                    .await
+                    .map_err(map_disk_error)?,
+                None => self.write_zeroes(0, sector_count, false).await?,
+            },
+            ses => {
+                tracelimit::warn_ratelimited!(nsid = self.nsid, ?ses, "unsupported secure erase");
+                return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the `byte_count` bytes of guest memory described by `range`
+    /// with the disk's data starting at `lba`.
+    async fn compare(
+        &self,
+        buffers: &mut CompareBuffers,
+        range: &PrpRange,
+        lba: u64,
+        byte_count: usize,
+    ) -> Result<(), NvmeError> {
+        // Buffers from an earlier command may be too small if the maximum
+        // transfer size has since grown.
+        if buffers.disk_data.len() < byte_count {
+            *buffers = CompareBuffers::new(byte_count);
+        }
+        let bounce_buffers = OwnedRequestBuffers::linear(0, byte_count, true);
+        self.disk
+            .read_vectored(&bounce_buffers.buffer(&buffers.bounce), lba)
+            .await
+            .map_err(map_disk_error)?;
+
+        let disk_data = &mut buffers.disk_data[..byte_count];
+        buffers
+            .bounce
+            .read_at(0, disk_data)
+            .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+        let guest_data = &mut buffers.guest_data[..byte_count];
+        range.read(&self.mem, guest_data)?;
+        if disk_data != guest_data {
+            return Err(spec::Status::MEDIA_COMPARE_FAILURE.into());
+        }
+        Ok(())
+    }
+
+    /// Writes zeroes to `count` blocks starting at `lba`.
+    async fn write_zeroes(&self, mut lba: u64, mut count: u64, fua: bool) -> Result<(), NvmeError> {
+        // Map every page of the request to the same zero page.
+        let gpns = [0; ZERO_PAGES];
+        let max_count = ((ZERO_PAGES * PAGE_SIZE) >> self.block_shift) as u64;
+        while count > 0 {
+            let this_count = count.min(max_count);
+            let range = PagedRange::new(0, (this_count << self.block_shift) as usize, &gpns)
+                .expect("range fits in the zero pages");
+            let buffers = RequestBuffers::new(&self.zero_page, range, false);
+            self.disk
+                .write_vectored(&buffers, lba, fua)
+                .await
+                .map_err(map_disk_error)?;
+            lba += this_count;
+            count -= this_count;
+        }
+        Ok(())
+    }
 }
 
 fn map_disk_error(err: disk_backend::DiskError) -> NvmeError {
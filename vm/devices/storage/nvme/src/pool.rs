@@ -0,0 +1,52 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Capacity pool for namespaces created by the guest.
+
+use async_trait::async_trait;
+use disk_backend::SimpleDisk;
+use nvme_resources::NamespaceDiskTemplateKind;
+use std::sync::Arc;
+use vm_resource::CanResolveTo;
+use vmcore::vm_task::VmTaskDriverSource;
+
+/// The sector size of guest-created namespaces.
+pub const POOL_SECTOR_SIZE: u32 = 512;
+
+/// A factory for the disks backing guest-created namespaces.
+#[async_trait]
+pub trait NamespaceDiskFactory: Send + Sync {
+    /// Creates a disk with `sector_count` sectors of `sector_size` bytes to
+    /// back namespace `nsid`.
+    async fn create_disk(
+        &self,
+        nsid: u32,
+        sector_size: u32,
+        sector_count: u64,
+    ) -> anyhow::Result<Arc<dyn SimpleDisk>>;
+}
+
+impl CanResolveTo<ResolvedNamespaceDiskFactory> for NamespaceDiskTemplateKind {
+    type Input<'a> = &'a VmTaskDriverSource;
+}
+
+/// A resolved namespace disk template.
+pub struct ResolvedNamespaceDiskFactory(pub Box<dyn NamespaceDiskFactory>);
+
+impl<T: 'static + NamespaceDiskFactory> From<T> for ResolvedNamespaceDiskFactory {
+    fn from(value: T) -> Self {
+        Self(Box::new(value))
+    }
+}
+
+/// A host-configured pool of capacity from which the guest can create
+/// namespaces with the namespace management commands.
+pub struct NamespacePool {
+    /// The total capacity of the pool, in bytes.
+    pub capacity: u64,
+    /// The maximum number of namespaces, which also bounds the namespace IDs
+    /// assigned to guest-created namespaces.
+    pub max_namespaces: u32,
+    /// The factory used to create the backing disks.
+    pub factory: Box<dyn NamespaceDiskFactory>,
+}
@@ -3,21 +3,34 @@
 
 //! Resource resolver for the nvme controller.
 
+use crate::NamespaceDiskFactory;
+use crate::NamespacePool;
 use crate::NsidConflict;
 use crate::NvmeController;
 use crate::NvmeControllerCaps;
+use crate::ResolvedNamespaceDiskFactory;
 use async_trait::async_trait;
 use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::SimpleDisk;
+use disk_backend_resources::RamDiskHandle;
 use nvme_resources::NamespaceDefinition;
+use nvme_resources::NamespaceDiskTemplateKind;
+use nvme_resources::NamespacePoolDefinition;
 use nvme_resources::NvmeControllerHandle;
+use nvme_resources::RamNamespaceDiskTemplateHandle;
 use pci_resources::ResolvePciDeviceHandleParams;
 use pci_resources::ResolvedPciDevice;
+use std::convert::Infallible;
+use std::sync::Arc;
 use thiserror::Error;
 use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
 use vm_resource::kind::PciDeviceHandleKind;
 use vm_resource::AsyncResolveResource;
 use vm_resource::ResolveError;
+use vm_resource::Resource;
 use vm_resource::ResourceResolver;
+use vmcore::vm_task::VmTaskDriverSource;
 
 /// Resource resolver for [`NvmeControllerHandle`].
 pub struct NvmeControllerResolver;
@@ -39,6 +52,8 @@ pub enum Error {
     },
     #[error(transparent)]
     NsidConflict(NsidConflict),
+    #[error("failed to resolve the namespace pool disk template")]
+    NamespaceDiskTemplate(#[source] ResolveError),
 }
 
 #[async_trait]
@@ -74,7 +89,7 @@ async fn resolve(
                     disk,
                     ResolveDiskParameters {
                         read_only,
-                        _async_trait_workaround: &(),
+                        driver_source: input.driver_source,
                     },
                 )
                 .await
@@ -85,6 +100,84 @@ async fn resolve(
                 .await
                 .map_err(Error::NsidConflict)?;
         }
+        if let Some(NamespacePoolDefinition {
+            capacity,
+            max_namespaces,
+            disk_template,
+        }) = resource.namespace_pool
+        {
+            let factory = resolver
+                .resolve(disk_template, input.driver_source)
+                .await
+                .map_err(Error::NamespaceDiskTemplate)?;
+            controller
+                .client()
+                .set_namespace_pool(NamespacePool {
+                    capacity,
+                    max_namespaces,
+                    factory: factory.0,
+                })
+                .await;
+        }
         Ok(controller.into())
     }
 }
+
+/// Resource resolver for [`RamNamespaceDiskTemplateHandle`].
+pub struct RamNamespaceDiskTemplateResolver;
+
+declare_static_async_resolver! {
+    RamNamespaceDiskTemplateResolver,
+    (NamespaceDiskTemplateKind, RamNamespaceDiskTemplateHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<NamespaceDiskTemplateKind, RamNamespaceDiskTemplateHandle>
+    for RamNamespaceDiskTemplateResolver
+{
+    type Output = ResolvedNamespaceDiskFactory;
+    type Error = Infallible;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        _resource: RamNamespaceDiskTemplateHandle,
+        driver_source: &VmTaskDriverSource,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(RamDiskFactory {
+            resolver: resolver.clone(),
+            driver_source: driver_source.clone(),
+        }
+        .into())
+    }
+}
+
+/// Creates RAM disks for guest-created namespaces.
+struct RamDiskFactory {
+    resolver: ResourceResolver,
+    driver_source: VmTaskDriverSource,
+}
+
+#[async_trait]
+impl NamespaceDiskFactory for RamDiskFactory {
+    async fn create_disk(
+        &self,
+        _nsid: u32,
+        sector_size: u32,
+        sector_count: u64,
+    ) -> anyhow::Result<Arc<dyn SimpleDisk>> {
+        let disk = self
+            .resolver
+            .resolve(
+                Resource::<DiskHandleKind>::new(RamDiskHandle {
+                    len: sector_count * sector_size as u64,
+                }),
+                ResolveDiskParameters {
+                    read_only: false,
+                    driver_source: &self.driver_source,
+                },
+            )
+            .await?;
+        Ok(disk.0)
+    }
+}
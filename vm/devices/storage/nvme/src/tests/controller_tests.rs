@@ -2,27 +2,40 @@
 // Licensed under the MIT License.
 
 use super::test_helpers::TestNvmeMmioRegistration;
+use crate::error::CommandResult;
+use crate::namespace::Namespace;
 use crate::prp::PrpRange;
 use crate::spec;
+use crate::spec::nvm;
 use crate::tests::test_helpers::read_completion_from_queue;
 use crate::tests::test_helpers::test_memory;
 use crate::tests::test_helpers::write_command_to_queue;
+use crate::NamespaceDiskFactory;
+use crate::NamespacePool;
 use crate::NvmeController;
 use crate::NvmeControllerCaps;
 use crate::BAR0_LEN;
+use crate::PAGE_SIZE;
 use crate::PAGE_SIZE64;
+use crate::POOL_SECTOR_SIZE;
+use async_trait::async_trait;
 use chipset_device::mmio::MmioIntercept;
 use chipset_device::pci::PciConfigSpace;
+use disk_backend::SimpleDisk;
+use disk_ramdisk::RamDisk;
 use guestmem::GuestMemory;
 use guid::Guid;
 use pal_async::async_test;
 use pal_async::DefaultDriver;
 use pci_core::msi::MsiInterruptSet;
 use pci_core::test_helpers::TestPciInterruptController;
+use scsi_buffers::OwnedRequestBuffers;
+use std::sync::Arc;
 use user_driver::backoff::Backoff;
 use vmcore::vm_task::SingleDriverBackend;
 use vmcore::vm_task::VmTaskDriverSource;
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 fn instantiate_controller(
@@ -326,3 +339,918 @@ async fn test_send_identify(driver: DefaultDriver) {
     let cqe = read_completion_from_queue(&gm, &dm1, 0);
     assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
 }
+
+struct RamDiskFactory;
+
+#[async_trait]
+impl NamespaceDiskFactory for RamDiskFactory {
+    async fn create_disk(
+        &self,
+        _nsid: u32,
+        sector_size: u32,
+        sector_count: u64,
+    ) -> anyhow::Result<Arc<dyn SimpleDisk>> {
+        Ok(Arc::new(RamDisk::new(
+            sector_count * sector_size as u64,
+            false,
+        )?))
+    }
+}
+
+async fn submit_admin_command(
+    driver: DefaultDriver,
+    nvmec: &mut NvmeController,
+    int_controller: &TestPciInterruptController,
+    gm: &GuestMemory,
+    acq: &PrpRange,
+    asq: &PrpRange,
+    slot: usize,
+    command: &spec::Command,
+) -> spec::Completion {
+    write_command_to_queue(gm, asq, slot, command);
+    nvmec
+        .write_bar0(0x1000, (slot as u32 + 1).as_bytes())
+        .unwrap();
+    wait_for_msi(driver, int_controller, 1000, 0xfeed0000, 0x1111).await;
+    read_completion_from_queue(gm, acq, slot)
+}
+
+#[async_test]
+async fn test_namespace_management(driver: DefaultDriver) {
+    let dm1 = PrpRange::new(vec![0], 0, PAGE_SIZE64).unwrap();
+    let dm2 = PrpRange::new(vec![0x1000], 0, PAGE_SIZE64).unwrap();
+    let gm = test_memory();
+    let int_controller = TestPciInterruptController::new();
+
+    let mut nvmec = instantiate_and_build_admin_queue(
+        &dm1,
+        64,
+        &dm2,
+        64,
+        true,
+        Some(&int_controller),
+        driver.clone(),
+        &gm,
+    )
+    .await;
+
+    nvmec
+        .client()
+        .set_namespace_pool(NamespacePool {
+            capacity: 1024 * 1024,
+            max_namespaces: 4,
+            factory: Box::new(RamDiskFactory),
+        })
+        .await;
+
+    // Creating a namespace larger than the pool should fail.
+    let sector_count = 2 * 1024 * 1024 / POOL_SECTOR_SIZE as u64;
+    let data = nvm::IdentifyNamespace {
+        nsze: sector_count,
+        ncap: sector_count,
+        ..FromZeroes::new_zeroed()
+    };
+    gm.write_plain(0x2000, &data).unwrap();
+    let mut command = spec::Command::new_zeroed();
+    command
+        .cdw0
+        .set_opcode(spec::AdminOpcode::NAMESPACE_MANAGEMENT.0);
+    command.cdw10 = spec::Cdw10NamespaceManagement::new()
+        .with_sel(spec::NamespaceManagementSelect::CREATE.0)
+        .into();
+    command.dptr[0] = 0x2000;
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        0,
+        &command,
+    )
+    .await;
+    assert_eq!(
+        cqe.status.status(),
+        spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY.0
+    );
+
+    // Create a namespace that fits.
+    let data = nvm::IdentifyNamespace {
+        nsze: sector_count / 4,
+        ncap: sector_count / 4,
+        ..FromZeroes::new_zeroed()
+    };
+    gm.write_plain(0x2000, &data).unwrap();
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        1,
+        &command,
+    )
+    .await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    let nsid = cqe.dw0;
+    assert_eq!(nsid, 1);
+
+    // Attach it to this controller.
+    let mut list = spec::ControllerList::new_zeroed();
+    list.count = 1;
+    gm.write_plain(0x3000, &list).unwrap();
+    let mut command = spec::Command::new_zeroed();
+    command
+        .cdw0
+        .set_opcode(spec::AdminOpcode::NAMESPACE_ATTACHMENT.0);
+    command.cdw10 = spec::Cdw10NamespaceAttachment::new()
+        .with_sel(spec::NamespaceAttachmentSelect::ATTACH.0)
+        .into();
+    command.nsid = nsid;
+    command.dptr[0] = 0x3000;
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        2,
+        &command,
+    )
+    .await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+
+    // The namespace should now be active.
+    let mut command = spec::Command::new_zeroed();
+    command.cdw0.set_opcode(spec::AdminOpcode::IDENTIFY.0);
+    command.cdw10 = spec::Cdw10Identify::new()
+        .with_cns(spec::Cns::ACTIVE_NAMESPACES.0)
+        .into();
+    command.dptr[0] = 0x4000;
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        3,
+        &command,
+    )
+    .await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    let nsids: [u32; 2] = gm.read_plain(0x4000).unwrap();
+    assert_eq!(nsids, [nsid, 0]);
+}
+
+/// An admin queue on a controller with a namespace pool, which tracks the
+/// submission and completion queue slots so that commands, such as
+/// asynchronous event requests, can complete out of order.
+struct PoolTest {
+    driver: DefaultDriver,
+    gm: GuestMemory,
+    int_controller: TestPciInterruptController,
+    nvmec: NvmeController,
+    acq: PrpRange,
+    asq: PrpRange,
+    sq_tail: usize,
+    cq_head: usize,
+}
+
+const POOL_DATA_GPA: u64 = 0x2000;
+const POOL_LIST_GPA: u64 = 0x3000;
+const POOL_OUTPUT_GPA: u64 = 0x4000;
+
+impl PoolTest {
+    async fn new(driver: DefaultDriver, capacity: u64, max_namespaces: u32) -> Self {
+        let acq = PrpRange::new(vec![0], 0, PAGE_SIZE64).unwrap();
+        let asq = PrpRange::new(vec![0x1000], 0, PAGE_SIZE64).unwrap();
+        let gm = test_memory();
+        let int_controller = TestPciInterruptController::new();
+        let nvmec = instantiate_and_build_admin_queue(
+            &acq,
+            64,
+            &asq,
+            64,
+            true,
+            Some(&int_controller),
+            driver.clone(),
+            &gm,
+        )
+        .await;
+        nvmec
+            .client()
+            .set_namespace_pool(NamespacePool {
+                capacity,
+                max_namespaces,
+                factory: Box::new(RamDiskFactory),
+            })
+            .await;
+        Self {
+            driver,
+            gm,
+            int_controller,
+            nvmec,
+            acq,
+            asq,
+            sq_tail: 0,
+            cq_head: 0,
+        }
+    }
+
+    /// Submits `command` without waiting for it to complete, returning its
+    /// command ID.
+    fn submit(&mut self, mut command: spec::Command) -> u16 {
+        let cid = self.sq_tail as u16;
+        command.cdw0.set_cid(cid);
+        write_command_to_queue(&self.gm, &self.asq, self.sq_tail, &command);
+        self.sq_tail += 1;
+        self.nvmec
+            .write_bar0(0x1000, (self.sq_tail as u32).as_bytes())
+            .unwrap();
+        cid
+    }
+
+    async fn next_completion(&mut self) -> spec::Completion {
+        wait_for_msi(
+            self.driver.clone(),
+            &self.int_controller,
+            1000,
+            0xfeed0000,
+            0x1111,
+        )
+        .await;
+        let cqe = read_completion_from_queue(&self.gm, &self.acq, self.cq_head);
+        self.cq_head += 1;
+        cqe
+    }
+
+    async fn command(&mut self, command: spec::Command) -> spec::Completion {
+        let cid = self.submit(command);
+        let cqe = self.next_completion().await;
+        assert_eq!(cqe.cid, cid);
+        cqe
+    }
+
+    /// Creates a namespace of `sector_count` sectors, returning the status and
+    /// the new namespace ID.
+    async fn create(&mut self, sector_count: u64) -> (spec::Status, u32) {
+        let data = nvm::IdentifyNamespace {
+            nsze: sector_count,
+            ncap: sector_count,
+            ..FromZeroes::new_zeroed()
+        };
+        self.gm.write_plain(POOL_DATA_GPA, &data).unwrap();
+        let mut command = spec::Command::new_zeroed();
+        command
+            .cdw0
+            .set_opcode(spec::AdminOpcode::NAMESPACE_MANAGEMENT.0);
+        command.cdw10 = spec::Cdw10NamespaceManagement::new()
+            .with_sel(spec::NamespaceManagementSelect::CREATE.0)
+            .into();
+        command.dptr[0] = POOL_DATA_GPA;
+        let cqe = self.command(command).await;
+        (spec::Status(cqe.status.status()), cqe.dw0)
+    }
+
+    async fn delete(&mut self, nsid: u32) -> spec::Status {
+        let mut command = spec::Command::new_zeroed();
+        command
+            .cdw0
+            .set_opcode(spec::AdminOpcode::NAMESPACE_MANAGEMENT.0);
+        command.cdw10 = spec::Cdw10NamespaceManagement::new()
+            .with_sel(spec::NamespaceManagementSelect::DELETE.0)
+            .into();
+        command.nsid = nsid;
+        let cqe = self.command(command).await;
+        spec::Status(cqe.status.status())
+    }
+
+    fn attachment_command(sel: spec::NamespaceAttachmentSelect, nsid: u32) -> spec::Command {
+        let mut command = spec::Command::new_zeroed();
+        command
+            .cdw0
+            .set_opcode(spec::AdminOpcode::NAMESPACE_ATTACHMENT.0);
+        command.cdw10 = spec::Cdw10NamespaceAttachment::new().with_sel(sel.0).into();
+        command.nsid = nsid;
+        command.dptr[0] = POOL_LIST_GPA;
+        command
+    }
+
+    async fn attachment(
+        &mut self,
+        sel: spec::NamespaceAttachmentSelect,
+        nsid: u32,
+    ) -> spec::Status {
+        let mut list = spec::ControllerList::new_zeroed();
+        list.count = 1;
+        self.gm.write_plain(POOL_LIST_GPA, &list).unwrap();
+        let cqe = self.command(Self::attachment_command(sel, nsid)).await;
+        spec::Status(cqe.status.status())
+    }
+
+    async fn attach(&mut self, nsid: u32) -> spec::Status {
+        self.attachment(spec::NamespaceAttachmentSelect::ATTACH, nsid)
+            .await
+    }
+
+    async fn detach(&mut self, nsid: u32) -> spec::Status {
+        self.attachment(spec::NamespaceAttachmentSelect::DETACH, nsid)
+            .await
+    }
+
+    /// Issues an identify command, returning the status and the 4096-byte
+    /// result.
+    async fn identify(&mut self, cns: spec::Cns, nsid: u32, cntid: u16) -> (spec::Status, Vec<u8>) {
+        let mut command = spec::Command::new_zeroed();
+        command.cdw0.set_opcode(spec::AdminOpcode::IDENTIFY.0);
+        command.cdw10 = spec::Cdw10Identify::new()
+            .with_cns(cns.0)
+            .with_cntid(cntid)
+            .into();
+        command.nsid = nsid;
+        command.dptr[0] = POOL_OUTPUT_GPA;
+        self.gm.fill_at(POOL_OUTPUT_GPA, 0xcc, 4096).unwrap();
+        let cqe = self.command(command).await;
+        let mut buf = vec![0; 4096];
+        self.gm.read_at(POOL_OUTPUT_GPA, &mut buf).unwrap();
+        (spec::Status(cqe.status.status()), buf)
+    }
+
+    /// Returns the namespace IDs in an identify namespace list.
+    async fn namespace_list(&mut self, cns: spec::Cns, nsid: u32) -> Vec<u32> {
+        let (status, buf) = self.identify(cns, nsid, 0).await;
+        assert_eq!(status, spec::Status::SUCCESS);
+        u32::slice_from(&buf)
+            .unwrap()
+            .iter()
+            .copied()
+            .take_while(|&nsid| nsid != 0)
+            .collect()
+    }
+
+    /// Returns the controller IDs in an identify controller list.
+    async fn controller_list(&mut self, cns: spec::Cns, nsid: u32) -> Vec<u16> {
+        let (status, buf) = self.identify(cns, nsid, 0).await;
+        assert_eq!(status, spec::Status::SUCCESS);
+        let list = spec::ControllerList::read_from(&buf[..]).unwrap();
+        list.ids[..list.count as usize].to_vec()
+    }
+
+    async fn identify_controller(&mut self) -> spec::IdentifyController {
+        let (status, buf) = self.identify(spec::Cns::CONTROLLER, 0, 0).await;
+        assert_eq!(status, spec::Status::SUCCESS);
+        spec::IdentifyController::read_from_prefix(&buf[..]).unwrap()
+    }
+
+    fn get_changed_namespace_list_command() -> spec::Command {
+        let mut command = spec::Command::new_zeroed();
+        command.cdw0.set_opcode(spec::AdminOpcode::GET_LOG_PAGE.0);
+        command.cdw10 = spec::Cdw10GetLogPage::new()
+            .with_lid(spec::LogPageIdentifier::CHANGED_NAMESPACE_LIST.0)
+            .with_numdl_z(1023)
+            .into();
+        command.nsid = !0;
+        command.dptr[0] = POOL_OUTPUT_GPA;
+        command
+    }
+
+    /// Reads and clears the changed namespace list log page.
+    async fn changed_namespaces(&mut self) -> Vec<u32> {
+        self.gm.fill_at(POOL_OUTPUT_GPA, 0xcc, 4096).unwrap();
+        let cqe = self
+            .command(Self::get_changed_namespace_list_command())
+            .await;
+        assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+        let mut buf = vec![0; 4096];
+        self.gm.read_at(POOL_OUTPUT_GPA, &mut buf).unwrap();
+        u32::slice_from(&buf)
+            .unwrap()
+            .iter()
+            .copied()
+            .take_while(|&nsid| nsid != 0)
+            .collect()
+    }
+
+    fn asynchronous_event_request_command() -> spec::Command {
+        let mut command = spec::Command::new_zeroed();
+        command
+            .cdw0
+            .set_opcode(spec::AdminOpcode::ASYNCHRONOUS_EVENT_REQUEST.0);
+        command
+    }
+}
+
+/// The size of the pool in the namespace pool tests, in sectors.
+const POOL_SECTORS: u64 = 1024 * 1024 / POOL_SECTOR_SIZE as u64;
+
+#[async_test]
+async fn test_namespace_delete(driver: DefaultDriver) {
+    let mut t = PoolTest::new(driver, POOL_SECTORS * POOL_SECTOR_SIZE as u64, 4).await;
+    assert_eq!(t.create(POOL_SECTORS / 2).await, (spec::Status::SUCCESS, 1));
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 2));
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 3));
+    assert_eq!(t.attach(1).await, spec::Status::SUCCESS);
+    assert_eq!(t.identify_controller().await.unvmcap.get(), 0);
+
+    // Deleting an attached namespace detaches it and returns its capacity to
+    // the pool.
+    assert_eq!(t.delete(1).await, spec::Status::SUCCESS);
+    assert_eq!(
+        t.namespace_list(spec::Cns::ACTIVE_NAMESPACES, 0).await,
+        [] as [u32; 0]
+    );
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0)
+            .await,
+        [2, 3]
+    );
+    assert_eq!(
+        t.identify_controller().await.unvmcap.get(),
+        (POOL_SECTORS / 2 * POOL_SECTOR_SIZE as u64).into()
+    );
+
+    // The namespace no longer exists.
+    assert_eq!(t.delete(1).await, spec::Status::INVALID_NAMESPACE_OR_FORMAT);
+    assert_eq!(t.attach(1).await, spec::Status::INVALID_NAMESPACE_OR_FORMAT);
+
+    // Its ID is reused.
+    assert_eq!(t.create(POOL_SECTORS / 2).await, (spec::Status::SUCCESS, 1));
+
+    // Delete all of them.
+    assert_eq!(t.delete(!0).await, spec::Status::SUCCESS);
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0)
+            .await,
+        [] as [u32; 0]
+    );
+    assert_eq!(
+        t.identify_controller().await.unvmcap.get(),
+        (POOL_SECTORS * POOL_SECTOR_SIZE as u64).into()
+    );
+}
+
+#[async_test]
+async fn test_namespace_attachment(driver: DefaultDriver) {
+    let mut t = PoolTest::new(driver, POOL_SECTORS * POOL_SECTOR_SIZE as u64, 4).await;
+    assert_eq!(t.create(POOL_SECTORS / 2).await, (spec::Status::SUCCESS, 1));
+
+    // A namespace that was never attached cannot be detached.
+    assert_eq!(t.detach(1).await, spec::Status::NAMESPACE_NOT_ATTACHED);
+
+    assert_eq!(t.attach(1).await, spec::Status::SUCCESS);
+    assert_eq!(t.attach(1).await, spec::Status::NAMESPACE_ALREADY_ATTACHED);
+    assert_eq!(t.namespace_list(spec::Cns::ACTIVE_NAMESPACES, 0).await, [1]);
+
+    assert_eq!(t.detach(1).await, spec::Status::SUCCESS);
+    assert_eq!(
+        t.namespace_list(spec::Cns::ACTIVE_NAMESPACES, 0).await,
+        [] as [u32; 0]
+    );
+    assert_eq!(t.detach(1).await, spec::Status::NAMESPACE_NOT_ATTACHED);
+
+    // Detaching leaves the namespace allocated, so it can be attached again.
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0)
+            .await,
+        [1]
+    );
+    assert_eq!(t.attach(1).await, spec::Status::SUCCESS);
+
+    // Namespaces that were never created cannot be attached or detached.
+    assert_eq!(t.attach(2).await, spec::Status::INVALID_NAMESPACE_OR_FORMAT);
+    assert_eq!(t.detach(2).await, spec::Status::INVALID_NAMESPACE_OR_FORMAT);
+
+    // Only this controller can be in the controller list.
+    let mut list = spec::ControllerList::new_zeroed();
+    list.count = 1;
+    list.ids[0] = 1;
+    t.gm.write_plain(POOL_LIST_GPA, &list).unwrap();
+    let cqe = t
+        .command(PoolTest::attachment_command(
+            spec::NamespaceAttachmentSelect::DETACH,
+            1,
+        ))
+        .await;
+    assert_eq!(cqe.status.status(), spec::Status::CONTROLLER_LIST_INVALID.0);
+}
+
+#[async_test]
+async fn test_namespace_change_notification(driver: DefaultDriver) {
+    let mut t = PoolTest::new(driver, POOL_SECTORS * POOL_SECTOR_SIZE as u64, 4).await;
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 1));
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 2));
+
+    // Creating namespaces does not change the active namespaces, so an
+    // outstanding event request only completes once one is attached.
+    let mut list = spec::ControllerList::new_zeroed();
+    list.count = 1;
+    t.gm.write_plain(POOL_LIST_GPA, &list).unwrap();
+    let aer_cid = t.submit(PoolTest::asynchronous_event_request_command());
+    let attach_cid = t.submit(PoolTest::attachment_command(
+        spec::NamespaceAttachmentSelect::ATTACH,
+        2,
+    ));
+    let cqe = t.next_completion().await;
+    assert_eq!(cqe.cid, attach_cid);
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+
+    let cqe = t.next_completion().await;
+    assert_eq!(cqe.cid, aer_cid);
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    let dw0 = spec::AsynchronousEventRequestDw0::from(cqe.dw0);
+    assert_eq!(dw0.event_type(), spec::AsynchronousEventType::NOTICE.0);
+    assert_eq!(
+        dw0.information(),
+        spec::AsynchronousEventInformationNotice::NAMESPACE_ATTRIBUTE_CHANGED.0
+    );
+    assert_eq!(
+        dw0.log_page_identifier(),
+        spec::LogPageIdentifier::CHANGED_NAMESPACE_LIST.0
+    );
+
+    // Reading the log clears it.
+    assert_eq!(t.changed_namespaces().await, [2]);
+    assert_eq!(t.changed_namespaces().await, [] as [u32; 0]);
+
+    // Changes made without an outstanding request are reported by the next
+    // one.
+    assert_eq!(t.attach(1).await, spec::Status::SUCCESS);
+    assert_eq!(t.detach(2).await, spec::Status::SUCCESS);
+    let cqe = t
+        .command(PoolTest::asynchronous_event_request_command())
+        .await;
+    assert_eq!(
+        spec::AsynchronousEventRequestDw0::from(cqe.dw0).information(),
+        spec::AsynchronousEventInformationNotice::NAMESPACE_ATTRIBUTE_CHANGED.0
+    );
+    assert_eq!(t.changed_namespaces().await, [1, 2]);
+
+    // Deleting an attached namespace is a change, but deleting a detached one
+    // is not.
+    assert_eq!(t.delete(2).await, spec::Status::SUCCESS);
+    assert_eq!(t.delete(1).await, spec::Status::SUCCESS);
+    assert_eq!(t.changed_namespaces().await, [1]);
+}
+
+#[async_test]
+async fn test_identify_pool_namespaces(driver: DefaultDriver) {
+    let capacity = POOL_SECTORS * POOL_SECTOR_SIZE as u64;
+    let mut t = PoolTest::new(driver, capacity, 4).await;
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 1));
+    assert_eq!(t.create(POOL_SECTORS / 2).await, (spec::Status::SUCCESS, 2));
+    assert_eq!(t.attach(2).await, spec::Status::SUCCESS);
+
+    let id = t.identify_controller().await;
+    assert!(id.oacs.ns_management());
+    assert_eq!(id.tnvmcap.get(), capacity.into());
+    assert_eq!(id.unvmcap.get(), (capacity / 4).into());
+    assert_eq!(id.nn, 4);
+
+    // Allocated namespace list (CNS 0x10), which includes detached
+    // namespaces, starting after the given ID.
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0)
+            .await,
+        [1, 2]
+    );
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 1)
+            .await,
+        [2]
+    );
+    assert_eq!(t.namespace_list(spec::Cns::ACTIVE_NAMESPACES, 0).await, [2]);
+    let (status, _) = t
+        .identify(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0xfffffffe, 0)
+        .await;
+    assert_eq!(status, spec::Status::INVALID_NAMESPACE_OR_FORMAT);
+
+    // Allocated namespace (CNS 0x11) reports detached namespaces, which the
+    // active namespace identify does not.
+    let (status, buf) = t.identify(spec::Cns::ALLOCATED_NAMESPACE, 1, 0).await;
+    assert_eq!(status, spec::Status::SUCCESS);
+    let ns = nvm::IdentifyNamespace::read_from_prefix(&buf[..]).unwrap();
+    assert_eq!(ns.nsze, POOL_SECTORS / 4);
+    let (status, buf) = t.identify(spec::Cns::NAMESPACE, 1, 0).await;
+    assert_eq!(status, spec::Status::SUCCESS);
+    let ns = nvm::IdentifyNamespace::read_from_prefix(&buf[..]).unwrap();
+    assert_eq!(ns.nsze, 0);
+    let (status, buf) = t.identify(spec::Cns::ALLOCATED_NAMESPACE, 2, 0).await;
+    assert_eq!(status, spec::Status::SUCCESS);
+    let ns = nvm::IdentifyNamespace::read_from_prefix(&buf[..]).unwrap();
+    assert_eq!(ns.nsze, POOL_SECTORS / 2);
+
+    // Controllers attached to a namespace (CNS 0x12).
+    assert_eq!(
+        t.controller_list(spec::Cns::CONTROLLER_LIST_OF_NSID, 1)
+            .await,
+        [] as [u16; 0]
+    );
+    assert_eq!(
+        t.controller_list(spec::Cns::CONTROLLER_LIST_OF_NSID, 2)
+            .await,
+        [0]
+    );
+
+    // Controllers in the subsystem (CNS 0x13), starting at the given ID.
+    assert_eq!(
+        t.controller_list(spec::Cns::CONTROLLER_LIST_OF_NVM_SUBSYSTEM, 0)
+            .await,
+        [0]
+    );
+    let (status, buf) = t
+        .identify(spec::Cns::CONTROLLER_LIST_OF_NVM_SUBSYSTEM, 0, 1)
+        .await;
+    assert_eq!(status, spec::Status::SUCCESS);
+    assert_eq!(spec::ControllerList::read_from(&buf[..]).unwrap().count, 0);
+}
+
+#[async_test]
+async fn test_namespace_pool_limits(driver: DefaultDriver) {
+    let capacity = POOL_SECTORS * POOL_SECTOR_SIZE as u64;
+    let mut t = PoolTest::new(driver, capacity, 2).await;
+
+    // Out of capacity.
+    assert_eq!(t.create(POOL_SECTORS / 2).await, (spec::Status::SUCCESS, 1));
+    assert_eq!(
+        t.create(POOL_SECTORS / 2 + 1).await.0,
+        spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY
+    );
+    // Sizes that overflow when converted to bytes.
+    assert_eq!(
+        t.create(u64::MAX / 2).await.0,
+        spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY
+    );
+
+    // Out of namespace IDs.
+    assert_eq!(t.create(POOL_SECTORS / 4).await, (spec::Status::SUCCESS, 2));
+    assert_eq!(
+        t.create(1).await.0,
+        spec::Status::NAMESPACE_IDENTIFIER_UNAVAILABLE
+    );
+    assert_eq!(t.delete(2).await, spec::Status::SUCCESS);
+    assert_eq!(t.create(1).await, (spec::Status::SUCCESS, 2));
+
+    // Shrinking the pool below what is allocated leaves no capacity, rather
+    // than underflowing.
+    t.nvmec
+        .client()
+        .set_namespace_pool(NamespacePool {
+            capacity: capacity / 4,
+            max_namespaces: 2,
+            factory: Box::new(RamDiskFactory),
+        })
+        .await;
+    let id = t.identify_controller().await;
+    assert_eq!(id.tnvmcap.get(), (capacity / 4).into());
+    assert_eq!(id.unvmcap.get(), 0);
+    assert_eq!(t.delete(2).await, spec::Status::SUCCESS);
+    assert_eq!(
+        t.create(1).await.0,
+        spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY
+    );
+    assert_eq!(
+        t.namespace_list(spec::Cns::ALLOCATED_NAMESPACE_LIST, 0)
+            .await,
+        [1]
+    );
+}
+
+const SECTOR_SIZE: usize = 512;
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| ((i / SECTOR_SIZE) as u8 ^ i as u8) | 1)
+        .collect()
+}
+
+async fn write_disk(disk: &dyn SimpleDisk, sector: u64, data: &[u8]) {
+    let mem = GuestMemory::allocate(data.len());
+    mem.write_at(0, data).unwrap();
+    disk.write_vectored(
+        &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+        sector,
+        false,
+    )
+    .await
+    .unwrap();
+}
+
+async fn read_disk(disk: &dyn SimpleDisk, sector: u64, len: usize) -> Vec<u8> {
+    let mem = GuestMemory::allocate(len);
+    disk.read_vectored(
+        &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+        sector,
+    )
+    .await
+    .unwrap();
+    let mut data = vec![0; len];
+    mem.read_at(0, &mut data).unwrap();
+    data
+}
+
+fn nvm_command(opcode: nvm::NvmOpcode, lba: u32, cdw12: u32, dptr: u64) -> spec::Command {
+    let mut command = spec::Command::new_zeroed();
+    command.cdw0.set_opcode(opcode.0);
+    command.nsid = 1;
+    command.cdw10 = nvm::Cdw10ReadWrite::new().with_sbla_low(lba).into();
+    command.cdw12 = cdw12;
+    command.dptr[0] = dptr;
+    command
+}
+
+fn command_status(result: Result<CommandResult, crate::error::NvmeError>) -> spec::Status {
+    match result {
+        Ok(result) => result.status,
+        Err(err) => CommandResult::from(err).status,
+    }
+}
+
+#[async_test]
+async fn test_write_zeroes(_driver: DefaultDriver) {
+    let gm = test_memory();
+    let write_zeroes = |lba, count: u16, deac| {
+        nvm_command(
+            nvm::NvmOpcode::WRITE_ZEROES,
+            lba,
+            nvm::Cdw12WriteZeroes::new()
+                .with_nlb_z(count - 1)
+                .with_deac(deac)
+                .into(),
+            0,
+        )
+    };
+    let mut expected = pattern(8 * SECTOR_SIZE);
+
+    // A disk whose unmapped sectors read as zero is deallocated, and says so.
+    let disk = Arc::new(RamDisk::new(1024 * 1024, false).unwrap());
+    write_disk(&*disk, 0, &expected).await;
+    let namespace = Namespace::new(gm.clone(), 1, disk.clone());
+    let mut buf = vec![0; size_of::<nvm::IdentifyNamespace>()];
+    namespace.identify(&mut buf);
+    let id = nvm::IdentifyNamespace::read_from_prefix(&buf[..]).unwrap();
+    assert_eq!(id.dlfeat.read_value(), 1);
+    assert!(id.dlfeat.write_zeroes_deallocate());
+
+    for (lba, deac) in [(1, false), (5, true)] {
+        namespace
+            .nvm_command(PAGE_SIZE, &write_zeroes(lba, 2, deac))
+            .await
+            .unwrap();
+        expected[lba as usize * SECTOR_SIZE..][..2 * SECTOR_SIZE].fill(0);
+        assert_eq!(read_disk(&*disk, 0, expected.len()).await, expected);
+    }
+
+    // A differencing disk over a disk with data must be written even when
+    // deallocation is requested.
+    let mut expected = pattern(8 * SECTOR_SIZE);
+    let lower = Arc::new(RamDisk::new(1024 * 1024, false).unwrap());
+    write_disk(&*lower, 0, &expected).await;
+    let disk = Arc::new(RamDisk::diff(lower, false).unwrap());
+    let namespace = Namespace::new(gm.clone(), 1, disk.clone());
+    namespace.identify(&mut buf);
+    let id = nvm::IdentifyNamespace::read_from_prefix(&buf[..]).unwrap();
+    assert_eq!(id.dlfeat.read_value(), 0);
+    assert!(!id.dlfeat.write_zeroes_deallocate());
+
+    namespace
+        .nvm_command(PAGE_SIZE, &write_zeroes(2, 3, true))
+        .await
+        .unwrap();
+    expected[2 * SECTOR_SIZE..][..3 * SECTOR_SIZE].fill(0);
+    assert_eq!(read_disk(&*disk, 0, expected.len()).await, expected);
+
+    // Out of range requests fail without writing.
+    let sector_count = disk.sector_count() as u32;
+    assert_eq!(
+        command_status(
+            namespace
+                .nvm_command(PAGE_SIZE, &write_zeroes(sector_count - 1, 2, false))
+                .await
+        ),
+        spec::Status::LBA_OUT_OF_RANGE
+    );
+}
+
+#[async_test]
+async fn test_compare(_driver: DefaultDriver) {
+    let gm = test_memory();
+    let data = pattern(8 * SECTOR_SIZE);
+    let disk = Arc::new(RamDisk::new(1024 * 1024, false).unwrap());
+    write_disk(&*disk, 4, &data).await;
+    let namespace = Namespace::new(gm.clone(), 1, disk);
+    let compare = |count: u16| {
+        nvm_command(
+            nvm::NvmOpcode::COMPARE,
+            4,
+            nvm::Cdw12ReadWrite::new().with_nlb_z(count - 1).into(),
+            0x2000,
+        )
+    };
+
+    gm.write_at(0x2000, &data).unwrap();
+    namespace
+        .nvm_command(2 * SECTOR_SIZE, &compare(2))
+        .await
+        .unwrap();
+
+    // A single differing byte fails the comparison, including when the
+    // buffers from the previous command are reused.
+    gm.write_at(0x2000 + SECTOR_SIZE as u64 + 7, &[0]).unwrap();
+    assert_eq!(
+        command_status(namespace.nvm_command(2 * SECTOR_SIZE, &compare(2)).await),
+        spec::Status::MEDIA_COMPARE_FAILURE
+    );
+
+    // Transfers larger than the reused buffers still work.
+    gm.write_at(0x2000, &data).unwrap();
+    namespace.nvm_command(PAGE_SIZE, &compare(8)).await.unwrap();
+
+    // The transfer size limit applies.
+    assert_eq!(
+        command_status(namespace.nvm_command(2 * SECTOR_SIZE, &compare(8)).await),
+        spec::Status::INVALID_FIELD_IN_COMMAND
+    );
+}
+
+#[async_test]
+async fn test_format_nvm(driver: DefaultDriver) {
+    let dm1 = PrpRange::new(vec![0], 0, PAGE_SIZE64).unwrap();
+    let dm2 = PrpRange::new(vec![0x1000], 0, PAGE_SIZE64).unwrap();
+    let gm = test_memory();
+    let int_controller = TestPciInterruptController::new();
+
+    let mut nvmec = instantiate_and_build_admin_queue(
+        &dm1,
+        64,
+        &dm2,
+        64,
+        true,
+        Some(&int_controller),
+        driver.clone(),
+        &gm,
+    )
+    .await;
+
+    // One disk that can be erased by deallocating it, and one that must be
+    // erased by writing zeroes.
+    let data = pattern(4 * SECTOR_SIZE);
+    let disk1 = Arc::new(RamDisk::new(1024 * 1024, false).unwrap());
+    write_disk(&*disk1, 0, &data).await;
+    let lower = Arc::new(RamDisk::new(1024 * 1024, false).unwrap());
+    write_disk(&*lower, 100, &data).await;
+    let disk2 = Arc::new(RamDisk::diff(lower, false).unwrap());
+    nvmec
+        .client()
+        .add_namespace(1, disk1.clone())
+        .await
+        .unwrap();
+    nvmec
+        .client()
+        .add_namespace(2, disk2.clone())
+        .await
+        .unwrap();
+
+    let mut command = spec::Command::new_zeroed();
+    command.cdw0.set_opcode(spec::AdminOpcode::FORMAT_NVM.0);
+    command.nsid = !0;
+
+    // Only the one LBA format is supported.
+    command.cdw10 = spec::Cdw10FormatNvm::new().with_lbafl(1).into();
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        0,
+        &command,
+    )
+    .await;
+    assert_eq!(cqe.status.status(), spec::Status::INVALID_FORMAT.0);
+
+    command.cdw10 = spec::Cdw10FormatNvm::new()
+        .with_ses(spec::SecureEraseSettings::USER_DATA_ERASE.0)
+        .into();
+    let cqe = submit_admin_command(
+        driver.clone(),
+        &mut nvmec,
+        &int_controller,
+        &gm,
+        &dm1,
+        &dm2,
+        1,
+        &command,
+    )
+    .await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    assert_eq!(read_disk(&*disk1, 0, data.len()).await, vec![0; data.len()]);
+    assert_eq!(
+        read_disk(&*disk2, 100, data.len()).await,
+        vec![0; data.len()]
+    );
+}
@@ -17,11 +17,15 @@
 use crate::queue::ShadowDoorbell;
 use crate::queue::SubmissionQueue;
 use crate::spec;
+use crate::spec::nvm;
+use crate::NamespaceDiskFactory;
+use crate::NamespacePool;
 use crate::DOORBELL_STRIDE_BITS;
 use crate::MAX_QES;
 use crate::NVME_VERSION;
 use crate::PAGE_MASK;
 use crate::PAGE_SIZE;
+use crate::POOL_SECTOR_SIZE;
 use crate::VENDOR_ID;
 use disk_backend::SimpleDisk;
 use futures::FutureExt;
@@ -57,6 +61,8 @@
 const IOCQES: u8 = 4;
 const MAX_ASYNC_EVENT_REQUESTS: u8 = 4; // minimum recommended by spec
 const ERROR_LOG_PAGE_ENTRIES: u8 = 1;
+/// The ID of this controller, which is the only one in its subsystem.
+const CONTROLLER_ID: u16 = 0;
 
 #[derive(Inspect)]
 pub struct AdminConfig {
@@ -81,6 +87,38 @@ pub struct AdminHandler {
     config: AdminConfig,
     #[inspect(iter_by_key)]
     namespaces: BTreeMap<u32, Arc<Namespace>>,
+    pool: Option<PoolState>,
+}
+
+/// The state of the namespace capacity pool.
+#[derive(Inspect)]
+struct PoolState {
+    capacity: u64,
+    max_namespaces: u32,
+    #[inspect(skip)]
+    factory: Box<dyn NamespaceDiskFactory>,
+    /// The namespaces created by the guest, whether or not they are attached.
+    #[inspect(iter_by_key)]
+    allocated: BTreeMap<u32, AllocatedNamespace>,
+}
+
+#[derive(Inspect)]
+struct AllocatedNamespace {
+    #[inspect(skip)]
+    namespace: Arc<Namespace>,
+    size: u64,
+}
+
+impl PoolState {
+    fn used(&self) -> u64 {
+        self.allocated.values().map(|ns| ns.size).sum()
+    }
+
+    /// Returns the unallocated capacity, which is zero if the pool has been
+    /// replaced with one smaller than the namespaces already created.
+    fn available(&self) -> u64 {
+        self.capacity.saturating_sub(self.used())
+    }
 }
 
 #[derive(Inspect)]
@@ -109,6 +147,10 @@ pub struct AdminState {
     send_changed_namespace: futures::channel::mpsc::Sender<u32>,
     #[inspect(skip)]
     poll_namespace_change: BTreeMap<u32, Task<()>>,
+    /// The command ID and task of the format in progress, which completes
+    /// asynchronously since erasing a namespace can take a long time.
+    #[inspect(with = "|x| x.as_ref().map(|(cid, _)| *cid)")]
+    pending_format: Option<(u16, Task<Result<(), NvmeError>>)>,
 }
 
 #[derive(Inspect)]
@@ -178,6 +220,7 @@ pub fn new(handler: &AdminHandler, asq: u64, asqs: u16, acq: u64, acqs: u16) ->
             recv_changed_namespace,
             send_changed_namespace,
             poll_namespace_change,
+            pending_format: None,
         };
         state.set_max_queues(handler, handler.config.max_sqs, handler.config.max_cqs);
         state
@@ -291,12 +334,6 @@ async fn remove_namespace(&mut self, nsid: u32) {
 
         // Notify the guest driver of the change.
         self.add_changed_namespace(nsid);
-
-        self.poll_namespace_change
-            .remove(&nsid)
-            .unwrap()
-            .cancel()
-            .await;
     }
 }
 
@@ -346,6 +383,7 @@ enum Event {
     Command(Result<spec::Command, QueueError>),
     SqDeleteComplete(u16),
     NamespaceChange(u32),
+    FormatComplete(Result<(), NvmeError>),
 }
 
 /// Error returned when adding a namespace with a conflicting ID.
@@ -359,15 +397,46 @@ pub fn new(driver: VmTaskDriver, config: AdminConfig) -> Self {
             driver,
             config,
             namespaces: Default::default(),
+            pool: None,
         }
     }
 
+    /// Sets the pool that guest-created namespaces are allocated from.
+    ///
+    /// Namespaces already created from a previous pool are kept, even if they
+    /// no longer fit in the new pool's capacity.
+    pub fn set_namespace_pool(&mut self, pool: NamespacePool) {
+        let NamespacePool {
+            capacity,
+            max_namespaces,
+            factory,
+        } = pool;
+        let allocated = self
+            .pool
+            .take()
+            .map(|pool| pool.allocated)
+            .unwrap_or_default();
+        self.pool = Some(PoolState {
+            capacity,
+            max_namespaces,
+            factory,
+            allocated,
+        });
+    }
+
     pub async fn add_namespace(
         &mut self,
         state: Option<&mut AdminState>,
         nsid: u32,
         disk: Arc<dyn SimpleDisk>,
     ) -> Result<(), NsidConflict> {
+        if self
+            .pool
+            .as_ref()
+            .is_some_and(|pool| pool.allocated.contains_key(&nsid))
+        {
+            return Err(NsidConflict(nsid));
+        }
         let namespace = &*match self.namespaces.entry(nsid) {
             btree_map::Entry::Vacant(entry) => entry.insert(Arc::new(Namespace::new(
                 self.config.mem.clone(),
@@ -438,8 +507,20 @@ async fn next_event(&mut self, state: &mut AdminState) -> Result<Event, QueueErr
                 };
                 Event::NamespaceChange(nsid)
             };
+            let pending_format = &mut state.pending_format;
+            let format_complete = async {
+                let Some((_, task)) = pending_format else {
+                    pending().await
+                };
+                Event::FormatComplete(task.await)
+            };
 
-            break (next_command, sq_delete_complete, changed_namespace)
+            break (
+                next_command,
+                sq_delete_complete,
+                changed_namespace,
+                format_complete,
+            )
                 .race()
                 .await;
         };
@@ -494,6 +575,15 @@ async fn process_event(
                     spec::AdminOpcode::DOORBELL_BUFFER_CONFIG => self
                         .handle_doorbell_buffer_config(state, &command)
                         .map(|()| Some(Default::default())),
+                    spec::AdminOpcode::NAMESPACE_MANAGEMENT => self
+                        .handle_namespace_management(state, &command)
+                        .await
+                        .map(Some),
+                    spec::AdminOpcode::NAMESPACE_ATTACHMENT => self
+                        .handle_namespace_attachment(state, &command)
+                        .await
+                        .map(|()| Some(Default::default())),
+                    spec::AdminOpcode::FORMAT_NVM => self.handle_format_nvm(state, &command),
                     opcode => {
                         tracelimit::warn_ratelimited!(?opcode, "unsupported opcode");
                         Err(spec::Status::INVALID_COMMAND_OPCODE.into())
@@ -536,6 +626,21 @@ async fn process_event(
                 state.add_changed_namespace(nsid);
                 return Ok(());
             }
+            Event::FormatComplete(result) => {
+                let (cid, _) = state.pending_format.take().unwrap();
+                let result = match result {
+                    Ok(()) => Default::default(),
+                    Err(err) => {
+                        tracelimit::warn_ratelimited!(
+                            error = &err as &dyn std::error::Error,
+                            cid,
+                            "format error"
+                        );
+                        err.into()
+                    }
+                };
+                (cid, result)
+            }
         };
 
         let status = spec::CompletionStatus::new().with_status(result.status.0);
@@ -589,6 +694,12 @@ fn handle_identify(&mut self, command: &spec::Command) -> Result<(), NvmeError>
             spec::Cns::NAMESPACE => {
                 if let Some(ns) = self.namespaces.get(&command.nsid) {
                     ns.identify(buf);
+                } else if command.nsid == !0 && self.pool.is_some() {
+                    // Report the capabilities common to namespaces created
+                    // from the pool.
+                    let id = nvm::IdentifyNamespace::mut_from_prefix(buf).unwrap();
+                    id.lbaf[0] =
+                        nvm::Lbaf::new().with_lbads(POOL_SECTOR_SIZE.trailing_zeros() as u8);
                 } else {
                     tracelimit::warn_ratelimited!(nsid = command.nsid, "unknown namespace id");
                 }
@@ -600,6 +711,51 @@ fn handle_identify(&mut self, command: &spec::Command) -> Result<(), NvmeError>
                     tracelimit::warn_ratelimited!(nsid = command.nsid, "unknown namespace id");
                 }
             }
+            spec::Cns::ALLOCATED_NAMESPACE_LIST => {
+                if command.nsid >= 0xfffffffe {
+                    return Err(spec::Status::INVALID_NAMESPACE_OR_FORMAT.into());
+                }
+                let mut allocated: Vec<_> = self.namespaces.keys().copied().collect();
+                if let Some(pool) = &self.pool {
+                    allocated.extend(pool.allocated.keys());
+                    allocated.sort();
+                    allocated.dedup();
+                }
+                let nsids = u32::mut_slice_from(buf).unwrap();
+                for (ns, nsid) in allocated
+                    .into_iter()
+                    .filter(|&ns| ns > command.nsid)
+                    .zip(nsids)
+                {
+                    *nsid = ns;
+                }
+            }
+            spec::Cns::ALLOCATED_NAMESPACE => {
+                let allocated = self
+                    .pool
+                    .as_ref()
+                    .and_then(|pool| pool.allocated.get(&command.nsid).map(|ns| &ns.namespace));
+                if let Some(ns) = allocated.or_else(|| self.namespaces.get(&command.nsid)) {
+                    ns.identify(buf);
+                } else {
+                    tracelimit::warn_ratelimited!(nsid = command.nsid, "unknown namespace id");
+                }
+            }
+            spec::Cns::CONTROLLER_LIST_OF_NSID | spec::Cns::CONTROLLER_LIST_OF_NVM_SUBSYSTEM => {
+                let attached = cdw10.cns() == spec::Cns::CONTROLLER_LIST_OF_NVM_SUBSYSTEM.0
+                    || self.namespaces.contains_key(&command.nsid);
+                let list = spec::ControllerList::mut_from_prefix(buf).unwrap();
+                if attached {
+                    // Report the controllers at or above the requested ID.
+                    for id in [CONTROLLER_ID]
+                        .into_iter()
+                        .filter(|&id| id >= cdw10.cntid())
+                    {
+                        list.ids[list.count as usize] = id;
+                        list.count += 1;
+                    }
+                }
+            }
             cns => {
                 tracelimit::warn_ratelimited!(?cns, "unsupported cns");
                 return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
@@ -610,7 +766,15 @@ fn handle_identify(&mut self, command: &spec::Command) -> Result<(), NvmeError>
     }
 
     fn identify_controller(&self) -> spec::IdentifyController {
-        let oacs = spec::OptionalAdminCommandSupport::from(0).with_doorbell_buffer_config(true);
+        let oacs = spec::OptionalAdminCommandSupport::from(0)
+            .with_format_nvm(true)
+            .with_ns_management(self.pool.is_some())
+            .with_doorbell_buffer_config(true);
+        let (tnvmcap, unvmcap, max_pool_nsid) = if let Some(pool) = &self.pool {
+            (pool.capacity, pool.available(), pool.max_namespaces)
+        } else {
+            (0, 0, 0)
+        };
         spec::IdentifyController {
             vid: VENDOR_ID,
             ssvid: VENDOR_ID,
@@ -625,7 +789,13 @@ fn identify_controller(&self) -> spec::IdentifyController {
                 .with_min(IOCQES)
                 .with_max(IOCQES),
             frmw: spec::FirmwareUpdates::new().with_ffsro(true).with_nofs(1),
-            nn: self.namespaces.keys().copied().max().unwrap_or(0),
+            nn: self
+                .namespaces
+                .keys()
+                .copied()
+                .max()
+                .unwrap_or(0)
+                .max(max_pool_nsid),
             ieee: [0x74, 0xe2, 0x8c], // Microsoft
             fr: (*b"v1.00000").into(),
             mn: (*b"MSFT NVMe Accelerator v1.0              ").into(),
@@ -634,14 +804,19 @@ fn identify_controller(&self) -> spec::IdentifyController {
             elpe: ERROR_LOG_PAGE_ENTRIES - 1,
             oaes: spec::Oaes::new().with_namespace_attribute(true),
             oncs: spec::Oncs::new()
+                .with_compare(true)
                 .with_dataset_management(true)
+                .with_write_zeroes(true)
                 // Namespaces still have to opt in individually via `rescap`.
                 .with_reservations(true),
             vwc: spec::VolatileWriteCache::new()
                 .with_present(true)
                 .with_broadcast_flush_behavior(spec::BroadcastFlushBehavior::NOT_SUPPORTED.0),
             cntrltype: spec::ControllerType::IO_CONTROLLER,
+            cntlid: CONTROLLER_ID,
             oacs,
+            tnvmcap: (tnvmcap as u128).into(),
+            unvmcap: (unvmcap as u128).into(),
             ..FromZeroes::new_zeroed()
         }
     }
@@ -1014,6 +1189,222 @@ fn handle_get_log_page(
         Ok(())
     }
 
+    async fn handle_namespace_management(
+        &mut self,
+        state: &mut AdminState,
+        command: &spec::Command,
+    ) -> Result<CommandResult, NvmeError> {
+        let cdw10: spec::Cdw10NamespaceManagement = command.cdw10.into();
+        if self.pool.is_none() {
+            return Err(spec::Status::INVALID_COMMAND_OPCODE.into());
+        }
+        match spec::NamespaceManagementSelect(cdw10.sel()) {
+            spec::NamespaceManagementSelect::CREATE => {
+                let nsid = self.create_namespace(command, cdw10).await?;
+                Ok(CommandResult::new(spec::Status::SUCCESS, [nsid, 0]))
+            }
+            spec::NamespaceManagementSelect::DELETE => {
+                self.delete_namespace(state, command.nsid).await?;
+                Ok(Default::default())
+            }
+            sel => {
+                tracelimit::warn_ratelimited!(?sel, "unsupported namespace management select");
+                Err(spec::Status::INVALID_FIELD_IN_COMMAND.into())
+            }
+        }
+    }
+
+    async fn create_namespace(
+        &mut self,
+        command: &spec::Command,
+        cdw10: spec::Cdw10NamespaceManagement,
+    ) -> Result<u32, NvmeError> {
+        let pool = self.pool.as_mut().unwrap();
+
+        // Only the NVM command set is supported.
+        if cdw10.csi() != 0 {
+            return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+        }
+
+        let mut data = nvm::IdentifyNamespace::new_zeroed();
+        PrpRange::parse(&self.config.mem, size_of_val(&data), command.dptr)?
+            .read(&self.config.mem, data.as_bytes_mut())?;
+
+        // Only the single pool LBA format without metadata or protection
+        // information is supported.
+        if data.flbas.low_index() != 0 || data.flbas.high_index() != 0 || data.dps != 0 {
+            return Err(spec::Status::INVALID_FORMAT.into());
+        }
+        if data.nsze == 0 {
+            return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+        }
+        if data.ncap != data.nsze {
+            return Err(spec::Status::THIN_PROVISIONING_NOT_SUPPORTED.into());
+        }
+        let size = data
+            .nsze
+            .checked_mul(POOL_SECTOR_SIZE.into())
+            .filter(|&size| size <= pool.available())
+            .ok_or(spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY)?;
+
+        let nsid = (1..=pool.max_namespaces)
+            .find(|nsid| !self.namespaces.contains_key(nsid) && !pool.allocated.contains_key(nsid))
+            .ok_or(spec::Status::NAMESPACE_IDENTIFIER_UNAVAILABLE)?;
+
+        let disk = pool
+            .factory
+            .create_disk(nsid, POOL_SECTOR_SIZE, data.nsze)
+            .await
+            .map_err(|err| NvmeError::new(spec::Status::INTERNAL_ERROR, err))?;
+
+        if disk.sector_size() != POOL_SECTOR_SIZE || disk.sector_count() != data.nsze {
+            return Err(NvmeError::new(
+                spec::Status::INTERNAL_ERROR,
+                "namespace disk has the wrong geometry",
+            ));
+        }
+
+        tracing::info!(nsid, size, "created namespace");
+        pool.allocated.insert(
+            nsid,
+            AllocatedNamespace {
+                namespace: Arc::new(Namespace::new(self.config.mem.clone(), nsid, disk)),
+                size,
+            },
+        );
+        Ok(nsid)
+    }
+
+    async fn delete_namespace(
+        &mut self,
+        state: &mut AdminState,
+        nsid: u32,
+    ) -> Result<(), NvmeError> {
+        let pool = self.pool.as_mut().unwrap();
+        let nsids: Vec<_> = if nsid == !0 {
+            pool.allocated.keys().copied().collect()
+        } else if pool.allocated.contains_key(&nsid) {
+            vec![nsid]
+        } else {
+            return Err(spec::Status::INVALID_NAMESPACE_OR_FORMAT.into());
+        };
+
+        for nsid in nsids {
+            // Deleting a namespace implicitly detaches it.
+            if self.namespaces.remove(&nsid).is_some() {
+                state.remove_namespace(nsid).await;
+            }
+            pool.allocated.remove(&nsid);
+            tracing::info!(nsid, "deleted namespace");
+        }
+        Ok(())
+    }
+
+    async fn handle_namespace_attachment(
+        &mut self,
+        state: &mut AdminState,
+        command: &spec::Command,
+    ) -> Result<(), NvmeError> {
+        let cdw10: spec::Cdw10NamespaceAttachment = command.cdw10.into();
+        let pool = self
+            .pool
+            .as_ref()
+            .ok_or(spec::Status::INVALID_COMMAND_OPCODE)?;
+
+        // Only namespaces created by the guest can be attached and detached.
+        let nsid = command.nsid;
+        let namespace = pool
+            .allocated
+            .get(&nsid)
+            .ok_or(spec::Status::INVALID_NAMESPACE_OR_FORMAT)?
+            .namespace
+            .clone();
+
+        let mut list = spec::ControllerList::new_zeroed();
+        PrpRange::parse(&self.config.mem, size_of_val(&list), command.dptr)?
+            .read(&self.config.mem, list.as_bytes_mut())?;
+        let ids = list
+            .ids
+            .get(..list.count as usize)
+            .ok_or(spec::Status::CONTROLLER_LIST_INVALID)?;
+        if ids != [CONTROLLER_ID] {
+            return Err(spec::Status::CONTROLLER_LIST_INVALID.into());
+        }
+
+        match spec::NamespaceAttachmentSelect(cdw10.sel()) {
+            spec::NamespaceAttachmentSelect::ATTACH => {
+                match self.namespaces.entry(nsid) {
+                    btree_map::Entry::Vacant(entry) => {
+                        entry.insert(namespace.clone());
+                    }
+                    btree_map::Entry::Occupied(_) => {
+                        return Err(spec::Status::NAMESPACE_ALREADY_ATTACHED.into());
+                    }
+                }
+                state.add_namespace(&self.driver, nsid, &namespace).await;
+                tracing::info!(nsid, "attached namespace");
+            }
+            spec::NamespaceAttachmentSelect::DETACH => {
+                if self.namespaces.remove(&nsid).is_none() {
+                    return Err(spec::Status::NAMESPACE_NOT_ATTACHED.into());
+                }
+                state.remove_namespace(nsid).await;
+                tracing::info!(nsid, "detached namespace");
+            }
+            sel => {
+                tracelimit::warn_ratelimited!(?sel, "unsupported namespace attachment select");
+                return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_format_nvm(
+        &self,
+        state: &mut AdminState,
+        command: &spec::Command,
+    ) -> Result<Option<CommandResult>, NvmeError> {
+        let cdw10: spec::Cdw10FormatNvm = command.cdw10.into();
+
+        // Only a single LBA format without metadata or protection information
+        // is supported.
+        if cdw10.lbafl() != 0
+            || cdw10.lbafu() != 0
+            || cdw10.mset()
+            || cdw10.pi() != 0
+            || cdw10.pil()
+        {
+            return Err(spec::Status::INVALID_FORMAT.into());
+        }
+
+        if state.pending_format.is_some() {
+            return Err(spec::Status::FORMAT_IN_PROGRESS.into());
+        }
+
+        let ses = spec::SecureEraseSettings(cdw10.ses());
+        let namespaces = if command.nsid == !0 {
+            self.namespaces.values().cloned().collect()
+        } else {
+            let namespace = self
+                .namespaces
+                .get(&command.nsid)
+                .ok_or(spec::Status::INVALID_NAMESPACE_OR_FORMAT)?;
+
+            vec![namespace.clone()]
+        };
+
+        // Complete the command when the format finishes, processing other
+        // admin commands in the meantime.
+        let task = self.driver.spawn("nvme-format", async move {
+            for namespace in namespaces {
+                namespace.format(ses).await?;
+            }
+            Ok(())
+        });
+        state.pending_format = Some((command.cdw0.cid(), task));
+        Ok(None)
+    }
+
     fn handle_doorbell_buffer_config(
         &self,
         state: &mut AdminState,
@@ -9,6 +9,7 @@
 use super::admin::NsidConflict;
 use super::IoQueueEntrySizes;
 use crate::queue::DoorbellRegister;
+use crate::NamespacePool;
 use disk_backend::SimpleDisk;
 use futures::FutureExt;
 use futures::StreamExt;
@@ -206,6 +207,18 @@ pub async fn remove_namespace(&self, nsid: u32) -> bool {
             .await
             .unwrap()
     }
+
+    /// Enables the namespace management commands, allowing the guest to
+    /// create namespaces from `pool`.
+    ///
+    /// If a pool was already set, namespaces previously created by the guest
+    /// are kept.
+    pub async fn set_namespace_pool(&self, pool: NamespacePool) {
+        self.send
+            .call(CoordinatorRequest::SetNamespacePool, pool)
+            .await
+            .unwrap()
+    }
 }
 
 #[derive(Inspect)]
@@ -221,6 +234,7 @@ enum CoordinatorRequest {
     EnableAdmin(Rpc<EnableAdminParams, ()>),
     AddNamespace(Rpc<(u32, Arc<dyn SimpleDisk>), Result<(), NsidConflict>>),
     RemoveNamespace(Rpc<u32, bool>),
+    SetNamespacePool(Rpc<NamespacePool, ()>),
     Inspect(inspect::Deferred),
     ControllerReset(Rpc<(), ()>),
 }
@@ -308,6 +322,19 @@ enum Event {
                         })
                         .await
                     }
+                    CoordinatorRequest::SetNamespacePool(rpc) => {
+                        rpc.handle(|pool| {
+                            let this = &mut self;
+                            async move {
+                                let running = this.admin.stop().await;
+                                this.admin.task_mut().set_namespace_pool(pool);
+                                if running {
+                                    this.admin.start();
+                                }
+                            }
+                        })
+                        .await
+                    }
                     CoordinatorRequest::ControllerReset(rpc) => {
                         assert!(self.reset.is_none());
                         self.reset = Some(rpc);
@@ -12,6 +12,7 @@
 use vm_resource::kind::PciDeviceHandleKind;
 use vm_resource::Resource;
 use vm_resource::ResourceId;
+use vm_resource::ResourceKind;
 
 /// A handle to an NVMe controller.
 #[derive(MeshPayload)]
@@ -24,6 +25,10 @@ pub struct NvmeControllerHandle {
     pub max_io_queues: u16,
     /// The initial set of namespaces.
     pub namespaces: Vec<NamespaceDefinition>,
+    /// The capacity pool from which the guest can create namespaces with the
+    /// namespace management commands. If `None`, namespace management is not
+    /// supported.
+    pub namespace_pool: Option<NamespacePoolDefinition>,
 }
 
 impl ResourceId<PciDeviceHandleKind> for NvmeControllerHandle {
@@ -40,3 +45,31 @@ pub struct NamespaceDefinition {
     /// The backing disk resource.
     pub disk: Resource<DiskHandleKind>,
 }
+
+/// A pool of capacity from which the guest can create namespaces.
+#[derive(MeshPayload)]
+pub struct NamespacePoolDefinition {
+    /// The total capacity of the pool, in bytes.
+    pub capacity: u64,
+    /// The maximum number of namespaces, which also bounds the namespace IDs
+    /// assigned to guest-created namespaces.
+    pub max_namespaces: u32,
+    /// The template for the disks backing guest-created namespaces.
+    pub disk_template: Resource<NamespaceDiskTemplateKind>,
+}
+
+/// A resource kind for templates describing how to create the disk backing
+/// each guest-created namespace.
+pub enum NamespaceDiskTemplateKind {}
+
+impl ResourceKind for NamespaceDiskTemplateKind {
+    const NAME: &'static str = "nvme_namespace_disk_template";
+}
+
+/// A template that backs each guest-created namespace with a new RAM disk.
+#[derive(MeshPayload)]
+pub struct RamNamespaceDiskTemplateHandle;
+
+impl ResourceId<NamespaceDiskTemplateKind> for RamNamespaceDiskTemplateHandle {
+    const ID: &'static str = "ram";
+}
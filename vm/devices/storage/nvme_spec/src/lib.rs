@@ -727,6 +727,79 @@ pub struct Cdw10DeleteIoQueue {
     pub rsvd: u16,
 }
 
+#[bitfield(u32)]
+pub struct Cdw10FormatNvm {
+    /// LBA format index, low 4 bits.
+    #[bits(4)]
+    pub lbafl: u8,
+    /// Metadata settings
+    pub mset: bool,
+    /// Protection information
+    #[bits(3)]
+    pub pi: u8,
+    /// Protection information location
+    pub pil: bool,
+    /// Secure erase settings
+    #[bits(3)]
+    pub ses: u8,
+    /// LBA format index, high 2 bits.
+    #[bits(2)]
+    pub lbafu: u8,
+    #[bits(18)]
+    _rsvd: u32,
+}
+
+open_enum! {
+    pub enum SecureEraseSettings: u8 {
+        NONE = 0,
+        USER_DATA_ERASE = 1,
+        CRYPTOGRAPHIC_ERASE = 2,
+    }
+}
+
+#[bitfield(u32)]
+pub struct Cdw10NamespaceManagement {
+    /// Select
+    #[bits(4)]
+    pub sel: u8,
+    #[bits(20)]
+    _rsvd: u32,
+    /// Command set identifier
+    pub csi: u8,
+}
+
+open_enum! {
+    pub enum NamespaceManagementSelect: u8 {
+        CREATE = 0,
+        DELETE = 1,
+    }
+}
+
+#[bitfield(u32)]
+pub struct Cdw10NamespaceAttachment {
+    /// Select
+    #[bits(4)]
+    pub sel: u8,
+    #[bits(28)]
+    _rsvd: u32,
+}
+
+open_enum! {
+    pub enum NamespaceAttachmentSelect: u8 {
+        ATTACH = 0,
+        DETACH = 1,
+    }
+}
+
+/// A list of controller identifiers, as used by namespace attachment and
+/// identify.
+#[repr(C)]
+#[derive(Debug, AsBytes, FromBytes, FromZeroes)]
+pub struct ControllerList {
+    pub count: u16,
+    pub ids: [u16; 2047],
+}
+
 #[bitfield(u32)]
 pub struct Cdw10GetLogPage {
     /// Log page identifier
@@ -31,7 +31,7 @@ pub struct IdentifyNamespace {
     pub nmic: u8,
     pub rescap: ReservationCapabilities,
     pub fpi: u8,
-    pub dlfeat: u8,
+    pub dlfeat: Dlfeat,
     pub nawun: u16,
     pub nawupf: u16,
     pub nacwu: u16,
@@ -103,6 +103,22 @@ pub struct Nsfeat {
     _rsvd: u8,
 }
 
+/// Deallocate logical block features
+#[derive(Inspect)]
+#[bitfield(u8)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+pub struct Dlfeat {
+    /// The values read from deallocated logical blocks. 1 means all zeroes.
+    #[bits(3)]
+    pub read_value: u8,
+    /// The deallocate bit of Write Zeroes is supported.
+    pub write_zeroes_deallocate: bool,
+    /// The guard field of deallocated logical blocks is the CRC of the data.
+    pub guard_crc: bool,
+    #[bits(3)]
+    _rsvd: u8,
+}
+
 /// LBA format
 #[derive(Inspect)]
 #[bitfield(u32)]
@@ -153,6 +169,8 @@ pub enum NvmOpcode: u8 {
         FLUSH = 0x00,
         WRITE = 0x01,
         READ = 0x02,
+        COMPARE = 0x05,
+        WRITE_ZEROES = 0x08,
         /// Dataset management.
         DSM = 0x09,
 
@@ -196,6 +214,25 @@ pub struct Cdw12ReadWrite {
     pub lr: bool,
 }
 
+#[bitfield(u32)]
+pub struct Cdw12WriteZeroes {
+    /// Number of logical blocks. Zero-based.
+    pub nlb_z: u16,
+    #[bits(8)]
+    _rsvd: u8,
+    /// Storage tag check.
+    pub stc: bool,
+    /// Deallocate.
+    pub deac: bool,
+    /// Protection information
+    #[bits(4)]
+    pub prinfo: u8,
+    /// Force unit access
+    pub fua: bool,
+    /// Limited retry
+    pub lr: bool,
+}
+
 #[bitfield(u32)]
 pub struct Cdw10Dsm {
     /// Number of ranges. Zero-based.
@@ -510,6 +510,8 @@ pub struct QueueResources {
 pub struct Resources {
     pub features: u64,
     pub queues: Vec<QueueResources>,
+    /// Notifies the driver that the device-specific configuration changed.
+    pub config_changed: Interrupt,
     pub shared_memory_region: Option<Arc<dyn MappedMemoryRegion>>,
     pub shared_memory_size: u64,
 }
@@ -1743,6 +1745,18 @@ async fn verify_pci_config(driver: DefaultDriver) {
             .unwrap();
         assert_eq!(buf, 12);
         next_cap_offset = header[1] as u32;
+        assert_ne!(next_cap_offset, 0);
+
+        let mut header = 0;
+        pci_test_device
+            .pci_device
+            .pci_cfg_read(next_cap_offset as u16, &mut header)
+            .unwrap();
+        let header = header.to_le_bytes();
+        assert_eq!(header[0], CapabilityId::PCI_EXPRESS.0);
+        // Version 2, endpoint.
+        assert_eq!(header[2], 0x02);
+        next_cap_offset = header[1] as u32;
         assert_eq!(next_cap_offset, 0);
     }
 
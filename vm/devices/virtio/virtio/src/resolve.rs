@@ -5,8 +5,10 @@
 
 use crate::VirtioDevice;
 use guestmem::GuestMemory;
+use std::sync::Arc;
 use vm_resource::kind::VirtioDeviceHandle;
 use vm_resource::CanResolveTo;
+use vmcore::ram_discard::DiscardRam;
 use vmcore::vm_task::VmTaskDriverSource;
 
 impl CanResolveTo<Box<dyn VirtioDevice>> for VirtioDeviceHandle {
@@ -19,4 +21,6 @@ pub struct VirtioResolveInput<'a> {
     pub driver_source: &'a VmTaskDriverSource,
     /// The guest memory for virtio device DMA.
     pub guest_memory: &'a GuestMemory,
+    /// Releases the host memory backing guest RAM.
+    pub ram_discard: &'a Arc<dyn DiscardRam>,
 }
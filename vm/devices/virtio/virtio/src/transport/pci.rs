@@ -26,6 +26,7 @@
 use inspect::InspectMut;
 use parking_lot::Mutex;
 use pci_core::capabilities::msix::MsixEmulator;
+use pci_core::capabilities::pci_express::PciExpressCapability;
 use pci_core::capabilities::PciCapability;
 use pci_core::capabilities::ReadOnlyCapability;
 use pci_core::cfg_space_emu::BarMemoryKind;
@@ -33,12 +34,15 @@
 use pci_core::cfg_space_emu::DeviceBars;
 use pci_core::cfg_space_emu::IntxInterrupt;
 use pci_core::msi::RegisterMsi;
+use pci_core::spec::caps::pci_express::DevicePortType;
 use pci_core::spec::hwid::ClassCode;
 use pci_core::spec::hwid::HardwareIds;
 use pci_core::spec::hwid::ProgrammingInterface;
 use pci_core::spec::hwid::Subclass;
 use pci_core::PciInterruptPin;
 use std::io;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use vmcore::device_state::ChangeDeviceState;
 use vmcore::interrupt::Interrupt;
@@ -84,7 +88,7 @@ pub struct VirtioPciDevice {
     interrupt_status: Arc<Mutex<u32>>,
     #[inspect(hex)]
     device_status: u32,
-    config_generation: u32,
+    config_generation: Arc<AtomicU32>,
     config_space: ConfigSpaceType0Emulator,
 
     #[inspect(skip)]
@@ -202,6 +206,10 @@ pub fn new(
             shared_memory_region = Some(region);
         }
 
+        caps.push(Box::new(PciExpressCapability::new(
+            DevicePortType::ENDPOINT,
+        )));
+
         let mut config_space = ConfigSpaceType0Emulator::new(hardware_ids, caps, bars);
         let interrupt_kind = match interrupt_model {
             PciInterruptModel::Msix(_) => InterruptKind::Msix(msix.unwrap()),
@@ -228,7 +236,7 @@ pub fn new(
             msix_vectors,
             interrupt_status: Arc::new(Mutex::new(0)),
             device_status: 0,
-            config_generation: 0,
+            config_generation: Arc::new(AtomicU32::new(0)),
             interrupt_kind,
             config_space,
             doorbells: VirtioDoorbells::new(doorbell_registration),
@@ -238,7 +246,7 @@ pub fn new(
     }
 
     fn update_config_generation(&mut self) {
-        self.config_generation = self.config_generation.wrapping_add(1);
+        self.config_generation.fetch_add(1, Ordering::Relaxed);
         if self.device_status & VIRTIO_DRIVER_OK != 0 {
             *self.interrupt_status.lock() |= 2;
             match &self.interrupt_kind {
@@ -279,7 +287,11 @@ fn read_u32(&mut self, offset: u16) -> u32 {
                 }
             }
             16 => (self.queues.len() as u32) << 16 | self.msix_config_vector as u32,
-            20 => self.queue_select << 24 | self.config_generation << 8 | self.device_status,
+            20 => {
+                self.queue_select << 24
+                    | (self.config_generation.load(Ordering::Relaxed) & 0xff) << 8
+                    | self.device_status
+            }
             24 => {
                 let size = if queue_select < self.queues.len() {
                     self.queues[queue_select].size
@@ -400,7 +412,7 @@ fn write_u32(&mut self, address: u64, offset: u16, val: u32) {
                 if val == 0 {
                     let started = (self.device_status & VIRTIO_DRIVER_OK) != 0;
                     self.device_status = 0;
-                    self.config_generation = 0;
+                    self.config_generation.store(0, Ordering::Relaxed);
                     if started {
                         self.doorbells.clear();
                         self.device.disable();
@@ -461,9 +473,29 @@ fn write_u32(&mut self, address: u64, offset: u16, val: u32) {
                         })
                         .collect();
 
+                    let config_changed = {
+                        let config_generation = self.config_generation.clone();
+                        let interrupt_status = self.interrupt_status.clone();
+                        let interrupt = match &self.interrupt_kind {
+                            InterruptKind::Msix(msix) => msix
+                                .interrupt(self.msix_config_vector)
+                                .unwrap_or_else(Interrupt::null),
+                            InterruptKind::IntX(line) => {
+                                let line = line.clone();
+                                Interrupt::from_fn(move || line.set_level(true))
+                            }
+                        };
+                        Interrupt::from_fn(move || {
+                            config_generation.fetch_add(1, Ordering::Relaxed);
+                            *interrupt_status.lock() |= 2;
+                            interrupt.deliver();
+                        })
+                    };
+
                     self.device.enable(Resources {
                         features,
                         queues,
+                        config_changed,
                         shared_memory_region: self.shared_memory_region.clone(),
                         shared_memory_size: self.shared_memory_size,
                     });
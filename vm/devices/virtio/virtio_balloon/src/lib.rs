@@ -0,0 +1,479 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio-balloon device.
+//!
+//! The guest gives pages to the host by inflating the balloon and by reporting
+//! free pages. In both cases, the host memory backing the pages is released.
+//! The guest may reuse deflated and reported pages without waiting for the
+//! host, which is fine since released memory reads as zero.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use async_trait::async_trait;
+use bitfield_struct::bitfield;
+use futures::FutureExt;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use pal_async::wait::PolledWait;
+use parking_lot::Mutex;
+use std::future::poll_fn;
+use std::io;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio_resources::balloon::BalloonRpc;
+use virtio_resources::balloon::BalloonStats;
+use virtio_resources::balloon::BalloonStatus;
+use vmcore::interrupt::Interrupt;
+use vmcore::ram_discard::DiscardRam;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+/// The size of the pages the balloon is measured in.
+const PAGE_SIZE: u64 = 4096;
+
+/// How often to request memory statistics from the guest.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// These correspond to VIRTIO_BALLOON_F_ flags.
+#[bitfield(u64)]
+struct BalloonFeatures {
+    pub must_tell_host: bool,
+    pub stats_vq: bool,
+    pub deflate_on_oom: bool,
+    pub free_page_hint: bool,
+    pub page_poison: bool,
+    pub page_reporting: bool,
+    #[bits(58)]
+    _reserved: u64,
+}
+
+/// The length of the device configuration: `num_pages`, `actual`,
+/// `free_page_hint_cmd_id`, and `poison_val`, each a le32.
+const CONFIG_LENGTH: u32 = 16;
+
+/// The length of a statistic: a le16 tag followed by a le64 value.
+const STAT_LENGTH: usize = 10;
+
+// These correspond to VIRTIO_BALLOON_S_ tags.
+open_enum::open_enum! {
+    enum StatTag: u16 {
+        SWAP_IN = 0,
+        SWAP_OUT = 1,
+        MAJFLT = 2,
+        MINFLT = 3,
+        MEMFREE = 4,
+        MEMTOT = 5,
+        AVAIL = 6,
+        CACHES = 7,
+    }
+}
+
+/// State shared between the device, its RPC task, and its worker.
+#[derive(Default)]
+struct Shared {
+    /// The target balloon size, in pages.
+    target: u32,
+    /// The balloon size reported by the guest, in pages.
+    actual: u32,
+    stats: BalloonStats,
+    config_changed: Option<Interrupt>,
+}
+
+pub struct Device {
+    driver: VmTaskDriver,
+    memory: GuestMemory,
+    ram_discard: Arc<dyn DiscardRam>,
+    shared: Arc<Mutex<Shared>>,
+    worker: Option<TaskControl<BalloonWorker, BalloonQueues>>,
+    _rpc_task: Task<()>,
+}
+
+impl Device {
+    /// Creates a balloon device, whose target size is set through `recv`.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        ram_discard: Arc<dyn DiscardRam>,
+        recv: mesh::Receiver<BalloonRpc>,
+    ) -> Self {
+        let driver = driver_source.simple();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let rpc_task = driver.spawn("virtio-balloon-rpc", handle_rpcs(recv, shared.clone()));
+        Self {
+            driver,
+            memory,
+            ram_discard,
+            shared,
+            worker: None,
+            _rpc_task: rpc_task,
+        }
+    }
+
+    fn new_queue(&self, features: u64, resources: QueueResources) -> Option<VirtioQueue> {
+        if !resources.params.enable {
+            return None;
+        }
+        let queue_event = PolledWait::new(&self.driver, resources.event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    err = err as &dyn std::error::Error,
+                    "Failed creating queue event"
+                )
+            })
+            .ok()?;
+        VirtioQueue::new(
+            features,
+            resources.params,
+            self.memory.clone(),
+            resources.notify,
+            queue_event,
+        )
+        .inspect_err(|err| {
+            tracing::error!(
+                err = err as &dyn std::error::Error,
+                "Failed creating virtio balloon queue"
+            )
+        })
+        .ok()
+    }
+}
+
+async fn handle_rpcs(mut recv: mesh::Receiver<BalloonRpc>, shared: Arc<Mutex<Shared>>) {
+    while let Some(rpc) = recv.next().await {
+        match rpc {
+            BalloonRpc::SetTarget(rpc) => rpc.handle_sync(|target| {
+                let mut shared = shared.lock();
+                shared.target = (target / PAGE_SIZE).try_into().unwrap_or(u32::MAX);
+                if let Some(config_changed) = &shared.config_changed {
+                    config_changed.deliver();
+                }
+            }),
+            BalloonRpc::Query(rpc) => rpc.handle_sync(|()| {
+                let shared = shared.lock();
+                BalloonStatus {
+                    target: shared.target as u64 * PAGE_SIZE,
+                    actual: shared.actual as u64 * PAGE_SIZE,
+                    stats: shared.stats.clone(),
+                }
+            }),
+        }
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: 5,
+            device_features: BalloonFeatures::new()
+                .with_stats_vq(true)
+                .with_deflate_on_oom(true)
+                .with_page_reporting(self.ram_discard.is_supported())
+                .into(),
+            max_queues: 4,
+            device_register_length: CONFIG_LENGTH,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        let shared = self.shared.lock();
+        match offset {
+            0 => shared.target,
+            4 => shared.actual,
+            _ => 0,
+        }
+    }
+
+    fn write_registers_u32(&mut self, offset: u16, val: u32) {
+        if offset == 4 {
+            self.shared.lock().actual = val;
+        }
+    }
+
+    fn enable(&mut self, resources: Resources) {
+        let features = BalloonFeatures::from(resources.features);
+        self.shared.lock().config_changed = Some(resources.config_changed);
+
+        // The queues for features that were not negotiated do not exist, so
+        // the queues that follow them move down.
+        let mut queues = resources.queues.into_iter();
+        let mut next_queue = |present: bool| {
+            if !present {
+                return None;
+            }
+            self.new_queue(resources.features, queues.next()?)
+        };
+        let (Some(inflate), Some(deflate)) = (next_queue(true), next_queue(true)) else {
+            tracing::error!("virtio balloon inflate and deflate queues not enabled");
+            return;
+        };
+        let stats = next_queue(features.stats_vq());
+        let reporting = next_queue(features.page_reporting());
+
+        let mut worker = TaskControl::new(BalloonWorker {
+            ram_discard: self.ram_discard.clone(),
+            memory: self.memory.clone(),
+            shared: self.shared.clone(),
+        });
+        worker.insert(
+            &self.driver,
+            "virtio-balloon",
+            BalloonQueues {
+                inflate,
+                deflate,
+                stats,
+                reporting,
+                stats_buffer: None,
+                stats_deadline: Instant::now(),
+                timer: PolledTimer::new(&self.driver),
+            },
+        );
+        worker.start();
+        self.worker = Some(worker);
+    }
+
+    fn disable(&mut self) {
+        self.shared.lock().config_changed = None;
+        if let Some(mut worker) = self.worker.take() {
+            self.driver
+                .spawn("shutdown-virtio-balloon", async move {
+                    worker.stop().await;
+                })
+                .detach();
+        }
+    }
+}
+
+struct BalloonWorker {
+    ram_discard: Arc<dyn DiscardRam>,
+    memory: GuestMemory,
+    shared: Arc<Mutex<Shared>>,
+}
+
+struct BalloonQueues {
+    inflate: VirtioQueue,
+    deflate: VirtioQueue,
+    stats: Option<VirtioQueue>,
+    reporting: Option<VirtioQueue>,
+    /// The buffer for the guest's next statistics, which is returned to the
+    /// guest to request them.
+    stats_buffer: Option<VirtioQueueCallbackWork>,
+    stats_deadline: Instant,
+    timer: PolledTimer,
+}
+
+enum Event {
+    Inflate(VirtioQueueCallbackWork),
+    Deflate(VirtioQueueCallbackWork),
+    Stats(VirtioQueueCallbackWork),
+    Report(VirtioQueueCallbackWork),
+    RequestStats,
+}
+
+impl BalloonQueues {
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Event>> {
+        let queues = [
+            (
+                Some(&mut self.inflate),
+                Event::Inflate as fn(VirtioQueueCallbackWork) -> Event,
+            ),
+            (Some(&mut self.deflate), Event::Deflate),
+            (self.stats.as_mut(), Event::Stats),
+            (self.reporting.as_mut(), Event::Report),
+        ];
+        for (queue, event) in queues {
+            if let Some(queue) = queue {
+                if let Poll::Ready(work) = queue.poll_next_unpin(cx) {
+                    return Poll::Ready(work.expect("queue never ends").map(event));
+                }
+            }
+        }
+        if self.stats_buffer.is_some()
+            && self
+                .timer
+                .sleep_until(self.stats_deadline)
+                .poll_unpin(cx)
+                .is_ready()
+        {
+            return Poll::Ready(Ok(Event::RequestStats));
+        }
+        Poll::Pending
+    }
+}
+
+#[async_trait]
+impl AsyncRun<BalloonQueues> for BalloonWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut BalloonQueues,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            loop {
+                let event = match poll_fn(|cx| state.poll_event(cx)).await {
+                    Ok(event) => event,
+                    Err(err) => {
+                        tracing::error!(
+                            err = &err as &dyn std::error::Error,
+                            "virtio balloon queue failure"
+                        );
+                        break;
+                    }
+                };
+                match event {
+                    Event::Inflate(mut work) => {
+                        for (gpa, len) in pfn_ranges(&self.read_payload(&work)) {
+                            self.discard(gpa, len);
+                        }
+                        work.complete(0);
+                    }
+                    Event::Deflate(mut work) => {
+                        // Deflated pages are backed on demand when the guest
+                        // uses them.
+                        work.complete(0);
+                    }
+                    Event::Stats(work) => {
+                        self.shared.lock().stats = parse_stats(&self.read_payload(&work));
+                        state.stats_buffer = Some(work);
+                        state.stats_deadline = Instant::now() + STATS_INTERVAL;
+                    }
+                    Event::Report(mut work) => {
+                        for payload in &work.payload {
+                            // Only whole pages can be released.
+                            let start = payload.address.next_multiple_of(PAGE_SIZE);
+                            let end =
+                                (payload.address + payload.length as u64) / PAGE_SIZE * PAGE_SIZE;
+                            if start < end {
+                                self.discard(start, end - start);
+                            }
+                        }
+                        work.complete(0);
+                    }
+                    Event::RequestStats => {
+                        // The guest refills and returns the buffer.
+                        if let Some(mut work) = state.stats_buffer.take() {
+                            work.complete(0);
+                        }
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl BalloonWorker {
+    fn read_payload(&self, work: &VirtioQueueCallbackWork) -> Vec<u8> {
+        let mut data = vec![0; work.get_payload_length(false) as usize];
+        if let Err(err) = work.read(&self.memory, &mut data) {
+            tracelimit::warn_ratelimited!(
+                err = &err as &dyn std::error::Error,
+                "failed to read virtio balloon buffer"
+            );
+            data.clear();
+        }
+        data
+    }
+
+    fn discard(&self, gpa: u64, len: u64) {
+        // Inflating the balloon still tracks the guest's view of its size when
+        // the memory cannot be released.
+        if !self.ram_discard.is_supported() {
+            return;
+        }
+        if let Err(err) = self.ram_discard.discard_ram(gpa, len) {
+            tracelimit::warn_ratelimited!(
+                err = &err as &dyn std::error::Error,
+                gpa,
+                len,
+                "failed to release balloon memory"
+            );
+        }
+    }
+}
+
+/// Converts a buffer of little-endian 32-bit page numbers into a list of
+/// `(gpa, len)` ranges, merging consecutive pages.
+fn pfn_ranges(data: &[u8]) -> Vec<(u64, u64)> {
+    let mut pfns = data
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64)
+        .collect::<Vec<_>>();
+    pfns.sort_unstable();
+    pfns.dedup();
+    pfns.chunk_by(|&a, &b| b == a + 1)
+        .map(|run| (run[0] * PAGE_SIZE, run.len() as u64 * PAGE_SIZE))
+        .collect()
+}
+
+/// Parses a buffer of statistics from the guest.
+fn parse_stats(data: &[u8]) -> BalloonStats {
+    let mut stats = BalloonStats::default();
+    for stat in data.chunks_exact(STAT_LENGTH) {
+        let (tag, val) = stat.split_at(2);
+        let field = match StatTag(u16::from_le_bytes(tag.try_into().unwrap())) {
+            StatTag::SWAP_IN => &mut stats.swap_in,
+            StatTag::SWAP_OUT => &mut stats.swap_out,
+            StatTag::MAJFLT => &mut stats.major_faults,
+            StatTag::MINFLT => &mut stats.minor_faults,
+            StatTag::MEMFREE => &mut stats.free_memory,
+            StatTag::MEMTOT => &mut stats.total_memory,
+            StatTag::AVAIL => &mut stats.available_memory,
+            StatTag::CACHES => &mut stats.disk_caches,
+            _ => continue,
+        };
+        *field = Some(u64::from_le_bytes(val.try_into().unwrap()));
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_stats;
+    use super::pfn_ranges;
+
+    #[test]
+    fn pfns() {
+        let data = [7u32, 3, 4, 5, 9, 4]
+            .iter()
+            .flat_map(|pfn| pfn.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pfn_ranges(&data),
+            [(0x3000, 0x3000), (0x7000, 0x1000), (0x9000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut data = Vec::new();
+        for (tag, val) in [(4u16, 0x1000u64), (5, 0x8000), (99, 1)] {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+        let stats = parse_stats(&data);
+        assert_eq!(stats.free_memory, Some(0x1000));
+        assert_eq!(stats.total_memory, Some(0x8000));
+        assert_eq!(stats.swap_in, None);
+    }
+}
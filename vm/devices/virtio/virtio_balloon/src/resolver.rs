@@ -0,0 +1,39 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-balloon devices.
+
+use crate::Device;
+use virtio::resolve::VirtioResolveInput;
+use virtio::VirtioDevice;
+use virtio_resources::balloon::VirtioBalloonHandle;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+use vm_resource::ResolveResource;
+
+/// Resolver for virtio-balloon devices.
+pub struct VirtioBalloonResolver;
+
+declare_static_resolver! {
+    VirtioBalloonResolver,
+    (VirtioDeviceHandle, VirtioBalloonHandle),
+}
+
+impl ResolveResource<VirtioDeviceHandle, VirtioBalloonHandle> for VirtioBalloonResolver {
+    type Output = Box<dyn VirtioDevice>;
+    type Error = anyhow::Error;
+
+    fn resolve(
+        &self,
+        resource: VirtioBalloonHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let device = Device::new(
+            input.driver_source,
+            input.guest_memory.clone(),
+            input.ram_discard.clone(),
+            resource.recv,
+        );
+        Ok(Box::new(device))
+    }
+}
@@ -0,0 +1,887 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio-blk device, backed by a [`SimpleDisk`].
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use async_trait::async_trait;
+use bitfield_struct::bitfield;
+use disk_backend::DiskError;
+use disk_backend::SimpleDisk;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use pal_async::task::Spawn;
+use pal_async::wait::PolledWait;
+use scsi_buffers::OwnedRequestBuffers;
+use std::future::poll_fn;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The unit of the sector fields in requests and config, regardless of the
+/// disk's sector size.
+const VIRTIO_SECTOR_SIZE: u64 = 512;
+
+/// The maximum number of data segments in a request.
+const MAX_SEGMENTS: u32 = 254;
+/// The maximum number of ranges in a discard request.
+const MAX_DISCARD_SEGMENTS: u32 = 32;
+/// The maximum number of ranges in a write zeroes request.
+const MAX_WRITE_ZEROES_SEGMENTS: u32 = 1;
+/// The maximum length of a discard or write zeroes range, in virtio sectors.
+const MAX_RANGE_SECTORS: u32 = 0x3fffff;
+/// The size of the zero buffer used for write zeroes requests.
+const ZERO_BUFFER_SIZE: usize = 64 * 1024;
+
+// These correspond to VIRTIO_BLK_F_ flags.
+#[bitfield(u64)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct BlkFeatures {
+    _barrier: bool,
+    pub size_max: bool,
+    pub seg_max: bool,
+    _reserved: bool,
+    pub geometry: bool,
+    pub ro: bool,
+    pub blk_size: bool,
+    _scsi: bool,
+    _reserved2: bool,
+    pub flush: bool,
+    pub topology: bool,
+    pub config_wce: bool,
+    pub mq: bool,
+    pub discard: bool,
+    pub write_zeroes: bool,
+    pub lifetime: bool,
+    pub secure_erase: bool,
+    #[bits(47)]
+    _reserved3: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct BlkConfig {
+    pub capacity: u64,
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub geometry_cylinders: u16,
+    pub geometry_heads: u8,
+    pub geometry_sectors: u8,
+    pub blk_size: u32,
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+    pub writeback: u8,
+    pub unused0: u8,
+    pub num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    pub unused1: [u8; 3],
+    pub max_secure_erase_sectors: u32,
+}
+
+open_enum::open_enum! {
+    enum RequestType: u32 {
+        IN = 0,
+        OUT = 1,
+        FLUSH = 4,
+        GET_ID = 8,
+        DISCARD = 11,
+        WRITE_ZEROES = 13,
+    }
+}
+
+open_enum::open_enum! {
+    enum Status: u8 {
+        OK = 0,
+        IOERR = 1,
+        UNSUPP = 2,
+    }
+}
+
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct RequestHeader {
+    pub request_type: u32,
+    pub reserved: u32,
+    pub sector: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct DiscardWriteZeroes {
+    pub sector: u64,
+    pub num_sectors: u32,
+    pub flags: u32,
+}
+
+/// The `flags` bit of a discard or write zeroes range that allows the device
+/// to deallocate the range. Only valid for write zeroes.
+const DISCARD_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// The length of the device ID returned by `GET_ID` requests.
+const DEVICE_ID_LEN: usize = 20;
+
+pub struct Device {
+    driver: VmTaskDriver,
+    driver_source: VmTaskDriverSource,
+    disk: Arc<BlkDisk>,
+    features: BlkFeatures,
+    config: BlkConfig,
+    workers: Vec<TaskControl<BlkWorker, BlkQueue>>,
+}
+
+impl Device {
+    /// Creates a device for `disk` with up to `max_queues` request queues.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        disk: Arc<dyn SimpleDisk>,
+        read_only: bool,
+        max_queues: u16,
+    ) -> Self {
+        let read_only = read_only || disk.is_read_only();
+        let max_queues = max_queues.max(1);
+        let sector_size = disk.sector_size();
+        let sector_shift = (sector_size as u64 / VIRTIO_SECTOR_SIZE).trailing_zeros();
+        let discard_alignment = disk
+            .unmap()
+            .map_or(1, |unmap| unmap.optimal_unmap_sectors().max(1));
+
+        let features = BlkFeatures::new()
+            .with_seg_max(true)
+            .with_blk_size(true)
+            .with_topology(true)
+            .with_flush(true)
+            .with_ro(read_only)
+            .with_mq(max_queues > 1)
+            .with_discard(!read_only && disk.unmap().is_some())
+            .with_write_zeroes(!read_only);
+
+        let config = BlkConfig {
+            capacity: disk.sector_count() << sector_shift,
+            seg_max: MAX_SEGMENTS,
+            blk_size: sector_size,
+            physical_block_exp: (disk.physical_sector_size() / sector_size).trailing_zeros() as u8,
+            min_io_size: 1,
+            num_queues: max_queues,
+            max_discard_sectors: MAX_RANGE_SECTORS,
+            max_discard_seg: MAX_DISCARD_SEGMENTS,
+            discard_sector_alignment: discard_alignment << sector_shift,
+            max_write_zeroes_sectors: MAX_RANGE_SECTORS,
+            max_write_zeroes_seg: MAX_WRITE_ZEROES_SEGMENTS,
+            ..FromZeroes::new_zeroed()
+        };
+
+        Self {
+            driver: driver_source.simple(),
+            driver_source: driver_source.clone(),
+            disk: Arc::new(BlkDisk {
+                disk,
+                memory,
+                read_only,
+                sector_shift,
+            }),
+            features,
+            config,
+            workers: Vec::new(),
+        }
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: 2,
+            device_features: self.features.into(),
+            max_queues: self.config.num_queues,
+            device_register_length: size_of::<BlkConfig>() as u32,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        let offset = offset as usize;
+        self.config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |v| u32::from_le_bytes(v.try_into().unwrap()))
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        for (i, queue_resources) in resources.queues.into_iter().enumerate() {
+            if !queue_resources.params.enable {
+                continue;
+            }
+            let driver = self
+                .driver_source
+                .builder()
+                .target_vp(i as u32)
+                .build("virtio-blk");
+            let queue_event = match PolledWait::new(&driver, queue_resources.event) {
+                Ok(event) => event,
+                Err(err) => {
+                    tracing::error!(
+                        err = &err as &dyn std::error::Error,
+                        "Failed creating queue event"
+                    );
+                    continue;
+                }
+            };
+            let queue = match VirtioQueue::new(
+                resources.features,
+                queue_resources.params,
+                self.disk.memory.clone(),
+                queue_resources.notify,
+                queue_event,
+            ) {
+                Ok(queue) => queue,
+                Err(err) => {
+                    tracing::error!(
+                        err = &err as &dyn std::error::Error,
+                        "Failed creating virtio blk queue"
+                    );
+                    continue;
+                }
+            };
+            let mut worker = TaskControl::new(BlkWorker {
+                disk: self.disk.clone(),
+            });
+            worker.insert(
+                &driver,
+                "virtio-blk-queue",
+                BlkQueue {
+                    queue,
+                    ios: FuturesUnordered::new(),
+                },
+            );
+            worker.start();
+            self.workers.push(worker);
+        }
+    }
+
+    fn disable(&mut self) {
+        let workers = std::mem::take(&mut self.workers);
+        if workers.is_empty() {
+            return;
+        }
+        self.driver
+            .spawn("shutdown-virtio-blk-queues", async move {
+                for mut worker in workers {
+                    worker.stop().await;
+                    // Wait for the in-flight IOs, which reference guest
+                    // memory and the queue.
+                    let mut state = worker.remove();
+                    while state.ios.next().await.is_some() {}
+                }
+            })
+            .detach();
+    }
+}
+
+/// The state shared by all of a device's queues.
+struct BlkDisk {
+    disk: Arc<dyn SimpleDisk>,
+    memory: GuestMemory,
+    read_only: bool,
+    /// The shift from virtio sectors to disk sectors.
+    sector_shift: u32,
+}
+
+struct BlkWorker {
+    disk: Arc<BlkDisk>,
+}
+
+struct BlkQueue {
+    queue: VirtioQueue,
+    /// The requests in flight. These are kept with the queue state, rather
+    /// than in the task, so that stopping the task does not cancel them.
+    ios: FuturesUnordered<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+#[async_trait]
+impl AsyncRun<BlkQueue> for BlkWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut BlkQueue,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            let mut failed = false;
+            loop {
+                let work = poll_fn(|cx| {
+                    while let Poll::Ready(Some(())) = state.ios.poll_next_unpin(cx) {}
+                    if failed {
+                        return Poll::Pending;
+                    }
+                    state.queue.poll_next_unpin(cx)
+                })
+                .await;
+                match work.expect("queue never ends") {
+                    Ok(work) => state.ios.push(Box::pin(self.disk.clone().handle(work))),
+                    Err(err) => {
+                        // Keep completing the IOs in flight.
+                        tracing::error!(
+                            err = &err as &dyn std::error::Error,
+                            "virtio blk queue failure"
+                        );
+                        failed = true;
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl BlkDisk {
+    async fn handle(self: Arc<Self>, mut work: VirtioQueueCallbackWork) {
+        let writeable_len = work.get_payload_length(true);
+        if writeable_len == 0 {
+            tracelimit::warn_ratelimited!("virtio blk request without status byte");
+            work.complete(0);
+            return;
+        }
+        let data_len = writeable_len - 1;
+        let (status, written) = match self.process(&work, data_len).await {
+            Ok(written) => (Status::OK, written),
+            Err(status) => (status, 0),
+        };
+        if let Err(err) = work.write_at_offset(data_len, &self.memory, &[status.0]) {
+            tracelimit::warn_ratelimited!(
+                err = &err as &dyn std::error::Error,
+                "failed to write virtio blk status"
+            );
+        }
+        work.complete(written + 1);
+    }
+
+    /// Processes a request, returning the number of data bytes written to the
+    /// request's writeable buffers.
+    async fn process(&self, work: &VirtioQueueCallbackWork, data_len: u64) -> Result<u32, Status> {
+        let mut header = RequestHeader::new_zeroed();
+        let n = work
+            .read(&self.memory, header.as_bytes_mut())
+            .map_err(|_| Status::IOERR)?;
+        if n < size_of::<RequestHeader>() {
+            return Err(Status::IOERR);
+        }
+        let readable = Ranges::new(work, false);
+        let writeable = Ranges::new(work, true);
+        let request_type = RequestType(header.request_type);
+        match request_type {
+            RequestType::IN => {
+                let buffers = writeable.subrange(0, data_len);
+                self.read(header.sector, &buffers).await?;
+                Ok(data_len as u32)
+            }
+            RequestType::OUT => {
+                if self.read_only {
+                    return Err(Status::IOERR);
+                }
+                let offset = size_of::<RequestHeader>() as u64;
+                let buffers = readable.subrange(offset, readable.len() - offset);
+                self.write(header.sector, &buffers).await?;
+                Ok(0)
+            }
+            RequestType::FLUSH => {
+                if !self.read_only {
+                    self.disk.sync_cache().await.map_err(io_error)?;
+                }
+                Ok(0)
+            }
+            RequestType::GET_ID => {
+                let id = self.device_id().ok_or(Status::UNSUPP)?;
+                let len = (data_len as usize).min(DEVICE_ID_LEN);
+                work.write(&self.memory, &id[..len])
+                    .map_err(|_| Status::IOERR)?;
+                Ok(len as u32)
+            }
+            RequestType::DISCARD | RequestType::WRITE_ZEROES => {
+                if self.read_only {
+                    return Err(Status::IOERR);
+                }
+                let mut data = vec![0; readable.len() as usize];
+                work.read(&self.memory, &mut data)
+                    .map_err(|_| Status::IOERR)?;
+                self.discard_or_write_zeroes(request_type, &data[size_of::<RequestHeader>()..])
+                    .await?;
+                Ok(0)
+            }
+            _ => {
+                tracelimit::warn_ratelimited!(?request_type, "unsupported virtio blk request");
+                Err(Status::UNSUPP)
+            }
+        }
+    }
+
+    /// Returns the 20-byte serial number for the disk, derived from its ID.
+    fn device_id(&self) -> Option<[u8; DEVICE_ID_LEN]> {
+        let disk_id = self.disk.disk_id()?;
+        let mut id = [0; DEVICE_ID_LEN];
+        let hex = disk_id
+            .iter()
+            .flat_map(|b| [b >> 4, b & 0xf])
+            .map(|n| char::from_digit(n.into(), 16).unwrap() as u8);
+        for (d, s) in id.iter_mut().zip(hex) {
+            *d = s;
+        }
+        Some(id)
+    }
+
+    /// Converts a range of `len` bytes at virtio sector `sector` to a disk
+    /// sector, validating that the range is aligned and within the disk.
+    fn disk_sector(&self, sector: u64, len: u64) -> Result<u64, Status> {
+        let sector_size = VIRTIO_SECTOR_SIZE << self.sector_shift;
+        if sector % (1 << self.sector_shift) != 0 || len % sector_size != 0 {
+            tracelimit::warn_ratelimited!(sector, len, "unaligned virtio blk request");
+            return Err(Status::IOERR);
+        }
+        let disk_sector = sector >> self.sector_shift;
+        let end = disk_sector.checked_add(len / sector_size);
+        if end.map_or(true, |end| end > self.disk.sector_count()) {
+            return Err(Status::IOERR);
+        }
+        Ok(disk_sector)
+    }
+
+    async fn read(&self, sector: u64, ranges: &Ranges) -> Result<(), Status> {
+        let disk_sector = self.disk_sector(sector, ranges.len())?;
+        if ranges.len() == 0 {
+            return Ok(());
+        }
+        if let Some(buffers) = ranges.paged() {
+            self.disk
+                .read_vectored(&buffers.buffer(&self.memory), disk_sector)
+                .await
+                .map_err(io_error)?;
+        } else {
+            let len = ranges.len() as usize;
+            let bounce = GuestMemory::allocate(len);
+            self.disk
+                .read_vectored(
+                    &OwnedRequestBuffers::linear(0, len, true).buffer(&bounce),
+                    disk_sector,
+                )
+                .await
+                .map_err(io_error)?;
+            let mut data = vec![0; len];
+            bounce.read_at(0, &mut data).map_err(|_| Status::IOERR)?;
+            ranges
+                .write(&self.memory, &data)
+                .map_err(|_| Status::IOERR)?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, sector: u64, ranges: &Ranges) -> Result<(), Status> {
+        let disk_sector = self.disk_sector(sector, ranges.len())?;
+        if ranges.len() == 0 {
+            return Ok(());
+        }
+        if let Some(buffers) = ranges.paged() {
+            self.disk
+                .write_vectored(&buffers.buffer(&self.memory), disk_sector, false)
+                .await
+                .map_err(io_error)?;
+        } else {
+            let len = ranges.len() as usize;
+            let mut data = vec![0; len];
+            ranges
+                .read(&self.memory, &mut data)
+                .map_err(|_| Status::IOERR)?;
+            let bounce = GuestMemory::allocate(len);
+            bounce.write_at(0, &data).map_err(|_| Status::IOERR)?;
+            self.disk
+                .write_vectored(
+                    &OwnedRequestBuffers::linear(0, len, false).buffer(&bounce),
+                    disk_sector,
+                    false,
+                )
+                .await
+                .map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Processes the ranges of a discard or write zeroes request, validating
+    /// them all against the limits in the device config before issuing any.
+    async fn discard_or_write_zeroes(
+        &self,
+        request_type: RequestType,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        let discard = request_type == RequestType::DISCARD;
+        let (max_segments, allowed_flags) = if discard {
+            (MAX_DISCARD_SEGMENTS, 0)
+        } else {
+            (MAX_WRITE_ZEROES_SEGMENTS, DISCARD_WRITE_ZEROES_FLAG_UNMAP)
+        };
+        if data.len() % size_of::<DiscardWriteZeroes>() != 0 {
+            tracelimit::warn_ratelimited!(len = data.len(), "malformed virtio blk range list");
+            return Err(Status::IOERR);
+        }
+        let segments = data
+            .chunks_exact(size_of::<DiscardWriteZeroes>())
+            .map(|v| DiscardWriteZeroes::read_from(v).unwrap())
+            .collect::<Vec<_>>();
+        if segments.is_empty() || segments.len() > max_segments as usize {
+            tracelimit::warn_ratelimited!(
+                ?request_type,
+                count = segments.len(),
+                "invalid virtio blk range count"
+            );
+            return Err(Status::IOERR);
+        }
+        for segment in &segments {
+            if segment.flags & !allowed_flags != 0 {
+                return Err(Status::UNSUPP);
+            }
+            if segment.num_sectors > MAX_RANGE_SECTORS {
+                tracelimit::warn_ratelimited!(
+                    ?request_type,
+                    num_sectors = segment.num_sectors,
+                    "virtio blk range too long"
+                );
+                return Err(Status::IOERR);
+            }
+        }
+        for segment in &segments {
+            if discard {
+                self.discard(segment).await?;
+            } else {
+                self.write_zeroes(segment).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn discard(&self, segment: &DiscardWriteZeroes) -> Result<(), Status> {
+        let len = segment.num_sectors as u64 * VIRTIO_SECTOR_SIZE;
+        let disk_sector = self.disk_sector(segment.sector, len)?;
+        let Some(unmap) = self.disk.unmap() else {
+            return Err(Status::UNSUPP);
+        };
+        unmap
+            .unmap(
+                disk_sector,
+                segment.num_sectors as u64 >> self.sector_shift,
+                false,
+            )
+            .await
+            .map_err(io_error)
+    }
+
+    async fn write_zeroes(&self, segment: &DiscardWriteZeroes) -> Result<(), Status> {
+        let len = segment.num_sectors as u64 * VIRTIO_SECTOR_SIZE;
+        let mut disk_sector = self.disk_sector(segment.sector, len)?;
+        let sector_size = VIRTIO_SECTOR_SIZE << self.sector_shift;
+        let zero = GuestMemory::allocate(ZERO_BUFFER_SIZE);
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(ZERO_BUFFER_SIZE as u64);
+            self.disk
+                .write_vectored(
+                    &OwnedRequestBuffers::linear(0, n as usize, false).buffer(&zero),
+                    disk_sector,
+                    false,
+                )
+                .await
+                .map_err(io_error)?;
+            disk_sector += n / sector_size;
+            remaining -= n;
+        }
+        Ok(())
+    }
+}
+
+fn io_error(err: DiskError) -> Status {
+    tracelimit::warn_ratelimited!(
+        err = &err as &dyn std::error::Error,
+        "virtio blk disk IO failed"
+    );
+    Status::IOERR
+}
+
+/// The guest memory ranges of one direction of a request's descriptor chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    fn new(work: &VirtioQueueCallbackWork, writeable: bool) -> Self {
+        Self(
+            work.payload
+                .iter()
+                .filter(|p| p.writeable == writeable && p.length != 0)
+                .map(|p| (p.address, p.length as u64))
+                .collect(),
+        )
+    }
+
+    fn len(&self) -> u64 {
+        self.0.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Returns the ranges covering `len` bytes starting `offset` bytes in.
+    fn subrange(&self, mut offset: u64, mut len: u64) -> Self {
+        let mut ranges = Vec::new();
+        for &(address, range_len) in &self.0 {
+            if len == 0 {
+                break;
+            }
+            if offset >= range_len {
+                offset -= range_len;
+                continue;
+            }
+            let n = (range_len - offset).min(len);
+            ranges.push((address + offset, n));
+            offset = 0;
+            len -= n;
+        }
+        Self(ranges)
+    }
+
+    /// Returns the ranges as request buffers, if they are contiguous within
+    /// their pages so that they can be described as a list of pages.
+    fn paged(&self) -> Option<OwnedRequestBuffers> {
+        let mut gpns = Vec::new();
+        for (i, &(address, len)) in self.0.iter().enumerate() {
+            let end = address.checked_add(len)?;
+            if (i != 0 && address % PAGE_SIZE != 0)
+                || (i != self.0.len() - 1 && end % PAGE_SIZE != 0)
+            {
+                return None;
+            }
+            gpns.extend(address / PAGE_SIZE..end.div_ceil(PAGE_SIZE));
+        }
+        let offset = self
+            .0
+            .first()
+            .map_or(0, |&(address, _)| address % PAGE_SIZE);
+        Some(OwnedRequestBuffers::new_unaligned(
+            &gpns,
+            offset as usize,
+            self.len() as usize,
+        ))
+    }
+
+    fn read(
+        &self,
+        memory: &GuestMemory,
+        data: &mut [u8],
+    ) -> Result<(), guestmem::GuestMemoryError> {
+        let mut data = data;
+        for &(address, len) in &self.0 {
+            let (this, rest) = data.split_at_mut(len as usize);
+            memory.read_at(address, this)?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn write(&self, memory: &GuestMemory, data: &[u8]) -> Result<(), guestmem::GuestMemoryError> {
+        let mut data = data;
+        for &(address, len) in &self.0 {
+            let (this, rest) = data.split_at(len as usize);
+            memory.write_at(address, this)?;
+            data = rest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlkDisk;
+    use super::DiscardWriteZeroes;
+    use super::Ranges;
+    use super::RequestType;
+    use super::Status;
+    use super::DISCARD_WRITE_ZEROES_FLAG_UNMAP;
+    use super::MAX_DISCARD_SEGMENTS;
+    use super::PAGE_SIZE;
+    use disk_backend::test_utilities::pattern;
+    use disk_backend::test_utilities::read;
+    use disk_backend::test_utilities::write;
+    use disk_backend::test_utilities::SECTOR_SIZE;
+    use disk_backend::SimpleDisk;
+    use disk_ramdisk::RamDisk;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use std::sync::Arc;
+    use zerocopy::AsBytes;
+
+    fn blk_disk(disk: Arc<dyn SimpleDisk>) -> BlkDisk {
+        BlkDisk {
+            disk,
+            memory: GuestMemory::allocate(PAGE_SIZE as usize),
+            read_only: false,
+            sector_shift: 0,
+        }
+    }
+
+    /// Encodes the range list of a discard or write zeroes request.
+    fn range_list(ranges: &[(u64, u32, u32)]) -> Vec<u8> {
+        ranges
+            .iter()
+            .flat_map(|&(sector, num_sectors, flags)| {
+                DiscardWriteZeroes {
+                    sector,
+                    num_sectors,
+                    flags,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges() {
+        let ranges = Ranges(vec![(0x1800, 0x800), (0x4000, 0x2000), (0x8000, 0x10)]);
+        assert_eq!(ranges.len(), 0x2810);
+        assert_eq!(
+            ranges.subrange(0x10, 0x1000),
+            Ranges(vec![(0x1810, 0x7f0), (0x4000, 0x810)])
+        );
+        assert_eq!(ranges.subrange(0x2800, 0x100), Ranges(vec![(0x8000, 0x10)]));
+
+        let buffers = ranges.paged().unwrap();
+        assert_eq!(buffers.len(), 0x2810);
+
+        // A range that does not end on a page boundary cannot be followed by
+        // another.
+        assert!(Ranges(vec![(0x1000, 0x800), (0x3000, 0x1000)])
+            .paged()
+            .is_none());
+        // Nor can a range that does not start on one follow another.
+        assert!(Ranges(vec![(0x1000, PAGE_SIZE), (0x3800, 0x800)])
+            .paged()
+            .is_none());
+        assert!(Ranges(Vec::new()).paged().is_some());
+    }
+
+    #[async_test]
+    async fn discard() {
+        let ram = Arc::new(RamDisk::new(1 << 20, false).unwrap());
+        let data = pattern(0, 32, 1);
+        write(ram.as_ref(), 0, &data).await;
+        let blk = blk_disk(ram.clone());
+
+        blk.discard_or_write_zeroes(RequestType::DISCARD, &range_list(&[(2, 2, 0), (8, 4, 0)]))
+            .await
+            .unwrap();
+        let mut expected = data;
+        expected[2 * SECTOR_SIZE..4 * SECTOR_SIZE].fill(0);
+        expected[8 * SECTOR_SIZE..12 * SECTOR_SIZE].fill(0);
+        assert_eq!(read(ram.as_ref(), 0, 32).await, expected);
+
+        // The unmap flag is only valid for write zeroes.
+        assert_eq!(
+            blk.discard_or_write_zeroes(
+                RequestType::DISCARD,
+                &range_list(&[(0, 1, DISCARD_WRITE_ZEROES_FLAG_UNMAP)])
+            )
+            .await,
+            Err(Status::UNSUPP)
+        );
+        // The request must have between one and `max_discard_seg` ranges.
+        let too_many = vec![(0, 1, 0); MAX_DISCARD_SEGMENTS as usize + 1];
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::DISCARD, &range_list(&too_many))
+                .await,
+            Err(Status::IOERR)
+        );
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::DISCARD, &[]).await,
+            Err(Status::IOERR)
+        );
+        // The ranges must be within the disk.
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::DISCARD, &range_list(&[(2047, 2, 0)]))
+                .await,
+            Err(Status::IOERR)
+        );
+        // Nothing was discarded by the failed requests.
+        assert_eq!(read(ram.as_ref(), 0, 32).await, expected);
+
+        // Disks that cannot unmap do not support discard.
+        let blk = blk_disk(Arc::new(RamDisk::diff(ram, false).unwrap()));
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::DISCARD, &range_list(&[(0, 1, 0)]))
+                .await,
+            Err(Status::UNSUPP)
+        );
+    }
+
+    #[async_test]
+    async fn write_zeroes() {
+        let ram = Arc::new(RamDisk::new(1 << 20, false).unwrap());
+        let data = pattern(0, 160, 2);
+        write(ram.as_ref(), 0, &data).await;
+        let blk = blk_disk(ram.clone());
+
+        // Cover a range larger than the zero buffer.
+        blk.discard_or_write_zeroes(
+            RequestType::WRITE_ZEROES,
+            &range_list(&[(4, 150, DISCARD_WRITE_ZEROES_FLAG_UNMAP)]),
+        )
+        .await
+        .unwrap();
+        let mut expected = data;
+        expected[4 * SECTOR_SIZE..154 * SECTOR_SIZE].fill(0);
+        assert_eq!(read(ram.as_ref(), 0, 160).await, expected);
+
+        // Only one range is allowed.
+        assert_eq!(
+            blk.discard_or_write_zeroes(
+                RequestType::WRITE_ZEROES,
+                &range_list(&[(0, 1, 0), (2, 1, 0)])
+            )
+            .await,
+            Err(Status::IOERR)
+        );
+        // Unknown flags are not supported.
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::WRITE_ZEROES, &range_list(&[(0, 1, 2)]))
+                .await,
+            Err(Status::UNSUPP)
+        );
+        // The range list must be a whole number of ranges.
+        assert_eq!(
+            blk.discard_or_write_zeroes(RequestType::WRITE_ZEROES, &[0; 8])
+                .await,
+            Err(Status::IOERR)
+        );
+        assert_eq!(read(ram.as_ref(), 0, 160).await, expected);
+    }
+}
@@ -3,8 +3,10 @@
 
 use crate::header_size;
 use crate::VirtioNetHeader;
+use crate::VirtioNetHeaderFlags;
 use guestmem::GuestMemory;
 use net_backend::BufferAccess;
+use net_backend::L4Protocol;
 use net_backend::RxBufferSegment;
 use net_backend::RxId;
 use net_backend::RxMetadata;
@@ -26,11 +28,13 @@ pub struct VirtioWorkPool {
     mem: GuestMemory,
     rx_packets: Arc<Vec<Mutex<RxPacket>>>,
     buffer_segments: Vec<RxBufferSegment>,
+    /// Whether the guest accepts VIRTIO_NET_HDR_F_DATA_VALID.
+    guest_csum: bool,
 }
 
 impl VirtioWorkPool {
     /// Create a new instance.
-    pub fn new(mem: GuestMemory, queue_size: u16) -> Self {
+    pub fn new(mem: GuestMemory, queue_size: u16, guest_csum: bool) -> Self {
         Self {
             mem,
             rx_packets: Arc::new(
@@ -39,6 +43,7 @@ pub fn new(mem: GuestMemory, queue_size: u16) -> Self {
                     .collect(),
             ),
             buffer_segments: Vec::new(),
+            guest_csum,
         }
     }
 
@@ -61,6 +66,31 @@ pub fn queue_work(&self, work: VirtioQueueCallbackWork) -> RxId {
         RxId(idx.into())
     }
 
+    /// Reads the destination MAC address of a packet written to the buffer.
+    pub fn destination(&self, rx_id: RxId) -> Option<[u8; 6]> {
+        let packet = self.rx_packets[rx_id.0 as usize].lock();
+        let work = packet.work.as_ref()?;
+        let mut mac = [0; 6];
+        let mut filled = 0;
+        let mut skip = header_size() as u64;
+        for p in work.payload.iter().filter(|p| p.writeable) {
+            if skip >= p.length as u64 {
+                skip -= p.length as u64;
+                continue;
+            }
+            let n = ((p.length as u64 - skip) as usize).min(mac.len() - filled);
+            self.mem
+                .read_at(p.address + skip, &mut mac[filled..filled + n])
+                .ok()?;
+            filled += n;
+            skip = 0;
+            if filled == mac.len() {
+                return Some(mac);
+            }
+        }
+        None
+    }
+
     /// Notify the client that a receive packet is ready (network packet available).
     pub fn complete_packet(&self, rx_id: RxId) {
         let mut packet = self.rx_packets[rx_id.0 as usize].lock();
@@ -113,13 +143,17 @@ fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
         assert_eq!(metadata.offset, 0);
         assert!(metadata.len > 0);
 
-        // let flags = if let RxChecksumState::Good = metadata.ip_checksum {
-        //     VirtioNetHeaderFlags::VIRTIO_NET_HDR_F_DATA_VALID.bits()
-        // } else {
-        //     0
-        // };
+        let data_valid = self.guest_csum
+            && metadata.l4_protocol != L4Protocol::Unknown
+            && metadata.l4_checksum.is_valid();
 
+        // Packets are never larger than a single buffer, even when mergeable
+        // receive buffers are negotiated, since no receive segmentation
+        // offloads are offered.
         let virtio_net_header = VirtioNetHeader {
+            flags: VirtioNetHeaderFlags::new()
+                .with_data_valid(data_valid)
+                .into(),
             num_buffers: 1,
             ..FromZeroes::new_zeroed()
         };
@@ -0,0 +1,249 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Control virtqueue commands and the receive filter they configure.
+
+use inspect::Inspect;
+use zerocopy::FromBytes;
+
+// These correspond to VIRTIO_NET_CTRL_ classes.
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+
+// VIRTIO_NET_CTRL_RX commands.
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_RX_ALLUNI: u8 = 2;
+const VIRTIO_NET_CTRL_RX_NOMULTI: u8 = 3;
+const VIRTIO_NET_CTRL_RX_NOUNI: u8 = 4;
+const VIRTIO_NET_CTRL_RX_NOBCAST: u8 = 5;
+
+// VIRTIO_NET_CTRL_MAC commands.
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+
+// VIRTIO_NET_CTRL_MQ commands.
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
+/// The command completed successfully.
+pub const VIRTIO_NET_OK: u8 = 0;
+/// The command failed or is not supported.
+pub const VIRTIO_NET_ERR: u8 = 1;
+
+/// A parsed control command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Sets one of the receive mode flags.
+    RxMode(RxMode, bool),
+    /// Replaces the unicast and multicast MAC filter tables.
+    MacTable {
+        unicast: Vec<[u8; 6]>,
+        multicast: Vec<[u8; 6]>,
+    },
+    /// Sets the number of queue pairs in use.
+    SetQueuePairs(u16),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RxMode {
+    Promiscuous,
+    AllMulticast,
+    AllUnicast,
+    NoMulticast,
+    NoUnicast,
+    NoBroadcast,
+}
+
+impl Command {
+    /// Parses a command from the device-readable part of a control queue
+    /// request, returning `None` if it is malformed or unsupported.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (&[class, command], data) = data.split_first_chunk::<2>()?;
+        let command = match (class, command) {
+            (VIRTIO_NET_CTRL_RX, command) => {
+                let mode = match command {
+                    VIRTIO_NET_CTRL_RX_PROMISC => RxMode::Promiscuous,
+                    VIRTIO_NET_CTRL_RX_ALLMULTI => RxMode::AllMulticast,
+                    VIRTIO_NET_CTRL_RX_ALLUNI => RxMode::AllUnicast,
+                    VIRTIO_NET_CTRL_RX_NOMULTI => RxMode::NoMulticast,
+                    VIRTIO_NET_CTRL_RX_NOUNI => RxMode::NoUnicast,
+                    VIRTIO_NET_CTRL_RX_NOBCAST => RxMode::NoBroadcast,
+                    _ => return None,
+                };
+                Self::RxMode(mode, *data.first()? != 0)
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => {
+                let (unicast, data) = parse_mac_table(data)?;
+                let (multicast, _) = parse_mac_table(data)?;
+                Self::MacTable { unicast, multicast }
+            }
+            (VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET) => {
+                Self::SetQueuePairs(u16::read_from_prefix(data)?)
+            }
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// Parses a `struct virtio_net_ctrl_mac`, returning the entries and the
+/// remaining data.
+fn parse_mac_table(data: &[u8]) -> Option<(Vec<[u8; 6]>, &[u8])> {
+    let entries = u32::read_from_prefix(data)? as usize;
+    let data = &data[4..];
+    let len = entries.checked_mul(6).filter(|&len| len <= data.len())?;
+    let (table, rest) = data.split_at(len);
+    let table = table
+        .chunks_exact(6)
+        .map(|mac| mac.try_into().unwrap())
+        .collect();
+    Some((table, rest))
+}
+
+/// The receive filter configured by the guest through `VIRTIO_NET_CTRL_RX`
+/// and `VIRTIO_NET_CTRL_MAC` commands.
+#[derive(Debug, Inspect)]
+pub struct RxFilter {
+    promiscuous: bool,
+    all_multicast: bool,
+    all_unicast: bool,
+    no_multicast: bool,
+    no_unicast: bool,
+    no_broadcast: bool,
+    #[inspect(with = "Vec::len")]
+    unicast: Vec<[u8; 6]>,
+    #[inspect(with = "Vec::len")]
+    multicast: Vec<[u8; 6]>,
+}
+
+impl Default for RxFilter {
+    fn default() -> Self {
+        // Receive everything until the guest configures a filter, as is the
+        // case when the control queue is not negotiated.
+        Self {
+            promiscuous: true,
+            all_multicast: false,
+            all_unicast: false,
+            no_multicast: false,
+            no_unicast: false,
+            no_broadcast: false,
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+        }
+    }
+}
+
+impl RxFilter {
+    /// Applies an RX mode or MAC table command, returning the status to report
+    /// to the guest.
+    pub fn apply(&mut self, command: Command) -> u8 {
+        match command {
+            Command::RxMode(mode, on) => {
+                let flag = match mode {
+                    RxMode::Promiscuous => &mut self.promiscuous,
+                    RxMode::AllMulticast => &mut self.all_multicast,
+                    RxMode::AllUnicast => &mut self.all_unicast,
+                    RxMode::NoMulticast => &mut self.no_multicast,
+                    RxMode::NoUnicast => &mut self.no_unicast,
+                    RxMode::NoBroadcast => &mut self.no_broadcast,
+                };
+                *flag = on;
+            }
+            Command::MacTable { unicast, multicast } => {
+                self.unicast = unicast;
+                self.multicast = multicast;
+            }
+            // Queue changes are not part of the filter.
+            Command::SetQueuePairs(_) => return VIRTIO_NET_ERR,
+        }
+        VIRTIO_NET_OK
+    }
+
+    /// Returns true if every packet is accepted, so that the destination
+    /// address need not be checked.
+    pub fn accepts_all(&self) -> bool {
+        self.promiscuous
+    }
+
+    /// Returns true if a packet sent to `dest` should be delivered to a guest
+    /// with MAC address `mac`.
+    pub fn accepts(&self, mac: [u8; 6], dest: [u8; 6]) -> bool {
+        if self.promiscuous {
+            true
+        } else if dest == [0xff; 6] {
+            !self.no_broadcast
+        } else if dest[0] & 1 != 0 {
+            !self.no_multicast && (self.all_multicast || self.multicast.contains(&dest))
+        } else {
+            !self.no_unicast && (self.all_unicast || dest == mac || self.unicast.contains(&dest))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+    use super::RxFilter;
+    use super::RxMode;
+    use super::VIRTIO_NET_ERR;
+    use super::VIRTIO_NET_OK;
+
+    const MAC: [u8; 6] = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x56];
+    const OTHER: [u8; 6] = [0x00, 0x15, 0x5d, 0x12, 0x34, 0x57];
+    const MULTICAST: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Command::parse(&[0, 0, 1]),
+            Some(Command::RxMode(RxMode::Promiscuous, true))
+        );
+        assert_eq!(
+            Command::parse(&[4, 0, 4, 0]),
+            Some(Command::SetQueuePairs(4))
+        );
+        let mut table = vec![1, 0];
+        table.extend([1, 0, 0, 0]);
+        table.extend(OTHER);
+        table.extend([0, 0, 0, 0]);
+        assert_eq!(
+            Command::parse(&table),
+            Some(Command::MacTable {
+                unicast: vec![OTHER],
+                multicast: Vec::new()
+            })
+        );
+        // Truncated table.
+        assert_eq!(Command::parse(&table[..10]), None);
+        assert_eq!(Command::parse(&[0]), None);
+    }
+
+    #[test]
+    fn filter() {
+        let mut filter = RxFilter::default();
+        assert!(filter.accepts(MAC, OTHER));
+        assert_eq!(
+            filter.apply(Command::RxMode(RxMode::Promiscuous, false)),
+            VIRTIO_NET_OK
+        );
+        assert!(filter.accepts(MAC, MAC));
+        assert!(filter.accepts(MAC, [0xff; 6]));
+        assert!(!filter.accepts(MAC, OTHER));
+        assert!(!filter.accepts(MAC, MULTICAST));
+        filter.apply(Command::MacTable {
+            unicast: vec![OTHER],
+            multicast: vec![MULTICAST],
+        });
+        assert!(filter.accepts(MAC, OTHER));
+        assert!(filter.accepts(MAC, MULTICAST));
+        filter.apply(Command::RxMode(RxMode::NoBroadcast, true));
+        assert!(!filter.accepts(MAC, [0xff; 6]));
+    }
+
+    #[test]
+    fn filter_rejects_queue_pairs() {
+        let mut filter = RxFilter::default();
+        assert_eq!(filter.apply(Command::SetQueuePairs(2)), VIRTIO_NET_ERR);
+        assert!(filter.accepts_all());
+    }
+}
@@ -2,10 +2,15 @@
 // Licensed under the MIT License.
 
 mod buffers;
+mod control;
 pub mod resolver;
 
-// use anyhow::Context;
 use crate::buffers::VirtioWorkPool;
+use crate::control::Command;
+use crate::control::RxFilter;
+use crate::control::VIRTIO_NET_ERR;
+use crate::control::VIRTIO_NET_OK;
+use anyhow::Context;
 use bitfield_struct::bitfield;
 use futures::FutureExt;
 use futures::StreamExt;
@@ -15,16 +20,22 @@
 use inspect::InspectMut;
 use inspect_counters::Counter;
 use inspect_counters::Histogram;
+use net_backend::checksum;
+use net_backend::checksum::TCP_CHECKSUM_OFFSET;
+use net_backend::checksum::UDP_CHECKSUM_OFFSET;
 use net_backend::Endpoint;
 use net_backend::EndpointAction;
+use net_backend::L3Protocol;
 use net_backend::QueueConfig;
 use net_backend::RxId;
 use net_backend::TxId;
 use net_backend::TxMetadata;
+use net_backend::TxOffloadSupport;
 use net_backend::TxSegment;
 use net_backend::TxSegmentType;
 use net_backend_resources::mac_address::MacAddress;
 use pal_async::wait::PolledWait;
+use parking_lot::Mutex;
 use std::future::pending;
 use std::mem::offset_of;
 use std::sync::Arc;
@@ -101,9 +112,19 @@ struct NetStatus {
 
 const DEFAULT_MTU: u16 = 1514;
 
-#[allow(dead_code)]
 const VIRTIO_NET_MAX_QUEUES: u16 = 0x8000;
 
+/// The most bytes of a transmit request read to find the headers needed for
+/// offloads.
+const MAX_TX_HEADERS_LEN: usize = 256;
+
+/// The most bytes read from a control queue request.
+const MAX_CONTROL_LEN: usize = 0x10000;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
 #[repr(C)]
 struct NetConfig {
     pub mac: [u8; 6],
@@ -184,7 +205,9 @@ struct Adapter {
     driver: VmTaskDriver,
     max_queues: u16,
     tx_fast_completions: bool,
+    offload_support: TxOffloadSupport,
     mac_address: MacAddress,
+    rx_filter: Mutex<RxFilter>,
 }
 
 pub struct Device {
@@ -202,11 +225,26 @@ fn drop(&mut self) {}
 
 impl VirtioDevice for Device {
     fn traits(&self) -> DeviceTraits {
-        // TODO: Add network features based on endpoint capabilities (NetworkFeatures::VIRTIO_NET_F_*)
+        // Checksum offload must cover both TCP and UDP, and segmentation
+        // offload requires checksum offload.
+        let offloads = &self.adapter.offload_support;
+        let csum = offloads.tcp && offloads.udp;
+        let tso = csum && offloads.tso;
+        let features = NetworkFeatures::new()
+            .with_mac(true)
+            .with_csum(csum)
+            .with_host_tso4(tso)
+            .with_host_tso6(tso)
+            .with_guest_csum(true)
+            .with_mrg_rxbuf(true)
+            .with_ctrl_vq(true)
+            .with_ctrl_rx(true)
+            .with_mq(self.registers.max_virtqueue_pairs > 1);
         DeviceTraits {
             device_id: 1,
-            device_features: NetworkFeatures::new().with_mac(true).into(),
-            max_queues: 2 * self.registers.max_virtqueue_pairs,
+            device_features: features.into(),
+            // The queue pairs are followed by the control queue.
+            max_queues: 2 * self.registers.max_virtqueue_pairs + 1,
             device_register_length: size_of::<NetConfig>() as u32,
             shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
         }
@@ -234,7 +272,52 @@ fn read_registers_u32(&self, offset: u16) -> u32 {
     fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
 
     fn enable(&mut self, resources: Resources) {
+        let features = NetworkFeatures::from(resources.features);
         let mut queue_resources: Vec<_> = resources.queues.into_iter().collect();
+        // Without VIRTIO_NET_F_MQ, the control queue follows the first queue
+        // pair.
+        let queue_pairs = if features.mq() {
+            self.registers.max_virtqueue_pairs
+        } else {
+            1
+        };
+        queue_resources.truncate(2 * queue_pairs as usize + 1);
+        let ctrl_resources = if features.ctrl_vq() {
+            queue_resources.pop()
+        } else {
+            None
+        };
+        let ctrl_queue = ctrl_resources
+            .filter(|resources| resources.params.enable)
+            .and_then(|ctrl_resources| {
+                let event = match PolledWait::new(&self.adapter.driver, ctrl_resources.event) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        tracing::error!(
+                            err = &err as &dyn std::error::Error,
+                            "Failed creating queue event"
+                        );
+                        return None;
+                    }
+                };
+                match VirtioQueue::new(
+                    resources.features,
+                    ctrl_resources.params,
+                    self.memory.clone(),
+                    ctrl_resources.notify,
+                    event,
+                ) {
+                    Ok(queue) => Some(queue),
+                    Err(err) => {
+                        tracing::error!(
+                            err = &err as &dyn std::error::Error,
+                            "Failed creating virtio net control queue"
+                        );
+                        None
+                    }
+                }
+            });
+
         let mut workers = Vec::with_capacity(queue_resources.len() / 2);
         while queue_resources.len() > 1 {
             let mut next = queue_resources.drain(..2);
@@ -299,11 +382,12 @@ fn enable(&mut self, resources: Resources) {
             });
         }
 
+        *self.adapter.rx_filter.lock() = RxFilter::default();
         let (tx, rx) = mesh::channel();
         self.coordinator_send = Some(tx);
-        self.insert_coordinator(rx, workers.len() as u16);
+        self.insert_coordinator(rx, workers.len() as u16, ctrl_queue);
         for (i, virtio_state) in workers.into_iter().enumerate() {
-            self.insert_worker(virtio_state, i);
+            self.insert_worker(virtio_state, i, features.guest_csum());
         }
         self.coordinator.start();
     }
@@ -381,6 +465,7 @@ struct QueueStats {
     tx_packets: Counter,
     tx_packets_per_wake: Histogram<10>,
     rx_packets_per_wake: Histogram<10>,
+    rx_filtered: Counter,
 }
 
 struct ActiveState {
@@ -391,10 +476,10 @@ struct ActiveState {
 }
 
 impl ActiveState {
-    fn new(mem: GuestMemory, rx_queue_size: u16, tx_queue_size: u16) -> Self {
+    fn new(mem: GuestMemory, rx_queue_size: u16, tx_queue_size: u16, guest_csum: bool) -> Self {
         Self {
             pending_tx_packets: (0..tx_queue_size).map(|_| None).collect(),
-            pending_rx_packets: VirtioWorkPool::new(mem, rx_queue_size),
+            pending_rx_packets: VirtioWorkPool::new(mem, rx_queue_size, guest_csum),
             data: ProcessingData::new(rx_queue_size, tx_queue_size),
             stats: Default::default(),
         }
@@ -424,17 +509,23 @@ pub fn build(
         endpoint: Box<dyn Endpoint>,
         mac_address: MacAddress,
     ) -> Device {
-        // TODO: Implement VIRTIO_NET_F_MQ and VIRTIO_NET_F_RSS logic based on mulitqueue support.
-        // let multiqueue = endpoint.multiqueue_support();
-        // let max_queues = self.max_queues.clamp(1, multiqueue.max_queues.min(VIRTIO_NET_MAX_QUEUES));
-        let max_queues = 1;
+        // TODO: Implement VIRTIO_NET_F_RSS logic based on multiqueue support.
+        // Leave room for the control queue in the 16-bit queue count.
+        let multiqueue = endpoint.multiqueue_support();
+        let max_queues = self
+            .max_queues
+            .min(multiqueue.max_queues)
+            .min(VIRTIO_NET_MAX_QUEUES - 1)
+            .max(1);
 
         let driver = driver_source.simple();
         let adapter = Arc::new(Adapter {
             driver,
             max_queues,
             tx_fast_completions: endpoint.tx_fast_completions(),
+            offload_support: endpoint.tx_offload_support(),
             mac_address,
+            rx_filter: Mutex::new(RxFilter::default()),
         });
 
         let coordinator = TaskControl::new(CoordinatorState {
@@ -478,7 +569,12 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>) {
 }
 
 impl Device {
-    fn insert_coordinator(&mut self, recv: mesh::Receiver<CoordinatorMessage>, num_queues: u16) {
+    fn insert_coordinator(
+        &mut self,
+        recv: mesh::Receiver<CoordinatorMessage>,
+        num_queues: u16,
+        ctrl_queue: Option<VirtioQueue>,
+    ) {
         self.coordinator.insert(
             &self.adapter.driver,
             "virtio-net-coordinator".to_string(),
@@ -488,6 +584,11 @@ fn insert_coordinator(&mut self, recv: mesh::Receiver<CoordinatorMessage>, num_q
                     .map(|_| TaskControl::new(NetQueue { state: None }))
                     .collect(),
                 num_queues,
+                // Only the first queue pair is used until the guest sets the
+                // number of pairs via the control queue.
+                active_queues: 1,
+                ctrl_queue,
+                mem: self.memory.clone(),
                 restart: true,
             },
         );
@@ -496,7 +597,7 @@ fn insert_coordinator(&mut self, recv: mesh::Receiver<CoordinatorMessage>, num_q
     /// Allocates and inserts a worker.
     ///
     /// The coordinator must be stopped.
-    fn insert_worker(&mut self, virtio_state: VirtioState, idx: usize) {
+    fn insert_worker(&mut self, virtio_state: VirtioState, idx: usize, guest_csum: bool) {
         let mut builder = self.driver_source.builder();
         // TODO: set this correctly
         builder.target_vp(0);
@@ -511,10 +612,13 @@ fn insert_worker(&mut self, virtio_state: VirtioState, idx: usize) {
             self.memory.clone(),
             virtio_state.rx_queue_size,
             virtio_state.tx_queue_size,
+            guest_csum,
         );
         let worker = Worker {
             virtio_state,
             active_state,
+            adapter: self.adapter.clone(),
+            mem: self.memory.clone(),
         };
         let coordinator = self.coordinator.state_mut().unwrap();
         let worker_task = &mut coordinator.workers[idx];
@@ -531,7 +635,12 @@ enum CoordinatorMessage {
 struct Coordinator {
     recv: mesh::Receiver<CoordinatorMessage>,
     workers: Vec<TaskControl<NetQueue, Worker>>,
+    /// The number of queue pairs enabled by the guest.
     num_queues: u16,
+    /// The number of queue pairs the guest has asked to use.
+    active_queues: u16,
+    ctrl_queue: Option<VirtioQueue>,
+    mem: GuestMemory,
     restart: bool,
 }
 
@@ -546,7 +655,8 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>, coordinator: Option<&mut Co
 
         let adapter = self.adapter.as_ref();
         resp.field("mac_address", adapter.mac_address)
-            .field("max_queues", adapter.max_queues);
+            .field("max_queues", adapter.max_queues)
+            .field("rx_filter", &*adapter.rx_filter.lock());
 
         resp.field("endpoint_type", self.endpoint.endpoint_type())
             .field(
@@ -556,6 +666,7 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>, coordinator: Option<&mut Co
             .field_mut("endpoint", self.endpoint.as_mut());
 
         if let Some(coordinator) = coordinator {
+            resp.field("active_queues", coordinator.active_queues);
             resp.fields_mut(
                 "queues",
                 coordinator.workers[..coordinator.num_queues as usize]
@@ -584,22 +695,14 @@ async fn process(
     ) -> Result<(), task_control::Cancelled> {
         loop {
             if self.restart {
-                stop.until_stopped(self.stop_workers()).await?;
-                // The queue restart operation is not restartable, so do not
-                // poll on `stop` here.
-                if let Err(err) = self.restart_queues(state).await {
-                    tracing::error!(
-                        error = &err as &dyn std::error::Error,
-                        "failed to restart queues"
-                    );
-                }
-                self.restart = false;
+                self.reconfigure_queues(stop, state).await?;
             }
             self.start_workers();
             enum Message {
                 Internal(CoordinatorMessage),
                 ChannelDisconnected,
                 UpdateFromEndpoint(EndpointAction),
+                Control(Result<VirtioQueueCallbackWork, std::io::Error>),
             }
             let message = {
                 let wait_for_message = async {
@@ -611,7 +714,16 @@ enum Message {
                         .endpoint
                         .wait_for_endpoint_action()
                         .map(Message::UpdateFromEndpoint);
-                    (internal_msg, endpoint_restart).race().await
+                    let ctrl_queue = &mut self.ctrl_queue;
+                    let control = async move {
+                        match ctrl_queue {
+                            Some(queue) => {
+                                Message::Control(queue.next().await.expect("queue never completes"))
+                            }
+                            None => pending().await,
+                        }
+                    };
+                    (internal_msg, endpoint_restart, control).race().await
                 };
                 stop.until_stopped(wait_for_message).await?
             };
@@ -624,11 +736,82 @@ enum Message {
                     stop.until_stopped(self.stop_workers()).await?;
                     break;
                 }
+                Message::Control(Ok(work)) => self.handle_control(stop, state, work).await?,
+                Message::Control(Err(err)) => {
+                    tracing::error!(
+                        err = &err as &dyn std::error::Error,
+                        "virtio net control queue failure"
+                    );
+                }
             };
         }
         Ok(())
     }
 
+    /// Stops the workers and gives them new endpoint queues.
+    async fn reconfigure_queues(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut CoordinatorState,
+    ) -> Result<(), task_control::Cancelled> {
+        stop.until_stopped(self.stop_workers()).await?;
+        // The queue restart operation is not restartable, so do not
+        // poll on `stop` here.
+        if let Err(err) = self.restart_queues(state).await {
+            tracing::error!(
+                error = &err as &dyn std::error::Error,
+                "failed to restart queues"
+            );
+        }
+        self.restart = false;
+        Ok(())
+    }
+
+    async fn handle_control(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut CoordinatorState,
+        mut work: VirtioQueueCallbackWork,
+    ) -> Result<(), task_control::Cancelled> {
+        let len = (work.get_payload_length(false) as usize).min(MAX_CONTROL_LEN);
+        let mut data = vec![0; len];
+        let command = match work.read(&self.mem, &mut data) {
+            Ok(n) => Command::parse(&data[..n]),
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to read control command"
+                );
+                None
+            }
+        };
+        let status = match command {
+            Some(Command::SetQueuePairs(pairs)) => {
+                if pairs == 0 || pairs > self.num_queues {
+                    VIRTIO_NET_ERR
+                } else {
+                    // Stop using the old queues before acknowledging the
+                    // change.
+                    if pairs != self.active_queues {
+                        self.active_queues = pairs;
+                        self.reconfigure_queues(stop, state).await?;
+                    }
+                    VIRTIO_NET_OK
+                }
+            }
+            Some(command) => state.adapter.rx_filter.lock().apply(command),
+            None => VIRTIO_NET_ERR,
+        };
+        if let Err(err) = work.write(&self.mem, &[status]) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to write control status"
+            );
+        }
+        work.complete(1);
+        Ok(())
+    }
+
     async fn stop_workers(&mut self) {
         for worker in &mut self.workers {
             worker.stop().await;
@@ -641,8 +824,10 @@ async fn restart_queues(&mut self, c_state: &mut CoordinatorState) -> Result<(),
             worker.task_mut().state = None;
         }
 
-        let (rx_pools, ready_packets): (Vec<_>, Vec<_>) = self
-            .workers
+        // Only give endpoint queues to the queue pairs in use; the rest wait
+        // idle.
+        let active = self.active_queues.min(self.num_queues) as usize;
+        let (rx_pools, ready_packets): (Vec<_>, Vec<_>) = self.workers[..active]
             .iter()
             .map(|worker| {
                 let pool = worker
@@ -673,9 +858,9 @@ async fn restart_queues(&mut self, c_state: &mut CoordinatorState) -> Result<(),
             .await
             .map_err(WorkerError::Endpoint)?;
 
-        assert_eq!(queues.len(), self.workers.len());
+        assert_eq!(queues.len(), active);
 
-        for (worker, queue) in self.workers.iter_mut().zip(queues) {
+        for (worker, queue) in self.workers[..active].iter_mut().zip(queues) {
             worker.task_mut().state = Some(EndpointQueueState { queue });
         }
 
@@ -740,6 +925,8 @@ enum PacketError {
 struct Worker {
     virtio_state: VirtioState,
     active_state: ActiveState,
+    adapter: Arc<Adapter>,
+    mem: GuestMemory,
 }
 
 impl Worker {
@@ -831,6 +1018,24 @@ enum WakeReason {
     }
 
     fn queue_tx_packet(&mut self, mut work: VirtioQueueCallbackWork) -> Result<(), WorkerError> {
+        // Read the header and the start of the frame to determine offloads.
+        let mut headers = [0; MAX_TX_HEADERS_LEN];
+        let headers_len = work.read(&self.mem, &mut headers).unwrap_or(0);
+        let offloads = match tx_offloads(&headers[..headers_len]) {
+            Some(offloads) => offloads,
+            None => {
+                // The endpoint cannot be asked to fill in a checksum that the
+                // guest left partial, so compute it here instead.
+                if let Err(err) = fill_tx_checksum(&self.mem, &work, &headers[..headers_len]) {
+                    tracelimit::warn_ratelimited!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "failed to compute transmit checksum"
+                    );
+                }
+                TxMetadata::default()
+            }
+        };
+
         let mut header_bytes_remaining = header_size() as u32;
         let mut segments = work
             .payload
@@ -867,7 +1072,7 @@ fn queue_tx_packet(&mut self, mut work: VirtioQueueCallbackWork) -> Result<(), W
             id: TxId(idx.into()),
             segment_count: segments.len(),
             len: work.get_payload_length(false) as usize - header_size(),
-            ..Default::default()
+            ..offloads
         });
         let state = &mut self.active_state;
         state.data.tx_segments.append(&mut segments);
@@ -907,9 +1112,27 @@ fn process_endpoint_rx(
             return Ok(false);
         }
 
-        for ready_id in state.data.rx_ready[..n].iter() {
+        let filter = self.adapter.rx_filter.lock();
+        let mac = self.adapter.mac_address.to_bytes();
+        let mut filtered = Vec::new();
+        for &ready_id in state.data.rx_ready[..n].iter() {
+            if !filter.accepts_all()
+                && !state
+                    .pending_rx_packets
+                    .destination(ready_id)
+                    .is_some_and(|dest| filter.accepts(mac, dest))
+            {
+                // Return the buffer to the endpoint for reuse.
+                state.stats.rx_filtered.increment();
+                filtered.push(ready_id);
+                continue;
+            }
             state.stats.rx_packets.increment();
-            state.pending_rx_packets.complete_packet(*ready_id);
+            state.pending_rx_packets.complete_packet(ready_id);
+        }
+        drop(filter);
+        if !filtered.is_empty() {
+            epqueue.rx_avail(&filtered);
         }
 
         state.stats.rx_packets_per_wake.add_sample(n as u64);
@@ -995,3 +1218,259 @@ fn complete_tx_packet(&mut self, id: TxId) -> Result<(), WorkerError> {
         Ok(())
     }
 }
+
+/// Computes the checksum of a transmit packet that requested
+/// `VIRTIO_NET_HDR_F_NEEDS_CSUM` but whose offloads could not be described to
+/// the endpoint, and stores it in the guest's buffer at `csum_start +
+/// csum_offset`.
+///
+/// The guest does not touch the buffer again until the packet is completed, so
+/// the frame can be fixed up in place before the endpoint sends it.
+fn fill_tx_checksum(
+    mem: &GuestMemory,
+    work: &VirtioQueueCallbackWork,
+    headers: &[u8],
+) -> anyhow::Result<()> {
+    let Some(bytes) = headers.get(..header_size()) else {
+        return Ok(());
+    };
+    let mut header = VirtioNetHeader::new_zeroed();
+    header.as_bytes_mut()[..header_size()].copy_from_slice(bytes);
+    if !VirtioNetHeaderFlags::from(header.flags).needs_csum() {
+        return Ok(());
+    }
+
+    let mut data = vec![0; work.get_payload_length(false) as usize];
+    let len = work.read(mem, &mut data)?;
+    let frame = &data[header_size().min(len)..len];
+    let csum_start = header.csum_start as usize;
+    let field = csum_start + header.csum_offset as usize;
+    let checksum =
+        checksum::complete(frame, csum_start, field).context("invalid checksum offsets")?;
+
+    // Write the checksum over the field in the device-readable descriptors,
+    // which may split it.
+    let mut offset = (header_size() + field) as u64;
+    let mut source = &checksum.to_be_bytes()[..];
+    for payload in work.payload.iter().filter(|p| !p.writeable) {
+        let len = payload.length as u64;
+        if offset >= len {
+            offset -= len;
+            continue;
+        }
+        let n = source.len().min((len - offset) as usize);
+        mem.write_at(payload.address + offset, &source[..n])?;
+        source = &source[n..];
+        if source.is_empty() {
+            break;
+        }
+        offset = 0;
+    }
+    Ok(())
+}
+
+/// Builds the transmit offload metadata from the virtio header and the start of
+/// the frame that follows it, or returns `None` if no offloads were requested
+/// or they cannot be described.
+fn tx_offloads(data: &[u8]) -> Option<TxMetadata> {
+    let mut header = VirtioNetHeader::new_zeroed();
+    header.as_bytes_mut()[..header_size()].copy_from_slice(data.get(..header_size())?);
+    let frame = &data[header_size()..];
+    if !VirtioNetHeaderFlags::from(header.flags).needs_csum() {
+        return None;
+    }
+
+    let ethertype = |offset: usize| {
+        frame
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+    };
+    let (l2_len, ethertype) = match ethertype(12)? {
+        ETHERTYPE_VLAN => (18, ethertype(16)?),
+        ethertype => (14, ethertype),
+    };
+    let l3_protocol = match ethertype {
+        ETHERTYPE_IPV4 => L3Protocol::Ipv4,
+        ETHERTYPE_IPV6 => L3Protocol::Ipv6,
+        _ => return None,
+    };
+    let l4_start = header.csum_start as usize;
+    let l3_len = l4_start.checked_sub(l2_len).filter(|&len| len > 0)?;
+    let mut metadata = TxMetadata {
+        l3_protocol,
+        l2_len: l2_len as u8,
+        l3_len: l3_len as u16,
+        ..Default::default()
+    };
+    match header.csum_offset {
+        TCP_CHECKSUM_OFFSET => metadata.offload_tcp_checksum = true,
+        UDP_CHECKSUM_OFFSET => metadata.offload_udp_checksum = true,
+        _ => return None,
+    }
+
+    match VirtioNetHeaderGso::from(header.gso_type).protocol() {
+        VirtioNetHeaderGsoProtocol::NONE => {}
+        VirtioNetHeaderGsoProtocol::TCPV4 | VirtioNetHeaderGsoProtocol::TCPV6
+            if metadata.offload_tcp_checksum =>
+        {
+            // The header length in the virtio header is only a hint, so get
+            // the TCP header length from its data offset.
+            let data_offset = *frame.get(l4_start + 12)? >> 4;
+            metadata.offload_tcp_segmentation = true;
+            metadata.offload_ip_header_checksum = l3_protocol == L3Protocol::Ipv4;
+            metadata.l4_len = data_offset * 4;
+            metadata.max_tcp_segment_size = header.gso_size;
+        }
+        protocol => {
+            tracelimit::warn_ratelimited!(?protocol, "unsupported gso type");
+            return None;
+        }
+    }
+    Some(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::header_size;
+    use super::tx_offloads;
+    use super::VirtioNetHeader;
+    use super::VirtioNetHeaderFlags;
+    use super::VirtioNetHeaderGso;
+    use super::VirtioNetHeaderGsoProtocol;
+    use super::ETHERTYPE_IPV4;
+    use super::ETHERTYPE_IPV6;
+    use super::ETHERTYPE_VLAN;
+    use net_backend::checksum::TCP_CHECKSUM_OFFSET;
+    use net_backend::checksum::UDP_CHECKSUM_OFFSET;
+    use net_backend::L3Protocol;
+    use zerocopy::AsBytes;
+    use zerocopy::FromZeroes;
+
+    /// Builds a transmit request: the virtio header followed by an Ethernet
+    /// frame with the given ethertypes and `l3_len` bytes of L3 header, then a
+    /// TCP header with a data offset of 8 words.
+    fn request(header: VirtioNetHeader, ethertypes: &[u16], l3_len: usize) -> Vec<u8> {
+        let mut data = header.as_bytes()[..header_size()].to_vec();
+        data.extend([0; 12]);
+        for (i, ethertype) in ethertypes.iter().enumerate() {
+            if i > 0 {
+                data.extend([0; 2]);
+            }
+            data.extend(ethertype.to_be_bytes());
+        }
+        data.extend(vec![0; l3_len]);
+        let mut tcp = [0; 32];
+        tcp[12] = 8 << 4;
+        data.extend(tcp);
+        data
+    }
+
+    fn header(
+        csum_start: u16,
+        csum_offset: u16,
+        gso: VirtioNetHeaderGsoProtocol,
+    ) -> VirtioNetHeader {
+        VirtioNetHeader {
+            flags: VirtioNetHeaderFlags::new().with_needs_csum(true).into(),
+            gso_type: VirtioNetHeaderGso::new().with_protocol(gso).into(),
+            gso_size: 1448,
+            csum_start,
+            csum_offset,
+            ..VirtioNetHeader::new_zeroed()
+        }
+    }
+
+    #[test]
+    fn no_offloads() {
+        let data = request(VirtioNetHeader::new_zeroed(), &[ETHERTYPE_IPV4], 20);
+        assert!(tx_offloads(&data).is_none());
+        assert!(tx_offloads(&data[..header_size() - 1]).is_none());
+    }
+
+    #[test]
+    fn checksum_offloads() {
+        let data = request(
+            header(34, TCP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::NONE),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        let metadata = tx_offloads(&data).unwrap();
+        assert_eq!(metadata.l3_protocol, L3Protocol::Ipv4);
+        assert_eq!((metadata.l2_len, metadata.l3_len), (14, 20));
+        assert!(metadata.offload_tcp_checksum);
+        assert!(!metadata.offload_udp_checksum);
+        assert!(!metadata.offload_tcp_segmentation);
+
+        let data = request(
+            header(58, UDP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::NONE),
+            &[ETHERTYPE_VLAN, ETHERTYPE_IPV6],
+            40,
+        );
+        let metadata = tx_offloads(&data).unwrap();
+        assert_eq!(metadata.l3_protocol, L3Protocol::Ipv6);
+        assert_eq!((metadata.l2_len, metadata.l3_len), (18, 40));
+        assert!(metadata.offload_udp_checksum);
+    }
+
+    #[test]
+    fn segmentation_offloads() {
+        let data = request(
+            header(34, TCP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::TCPV4),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        let metadata = tx_offloads(&data).unwrap();
+        assert!(metadata.offload_tcp_segmentation);
+        assert!(metadata.offload_ip_header_checksum);
+        assert_eq!(metadata.l4_len, 32);
+        assert_eq!(metadata.max_tcp_segment_size, 1448);
+
+        let data = request(
+            header(54, TCP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::TCPV6),
+            &[ETHERTYPE_IPV6],
+            40,
+        );
+        let metadata = tx_offloads(&data).unwrap();
+        assert!(metadata.offload_tcp_segmentation);
+        assert!(!metadata.offload_ip_header_checksum);
+    }
+
+    #[test]
+    fn undescribable_offloads() {
+        // Unknown ethertype.
+        let data = request(
+            header(34, TCP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::NONE),
+            &[0x0806],
+            20,
+        );
+        assert!(tx_offloads(&data).is_none());
+        // Checksum starting inside the Ethernet header.
+        let data = request(
+            header(14, TCP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::NONE),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        assert!(tx_offloads(&data).is_none());
+        // Checksum that is neither TCP nor UDP.
+        let data = request(
+            header(34, 2, VirtioNetHeaderGsoProtocol::NONE),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        assert!(tx_offloads(&data).is_none());
+        // Segmentation without TCP checksum offload.
+        let data = request(
+            header(34, UDP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::TCPV4),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        assert!(tx_offloads(&data).is_none());
+        // Unsupported segmentation type.
+        let data = request(
+            header(34, UDP_CHECKSUM_OFFSET, VirtioNetHeaderGsoProtocol::UDP_L4),
+            &[ETHERTYPE_IPV4],
+            20,
+        );
+        assert!(tx_offloads(&data).is_none());
+    }
+}
@@ -62,6 +62,85 @@ impl ResourceId<VirtioDeviceHandle> for VirtioPmemHandle {
     }
 }
 
+pub mod balloon {
+    use mesh::rpc::Rpc;
+    use mesh::MeshPayload;
+    use vm_resource::kind::VirtioDeviceHandle;
+    use vm_resource::ResourceId;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioBalloonHandle {
+        /// The channel by which to receive balloon requests.
+        pub recv: mesh::Receiver<BalloonRpc>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioBalloonHandle {
+        const ID: &'static str = "virtio-balloon";
+    }
+
+    /// An RPC request to the balloon device.
+    #[derive(MeshPayload)]
+    pub enum BalloonRpc {
+        /// Set the size, in bytes, that the guest should inflate the balloon
+        /// to.
+        SetTarget(Rpc<u64, ()>),
+        /// Get the balloon's size and the guest's memory statistics.
+        Query(Rpc<(), BalloonStatus>),
+    }
+
+    /// The state of the balloon.
+    #[derive(Debug, MeshPayload)]
+    pub struct BalloonStatus {
+        /// The target size of the balloon, in bytes.
+        pub target: u64,
+        /// The size of the balloon reported by the guest, in bytes.
+        pub actual: u64,
+        /// The guest's most recently reported memory statistics.
+        pub stats: BalloonStats,
+    }
+
+    /// Guest memory statistics. Statistics the guest has not reported are
+    /// `None`.
+    #[derive(Debug, Default, Clone, MeshPayload)]
+    pub struct BalloonStats {
+        /// Bytes swapped in.
+        pub swap_in: Option<u64>,
+        /// Bytes swapped out.
+        pub swap_out: Option<u64>,
+        /// Major page faults.
+        pub major_faults: Option<u64>,
+        /// Minor page faults.
+        pub minor_faults: Option<u64>,
+        /// Bytes of unused memory.
+        pub free_memory: Option<u64>,
+        /// Bytes of memory available to the guest.
+        pub total_memory: Option<u64>,
+        /// Estimated bytes of memory available for new allocations.
+        pub available_memory: Option<u64>,
+        /// Bytes of memory in the file cache.
+        pub disk_caches: Option<u64>,
+    }
+}
+
+pub mod blk {
+    use mesh::MeshPayload;
+    use vm_resource::kind::DiskHandleKind;
+    use vm_resource::kind::VirtioDeviceHandle;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioBlkHandle {
+        pub disk: Resource<DiskHandleKind>,
+        pub read_only: bool,
+        pub max_queues: Option<u16>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioBlkHandle {
+        const ID: &'static str = "virtio-blk";
+    }
+}
+
 pub mod net {
     use mesh::MeshPayload;
     use net_backend_resources::mac_address::MacAddress;
@@ -81,3 +160,44 @@ impl ResourceId<VirtioDeviceHandle> for VirtioNetHandle {
         const ID: &'static str = "virtio-net";
     }
 }
+
+pub mod rng {
+    use mesh::MeshPayload;
+    use vm_resource::kind::VirtioDeviceHandle;
+    use vm_resource::ResourceId;
+
+    /// A virtio entropy device.
+    #[derive(MeshPayload)]
+    pub struct VirtioRngHandle {
+        /// The most bytes to supply to the guest per second.
+        pub rate_limit: Option<u64>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioRngHandle {
+        const ID: &'static str = "virtio-rng";
+    }
+}
+
+pub mod vsock {
+    use mesh::MeshPayload;
+    use unix_socket::UnixListener;
+    use vm_resource::kind::VirtioDeviceHandle;
+    use vm_resource::ResourceId;
+
+    /// A virtio-vsock device that relays connections to Unix sockets using the
+    /// hybrid vsock convention.
+    #[derive(MeshPayload)]
+    pub struct VirtioVsockHandle {
+        /// The guest's context ID.
+        pub guest_cid: u64,
+        /// The path that guest connections to port `N` are relayed to, as
+        /// `<base_path>_N`.
+        pub base_path: String,
+        /// The listener for host connections, bound at `base_path`.
+        pub listener: UnixListener,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioVsockHandle {
+        const ID: &'static str = "virtio-vsock";
+    }
+}
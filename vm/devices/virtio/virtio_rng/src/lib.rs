@@ -0,0 +1,344 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio entropy device, which supplies the guest with random bytes from
+//! the host's CSPRNG.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use pal_async::task::Spawn;
+use pal_async::wait::PolledWait;
+use std::sync::Arc;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::StopTask;
+use task_control::TaskControl;
+use token_bucket::Clock;
+use token_bucket::DriverClock;
+use token_bucket::TokenBucket;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+/// The most bytes supplied for a single request.
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+pub struct Device {
+    driver: VmTaskDriver,
+    memory: GuestMemory,
+    rate_limit: Option<u64>,
+    clock: Arc<dyn Clock>,
+    worker: Option<TaskControl<RngWorker, RngQueue>>,
+}
+
+impl Device {
+    /// Creates an entropy device, supplying at most `rate_limit` bytes per
+    /// second if specified.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        let driver = driver_source.simple();
+        Self {
+            clock: Arc::new(DriverClock::new(driver.clone())),
+            driver,
+            memory,
+            rate_limit: rate_limit.filter(|&rate| rate != 0),
+            worker: None,
+        }
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: 4,
+            device_features: 0,
+            max_queues: 1,
+            device_register_length: 0,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, _offset: u16) -> u32 {
+        0
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        let Some(queue_resources) = resources.queues.into_iter().next() else {
+            return;
+        };
+        if !queue_resources.params.enable {
+            return;
+        }
+        let queue_event = match PolledWait::new(&self.driver, queue_resources.event) {
+            Ok(event) => event,
+            Err(err) => {
+                tracing::error!(
+                    err = &err as &dyn std::error::Error,
+                    "Failed creating queue event"
+                );
+                return;
+            }
+        };
+        let queue = match VirtioQueue::new(
+            resources.features,
+            queue_resources.params,
+            self.memory.clone(),
+            queue_resources.notify,
+            queue_event,
+        ) {
+            Ok(queue) => queue,
+            Err(err) => {
+                tracing::error!(
+                    err = &err as &dyn std::error::Error,
+                    "Failed creating virtio rng queue"
+                );
+                return;
+            }
+        };
+
+        let mut worker = TaskControl::new(RngWorker {
+            memory: self.memory.clone(),
+            // Allow bursts of up to one second's worth of bytes.
+            limiter: self.rate_limit.map(|rate| TokenBucket::new(rate, rate)),
+            clock: self.clock.clone(),
+        });
+        worker.insert(&self.driver, "virtio-rng", RngQueue { queue });
+        worker.start();
+        self.worker = Some(worker);
+    }
+
+    fn disable(&mut self) {
+        if let Some(mut worker) = self.worker.take() {
+            self.driver
+                .spawn("shutdown-virtio-rng", async move {
+                    worker.stop().await;
+                })
+                .detach();
+        }
+    }
+}
+
+struct RngWorker {
+    memory: GuestMemory,
+    limiter: Option<TokenBucket>,
+    clock: Arc<dyn Clock>,
+}
+
+struct RngQueue {
+    queue: VirtioQueue,
+}
+
+#[async_trait]
+impl AsyncRun<RngQueue> for RngWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut RngQueue,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            while let Some(work) = state.queue.next().await {
+                let mut work = match work {
+                    Ok(work) => work,
+                    Err(err) => {
+                        tracing::error!(
+                            err = &err as &dyn std::error::Error,
+                            "virtio rng queue failure"
+                        );
+                        break;
+                    }
+                };
+                let mut len = work.get_payload_length(true).min(MAX_REQUEST_SIZE);
+                if let Some(limiter) = &mut self.limiter {
+                    len = loop {
+                        let now = self.clock.now();
+                        match limiter.take(now, len) {
+                            Ok(n) => break n,
+                            Err(wait) => self.clock.sleep_until(now.saturating_add(wait)).await,
+                        }
+                    };
+                }
+                let mut buf = vec![0; len as usize];
+                if let Err(err) = getrandom::getrandom(&mut buf) {
+                    tracelimit::error_ratelimited!(
+                        err = &err as &dyn std::error::Error,
+                        "failed to get random bytes"
+                    );
+                    continue;
+                }
+                match work.write(&self.memory, &buf) {
+                    Ok(()) => work.complete(len as u32),
+                    Err(err) => {
+                        tracelimit::warn_ratelimited!(
+                            err = &err as &dyn std::error::Error,
+                            "failed to write random bytes"
+                        );
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Device;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use pal_async::DefaultDriver;
+    use pal_event::Event;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use token_bucket::test_helpers::MockClock;
+    use token_bucket::Clock;
+    use virtio::queue::QueueParams;
+    use virtio::QueueResources;
+    use virtio::Resources;
+    use virtio::VirtioDevice;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+
+    const QUEUE_SIZE: u16 = 16;
+    const DESC_ADDR: u64 = 0x0;
+    const AVAIL_ADDR: u64 = 0x1000;
+    const USED_ADDR: u64 = 0x2000;
+    const DATA_ADDR: u64 = 0x4000;
+    /// Descriptor flag marking a buffer the device writes.
+    const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+    /// A guest driving the device's request queue.
+    struct TestGuest {
+        memory: GuestMemory,
+        event: Event,
+        used: mpsc::UnboundedReceiver<()>,
+        avail_idx: u16,
+    }
+
+    impl TestGuest {
+        fn new(device: &mut Device, memory: GuestMemory) -> Self {
+            let event = Event::new();
+            let (send, used) = mpsc::unbounded();
+            device.enable(Resources {
+                features: 0,
+                queues: vec![QueueResources {
+                    params: QueueParams {
+                        size: QUEUE_SIZE,
+                        enable: true,
+                        desc_addr: DESC_ADDR,
+                        avail_addr: AVAIL_ADDR,
+                        used_addr: USED_ADDR,
+                    },
+                    notify: Interrupt::from_fn(move || {
+                        let _ = send.unbounded_send(());
+                    }),
+                    event: event.clone(),
+                }],
+                config_changed: Interrupt::null(),
+                shared_memory_region: None,
+                shared_memory_size: 0,
+            });
+            Self {
+                memory,
+                event,
+                used,
+                avail_idx: 0,
+            }
+        }
+
+        /// Submits a request for `len` bytes and returns the number of bytes
+        /// the device supplied.
+        async fn request(&mut self, len: u32) -> u32 {
+            let desc = self.avail_idx % QUEUE_SIZE;
+            let desc_addr = DESC_ADDR + desc as u64 * 16;
+            self.memory.write_plain(desc_addr, &DATA_ADDR).unwrap();
+            self.memory.write_plain(desc_addr + 8, &len).unwrap();
+            self.memory
+                .write_plain(desc_addr + 12, &VIRTQ_DESC_F_WRITE)
+                .unwrap();
+            self.memory
+                .write_plain(AVAIL_ADDR + 4 + desc as u64 * 2, &desc)
+                .unwrap();
+            self.avail_idx += 1;
+            self.memory
+                .write_plain(AVAIL_ADDR + 2, &self.avail_idx)
+                .unwrap();
+            self.event.signal();
+
+            self.used.next().await.unwrap();
+            let used_idx: u16 = self.memory.read_plain(USED_ADDR + 2).unwrap();
+            assert_eq!(used_idx, self.avail_idx);
+            let elem = USED_ADDR + 4 + desc as u64 * 8;
+            let id: u32 = self.memory.read_plain(elem).unwrap();
+            assert_eq!(id, desc.into());
+            self.memory.read_plain(elem + 4).unwrap()
+        }
+    }
+
+    fn new_device(driver: &DefaultDriver, memory: &GuestMemory, rate_limit: Option<u64>) -> Device {
+        Device::new(
+            &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+            memory.clone(),
+            rate_limit,
+        )
+    }
+
+    #[async_test]
+    async fn fill_buffer(driver: DefaultDriver) {
+        let memory = GuestMemory::allocate(0x10000);
+        let mut device = new_device(&driver, &memory, None);
+        let mut guest = TestGuest::new(&mut device, memory.clone());
+
+        assert_eq!(guest.request(256).await, 256);
+        let mut data = [0; 256];
+        memory.read_at(DATA_ADDR, &mut data).unwrap();
+        assert_ne!(data, [0; 256]);
+
+        device.disable();
+    }
+
+    #[async_test]
+    async fn rate_limit(driver: DefaultDriver) {
+        let memory = GuestMemory::allocate(0x10000);
+        let clock = Arc::new(MockClock::default());
+        let mut device = new_device(&driver, &memory, Some(1000));
+        device.clock = clock.clone();
+        let mut guest = TestGuest::new(&mut device, memory);
+
+        // A full second's worth of bytes is available immediately.
+        assert_eq!(guest.request(600).await, 600);
+        assert_eq!(guest.request(600).await, 400);
+        assert_eq!(clock.now().as_nanos(), 0);
+
+        // Then requests wait for the bucket to refill, and are shortened to
+        // what it holds.
+        assert_eq!(guest.request(100).await, 100);
+        assert_eq!(
+            clock.now().as_nanos(),
+            Duration::from_millis(100).as_nanos() as u64
+        );
+        assert_eq!(guest.request(5000).await, 1000);
+        assert_eq!(
+            clock.now().as_nanos(),
+            Duration::from_millis(1100).as_nanos() as u64
+        );
+
+        device.disable();
+    }
+}
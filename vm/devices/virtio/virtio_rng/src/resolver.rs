@@ -0,0 +1,39 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-rng devices.
+
+use crate::Device;
+use std::convert::Infallible;
+use virtio::resolve::VirtioResolveInput;
+use virtio::VirtioDevice;
+use virtio_resources::rng::VirtioRngHandle;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+use vm_resource::ResolveResource;
+
+/// Resolver for virtio-rng devices.
+pub struct VirtioRngResolver;
+
+declare_static_resolver! {
+    VirtioRngResolver,
+    (VirtioDeviceHandle, VirtioRngHandle),
+}
+
+impl ResolveResource<VirtioDeviceHandle, VirtioRngHandle> for VirtioRngResolver {
+    type Output = Box<dyn VirtioDevice>;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        resource: VirtioRngHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let device = Device::new(
+            input.driver_source,
+            input.guest_memory.clone(),
+            resource.rate_limit,
+        );
+        Ok(Box::new(device))
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio-vsock device that relays guest stream connections to Unix sockets.
+//!
+//! This supports the [hybrid vsock connection model][1] established by
+//! Firecracker, using the same path naming as the VMBus hvsocket relay. A guest
+//! connection to host port `N` is relayed to the Unix socket at `<path>_N`. A
+//! host process connects to guest port `N` by connecting to the Unix socket at
+//! `<path>` and writing `CONNECT N\n`; once the guest accepts, the device
+//! replies with `OK <host port>\n`.
+//!
+//! [1]: <https://github.com/firecracker-microvm/firecracker/blob/7b2e87dc65fc45162303e5708b83c379cf1b0426/docs/vsock.md>
+
+#![forbid(unsafe_code)]
+
+mod relay;
+pub mod resolver;
+mod spec;
+
+use guestmem::GuestMemory;
+use pal_async::socket::PolledSocket;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::wait::PolledWait;
+use relay::Relay;
+use relay::RelayMessage;
+use relay::RelayQueues;
+use std::path::PathBuf;
+use unix_socket::UnixListener;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+pub struct Device {
+    driver: VmTaskDriver,
+    memory: GuestMemory,
+    guest_cid: u64,
+    relay_send: mesh::Sender<RelayMessage>,
+    _relay_task: Task<()>,
+}
+
+impl Device {
+    /// Creates a device for a guest with context ID `guest_cid`, relaying
+    /// connections to sockets named after `base_path`. `listener` must be
+    /// bound at `base_path`.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        guest_cid: u64,
+        base_path: PathBuf,
+        listener: UnixListener,
+    ) -> anyhow::Result<Self> {
+        let driver = driver_source.simple();
+        let listener = PolledSocket::new(&driver, listener)?;
+        let (relay_send, relay_recv) = mesh::channel();
+        let relay = Relay::new(
+            driver.clone(),
+            memory.clone(),
+            guest_cid,
+            base_path,
+            listener,
+        );
+        let relay_task = driver.spawn("virtio-vsock-relay", relay.run(relay_recv));
+        Ok(Self {
+            driver,
+            memory,
+            guest_cid,
+            relay_send,
+            _relay_task: relay_task,
+        })
+    }
+
+    fn new_queue(&self, features: u64, resources: QueueResources) -> Option<VirtioQueue> {
+        if !resources.params.enable {
+            return None;
+        }
+        let queue_event = PolledWait::new(&self.driver, resources.event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    err = err as &dyn std::error::Error,
+                    "Failed creating queue event"
+                )
+            })
+            .ok()?;
+        VirtioQueue::new(
+            features,
+            resources.params,
+            self.memory.clone(),
+            resources.notify,
+            queue_event,
+        )
+        .inspect_err(|err| {
+            tracing::error!(
+                err = err as &dyn std::error::Error,
+                "Failed creating virtio vsock queue"
+            )
+        })
+        .ok()
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: spec::VIRTIO_VSOCK_DEVICE_ID,
+            device_features: 0,
+            // The rx, tx, and event queues.
+            max_queues: 3,
+            device_register_length: size_of::<u64>() as u32,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        match offset {
+            0 => self.guest_cid as u32,
+            4 => (self.guest_cid >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        let mut queues = resources.queues.into_iter();
+        let rx = queues
+            .next()
+            .and_then(|q| self.new_queue(resources.features, q));
+        let tx = queues
+            .next()
+            .and_then(|q| self.new_queue(resources.features, q));
+        // The event queue is only used to report transport resets, which
+        // happen only on migration.
+        let (Some(rx), Some(tx)) = (rx, tx) else {
+            tracing::error!("virtio vsock rx and tx queues not enabled");
+            return;
+        };
+        self.relay_send
+            .send(RelayMessage::Enable(RelayQueues { rx, tx }));
+    }
+
+    fn disable(&mut self) {
+        self.relay_send.send(RelayMessage::Disable);
+    }
+}
@@ -0,0 +1,811 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The relay between guest vsock connections and host Unix sockets.
+
+use crate::spec::Header;
+use crate::spec::Op;
+use crate::spec::ShutdownFlags;
+use crate::spec::SocketType;
+use crate::spec::VMADDR_CID_HOST;
+use anyhow::Context as _;
+use futures::future::BoxFuture;
+use futures::future::Either;
+use futures::stream::FuturesUnordered;
+use futures::AsyncRead;
+use futures::AsyncWrite;
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use mesh::CancelContext;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use pal_async::timer::PolledTimer;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use unix_socket::UnixListener;
+use unix_socket::UnixStream;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use vmbus_server::hvsock::read_hybrid_vsock_connect;
+use vmbus_server::hvsock::vsock_port;
+use vmcore::vm_task::VmTaskDriver;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+
+/// The receive buffer space advertised to the guest for each connection.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// The largest data packet sent to or accepted from the guest.
+const MAX_PACKET_DATA: usize = 64 * 1024;
+/// Tell the guest about newly freed buffer space once its view of the free
+/// space falls below this.
+const CREDIT_UPDATE_THRESHOLD: u32 = 64 * 1024;
+/// The first host port used for connections initiated by the host.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+/// How long to wait for a host process's connect request, and for the guest
+/// to accept the connection, as for Hyper-V sockets.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) enum RelayMessage {
+    Enable(RelayQueues),
+    Disable,
+}
+
+pub(crate) struct RelayQueues {
+    pub rx: VirtioQueue,
+    pub tx: VirtioQueue,
+}
+
+/// Relays connections for the lifetime of the device, across resets.
+pub(crate) struct Relay {
+    driver: VmTaskDriver,
+    memory: GuestMemory,
+    guest_cid: u64,
+    base_path: PathBuf,
+    listener: PolledSocket<UnixListener>,
+}
+
+impl Relay {
+    pub fn new(
+        driver: VmTaskDriver,
+        memory: GuestMemory,
+        guest_cid: u64,
+        base_path: PathBuf,
+        listener: PolledSocket<UnixListener>,
+    ) -> Self {
+        Self {
+            driver,
+            memory,
+            guest_cid,
+            base_path,
+            listener,
+        }
+    }
+
+    pub async fn run(mut self, mut recv: mesh::Receiver<RelayMessage>) {
+        let mut queues = None;
+        loop {
+            // Connections only last as long as the device is enabled.
+            let message = if let Some(queues) = queues.take() {
+                let session = Session {
+                    driver: self.driver.clone(),
+                    memory: self.memory.clone(),
+                    guest_cid: self.guest_cid,
+                    base_path: self.base_path.clone(),
+                    queues,
+                    rx_work: None,
+                    connections: BTreeMap::new(),
+                    control: VecDeque::new(),
+                    pending: FuturesUnordered::new(),
+                    next_host_port: FIRST_HOST_PORT,
+                    last_read: ConnKey::default(),
+                };
+                let run = pin!(session.run(&mut self.listener));
+                match futures::future::select(recv.next(), run).await {
+                    Either::Left((message, _)) => message,
+                    Either::Right(((), _)) => recv.next().await,
+                }
+            } else {
+                recv.next().await
+            };
+            match message {
+                Some(RelayMessage::Enable(new_queues)) => queues = Some(new_queues),
+                Some(RelayMessage::Disable) => {}
+                None => break,
+            }
+        }
+    }
+}
+
+/// Identifies a connection by its ports.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct ConnKey {
+    guest_port: u32,
+    host_port: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ConnState {
+    /// The host requested the connection and is waiting for the guest to
+    /// accept it.
+    Requested,
+    Connected,
+}
+
+struct Connection {
+    socket: PolledSocket<UnixStream>,
+    state: ConnState,
+    /// Data waiting to be written to the socket.
+    tx_buf: VecDeque<u8>,
+    /// The number of bytes at the front of `tx_buf` that are the reply to the
+    /// host's connect request rather than guest data.
+    reply_len: usize,
+    /// The guest data written to the socket, in bytes.
+    fwd_cnt: u32,
+    /// `fwd_cnt` as last sent to the guest.
+    sent_fwd_cnt: u32,
+    credit_update_queued: bool,
+    /// The data sent to the guest, in bytes.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    guest_shutdown: ShutdownFlags,
+    /// The socket has been read to the end, and the guest told so.
+    host_eof: bool,
+    write_closed: bool,
+}
+
+impl Connection {
+    fn new(socket: PolledSocket<UnixStream>, state: ConnState, header: Option<&Header>) -> Self {
+        Self {
+            socket,
+            state,
+            tx_buf: VecDeque::new(),
+            reply_len: 0,
+            fwd_cnt: 0,
+            sent_fwd_cnt: 0,
+            credit_update_queued: false,
+            tx_cnt: 0,
+            peer_buf_alloc: header.map_or(0, |h| h.buf_alloc.get()),
+            peer_fwd_cnt: header.map_or(0, |h| h.fwd_cnt.get()),
+            guest_shutdown: ShutdownFlags::new(),
+            host_eof: false,
+            write_closed: false,
+        }
+    }
+
+    /// The guest data received but not yet written to the socket, in bytes.
+    fn buffered(&self) -> usize {
+        self.tx_buf.len() - self.reply_len
+    }
+
+    /// The number of bytes that the guest can currently receive.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn can_read(&self) -> bool {
+        self.state == ConnState::Connected
+            && !self.host_eof
+            && !self.guest_shutdown.receive()
+            && self.peer_credit() > 0
+    }
+
+    /// Returns whether the guest should be told about freed buffer space
+    /// before it runs out.
+    fn needs_credit_update(&self) -> bool {
+        let used = (self.fwd_cnt.wrapping_sub(self.sent_fwd_cnt) as usize + self.buffered())
+            .try_into()
+            .unwrap_or(u32::MAX);
+        !self.credit_update_queued
+            && self.fwd_cnt != self.sent_fwd_cnt
+            && BUF_ALLOC.saturating_sub(used) < CREDIT_UPDATE_THRESHOLD
+    }
+
+    /// Writes buffered data to the socket, and shuts down the socket once the
+    /// guest will send no more.
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            let (buf, _) = self.tx_buf.as_slices();
+            match Pin::new(&mut self.socket).poll_write(cx, buf) {
+                Poll::Ready(Ok(0)) => return Err(io::ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(n)) => {
+                    self.tx_buf.drain(..n);
+                    let reply = n.min(self.reply_len);
+                    self.reply_len -= reply;
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add((n - reply) as u32);
+                }
+                Poll::Ready(Err(err)) => return Err(err),
+                Poll::Pending => break,
+            }
+        }
+        if self.tx_buf.is_empty() && self.guest_shutdown.send() && !self.write_closed {
+            self.socket.get().shutdown(std::net::Shutdown::Write)?;
+            self.write_closed = true;
+        }
+        Ok(())
+    }
+}
+
+/// A packet to send to the guest without data.
+struct Control {
+    key: ConnKey,
+    op: Op,
+    flags: u32,
+}
+
+impl Control {
+    fn new(key: ConnKey, op: Op) -> Self {
+        Self { key, op, flags: 0 }
+    }
+}
+
+/// An asynchronous connection setup step.
+enum Pending {
+    /// A host process connected to the listener and asked to connect to a
+    /// guest port.
+    HostConnect(anyhow::Result<(PolledSocket<UnixStream>, u32)>),
+    /// A connection to a host Unix socket, as requested by the guest, has
+    /// completed.
+    GuestConnect {
+        key: ConnKey,
+        header: Header,
+        result: io::Result<PolledSocket<UnixStream>>,
+    },
+    /// The guest has had [`CONNECT_TIMEOUT`] to accept a host connection.
+    ConnectTimeout(ConnKey),
+}
+
+/// The relay state while the device is enabled.
+struct Session {
+    driver: VmTaskDriver,
+    memory: GuestMemory,
+    guest_cid: u64,
+    base_path: PathBuf,
+    queues: RelayQueues,
+    /// An rx buffer waiting for a packet.
+    rx_work: Option<VirtioQueueCallbackWork>,
+    connections: BTreeMap<ConnKey, Connection>,
+    /// Packets waiting for rx buffers.
+    control: VecDeque<Control>,
+    pending: FuturesUnordered<BoxFuture<'static, Pending>>,
+    next_host_port: u32,
+    /// The connection that data was last sent to the guest for, so that
+    /// connections take turns.
+    last_read: ConnKey,
+}
+
+impl Session {
+    async fn run(mut self, listener: &mut PolledSocket<UnixListener>) {
+        let err = poll_fn(|cx| self.poll(cx, listener)).await;
+        tracing::error!(
+            error = &err as &dyn std::error::Error,
+            "virtio-vsock queue failure"
+        );
+    }
+
+    /// Makes as much progress as possible, returning only on queue failure.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        listener: &mut PolledSocket<UnixListener>,
+    ) -> Poll<io::Error> {
+        while let Poll::Ready(item) = self.queues.tx.poll_next_unpin(cx) {
+            match item.expect("queue never ends") {
+                Ok(work) => self.handle_tx(work),
+                Err(err) => return Poll::Ready(err),
+            }
+        }
+
+        while let Poll::Ready(r) = listener.poll_accept(cx) {
+            match r {
+                Ok((socket, _)) => {
+                    let driver = self.driver.clone();
+                    self.pending.push(Box::pin(async move {
+                        Pending::HostConnect(read_connect(&driver, socket).await)
+                    }));
+                }
+                Err(err) => {
+                    tracelimit::warn_ratelimited!(
+                        error = &err as &dyn std::error::Error,
+                        "failed to accept hybrid vsock connection"
+                    );
+                    break;
+                }
+            }
+        }
+
+        while let Poll::Ready(Some(pending)) = self.pending.poll_next_unpin(cx) {
+            self.handle_pending(pending);
+        }
+
+        let control = &mut self.control;
+        self.connections.retain(|&key, conn| {
+            if let Err(err) = conn.poll_flush(cx) {
+                tracing::debug!(
+                    ?key,
+                    error = &err as &dyn std::error::Error,
+                    "failed to write to host socket"
+                );
+                control.push_back(Control::new(key, Op::RST));
+                return false;
+            }
+            if conn.guest_shutdown.send() && conn.guest_shutdown.receive() && conn.tx_buf.is_empty()
+            {
+                // The guest has closed the connection.
+                control.push_back(Control::new(key, Op::RST));
+                return false;
+            }
+            if conn.needs_credit_update() {
+                conn.credit_update_queued = true;
+                control.push_back(Control::new(key, Op::CREDIT_UPDATE));
+            }
+            true
+        });
+
+        loop {
+            if self.rx_work.is_none() {
+                if self.control.is_empty() && !self.connections.values().any(|c| c.can_read()) {
+                    break;
+                }
+                match self.queues.rx.poll_next_unpin(cx) {
+                    Poll::Ready(item) => match item.expect("queue never ends") {
+                        Ok(work) => self.rx_work = Some(work),
+                        Err(err) => return Poll::Ready(err),
+                    },
+                    Poll::Pending => break,
+                }
+            }
+            if let Some(control) = self.control.pop_front() {
+                self.send(control.key, control.op, control.flags, &[]);
+            } else if !self.poll_read(cx) {
+                break;
+            }
+        }
+
+        Poll::Pending
+    }
+
+    fn handle_tx(&mut self, mut work: VirtioQueueCallbackWork) {
+        let len = work.get_payload_length(false) as usize;
+        if len < size_of::<Header>() || len > size_of::<Header>() + MAX_PACKET_DATA {
+            tracelimit::warn_ratelimited!(len, "invalid virtio-vsock packet length");
+            return;
+        }
+        let mut buf = vec![0; len];
+        if let Err(err) = work.read(&self.memory, &mut buf) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to read virtio-vsock packet"
+            );
+            return;
+        }
+        work.complete(0);
+
+        let (header, data) = buf.split_at(size_of::<Header>());
+        let header = Header::read_from(header).unwrap();
+        let Some(data) = data.get(..header.len.get() as usize) else {
+            tracelimit::warn_ratelimited!(
+                len = header.len.get(),
+                "virtio-vsock packet data length too large"
+            );
+            return;
+        };
+        self.handle_packet(&header, data);
+    }
+
+    fn handle_packet(&mut self, header: &Header, data: &[u8]) {
+        let key = ConnKey {
+            guest_port: header.src_port.get(),
+            host_port: header.dst_port.get(),
+        };
+        let op = Op(header.op.get());
+        if header.src_cid.get() != self.guest_cid
+            || header.dst_cid.get() != VMADDR_CID_HOST
+            || SocketType(header.socket_type.get()) != SocketType::STREAM
+        {
+            tracelimit::warn_ratelimited!(?header, "unsupported virtio-vsock packet");
+            if op != Op::RST {
+                self.control.push_back(Control::new(key, Op::RST));
+            }
+            return;
+        }
+
+        let Some(conn) = self.connections.get_mut(&key) else {
+            match op {
+                Op::REQUEST => self.connect_to_host(key, header),
+                Op::RST => {}
+                _ => self.control.push_back(Control::new(key, Op::RST)),
+            }
+            return;
+        };
+
+        conn.peer_buf_alloc = header.buf_alloc.get();
+        conn.peer_fwd_cnt = header.fwd_cnt.get();
+        let reset = match (conn.state, op) {
+            (ConnState::Requested, Op::RESPONSE) => {
+                conn.state = ConnState::Connected;
+                let reply = format!("OK {}\n", key.host_port);
+                conn.reply_len = reply.len();
+                conn.tx_buf.extend(reply.as_bytes());
+                false
+            }
+            (ConnState::Connected, Op::RW) => {
+                // The guest must not send more than the advertised buffer
+                // space.
+                if conn.guest_shutdown.send() || conn.buffered() + data.len() > BUF_ALLOC as usize {
+                    true
+                } else {
+                    conn.tx_buf.extend(data);
+                    false
+                }
+            }
+            (ConnState::Connected, Op::SHUTDOWN) => {
+                conn.guest_shutdown =
+                    ShutdownFlags::from(u32::from(conn.guest_shutdown) | header.flags.get());
+                false
+            }
+            (ConnState::Connected, Op::CREDIT_REQUEST) => {
+                self.control.push_back(Control::new(key, Op::CREDIT_UPDATE));
+                false
+            }
+            (ConnState::Connected, Op::CREDIT_UPDATE) => false,
+            (_, Op::RST) => {
+                self.connections.remove(&key);
+                return;
+            }
+            _ => true,
+        };
+        if reset {
+            tracelimit::warn_ratelimited!(?key, ?op, "unexpected virtio-vsock packet");
+            self.connections.remove(&key);
+            self.control.push_back(Control::new(key, Op::RST));
+        }
+    }
+
+    /// Relays a guest connection request to the Unix socket for the port.
+    fn connect_to_host(&mut self, key: ConnKey, header: &Header) {
+        let mut path = self.base_path.clone().into_os_string();
+        path.push(format!("_{}", key.host_port));
+        let driver = self.driver.clone();
+        let header = *header;
+        self.pending.push(Box::pin(async move {
+            let result = PolledSocket::connect_unix(&driver, path).await;
+            Pending::GuestConnect {
+                key,
+                header,
+                result,
+            }
+        }));
+    }
+
+    fn handle_pending(&mut self, pending: Pending) {
+        match pending {
+            Pending::HostConnect(Ok((socket, guest_port))) => {
+                let key = ConnKey {
+                    guest_port,
+                    host_port: self.allocate_host_port(guest_port),
+                };
+                tracing::debug!(?key, "relaying host connection to guest");
+                self.connections
+                    .insert(key, Connection::new(socket, ConnState::Requested, None));
+                self.control.push_back(Control::new(key, Op::REQUEST));
+                let driver = self.driver.clone();
+                self.pending.push(Box::pin(async move {
+                    PolledTimer::new(&driver).sleep(CONNECT_TIMEOUT).await;
+                    Pending::ConnectTimeout(key)
+                }));
+            }
+            Pending::HostConnect(Err(err)) => {
+                tracelimit::warn_ratelimited!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "failed to read hybrid vsock connect request"
+                );
+            }
+            Pending::GuestConnect {
+                key,
+                header,
+                result,
+            } => match result {
+                Ok(socket) => {
+                    // The guest may have given up and retried in the meantime.
+                    if !self.connections.contains_key(&key) {
+                        tracing::debug!(?key, "relaying guest connection to host");
+                        self.connections.insert(
+                            key,
+                            Connection::new(socket, ConnState::Connected, Some(&header)),
+                        );
+                        self.control.push_back(Control::new(key, Op::RESPONSE));
+                    }
+                }
+                Err(err) => {
+                    tracing::debug!(
+                        ?key,
+                        error = &err as &dyn std::error::Error,
+                        "failed to connect to host socket"
+                    );
+                    self.control.push_back(Control::new(key, Op::RST));
+                }
+            },
+            Pending::ConnectTimeout(key) => {
+                if self
+                    .connections
+                    .get(&key)
+                    .is_some_and(|conn| conn.state == ConnState::Requested)
+                {
+                    tracing::debug!(?key, "guest did not accept host connection");
+                    self.connections.remove(&key);
+                    self.control.push_back(Control::new(key, Op::RST));
+                }
+            }
+        }
+    }
+
+    fn allocate_host_port(&mut self, guest_port: u32) -> u32 {
+        loop {
+            let host_port = self.next_host_port;
+            self.next_host_port = host_port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            if !self.connections.contains_key(&ConnKey {
+                guest_port,
+                host_port,
+            }) {
+                break host_port;
+            }
+        }
+    }
+
+    /// Reads data for the guest from the next readable connection. Returns
+    /// false if no connection is readable.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> bool {
+        let capacity = (self.rx_work.as_ref().unwrap().get_payload_length(true) as usize)
+            .saturating_sub(size_of::<Header>())
+            .min(MAX_PACKET_DATA);
+        if capacity == 0 {
+            tracelimit::warn_ratelimited!("virtio-vsock rx buffer too small");
+            self.rx_work = None;
+            return true;
+        }
+
+        let mut buf = vec![0; capacity];
+        let last_read = self.last_read;
+        let after = self
+            .connections
+            .range_mut((Bound::Excluded(last_read), Bound::Unbounded));
+        let Some((key, result)) = poll_read_any(cx, after, &mut buf)
+            .or_else(|| poll_read_any(cx, self.connections.range_mut(..=last_read), &mut buf))
+        else {
+            return false;
+        };
+
+        match result {
+            Ok(0) => {
+                self.connections.get_mut(&key).unwrap().host_eof = true;
+                self.control.push_back(Control {
+                    key,
+                    op: Op::SHUTDOWN,
+                    flags: ShutdownFlags::new().with_send(true).into(),
+                });
+            }
+            Ok(n) => {
+                let conn = self.connections.get_mut(&key).unwrap();
+                conn.tx_cnt = conn.tx_cnt.wrapping_add(n as u32);
+                self.last_read = key;
+                self.send(key, Op::RW, 0, &buf[..n]);
+            }
+            Err(err) => {
+                tracing::debug!(
+                    ?key,
+                    error = &err as &dyn std::error::Error,
+                    "failed to read from host socket"
+                );
+                self.connections.remove(&key);
+                self.control.push_back(Control::new(key, Op::RST));
+            }
+        }
+        true
+    }
+
+    /// Sends a packet to the guest in the held rx buffer.
+    fn send(&mut self, key: ConnKey, op: Op, flags: u32, data: &[u8]) {
+        let mut work = self.rx_work.take().expect("rx buffer available");
+        let mut header = Header {
+            src_cid: VMADDR_CID_HOST.into(),
+            dst_cid: self.guest_cid.into(),
+            src_port: key.host_port.into(),
+            dst_port: key.guest_port.into(),
+            len: (data.len() as u32).into(),
+            socket_type: SocketType::STREAM.0.into(),
+            op: op.0.into(),
+            flags: flags.into(),
+            buf_alloc: 0.into(),
+            fwd_cnt: 0.into(),
+        };
+        if let Some(conn) = self.connections.get_mut(&key) {
+            header.buf_alloc = BUF_ALLOC.into();
+            header.fwd_cnt = conn.fwd_cnt.into();
+            conn.sent_fwd_cnt = conn.fwd_cnt;
+            if op == Op::CREDIT_UPDATE {
+                conn.credit_update_queued = false;
+            }
+        }
+        let r = work
+            .write(&self.memory, header.as_bytes())
+            .and_then(|()| work.write_at_offset(size_of::<Header>() as u64, &self.memory, data));
+        match r {
+            Ok(()) => work.complete((size_of::<Header>() + data.len()) as u32),
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to write virtio-vsock packet"
+                );
+                // The packet is lost, and any data in it has already been
+                // read from the socket, so the stream cannot continue.
+                if op != Op::RST && self.connections.remove(&key).is_some() {
+                    self.control.push_back(Control::new(key, Op::RST));
+                }
+            }
+        }
+    }
+}
+
+/// Polls each readable connection for data in turn, returning the first result.
+fn poll_read_any<'a>(
+    cx: &mut Context<'_>,
+    connections: impl Iterator<Item = (&'a ConnKey, &'a mut Connection)>,
+    buf: &mut [u8],
+) -> Option<(ConnKey, io::Result<usize>)> {
+    for (&key, conn) in connections {
+        if !conn.can_read() {
+            continue;
+        }
+        let len = buf.len().min(conn.peer_credit() as usize);
+        if let Poll::Ready(r) = Pin::new(&mut conn.socket).poll_read(cx, &mut buf[..len]) {
+            return Some((key, r));
+        }
+    }
+    None
+}
+
+/// Reads a hybrid vsock connect request for a vsock port from a host process.
+async fn read_connect(
+    driver: &(impl ?Sized + Driver),
+    socket: UnixStream,
+) -> anyhow::Result<(PolledSocket<UnixStream>, u32)> {
+    let mut socket = PolledSocket::new(driver, socket)?;
+    let (service_id, _) = CancelContext::new()
+        .with_timeout(CONNECT_TIMEOUT)
+        .until_cancelled(read_hybrid_vsock_connect(&mut socket))
+        .await
+        .context("timed out waiting for connect request")??;
+    let port = vsock_port(&service_id)
+        .with_context(|| format!("connect request for {service_id} is not for a vsock port"))?;
+    Ok((socket, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_connect;
+    use super::ConnState;
+    use super::Connection;
+    use super::BUF_ALLOC;
+    use crate::spec::Header;
+    use crate::spec::ShutdownFlags;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use pal_async::async_test;
+    use pal_async::socket::PolledSocket;
+    use pal_async::DefaultDriver;
+    use std::future::poll_fn;
+    use std::task::Poll;
+    use unix_socket::UnixStream;
+    use zerocopy::FromZeroes;
+
+    async fn connect_request(driver: &DefaultDriver, request: &[u8]) -> anyhow::Result<u32> {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = PolledSocket::new(driver, client).unwrap();
+        client.write_all(request).await.unwrap();
+        drop(client);
+        let (_, port) = read_connect(driver, server).await?;
+        Ok(port)
+    }
+
+    #[async_test]
+    async fn connect_requests(driver: DefaultDriver) {
+        assert_eq!(
+            connect_request(&driver, b"CONNECT 1234\n").await.unwrap(),
+            1234
+        );
+        // Hyper-V socket service IDs that embed a vsock port are accepted.
+        assert_eq!(
+            connect_request(&driver, b"CONNECT 000004d2-facb-11e6-bd58-64006a7986d3\n")
+                .await
+                .unwrap(),
+            1234
+        );
+        for request in [
+            &b"CONNECT 00000000-0000-11e6-bd58-64006a7986d3\n"[..],
+            b"CONNECT 1234",
+            b"CONNECT \n",
+            b"LISTEN 1234\n",
+        ] {
+            assert!(connect_request(&driver, request).await.is_err());
+        }
+    }
+
+    #[async_test]
+    async fn connect_request_timeout(driver: DefaultDriver) {
+        let (_client, server) = UnixStream::pair().unwrap();
+        assert!(read_connect(&driver, server).await.is_err());
+    }
+
+    fn connection(
+        driver: &DefaultDriver,
+        buf_alloc: u32,
+    ) -> (Connection, PolledSocket<UnixStream>) {
+        let (host, relay) = UnixStream::pair().unwrap();
+        let mut header = Header::new_zeroed();
+        header.buf_alloc = buf_alloc.into();
+        let conn = Connection::new(
+            PolledSocket::new(driver, relay).unwrap(),
+            ConnState::Connected,
+            Some(&header),
+        );
+        (conn, PolledSocket::new(driver, host).unwrap())
+    }
+
+    #[async_test]
+    async fn credit(driver: DefaultDriver) {
+        let (mut conn, _host) = connection(&driver, 1000);
+        assert_eq!(conn.peer_credit(), 1000);
+        assert!(conn.can_read());
+
+        conn.tx_cnt = 1000;
+        assert_eq!(conn.peer_credit(), 0);
+        assert!(!conn.can_read());
+
+        // The counters wrap.
+        conn.peer_fwd_cnt = u32::MAX - 99;
+        conn.tx_cnt = 100;
+        assert_eq!(conn.peer_credit(), 800);
+
+        // The guest is told about freed space once it is running low.
+        conn.fwd_cnt = BUF_ALLOC / 2;
+        assert!(!conn.needs_credit_update());
+        conn.fwd_cnt = BUF_ALLOC - 1;
+        assert!(conn.needs_credit_update());
+        conn.credit_update_queued = true;
+        assert!(!conn.needs_credit_update());
+    }
+
+    #[async_test]
+    async fn flush(driver: DefaultDriver) {
+        let (mut conn, mut host) = connection(&driver, 1000);
+        conn.reply_len = 5;
+        conn.tx_buf.extend(b"OK 5\nhello");
+        poll_fn(|cx| Poll::Ready(conn.poll_flush(cx)))
+            .await
+            .unwrap();
+        // Only guest data counts toward the guest's credit.
+        assert_eq!(conn.fwd_cnt, 5);
+        assert_eq!(conn.buffered(), 0);
+
+        // The socket is shut down once the guest will send no more.
+        conn.guest_shutdown = ShutdownFlags::new().with_send(true);
+        poll_fn(|cx| Poll::Ready(conn.poll_flush(cx)))
+            .await
+            .unwrap();
+        assert!(conn.write_closed);
+        let mut data = Vec::new();
+        host.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"OK 5\nhello");
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Definitions from the virtio-vsock specification.
+
+use bitfield_struct::bitfield;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+use zerocopy::FromZeroes;
+use zerocopy::LE;
+use zerocopy::U16;
+use zerocopy::U32;
+use zerocopy::U64;
+
+pub const VIRTIO_VSOCK_DEVICE_ID: u16 = 19;
+
+/// The well-known context ID of the host.
+pub const VMADDR_CID_HOST: u64 = 2;
+
+/// The header preceding each packet on the rx and tx queues.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct Header {
+    pub src_cid: U64<LE>,
+    pub dst_cid: U64<LE>,
+    pub src_port: U32<LE>,
+    pub dst_port: U32<LE>,
+    pub len: U32<LE>,
+    pub socket_type: U16<LE>,
+    pub op: U16<LE>,
+    pub flags: U32<LE>,
+    pub buf_alloc: U32<LE>,
+    pub fwd_cnt: U32<LE>,
+}
+
+open_enum::open_enum! {
+    pub enum SocketType: u16 {
+        STREAM = 1,
+        SEQPACKET = 2,
+    }
+}
+
+open_enum::open_enum! {
+    pub enum Op: u16 {
+        INVALID = 0,
+        REQUEST = 1,
+        RESPONSE = 2,
+        RST = 3,
+        SHUTDOWN = 4,
+        RW = 5,
+        CREDIT_UPDATE = 6,
+        CREDIT_REQUEST = 7,
+    }
+}
+
+/// The flags of an [`Op::SHUTDOWN`] packet.
+#[bitfield(u32)]
+pub struct ShutdownFlags {
+    /// The sender will receive no more data.
+    pub receive: bool,
+    /// The sender will send no more data.
+    pub send: bool,
+    #[bits(30)]
+    _reserved: u32,
+}
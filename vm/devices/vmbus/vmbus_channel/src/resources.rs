@@ -7,6 +7,7 @@
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_resource::CanResolveTo;
 use vmcore::vm_task::VmTaskDriverSource;
+use vmcore::vmtime::VmTimeSource;
 
 impl CanResolveTo<ResolvedVmbusDevice> for VmbusDeviceHandleKind {
     type Input<'a> = ResolveVmbusDeviceHandleParams<'a>;
@@ -16,6 +17,8 @@ impl CanResolveTo<ResolvedVmbusDevice> for VmbusDeviceHandleKind {
 pub struct ResolveVmbusDeviceHandleParams<'a> {
     /// The driver source to use for spawning tasks and IO.
     pub driver_source: &'a VmTaskDriverSource,
+    /// The VM time source.
+    pub vmtime: &'a VmTimeSource,
 }
 
 /// A resolved vmbus device.
@@ -242,13 +242,20 @@ async fn spawn_relay(&self, connection: UnixStream) -> anyhow::Result<Task<()>>
     }
 }
 
+/// The format of the service ID in a hybrid vsock connect request.
 #[derive(Debug)]
-enum ServiceIdFormat {
+pub enum ServiceIdFormat {
+    /// A vsock port, `CONNECT <port>`.
     Vsock,
+    /// A Hyper-V socket service ID, `CONNECT <guid>`.
     HyperV,
 }
 
-async fn read_hybrid_vsock_connect(
+/// Reads a hybrid vsock connect request, `CONNECT <port>\n` or
+/// `CONNECT <service ID>\n`, from a host process.
+///
+/// Vsock ports are returned embedded in a service ID, see [`vsock_port`].
+pub async fn read_hybrid_vsock_connect(
     socket: &mut PolledSocket<UnixStream>,
 ) -> anyhow::Result<(Guid, ServiceIdFormat)> {
     let mut buf = [0; "CONNECT 00000000-facb-11e6-bd58-64006a7986d3\n".len()];
@@ -314,7 +321,9 @@ fn drop(&mut self) {
 // AF_HYPERV service ID.
 static VSOCK_TEMPLATE: Guid = Guid::from_static_str("00000000-facb-11e6-bd58-64006a7986d3");
 
-fn vsock_port(service_id: &Guid) -> Option<u32> {
+/// Returns the vsock port embedded in a Hyper-V socket service ID, if it is
+/// one.
+pub fn vsock_port(service_id: &Guid) -> Option<u32> {
     let stripped_id = Guid {
         data1: 0,
         ..*service_id
@@ -2,8 +2,6 @@
 // Licensed under the MIT License.
 
 //! VHD1 file format definitions.
-//!
-//! Currently incomplete (missing defs for non-fixed disks).
 
 #![no_std]
 
@@ -50,17 +48,29 @@ impl VhdFooter {
     pub const FIXED_DATA_OFFSET: u64 = !0;
     pub const CREATOR_VERSION_MAGIC: u32 = 0x000a0000;
     pub const DISK_TYPE_FIXED: u32 = 2;
+    pub const DISK_TYPE_DYNAMIC: u32 = 3;
+    pub const DISK_TYPE_DIFFERENCING: u32 = 4;
 
     pub fn new_fixed(size: u64, guid: Guid) -> Self {
+        Self::new(size, guid, Self::DISK_TYPE_FIXED, Self::FIXED_DATA_OFFSET)
+    }
+
+    /// Returns a footer for a dynamic or differencing disk, whose dynamic
+    /// header is at `header_offset`.
+    pub fn new_dynamic(size: u64, guid: Guid, disk_type: u32, header_offset: u64) -> Self {
+        Self::new(size, guid, disk_type, header_offset)
+    }
+
+    fn new(size: u64, guid: Guid, disk_type: u32, data_offset: u64) -> Self {
         let mut footer = Self {
             cookie: Self::COOKIE_MAGIC,
             features: Self::FEATURE_MASK.into(),
             file_format_version: Self::FILE_FORMAT_VERSION_MAGIC.into(),
-            data_offset: Self::FIXED_DATA_OFFSET.into(),
+            data_offset: data_offset.into(),
             creator_version: Self::CREATOR_VERSION_MAGIC.into(),
             original_size: size.into(),
             current_size: size.into(),
-            disk_type: Self::DISK_TYPE_FIXED.into(),
+            disk_type: disk_type.into(),
             ..FromZeroes::new_zeroed()
         };
 
@@ -79,3 +89,67 @@ pub fn compute_checksum(&self) -> u32 {
                 .sum::<u32>())
     }
 }
+
+/// The header for dynamic and differencing disks, located at the footer's
+/// `data_offset`.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct VhdDynamicHeader {
+    pub cookie: u64_be,
+    pub data_offset: u64_be,
+    pub table_offset: u64_be,
+    pub header_version: u32_be,
+    pub max_table_entries: u32_be,
+    pub block_size: u32_be,
+    pub checksum: u32_be,
+    pub parent_unique_id: Guid,
+    pub parent_time_stamp: u32_be,
+    pub reserved: u32_be,
+    /// The parent's file name, in UTF-16 big endian.
+    pub parent_unicode_name: [u8; 512],
+    pub parent_locators: [VhdParentLocator; 8],
+    pub reserved2: [u8; 256],
+}
+
+impl VhdDynamicHeader {
+    pub const LEN: u64 = 1024;
+
+    pub const COOKIE_MAGIC: u64_be = u64_be::from_bytes(*b"cxsparse");
+    pub const DATA_OFFSET: u64 = !0;
+    pub const HEADER_VERSION: u32 = 0x00010000;
+    pub const DEFAULT_BLOCK_SIZE: u32 = 0x200000;
+
+    pub fn compute_checksum(&self) -> u32 {
+        !(self.as_bytes().iter().map(|b| *b as u32).sum::<u32>()
+            - self
+                .checksum
+                .as_bytes()
+                .iter()
+                .map(|b| *b as u32)
+                .sum::<u32>())
+    }
+}
+
+/// A pointer to the parent of a differencing disk.
+#[repr(C)]
+#[derive(Copy, Clone, AsBytes, FromBytes, FromZeroes)]
+pub struct VhdParentLocator {
+    pub platform_code: u32_be,
+    pub platform_data_space: u32_be,
+    pub platform_data_length: u32_be,
+    pub reserved: u32_be,
+    pub platform_data_offset: u64_be,
+}
+
+impl VhdParentLocator {
+    pub const PLATFORM_CODE_NONE: u32 = 0;
+    /// Windows relative path, in UTF-16 little endian.
+    pub const PLATFORM_CODE_W2RU: u32 = u32::from_be_bytes(*b"W2ru");
+    /// Windows absolute path, in UTF-16 little endian.
+    pub const PLATFORM_CODE_W2KU: u32 = u32::from_be_bytes(*b"W2ku");
+    /// Mac OS X file URL, in UTF-8.
+    pub const PLATFORM_CODE_MACX: u32 = u32::from_be_bytes(*b"MacX");
+}
+
+/// The value of an unallocated block allocation table entry.
+pub const BAT_ENTRY_UNALLOCATED: u32 = !0;
@@ -20,6 +20,7 @@
 pub mod monitor;
 pub mod non_volatile_store;
 pub mod notify;
+pub mod ram_discard;
 pub mod reference_time_source;
 pub mod save_restore;
 pub mod slim_event;
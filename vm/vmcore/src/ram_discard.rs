@@ -0,0 +1,21 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Support for returning the host memory backing guest RAM to the host.
+
+use std::io;
+
+/// Releases the host memory backing ranges of guest RAM.
+///
+/// This is used by devices, such as memory balloons, that learn from the guest
+/// that it is not using some of its RAM. Released RAM reads as zero when the
+/// guest next accesses it.
+pub trait DiscardRam: Send + Sync {
+    /// Returns whether RAM can be released on this host. Devices should not
+    /// offer features that depend on releasing RAM if not.
+    fn is_supported(&self) -> bool;
+
+    /// Releases the host memory backing the page-aligned guest physical
+    /// address range `gpa..gpa + len`.
+    fn discard_ram(&self, gpa: u64, len: u64) -> io::Result<()>;
+}
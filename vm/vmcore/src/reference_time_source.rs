@@ -1,6 +1,9 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use crate::vmtime::VmTimeAccess;
+use crate::vmtime::VmTimeSource;
+
 /// Trait for reference time.
 pub trait ReferenceTimeSource: Send + Sync {
     /// Returns the current time in 100ns units.
@@ -8,3 +11,28 @@ pub trait ReferenceTimeSource: Send + Sync {
     /// Returns if this reference time is backed by TSC.
     fn is_backed_by_tsc(&self) -> bool;
 }
+
+/// A reference time source based on VM time, for use when the reference time
+/// is emulated.
+pub struct VmTimeReferenceTimeSource {
+    vmtime: VmTimeAccess,
+}
+
+impl VmTimeReferenceTimeSource {
+    /// Returns a new reference time source backed by `vmtime`.
+    pub fn new(vmtime: &VmTimeSource) -> Self {
+        Self {
+            vmtime: vmtime.access("reftime"),
+        }
+    }
+}
+
+impl ReferenceTimeSource for VmTimeReferenceTimeSource {
+    fn now_100ns(&self) -> u64 {
+        self.vmtime.now().as_100ns()
+    }
+
+    fn is_backed_by_tsc(&self) -> bool {
+        false
+    }
+}
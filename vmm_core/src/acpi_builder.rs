@@ -14,6 +14,7 @@
 use chipset::ioapic;
 use chipset::psp;
 use inspect::Inspect;
+use memory_range::MemoryRange;
 use std::collections::BTreeMap;
 use vm_topology::memory::MemoryLayout;
 use vm_topology::processor::aarch64::Aarch64Topology;
@@ -56,6 +57,11 @@ pub struct AcpiTablesBuilder<'a, T: AcpiTopology> {
     pub pm_base: u16,
     /// ACPI IRQ number
     pub acpi_irq: u32,
+    /// The ECAM region of the PCIe root complex, if present.
+    ///
+    /// If and only if this is set, then the MCFG table will be generated. The
+    /// region is assumed to decode PCI segment 0, starting at bus 0.
+    pub pcie_ecam: Option<MemoryRange>,
 }
 
 pub const OEM_INFO: acpi::builder::OemInfo = acpi::builder::OemInfo {
@@ -365,6 +371,30 @@ fn with_pptt<F, R>(&self, f: F) -> R
         ))
     }
 
+    fn with_mcfg<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&acpi::builder::Table<'_>) -> R,
+    {
+        use acpi_spec::mcfg;
+
+        let ecam = self.pcie_ecam.expect("pcie ecam region is required");
+        let bus_count = ecam.len() / pci_bus::ECAM_BUS_SIZE;
+        assert!(
+            (1..=256).contains(&bus_count) && ecam.len() % pci_bus::ECAM_BUS_SIZE == 0,
+            "invalid ecam region {ecam}"
+        );
+
+        (f)(&acpi::builder::Table::new_dyn(
+            mcfg::MCFG_REVISION,
+            None,
+            &mcfg::McfgHeader::new(),
+            &[
+                mcfg::McfgSegmentBusRange::new(ecam.start(), 0, 0, (bus_count - 1) as u8)
+                    .as_bytes(),
+            ],
+        ))
+    }
+
     /// Build ACPI tables based on the supplied closure that adds devices to the DSDT.
     ///
     /// The RDSP is assumed to take one whole page.
@@ -532,6 +562,9 @@ fn build_acpi_tables_inner(&self, gpa: u64, dsdt: &[u8]) -> BuiltAcpiTables {
         if self.cache_topology.is_some() {
             self.with_pptt(|t| b.append(t));
         }
+        if self.pcie_ecam.is_some() {
+            self.with_mcfg(|t| b.append(t));
+        }
 
         let (rdsp, tables) = b.build();
 
@@ -558,6 +591,15 @@ pub fn build_srat(&self) -> Vec<u8> {
     pub fn build_pptt(&self) -> Vec<u8> {
         self.with_pptt(|t| t.to_vec(&OEM_INFO))
     }
+
+    /// Helper method to construct an MCFG without constructing the rest of the
+    /// ACPI tables.
+    ///
+    /// # Panics
+    /// Panics if `self.pcie_ecam` is not set.
+    pub fn build_mcfg(&self) -> Vec<u8> {
+        self.with_mcfg(|t| t.to_vec(&OEM_INFO))
+    }
 }
 
 #[cfg(test)]
@@ -598,6 +640,7 @@ fn new_builder<'a>(
             with_psp: false,
             pm_base: 1234,
             acpi_irq: 2,
+            pcie_ecam: None,
         }
     }
 
@@ -641,4 +684,26 @@ fn test_basic_madt_cpu() {
             apic_ids.iter().map(|e| Some(*e)).collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn test_mcfg() {
+        let mem = new_mem();
+        let topology = TopologyBuilder::new_x86().build(1).unwrap();
+        let builder = AcpiTablesBuilder {
+            pcie_ecam: Some(MemoryRange::new(GB + 16 * MB..GB + 32 * MB)),
+            ..new_builder(&mem, &topology)
+        };
+        let mcfg = builder.build_mcfg();
+
+        let header_len = size_of::<acpi_spec::Header>() + size_of::<acpi_spec::mcfg::McfgHeader>();
+        assert_eq!(
+            mcfg.len(),
+            header_len + size_of::<acpi_spec::mcfg::McfgSegmentBusRange>()
+        );
+        assert_eq!(&mcfg[..4], b"MCFG");
+        assert_eq!(
+            &mcfg[header_len..],
+            acpi_spec::mcfg::McfgSegmentBusRange::new(GB + 16 * MB, 0, 0, 15).as_bytes()
+        );
+    }
 }
@@ -4,7 +4,10 @@
 //! Functions for resolving and building devices.
 
 use anyhow::Context as _;
+use chipset_device::mmio::RegisterMmioIntercept;
+use chipset_device_resources::ErasedChipsetDevice;
 use guestmem::GuestMemory;
+use pci_core::msi::MsiControl;
 use pci_core::msi::MsiInterruptSet;
 use pci_core::msi::MsiInterruptTarget;
 use std::sync::Arc;
@@ -89,3 +92,55 @@ pub async fn build_vpci_device(
 
     Ok(())
 }
+
+/// An MSI target that signals each MSI directly, for devices whose MSIs the
+/// guest programs in the architectural format rather than through a VPCI
+/// interrupt mapping.
+pub struct DirectMsiTarget(Arc<dyn Fn(u64, u32) + Send + Sync>);
+
+impl DirectMsiTarget {
+    /// Creates a new target that calls `signal` with the address and data of
+    /// each MSI.
+    pub fn new(signal: impl 'static + Fn(u64, u32) + Send + Sync) -> Self {
+        Self(Arc::new(signal))
+    }
+}
+
+impl MsiInterruptTarget for DirectMsiTarget {
+    fn new_interrupt(&self) -> Box<dyn MsiControl> {
+        let signal = self.0.clone();
+        Box::new(move |address, data| signal(address, data))
+    }
+}
+
+/// Resolves a PCI device resource and builds the corresponding device, to be
+/// plugged into a PCIe root port.
+///
+/// The device registers its BARs with `register_mmio`, which must belong to
+/// the root port, and its MSIs are connected to `msi_target`.
+pub async fn build_pcie_device(
+    driver_source: &VmTaskDriverSource,
+    resolver: &ResourceResolver,
+    guest_memory: &GuestMemory,
+    resource: Resource<PciDeviceHandleKind>,
+    register_mmio: &mut (dyn RegisterMmioIntercept + Send),
+    msi_target: &dyn MsiInterruptTarget,
+) -> anyhow::Result<ErasedChipsetDevice> {
+    let mut msi_set = MsiInterruptSet::new();
+    let device = resolver
+        .resolve(
+            resource,
+            pci_resources::ResolvePciDeviceHandleParams {
+                register_msi: &mut msi_set,
+                register_mmio,
+                driver_source,
+                guest_memory,
+            },
+        )
+        .await
+        .context("failed to resolve pci device")?
+        .0;
+
+    msi_set.connect(msi_target);
+    Ok(device)
+}
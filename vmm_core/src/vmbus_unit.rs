@@ -32,6 +32,7 @@
 use vmcore::save_restore::SaveError;
 use vmcore::save_restore::SavedStateBlob;
 use vmcore::vm_task::VmTaskDriverSource;
+use vmcore::vmtime::VmTimeSource;
 
 /// A handle to a vmbus server that is registered as a state unit.
 ///
@@ -225,13 +226,20 @@ async fn restore(&mut self, state: SavedStateBlob) -> Result<(), RestoreError> {
 /// Offers a channel, creates a unit for it, and adds it to `state_units`.
 pub async fn offer_vmbus_device_handle_unit(
     driver_source: &VmTaskDriverSource,
+    vmtime: &VmTimeSource,
     state_units: &StateUnits,
     vmbus: &VmbusServerHandle,
     resolver: &ResourceResolver,
     resource: Resource<VmbusDeviceHandleKind>,
 ) -> anyhow::Result<SpawnedUnit<ChannelUnit<dyn VmbusDevice>>> {
     let channel = resolver
-        .resolve(resource, ResolveVmbusDeviceHandleParams { driver_source })
+        .resolve(
+            resource,
+            ResolveVmbusDeviceHandleParams {
+                driver_source,
+                vmtime,
+            },
+        )
         .await?;
     let offer = channel.0.offer();
     let name = format!("{}:{}", offer.interface_name, offer.instance_id);
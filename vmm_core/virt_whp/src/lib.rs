@@ -63,9 +63,8 @@
 use vm_topology::memory::MemoryLayout;
 use vm_topology::processor::TargetVpInfo;
 use vmcore::monitor::MonitorPage;
-use vmcore::reference_time_source::ReferenceTimeSource;
+use vmcore::reference_time_source::VmTimeReferenceTimeSource;
 use vmcore::vmtime::VmTimeAccess;
-use vmcore::vmtime::VmTimeSource;
 use vp::WhpRunVpError;
 use vp_state::WhpVpStateAccess;
 use vtl_array::VtlSet;
@@ -1105,29 +1104,6 @@ fn post_message(&self, vtl: Vtl, vp: VpIndex, sint: u8, typ: HvMessageType, payl
     }
 }
 
-/// A time implementation based on VmTime.
-impl ReferenceTimeSource for VmTimeReferenceTimeSource {
-    fn now_100ns(&self) -> u64 {
-        self.vmtime.now().as_100ns()
-    }
-
-    fn is_backed_by_tsc(&self) -> bool {
-        false
-    }
-}
-
-struct VmTimeReferenceTimeSource {
-    vmtime: VmTimeAccess,
-}
-
-impl VmTimeReferenceTimeSource {
-    fn new(vmtime: VmTimeSource) -> Self {
-        VmTimeReferenceTimeSource {
-            vmtime: vmtime.access("reftime"),
-        }
-    }
-}
-
 impl VtlPartition {
     fn new(config: &ProtoPartitionConfig<'_>, vendor: Vendor, vtl: Vtl) -> Result<Self, Error> {
         let mut hypervisor_enlightened = false;
@@ -1355,7 +1331,7 @@ fn new(config: &ProtoPartitionConfig<'_>, vendor: Vendor, vtl: Vtl) -> Result<Se
                 Hv1State::Offloaded
             } else {
                 let tsc_frequency = whp.tsc_frequency().for_op("get tsc frequency")?;
-                let ref_time = Box::new(VmTimeReferenceTimeSource::new(config.vmtime.clone()));
+                let ref_time = Box::new(VmTimeReferenceTimeSource::new(config.vmtime));
                 Hv1State::Emulated(GlobalHv::new(GlobalHvParams {
                     max_vp_count: config.processor_topology.vp_count(),
                     vendor,
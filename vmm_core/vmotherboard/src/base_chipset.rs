@@ -260,10 +260,17 @@ pub async fn build(
             bus_id,
             pio_addr,
             pio_data,
+            ecam,
         }) = deps_generic_pci_bus
         {
             let pci = builder.arc_mutex_device("pci_bus").add(|services| {
-                pci_bus::GenericPciBus::new(&mut services.register_pio(), pio_addr, pio_data)
+                let bus =
+                    pci_bus::GenericPciBus::new(&mut services.register_pio(), pio_addr, pio_data);
+                if let Some((base, bus_count)) = ecam {
+                    bus.with_ecam(&mut services.register_mmio(), base, bus_count)
+                } else {
+                    bus
+                }
             })?;
 
             builder.register_weak_mutex_pci_bus(bus_id, Box::new(pci));
@@ -833,6 +840,36 @@ fn pci_cfg_write(&mut self, offset: u16, value: u32) -> Option<IoResult> {
                     .pci_cfg_write(offset, value),
             )
         }
+
+        fn pci_cfg_read_forward(
+            &mut self,
+            bus: u8,
+            device_function: u8,
+            offset: u16,
+            value: &mut u32,
+        ) -> Option<IoResult> {
+            self.0
+                .upgrade()?
+                .lock()
+                .supports_pci()
+                .expect("builder code ensures supports_pci.is_some()")
+                .pci_cfg_read_forward(bus, device_function, offset, value)
+        }
+
+        fn pci_cfg_write_forward(
+            &mut self,
+            bus: u8,
+            device_function: u8,
+            offset: u16,
+            value: u32,
+        ) -> Option<IoResult> {
+            self.0
+                .upgrade()?
+                .lock()
+                .supports_pci()
+                .expect("builder code ensures supports_pci.is_some()")
+                .pci_cfg_write_forward(bus, device_function, offset, value)
+        }
     }
 
     // wiring to enable using the generic PCI bus alongside the Arc+CloseableMutex device infra
@@ -1212,6 +1249,11 @@ pub struct GenericPciBusDeps {
             pub pio_addr: u16,
             /// Port io address of the 32-bit PCI DATA register
             pub pio_data: u16,
+            /// PCI Express ECAM region, as `(base_address, bus_count)`.
+            ///
+            /// If set, configuration space is additionally accessible via
+            /// ECAM, for buses starting at 0.
+            pub ecam: Option<(u64, u16)>,
         }
 
         /// PIIX4 PCI Bus
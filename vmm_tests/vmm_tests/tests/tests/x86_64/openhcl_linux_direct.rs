@@ -46,8 +46,12 @@ async fn boot_openhcl_linux_mana_nic(config: PetriVmConfig) -> Result<(), anyhow
                 resource: GdmaDeviceHandle {
                     vports: vec![VportDefinition {
                         mac_address: [0x00, 0x15, 0x5D, 0x12, 0x12, 0x12].into(),
-                        endpoint: net_backend_resources::consomme::ConsommeHandle { cidr: None }
-                            .into_resource(),
+                        endpoint: net_backend_resources::consomme::ConsommeHandle {
+                            cidr: None,
+                            ipv6_prefix: None,
+                            port_forwards: Vec::new(),
+                        }
+                        .into_resource(),
                     }],
                 }
                 .into_resource(),
@@ -159,6 +163,7 @@ async fn storvsp(config: PetriVmConfig) -> Result<(), anyhow::Error> {
                         .into_resource()),
                         read_only: false,
                     }],
+                    namespace_pool: None,
                 }
                 .into_resource(),
             });
@@ -414,6 +419,7 @@ async fn openhcl_linux_stripe_storvsp(config: PetriVmConfig) -> Result<(), anyho
                             .into_resource()),
                             read_only: false,
                         }],
+                        namespace_pool: None,
                     }
                     .into_resource(),
                 },
@@ -432,6 +438,7 @@ async fn openhcl_linux_stripe_storvsp(config: PetriVmConfig) -> Result<(), anyho
                             .into_resource()),
                             read_only: false,
                         }],
+                        namespace_pool: None,
                     }
                     .into_resource(),
                 },